@@ -0,0 +1,136 @@
+use reqwest::Url;
+use std::time::Duration;
+
+use crate::{Client, Error, RetryPolicy};
+
+/// How requests authenticate.
+#[derive(Debug, Clone, Default)]
+pub enum Auth {
+    #[default]
+    None,
+    /// Sent as `X-API-Key`.
+    ApiKey(String),
+    /// Every request is signed with HMAC-SHA256 (`X-Signature`), so the
+    /// secret itself never goes over the wire.
+    Signed { key_id: String, secret: String },
+}
+
+/// Configures a [`Client`].
+///
+/// ```no_run
+/// # fn run() -> Result<(), json_storage_client::Error> {
+/// use std::time::Duration;
+///
+/// let client = json_storage_client::Client::builder("https://storage.example.com")
+///     .api_key("secret")
+///     .timeout(Duration::from_secs(10))
+///     .pool_max_idle_per_host(8)
+///     .build()?;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug, Clone)]
+pub struct ClientBuilder {
+    base_url: String,
+    auth: Auth,
+    timeout: Option<Duration>,
+    connect_timeout: Option<Duration>,
+    retry: RetryPolicy,
+    pool_idle_timeout: Option<Duration>,
+    pool_max_idle_per_host: Option<usize>,
+    tcp_keepalive: Option<Duration>,
+    user_agent: Option<String>,
+}
+
+impl ClientBuilder {
+    pub fn new(base_url: impl Into<String>) -> Self {
+        ClientBuilder {
+            base_url: base_url.into(),
+            auth: Auth::None,
+            timeout: None,
+            connect_timeout: None,
+            retry: RetryPolicy::default(),
+            pool_idle_timeout: None,
+            pool_max_idle_per_host: None,
+            tcp_keepalive: None,
+            user_agent: None,
+        }
+    }
+
+    pub fn auth(mut self, auth: Auth) -> Self {
+        self.auth = auth;
+        self
+    }
+
+    pub fn api_key(self, key: impl Into<String>) -> Self {
+        self.auth(Auth::ApiKey(key.into()))
+    }
+
+    /// Signs requests with the signing key `key_id` configured on the server.
+    pub fn signing_key(self, key_id: impl Into<String>, secret: impl Into<String>) -> Self {
+        self.auth(Auth::Signed { key_id: key_id.into(), secret: secret.into() })
+    }
+
+    /// Limit on each attempt of a request, from connecting to reading the body.
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    pub fn connect_timeout(mut self, timeout: Duration) -> Self {
+        self.connect_timeout = Some(timeout);
+        self
+    }
+
+    pub fn retry(mut self, retry: RetryPolicy) -> Self {
+        self.retry = retry;
+        self
+    }
+
+    /// How long an idle pooled connection is kept open (90s by default).
+    pub fn pool_idle_timeout(mut self, timeout: Duration) -> Self {
+        self.pool_idle_timeout = Some(timeout);
+        self
+    }
+
+    /// Most idle connections kept per host; 0 disables keep-alive reuse.
+    pub fn pool_max_idle_per_host(mut self, max: usize) -> Self {
+        self.pool_max_idle_per_host = Some(max);
+        self
+    }
+
+    /// Interval of TCP keep-alive probes on open connections.
+    pub fn tcp_keepalive(mut self, interval: Duration) -> Self {
+        self.tcp_keepalive = Some(interval);
+        self
+    }
+
+    pub fn user_agent(mut self, user_agent: impl Into<String>) -> Self {
+        self.user_agent = Some(user_agent.into());
+        self
+    }
+
+    pub fn build(self) -> Result<Client, Error> {
+        let base = Url::parse(&self.base_url).map_err(|_| Error::Url(self.base_url.clone()))?;
+        let mut http = reqwest::Client::builder();
+        if let Some(timeout) = self.timeout {
+            http = http.timeout(timeout);
+        }
+        if let Some(timeout) = self.connect_timeout {
+            http = http.connect_timeout(timeout);
+        }
+        if let Some(timeout) = self.pool_idle_timeout {
+            http = http.pool_idle_timeout(timeout);
+        }
+        if let Some(max) = self.pool_max_idle_per_host {
+            http = http.pool_max_idle_per_host(max);
+        }
+        if let Some(interval) = self.tcp_keepalive {
+            http = http.tcp_keepalive(interval);
+        }
+        if let Some(user_agent) = self.user_agent {
+            http = http.user_agent(user_agent);
+        }
+        Ok(Client { http: http.build()?, base, auth: self.auth, retry: self.retry })
+    }
+}
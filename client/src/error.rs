@@ -0,0 +1,48 @@
+use std::fmt;
+
+#[derive(Debug)]
+pub enum Error {
+    /// The request could not be sent or its response could not be read.
+    Http(reqwest::Error),
+    /// A response body or an imported line was not the expected JSON.
+    Decode(serde_json::Error),
+    /// Reading or writing a local stream failed.
+    Io(std::io::Error),
+    /// The base URL or a path built from it is not a valid URL.
+    Url(String),
+    /// The collection or document does not exist.
+    NotFound,
+    /// A conditional write found the document at another version, or gone.
+    VersionConflict { current: Option<i64> },
+    /// Any other unsuccessful response, with the server's message.
+    Status { status: u16, message: String },
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Http(e) => write!(f, "{}", e),
+            Error::Decode(e) => write!(f, "invalid JSON: {}", e),
+            Error::Io(e) => write!(f, "{}", e),
+            Error::Url(url) => write!(f, "invalid URL: {}", url),
+            Error::NotFound => write!(f, "not found"),
+            Error::VersionConflict { current: Some(current) } => write!(f, "document is at version {}", current),
+            Error::VersionConflict { current: None } => write!(f, "document no longer exists"),
+            Error::Status { status, message } => write!(f, "{} {}", status, message),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<reqwest::Error> for Error {
+    fn from(e: reqwest::Error) -> Self {
+        Error::Http(e)
+    }
+}
+
+impl From<serde_json::Error> for Error {
+    fn from(e: serde_json::Error) -> Self {
+        Error::Decode(e)
+    }
+}
@@ -0,0 +1,574 @@
+//! Async client for the json_storage HTTP API.
+//!
+//! ```no_run
+//! # async fn run() -> Result<(), json_storage_client::Error> {
+//! use serde::{Deserialize, Serialize};
+//!
+//! #[derive(Serialize, Deserialize)]
+//! struct User {
+//!     name: String,
+//!     age: i64,
+//! }
+//!
+//! let client = json_storage_client::Client::builder("http://127.0.0.1:8080").api_key("secret").build()?;
+//! let users = client.collection::<User>("users");
+//! let id = users.insert(&User { name: "Ann".into(), age: 30 }).await?;
+//! let user = users.get(id).await?.expect("just inserted");
+//! users.update(id, &serde_json::json!({ "age": 31 }), Some(user.version)).await?;
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! Documents come back as [`Document`], which carries the id and `_version`
+//! next to the typed fields. Transient failures are retried according to the
+//! client's [`RetryPolicy`]; see [`ClientBuilder`] for timeouts, connection
+//! pooling and authentication.
+
+mod builder;
+mod error;
+mod ndjson;
+mod retry;
+
+pub use builder::{Auth, ClientBuilder};
+pub use error::Error;
+pub use retry::RetryPolicy;
+
+use hmac::{Hmac, Mac};
+use rand::Rng;
+use reqwest::header::{CONTENT_TYPE, ETAG, IF_MATCH, LOCATION};
+use reqwest::{Method, RequestBuilder, Response, StatusCode, Url};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use sha2::Sha256;
+use std::marker::PhantomData;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use retry::{retry_after, retryable_error, retryable_status};
+
+const API_KEY_HEADER: &str = "X-API-Key";
+const SIGNATURE_HEADER: &str = "X-Signature";
+const SIGNATURE_KEY_HEADER: &str = "X-Signature-Key";
+const SIGNATURE_TIMESTAMP_HEADER: &str = "X-Signature-Timestamp";
+const IDEMPOTENCY_HEADER: &str = "Idempotency-Key";
+const TOTAL_COUNT_HEADER: &str = "X-Total-Count";
+
+/// A stored document: its id and version, and the fields as `T`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Document<T> {
+    pub id: i64,
+    /// Bumped by every write; pass it back to make a write conditional.
+    #[serde(rename = "_version", default = "first_version")]
+    pub version: i64,
+    #[serde(flatten)]
+    pub data: T,
+}
+
+fn first_version() -> i64 {
+    1
+}
+
+/// Result of [`Collection::get_many`].
+#[derive(Debug, Clone, Deserialize)]
+#[serde(bound = "T: DeserializeOwned")]
+pub struct Many<T> {
+    /// Found documents, in the order they were asked for.
+    pub documents: Vec<Document<T>>,
+    /// Requested ids with no document.
+    pub missing: Vec<i64>,
+}
+
+/// One result of [`Client::search`].
+#[derive(Debug, Clone, Deserialize)]
+pub struct Hit {
+    pub collection: String,
+    pub id: Option<i64>,
+    pub document: Value,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct SearchResult {
+    pub hits: Vec<Hit>,
+    /// More documents matched than the limit.
+    pub truncated: bool,
+}
+
+/// One path of [`Client::paths`].
+#[derive(Debug, Clone, Deserialize)]
+pub struct PathEntry {
+    pub path: String,
+    /// A collection lives at this path itself.
+    pub collection: bool,
+    /// Readable collections at or below this path.
+    pub collections: usize,
+}
+
+#[derive(Deserialize)]
+struct Paths {
+    paths: Vec<PathEntry>,
+}
+
+/// Connection to one server. Cheap to clone; clones share the connection pool.
+#[derive(Debug, Clone)]
+pub struct Client {
+    http: reqwest::Client,
+    base: Url,
+    auth: Auth,
+    retry: RetryPolicy,
+}
+
+impl Client {
+    /// Client with default settings and no authentication.
+    pub fn new(base_url: &str) -> Result<Self, Error> {
+        ClientBuilder::new(base_url).build()
+    }
+
+    pub fn builder(base_url: impl Into<String>) -> ClientBuilder {
+        ClientBuilder::new(base_url)
+    }
+
+    /// Typed handle on the documents stored under `name`, a collection path
+    /// such as `users` or `api/v1/users`.
+    pub fn collection<T>(&self, name: impl Into<String>) -> Collection<T> {
+        Collection { client: self.clone(), name: name.into(), _marker: PhantomData }
+    }
+
+    /// Searches every collection the caller may read; see `GET /_search`.
+    pub async fn search(&self, q: &str, limit: Option<u32>) -> Result<SearchResult, Error> {
+        let mut url = self.url("_search")?;
+        url.query_pairs_mut().append_pair("q", q);
+        if let Some(limit) = limit {
+            url.query_pairs_mut().append_pair("limit", &limit.to_string());
+        }
+        let response = self.send(Method::GET, url, None, true, |r| r).await?;
+        Ok(response.json().await?)
+    }
+
+    /// Paths one level below `prefix` that lead to collections the caller
+    /// may read; `""` lists the first segments. See `GET /_paths`.
+    pub async fn paths(&self, prefix: &str) -> Result<Vec<PathEntry>, Error> {
+        let mut url = self.url("_paths")?;
+        url.query_pairs_mut().append_pair("prefix", prefix);
+        let response = self.send(Method::GET, url, None, true, |r| r).await?;
+        Ok(response.json::<Paths>().await?.paths)
+    }
+
+    fn url(&self, path: &str) -> Result<Url, Error> {
+        let base = self.base.as_str().trim_end_matches('/');
+        let url = format!("{}/{}", base, path);
+        Url::parse(&url).map_err(|_| Error::Url(url))
+    }
+
+    // 发送请求, body 为 JSON; retry 为 true 时按重试策略重发暂时性失败的请求
+    async fn send(
+        &self,
+        method: Method,
+        url: Url,
+        body: Option<&Value>,
+        retry: bool,
+        build: impl Fn(RequestBuilder) -> RequestBuilder,
+    ) -> Result<Response, Error> {
+        let body = body.map(serde_json::to_vec).transpose()?;
+        let max_retries = if retry { self.retry.max_retries } else { 0 };
+        let mut attempt = 0;
+        loop {
+            let mut request = build(self.http.request(method.clone(), url.clone()));
+            if let Some(body) = &body {
+                request = request.header(CONTENT_TYPE, "application/json").body(body.clone());
+            }
+            request = self.authenticate(request, &method, &url, body.as_deref().unwrap_or_default());
+            let wait = match request.send().await {
+                Ok(response) if attempt < max_retries && retryable_status(response.status()) => {
+                    retry_after(&response, &self.retry).unwrap_or_else(|| self.retry.backoff(attempt))
+                }
+                Ok(response) => return check(response).await,
+                Err(e) if attempt < max_retries && retryable_error(&e) => self.retry.backoff(attempt),
+                Err(e) => return Err(e.into()),
+            };
+            tokio::time::sleep(wait).await;
+            attempt += 1;
+        }
+    }
+
+    // 签名在每次发送时重新计算, 重试不会因时间戳过期而失败
+    fn authenticate(&self, request: RequestBuilder, method: &Method, url: &Url, body: &[u8]) -> RequestBuilder {
+        match &self.auth {
+            Auth::None => request,
+            Auth::ApiKey(key) => request.header(API_KEY_HEADER, key),
+            Auth::Signed { key_id, secret } => {
+                let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs());
+                let target = match url.query() {
+                    Some(query) => format!("{}?{}", url.path(), query),
+                    None => url.path().to_string(),
+                };
+                let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC accepts any key length");
+                mac.update(format!("{}\n{}\n{}\n", timestamp, method, target).as_bytes());
+                mac.update(body);
+                request
+                    .header(SIGNATURE_KEY_HEADER, key_id)
+                    .header(SIGNATURE_TIMESTAMP_HEADER, timestamp.to_string())
+                    .header(SIGNATURE_HEADER, format!("sha256={}", hex(&mac.finalize().into_bytes())))
+            }
+        }
+    }
+}
+
+// 把失败的响应转换为 Error
+async fn check(response: Response) -> Result<Response, Error> {
+    let status = response.status();
+    if status.is_success() {
+        return Ok(response);
+    }
+    match status {
+        StatusCode::NOT_FOUND => Err(Error::NotFound),
+        StatusCode::PRECONDITION_FAILED => Err(Error::VersionConflict { current: version(&response) }),
+        _ => {
+            let text = response.text().await.unwrap_or_default();
+            // 服务端的错误信息是 JSON 字符串
+            let message = serde_json::from_str::<String>(&text).unwrap_or(text);
+            Err(Error::Status { status: status.as_u16(), message })
+        }
+    }
+}
+
+// 从 ETag 中取出版本号
+fn version(response: &Response) -> Option<i64> {
+    let tag = response.headers().get(ETAG)?.to_str().ok()?;
+    tag.trim_matches('"').parse().ok()
+}
+
+/// The documents of one collection, deserialized as `T`.
+#[derive(Debug, Clone)]
+pub struct Collection<T> {
+    client: Client,
+    name: String,
+    _marker: PhantomData<fn() -> T>,
+}
+
+impl<T: Serialize + DeserializeOwned> Collection<T> {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Stores `doc` and returns its id; for a collection with deduplication
+    /// this may be the id of an existing document.
+    ///
+    /// Each insert carries a fresh `Idempotency-Key`, so a retried insert
+    /// does not store the document twice.
+    pub async fn insert(&self, doc: &T) -> Result<i64, Error> {
+        self.insert_value(&serde_json::to_value(doc)?).await
+    }
+
+    async fn insert_value(&self, doc: &Value) -> Result<i64, Error> {
+        let url = self.client.url(&self.name)?;
+        let body = json!({ "uri": self.name, "data": doc });
+        let key = idempotency_key();
+        let response = self
+            .client
+            .send(Method::POST, url, Some(&body), true, |r| r.header(IDEMPOTENCY_HEADER, &key))
+            .await?;
+
+        let location = response.headers().get(LOCATION).and_then(|v| v.to_str().ok()).map(str::to_string);
+        if let Some(id) = location.as_deref().and_then(|l| l.rsplit('/').next()).and_then(|id| id.parse().ok()) {
+            return Ok(id);
+        }
+        // 没有 Location 时, 去重集合的响应体里也有 id
+        let body: Value = response.json().await?;
+        body.get("id").and_then(Value::as_i64).ok_or_else(|| Error::Status {
+            status: StatusCode::OK.as_u16(),
+            message: "response does not say the id of the new document".to_string(),
+        })
+    }
+
+    /// Every document in the collection, fetched a page at a time; documents
+    /// written in between may be missed or returned twice.
+    pub async fn list(&self) -> Result<Vec<Document<T>>, Error> {
+        self.list_where(&[]).await
+    }
+
+    /// The documents whose fields equal the given values, as query parameters
+    /// of `GET /{uri}`; a field given twice matches either value. Pages are
+    /// fetched as for [`Collection::list`].
+    pub async fn list_where(&self, fields: &[(&str, &str)]) -> Result<Vec<Document<T>>, Error> {
+        let mut docs = Vec::new();
+        loop {
+            let mut url = self.client.url(&self.name)?;
+            url.query_pairs_mut().extend_pairs(fields).append_pair("offset", &docs.len().to_string());
+            let response = self.client.send(Method::GET, url, None, true, |r| r).await?;
+            let total: Option<usize> =
+                response.headers().get(TOTAL_COUNT_HEADER).and_then(|v| v.to_str().ok()).and_then(|v| v.parse().ok());
+            let page: Vec<Document<T>> = response.json().await?;
+            // 不返回总数的服务端不分页
+            let done = page.is_empty() || total.is_none_or(|total| docs.len() + page.len() >= total);
+            docs.extend(page);
+            if done {
+                return Ok(docs);
+            }
+        }
+    }
+
+    pub async fn get(&self, id: i64) -> Result<Option<Document<T>>, Error> {
+        let url = self.client.url(&format!("{}/{}", self.name, id))?;
+        match self.client.send(Method::GET, url, None, true, |r| r).await {
+            Ok(response) => Ok(Some(response.json().await?)),
+            Err(Error::NotFound) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// The documents with the given ids, in one request.
+    pub async fn get_many(&self, ids: &[i64]) -> Result<Many<T>, Error> {
+        let url = self.client.url(&format!("{}/_mget", self.name))?;
+        let body = json!({ "ids": ids });
+        let response = self.client.send(Method::POST, url, Some(&body), true, |r| r).await?;
+        Ok(response.json().await?)
+    }
+
+    /// Replaces document `id`; fields missing from `doc` are cleared.
+    ///
+    /// With `if_version` set, the write only happens while the document is at
+    /// that version, otherwise it fails with [`Error::VersionConflict`].
+    pub async fn replace(&self, id: i64, doc: &T, if_version: Option<i64>) -> Result<Document<T>, Error> {
+        self.write(Method::PUT, id, &serde_json::to_value(doc)?, if_version).await
+    }
+
+    /// Sets the fields in `fields` on document `id`, leaving the others alone.
+    /// `if_version` works as for [`Collection::replace`].
+    pub async fn update<P: Serialize>(&self, id: i64, fields: &P, if_version: Option<i64>) -> Result<Document<T>, Error> {
+        self.write(Method::PATCH, id, &serde_json::to_value(fields)?, if_version).await
+    }
+
+    /// Deletes document `id`; `if_version` works as for [`Collection::replace`].
+    pub async fn delete(&self, id: i64, if_version: Option<i64>) -> Result<(), Error> {
+        let url = self.client.url(&format!("{}/{}", self.name, id))?;
+        self.client.send(Method::DELETE, url, None, true, |r| with_if_match(r, if_version)).await?;
+        Ok(())
+    }
+
+    async fn write(&self, method: Method, id: i64, body: &Value, if_version: Option<i64>) -> Result<Document<T>, Error> {
+        let url = self.client.url(&format!("{}/{}", self.name, id))?;
+        let response = self.client.send(method, url, Some(body), true, |r| with_if_match(r, if_version)).await?;
+        Ok(response.json().await?)
+    }
+}
+
+fn with_if_match(request: RequestBuilder, version: Option<i64>) -> RequestBuilder {
+    match version {
+        Some(version) => request.header(IF_MATCH, format!("\"{}\"", version)),
+        None => request,
+    }
+}
+
+fn idempotency_key() -> String {
+    let bytes: [u8; 16] = rand::thread_rng().gen();
+    hex(&bytes)
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeMap;
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    #[derive(Debug)]
+    struct Request {
+        method: String,
+        target: String,
+        // 头名为小写
+        headers: BTreeMap<String, String>,
+        body: Vec<u8>,
+    }
+
+    impl Request {
+        fn json(&self) -> Value {
+            serde_json::from_slice(&self.body).unwrap()
+        }
+    }
+
+    struct Reply {
+        status: u16,
+        headers: Vec<(&'static str, String)>,
+        body: String,
+    }
+
+    fn reply(status: u16, body: Value) -> Reply {
+        Reply { status, headers: Vec::new(), body: body.to_string() }
+    }
+
+    type Received = Arc<Mutex<Vec<Request>>>;
+
+    // 每个连接回答一个请求, 按顺序给出 replies 中的响应
+    async fn serve(replies: Vec<Reply>) -> (Client, Received) {
+        serve_with(replies, Auth::ApiKey("secret".to_string())).await
+    }
+
+    async fn serve_with(replies: Vec<Reply>, auth: Auth) -> (Client, Received) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let base = format!("http://{}", listener.local_addr().unwrap());
+        let received = Received::default();
+        let requests = received.clone();
+        tokio::spawn(async move {
+            for reply in replies {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let mut buf = Vec::new();
+                let end = loop {
+                    let mut chunk = [0; 4096];
+                    let n = stream.read(&mut chunk).await.unwrap();
+                    buf.extend_from_slice(&chunk[..n]);
+                    if let Some(end) = buf.windows(4).position(|w| w == b"\r\n\r\n") {
+                        break end;
+                    }
+                };
+                let head = String::from_utf8(buf[..end].to_vec()).unwrap();
+                let mut lines = head.split("\r\n");
+                let mut start = lines.next().unwrap().split(' ');
+                let (method, target) = (start.next().unwrap().to_string(), start.next().unwrap().to_string());
+                let headers: BTreeMap<String, String> = lines
+                    .filter_map(|line| line.split_once(':'))
+                    .map(|(name, value)| (name.to_ascii_lowercase(), value.trim().to_string()))
+                    .collect();
+                let length: usize = headers.get("content-length").map_or(0, |l| l.parse().unwrap());
+                let mut body = buf[end + 4..].to_vec();
+                while body.len() < length {
+                    let mut chunk = [0; 4096];
+                    let n = stream.read(&mut chunk).await.unwrap();
+                    body.extend_from_slice(&chunk[..n]);
+                }
+                requests.lock().unwrap().push(Request { method, target, headers, body });
+
+                let mut response = format!("HTTP/1.1 {} X\r\ncontent-length: {}\r\nconnection: close\r\n", reply.status, reply.body.len());
+                for (name, value) in &reply.headers {
+                    response.push_str(&format!("{}: {}\r\n", name, value));
+                }
+                response.push_str("\r\n");
+                response.push_str(&reply.body);
+                stream.write_all(response.as_bytes()).await.unwrap();
+                stream.shutdown().await.unwrap();
+            }
+        });
+        let retry = RetryPolicy { max_retries: 2, initial_backoff: Duration::from_millis(1), max_backoff: Duration::from_millis(5) };
+        let client = Client::builder(base).auth(auth).retry(retry).build().unwrap();
+        (client, received)
+    }
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct User {
+        name: String,
+    }
+
+    fn ann() -> User {
+        User { name: "Ann".to_string() }
+    }
+
+    #[tokio::test]
+    async fn an_insert_is_retried_with_the_same_idempotency_key() {
+        let created = Reply { status: 201, headers: vec![("location", "/api/users/7".to_string())], body: "{}".to_string() };
+        let (client, received) = serve(vec![reply(503, json!("busy")), created]).await;
+        assert_eq!(client.collection::<User>("api/users").insert(&ann()).await.unwrap(), 7);
+
+        let received = received.lock().unwrap();
+        assert_eq!(received.len(), 2);
+        for request in received.iter() {
+            assert_eq!((request.method.as_str(), request.target.as_str()), ("POST", "/api/users"));
+            assert_eq!(request.headers["x-api-key"], "secret");
+            assert_eq!(request.headers["content-type"], "application/json");
+            assert_eq!(request.json(), json!({ "uri": "api/users", "data": { "name": "Ann" } }));
+        }
+        let key = &received[0].headers["idempotency-key"];
+        assert_eq!(key.len(), 32);
+        assert_eq!(&received[1].headers["idempotency-key"], key);
+    }
+
+    #[tokio::test]
+    async fn an_insert_without_location_reads_the_id_from_the_body() {
+        let (client, _) = serve(vec![reply(200, json!({ "id": 3, "duplicate": true }))]).await;
+        assert_eq!(client.collection::<User>("users").insert(&ann()).await.unwrap(), 3);
+    }
+
+    #[tokio::test]
+    async fn documents_come_back_with_id_and_version() {
+        let (client, received) = serve(vec![reply(200, json!({ "id": 4, "_version": 2, "name": "Ann" })), reply(404, json!("gone"))]).await;
+        let users = client.collection::<User>("users");
+        let user = users.get(4).await.unwrap().unwrap();
+        assert_eq!((user.id, user.version, user.data), (4, 2, ann()));
+        assert!(users.get(5).await.unwrap().is_none());
+        let targets: Vec<_> = received.lock().unwrap().iter().map(|r| r.target.clone()).collect();
+        assert_eq!(targets, ["/users/4", "/users/5"]);
+    }
+
+    #[tokio::test]
+    async fn conditional_writes_send_if_match_and_report_the_current_version() {
+        let conflict = Reply { status: 412, headers: vec![("etag", "\"5\"".to_string())], body: "\"conflict\"".to_string() };
+        let (client, received) = serve(vec![conflict, reply(412, json!("gone")), reply(200, json!({ "id": 1, "_version": 6, "name": "Bo" }))]).await;
+        let users = client.collection::<User>("users");
+        let fields = json!({ "name": "Bo" });
+        assert!(matches!(users.update(1, &fields, Some(4)).await, Err(Error::VersionConflict { current: Some(5) })));
+        assert!(matches!(users.update(1, &fields, Some(5)).await, Err(Error::VersionConflict { current: None })));
+        assert_eq!(users.update(1, &fields, None).await.unwrap().version, 6);
+
+        let received = received.lock().unwrap();
+        assert_eq!(received[0].method, "PATCH");
+        assert_eq!(received[0].json(), fields);
+        assert_eq!(received[0].headers["if-match"], "\"4\"");
+        assert!(!received[2].headers.contains_key("if-match"));
+    }
+
+    #[tokio::test]
+    async fn other_failures_carry_the_server_message_and_are_not_retried() {
+        let text = Reply { status: 500, headers: Vec::new(), body: "not json".to_string() };
+        let (client, received) = serve(vec![reply(400, json!("Field names cannot be empty")), text]).await;
+        let users = client.collection::<User>("users");
+        match users.get(1).await {
+            Err(Error::Status { status: 400, message }) => assert_eq!(message, "Field names cannot be empty"),
+            other => panic!("{:?}", other),
+        }
+        match users.delete(1, Some(2)).await {
+            Err(Error::Status { status: 500, message }) => assert_eq!(message, "not json"),
+            other => panic!("{:?}", other),
+        }
+        let received = received.lock().unwrap();
+        assert_eq!(received.len(), 2);
+        assert_eq!((received[1].method.as_str(), received[1].headers["if-match"].as_str()), ("DELETE", "\"2\""));
+    }
+
+    #[tokio::test]
+    async fn a_body_that_does_not_match_the_type_fails_to_decode() {
+        let (client, _) = serve(vec![reply(200, json!({ "id": 4, "name": 12 }))]).await;
+        assert!(matches!(client.collection::<User>("users").get(4).await, Err(Error::Http(e)) if e.is_decode()));
+    }
+
+    #[tokio::test]
+    async fn lists_are_fetched_a_page_at_a_time() {
+        let page = |ids: &[i64]| {
+            let docs: Vec<_> = ids.iter().map(|id| json!({ "id": id, "_version": 1, "name": "Ann" })).collect();
+            Reply { status: 200, headers: vec![("x-total-count", "3".to_string())], body: json!(docs).to_string() }
+        };
+        let (client, received) = serve(vec![page(&[1, 2]), page(&[3])]).await;
+        let docs = client.collection::<User>("users").list_where(&[("name", "Ann")]).await.unwrap();
+        assert_eq!(docs.iter().map(|d| d.id).collect::<Vec<_>>(), [1, 2, 3]);
+        let targets: Vec<_> = received.lock().unwrap().iter().map(|r| r.target.clone()).collect();
+        assert_eq!(targets, ["/users?name=Ann&offset=0", "/users?name=Ann&offset=2"]);
+    }
+
+    #[tokio::test]
+    async fn signed_requests_carry_an_hmac_of_the_request() {
+        let auth = Auth::Signed { key_id: "k1".to_string(), secret: "shh".to_string() };
+        let (client, received) = serve_with(vec![reply(200, json!({ "hits": [], "truncated": false }))], auth).await;
+        assert!(client.search("ann", Some(5)).await.unwrap().hits.is_empty());
+
+        let received = received.lock().unwrap();
+        let headers = &received[0].headers;
+        assert!(!headers.contains_key("x-api-key"));
+        assert_eq!(headers["x-signature-key"], "k1");
+        let mut mac = Hmac::<Sha256>::new_from_slice(b"shh").unwrap();
+        mac.update(format!("{}\nGET\n/_search?q=ann&limit=5\n", headers["x-signature-timestamp"]).as_bytes());
+        assert_eq!(headers["x-signature"], format!("sha256={}", hex(&mac.finalize().into_bytes())));
+    }
+}
@@ -0,0 +1,62 @@
+use rand::Rng;
+use reqwest::header::RETRY_AFTER;
+use reqwest::{Response, StatusCode};
+use std::time::Duration;
+
+/// When and how long to wait before retrying a failed request.
+///
+/// Only requests that are safe to repeat are retried: reads, `PUT`, `PATCH`
+/// and `DELETE`, and inserts, which carry an `Idempotency-Key`.
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    /// Retries after the first attempt; 0 disables retrying.
+    pub max_retries: u32,
+    /// Wait before the first retry, doubled for every further one.
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_retries: 3,
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_secs(5),
+        }
+    }
+}
+
+impl RetryPolicy {
+    pub fn none() -> Self {
+        RetryPolicy { max_retries: 0, ..RetryPolicy::default() }
+    }
+
+    // 第 attempt 次重试前的等待时间, 加上随机抖动
+    pub(crate) fn backoff(&self, attempt: u32) -> Duration {
+        let exp = self.initial_backoff.saturating_mul(2u32.saturating_pow(attempt));
+        let capped = exp.min(self.max_backoff);
+        let jitter = rand::thread_rng().gen_range(0.5..=1.0);
+        capped.mul_f64(jitter)
+    }
+}
+
+/// Transient failures worth another attempt.
+pub(crate) fn retryable_error(e: &reqwest::Error) -> bool {
+    e.is_connect() || e.is_timeout()
+}
+
+pub(crate) fn retryable_status(status: StatusCode) -> bool {
+    matches!(
+        status,
+        StatusCode::TOO_MANY_REQUESTS
+            | StatusCode::BAD_GATEWAY
+            | StatusCode::SERVICE_UNAVAILABLE
+            | StatusCode::GATEWAY_TIMEOUT
+    )
+}
+
+// 服务端给出 Retry-After (秒) 时按它等待, 但不超过 max_backoff
+pub(crate) fn retry_after(response: &Response, policy: &RetryPolicy) -> Option<Duration> {
+    let secs: u64 = response.headers().get(RETRY_AFTER)?.to_str().ok()?.trim().parse().ok()?;
+    Some(Duration::from_secs(secs).min(policy.max_backoff))
+}
@@ -0,0 +1,226 @@
+//! Per-request access log, kept apart from the application log.
+//!
+//! Lines go to stdout, or to the rotating file in `access_log.file`, in either
+//! the combined log format or JSON. Besides the usual request fields each line
+//! carries the caller's tenant and API key id, the collection and the number of
+//! rows the handler returned or wrote (see [`annotate`]). Only the requests
+//! kept by [`sampling`] are logged.
+
+use actix_web::body::{BodySize, MessageBody};
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::middleware::Next;
+use actix_web::{web, Error, HttpMessage, HttpResponse};
+use serde_json::json;
+use std::io::{self, Write};
+use std::sync::Mutex;
+use std::time::Instant;
+
+use crate::auth::Principal;
+use crate::config::{AccessLogFormat, ConfigHandle, LogFile};
+use crate::ip_filter::client_ip;
+use crate::logging::{now, FileSink};
+use crate::sampling;
+
+static SINK: Mutex<Option<FileSink>> = Mutex::new(None);
+
+/// Sends access log lines to `settings`, or back to stdout with `None`.
+pub fn set_file(settings: Option<&LogFile>) -> io::Result<()> {
+    let mut current = SINK.lock().unwrap_or_else(|e| e.into_inner());
+    if current.as_ref().map(|sink| &sink.settings) == settings {
+        return Ok(());
+    }
+    *current = match settings {
+        Some(settings) => Some(FileSink::open(settings.clone())?),
+        None => None,
+    };
+    Ok(())
+}
+
+/// What a handler touched, attached to its response for the access log.
+#[derive(Debug, Clone)]
+pub struct AccessInfo {
+    pub collection: String,
+    pub rows: u64,
+}
+
+/// Records the collection and row count on `response`.
+pub fn annotate(mut response: HttpResponse, collection: &str, rows: usize) -> HttpResponse {
+    response.extensions_mut().insert(AccessInfo {
+        collection: collection.to_string(),
+        rows: rows as u64,
+    });
+    response
+}
+
+struct Entry {
+    ts: u64,
+    remote: String,
+    method: String,
+    target: String,
+    version: String,
+    status: u16,
+    bytes: Option<u64>,
+    referer: Option<String>,
+    user_agent: Option<String>,
+    key_id: Option<String>,
+    tenant: Option<String>,
+    collection: Option<String>,
+    rows: Option<u64>,
+    duration_ms: f64,
+}
+
+/// Writes one access log line per request.
+pub async fn log_request(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<impl MessageBody>, Error> {
+    let Some(config) = req.app_data::<web::Data<ConfigHandle>>().map(|c| c.get()) else {
+        return next.call(req).await;
+    };
+    if !config.access_log.enabled {
+        return next.call(req).await;
+    }
+
+    let started = Instant::now();
+    sampling::drawn(&req, &config.sampling);
+    let header = |name: &str| req.headers().get(name).and_then(|v| v.to_str().ok()).map(str::to_string);
+    let mut entry = Entry {
+        ts: now(),
+        remote: client_ip(&req, &config.ip_filter)
+            .map(|ip| ip.to_string())
+            .unwrap_or_else(|| "-".to_string()),
+        method: req.method().to_string(),
+        target: req.uri().path_and_query().map_or("/", |p| p.as_str()).to_string(),
+        version: format!("{:?}", req.version()),
+        status: 0,
+        bytes: None,
+        referer: header("Referer"),
+        user_agent: header("User-Agent"),
+        key_id: None,
+        tenant: None,
+        collection: None,
+        rows: None,
+        duration_ms: 0.0,
+    };
+
+    let result = next.call(req).await;
+    let elapsed = started.elapsed();
+    entry.duration_ms = elapsed.as_secs_f64() * 1000.0;
+    match &result {
+        Ok(res) => {
+            if !sampling::keep(res.request(), &config.sampling, res.status().as_u16(), elapsed) {
+                return result;
+            }
+            entry.status = res.status().as_u16();
+            entry.bytes = match res.response().body().size() {
+                BodySize::Sized(n) => Some(n),
+                _ => None,
+            };
+            if let Some(principal) = res.request().extensions().get::<Principal>() {
+                entry.key_id = Some(principal.id.clone());
+                entry.tenant = principal.tenant.clone();
+            }
+            match res.response().extensions().get::<AccessInfo>() {
+                Some(info) => {
+                    entry.collection = Some(info.collection.clone());
+                    entry.rows = Some(info.rows);
+                }
+                // 处理器没有标注时, 从路由参数取集合名
+                None => {
+                    let params = res.request().match_info();
+                    entry.collection = params.get("uri").or_else(|| params.get("name")).map(str::to_string);
+                }
+            }
+        }
+        Err(e) => entry.status = e.as_response_error().status_code().as_u16(),
+    }
+
+    let line = match config.access_log.format {
+        AccessLogFormat::Combined => combined(&entry),
+        AccessLogFormat::Json => json_line(&entry),
+    };
+    write_line(&line, entry.ts);
+    result
+}
+
+fn write_line(line: &str, ts: u64) {
+    let mut sink = SINK.lock().unwrap_or_else(|e| e.into_inner());
+    match sink.as_mut() {
+        Some(sink) => {
+            if let Err(e) = sink.write(line, ts) {
+                log::error!("failed to write access log {}: {}", sink.settings.path.display(), e);
+            }
+        }
+        None => {
+            let _ = io::stdout().lock().write_all(line.as_bytes());
+        }
+    }
+}
+
+// host - user [time] "request" status bytes "referer" "user-agent" 之后追加 key=value 字段
+fn combined(e: &Entry) -> String {
+    let dash = |v: &Option<String>| v.clone().unwrap_or_else(|| "-".to_string());
+    let (y, mo, d, h, mi, s) = civil(e.ts);
+    format!(
+        "{} - {} [{:02}/{}/{}:{:02}:{:02}:{:02} +0000] \"{} {} {}\" {} {} \"{}\" \"{}\" tenant={} collection={} rows={} duration_ms={:.3}\n",
+        e.remote,
+        dash(&e.key_id),
+        d,
+        MONTHS[mo as usize - 1],
+        y,
+        h,
+        mi,
+        s,
+        e.method,
+        e.target,
+        e.version,
+        e.status,
+        e.bytes.map(|b| b.to_string()).unwrap_or_else(|| "-".to_string()),
+        dash(&e.referer).replace('"', "\\\""),
+        dash(&e.user_agent).replace('"', "\\\""),
+        dash(&e.tenant),
+        dash(&e.collection),
+        e.rows.map(|r| r.to_string()).unwrap_or_else(|| "-".to_string()),
+        e.duration_ms,
+    )
+}
+
+fn json_line(e: &Entry) -> String {
+    let (y, mo, d, h, mi, s) = civil(e.ts);
+    let line = json!({
+        "time": format!("{}-{:02}-{:02}T{:02}:{:02}:{:02}Z", y, mo, d, h, mi, s),
+        "remote": e.remote,
+        "method": e.method,
+        "target": e.target,
+        "protocol": e.version,
+        "status": e.status,
+        "bytes": e.bytes,
+        "referer": e.referer,
+        "user_agent": e.user_agent,
+        "key_id": e.key_id,
+        "tenant": e.tenant,
+        "collection": e.collection,
+        "rows": e.rows,
+        "duration_ms": e.duration_ms,
+    });
+    format!("{}\n", line)
+}
+
+const MONTHS: [&str; 12] = ["Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec"];
+
+// unix 时间戳转换为 UTC 日期时间 (year, month, day, hour, minute, second)
+pub(crate) fn civil(ts: u64) -> (i64, u32, u32, u32, u32, u32) {
+    let days = (ts / 86400) as i64;
+    let secs = ts % 86400;
+    // Howard Hinnant 的 civil_from_days 算法
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day, (secs / 3600) as u32, (secs % 3600 / 60) as u32, (secs % 60) as u32)
+}
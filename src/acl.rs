@@ -0,0 +1,454 @@
+//! Collection-level access control lists.
+//!
+//! Entries are kept in the `_acl` table and grant a principal (API key id,
+//! certificate subject or SSO user, or `*` for everybody) `read` and/or `write`
+//! on the collections matching a pattern such as `orders`, `logs_*` or `*`.
+//! A principal that no entry applies to is limited by its role alone; once an
+//! entry applies, it may only touch collections some entry grants. Admins are
+//! never restricted, and only they can manage entries under `/_acl`.
+
+use actix_web::body::{EitherBody, MessageBody};
+use actix_web::dev::{ResourceDef, ServiceRequest, ServiceResponse};
+use actix_web::http::Method;
+use actix_web::middleware::{from_fn, Next};
+use actix_web::{web, Error, HttpMessage, HttpRequest, HttpResponse};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use sqlx::sqlite::SqliteRow;
+use sqlx::{Row, SqlitePool};
+
+use crate::auth::{self, buffer_body, reads_only, Principal};
+use crate::config::Role;
+use crate::handlers::route;
+use crate::logging::now;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Permission {
+    Read,
+    Write,
+}
+
+impl Permission {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Permission::Read => "read",
+            Permission::Write => "write",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct AclEntry {
+    pub id: i64,
+    pub principal: String,
+    /// Collection name; `*` matches any run of characters.
+    pub collection: String,
+    pub permissions: Vec<Permission>,
+    /// Principal that created or last changed the entry.
+    pub created_by: Option<String>,
+    pub created_at: u64,
+}
+
+impl AclEntry {
+    fn from_row(row: &SqliteRow) -> Self {
+        let permissions: String = row.get("permissions");
+        Self {
+            id: row.get("id"),
+            principal: row.get("principal"),
+            collection: row.get("collection"),
+            permissions: permissions
+                .split(',')
+                .filter_map(|p| match p {
+                    "read" => Some(Permission::Read),
+                    "write" => Some(Permission::Write),
+                    _ => None,
+                })
+                .collect(),
+            created_by: row.get("created_by"),
+            created_at: row.get::<i64, _>("created_at") as u64,
+        }
+    }
+
+    fn allows(&self, collection: &str, permission: Permission) -> bool {
+        self.permissions.contains(&permission) && glob_match(&self.collection, collection)
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct NewAclEntry {
+    pub principal: String,
+    pub collection: String,
+    pub permissions: Vec<Permission>,
+}
+
+impl NewAclEntry {
+    fn validate(&self) -> Result<(), String> {
+        if self.principal.trim().is_empty() {
+            return Err("principal must not be empty".to_string());
+        }
+        if self.collection.trim().is_empty() {
+            return Err("collection must not be empty".to_string());
+        }
+        if self.permissions.is_empty() {
+            return Err("permissions must not be empty".to_string());
+        }
+        Ok(())
+    }
+
+    fn permissions(&self) -> String {
+        let mut permissions: Vec<&str> = self.permissions.iter().map(|p| p.as_str()).collect();
+        permissions.sort_unstable();
+        permissions.dedup();
+        permissions.join(",")
+    }
+}
+
+// `*` 匹配任意长度的字符, 其余字符按原样比较
+fn glob_match(pattern: &str, name: &str) -> bool {
+    let mut parts = pattern.split('*');
+    let first = parts.next().unwrap_or_default();
+    let Some(mut rest) = name.strip_prefix(first) else {
+        return false;
+    };
+    let parts: Vec<&str> = parts.collect();
+    let Some((last, middle)) = parts.split_last() else {
+        return rest.is_empty();
+    };
+    for part in middle {
+        match rest.find(part) {
+            Some(i) => rest = &rest[i + part.len()..],
+            None => return false,
+        }
+    }
+    rest.len() >= last.len() && rest.ends_with(last)
+}
+
+/// Entries that apply to `principal`, its own and those for `*`.
+pub async fn entries_for(pool: &SqlitePool, principal: &str) -> Result<Vec<AclEntry>, sqlx::Error> {
+    let rows = sqlx::query("SELECT * FROM _acl WHERE principal = ? OR principal = '*' ORDER BY id")
+        .bind(principal)
+        .fetch_all(pool)
+        .await?;
+    Ok(rows.iter().map(AclEntry::from_row).collect())
+}
+
+/// What a caller may access, for endpoints that span collections.
+pub struct Grants {
+    /// `None` when the caller is not restricted at all.
+    entries: Option<Vec<AclEntry>>,
+}
+
+impl Grants {
+    pub fn allows(&self, collection: &str, permission: Permission) -> bool {
+        match &self.entries {
+            Some(entries) => entries.iter().any(|entry| entry.allows(collection, permission)),
+            None => true,
+        }
+    }
+}
+
+/// The grants of `principal`; admins, anonymous callers and principals no entry
+/// applies to are unrestricted.
+pub async fn grants(pool: &SqlitePool, principal: Option<&Principal>) -> Result<Grants, sqlx::Error> {
+    let Some(principal) = principal.filter(|p| p.role < Role::Admin) else {
+        return Ok(Grants { entries: None });
+    };
+    let entries = entries_for(pool, &principal.id).await?;
+    Ok(Grants { entries: Some(entries).filter(|e| !e.is_empty()) })
+}
+
+// 请求体解析为 JSON, 无法解析时为 Null, 留给处理器报错
+async fn body_json(req: &mut ServiceRequest) -> Result<Value, HttpResponse> {
+    let body = buffer_body(req).await?;
+    Ok(serde_json::from_slice(&body).unwrap_or(Value::Null))
+}
+
+/// The collections a document route names, and whether it reads or writes
+/// them.
+///
+/// The collection is the path up to the document id or the first segment
+/// starting with `_`, see [`URI`](crate::handlers::URI), and, for inserts, also the `uri`
+/// field of the body. For the multi-collection `/_mget` and `/_snapshot` they
+/// are the keys of the body, and for the writes of a transaction under
+/// `/_txn` its `uri` field.
+pub(crate) async fn requested(req: &mut ServiceRequest) -> Result<(Permission, Vec<String>), HttpResponse> {
+    let permission = if reads_only(req) { Permission::Read } else { Permission::Write };
+    let mut collections = Vec::new();
+    if req.path() == "/_mget" || req.path() == "/_snapshot" {
+        // 多集合读取: 集合名是请求体的键
+        let body = body_json(req).await?;
+        collections.extend(body.as_object().into_iter().flat_map(|o| o.keys().cloned()));
+    } else if req.path() == "/_txn" || req.path().starts_with("/_txn/") {
+        // 事务的写入在请求体中带有集合名, 开始和结束事务不涉及集合
+        let body = body_json(req).await?;
+        collections.extend(body.get("uri").and_then(Value::as_str).map(str::to_string));
+    } else {
+        // 中间件在路由匹配之前执行, 自行从路径中取集合名
+        let mut path = req.match_info().clone();
+        if ResourceDef::prefix(route("/{uri}")).capture_match_info(&mut path) {
+            collections.extend(path.get("uri").map(str::to_string));
+        }
+        // 插入请求的请求体中也带有集合名 (uri 字段)
+        if *req.method() == Method::POST && permission == Permission::Write {
+            let body = body_json(req).await?;
+            collections.extend(body.get("uri").and_then(Value::as_str).map(str::to_string));
+        }
+    }
+    Ok((permission, collections))
+}
+
+/// Checks the caller's ACL entries for the collection of a document route.
+///
+/// Every collection the route names, see [`requested`], has to be granted.
+pub async fn enforce(
+    mut req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<EitherBody<impl MessageBody>>, Error> {
+    let principal = req.extensions().get::<Principal>().cloned();
+    let Some(pool) = req.app_data::<web::Data<SqlitePool>>().cloned() else {
+        return next.call(req).await.map(ServiceResponse::map_into_left_body);
+    };
+    let grants = match grants(&pool, principal.as_ref()).await {
+        Ok(grants) if grants.entries.is_some() => grants,
+        Ok(_) => return next.call(req).await.map(ServiceResponse::map_into_left_body),
+        Err(e) => {
+            let response = HttpResponse::InternalServerError().json(format!("Failed to read ACL: {}", e));
+            return Ok(req.into_response(response).map_into_right_body());
+        }
+    };
+
+    let (permission, collections) = match requested(&mut req).await {
+        Ok(requested) => requested,
+        Err(response) => return Ok(req.into_response(response).map_into_right_body()),
+    };
+    if let Some(collection) = collections.iter().find(|c| !grants.allows(c, permission)) {
+        let id = principal.map(|p| p.id).unwrap_or_default();
+        log::info!("ACL denied {} {} on '{}'", id, permission.as_str(), collection);
+        let response = HttpResponse::Forbidden().json(format!(
+            "No {} access to collection '{}'",
+            permission.as_str(),
+            collection
+        ));
+        return Ok(req.into_response(response).map_into_right_body());
+    }
+    next.call(req).await.map(ServiceResponse::map_into_left_body)
+}
+
+// 注册 ACL 管理接口, 需要 admin 角色
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(
+        web::scope("/_acl")
+            .wrap(from_fn(auth::require_admin))
+            .route("", web::get().to(list_entries))
+            .route("", web::post().to(create_entry))
+            .route("/{id}", web::get().to(get_entry))
+            .route("/{id}", web::put().to(update_entry))
+            .route("/{id}", web::delete().to(delete_entry)),
+    );
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ListParams {
+    pub principal: Option<String>,
+}
+
+// 列出 ACL 条目, 可按 principal 过滤
+pub async fn list_entries(params: web::Query<ListParams>, pool: web::Data<SqlitePool>) -> HttpResponse {
+    let rows = match &params.principal {
+        Some(principal) => {
+            sqlx::query("SELECT * FROM _acl WHERE principal = ? ORDER BY id")
+                .bind(principal)
+                .fetch_all(&**pool)
+                .await
+        }
+        None => sqlx::query("SELECT * FROM _acl ORDER BY id").fetch_all(&**pool).await,
+    };
+    match rows {
+        Ok(rows) => HttpResponse::Ok().json(rows.iter().map(AclEntry::from_row).collect::<Vec<_>>()),
+        Err(e) => HttpResponse::InternalServerError().json(format!("Failed to list ACL entries: {}", e)),
+    }
+}
+
+pub async fn get_entry(id: web::Path<i64>, pool: web::Data<SqlitePool>) -> HttpResponse {
+    match fetch_entry(&pool, *id).await {
+        Ok(Some(entry)) => HttpResponse::Ok().json(entry),
+        Ok(None) => HttpResponse::NotFound().json(format!("No ACL entry with id {}", id)),
+        Err(e) => HttpResponse::InternalServerError().json(format!("Failed to read ACL entry: {}", e)),
+    }
+}
+
+pub async fn create_entry(
+    req: HttpRequest,
+    entry: web::Json<NewAclEntry>,
+    pool: web::Data<SqlitePool>,
+) -> HttpResponse {
+    if let Err(e) = entry.validate() {
+        return HttpResponse::BadRequest().json(format!("Invalid ACL entry: {}", e));
+    }
+    let actor = actor(&req);
+    let result = sqlx::query(
+        "INSERT INTO _acl (principal, collection, permissions, created_by, created_at) VALUES (?, ?, ?, ?, ?)",
+    )
+    .bind(&entry.principal)
+    .bind(&entry.collection)
+    .bind(entry.permissions())
+    .bind(&actor)
+    .bind(now() as i64)
+    .execute(&**pool)
+    .await;
+
+    let id = match result {
+        Ok(result) => result.last_insert_rowid(),
+        Err(e) => return HttpResponse::InternalServerError().json(format!("Failed to create ACL entry: {}", e)),
+    };
+    log::info!(
+        "ACL entry {} created by {}: {} {} on '{}'",
+        id,
+        actor.as_deref().unwrap_or("-"),
+        entry.principal,
+        entry.permissions(),
+        entry.collection
+    );
+    match fetch_entry(&pool, id).await {
+        Ok(Some(entry)) => HttpResponse::Created().json(entry),
+        Ok(None) => HttpResponse::NotFound().json(format!("No ACL entry with id {}", id)),
+        Err(e) => HttpResponse::InternalServerError().json(format!("Failed to read ACL entry: {}", e)),
+    }
+}
+
+pub async fn update_entry(
+    req: HttpRequest,
+    id: web::Path<i64>,
+    entry: web::Json<NewAclEntry>,
+    pool: web::Data<SqlitePool>,
+) -> HttpResponse {
+    if let Err(e) = entry.validate() {
+        return HttpResponse::BadRequest().json(format!("Invalid ACL entry: {}", e));
+    }
+    let actor = actor(&req);
+    let result = sqlx::query(
+        "UPDATE _acl SET principal = ?, collection = ?, permissions = ?, created_by = ?, created_at = ? WHERE id = ?",
+    )
+    .bind(&entry.principal)
+    .bind(&entry.collection)
+    .bind(entry.permissions())
+    .bind(&actor)
+    .bind(now() as i64)
+    .bind(*id)
+    .execute(&**pool)
+    .await;
+
+    match result {
+        Ok(r) if r.rows_affected() == 0 => return HttpResponse::NotFound().json(format!("No ACL entry with id {}", id)),
+        Ok(_) => {}
+        Err(e) => return HttpResponse::InternalServerError().json(format!("Failed to update ACL entry: {}", e)),
+    }
+    log::info!(
+        "ACL entry {} updated by {}: {} {} on '{}'",
+        id,
+        actor.as_deref().unwrap_or("-"),
+        entry.principal,
+        entry.permissions(),
+        entry.collection
+    );
+    match fetch_entry(&pool, *id).await {
+        Ok(Some(entry)) => HttpResponse::Ok().json(entry),
+        Ok(None) => HttpResponse::NotFound().json(format!("No ACL entry with id {}", id)),
+        Err(e) => HttpResponse::InternalServerError().json(format!("Failed to read ACL entry: {}", e)),
+    }
+}
+
+pub async fn delete_entry(req: HttpRequest, id: web::Path<i64>, pool: web::Data<SqlitePool>) -> HttpResponse {
+    let result = sqlx::query("DELETE FROM _acl WHERE id = ?").bind(*id).execute(&**pool).await;
+
+    match result {
+        Ok(r) if r.rows_affected() == 0 => HttpResponse::NotFound().json(format!("No ACL entry with id {}", id)),
+        Ok(_) => {
+            log::info!("ACL entry {} deleted by {}", id, actor(&req).as_deref().unwrap_or("-"));
+            HttpResponse::NoContent().finish()
+        }
+        Err(e) => HttpResponse::InternalServerError().json(format!("Failed to delete ACL entry: {}", e)),
+    }
+}
+
+async fn fetch_entry(pool: &SqlitePool, id: i64) -> Result<Option<AclEntry>, sqlx::Error> {
+    let row = sqlx::query("SELECT * FROM _acl WHERE id = ?").bind(id).fetch_optional(pool).await?;
+    Ok(row.as_ref().map(AclEntry::from_row))
+}
+
+// 执行修改的调用方, 记录在条目上以便审计
+fn actor(req: &HttpRequest) -> Option<String> {
+    req.extensions().get::<Principal>().map(|p| p.id.clone())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::TestRequest;
+    use serde_json::json;
+
+    #[test]
+    fn glob_match_treats_star_as_any_run_of_characters() {
+        for (pattern, name, matches) in [
+            ("orders", "orders", true),
+            ("orders", "orders2", false),
+            ("orders", "order", false),
+            ("logs_*", "logs_2024", true),
+            ("logs_*", "logs_", true),
+            ("logs_*", "log", false),
+            ("*_archive", "2024_archive", true),
+            ("*_archive", "2024_archive_old", false),
+            ("*", "anything/at/all", true),
+            ("*", "", true),
+            ("**", "x", true),
+            ("a*b*c", "abc", true),
+            ("a*b*c", "a1b2c", true),
+            ("a*b*c", "acb", false),
+            ("a*a", "a", false),
+            ("a*a", "aa", true),
+            ("api/*/users", "api/v1/users", true),
+            ("api/*/users", "api/v1/orders", false),
+            ("", "", true),
+            ("", "x", false),
+            ("Orders", "orders", false),
+        ] {
+            assert_eq!(glob_match(pattern, name), matches, "{} against {}", pattern, name);
+        }
+    }
+
+    #[actix_web::test]
+    async fn requested_names_the_collections_of_each_route() {
+        let post = |uri: &str, body: Value| TestRequest::post().uri(uri).set_json(body);
+        for (req, permission, collections) in [
+            (TestRequest::get().uri("/api/v1/users/7/_history"), Permission::Read, vec!["api/v1/users"]),
+            (TestRequest::delete().uri("/orders/7"), Permission::Write, vec!["orders"]),
+            (post("/orders/_find", json!({ "filter": {} })), Permission::Read, vec!["orders"]),
+            // 插入时请求体的 uri 也要有权限
+            (post("/orders", json!({ "uri": "secrets", "data": {} })), Permission::Write, vec!["orders", "secrets"]),
+            (post("/orders", json!([1])), Permission::Write, vec!["orders"]),
+            (post("/_mget", json!({ "orders": [1], "users": [2] })), Permission::Read, vec!["orders", "users"]),
+            (post("/_snapshot", json!({ "logs_2024": {} })), Permission::Read, vec!["logs_2024"]),
+            (post("/_txn", json!({})), Permission::Write, vec![]),
+            (post("/_txn/abc", json!({ "op": "insert", "uri": "orders", "data": {} })), Permission::Write, vec!["orders"]),
+            (TestRequest::delete().uri("/_txn/abc"), Permission::Write, vec![]),
+        ] {
+            let mut req = req.to_srv_request();
+            let path = req.path().to_string();
+            let (found, names) = requested(&mut req).await.unwrap();
+            assert_eq!((found, names), (permission, collections.iter().map(|c| c.to_string()).collect()), "{}", path);
+        }
+    }
+
+    #[actix_web::test]
+    async fn requested_leaves_the_body_for_the_handler() {
+        let body = json!({ "uri": "orders", "data": { "name": "a" } });
+        let mut req = TestRequest::post().uri("/orders").set_json(&body).to_srv_request();
+        requested(&mut req).await.unwrap();
+        let read = req.extract::<web::Json<Value>>().await.unwrap();
+        assert_eq!(read.into_inner(), body);
+        // 不是 JSON 的请求体留给处理器报错
+        let mut req = TestRequest::post().uri("/_mget").set_payload("not json").to_srv_request();
+        assert_eq!(requested(&mut req).await.unwrap(), (Permission::Read, Vec::new()));
+    }
+}
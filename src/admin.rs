@@ -0,0 +1,327 @@
+use actix_web::middleware::from_fn;
+use actix_web::{web, HttpResponse};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use sqlx::{Row, SqlitePool};
+use std::time::Instant;
+
+use crate::access_log::annotate;
+use crate::alerts;
+use crate::analytics;
+use crate::auth;
+use crate::config::ConfigHandle;
+use crate::database::{collection_exists, count_rows, encode_value, list_collections, row_to_json, table_columns};
+use crate::integrity;
+use crate::partition::Partitions;
+use crate::query::{parse_filter, quote};
+use crate::schedule;
+use crate::shard::Shards;
+use crate::telemetry::db_span;
+
+const UI_HTML: &str = include_str!("ui/index.html");
+const CONSOLE_HTML: &str = include_str!("ui/console.html");
+const COMMON_JS: &str = include_str!("ui/common.js");
+const COMMON_CSS: &str = include_str!("ui/common.css");
+
+const DEFAULT_PAGE_SIZE: i64 = 20;
+const MAX_PAGE_SIZE: i64 = 500;
+
+// 注册管理接口; 页面本身公开, /_admin 下的接口需要 admin 角色
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.route("/_ui", web::get().to(ui))
+        .route("/_ui/console", web::get().to(console_ui))
+        .route("/_ui/common.js", web::get().to(common_js))
+        .route("/_ui/common.css", web::get().to(common_css))
+        .service(
+            web::scope("/_admin")
+                .wrap(from_fn(auth::require_admin))
+                .route("/reload", web::post().to(reload_config))
+                .route("/verify", web::post().to(integrity::verify))
+                .route("/collections", web::get().to(collections))
+                .route("/collections/{name}", web::get().to(collection_stats))
+                .route("/collections/{name}/documents", web::get().to(browse_documents))
+                .route("/console", web::post().to(run_console_query))
+                .route("/queries", web::get().to(list_saved_queries))
+                .route("/queries", web::post().to(save_query))
+                .route("/queries/{name}", web::delete().to(delete_saved_query))
+                .route("/alerts", web::get().to(alerts::list_alerts))
+                .route("/usage", web::get().to(analytics::report))
+                .route("/schedules", web::get().to(schedule::list_schedules))
+                .route("/schedules/{name}/runs", web::get().to(schedule::list_runs))
+                .route("/schedules/{name}/run", web::post().to(schedule::run_now)),
+        );
+}
+
+// 管理界面
+pub async fn ui() -> HttpResponse {
+    HttpResponse::Ok().content_type("text/html; charset=utf-8").body(UI_HTML)
+}
+
+pub async fn console_ui() -> HttpResponse {
+    HttpResponse::Ok().content_type("text/html; charset=utf-8").body(CONSOLE_HTML)
+}
+
+pub async fn common_js() -> HttpResponse {
+    HttpResponse::Ok().content_type("text/javascript; charset=utf-8").body(COMMON_JS)
+}
+
+pub async fn common_css() -> HttpResponse {
+    HttpResponse::Ok().content_type("text/css; charset=utf-8").body(COMMON_CSS)
+}
+
+// 重新加载配置文件
+pub async fn reload_config(config: web::Data<ConfigHandle>) -> HttpResponse {
+    match config.reload() {
+        Ok(config) => {
+            log::info!("reloaded config via /_admin/reload");
+            HttpResponse::Ok().json(&*config)
+        }
+        Err(e) => HttpResponse::BadRequest().json(e.to_string()),
+    }
+}
+
+// 所有集合及其文档数
+pub async fn collections(pool: web::Data<SqlitePool>) -> HttpResponse {
+    let names = match list_collections(&pool).await {
+        Ok(names) => names,
+        Err(e) => return HttpResponse::InternalServerError().json(format!("Failed to list collections: {}", e)),
+    };
+
+    let mut result = Vec::new();
+    for name in names {
+        let documents = count_rows(&pool, &name).await.unwrap_or_default();
+        let columns = table_columns(&pool, &name).await.map(|c| c.len()).unwrap_or_default();
+        result.push(json!({ "name": name, "documents": documents, "columns": columns }));
+    }
+    HttpResponse::Ok().json(result)
+}
+
+// 单个集合的统计信息
+pub async fn collection_stats(name: web::Path<String>, pool: web::Data<SqlitePool>) -> HttpResponse {
+    if let Some(response) = ensure_collection(&pool, &name).await {
+        return response;
+    }
+
+    let documents = count_rows(&pool, &name).await;
+    let columns = table_columns(&pool, &name).await;
+    match (documents, columns) {
+        (Ok(documents), Ok(columns)) => {
+            let columns: Vec<Value> = columns
+                .into_iter()
+                .map(|(name, ty)| json!({ "name": name, "type": ty }))
+                .collect();
+            HttpResponse::Ok().json(json!({ "name": &*name, "documents": documents, "columns": columns }))
+        }
+        (Err(e), _) | (_, Err(e)) => {
+            HttpResponse::InternalServerError().json(format!("Failed to read collection: {}", e))
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct PageParams {
+    pub limit: Option<i64>,
+    pub offset: Option<i64>,
+}
+
+// 分页浏览集合中的文档
+pub async fn browse_documents(
+    name: web::Path<String>,
+    params: web::Query<PageParams>,
+    pool: web::Data<SqlitePool>,
+) -> HttpResponse {
+    if let Some(response) = ensure_collection(&pool, &name).await {
+        return response;
+    }
+
+    let limit = params.limit.unwrap_or(DEFAULT_PAGE_SIZE).clamp(1, MAX_PAGE_SIZE);
+    let offset = params.offset.unwrap_or(0).max(0);
+
+    let total = match count_rows(&pool, &name).await {
+        Ok(total) => total,
+        Err(e) => return HttpResponse::InternalServerError().json(format!("Failed to query data: {}", e)),
+    };
+    let rows = sqlx::query(&format!("SELECT * FROM {} ORDER BY rowid LIMIT ? OFFSET ?", quote(&name)))
+        .bind(limit)
+        .bind(offset)
+        .fetch_all(&**pool)
+        .await;
+
+    match rows {
+        Ok(rows) => {
+            let documents: Vec<Value> = rows.iter().map(row_to_json).collect();
+            let count = documents.len();
+            let response = HttpResponse::Ok().json(json!({
+                "total": total,
+                "limit": limit,
+                "offset": offset,
+                "documents": documents,
+            }));
+            annotate(response, &name, count)
+        }
+        Err(e) => HttpResponse::InternalServerError().json(format!("Failed to query data: {}", e)),
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ConsoleQuery {
+    pub collection: String,
+    #[serde(default)]
+    pub filter: Value,
+    pub limit: Option<i64>,
+}
+
+// 控制台: 执行过滤查询并返回生成的 SQL; 分片或分区的集合查询每个相关的表后按 id 合并
+pub async fn run_console_query(
+    query: web::Json<ConsoleQuery>,
+    pool: web::Data<SqlitePool>,
+    shards: web::Data<Shards>,
+    partitions: web::Data<Partitions>,
+) -> HttpResponse {
+    let query = query.into_inner();
+    let filter = match parse_filter(&query.filter) {
+        Ok(filter) => filter,
+        Err(e) => return HttpResponse::BadRequest().json(format!("Invalid filter: {}", e)),
+    };
+
+    // (连接池, 表名): 先列出所有表检查列, 再只查询过滤条件涉及的表
+    type Targets = Vec<(SqlitePool, String)>;
+    let (all, targets): (Targets, Targets) =
+        if let Some(collection) = shards.get(&query.collection) {
+            let targets: Vec<_> = collection.pools().into_iter().map(|p| (p, query.collection.clone())).collect();
+            (targets.clone(), targets)
+        } else if let Some(tables) = partitions.tables(&query.collection, None).await {
+            let selected = partitions.tables(&query.collection, Some(&filter)).await.unwrap_or_default();
+            let with_pool = |tables: Vec<String>| tables.into_iter().map(|t| (pool.get_ref().clone(), t)).collect();
+            (with_pool(tables), with_pool(selected))
+        } else {
+            let target = vec![(pool.get_ref().clone(), query.collection.clone())];
+            (target.clone(), target)
+        };
+    let Some((schema_pool, schema_table)) = all.last() else {
+        return HttpResponse::NotFound().json(format!("No collection '{}'", query.collection));
+    };
+    if let Some(response) = ensure_collection(schema_pool, schema_table).await {
+        return response;
+    }
+    let columns: Vec<String> = match table_columns(schema_pool, schema_table).await {
+        Ok(columns) => columns.into_iter().map(|(name, _)| name).collect(),
+        Err(e) => return HttpResponse::InternalServerError().json(format!("Failed to read collection: {}", e)),
+    };
+    if let Err(e) = filter.validate(&columns) {
+        return HttpResponse::BadRequest().json(format!("Invalid filter: {}", e));
+    }
+
+    let limit = query.limit.unwrap_or(DEFAULT_PAGE_SIZE).clamp(1, MAX_PAGE_SIZE);
+    let mut params = Vec::new();
+    let condition = filter.to_sql(&mut params);
+    let select = |table: &str| format!("SELECT * FROM {} WHERE {} LIMIT {}", quote(table), condition, limit);
+    let sql = select(&query.collection);
+
+    let started = Instant::now();
+    let mut span = db_span(&sql, &query.collection);
+    let mut documents: Vec<Value> = Vec::new();
+    for (pool, table) in &targets {
+        let table_sql = select(table);
+        let mut statement = sqlx::query(&table_sql);
+        for param in &params {
+            statement = statement.bind(encode_value(param));
+        }
+        match statement.fetch_all(pool).await {
+            Ok(rows) => documents.extend(rows.iter().map(row_to_json)),
+            Err(e) => {
+                span.error(&e);
+                return HttpResponse::BadRequest().json(json!({ "sql": table_sql, "error": e.to_string() }));
+            }
+        }
+    }
+    if targets.len() > 1 {
+        documents.sort_by_key(|doc| doc.get("id").and_then(Value::as_i64));
+        documents.truncate(limit as usize);
+    }
+    span.set_i64("db.response.returned_rows", documents.len() as i64);
+    let bound: Vec<String> = params.iter().map(encode_value).collect();
+    let count = documents.len();
+    let tables: Vec<&str> = targets.iter().map(|(_, table)| table.as_str()).collect();
+    let response = HttpResponse::Ok().json(json!({
+        "sql": sql,
+        "params": bound,
+        "tables": tables,
+        "took_ms": started.elapsed().as_secs_f64() * 1000.0,
+        "count": count,
+        "documents": documents,
+    }));
+    annotate(response, &query.collection, count)
+}
+
+/// A named query that the admin UI can re-run.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SavedQuery {
+    pub name: String,
+    pub collection: String,
+    /// Query options: `limit`, `offset` and, for console queries, `filter`.
+    #[serde(default)]
+    pub query: Value,
+}
+
+pub async fn list_saved_queries(pool: web::Data<SqlitePool>) -> HttpResponse {
+    let rows = sqlx::query("SELECT name, collection, query FROM _saved_queries ORDER BY name")
+        .fetch_all(&**pool)
+        .await;
+
+    match rows {
+        Ok(rows) => {
+            let queries: Vec<SavedQuery> = rows
+                .iter()
+                .map(|row| SavedQuery {
+                    name: row.get("name"),
+                    collection: row.get("collection"),
+                    query: serde_json::from_str(row.get("query")).unwrap_or(Value::Null),
+                })
+                .collect();
+            HttpResponse::Ok().json(queries)
+        }
+        Err(e) => HttpResponse::InternalServerError().json(format!("Failed to list queries: {}", e)),
+    }
+}
+
+pub async fn save_query(query: web::Json<SavedQuery>, pool: web::Data<SqlitePool>) -> HttpResponse {
+    let query = query.into_inner();
+    if query.name.trim().is_empty() {
+        return HttpResponse::BadRequest().json("Query name must not be empty");
+    }
+
+    let result = sqlx::query("INSERT OR REPLACE INTO _saved_queries (name, collection, query) VALUES (?, ?, ?)")
+        .bind(&query.name)
+        .bind(&query.collection)
+        .bind(query.query.to_string())
+        .execute(&**pool)
+        .await;
+
+    match result {
+        Ok(_) => HttpResponse::Ok().json(query),
+        Err(e) => HttpResponse::InternalServerError().json(format!("Failed to save query: {}", e)),
+    }
+}
+
+pub async fn delete_saved_query(name: web::Path<String>, pool: web::Data<SqlitePool>) -> HttpResponse {
+    let result = sqlx::query("DELETE FROM _saved_queries WHERE name = ?")
+        .bind(&*name)
+        .execute(&**pool)
+        .await;
+
+    match result {
+        Ok(r) if r.rows_affected() == 0 => HttpResponse::NotFound().json(format!("No saved query '{}'", name)),
+        Ok(_) => HttpResponse::NoContent().finish(),
+        Err(e) => HttpResponse::InternalServerError().json(format!("Failed to delete query: {}", e)),
+    }
+}
+
+// 集合名会被拼进 SQL, 只允许已存在的表
+async fn ensure_collection(pool: &SqlitePool, name: &str) -> Option<HttpResponse> {
+    match collection_exists(pool, name).await {
+        Ok(true) => None,
+        Ok(false) => Some(HttpResponse::NotFound().json(format!("No collection '{}'", name))),
+        Err(e) => Some(HttpResponse::InternalServerError().json(format!("Failed to read collection: {}", e))),
+    }
+}
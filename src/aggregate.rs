@@ -0,0 +1,373 @@
+//! Aggregation pipelines.
+//!
+//! `POST /{uri}/_aggregate` runs a list of stages in the manner of MongoDB's
+//! aggregation framework, compiled to one SQL query in which every stage
+//! reads the rows of the one before it:
+//!
+//! | Stage      | Example                                                   | SQL                |
+//! |------------|-----------------------------------------------------------|--------------------|
+//! | `$match`   | `{"$match": {"status": "paid"}}`                          | `WHERE`            |
+//! | `$group`   | `{"$group": {"_id": "$city", "total": {"$sum": "$amount"}}}` | `GROUP BY`, aggregates |
+//! | `$sort`    | `{"$sort": {"total": -1}}`                                | `ORDER BY`         |
+//! | `$project` | `{"$project": {"city": 1, "total": 1}}`                   | column list        |
+//! | `$skip`    | `{"$skip": 20}`                                           | `OFFSET`           |
+//! | `$limit`   | `{"$limit": 10}`                                          | `LIMIT`            |
+//! | `$count`   | `{"$count": "orders"}`                                    | `COUNT(*)`         |
+//!
+//! `$match` takes the filter documents of [`query`](crate::query), with the
+//! values bound as parameters. A group's `_id` is `null`, a `"$field"` or an
+//! object of them; the fields of an object `_id` are named `_id.field` in
+//! later stages. Accumulators are `$sum` (of a field or a number, so
+//! `{"$sum": 1}` counts), `$avg`, `$min`, `$max` and `$count`. Every field a
+//! stage names is checked against the fields the stage before it produces.
+
+use actix_web::{web, HttpResponse};
+use serde_json::{Map, Value};
+use sqlx::SqlitePool;
+
+use crate::database::{bind_value, collection_tables, row_to_json, table_columns, table_name};
+use crate::handlers::route;
+use crate::odata::identifier;
+use crate::partition::Partitions;
+use crate::query::{parse_filter, quote, QueryError};
+use crate::shard::Shards;
+use crate::telemetry::db_span;
+
+// 注册集合下的聚合接口, 与其他文档接口一样检查 ACL
+pub fn configure_documents(cfg: &mut web::ServiceConfig) {
+    cfg.route(&route("/{uri}/_aggregate"), web::post().to(aggregate_collection));
+}
+
+// 按聚合管道查询集合
+async fn aggregate_collection(
+    uri: web::Path<String>,
+    pipeline: web::Json<Vec<Value>>,
+    pool: web::Data<SqlitePool>,
+    shards: web::Data<Shards>,
+    partitions: web::Data<Partitions>,
+) -> HttpResponse {
+    if shards.get(&table_name(&uri)).is_some() {
+        return HttpResponse::BadRequest().json(format!("Sharded collection '{}' cannot be aggregated", uri));
+    }
+    let tables: Vec<String> = match collection_tables(&pool, &shards, &partitions, &uri).await {
+        Ok(tables) if tables.is_empty() => return HttpResponse::NotFound().json(format!("No collection '{}'", uri)),
+        Ok(tables) => tables.into_iter().map(|(_, table)| table).collect(),
+        Err(e) => return HttpResponse::InternalServerError().json(format!("Failed to read collections: {}", e)),
+    };
+
+    match aggregate(&pool, &uri, &tables, &pipeline).await {
+        Ok(docs) => HttpResponse::Ok().json(docs),
+        Err(AggregateError::Invalid(e)) => HttpResponse::BadRequest().json(format!("Invalid pipeline: {}", e)),
+        Err(AggregateError::Database(e)) => HttpResponse::InternalServerError().json(format!("Failed to aggregate: {}", e)),
+    }
+}
+
+/// Why a pipeline was not run.
+#[derive(Debug)]
+pub enum AggregateError {
+    Invalid(QueryError),
+    Database(sqlx::Error),
+}
+
+impl From<QueryError> for AggregateError {
+    fn from(e: QueryError) -> Self {
+        AggregateError::Invalid(e)
+    }
+}
+
+impl From<sqlx::Error> for AggregateError {
+    fn from(e: sqlx::Error) -> Self {
+        AggregateError::Database(e)
+    }
+}
+
+fn invalid<T>(msg: impl Into<String>) -> Result<T, QueryError> {
+    Err(QueryError(msg.into()))
+}
+
+/// Runs `pipeline` over the collection `uri` stored in `tables`.
+pub async fn aggregate(pool: &SqlitePool, uri: &str, tables: &[String], pipeline: &[Value]) -> Result<Vec<Value>, AggregateError> {
+    // 各分区的列可能不同, 取所有列的并集, 没有该列的分区按 NULL 读出
+    let mut columns: Vec<String> = Vec::new();
+    let mut table_fields = Vec::new();
+    for table in tables {
+        let fields: Vec<String> = table_columns(pool, table).await?.into_iter().map(|(name, _)| name).collect();
+        for name in &fields {
+            if !columns.contains(name) {
+                columns.push(name.clone());
+            }
+        }
+        table_fields.push((table, fields));
+    }
+    let source: Vec<String> = table_fields
+        .iter()
+        .map(|(table, fields)| {
+            let list: Vec<String> = columns
+                .iter()
+                .map(|column| match fields.contains(column) {
+                    true => quote(column),
+                    false => format!("NULL AS {}", quote(column)),
+                })
+                .collect();
+            format!("SELECT {} FROM {}", list.join(", "), quote(table))
+        })
+        .collect();
+
+    let mut query = Query { sql: source.join(" UNION ALL "), params: Vec::new(), columns, order: Vec::new() };
+    for stage in pipeline {
+        query.stage(stage)?;
+    }
+
+    let mut span = db_span(&query.sql, &table_name(uri));
+    let mut statement = sqlx::query(&query.sql);
+    for param in &query.params {
+        statement = bind_value(statement, Some(param));
+    }
+    let rows = statement.fetch_all(pool).await.inspect_err(|e| span.error(e))?;
+    span.set_i64("db.response.returned_rows", rows.len() as i64);
+    Ok(rows.iter().map(|row| nest(row_to_json(row))).collect())
+}
+
+/// The SQL of the stages compiled so far.
+struct Query {
+    sql: String,
+    /// Values bound to the `?` of `sql`, in order.
+    params: Vec<Value>,
+    /// Columns the query returns.
+    columns: Vec<String>,
+    /// `ORDER BY` terms of the last `$sort`, repeated by each later stage
+    /// that reads the rows, while the rows keep its fields.
+    order: Vec<String>,
+}
+
+impl Query {
+    fn stage(&mut self, stage: &Value) -> Result<(), QueryError> {
+        let Some((name, spec)) = stage.as_object().filter(|stage| stage.len() == 1).and_then(|stage| stage.iter().next()) else {
+            return invalid("each stage must be an object with one operator");
+        };
+        match name.as_str() {
+            "$match" => self.matching(spec),
+            "$group" => self.group(spec),
+            "$sort" => self.sort(spec),
+            "$project" => self.project(spec),
+            "$skip" => self.window("OFFSET", spec, name),
+            "$limit" => self.window("LIMIT", spec, name),
+            "$count" => self.count(spec),
+            other => invalid(format!("unknown stage '{}'", other)),
+        }
+    }
+
+    // 检查字段是当前阶段的列, 返回列名
+    fn column(&self, field: &str) -> Result<String, QueryError> {
+        let column = column_name(field);
+        match self.columns.contains(&column) {
+            true => Ok(column),
+            false => invalid(format!("unknown field '{}'", field)),
+        }
+    }
+
+    // "$field" 形式的字段引用, 返回加引号的列名
+    fn reference(&self, value: &Value, context: &str) -> Result<String, QueryError> {
+        match value.as_str().and_then(|value| value.strip_prefix('$')) {
+            Some(field) => self.column(field).map(|column| quote(&column)),
+            None => invalid(format!("{} expects a field reference such as \"$amount\"", context)),
+        }
+    }
+
+    fn order_by(&self) -> String {
+        match self.order.is_empty() {
+            true => String::new(),
+            false => format!(" ORDER BY {}", self.order.join(", ")),
+        }
+    }
+
+    fn matching(&mut self, spec: &Value) -> Result<(), QueryError> {
+        let filter = parse_filter(&rename_fields(spec))?;
+        filter.validate(&self.columns)?;
+        let mut params = Vec::new();
+        let condition = filter.to_sql(&mut params);
+        self.sql = format!("SELECT * FROM ({}) WHERE {}{}", self.sql, condition, self.order_by());
+        self.params.extend(params);
+        Ok(())
+    }
+
+    fn group(&mut self, spec: &Value) -> Result<(), QueryError> {
+        let Some(spec) = spec.as_object() else {
+            return invalid("'$group' expects an object");
+        };
+        let Some(id) = spec.get("_id") else {
+            return invalid("'$group' needs an '_id'");
+        };
+        let mut keys = Vec::new();
+        let mut outputs = Vec::new();
+        match id {
+            Value::Null => outputs.push("NULL AS _id".to_string()),
+            Value::Object(fields) => {
+                for (name, value) in fields {
+                    if !identifier(name) || name.contains("__") {
+                        return invalid(format!("invalid group key name '{}'", name));
+                    }
+                    let column = self.reference(value, "a group key")?;
+                    outputs.push(format!("{} AS _id__{}", column, name));
+                    keys.push(column);
+                }
+            }
+            value => {
+                let column = self.reference(value, "'_id'")?;
+                outputs.push(format!("{} AS _id", column));
+                keys.push(column);
+            }
+        }
+        let mut columns: Vec<String> = match id {
+            Value::Object(fields) => fields.keys().map(|name| format!("_id__{}", name)).collect(),
+            _ => vec!["_id".to_string()],
+        };
+
+        for (name, accumulator) in spec.iter().filter(|(name, _)| *name != "_id") {
+            if !identifier(name) || name.contains("__") {
+                return invalid(format!("invalid output field name '{}'", name));
+            }
+            let Some((op, operand)) = accumulator.as_object().filter(|a| a.len() == 1).and_then(|a| a.iter().next()) else {
+                return invalid(format!("'{}' must be an object with one accumulator", name));
+            };
+            let expression = match (op.as_str(), operand) {
+                ("$sum", Value::Number(n)) => format!("COALESCE(SUM({}), 0)", n),
+                ("$count", Value::Object(o)) if o.is_empty() => "COUNT(*)".to_string(),
+                ("$count", _) => return invalid("'$count' expects {}"),
+                ("$sum", operand) => format!("COALESCE(SUM({}), 0)", self.reference(operand, "'$sum'")?),
+                ("$avg" | "$min" | "$max", operand) => {
+                    let function = op[1..].to_uppercase();
+                    format!("{}({})", function, self.reference(operand, &format!("'{}'", op))?)
+                }
+                (other, _) => return invalid(format!("unknown accumulator '{}'", other)),
+            };
+            outputs.push(format!("{} AS {}", expression, name));
+            columns.push(name.clone());
+        }
+
+        let group_by = match keys.is_empty() {
+            true => String::new(),
+            false => format!(" GROUP BY {}", keys.join(", ")),
+        };
+        self.sql = format!("SELECT {} FROM ({}){}", outputs.join(", "), self.sql, group_by);
+        self.columns = columns;
+        self.order.clear();
+        Ok(())
+    }
+
+    fn sort(&mut self, spec: &Value) -> Result<(), QueryError> {
+        let Some(fields) = spec.as_object().filter(|fields| !fields.is_empty()) else {
+            return invalid("'$sort' expects an object of fields");
+        };
+        let mut order = Vec::new();
+        for (field, direction) in fields {
+            let column = self.column(field)?;
+            match direction.as_i64() {
+                Some(1) => order.push(quote(&column)),
+                Some(-1) => order.push(format!("{} DESC", quote(&column))),
+                _ => return invalid(format!("sort direction of '{}' must be 1 or -1", field)),
+            }
+        }
+        self.order = order;
+        self.sql = format!("SELECT * FROM ({}){}", self.sql, self.order_by());
+        Ok(())
+    }
+
+    fn project(&mut self, spec: &Value) -> Result<(), QueryError> {
+        let Some(fields) = spec.as_object().filter(|fields| !fields.is_empty()) else {
+            return invalid("'$project' expects an object of fields");
+        };
+        let mut included = Vec::new();
+        let mut excluded = Vec::new();
+        for (field, flag) in fields {
+            let column = self.column(field)?;
+            match flag {
+                Value::Bool(true) => included.push(column),
+                Value::Number(n) if n.as_i64() == Some(1) => included.push(column),
+                Value::Bool(false) => excluded.push(column),
+                Value::Number(n) if n.as_i64() == Some(0) => excluded.push(column),
+                _ => return invalid(format!("'$project' of '{}' must be 1 or 0", field)),
+            }
+        }
+        let columns: Vec<String> = match (included.is_empty(), excluded.is_empty()) {
+            (false, true) => self.columns.iter().filter(|c| included.contains(c)).cloned().collect(),
+            (true, false) => self.columns.iter().filter(|c| !excluded.contains(c)).cloned().collect(),
+            _ => return invalid("'$project' cannot both include and exclude fields"),
+        };
+        if columns.is_empty() {
+            return invalid("'$project' leaves no fields");
+        }
+        let list: Vec<String> = columns.iter().map(|column| quote(column)).collect();
+        self.sql = format!("SELECT {} FROM ({}){}", list.join(", "), self.sql, self.order_by());
+        if self.order.iter().any(|term| !list.contains(&term.trim_end_matches(" DESC").to_string())) {
+            self.order.clear();
+        }
+        self.columns = columns;
+        Ok(())
+    }
+
+    fn window(&mut self, clause: &str, spec: &Value, name: &str) -> Result<(), QueryError> {
+        let Some(n) = spec.as_u64() else {
+            return invalid(format!("'{}' expects a non-negative integer", name));
+        };
+        // OFFSET 必须跟在 LIMIT 之后
+        let limit = if clause == "OFFSET" { " LIMIT -1" } else { "" };
+        self.sql = format!("SELECT * FROM ({}){}{} {} {}", self.sql, self.order_by(), limit, clause, n);
+        Ok(())
+    }
+
+    fn count(&mut self, spec: &Value) -> Result<(), QueryError> {
+        let name = match spec.as_str() {
+            Some(name) if identifier(name) && !name.contains("__") && name != "_id" => name,
+            _ => return invalid("'$count' expects the name of the output field"),
+        };
+        self.sql = format!("SELECT COUNT(*) AS {} FROM ({})", name, self.sql);
+        self.columns = vec![name.to_string()];
+        self.order.clear();
+        Ok(())
+    }
+}
+
+// 复合 _id 的字段 _id.city 保存在列 _id__city 中
+fn column_name(field: &str) -> String {
+    field.replace('.', "__")
+}
+
+// 把过滤文档中的字段名换成列名
+fn rename_fields(filter: &Value) -> Value {
+    match filter {
+        Value::Object(fields) => Value::Object(
+            fields
+                .iter()
+                .map(|(key, value)| match key.as_str() {
+                    "$and" | "$or" => (key.clone(), value.as_array().map_or(value.clone(), |items| items.iter().map(rename_fields).collect())),
+                    _ => (column_name(key), value.clone()),
+                })
+                .collect(),
+        ),
+        filter => filter.clone(),
+    }
+}
+
+// 把 _id__city 形式的列放回 _id 对象中
+fn nest(doc: Value) -> Value {
+    let Value::Object(fields) = doc else {
+        return doc;
+    };
+    let mut nested = Map::new();
+    let mut id = Map::new();
+    for (key, value) in fields {
+        match key.strip_prefix("_id__") {
+            Some(field) => {
+                id.insert(field.to_string(), value);
+            }
+            None => {
+                nested.insert(key, value);
+            }
+        }
+    }
+    if !id.is_empty() {
+        nested.insert("_id".to_string(), Value::Object(id));
+    }
+    Value::Object(nested)
+}
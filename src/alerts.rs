@@ -0,0 +1,228 @@
+//! Threshold alerts.
+//!
+//! Every minute each alert in `alerts` is checked. When its condition starts
+//! to hold an event with `status: "firing"` is logged as a warning and, if
+//! the alert has a `webhook`, posted there through the job queue; when it
+//! stops holding a `resolved` event follows:
+//!
+//! ```json
+//! { "alert": "big-logs", "status": "firing", "when": "documents",
+//!   "value": 1000412, "threshold": 1000000, "at": 1760000000000,
+//!   "message": "'logs' holds 1000412 documents, the limit is 1000000" }
+//! ```
+//!
+//! `GET /_admin/alerts` shows the last value of every alert and whether it
+//! is firing. The state is kept in memory, so an alert still holding after a
+//! restart fires again.
+
+use actix_web::{web, HttpResponse};
+use serde::Serialize;
+use serde_json::{json, Value};
+use sqlx::SqlitePool;
+use std::collections::{BTreeMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use crate::cdc::now_millis;
+use crate::config::{Condition, ConfigHandle};
+use crate::database::{collection_tables, count_rows};
+use crate::jobs::Queue;
+use crate::metrics::Metrics;
+use crate::partition::Partitions;
+use crate::shard::Shards;
+use crate::webhook;
+
+/// How often the alerts are checked.
+const CHECK_INTERVAL: Duration = Duration::from_secs(60);
+
+/// The last check of an alert.
+#[derive(Debug, Clone, Serialize)]
+pub struct AlertState {
+    pub firing: bool,
+    pub value: Option<f64>,
+    pub threshold: f64,
+    pub message: Option<String>,
+    /// Since when the alert is firing, or was last resolved.
+    pub since: Option<u64>,
+    pub checked_at: u64,
+}
+
+/// The alerts of this server; [`start`] checks them, and `main` registers
+/// them as app data for `GET /_admin/alerts`.
+pub struct Alerts {
+    config: Arc<ConfigHandle>,
+    pool: SqlitePool,
+    shards: Arc<Shards>,
+    partitions: Arc<Partitions>,
+    metrics: Arc<Metrics>,
+    queue: Arc<Queue>,
+    started_at: u64,
+    states: Mutex<BTreeMap<String, AlertState>>,
+    /// Request and 5xx totals of the recent checks, for error rates.
+    requests: Mutex<VecDeque<(u64, f64, f64)>>,
+}
+
+impl Alerts {
+    pub fn new(
+        config: Arc<ConfigHandle>,
+        pool: SqlitePool,
+        shards: Arc<Shards>,
+        partitions: Arc<Partitions>,
+        metrics: Arc<Metrics>,
+        queue: Arc<Queue>,
+    ) -> Self {
+        Self {
+            config,
+            pool,
+            shards,
+            partitions,
+            metrics,
+            queue,
+            started_at: now_millis(),
+            states: Mutex::new(BTreeMap::new()),
+            requests: Mutex::new(VecDeque::new()),
+        }
+    }
+
+    // 检查所有告警, 状态变化时发出事件
+    async fn check(&self) {
+        let now = now_millis();
+        self.sample_requests(now);
+        let alerts = self.config.get().alerts.clone();
+        self.states.lock().unwrap().retain(|name, _| alerts.contains_key(name));
+        for (name, alert) in &alerts {
+            let (value, threshold, message) = match self.measure(&alert.condition, now).await {
+                Ok(measured) => measured,
+                Err(e) => {
+                    log::warn!("failed to check alert '{}': {}", name, e);
+                    continue;
+                }
+            };
+            let firing = value.is_some_and(|value| value > threshold);
+            let changed = {
+                let mut states = self.states.lock().unwrap();
+                let previous = states.get(name).map(|state| (state.firing, state.since));
+                let changed = previous.map_or(firing, |(was, _)| was != firing);
+                let since = if changed { Some(now) } else { previous.and_then(|(_, since)| since) };
+                let message = message.clone();
+                states.insert(name.clone(), AlertState { firing, value, threshold, message, since, checked_at: now });
+                changed
+            };
+            if !changed {
+                continue;
+            }
+            let event = json!({
+                "alert": name,
+                "status": if firing { "firing" } else { "resolved" },
+                "when": serde_json::to_value(&alert.condition).ok().and_then(|c| c.get("when").cloned()),
+                "value": value,
+                "threshold": threshold,
+                "at": now,
+                "message": message,
+            });
+            match firing {
+                true => log::warn!("alert '{}' firing: {}", name, message.as_deref().unwrap_or_default()),
+                false => log::info!("alert '{}' resolved", name),
+            }
+            if let Some(url) = &alert.webhook {
+                if let Err(e) = webhook::deliver(&self.queue, url, &event).await {
+                    log::error!("failed to queue the webhook of alert '{}': {}", name, e);
+                }
+            }
+        }
+    }
+
+    // 取得条件的当前值、阈值和说明; 无从判断时值为空
+    async fn measure(&self, condition: &Condition, now: u64) -> Result<(Option<f64>, f64, Option<String>), sqlx::Error> {
+        Ok(match condition {
+            Condition::Documents { collection, above } => {
+                let count = self.count_documents(collection).await?;
+                let message = format!("'{}' holds {} documents, the limit is {}", collection, count, above);
+                (Some(count as f64), *above as f64, Some(message))
+            }
+            Condition::ErrorRate { above, window_secs } => {
+                let rate = self.error_rate(now, window_secs * 1000);
+                let message = rate.map(|rate| format!("{:.1}% of the requests failed in the last {} seconds", rate * 100.0, window_secs));
+                (rate, *above, message)
+            }
+            Condition::ScheduleStale { schedule, hours } => {
+                let last: Option<i64> =
+                    sqlx::query_scalar("SELECT MAX(finished_at) FROM _schedule_runs WHERE schedule = ? AND status = 'done'")
+                        .bind(schedule)
+                        .fetch_one(&self.pool)
+                        .await?;
+                // 从未成功运行过时从启动时算起
+                let since = last.map_or(self.started_at, |last| last as u64);
+                let stale = now.saturating_sub(since) as f64 / 3_600_000.0;
+                let message = match last {
+                    Some(_) => format!("schedule '{}' last succeeded {:.1} hours ago", schedule, stale),
+                    None => format!("schedule '{}' has not succeeded in the {:.1} hours since startup", schedule, stale),
+                };
+                (Some(stale), *hours as f64, Some(message))
+            }
+        })
+    }
+
+    // 集合的文档数, 包括各分片和各分区
+    async fn count_documents(&self, uri: &str) -> Result<i64, sqlx::Error> {
+        let mut total = 0;
+        for (pool, table) in collection_tables(&self.pool, &self.shards, &self.partitions, uri).await? {
+            total += count_rows(&pool, &table).await?;
+        }
+        Ok(total)
+    }
+
+    // 记下目前的请求总数和 5xx 数, 只保留一天
+    fn sample_requests(&self, now: u64) {
+        let total = self.metrics.sum("http_requests_total", |_| true);
+        let failed = self.metrics.sum("http_requests_total", |labels| {
+            labels.iter().any(|(name, value)| *name == "status" && value.starts_with('5'))
+        });
+        let mut requests = self.requests.lock().unwrap();
+        requests.push_back((now, total, failed));
+        while requests.front().is_some_and(|(at, ..)| now - at > 24 * 3_600_000) {
+            requests.pop_front();
+        }
+    }
+
+    // 窗口内失败请求的比例; 没有请求时为空
+    fn error_rate(&self, now: u64, window: u64) -> Option<f64> {
+        let requests = self.requests.lock().unwrap();
+        let (_, total, failed) = *requests.back()?;
+        let (_, start_total, start_failed) = *requests.iter().find(|(at, ..)| now - at <= window)?;
+        let count = total - start_total;
+        (count > 0.0).then(|| (failed - start_failed) / count)
+    }
+}
+
+/// Checks the alerts every minute.
+pub fn start(alerts: Arc<Alerts>) {
+    #[cfg(not(feature = "webhooks"))]
+    if alerts.config.get().alerts.values().any(|alert| alert.webhook.is_some()) {
+        log::warn!("alert webhooks are configured but the server was built without the `webhooks` feature");
+    }
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(CHECK_INTERVAL);
+        loop {
+            interval.tick().await;
+            alerts.check().await;
+        }
+    });
+}
+
+// 列出告警及其最近一次检查的结果
+pub async fn list_alerts(alerts: web::Data<Alerts>) -> HttpResponse {
+    let config = alerts.config.get();
+    let states = alerts.states.lock().unwrap();
+    let list: Vec<Value> = config
+        .alerts
+        .iter()
+        .map(|(name, alert)| {
+            let mut entry = serde_json::to_value(alert).unwrap_or_default();
+            entry["name"] = json!(name);
+            entry["state"] = json!(states.get(name));
+            entry
+        })
+        .collect();
+    HttpResponse::Ok().json(list)
+}
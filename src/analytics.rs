@@ -0,0 +1,262 @@
+//! Usage analytics.
+//!
+//! Every document request is counted per day, collection, caller and
+//! operation in `_usage`: how many requests there were and how many bytes
+//! they sent and received. Counts are collected in memory and written every
+//! ten seconds, and at shutdown.
+//!
+//! `GET /_admin/usage` sums them up, by default per day, collection, key and
+//! operation; `group_by` picks fewer of these, and `from`, `to`,
+//! `collection` and `key` narrow the rows:
+//!
+//! ```json
+//! [{ "collection": "orders", "key": "billing", "requests": 1520,
+//!    "bytes_in": 401233, "bytes_out": 9813220 }]
+//! ```
+
+use actix_web::body::{BodySize, MessageBody};
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::http::header::CONTENT_LENGTH;
+use actix_web::middleware::Next;
+use actix_web::{web, Error, HttpMessage, HttpResponse};
+use serde::Deserialize;
+use serde_json::{Map, Value};
+use sqlx::{Row, SqlitePool};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use crate::access_log::civil;
+use crate::acl::requested;
+use crate::auth::Principal;
+use crate::cdc::now_millis;
+use crate::handlers::URI;
+
+/// How often the counts are written to `_usage`.
+const FLUSH_INTERVAL: Duration = Duration::from_secs(10);
+
+/// The columns a report may be grouped by.
+const GROUPS: [&str; 4] = ["day", "collection", "key", "operation"];
+
+/// Day, collection, principal id (empty for anonymous callers) and
+/// operation.
+type Key = (String, String, String, String);
+
+#[derive(Default, Clone, Copy)]
+struct Counts {
+    requests: i64,
+    bytes_in: i64,
+    bytes_out: i64,
+}
+
+impl Counts {
+    fn add(&mut self, other: Counts) {
+        self.requests += other.requests;
+        self.bytes_in += other.bytes_in;
+        self.bytes_out += other.bytes_out;
+    }
+}
+
+/// Counts not yet written; create one in `main`, [`start`] it and register
+/// it as app data.
+pub struct Analytics {
+    pool: SqlitePool,
+    pending: Mutex<HashMap<Key, Counts>>,
+}
+
+impl Analytics {
+    pub fn new(pool: SqlitePool) -> Self {
+        Self { pool, pending: Mutex::new(HashMap::new()) }
+    }
+
+    fn add(&self, key: Key, counts: Counts) {
+        self.pending.lock().unwrap().entry(key).or_default().add(counts);
+    }
+
+    /// Writes the counts collected so far.
+    pub async fn flush(&self) -> Result<(), sqlx::Error> {
+        let pending = std::mem::take(&mut *self.pending.lock().unwrap());
+        if pending.is_empty() {
+            return Ok(());
+        }
+        let written = self.write(&pending).await;
+        // 写入失败时放回, 下次再写
+        if written.is_err() {
+            for (key, counts) in pending {
+                self.add(key, counts);
+            }
+        }
+        written
+    }
+
+    async fn write(&self, pending: &HashMap<Key, Counts>) -> Result<(), sqlx::Error> {
+        let mut tx = self.pool.begin().await?;
+        for ((day, collection, key, operation), counts) in pending {
+            sqlx::query(
+                r#"
+                INSERT INTO _usage (day, collection, key, operation, requests, bytes_in, bytes_out)
+                VALUES (?, ?, ?, ?, ?, ?, ?)
+                ON CONFLICT (day, collection, key, operation) DO UPDATE SET
+                    requests = requests + excluded.requests,
+                    bytes_in = bytes_in + excluded.bytes_in,
+                    bytes_out = bytes_out + excluded.bytes_out
+                "#,
+            )
+            .bind(day)
+            .bind(collection)
+            .bind(key)
+            .bind(operation)
+            .bind(counts.requests)
+            .bind(counts.bytes_in)
+            .bind(counts.bytes_out)
+            .execute(&mut *tx)
+            .await?;
+        }
+        tx.commit().await
+    }
+}
+
+/// Writes the collected counts every ten seconds.
+pub fn start(analytics: Arc<Analytics>) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(FLUSH_INTERVAL);
+        loop {
+            interval.tick().await;
+            if let Err(e) = analytics.flush().await {
+                log::error!("failed to write usage analytics: {}", e);
+            }
+        }
+    });
+}
+
+// 操作类型: 路由中的 _ 动作名, 没有时按请求方法
+fn operation(method: &str, pattern: Option<String>) -> String {
+    let pattern = pattern.unwrap_or_default();
+    let rest = pattern.split_once(URI).map_or(pattern.as_str(), |(_, rest)| rest);
+    if let Some(action) = rest.split('/').find(|segment| segment.starts_with('_')) {
+        return action[1..].to_string();
+    }
+    match method {
+        "GET" | "HEAD" => "read",
+        "POST" => "insert",
+        "PUT" => "replace",
+        "PATCH" => "update",
+        "DELETE" => "delete",
+        _ => "other",
+    }
+    .to_string()
+}
+
+/// Counts document requests for [`Analytics`]; a request naming several
+/// collections counts for each of them.
+pub async fn record(
+    mut req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<impl MessageBody>, Error> {
+    let Some(analytics) = req.app_data::<web::Data<Analytics>>().cloned() else {
+        return next.call(req).await;
+    };
+    // 请求体无法解析时不在这里报错, 留给之后的处理
+    let mut collections = requested(&mut req).await.map(|(_, collections)| collections).unwrap_or_default();
+    // 插入请求的路径和请求体中是同一个集合
+    collections.sort();
+    collections.dedup();
+    let key = req.extensions().get::<Principal>().map(|p| p.id.clone()).unwrap_or_default();
+    let method = req.method().to_string();
+    let bytes_in = req
+        .headers()
+        .get(CONTENT_LENGTH)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse().ok())
+        .unwrap_or(0);
+
+    let res = next.call(req).await?;
+    let bytes_out = match res.response().body().size() {
+        BodySize::Sized(size) => size as i64,
+        _ => 0,
+    };
+    let operation = operation(&method, res.request().match_pattern());
+    let (year, month, day, ..) = civil(now_millis() / 1000);
+    let day = format!("{:04}-{:02}-{:02}", year, month, day);
+    let counts = Counts { requests: 1, bytes_in, bytes_out };
+    for collection in collections {
+        analytics.add((day.clone(), collection, key.clone(), operation.clone()), counts);
+    }
+    Ok(res)
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ReportQuery {
+    /// First and last day, as `YYYY-MM-DD`.
+    from: Option<String>,
+    to: Option<String>,
+    collection: Option<String>,
+    key: Option<String>,
+    /// Comma-separated columns of [`GROUPS`].
+    group_by: Option<String>,
+}
+
+// 按所选的列汇总用量
+pub async fn report(query: web::Query<ReportQuery>, analytics: web::Data<Analytics>) -> HttpResponse {
+    let groups: Vec<&str> = match &query.group_by {
+        Some(group_by) => group_by.split(',').map(str::trim).filter(|g| !g.is_empty()).collect(),
+        None => GROUPS.to_vec(),
+    };
+    if let Some(group) = groups.iter().find(|g| !GROUPS.contains(g)) {
+        return HttpResponse::BadRequest().json(format!("Cannot group usage by '{}'", group));
+    }
+    if let Err(e) = analytics.flush().await {
+        return HttpResponse::InternalServerError().json(format!("Failed to write usage analytics: {}", e));
+    }
+
+    let mut conditions = Vec::new();
+    let mut values = Vec::new();
+    for (condition, value) in [
+        ("day >= ?", &query.from),
+        ("day <= ?", &query.to),
+        ("collection = ?", &query.collection),
+        ("key = ?", &query.key),
+    ] {
+        if let Some(value) = value {
+            conditions.push(condition);
+            values.push(value);
+        }
+    }
+    let columns = groups.join(", ");
+    let mut sql = String::from("SELECT ");
+    if !groups.is_empty() {
+        sql.push_str(&columns);
+        sql.push_str(", ");
+    }
+    sql.push_str("SUM(requests) AS requests, SUM(bytes_in) AS bytes_in, SUM(bytes_out) AS bytes_out FROM _usage");
+    if !conditions.is_empty() {
+        sql.push_str(&format!(" WHERE {}", conditions.join(" AND ")));
+    }
+    if !groups.is_empty() {
+        sql.push_str(&format!(" GROUP BY {} ORDER BY {}", columns, columns));
+    }
+    let mut rows = sqlx::query(&sql);
+    for value in values {
+        rows = rows.bind(value);
+    }
+    let rows = match rows.fetch_all(&analytics.pool).await {
+        Ok(rows) => rows,
+        Err(e) => return HttpResponse::InternalServerError().json(format!("Failed to read usage analytics: {}", e)),
+    };
+    let report: Vec<Value> = rows
+        .iter()
+        // 没有任何记录时不分组的汇总是一行空值
+        .filter(|row| row.get::<Option<i64>, _>("requests").is_some())
+        .map(|row| {
+            let mut entry = Map::new();
+            for group in &groups {
+                entry.insert(group.to_string(), Value::from(row.get::<String, _>(*group)));
+            }
+            for total in ["requests", "bytes_in", "bytes_out"] {
+                entry.insert(total.to_string(), Value::from(row.get::<i64, _>(total)));
+            }
+            Value::Object(entry)
+        })
+        .collect();
+    HttpResponse::Ok().json(report)
+}
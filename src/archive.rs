@@ -0,0 +1,495 @@
+//! Continuous WAL archiving and point-in-time recovery.
+//!
+//! With `archive` configured the database runs in WAL mode with automatic
+//! checkpoints turned off, and this module becomes the only checkpointer.
+//! Every `segment_secs` it briefly takes the write lock, copies the frames
+//! committed since the last round to `<directory>/wal/<generation>/`, and
+//! checkpoints them into the database file. SQLite starts the WAL over after
+//! a complete checkpoint, with new salts; each such restart begins a new
+//! generation, whose first segment carries the WAL header. Every
+//! `base_backup_secs`, right after a complete checkpoint, the database file
+//! itself is copied to `<directory>/base/`.
+//!
+//! `json_storage restore <directory> <target> [--until <unix seconds>]`
+//! copies the newest base taken before `--until`, then replays the
+//! generations from the base's onwards, one WAL file per generation, with
+//! the segments archived up to that time. Replaying a generation from its
+//! start over a base taken in its middle is harmless: frames are whole page
+//! images applied in order, so the last one of each page wins.
+//!
+//! Frames written by another program that checkpoints on its own can be
+//! lost; anything else writing to the database must also set
+//! `PRAGMA wal_autocheckpoint = 0`.
+
+use serde::{Deserialize, Serialize};
+use sqlx::sqlite::{SqliteConnectOptions, SqliteJournalMode};
+use sqlx::{Connection, SqliteConnection};
+use std::io::{self, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::fs;
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+use tokio::sync::Mutex;
+
+use crate::cdc::now_millis;
+use crate::config::{Archive, ConfigHandle};
+use crate::metrics::Metrics;
+
+const WAL_HEADER: usize = 32;
+const FRAME_HEADER: usize = 24;
+
+const USAGE: &str = "usage: json_storage restore <archive directory> <target database> [--until <unix seconds>]";
+
+/// Connection options the archived database has to be opened with.
+pub fn connect_options(options: SqliteConnectOptions) -> SqliteConnectOptions {
+    options.journal_mode(SqliteJournalMode::Wal).pragma("wal_autocheckpoint", "0")
+}
+
+/// How far the WAL has been archived, kept in `<directory>/position.json`
+/// so a restart carries on where the last round stopped.
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+struct Position {
+    generation: u64,
+    /// Salts of the generation's WAL header.
+    salt: u64,
+    /// Bytes of the WAL archived so far.
+    offset: u64,
+    /// Number of the next segment.
+    segment: u64,
+}
+
+struct Session {
+    /// Holds the write lock while the WAL is copied.
+    lock: SqliteConnection,
+    checkpoint: SqliteConnection,
+    position: Position,
+    last_base: Option<Instant>,
+}
+
+/// Archives the WAL of one database file.
+pub struct Archiver {
+    settings: Archive,
+    database: PathBuf,
+    session: Mutex<Session>,
+    metrics: Arc<Metrics>,
+}
+
+impl Archiver {
+    pub async fn open(settings: Archive, options: &SqliteConnectOptions, metrics: Arc<Metrics>) -> io::Result<Self> {
+        let database = options.clone().get_filename().into_owned();
+        if database.as_os_str() == ":memory:" {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "an in-memory database cannot be archived"));
+        }
+        fs::create_dir_all(settings.directory.join("base")).await?;
+        fs::create_dir_all(settings.directory.join("wal")).await?;
+        let position = match fs::read(settings.directory.join("position.json")).await {
+            Ok(bytes) => serde_json::from_slice(&bytes).map_err(io::Error::other)?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Position::default(),
+            Err(e) => return Err(e),
+        };
+        let session = Session {
+            lock: SqliteConnection::connect_with(options).await.map_err(io::Error::other)?,
+            checkpoint: SqliteConnection::connect_with(options).await.map_err(io::Error::other)?,
+            position,
+            last_base: None,
+        };
+        Ok(Archiver { settings, database, session: Mutex::new(session), metrics })
+    }
+
+    /// Archives the frames committed since the last round and checkpoints them.
+    pub async fn archive(&self) -> io::Result<()> {
+        let mut session = self.session.lock().await;
+        // 持有写锁期间 WAL 不会变化, 复制的帧都以提交结束
+        sqlx::query("BEGIN IMMEDIATE").execute(&mut session.lock).await.map_err(io::Error::other)?;
+        let result = self.archive_locked(&mut session).await;
+        sqlx::query("ROLLBACK").execute(&mut session.lock).await.map_err(io::Error::other)?;
+        result
+    }
+
+    async fn archive_locked(&self, session: &mut Session) -> io::Result<()> {
+        if let Some((header, frames)) = self.committed_frames(&mut session.position).await? {
+            if !frames.is_empty() {
+                self.save_segment(&session.position, &header, &frames).await?;
+                session.position.offset += frames.len() as u64;
+                session.position.segment += 1;
+                self.save_position(&session.position).await?;
+                self.metrics.incr("wal_archived_bytes_total", vec![], frames.len() as f64);
+            }
+        }
+
+        let (busy, log, checkpointed): (i64, i64, i64) =
+            sqlx::query_as("PRAGMA wal_checkpoint(PASSIVE)").fetch_one(&mut session.checkpoint).await.map_err(io::Error::other)?;
+        let complete = busy == 0 && log == checkpointed;
+        let due = session.last_base.is_none_or(|last| last.elapsed() >= Duration::from_secs(self.settings.base_backup_secs));
+        if complete && due {
+            self.save_base(session.position.generation).await?;
+            session.last_base = Some(Instant::now());
+            self.prune().await?;
+        }
+        Ok(())
+    }
+
+    // 读取 WAL 中尚未归档且已提交的帧; WAL 重新开始时进入新的一代
+    async fn committed_frames(&self, position: &mut Position) -> io::Result<Option<(Vec<u8>, Vec<u8>)>> {
+        let mut file = match fs::File::open(wal_path(&self.database)).await {
+            Ok(file) => file,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        let mut header = vec![0; WAL_HEADER];
+        if file.read_exact(&mut header).await.is_err() {
+            return Ok(None);
+        }
+        let Some(frame_size) = frame_size(&header) else {
+            return Ok(None);
+        };
+        let salt = u64::from_be_bytes(header[16..24].try_into().expect("WAL headers are 32 bytes"));
+        if salt != position.salt || position.offset < WAL_HEADER as u64 {
+            *position = Position { generation: position.generation + 1, salt, offset: WAL_HEADER as u64, segment: 0 };
+        }
+
+        file.seek(SeekFrom::Start(position.offset)).await?;
+        let mut tail = Vec::new();
+        file.read_to_end(&mut tail).await?;
+        let mut end = 0;
+        let mut at = 0;
+        while at + frame_size <= tail.len() {
+            let frame = &tail[at..at + FRAME_HEADER];
+            if frame[8..16] != header[16..24] {
+                break;
+            }
+            at += frame_size;
+            // 提交帧记录了提交后数据库的页数, 其他帧为 0
+            if frame[4..8] != [0; 4] {
+                end = at;
+            }
+        }
+        tail.truncate(end);
+        Ok(Some((header, tail)))
+    }
+
+    async fn save_segment(&self, position: &Position, header: &[u8], frames: &[u8]) -> io::Result<()> {
+        let directory = self.settings.directory.join("wal").join(format!("{:010}", position.generation));
+        fs::create_dir_all(&directory).await?;
+        let mut contents = Vec::with_capacity(WAL_HEADER + frames.len());
+        // 每一代的第一段带上 WAL 头
+        if position.segment == 0 {
+            contents.extend_from_slice(header);
+        }
+        contents.extend_from_slice(frames);
+        let name = format!("{:010}-{}.wal", position.segment, now_millis());
+        write_atomically(&directory.join(name), &contents).await
+    }
+
+    async fn save_position(&self, position: &Position) -> io::Result<()> {
+        let contents = serde_json::to_vec(position).map_err(io::Error::other)?;
+        write_atomically(&self.settings.directory.join("position.json"), &contents).await
+    }
+
+    async fn save_base(&self, generation: u64) -> io::Result<()> {
+        let name = format!("{:010}-{}.db", generation, now_millis());
+        let path = self.settings.directory.join("base").join(&name);
+        let partial = sidecar(&path, ".partial");
+        fs::copy(&self.database, &partial).await?;
+        fs::File::open(&partial).await?.sync_all().await?;
+        fs::rename(&partial, &path).await?;
+        log::info!("archived a base copy of {} as {}", self.database.display(), name);
+        self.metrics.incr("wal_base_copies_total", vec![], 1.0);
+        Ok(())
+    }
+
+    // 只保留最近 keep_bases 份基础备份, 以及从其中最早一份开始重放所需的各代 WAL
+    async fn prune(&self) -> io::Result<()> {
+        let bases = list(&self.settings.directory.join("base"), ".db").await?;
+        let keep = bases.len().saturating_sub(self.settings.keep_bases);
+        for (_, _, path) in &bases[..keep] {
+            fs::remove_file(path).await?;
+        }
+        let Some((oldest, _, _)) = bases.get(keep) else {
+            return Ok(());
+        };
+        for (generation, _, path) in list(&self.settings.directory.join("wal"), "").await? {
+            if generation < *oldest {
+                fs::remove_dir_all(path).await?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Archives the WAL every `segment_secs` when `archive` is configured.
+pub async fn start(config: &ConfigHandle, options: &SqliteConnectOptions, metrics: Arc<Metrics>) -> io::Result<Option<Arc<Archiver>>> {
+    let Some(settings) = config.get().archive.clone() else {
+        return Ok(None);
+    };
+    let interval = Duration::from_secs(settings.segment_secs);
+    let archiver = Arc::new(Archiver::open(settings, options, metrics).await?);
+    archiver.archive().await?;
+    log::info!("archiving the WAL of {} to {}", archiver.database.display(), archiver.settings.directory.display());
+
+    let task = archiver.clone();
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(interval);
+        loop {
+            ticker.tick().await;
+            if let Err(e) = task.archive().await {
+                log::error!("failed to archive the WAL: {}", e);
+                task.metrics.incr("wal_archive_failures_total", vec![], 1.0);
+            }
+        }
+    });
+    Ok(Some(archiver))
+}
+
+/// Entry point of the `restore` subcommand; `args` follow the subcommand name.
+pub async fn run(args: &[String]) -> io::Result<()> {
+    let invalid = |msg: String| io::Error::new(io::ErrorKind::InvalidInput, msg);
+    let mut paths = Vec::new();
+    let mut until = u64::MAX;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--until" => {
+                until = match args.next().and_then(|secs| secs.parse::<u64>().ok()) {
+                    Some(secs) => secs.saturating_mul(1000),
+                    None => return Err(invalid(USAGE.to_string())),
+                }
+            }
+            path if !path.starts_with('-') => paths.push(PathBuf::from(path)),
+            _ => return Err(invalid(USAGE.to_string())),
+        }
+    }
+    let [directory, target] = <[PathBuf; 2]>::try_from(paths).map_err(|_| invalid(USAGE.to_string()))?;
+    if fs::try_exists(&target).await? {
+        return Err(invalid(format!("{} already exists", target.display())));
+    }
+
+    let bases = list(&directory.join("base"), ".db").await?;
+    let Some((first, taken, base)) = bases.into_iter().rfind(|(_, taken, _)| *taken <= until) else {
+        return Err(invalid("no base copy was taken before that time".to_string()));
+    };
+    fs::copy(&base, &target).await?;
+    eprintln!("restored base copy {}", base.display());
+
+    let mut restored = taken;
+    for (generation, _, path) in list(&directory.join("wal"), "").await? {
+        if generation < first {
+            continue;
+        }
+        let segments = list(&path, ".wal").await?;
+        let mut wal = Vec::new();
+        let mut complete = true;
+        for (expected, (number, archived, segment)) in segments.into_iter().enumerate() {
+            if number != expected as u64 {
+                return Err(invalid(format!("segment {} of generation {} is missing", expected, generation)));
+            }
+            if archived > until {
+                complete = false;
+                break;
+            }
+            wal.extend(fs::read(&segment).await?);
+            restored = restored.max(archived);
+        }
+        if !wal.is_empty() {
+            replay(&target, &wal).await?;
+            eprintln!("replayed generation {}", generation);
+        }
+        if !complete {
+            break;
+        }
+    }
+    eprintln!("{} holds the database as of {} ms since the epoch", target.display(), restored);
+    Ok(())
+}
+
+// 把一代 WAL 放在目标库旁边, 打开时 SQLite 会恢复它, 再 checkpoint 进数据库文件
+async fn replay(target: &Path, wal: &[u8]) -> io::Result<()> {
+    let shm = sidecar(target, "-shm");
+    if fs::try_exists(&shm).await? {
+        fs::remove_file(&shm).await?;
+    }
+    fs::write(wal_path(target), wal).await?;
+    let options = SqliteConnectOptions::new().filename(target);
+    let mut conn = SqliteConnection::connect_with(&options).await.map_err(io::Error::other)?;
+    sqlx::query("PRAGMA wal_checkpoint(TRUNCATE)").execute(&mut conn).await.map_err(io::Error::other)?;
+    conn.close().await.map_err(io::Error::other)
+}
+
+/// Size of one frame of the WAL with this header, or `None` for a file that
+/// is not a WAL.
+fn frame_size(header: &[u8]) -> Option<usize> {
+    let magic = u32::from_be_bytes(header[0..4].try_into().ok()?);
+    if magic & !1 != 0x377f0682 {
+        return None;
+    }
+    let page_size = u32::from_be_bytes(header[8..12].try_into().ok()?) as usize;
+    Some(FRAME_HEADER + page_size)
+}
+
+fn wal_path(database: &Path) -> PathBuf {
+    sidecar(database, "-wal")
+}
+
+fn sidecar(database: &Path, suffix: &str) -> PathBuf {
+    let mut path = database.as_os_str().to_owned();
+    path.push(suffix);
+    PathBuf::from(path)
+}
+
+// 目录下名为 <序号>[-<毫秒时间戳>]<后缀> 的文件, 按序号排列
+async fn list(directory: &Path, suffix: &str) -> io::Result<Vec<(u64, u64, PathBuf)>> {
+    let mut found = Vec::new();
+    let mut entries = match fs::read_dir(directory).await {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(found),
+        Err(e) => return Err(e),
+    };
+    while let Some(entry) = entries.next_entry().await? {
+        let name = entry.file_name().to_string_lossy().into_owned();
+        let Some(stem) = name.strip_suffix(suffix) else { continue };
+        let (number, millis) = stem.split_once('-').unwrap_or((stem, "0"));
+        if let (Ok(number), Ok(millis)) = (number.parse(), millis.parse()) {
+            found.push((number, millis, entry.path()));
+        }
+    }
+    found.sort();
+    Ok(found)
+}
+
+async fn write_atomically(path: &Path, contents: &[u8]) -> io::Result<()> {
+    let partial = sidecar(path, ".partial");
+    let mut file = fs::File::create(&partial).await?;
+    file.write_all(contents).await?;
+    file.sync_all().await?;
+    fs::rename(&partial, path).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Scratch(PathBuf);
+
+    impl Scratch {
+        fn new(name: &str) -> Self {
+            let path = std::env::temp_dir().join(format!("json_storage_archive_{}_{}", std::process::id(), name));
+            let _ = std::fs::remove_dir_all(&path);
+            std::fs::create_dir_all(&path).unwrap();
+            Scratch(path)
+        }
+    }
+
+    impl Drop for Scratch {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn header(magic: u32, page_size: u32) -> Vec<u8> {
+        let mut header = vec![0; WAL_HEADER];
+        header[0..4].copy_from_slice(&magic.to_be_bytes());
+        header[8..12].copy_from_slice(&page_size.to_be_bytes());
+        header
+    }
+
+    async fn numbers(database: &Path) -> Vec<i64> {
+        let mut conn = SqliteConnection::connect_with(&SqliteConnectOptions::new().filename(database)).await.unwrap();
+        sqlx::query_scalar("SELECT n FROM t ORDER BY n").fetch_all(&mut conn).await.unwrap()
+    }
+
+    // 把归档文件名中的时间戳改为 secs, 以便按时间恢复
+    async fn archived_at(directory: &Path, suffix: &str, secs: u64) {
+        for (number, _, path) in list(directory, suffix).await.unwrap() {
+            fs::rename(&path, directory.join(format!("{:010}-{}{}", number, secs * 1000, suffix))).await.unwrap();
+        }
+    }
+
+    #[test]
+    fn the_frame_size_comes_from_the_wal_header() {
+        // 魔数的最低位表示校验和的字节序, 两种都是 WAL
+        assert_eq!(frame_size(&header(0x377f0682, 4096)), Some(FRAME_HEADER + 4096));
+        assert_eq!(frame_size(&header(0x377f0683, 1024)), Some(FRAME_HEADER + 1024));
+        assert_eq!(frame_size(&header(0x377f0684, 4096)), None);
+        assert_eq!(frame_size(&[0; WAL_HEADER]), None);
+    }
+
+    #[tokio::test]
+    async fn each_restart_of_the_wal_is_archived_as_a_new_generation() {
+        let scratch = Scratch::new("generations");
+        let database = scratch.0.join("data.db");
+        let options = connect_options(SqliteConnectOptions::new().filename(&database).create_if_missing(true));
+        let mut conn = SqliteConnection::connect_with(&options).await.unwrap();
+        sqlx::query("CREATE TABLE t (n INTEGER)").execute(&mut conn).await.unwrap();
+
+        // SQLite 自己写的 WAL 头也能读出帧大小
+        let wal = fs::read(wal_path(&database)).await.unwrap();
+        let page_size: i64 = sqlx::query_scalar("PRAGMA page_size").fetch_one(&mut conn).await.unwrap();
+        assert_eq!(frame_size(&wal[..WAL_HEADER]), Some(FRAME_HEADER + page_size as usize));
+
+        let settings = Archive { directory: scratch.0.join("archive"), segment_secs: 10, base_backup_secs: 86400, keep_bases: 7 };
+        let archiver = Archiver::open(settings, &options, Arc::new(Metrics::new())).await.unwrap();
+        archiver.archive().await.unwrap();
+        let first = archiver.session.lock().await.position.clone();
+        assert_eq!((first.generation, first.segment, first.offset), (1, 1, wal.len() as u64));
+        // 没有新的写入时不产生新段
+        archiver.archive().await.unwrap();
+        assert_eq!(archiver.session.lock().await.position.segment, 1);
+
+        sqlx::query("INSERT INTO t VALUES (1)").execute(&mut conn).await.unwrap();
+        archiver.archive().await.unwrap();
+        let second = archiver.session.lock().await.position.clone();
+        assert_eq!((second.generation, second.segment), (2, 1));
+        assert_ne!(second.salt, first.salt);
+
+        let segment = list(&scratch.0.join("archive/wal/0000000002"), ".wal").await.unwrap();
+        let bytes = fs::read(&segment[0].2).await.unwrap();
+        assert_eq!(frame_size(&bytes[..WAL_HEADER]), Some(FRAME_HEADER + page_size as usize));
+        assert_eq!((bytes.len() - WAL_HEADER) % (FRAME_HEADER + page_size as usize), 0);
+        let saved: Position = serde_json::from_slice(&fs::read(scratch.0.join("archive/position.json")).await.unwrap()).unwrap();
+        assert_eq!((saved.generation, saved.offset), (second.generation, second.offset));
+    }
+
+    #[tokio::test]
+    async fn a_restore_replays_the_segments_archived_up_to_the_given_time() {
+        let scratch = Scratch::new("restore");
+        let database = scratch.0.join("data.db");
+        let directory = scratch.0.join("archive");
+        let options = connect_options(SqliteConnectOptions::new().filename(&database).create_if_missing(true));
+        let mut conn = SqliteConnection::connect_with(&options).await.unwrap();
+        sqlx::query("CREATE TABLE t (n INTEGER)").execute(&mut conn).await.unwrap();
+        sqlx::query("INSERT INTO t VALUES (1)").execute(&mut conn).await.unwrap();
+
+        let settings = Archive { directory: directory.clone(), segment_secs: 10, base_backup_secs: 86400, keep_bases: 7 };
+        let archiver = Archiver::open(settings, &options, Arc::new(Metrics::new())).await.unwrap();
+        archiver.archive().await.unwrap();
+        archived_at(&directory.join("base"), ".db", 1000).await;
+        archived_at(&directory.join("wal/0000000001"), ".wal", 1000).await;
+        for (n, secs) in [(2, 2000), (3, 3000)] {
+            sqlx::query("INSERT INTO t VALUES (?)").bind(n).execute(&mut conn).await.unwrap();
+            archiver.archive().await.unwrap();
+            let generation = archiver.session.lock().await.position.generation;
+            archived_at(&directory.join(format!("wal/{:010}", generation)), ".wal", secs).await;
+        }
+
+        let restore = |target: &str, until: Option<&str>| {
+            let mut args = vec![directory.display().to_string(), scratch.0.join(target).display().to_string()];
+            if let Some(until) = until {
+                args.extend(["--until".to_string(), until.to_string()]);
+            }
+            args
+        };
+        run(&restore("at_2500.db", Some("2500"))).await.unwrap();
+        assert_eq!(numbers(&scratch.0.join("at_2500.db")).await, [1, 2]);
+        run(&restore("at_3000.db", Some("3000"))).await.unwrap();
+        assert_eq!(numbers(&scratch.0.join("at_3000.db")).await, [1, 2, 3]);
+        run(&restore("latest.db", None)).await.unwrap();
+        assert_eq!(numbers(&scratch.0.join("latest.db")).await, [1, 2, 3]);
+
+        let early = run(&restore("at_500.db", Some("500"))).await.unwrap_err();
+        assert_eq!(early.to_string(), "no base copy was taken before that time");
+        let existing = run(&restore("latest.db", None)).await.unwrap_err();
+        assert_eq!(existing.kind(), io::ErrorKind::InvalidInput);
+        assert_eq!(run(&restore("x.db", Some("soon"))).await.unwrap_err().to_string(), USAGE);
+    }
+}
@@ -0,0 +1,230 @@
+use actix_web::body::{EitherBody, MessageBody};
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::http::Method;
+use actix_web::middleware::Next;
+use actix_web::web::Bytes;
+use actix_web::{web, Error, HttpMessage, HttpResponse};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+use crate::config::{ApiKey, Config, ConfigHandle, Role};
+use crate::logging::now;
+use crate::sessions::{self, hex, ActiveSession, SessionStore};
+
+pub const SIGNATURE_HEADER: &str = "X-Signature";
+pub const SIGNATURE_KEY_HEADER: &str = "X-Signature-Key";
+pub const SIGNATURE_TIMESTAMP_HEADER: &str = "X-Signature-Timestamp";
+
+/// Largest request body middleware will buffer, same as the JSON extractor's limit.
+const MAX_BODY: usize = 2 * 1024 * 1024;
+
+/// The caller identified by [`authenticate`], stored in the request extensions.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Principal {
+    pub id: String,
+    pub role: Role,
+    pub tenant: Option<String>,
+}
+
+/// Verified client certificate of a TLS connection, stored in the connection data.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ClientCertificate {
+    /// Subject in `CN=...,O=...` form.
+    pub subject: String,
+    pub common_name: Option<String>,
+}
+
+// 从请求头中取出 API key
+fn presented_key(req: &ServiceRequest) -> Option<String> {
+    let headers = req.headers();
+    if let Some(value) = headers.get("X-API-Key").and_then(|v| v.to_str().ok()) {
+        return Some(value.to_string());
+    }
+    headers
+        .get("Authorization")
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "))
+        .map(|v| v.trim().to_string())
+}
+
+// 逐字节比较, 避免通过响应时间猜测 key
+pub(crate) fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+pub(crate) fn find_api_key<'a>(config: &'a Config, presented: &str) -> Option<&'a ApiKey> {
+    config
+        .api_keys
+        .iter()
+        .find(|k| constant_time_eq(k.key.as_bytes(), presented.as_bytes()))
+}
+
+/// Reads the whole request body and puts it back, so handlers still see it.
+pub(crate) async fn buffer_body(req: &mut ServiceRequest) -> Result<Bytes, HttpResponse> {
+    let payload = req.extract::<web::Payload>().await.map_err(|e| e.error_response())?;
+    let body = match payload.to_bytes_limited(MAX_BODY).await {
+        Ok(Ok(body)) => body,
+        Ok(Err(e)) => return Err(HttpResponse::BadRequest().json(format!("Failed to read body: {}", e))),
+        Err(_) => return Err(HttpResponse::PayloadTooLarge().json("Request body too large")),
+    };
+
+    let (_, mut payload) = actix_http::h1::Payload::create(true);
+    payload.unread_data(body.clone());
+    req.set_payload(payload.into());
+    Ok(body)
+}
+
+/// Resolves the API key on the request, if any, to a [`Principal`].
+///
+/// Requests without a key may be signed instead: `X-Signature-Key` names one of
+/// `request_signing.keys`, `X-Signature-Timestamp` is the unix time, and
+/// `X-Signature` is `sha256=<hex HMAC-SHA256>` of
+/// `"{timestamp}\n{METHOD}\n{path and query}\n{body}"` under the key's secret.
+/// A bad signature or a timestamp outside `tolerance_secs` gets 401.
+///
+/// Otherwise the connection's client certificate, mapped through
+/// `client_certs`, then a browser session is used, and failing all of these
+/// the request passes through anonymously; a key that matches nothing is
+/// rejected with 401. Mutating requests authenticated only by the session
+/// cookie must also carry the session's CSRF token in `X-CSRF-Token`, or get 403.
+pub async fn authenticate(
+    mut req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<EitherBody<impl MessageBody>>, Error> {
+    if let Some(presented) = presented_key(&req) {
+        let config = req.app_data::<web::Data<ConfigHandle>>().map(|c| c.get());
+        let matched = config.as_ref().and_then(|config| find_api_key(config, &presented));
+
+        match matched {
+            Some(key) => {
+                req.extensions_mut().insert(Principal {
+                    id: key.id.clone(),
+                    role: key.role,
+                    tenant: key.tenant.clone(),
+                });
+            }
+            None => {
+                let response = HttpResponse::Unauthorized().json("Invalid API key");
+                return Ok(req.into_response(response).map_into_right_body());
+            }
+        }
+    } else if req.headers().contains_key(SIGNATURE_KEY_HEADER) {
+        match verify_signature(&mut req).await {
+            Ok(principal) => {
+                req.extensions_mut().insert(principal);
+            }
+            Err(response) => return Ok(req.into_response(response).map_into_right_body()),
+        }
+    } else if let Some(principal) = certificate_principal(&req) {
+        req.extensions_mut().insert(principal);
+    } else if let Some(session) = active_session(&req) {
+        let safe = matches!(*req.method(), Method::GET | Method::HEAD | Method::OPTIONS);
+        let token = req.headers().get(sessions::CSRF_HEADER).map(|v| v.as_bytes());
+        if !safe && !token.is_some_and(|t| constant_time_eq(t, session.csrf_token.as_bytes())) {
+            let response = HttpResponse::Forbidden().json("Missing or invalid CSRF token");
+            return Ok(req.into_response(response).map_into_right_body());
+        }
+        req.extensions_mut().insert(session.principal.clone());
+        req.extensions_mut().insert(session);
+    }
+    next.call(req).await.map(ServiceResponse::map_into_left_body)
+}
+
+// 校验请求签名, 签名覆盖时间戳、方法、路径和请求体
+async fn verify_signature(req: &mut ServiceRequest) -> Result<Principal, HttpResponse> {
+    let rejected = |msg: &str| HttpResponse::Unauthorized().json(msg);
+    let header = |name: &str| req.headers().get(name).and_then(|v| v.to_str().ok()).map(str::to_string);
+    let (Some(key_id), Some(timestamp), Some(signature)) =
+        (header(SIGNATURE_KEY_HEADER), header(SIGNATURE_TIMESTAMP_HEADER), header(SIGNATURE_HEADER))
+    else {
+        return Err(rejected("Incomplete request signature"));
+    };
+
+    let Some(config) = req.app_data::<web::Data<ConfigHandle>>().map(|c| c.get()) else {
+        return Err(rejected("Invalid request signature"));
+    };
+    let Some(key) = config.request_signing.keys.iter().find(|k| k.id == key_id) else {
+        return Err(rejected("Invalid request signature"));
+    };
+    let fresh = timestamp
+        .parse::<u64>()
+        .is_ok_and(|ts| ts.abs_diff(now()) <= config.request_signing.tolerance_secs);
+    if !fresh {
+        return Err(rejected("Request signature expired"));
+    }
+
+    let body = buffer_body(req).await?;
+    let target = req.uri().path_and_query().map_or("/", |p| p.as_str());
+    let mut mac = Hmac::<Sha256>::new_from_slice(key.secret.as_bytes()).expect("HMAC accepts any key length");
+    mac.update(format!("{}\n{}\n{}\n", timestamp, req.method(), target).as_bytes());
+    mac.update(&body);
+    let expected = format!("sha256={}", hex(&mac.finalize().into_bytes()));
+    if !constant_time_eq(expected.as_bytes(), signature.trim().to_ascii_lowercase().as_bytes()) {
+        return Err(rejected("Invalid request signature"));
+    }
+
+    Ok(Principal {
+        id: key.id.clone(),
+        role: key.role,
+        tenant: key.tenant.clone(),
+    })
+}
+
+// 浏览器会话 cookie 对应的会话
+fn active_session(req: &ServiceRequest) -> Option<ActiveSession> {
+    let cookie = req.cookie(sessions::COOKIE)?;
+    req.app_data::<web::Data<SessionStore>>()?.get(cookie.value())
+}
+
+// 按证书主题 (完整主题或 CN) 查找对应的角色
+fn certificate_principal(req: &ServiceRequest) -> Option<Principal> {
+    let cert = req.conn_data::<ClientCertificate>()?;
+    let config = req.app_data::<web::Data<ConfigHandle>>()?.get();
+    let mapped = config.client_certs.iter().find(|c| {
+        c.subject == cert.subject || cert.common_name.as_deref() == Some(c.subject.as_str())
+    })?;
+    Some(Principal {
+        id: cert.subject.clone(),
+        role: mapped.role,
+        tenant: mapped.tenant.clone(),
+    })
+}
+
+/// Whether `req` only reads documents: safe methods, and the POSTs of
+/// `_mget` and `_snapshot`, which carry their ids in the body, and of
+/// `_find` and `_aggregate`, which carry their queries there.
+pub fn reads_only(req: &ServiceRequest) -> bool {
+    matches!(*req.method(), Method::GET | Method::HEAD | Method::OPTIONS)
+        || req.path().ends_with("/_mget")
+        || req.path().ends_with("/_find")
+        || req.path().ends_with("/_aggregate")
+        || req.path() == "/_snapshot"
+}
+
+/// Checks that the caller holds at least `role`. Always passes while
+/// authentication is off (see [`Config::auth_enabled`]).
+pub fn check_role(req: &ServiceRequest, role: Role) -> Result<(), HttpResponse> {
+    let auth_enabled = req
+        .app_data::<web::Data<ConfigHandle>>()
+        .is_some_and(|c| c.get().auth_enabled());
+    if !auth_enabled {
+        return Ok(());
+    }
+
+    match req.extensions().get::<Principal>() {
+        Some(principal) if principal.role >= role => Ok(()),
+        Some(_) => Err(HttpResponse::Forbidden().json("Insufficient permissions")),
+        None => Err(HttpResponse::Unauthorized().json("Authentication required")),
+    }
+}
+
+/// Guards a scope so only admins get through.
+pub async fn require_admin(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<EitherBody<impl MessageBody>>, Error> {
+    if let Err(response) = check_role(&req, Role::Admin) {
+        return Ok(req.into_response(response).map_into_right_body());
+    }
+    next.call(req).await.map(ServiceResponse::map_into_left_body)
+}
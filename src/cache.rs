@@ -0,0 +1,265 @@
+//! Redis read cache.
+//!
+//! With `redis` configured, documents fetched by id and whole collection
+//! listings ([`DocumentStore::list`], not the pages of `GET /{uri}`) are
+//! cached in Redis, which every instance of a deployment shares. A write
+//! through any instance deletes the cached document and the collection's
+//! listing once the store has accepted it, so the other instances see it on
+//! their next read. A read racing a write can still put the old value back;
+//! it then stays until its TTL runs out, which bounds how stale a read can
+//! be. Reads carrying a recent `X-Session-Token` skip the cache; see
+//! [`crate::consistency`].
+//!
+//! Redis errors never fail a request: reads fall back to the store and a
+//! failed invalidation is logged. The cache needs the `redis` cargo
+//! feature; without it [`wrap`] only warns that `redis` is configured.
+
+use std::sync::Arc;
+
+use crate::config::ConfigHandle;
+use crate::metrics::Metrics;
+use crate::store::DocumentStore;
+
+/// Key of the cached document `id` of `uri`.
+pub fn document_key(prefix: &str, uri: &str, id: i64) -> String {
+    format!("{}:doc:{}:{}", prefix, uri, id)
+}
+
+/// Key of the cached listing of `uri`.
+pub fn list_key(prefix: &str, uri: &str) -> String {
+    format!("{}:list:{}", prefix, uri)
+}
+
+/// Wraps `store` in a Redis cache when `redis` is configured, and returns it
+/// unchanged otherwise or when Redis cannot be reached at startup.
+#[cfg(feature = "redis")]
+pub async fn wrap(config: &ConfigHandle, store: Arc<dyn DocumentStore>, metrics: Arc<Metrics>) -> Arc<dyn DocumentStore> {
+    let Some(settings) = config.get().redis.clone() else {
+        return store;
+    };
+    match redis_cache::CachedStore::connect(store.clone(), settings, metrics).await {
+        Ok(cached) => Arc::new(cached),
+        Err(e) => {
+            log::error!("redis could not be reached, running without the cache: {}", e);
+            store
+        }
+    }
+}
+
+#[cfg(not(feature = "redis"))]
+pub async fn wrap(config: &ConfigHandle, store: Arc<dyn DocumentStore>, _metrics: Arc<Metrics>) -> Arc<dyn DocumentStore> {
+    if config.get().redis.is_some() {
+        log::warn!("redis is configured but the server was built without the `redis` feature");
+    }
+    store
+}
+
+#[cfg(feature = "redis")]
+mod redis_cache {
+    use async_trait::async_trait;
+    use redis::aio::{ConnectionManager, ConnectionManagerConfig};
+    use redis::FromRedisValue;
+    use serde_json::Value;
+    use std::collections::BTreeMap;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use super::{document_key, list_key};
+    use crate::config::Redis;
+    use crate::consistency;
+    use crate::metrics::Metrics;
+    use crate::store::{DedupMode, DocumentStore, Inserted, PageQuery, Paged, StoreError, UpdatedMany, Upserted, Written};
+
+    /// Redis calls slower than this count as failed, so a stuck Redis only slows reads down this much.
+    const TIMEOUT: Duration = Duration::from_millis(500);
+    /// The first connection retries with backoff; give up on it after this long.
+    const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+    pub struct CachedStore {
+        inner: Arc<dyn DocumentStore>,
+        redis: ConnectionManager,
+        settings: Redis,
+        metrics: Arc<Metrics>,
+    }
+
+    impl CachedStore {
+        pub async fn connect(inner: Arc<dyn DocumentStore>, settings: Redis, metrics: Arc<Metrics>) -> Result<Self, String> {
+            let client = redis::Client::open(settings.url.as_str()).map_err(|e| e.to_string())?;
+            let config = ConnectionManagerConfig::new()
+                .set_connection_timeout(TIMEOUT)
+                .set_response_timeout(TIMEOUT);
+            let redis = match tokio::time::timeout(CONNECT_TIMEOUT, ConnectionManager::new_with_config(client, config)).await {
+                Ok(redis) => redis.map_err(|e| e.to_string())?,
+                Err(_) => return Err("timed out".to_string()),
+            };
+            Ok(CachedStore { inner, redis, settings, metrics })
+        }
+
+        // 重连期间连接会一直等待, 所以每次调用都加上超时
+        async fn query<T: FromRedisValue>(&self, cmd: &redis::Cmd) -> Result<T, String> {
+            match tokio::time::timeout(TIMEOUT, cmd.query_async(&mut self.redis.clone())).await {
+                Ok(result) => result.map_err(|e| e.to_string()),
+                Err(_) => Err("timed out".to_string()),
+            }
+        }
+
+        // 读缓存, 出错时当作未命中
+        async fn lookup(&self, key: &str, kind: &str) -> Option<Value> {
+            let cached: Result<Option<String>, String> = self.query(redis::cmd("GET").arg(key)).await;
+            let value = match cached {
+                Ok(cached) => cached.and_then(|text| serde_json::from_str(&text).ok()),
+                Err(e) => {
+                    log::debug!("redis failed to read '{}': {}", key, e);
+                    None
+                }
+            };
+            let result = if value.is_some() { "hit" } else { "miss" };
+            self.metrics.incr(
+                "cache_requests_total",
+                vec![("kind", kind.to_string()), ("result", result.to_string())],
+                1.0,
+            );
+            value
+        }
+
+        async fn fill(&self, key: &str, value: &Value, ttl: u64) {
+            let text = value.to_string();
+            let stored: Result<(), String> = self.query(redis::cmd("SET").arg(key).arg(text).arg("EX").arg(ttl)).await;
+            if let Err(e) = stored {
+                log::debug!("redis failed to cache '{}': {}", key, e);
+            }
+        }
+
+        // 写入后删除文档和列表的缓存
+        async fn invalidate(&self, uri: &str, ids: &[i64]) {
+            let prefix = &self.settings.key_prefix;
+            let mut keys = vec![list_key(prefix, uri)];
+            keys.extend(ids.iter().map(|id| document_key(prefix, uri, *id)));
+            let deleted: Result<(), String> = self.query(redis::cmd("DEL").arg(&keys)).await;
+            if let Err(e) = deleted {
+                log::warn!("redis failed to invalidate {:?}, cached reads may be stale until they expire: {}", keys, e);
+            }
+        }
+    }
+
+    #[async_trait]
+    impl DocumentStore for CachedStore {
+        async fn insert(&self, uri: &str, doc: &Value) -> Result<i64, StoreError> {
+            let id = self.inner.insert(uri, doc).await?;
+            self.invalidate(uri, &[]).await;
+            Ok(id)
+        }
+
+        async fn list(&self, uri: &str) -> Result<Vec<Value>, StoreError> {
+            let ttl = self.settings.list_ttl_secs;
+            if ttl == 0 {
+                return self.inner.list(uri).await;
+            }
+            let key = list_key(&self.settings.key_prefix, uri);
+            // 会话刚写过时, 缓存的值可能早于那次写入
+            let cached = if consistency::fresh_reads() { None } else { self.lookup(&key, "list").await };
+            if let Some(Value::Array(docs)) = cached {
+                return Ok(docs);
+            }
+            let docs = self.inner.list(uri).await?;
+            if docs.len() <= self.settings.list_max_documents {
+                self.fill(&key, &Value::Array(docs.clone()), ttl).await;
+            }
+            Ok(docs)
+        }
+
+        async fn get(&self, uri: &str, id: i64) -> Result<Option<Value>, StoreError> {
+            let ttl = self.settings.document_ttl_secs;
+            if ttl == 0 {
+                return self.inner.get(uri, id).await;
+            }
+            let key = document_key(&self.settings.key_prefix, uri, id);
+            let cached = if consistency::fresh_reads() { None } else { self.lookup(&key, "document").await };
+            if let Some(doc) = cached {
+                return Ok(Some(doc));
+            }
+            let doc = self.inner.get(uri, id).await?;
+            if let Some(doc) = &doc {
+                self.fill(&key, doc, ttl).await;
+            }
+            Ok(doc)
+        }
+
+        async fn get_many(&self, uri: &str, ids: &[i64]) -> Result<Vec<Value>, StoreError> {
+            self.inner.get_many(uri, ids).await
+        }
+
+        async fn insert_unique(&self, uri: &str, doc: &Value, hash: &str, mode: DedupMode) -> Result<Inserted, StoreError> {
+            let inserted = self.inner.insert_unique(uri, doc, hash, mode).await?;
+            self.invalidate(uri, &[inserted.id]).await;
+            Ok(inserted)
+        }
+
+        async fn upsert(&self, uri: &str, key: &str, doc: &Value) -> Result<Upserted, StoreError> {
+            let upserted = self.inner.upsert(uri, key, doc).await?;
+            if upserted.changed {
+                self.invalidate(uri, &[upserted.id]).await;
+            }
+            Ok(upserted)
+        }
+
+        async fn replace(&self, uri: &str, id: i64, doc: &Value, expected: Option<&[i64]>) -> Result<i64, StoreError> {
+            let version = self.inner.replace(uri, id, doc, expected).await?;
+            self.invalidate(uri, &[id]).await;
+            Ok(version)
+        }
+
+        async fn update(&self, uri: &str, id: i64, doc: &Value, expected: Option<&[i64]>) -> Result<i64, StoreError> {
+            let version = self.inner.update(uri, id, doc, expected).await?;
+            self.invalidate(uri, &[id]).await;
+            Ok(version)
+        }
+
+        async fn delete(&self, uri: &str, id: i64, expected: Option<&[i64]>) -> Result<(), StoreError> {
+            self.inner.delete(uri, id, expected).await?;
+            self.invalidate(uri, &[id]).await;
+            Ok(())
+        }
+
+        async fn update_many(&self, uri: &str, filter: &Value, update: &Value) -> Result<UpdatedMany, StoreError> {
+            let updated = self.inner.update_many(uri, filter, update).await?;
+            self.invalidate(uri, &updated.modified).await;
+            Ok(updated)
+        }
+
+        async fn delete_many(&self, uri: &str, filter: &Value, dry_run: bool) -> Result<Vec<i64>, StoreError> {
+            let deleted = self.inner.delete_many(uri, filter, dry_run).await?;
+            if !dry_run {
+                self.invalidate(uri, &deleted).await;
+            }
+            Ok(deleted)
+        }
+
+        async fn find(&self, uri: &str, filter: &Value) -> Result<Vec<Value>, StoreError> {
+            self.inner.find(uri, filter).await
+        }
+
+        async fn count(&self, uri: &str, filter: &Value) -> Result<u64, StoreError> {
+            self.inner.count(uri, filter).await
+        }
+
+        async fn find_page(&self, uri: &str, filter: &Value, page: &PageQuery) -> Result<Paged, StoreError> {
+            self.inner.find_page(uri, filter, page).await
+        }
+
+        async fn version(&self, uri: &str) -> Result<String, StoreError> {
+            self.inner.version(uri).await
+        }
+
+        async fn committed(&self, writes: &[Written]) {
+            self.inner.committed(writes).await;
+            let mut ids: BTreeMap<&str, Vec<i64>> = BTreeMap::new();
+            for write in writes {
+                ids.entry(&write.uri).or_default().push(write.id);
+            }
+            for (uri, ids) in ids {
+                self.invalidate(uri, &ids).await;
+            }
+        }
+    }
+}
@@ -0,0 +1,65 @@
+//! Key casing of documents in responses.
+//!
+//! Reads can rename the keys of the documents they return to camelCase or
+//! snake_case, nested objects included, with `?case=camel|snake` or the
+//! collection's `case` setting. Keys starting with `_`, such as `_version`,
+//! are left alone. Only responses are renamed: filters and writes still use
+//! the stored names.
+
+use serde_json::{Map, Value};
+
+use crate::config::KeyCase;
+
+/// Renames the keys of `doc` to `case`.
+pub fn convert(doc: Value, case: KeyCase) -> Value {
+    match doc {
+        Value::Object(fields) => {
+            let renamed: Map<String, Value> = fields
+                .into_iter()
+                .map(|(key, value)| {
+                    let key = match key.starts_with('_') {
+                        true => key,
+                        false => rename(&key, case),
+                    };
+                    (key, convert(value, case))
+                })
+                .collect();
+            Value::Object(renamed)
+        }
+        Value::Array(items) => Value::Array(items.into_iter().map(|item| convert(item, case)).collect()),
+        other => other,
+    }
+}
+
+/// `key` in `case`: `first_name` and `firstName` become each other, and an
+/// acronym stays one word, so `HTTPStatus` is `http_status`.
+pub fn rename(key: &str, case: KeyCase) -> String {
+    match case {
+        KeyCase::Camel => {
+            let mut words = key.split('_').filter(|word| !word.is_empty());
+            let mut camel = words.next().unwrap_or_default().to_string();
+            for word in words {
+                let mut chars = word.chars();
+                camel.extend(chars.next().map(|c| c.to_ascii_uppercase()));
+                camel.push_str(chars.as_str());
+            }
+            camel
+        }
+        KeyCase::Snake => {
+            let chars: Vec<char> = key.chars().collect();
+            let mut snake = String::with_capacity(key.len() + 4);
+            for (i, &c) in chars.iter().enumerate() {
+                if c.is_ascii_uppercase() && i > 0 {
+                    let previous = chars[i - 1];
+                    let next_lower = chars.get(i + 1).is_some_and(char::is_ascii_lowercase);
+                    // 小写或数字之后, 或缩写词的最后一个字母开始新词
+                    if previous.is_ascii_lowercase() || previous.is_ascii_digit() || (previous.is_ascii_uppercase() && next_lower) {
+                        snake.push('_');
+                    }
+                }
+                snake.push(c.to_ascii_lowercase());
+            }
+            snake
+        }
+    }
+}
@@ -0,0 +1,365 @@
+//! Change data capture.
+//!
+//! Every successful write through the store becomes a change event, handed
+//! to the [`ChangeFeed`] that WebSocket subscribers listen on and, with
+//! `kafka.changes` configured, published to the collection's Kafka topic
+//! keyed by document id so the events of one document stay in order:
+//!
+//! ```text
+//! {"op": "update", "collection": "users", "id": 7,
+//!  "before": {"id": 7, "_version": 1, "name": "Ann"},
+//!  "after": {"id": 7, "_version": 2, "name": "Anne"},
+//!  "timestamp": 1760000000000}
+//! ```
+//!
+//! `before` and `after` are only read for collections some sink wants, and
+//! they are read around the write rather than inside it, so under concurrent
+//! writes to the same document they may show a neighbouring version. Events
+//! are published after the write succeeds; a crash or a broker outage in
+//! between loses the event. Publishing to Kafka needs the `kafka` cargo
+//! feature; without it [`wrap`] only warns that changes are configured.
+
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::sync::broadcast;
+
+use crate::config::ConfigHandle;
+use crate::metrics::Metrics;
+use crate::store::{DedupMode, DocumentStore, Inserted, PageQuery, Paged, StoreError, UpdatedMany, Upserted, WriteOp, Written};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Op {
+    Insert,
+    Replace,
+    Update,
+    Delete,
+}
+
+/// One change event.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Change {
+    pub op: Op,
+    pub collection: String,
+    pub id: i64,
+    /// The document before the write; null for inserts, and for updates
+    /// made by a deduplicating upsert.
+    pub before: Option<Value>,
+    /// The document after the write; null for deletes.
+    pub after: Option<Value>,
+    /// Milliseconds since the Unix epoch.
+    pub timestamp: u64,
+}
+
+/// Where change events go.
+pub trait ChangeSink: Send + Sync {
+    /// Whether changes to `collection` are wanted; nothing is read for the others.
+    fn captures(&self, collection: &str) -> bool;
+
+    fn publish(&self, change: &Change);
+}
+
+/// Changes of this process, or of every instance when they are relayed
+/// through Redis, for the WebSocket subscribers.
+pub struct ChangeFeed {
+    sender: broadcast::Sender<Arc<Change>>,
+    relay: Option<Box<dyn ChangeSink>>,
+}
+
+/// Events a slow subscriber can fall behind by before it misses some.
+const FEED_CAPACITY: usize = 1024;
+
+impl ChangeFeed {
+    /// With a `relay`, changes are handed to it instead of the local
+    /// subscribers, and come back through [`ChangeFeed::deliver`].
+    pub fn new(relay: Option<Box<dyn ChangeSink>>) -> Self {
+        ChangeFeed { sender: broadcast::channel(FEED_CAPACITY).0, relay }
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<Arc<Change>> {
+        self.sender.subscribe()
+    }
+
+    /// Whether changes go through a relay that hands them to every instance.
+    pub fn relayed(&self) -> bool {
+        self.relay.is_some()
+    }
+
+    /// Hands `change` to the local subscribers.
+    pub fn deliver(&self, change: Change) {
+        let _ = self.sender.send(Arc::new(change));
+    }
+}
+
+impl ChangeSink for ChangeFeed {
+    fn captures(&self, _collection: &str) -> bool {
+        self.relay.is_some() || self.sender.receiver_count() > 0
+    }
+
+    fn publish(&self, change: &Change) {
+        match &self.relay {
+            Some(relay) => relay.publish(change),
+            None => self.deliver(change.clone()),
+        }
+    }
+}
+
+/// A [`DocumentStore`] that passes every call on to `inner` and publishes
+/// the writes to the sinks that capture the collection.
+pub struct ChangeStore {
+    inner: Arc<dyn DocumentStore>,
+    sinks: Vec<Arc<dyn ChangeSink>>,
+}
+
+impl ChangeStore {
+    pub fn new(inner: Arc<dyn DocumentStore>, sinks: Vec<Arc<dyn ChangeSink>>) -> Self {
+        ChangeStore { inner, sinks }
+    }
+
+    fn captured(&self, uri: &str) -> bool {
+        self.sinks.iter().any(|sink| sink.captures(uri))
+    }
+
+    // 没有 sink 关心的集合不读取前后文档
+    async fn before(&self, uri: &str, id: i64) -> Option<Value> {
+        if !self.captured(uri) {
+            return None;
+        }
+        self.inner.get(uri, id).await.ok().flatten()
+    }
+
+    async fn emit(&self, op: Op, uri: &str, id: i64, before: Option<Value>) {
+        if !self.captured(uri) {
+            return;
+        }
+        let after = match op {
+            Op::Delete => None,
+            _ => self.inner.get(uri, id).await.ok().flatten(),
+        };
+        let change = Change { op, collection: uri.to_string(), id, before, after, timestamp: now_millis() };
+        for sink in self.sinks.iter().filter(|sink| sink.captures(uri)) {
+            sink.publish(&change);
+        }
+    }
+}
+
+#[async_trait]
+impl DocumentStore for ChangeStore {
+    async fn insert(&self, uri: &str, doc: &Value) -> Result<i64, StoreError> {
+        let id = self.inner.insert(uri, doc).await?;
+        self.emit(Op::Insert, uri, id, None).await;
+        Ok(id)
+    }
+
+    async fn list(&self, uri: &str) -> Result<Vec<Value>, StoreError> {
+        self.inner.list(uri).await
+    }
+
+    async fn get(&self, uri: &str, id: i64) -> Result<Option<Value>, StoreError> {
+        self.inner.get(uri, id).await
+    }
+
+    async fn get_many(&self, uri: &str, ids: &[i64]) -> Result<Vec<Value>, StoreError> {
+        self.inner.get_many(uri, ids).await
+    }
+
+    async fn insert_unique(&self, uri: &str, doc: &Value, hash: &str, mode: DedupMode) -> Result<Inserted, StoreError> {
+        let inserted = self.inner.insert_unique(uri, doc, hash, mode).await?;
+        match (inserted.duplicate, mode) {
+            (false, _) => self.emit(Op::Insert, uri, inserted.id, None).await,
+            (true, DedupMode::Upsert) => self.emit(Op::Update, uri, inserted.id, None).await,
+            (true, DedupMode::Skip) => {}
+        }
+        Ok(inserted)
+    }
+
+    async fn upsert(&self, uri: &str, key: &str, doc: &Value) -> Result<Upserted, StoreError> {
+        let upserted = self.inner.upsert(uri, key, doc).await?;
+        match (upserted.changed, upserted.existed) {
+            (false, _) => {}
+            (true, false) => self.emit(Op::Insert, uri, upserted.id, None).await,
+            (true, true) => self.emit(Op::Update, uri, upserted.id, None).await,
+        }
+        Ok(upserted)
+    }
+
+    async fn replace(&self, uri: &str, id: i64, doc: &Value, expected: Option<&[i64]>) -> Result<i64, StoreError> {
+        let before = self.before(uri, id).await;
+        let version = self.inner.replace(uri, id, doc, expected).await?;
+        self.emit(Op::Replace, uri, id, before).await;
+        Ok(version)
+    }
+
+    async fn update(&self, uri: &str, id: i64, doc: &Value, expected: Option<&[i64]>) -> Result<i64, StoreError> {
+        let before = self.before(uri, id).await;
+        let version = self.inner.update(uri, id, doc, expected).await?;
+        self.emit(Op::Update, uri, id, before).await;
+        Ok(version)
+    }
+
+    async fn delete(&self, uri: &str, id: i64, expected: Option<&[i64]>) -> Result<(), StoreError> {
+        let before = self.before(uri, id).await;
+        self.inner.delete(uri, id, expected).await?;
+        self.emit(Op::Delete, uri, id, before).await;
+        Ok(())
+    }
+
+    // 批量修改和删除不读取之前的文档, 事件中没有 before
+    async fn update_many(&self, uri: &str, filter: &Value, update: &Value) -> Result<UpdatedMany, StoreError> {
+        let updated = self.inner.update_many(uri, filter, update).await?;
+        for id in &updated.modified {
+            self.emit(Op::Update, uri, *id, None).await;
+        }
+        Ok(updated)
+    }
+
+    async fn delete_many(&self, uri: &str, filter: &Value, dry_run: bool) -> Result<Vec<i64>, StoreError> {
+        let deleted = self.inner.delete_many(uri, filter, dry_run).await?;
+        if !dry_run {
+            for id in &deleted {
+                self.emit(Op::Delete, uri, *id, None).await;
+            }
+        }
+        Ok(deleted)
+    }
+
+    async fn find(&self, uri: &str, filter: &Value) -> Result<Vec<Value>, StoreError> {
+        self.inner.find(uri, filter).await
+    }
+
+    async fn count(&self, uri: &str, filter: &Value) -> Result<u64, StoreError> {
+        self.inner.count(uri, filter).await
+    }
+
+    async fn find_page(&self, uri: &str, filter: &Value, page: &PageQuery) -> Result<Paged, StoreError> {
+        self.inner.find_page(uri, filter, page).await
+    }
+
+    async fn version(&self, uri: &str) -> Result<String, StoreError> {
+        self.inner.version(uri).await
+    }
+
+    // 事务提交之后才发布, 事件中没有 before
+    async fn committed(&self, writes: &[Written]) {
+        self.inner.committed(writes).await;
+        for write in writes {
+            let op = match write.op {
+                WriteOp::Insert => Op::Insert,
+                WriteOp::Replace => Op::Replace,
+                WriteOp::Update => Op::Update,
+                WriteOp::Delete => Op::Delete,
+            };
+            self.emit(op, &write.uri, write.id, None).await;
+        }
+    }
+}
+
+/// Wraps `store` in a [`ChangeStore`] publishing to `feed` and, when
+/// `kafka.changes` is configured, to Kafka.
+#[cfg(feature = "kafka")]
+pub fn wrap(
+    config: &ConfigHandle,
+    store: Arc<dyn DocumentStore>,
+    feed: Arc<ChangeFeed>,
+    metrics: Arc<Metrics>,
+) -> Arc<dyn DocumentStore> {
+    let mut sinks: Vec<Arc<dyn ChangeSink>> = vec![feed];
+    if let Some(settings) = config.get().kafka.clone().filter(|k| !k.changes.is_empty()) {
+        match producer::KafkaSink::new(settings, metrics) {
+            Ok(sink) => sinks.push(Arc::new(sink)),
+            Err(e) => log::error!("kafka producer could not be created, changes are not published: {}", e),
+        }
+    }
+    Arc::new(ChangeStore::new(store, sinks))
+}
+
+#[cfg(not(feature = "kafka"))]
+pub fn wrap(
+    config: &ConfigHandle,
+    store: Arc<dyn DocumentStore>,
+    feed: Arc<ChangeFeed>,
+    _metrics: Arc<Metrics>,
+) -> Arc<dyn DocumentStore> {
+    if config.get().kafka.as_ref().is_some_and(|k| !k.changes.is_empty()) {
+        log::warn!("kafka changes are configured but the server was built without the `kafka` feature");
+    }
+    Arc::new(ChangeStore::new(store, vec![feed]))
+}
+
+pub(crate) fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or_default()
+}
+
+#[cfg(feature = "kafka")]
+mod producer {
+    use rdkafka::error::KafkaError;
+    use rdkafka::producer::{FutureProducer, FutureRecord};
+    use rdkafka::ClientConfig;
+    use std::sync::Arc;
+
+    use super::{Change, ChangeSink};
+    use crate::config::Kafka;
+    use crate::metrics::Metrics;
+
+    pub struct KafkaSink {
+        producer: FutureProducer,
+        settings: Kafka,
+        metrics: Arc<Metrics>,
+    }
+
+    impl KafkaSink {
+        pub fn new(settings: Kafka, metrics: Arc<Metrics>) -> Result<Self, KafkaError> {
+            let mut client = ClientConfig::new();
+            // 幂等发送, 重试时同一文档的事件不会乱序
+            client.set("bootstrap.servers", &settings.brokers).set("enable.idempotence", "true");
+            for (key, value) in &settings.properties {
+                client.set(key, value);
+            }
+            Ok(KafkaSink { producer: client.create()?, settings, metrics })
+        }
+    }
+
+    impl ChangeSink for KafkaSink {
+        fn captures(&self, collection: &str) -> bool {
+            self.settings.change_topic(collection).is_some()
+        }
+
+        fn publish(&self, change: &Change) {
+            let Some(topic) = self.settings.change_topic(&change.collection) else {
+                return;
+            };
+            let payload = serde_json::to_vec(change).unwrap_or_default();
+            let key = change.id.to_string();
+            let record = FutureRecord::to(topic).key(&key).payload(&payload);
+            let delivery = match self.producer.send_result(record) {
+                Ok(delivery) => delivery,
+                Err((e, _)) => {
+                    log::warn!("kafka failed to queue change of {}/{}: {}", change.collection, change.id, e);
+                    self.metrics.incr("cdc_events_total", vec![("topic", topic.to_string()), ("status", "failed".to_string())], 1.0);
+                    return;
+                }
+            };
+
+            let metrics = self.metrics.clone();
+            let topic = topic.to_string();
+            let (collection, id) = (change.collection.clone(), change.id);
+            tokio::spawn(async move {
+                let status = match delivery.await {
+                    Ok(Ok(_)) => "delivered",
+                    Ok(Err((e, _))) => {
+                        log::warn!("kafka failed to deliver change of {}/{}: {}", collection, id, e);
+                        "failed"
+                    }
+                    Err(_) => "failed",
+                };
+                metrics.incr("cdc_events_total", vec![("topic", topic), ("status", status.to_string())], 1.0);
+            });
+        }
+    }
+}
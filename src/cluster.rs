@@ -0,0 +1,1305 @@
+//! Cluster mode.
+//!
+//! With `cluster` configured, the node asks its peers for their status every
+//! `heartbeat_ms`. A node that has heard from no leader for
+//! `election_timeout_ms` while it can reach a majority of the cluster starts
+//! an election: the reachable node with the most replicated data (ties go to
+//! the lowest `node_id`) takes the next term and leads. A leader that loses
+//! contact with a majority steps down, so a minority partition never accepts
+//! writes.
+//!
+//! Only the leader writes to its database. Followers serve reads from their
+//! own copy and send every write to the leader over `/_cluster/write`, then
+//! wait briefly until they have replicated it, so a client reading from the
+//! node it wrote through sees its write. The leader logs the id of every
+//! document it writes in `_cluster_log`; followers pull that log and copy the
+//! current version of each document in it. A follower that starts following
+//! a new leader, or falls further behind than the log reaches, first copies
+//! every collection.
+//!
+//! Replication is asynchronous and the election is not a consensus protocol:
+//! writes a leader accepted but had not yet replicated are lost when another
+//! node takes over, and so are writes to a node that briefly led alongside
+//! another. Only collections are replicated; ACL entries, idempotency keys and
+//! the other `_` tables stay local to each node. Peers need the `cluster`
+//! cargo feature; without it [`start`] refuses to run with `cluster` set.
+
+use actix_web::body::{EitherBody, MessageBody};
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::http::StatusCode;
+use actix_web::middleware::{from_fn, Next};
+use actix_web::{web, Error, HttpResponse};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use sqlx::{Row, SqlitePool};
+use std::cmp::Reverse;
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicI64, Ordering};
+use std::sync::{Arc, RwLock, RwLockReadGuard, RwLockWriteGuard};
+use std::time::{Duration, Instant};
+use tokio::sync::watch;
+
+use crate::auth::{self, constant_time_eq};
+use crate::cdc::ChangeFeed;
+use crate::config::{Cluster, ConfigHandle, Role};
+use crate::database::{collection_exists, list_collections, row_to_json, table_columns, table_name};
+use crate::metrics::Metrics;
+use crate::query::quote;
+use crate::store::{DedupMode, DocumentStore, StoreError};
+
+/// Header carrying `cluster.secret` on requests between nodes.
+pub const SECRET_HEADER: &str = "X-Cluster-Secret";
+
+/// Largest write a follower forwards, same as a WebSocket frame.
+const MAX_BODY: usize = 2 * 1024 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum NodeRole {
+    Leader,
+    Follower,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Peer {
+    pub node_id: String,
+    pub url: String,
+}
+
+/// How much of which leader's log a node holds; later terms rank higher.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct Position {
+    pub term: u64,
+    pub seq: i64,
+}
+
+/// What a node reports at `/_cluster/status`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Status {
+    pub node_id: String,
+    pub url: String,
+    pub term: u64,
+    pub role: NodeRole,
+    /// The leader a follower currently hears from.
+    pub leader: Option<Peer>,
+    pub position: Position,
+}
+
+impl Status {
+    // 比较两个 leader: term 高者优先, 其次数据多者, 最后 node_id 小者
+    fn rank(&self) -> (u64, Position, Reverse<&str>) {
+        (self.term, self.position, Reverse(self.node_id.as_str()))
+    }
+}
+
+struct State {
+    term: u64,
+    role: NodeRole,
+    leader: Option<Peer>,
+    /// Replication progress while following.
+    position: Position,
+    leader_seen: Instant,
+    majority_seen: Instant,
+}
+
+/// This node's view of the cluster.
+pub struct Node {
+    settings: Cluster,
+    pool: SqlitePool,
+    state: RwLock<State>,
+    /// Last sequence number in `_cluster_log`.
+    log_seq: AtomicI64,
+    /// Last log entry applied here.
+    applied: watch::Sender<Position>,
+}
+
+impl Node {
+    /// Loads the term and replication progress saved by an earlier run.
+    pub async fn open(settings: Cluster, pool: SqlitePool) -> Result<Self, sqlx::Error> {
+        let saved: BTreeMap<String, i64> = sqlx::query_as("SELECT key, value FROM _cluster_state")
+            .fetch_all(&pool)
+            .await?
+            .into_iter()
+            .collect();
+        let log_seq: i64 = sqlx::query_scalar("SELECT COALESCE(MAX(seq), 0) FROM _cluster_log").fetch_one(&pool).await?;
+        let saved = |key: &str| saved.get(key).copied().unwrap_or_default();
+        let position = Position { term: saved("position_term") as u64, seq: saved("position_seq") };
+        let now = Instant::now();
+        let state = State {
+            term: saved("term") as u64,
+            role: NodeRole::Follower,
+            leader: None,
+            position,
+            leader_seen: now,
+            majority_seen: now,
+        };
+        Ok(Node {
+            settings,
+            pool,
+            state: RwLock::new(state),
+            log_seq: AtomicI64::new(log_seq),
+            applied: watch::Sender::new(position),
+        })
+    }
+
+    // 状态的每次改动都不会中途 panic, 锁中毒时其中的值仍然完整
+    fn read_state(&self) -> RwLockReadGuard<'_, State> {
+        self.state.read().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn write_state(&self) -> RwLockWriteGuard<'_, State> {
+        self.state.write().unwrap_or_else(|e| e.into_inner())
+    }
+
+    pub fn status(&self) -> Status {
+        let state = self.read_state();
+        let position = match state.role {
+            NodeRole::Leader => Position { term: state.term, seq: self.log_seq() },
+            NodeRole::Follower => state.position,
+        };
+        Status {
+            node_id: self.settings.node_id.clone(),
+            url: self.settings.advertise_url.clone(),
+            term: state.term,
+            role: state.role,
+            leader: state.leader.clone(),
+            position,
+        }
+    }
+
+    pub fn is_leader(&self) -> bool {
+        self.read_state().role == NodeRole::Leader
+    }
+
+    /// The leader this node follows and its term, while it hears from one.
+    pub fn following(&self) -> Option<(Peer, u64)> {
+        let state = self.read_state();
+        state.leader.clone().map(|leader| (leader, state.term))
+    }
+
+    pub fn log_seq(&self) -> i64 {
+        self.log_seq.load(Ordering::SeqCst)
+    }
+
+    /// Adds a write to document `id` of `uri` to the log followers pull.
+    pub async fn record(&self, uri: &str, id: i64) {
+        let appended = sqlx::query("INSERT INTO _cluster_log (collection, document_id) VALUES (?, ?)")
+            .bind(uri)
+            .bind(id)
+            .execute(&self.pool)
+            .await;
+        match appended {
+            Ok(result) => {
+                self.log_seq.fetch_max(result.last_insert_rowid(), Ordering::SeqCst);
+            }
+            Err(e) => log::error!("cluster failed to log the write to {}/{}, followers will miss it: {}", uri, id, e),
+        }
+    }
+
+    /// Drops log entries beyond the newest `log_retention`.
+    pub async fn trim_log(&self) {
+        let keep_after = self.log_seq() - self.settings.log_retention;
+        if keep_after <= 0 {
+            return;
+        }
+        if let Err(e) = sqlx::query("DELETE FROM _cluster_log WHERE seq <= ?").bind(keep_after).execute(&self.pool).await {
+            log::warn!("cluster failed to trim its log: {}", e);
+        }
+    }
+
+    /// Updates this node's view from the statuses of the peers that answered.
+    /// Returns whether its term or role changed.
+    pub fn observe(&self, peers: &[Status]) -> bool {
+        let timeout = Duration::from_millis(self.settings.election_timeout_ms);
+        let now = Instant::now();
+        let me = self.status();
+        let mut state = self.write_state();
+        let before = (state.term, state.role);
+
+        if (peers.len() + 1) * 2 > self.settings.peers.len() + 1 {
+            state.majority_seen = now;
+        }
+        let leader = peers.iter().filter(|s| s.role == NodeRole::Leader).max_by(|a, b| a.rank().cmp(&b.rank()));
+
+        match (state.role, leader) {
+            (NodeRole::Leader, _) if now.duration_since(state.majority_seen) > timeout => {
+                log::warn!("cluster lost contact with a majority, stepping down at term {}", state.term);
+                state.position = me.position;
+                state.role = NodeRole::Follower;
+                state.leader_seen = now;
+            }
+            (NodeRole::Leader, Some(other)) if other.rank() > me.rank() => {
+                log::warn!("cluster node {} leads at term {}, stepping down", other.node_id, other.term);
+                state.position = me.position;
+                state.role = NodeRole::Follower;
+                follow(&mut state, other, now);
+            }
+            (NodeRole::Leader, _) => {}
+            (NodeRole::Follower, Some(other)) => follow(&mut state, other, now),
+            (NodeRole::Follower, None) => {
+                if now.duration_since(state.leader_seen) <= timeout {
+                    return false;
+                }
+                if let Some(lost) = state.leader.take() {
+                    log::warn!("cluster lost contact with leader {}", lost.node_id);
+                }
+                // 还有节点能联系到 leader, 或者联系不到多数节点时不选举
+                let majority = now.duration_since(state.majority_seen).is_zero();
+                if !majority || peers.iter().any(|s| s.leader.is_some()) {
+                    return false;
+                }
+                let best = peers
+                    .iter()
+                    .map(|s| (s.position, Reverse(s.node_id.as_str())))
+                    .max()
+                    .is_none_or(|best| (me.position, Reverse(me.node_id.as_str())) > best);
+                if best {
+                    state.term = peers.iter().map(|s| s.term).max().unwrap_or_default().max(state.term) + 1;
+                    state.role = NodeRole::Leader;
+                    state.majority_seen = now;
+                    log::info!("cluster elected this node leader at term {}", state.term);
+                }
+            }
+        }
+        before != (state.term, state.role)
+    }
+
+    /// Records replication up to `position` and saves it.
+    pub async fn replicated(&self, position: Position) {
+        {
+            let mut state = self.write_state();
+            if state.position == position {
+                return;
+            }
+            state.position = position;
+        }
+        self.applied.send_replace(position);
+        self.save("position_term", position.term as i64).await;
+        self.save("position_seq", position.seq).await;
+    }
+
+    /// Waits up to `timeout` until this node holds the log up to `position`.
+    /// A leader holds its own log, and a position of an earlier leader counts
+    /// as held once this node replicates a later one.
+    pub async fn wait_applied(&self, position: Position, timeout: Duration) -> bool {
+        if self.is_leader() {
+            return self.status().position >= position;
+        }
+        let mut applied = self.applied.subscribe();
+        let caught_up = tokio::time::timeout(timeout, applied.wait_for(|applied| *applied >= position)).await;
+        caught_up.is_ok()
+    }
+
+    /// Saves the current term, so a restarted node never reuses one.
+    pub async fn save_term(&self) {
+        let term = self.read_state().term;
+        self.save("term", term as i64).await;
+    }
+
+    async fn save(&self, key: &str, value: i64) {
+        let saved = sqlx::query(
+            "INSERT INTO _cluster_state (key, value) VALUES (?, ?) ON CONFLICT (key) DO UPDATE SET value = excluded.value",
+        )
+        .bind(key)
+        .bind(value)
+        .execute(&self.pool)
+        .await;
+        if let Err(e) = saved {
+            log::warn!("cluster failed to save {}: {}", key, e);
+        }
+    }
+}
+
+fn follow(state: &mut State, leader: &Status, now: Instant) {
+    let peer = Peer { node_id: leader.node_id.clone(), url: leader.url.clone() };
+    if state.leader.as_ref() != Some(&peer) || state.term != leader.term {
+        log::info!("cluster following {} at term {}", leader.node_id, leader.term);
+    }
+    state.term = leader.term;
+    state.leader = Some(peer);
+    state.leader_seen = now;
+}
+
+/// Joins the cluster when `cluster` is configured: wraps `store` so writes
+/// go to the leader, and starts the heartbeat and replication tasks.
+#[cfg(feature = "cluster")]
+pub async fn start(
+    config: &ConfigHandle,
+    store: Arc<dyn DocumentStore>,
+    pool: SqlitePool,
+    feed: Arc<ChangeFeed>,
+    metrics: Arc<Metrics>,
+) -> Result<(Arc<dyn DocumentStore>, Option<Arc<Node>>), sqlx::Error> {
+    let Some(settings) = config.get().cluster.clone() else {
+        return Ok((store, None));
+    };
+    let node = Arc::new(Node::open(settings, pool).await?);
+    let client = remote::client();
+    remote::spawn(node.clone(), client.clone(), feed, metrics);
+    Ok((Arc::new(remote::ClusterStore::new(store, node.clone(), client)), Some(node)))
+}
+
+#[cfg(not(feature = "cluster"))]
+pub async fn start(
+    config: &ConfigHandle,
+    store: Arc<dyn DocumentStore>,
+    _pool: SqlitePool,
+    _feed: Arc<ChangeFeed>,
+    _metrics: Arc<Metrics>,
+) -> Result<(Arc<dyn DocumentStore>, Option<Arc<Node>>), sqlx::Error> {
+    if config.get().cluster.is_some() {
+        // 单独运行会让节点间的数据分叉, 所以直接拒绝启动
+        panic!("cluster is configured but the server was built without the `cluster` feature");
+    }
+    Ok((store, None))
+}
+
+// 注册节点之间使用的接口
+pub fn configure(cfg: &mut web::ServiceConfig, node: Option<Arc<Node>>) {
+    let Some(node) = node else {
+        return;
+    };
+    cfg.app_data(web::Data::from(node)).service(
+        web::scope("/_cluster")
+            .app_data(web::JsonConfig::default().limit(MAX_BODY))
+            .wrap(from_fn(require_peer))
+            .route("/status", web::get().to(status))
+            .route("/snapshot", web::get().to(snapshot))
+            .route("/snapshot/{collection}", web::get().to(snapshot_collection))
+            .route("/log", web::get().to(log_page))
+            .route("/write", web::post().to(write)),
+    );
+}
+
+/// Lets through requests carrying the cluster secret, and admins.
+pub async fn require_peer(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<EitherBody<impl MessageBody>>, Error> {
+    let secret = req.app_data::<web::Data<Node>>().map(|node| node.settings.secret.clone());
+    let presented = req.headers().get(SECRET_HEADER).map(|v| v.as_bytes());
+    let peer = matches!((&secret, presented), (Some(secret), Some(presented)) if constant_time_eq(secret.as_bytes(), presented));
+    if !peer {
+        if let Err(response) = auth::check_role(&req, Role::Admin) {
+            return Ok(req.into_response(response).map_into_right_body());
+        }
+    }
+    next.call(req).await.map(ServiceResponse::map_into_left_body)
+}
+
+async fn status(node: web::Data<Node>) -> HttpResponse {
+    HttpResponse::Ok().json(node.status())
+}
+
+fn not_leader() -> HttpResponse {
+    HttpResponse::Conflict().json("This node is not the cluster leader")
+}
+
+/// Start of a full copy: the log position it is current as of, and the collections to copy.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Snapshot {
+    pub term: u64,
+    pub seq: i64,
+    pub collections: Vec<Table>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Table {
+    pub name: String,
+    pub columns: Vec<(String, String)>,
+}
+
+// 先记下日志位置再列出集合, 之后的写入由日志补上
+async fn snapshot(node: web::Data<Node>) -> HttpResponse {
+    if !node.is_leader() {
+        return not_leader();
+    }
+    let (term, seq) = (node.status().term, node.log_seq());
+    let tables = match list_collections(&node.pool).await {
+        Ok(tables) => tables,
+        Err(e) => return HttpResponse::InternalServerError().json(format!("Failed to list collections: {}", e)),
+    };
+    let mut collections = Vec::new();
+    for name in tables {
+        match table_columns(&node.pool, &name).await {
+            Ok(columns) => collections.push(Table { name, columns }),
+            Err(e) => return HttpResponse::InternalServerError().json(format!("Failed to read columns: {}", e)),
+        }
+    }
+    HttpResponse::Ok().json(Snapshot { term, seq, collections })
+}
+
+async fn snapshot_collection(node: web::Data<Node>, path: web::Path<String>) -> HttpResponse {
+    if !node.is_leader() {
+        return not_leader();
+    }
+    let table = path.into_inner();
+    match list_collections(&node.pool).await {
+        Ok(tables) if tables.contains(&table) => {}
+        Ok(_) => return HttpResponse::NotFound().json(format!("No collection '{}'", table)),
+        Err(e) => return HttpResponse::InternalServerError().json(format!("Failed to list collections: {}", e)),
+    }
+    match sqlx::query(&format!("SELECT * FROM {}", quote(&table))).fetch_all(&node.pool).await {
+        Ok(rows) => HttpResponse::Ok().json(rows.iter().map(row_to_json).collect::<Vec<_>>()),
+        Err(e) => HttpResponse::InternalServerError().json(format!("Failed to query data: {}", e)),
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct LogParams {
+    pub after: i64,
+    pub limit: Option<i64>,
+}
+
+const MAX_PAGE: i64 = 500;
+
+/// Log entries after a position, each with the document as it is now
+/// (`null` once deleted), and the columns of the tables they touch.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct LogPage {
+    pub term: u64,
+    pub entries: Vec<Entry>,
+    pub columns: BTreeMap<String, Vec<(String, String)>>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Entry {
+    pub seq: i64,
+    pub collection: String,
+    pub id: i64,
+    pub document: Option<Value>,
+}
+
+// 日志已被截断到请求的位置之后时返回 410, follower 需要完整复制
+async fn log_page(node: web::Data<Node>, params: web::Query<LogParams>) -> HttpResponse {
+    if !node.is_leader() {
+        return not_leader();
+    }
+    let term = node.status().term;
+    let limit = params.limit.unwrap_or(MAX_PAGE).clamp(1, MAX_PAGE);
+    let oldest: Option<i64> = match sqlx::query_scalar("SELECT MIN(seq) FROM _cluster_log").fetch_one(&node.pool).await {
+        Ok(oldest) => oldest,
+        Err(e) => return HttpResponse::InternalServerError().json(format!("Failed to read the log: {}", e)),
+    };
+    if params.after < oldest.unwrap_or(node.log_seq() + 1) - 1 {
+        return HttpResponse::Gone().json("The log no longer reaches that far back");
+    }
+
+    let rows = sqlx::query("SELECT seq, collection, document_id FROM _cluster_log WHERE seq > ? ORDER BY seq LIMIT ?")
+        .bind(params.after)
+        .bind(limit)
+        .fetch_all(&node.pool)
+        .await;
+    let rows = match rows {
+        Ok(rows) => rows,
+        Err(e) => return HttpResponse::InternalServerError().json(format!("Failed to read the log: {}", e)),
+    };
+    let mut page = LogPage { term, entries: Vec::new(), columns: BTreeMap::new() };
+    for row in rows {
+        let (seq, collection, id): (i64, String, i64) = (row.get(0), row.get(1), row.get(2));
+        let table = table_name(&collection);
+        let document = match current_document(&node.pool, &table, id).await {
+            Ok(document) => document,
+            Err(e) => return HttpResponse::InternalServerError().json(format!("Failed to query data: {}", e)),
+        };
+        if document.is_some() && !page.columns.contains_key(&table) {
+            match table_columns(&node.pool, &table).await {
+                Ok(columns) => page.columns.insert(table, columns),
+                Err(e) => return HttpResponse::InternalServerError().json(format!("Failed to read columns: {}", e)),
+            };
+        }
+        page.entries.push(Entry { seq, collection, id, document });
+    }
+    HttpResponse::Ok().json(page)
+}
+
+async fn current_document(pool: &SqlitePool, table: &str, id: i64) -> Result<Option<Value>, sqlx::Error> {
+    if !collection_exists(pool, table).await? {
+        return Ok(None);
+    }
+    let row = sqlx::query(&format!("SELECT * FROM {} WHERE id = ?", quote(table))).bind(id).fetch_optional(pool).await?;
+    Ok(row.as_ref().map(row_to_json))
+}
+
+/// A write a follower forwards to the leader.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub enum Write {
+    Insert { collection: String, document: Value },
+    InsertUnique { collection: String, document: Value, hash: String, mode: DedupMode },
+    Upsert { collection: String, key: String, document: Value },
+    Replace { collection: String, id: i64, document: Value, expected: Option<Vec<i64>> },
+    Update { collection: String, id: i64, document: Value, expected: Option<Vec<i64>> },
+    Delete { collection: String, id: i64, expected: Option<Vec<i64>> },
+    UpdateMany { collection: String, filter: Value, update: Value },
+    DeleteMany { collection: String, filter: Value },
+}
+
+/// The store's result, and the log position the follower waits for.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct WriteReply {
+    pub result: Value,
+    pub seq: i64,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FailureKind {
+    NotFound,
+    VersionConflict,
+    Schema,
+    Database,
+    Unavailable,
+    Invalid,
+}
+
+/// A [`StoreError`] sent back to the follower.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct WriteFailure {
+    pub kind: FailureKind,
+    pub message: String,
+    pub current: Option<i64>,
+}
+
+impl WriteFailure {
+    fn new(e: StoreError) -> (StatusCode, Self) {
+        let message = e.to_string();
+        let (status, kind, current) = match e {
+            StoreError::NotFound => (StatusCode::NOT_FOUND, FailureKind::NotFound, None),
+            StoreError::VersionConflict { current } => {
+                (StatusCode::PRECONDITION_FAILED, FailureKind::VersionConflict, Some(current))
+            }
+            StoreError::Schema(_) => (StatusCode::INTERNAL_SERVER_ERROR, FailureKind::Schema, None),
+            StoreError::Database(_) => (StatusCode::INTERNAL_SERVER_ERROR, FailureKind::Database, None),
+            StoreError::Unavailable(_) => (StatusCode::SERVICE_UNAVAILABLE, FailureKind::Unavailable, None),
+            StoreError::Invalid(_) => (StatusCode::BAD_REQUEST, FailureKind::Invalid, None),
+        };
+        (status, WriteFailure { kind, message, current })
+    }
+
+    pub fn into_error(self) -> StoreError {
+        match self.kind {
+            FailureKind::NotFound => StoreError::NotFound,
+            FailureKind::VersionConflict => StoreError::VersionConflict { current: self.current.unwrap_or_default() },
+            FailureKind::Schema => StoreError::Schema(sqlx::Error::Protocol(self.message)),
+            FailureKind::Database => StoreError::Database(sqlx::Error::Protocol(self.message)),
+            FailureKind::Unavailable => StoreError::Unavailable(self.message),
+            FailureKind::Invalid => StoreError::Invalid(self.message),
+        }
+    }
+}
+
+// 在 leader 上执行 follower 转发的写入
+async fn write(node: web::Data<Node>, store: web::Data<dyn DocumentStore>, body: web::Json<Write>) -> HttpResponse {
+    if !node.is_leader() {
+        let (status, failure) = WriteFailure::new(StoreError::Unavailable("the node is no longer the leader".to_string()));
+        return HttpResponse::build(status).json(failure);
+    }
+    let store = store.get_ref();
+    let result = match body.into_inner() {
+        Write::Insert { collection, document } => store.insert(&collection, &document).await.map(|id| json!(id)),
+        Write::InsertUnique { collection, document, hash, mode } => store
+            .insert_unique(&collection, &document, &hash, mode)
+            .await
+            .map(|inserted| json!(inserted)),
+        Write::Upsert { collection, key, document } => {
+            store.upsert(&collection, &key, &document).await.map(|upserted| json!(upserted))
+        }
+        Write::Replace { collection, id, document, expected } => {
+            store.replace(&collection, id, &document, expected.as_deref()).await.map(|version| json!(version))
+        }
+        Write::Update { collection, id, document, expected } => {
+            store.update(&collection, id, &document, expected.as_deref()).await.map(|version| json!(version))
+        }
+        Write::Delete { collection, id, expected } => {
+            store.delete(&collection, id, expected.as_deref()).await.map(|()| Value::Null)
+        }
+        Write::UpdateMany { collection, filter, update } => {
+            store.update_many(&collection, &filter, &update).await.map(|updated| json!(updated))
+        }
+        Write::DeleteMany { collection, filter } => store.delete_many(&collection, &filter, false).await.map(|ids| json!(ids)),
+    };
+    match result {
+        Ok(result) => HttpResponse::Ok().json(WriteReply { result, seq: node.log_seq() }),
+        Err(e) => {
+            let (status, failure) = WriteFailure::new(e);
+            HttpResponse::build(status).json(failure)
+        }
+    }
+}
+
+#[cfg(feature = "cluster")]
+mod remote {
+    use async_trait::async_trait;
+    use reqwest::StatusCode;
+    use serde::de::DeserializeOwned;
+    use serde_json::Value;
+    use sqlx::sqlite::SqliteConnection;
+    use sqlx::SqlitePool;
+    use std::sync::Arc;
+    use std::time::Duration;
+    use tokio::task::JoinSet;
+
+    use super::{current_document, Entry, LogPage, Node, NodeRole, Peer, Position, Snapshot, Status, Write, WriteFailure, WriteReply, MAX_PAGE, SECRET_HEADER};
+    use crate::cdc::{now_millis, Change, ChangeFeed, ChangeSink, Op};
+    use crate::consistency;
+    use crate::database::{bind_value, collection_exists, list_collections, schema_changed, table_columns, table_name};
+    use crate::metrics::Metrics;
+    use crate::query::quote;
+    use crate::store::{DedupMode, DocumentStore, Inserted, PageQuery, Paged, StoreError, UpdatedMany, Upserted, Written, VERSION_FIELD};
+
+    /// Requests for a whole collection or a forwarded write.
+    const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+    /// How long a follower waits to replicate its own write before answering anyway.
+    const CATCH_UP: Duration = Duration::from_secs(2);
+    /// Pause between log polls once a follower has caught up.
+    const POLL_INTERVAL: Duration = Duration::from_millis(200);
+    const RETRY_DELAY: Duration = Duration::from_secs(2);
+
+    pub fn client() -> reqwest::Client {
+        reqwest::Client::builder()
+            .timeout(REQUEST_TIMEOUT)
+            .build()
+            .expect("HTTP client settings are valid")
+    }
+
+    pub fn spawn(node: Arc<Node>, client: reqwest::Client, feed: Arc<ChangeFeed>, metrics: Arc<Metrics>) {
+        tokio::spawn(heartbeat(node.clone(), client.clone(), metrics.clone()));
+        tokio::spawn(replicate(node, client, feed, metrics));
+    }
+
+    async fn get<T: DeserializeOwned>(node: &Node, client: &reqwest::Client, url: String) -> Result<T, String> {
+        let response = client
+            .get(&url)
+            .header(SECRET_HEADER, &node.settings.secret)
+            .send()
+            .await
+            .map_err(|e| e.to_string())?;
+        if !response.status().is_success() {
+            return Err(format!("{} answered {}", url, response.status()));
+        }
+        response.json().await.map_err(|e| e.to_string())
+    }
+
+    // 定期询问所有节点的状态, 据此跟随 leader 或发起选举
+    async fn heartbeat(node: Arc<Node>, client: reqwest::Client, metrics: Arc<Metrics>) {
+        let period = Duration::from_millis(node.settings.heartbeat_ms);
+        let mut ticker = tokio::time::interval(period);
+        loop {
+            ticker.tick().await;
+            let mut requests = JoinSet::new();
+            for url in node.settings.peers.clone() {
+                let (node, client) = (node.clone(), client.clone());
+                requests.spawn(async move {
+                    let status = client
+                        .get(format!("{}/_cluster/status", url))
+                        .header(SECRET_HEADER, &node.settings.secret)
+                        .timeout(period)
+                        .send()
+                        .await
+                        .and_then(|r| r.error_for_status());
+                    match status {
+                        Ok(response) => response.json::<Status>().await.ok(),
+                        Err(e) => {
+                            log::debug!("cluster peer {} did not answer: {}", url, e);
+                            None
+                        }
+                    }
+                });
+            }
+            let mut peers = Vec::new();
+            while let Some(status) = requests.join_next().await {
+                peers.extend(status.ok().flatten().filter(|s| s.node_id != node.settings.node_id));
+            }
+
+            if node.observe(&peers) {
+                node.save_term().await;
+                let role = match node.status().role {
+                    NodeRole::Leader => "leader",
+                    NodeRole::Follower => "follower",
+                };
+                metrics.incr("cluster_role_changes_total", vec![("role", role.to_string())], 1.0);
+            }
+            if node.is_leader() {
+                node.trim_log().await;
+            }
+        }
+    }
+
+    async fn replicate(node: Arc<Node>, client: reqwest::Client, feed: Arc<ChangeFeed>, metrics: Arc<Metrics>) {
+        loop {
+            let Some((leader, term)) = node.following() else {
+                tokio::time::sleep(POLL_INTERVAL).await;
+                continue;
+            };
+            if let Err(e) = follow(&node, &client, &feed, &metrics, &leader, term).await {
+                log::warn!("cluster replication from {} failed: {}", leader.node_id, e);
+                tokio::time::sleep(RETRY_DELAY).await;
+            }
+        }
+    }
+
+    // 从日志位置继续复制, 换了 leader 或落后太多时先完整复制
+    async fn follow(
+        node: &Node,
+        client: &reqwest::Client,
+        feed: &ChangeFeed,
+        metrics: &Metrics,
+        leader: &Peer,
+        term: u64,
+    ) -> Result<(), String> {
+        let position = node.read_state().position;
+        let mut seq = match position.term == term {
+            true => position.seq,
+            false => resync(node, client, leader, term).await?,
+        };
+        loop {
+            if node.following() != Some((leader.clone(), term)) {
+                return Ok(());
+            }
+            let url = format!("{}/_cluster/log?after={}&limit={}", leader.url, seq, MAX_PAGE);
+            let response = client
+                .get(&url)
+                .header(SECRET_HEADER, &node.settings.secret)
+                .send()
+                .await
+                .map_err(|e| e.to_string())?;
+            if response.status() == StatusCode::GONE {
+                seq = resync(node, client, leader, term).await?;
+                continue;
+            }
+            if !response.status().is_success() {
+                return Err(format!("{} answered {}", url, response.status()));
+            }
+            let page: LogPage = response.json().await.map_err(|e| e.to_string())?;
+            if page.term != term {
+                return Err(format!("leader moved on to term {}", page.term));
+            }
+
+            for (table, columns) in &page.columns {
+                ensure_table(&node.pool, table, columns).await.map_err(|e| e.to_string())?;
+            }
+            for entry in &page.entries {
+                apply(&node.pool, feed, entry).await.map_err(|e| e.to_string())?;
+                seq = entry.seq;
+            }
+            metrics.incr("cluster_entries_applied_total", Vec::new(), page.entries.len() as f64);
+            node.replicated(Position { term, seq }).await;
+            if (page.entries.len() as i64) < MAX_PAGE {
+                tokio::time::sleep(POLL_INTERVAL).await;
+            }
+        }
+    }
+
+    // 完整复制 leader 的所有集合, 返回复制时的日志位置
+    async fn resync(node: &Node, client: &reqwest::Client, leader: &Peer, term: u64) -> Result<i64, String> {
+        log::info!("cluster copying every collection from {}", leader.node_id);
+        let snapshot: Snapshot = get(node, client, format!("{}/_cluster/snapshot", leader.url)).await?;
+        if snapshot.term != term {
+            return Err(format!("leader moved on to term {}", snapshot.term));
+        }
+        let local = list_collections(&node.pool).await.map_err(|e| e.to_string())?;
+        for table in &snapshot.collections {
+            let url = format!("{}/_cluster/snapshot/{}", leader.url, table.name);
+            let documents: Vec<Value> = get(node, client, url).await?;
+            ensure_table(&node.pool, &table.name, &table.columns).await.map_err(|e| e.to_string())?;
+            let mut tx = node.pool.begin().await.map_err(|e| e.to_string())?;
+            sqlx::query(&format!("DELETE FROM {}", quote(&table.name))).execute(&mut *tx).await.map_err(|e| e.to_string())?;
+            for document in &documents {
+                upsert_row(&mut tx, &table.name, document).await.map_err(|e| e.to_string())?;
+            }
+            tx.commit().await.map_err(|e| e.to_string())?;
+        }
+        // leader 上没有的集合清空
+        for table in local.iter().filter(|t| !snapshot.collections.iter().any(|c| &c.name == *t)) {
+            sqlx::query(&format!("DELETE FROM {}", quote(table))).execute(&node.pool).await.map_err(|e| e.to_string())?;
+        }
+        node.replicated(Position { term, seq: snapshot.seq }).await;
+        log::info!("cluster copied {} collections from {}", snapshot.collections.len(), leader.node_id);
+        Ok(snapshot.seq)
+    }
+
+    // 按 leader 的列定义建表, 缺少的列补上
+    async fn ensure_table(pool: &SqlitePool, table: &str, columns: &[(String, String)]) -> Result<(), sqlx::Error> {
+        let definition = |(name, ty): &(String, String)| match name.as_str() {
+            "id" => "id INTEGER PRIMARY KEY AUTOINCREMENT".to_string(),
+            VERSION_FIELD => format!("{} INTEGER NOT NULL DEFAULT 1", VERSION_FIELD),
+            _ => format!("{} {}", quote(name), ty),
+        };
+        if !collection_exists(pool, table).await? {
+            let columns: Vec<String> = columns.iter().map(definition).collect();
+            sqlx::query(&format!("CREATE TABLE IF NOT EXISTS {} ({})", quote(table), columns.join(", "))).execute(pool).await?;
+            schema_changed();
+            return Ok(());
+        }
+        let existing = table_columns(pool, table).await?;
+        for column in columns.iter().filter(|(name, _)| !existing.iter().any(|(e, _)| e == name)) {
+            sqlx::query(&format!("ALTER TABLE {} ADD COLUMN {}", quote(table), definition(column))).execute(pool).await?;
+            schema_changed();
+        }
+        Ok(())
+    }
+
+    async fn upsert_row(conn: &mut SqliteConnection, table: &str, document: &Value) -> Result<(), sqlx::Error> {
+        let Some(fields) = document.as_object() else {
+            return Ok(());
+        };
+        let columns: Vec<String> = fields.keys().map(|name| quote(name)).collect();
+        let updates: Vec<String> =
+            fields.keys().zip(&columns).filter(|(name, _)| *name != "id").map(|(_, c)| format!("{0} = excluded.{0}", c)).collect();
+        let query = format!(
+            "INSERT INTO {} ({}) VALUES ({}) ON CONFLICT (id) DO {}",
+            quote(table),
+            columns.join(", "),
+            vec!["?"; columns.len()].join(", "),
+            if updates.is_empty() { "NOTHING".to_string() } else { format!("UPDATE SET {}", updates.join(", ")) }
+        );
+        let mut statement = sqlx::query(&query);
+        for (name, value) in fields {
+            statement = match value {
+                Value::Null => statement.bind(None::<String>),
+                Value::Number(n) if name == "id" || name == VERSION_FIELD => statement.bind(n.as_i64()),
+                value => bind_value(statement, Some(value)),
+            };
+        }
+        statement.execute(conn).await?;
+        Ok(())
+    }
+
+    // 应用一条日志; 没有 Redis 转发时在这里把变更交给本节点的订阅者
+    async fn apply(pool: &SqlitePool, feed: &ChangeFeed, entry: &Entry) -> Result<(), sqlx::Error> {
+        let table = table_name(&entry.collection);
+        let notify = !feed.relayed() && feed.captures(&entry.collection);
+        let before = match notify {
+            true => current_document(pool, &table, entry.id).await?,
+            false => None,
+        };
+        match &entry.document {
+            Some(document) => {
+                let mut conn = pool.acquire().await?;
+                upsert_row(&mut conn, &table, document).await?;
+            }
+            None if collection_exists(pool, &table).await? => {
+                sqlx::query(&format!("DELETE FROM {} WHERE id = ?", quote(&table))).bind(entry.id).execute(pool).await?;
+            }
+            None => {}
+        }
+        let op = match (&before, &entry.document) {
+            (None, Some(_)) => Op::Insert,
+            (Some(_), Some(_)) => Op::Update,
+            (Some(_), None) => Op::Delete,
+            (None, None) => return Ok(()),
+        };
+        if notify {
+            feed.deliver(Change {
+                op,
+                collection: entry.collection.clone(),
+                id: entry.id,
+                before,
+                after: entry.document.clone(),
+                timestamp: now_millis(),
+            });
+        }
+        Ok(())
+    }
+
+    /// Sends writes to the leader, or logs them when this node leads.
+    pub struct ClusterStore {
+        inner: Arc<dyn DocumentStore>,
+        node: Arc<Node>,
+        client: reqwest::Client,
+    }
+
+    impl ClusterStore {
+        pub fn new(inner: Arc<dyn DocumentStore>, node: Arc<Node>, client: reqwest::Client) -> Self {
+            ClusterStore { inner, node, client }
+        }
+
+        async fn forward(&self, write: Write) -> Result<Value, StoreError> {
+            let Some((leader, term)) = self.node.following() else {
+                return Err(StoreError::Unavailable("the cluster has no leader".to_string()));
+            };
+            let unavailable = |e: reqwest::Error| StoreError::Unavailable(format!("leader {} failed: {}", leader.node_id, e));
+            let response = self
+                .client
+                .post(format!("{}/_cluster/write", leader.url))
+                .header(SECRET_HEADER, &self.node.settings.secret)
+                .json(&write)
+                .send()
+                .await
+                .map_err(unavailable)?;
+            if !response.status().is_success() {
+                let failure: WriteFailure = response.json().await.map_err(unavailable)?;
+                return Err(failure.into_error());
+            }
+            let reply: WriteReply = response.json().await.map_err(unavailable)?;
+
+            // 等本节点复制到这次写入, 之后在本节点读取能看到它
+            consistency::replicated_to(term, reply.seq);
+            if !self.node.wait_applied(Position { term, seq: reply.seq }, CATCH_UP).await {
+                log::debug!("cluster answered a write before replicating log entry {}", reply.seq);
+            }
+            Ok(reply.result)
+        }
+
+        fn decode<T: DeserializeOwned>(result: Value) -> Result<T, StoreError> {
+            serde_json::from_value(result).map_err(|e| StoreError::Unavailable(format!("unexpected reply from the leader: {}", e)))
+        }
+    }
+
+    #[async_trait]
+    impl DocumentStore for ClusterStore {
+        async fn insert(&self, uri: &str, doc: &Value) -> Result<i64, StoreError> {
+            if !self.node.is_leader() {
+                let write = Write::Insert { collection: uri.to_string(), document: doc.clone() };
+                return Self::decode(self.forward(write).await?);
+            }
+            let id = self.inner.insert(uri, doc).await?;
+            self.node.record(uri, id).await;
+            Ok(id)
+        }
+
+        async fn list(&self, uri: &str) -> Result<Vec<Value>, StoreError> {
+            self.inner.list(uri).await
+        }
+
+        async fn get(&self, uri: &str, id: i64) -> Result<Option<Value>, StoreError> {
+            self.inner.get(uri, id).await
+        }
+
+        async fn get_many(&self, uri: &str, ids: &[i64]) -> Result<Vec<Value>, StoreError> {
+            self.inner.get_many(uri, ids).await
+        }
+
+        async fn insert_unique(&self, uri: &str, doc: &Value, hash: &str, mode: DedupMode) -> Result<Inserted, StoreError> {
+            if !self.node.is_leader() {
+                let write = Write::InsertUnique { collection: uri.to_string(), document: doc.clone(), hash: hash.to_string(), mode };
+                return Self::decode(self.forward(write).await?);
+            }
+            let inserted = self.inner.insert_unique(uri, doc, hash, mode).await?;
+            if !inserted.duplicate || mode == DedupMode::Upsert {
+                self.node.record(uri, inserted.id).await;
+            }
+            Ok(inserted)
+        }
+
+        async fn upsert(&self, uri: &str, key: &str, doc: &Value) -> Result<Upserted, StoreError> {
+            if !self.node.is_leader() {
+                let write = Write::Upsert { collection: uri.to_string(), key: key.to_string(), document: doc.clone() };
+                return Self::decode(self.forward(write).await?);
+            }
+            let upserted = self.inner.upsert(uri, key, doc).await?;
+            if upserted.changed {
+                self.node.record(uri, upserted.id).await;
+            }
+            Ok(upserted)
+        }
+
+        async fn replace(&self, uri: &str, id: i64, doc: &Value, expected: Option<&[i64]>) -> Result<i64, StoreError> {
+            if !self.node.is_leader() {
+                let write = Write::Replace {
+                    collection: uri.to_string(),
+                    id,
+                    document: doc.clone(),
+                    expected: expected.map(<[i64]>::to_vec),
+                };
+                return Self::decode(self.forward(write).await?);
+            }
+            let version = self.inner.replace(uri, id, doc, expected).await?;
+            self.node.record(uri, id).await;
+            Ok(version)
+        }
+
+        async fn update(&self, uri: &str, id: i64, doc: &Value, expected: Option<&[i64]>) -> Result<i64, StoreError> {
+            if !self.node.is_leader() {
+                let write = Write::Update {
+                    collection: uri.to_string(),
+                    id,
+                    document: doc.clone(),
+                    expected: expected.map(<[i64]>::to_vec),
+                };
+                return Self::decode(self.forward(write).await?);
+            }
+            let version = self.inner.update(uri, id, doc, expected).await?;
+            self.node.record(uri, id).await;
+            Ok(version)
+        }
+
+        async fn delete(&self, uri: &str, id: i64, expected: Option<&[i64]>) -> Result<(), StoreError> {
+            if !self.node.is_leader() {
+                let write = Write::Delete { collection: uri.to_string(), id, expected: expected.map(<[i64]>::to_vec) };
+                return Self::decode(self.forward(write).await?);
+            }
+            self.inner.delete(uri, id, expected).await?;
+            self.node.record(uri, id).await;
+            Ok(())
+        }
+
+        async fn update_many(&self, uri: &str, filter: &Value, update: &Value) -> Result<UpdatedMany, StoreError> {
+            if !self.node.is_leader() {
+                let write = Write::UpdateMany { collection: uri.to_string(), filter: filter.clone(), update: update.clone() };
+                return Self::decode(self.forward(write).await?);
+            }
+            let updated = self.inner.update_many(uri, filter, update).await?;
+            for id in &updated.modified {
+                self.node.record(uri, *id).await;
+            }
+            Ok(updated)
+        }
+
+        // 只查找不删除时在本节点执行
+        async fn delete_many(&self, uri: &str, filter: &Value, dry_run: bool) -> Result<Vec<i64>, StoreError> {
+            if !self.node.is_leader() && !dry_run {
+                let write = Write::DeleteMany { collection: uri.to_string(), filter: filter.clone() };
+                return Self::decode(self.forward(write).await?);
+            }
+            let deleted = self.inner.delete_many(uri, filter, dry_run).await?;
+            if !dry_run {
+                for id in &deleted {
+                    self.node.record(uri, *id).await;
+                }
+            }
+            Ok(deleted)
+        }
+
+        async fn find(&self, uri: &str, filter: &Value) -> Result<Vec<Value>, StoreError> {
+            self.inner.find(uri, filter).await
+        }
+
+        async fn count(&self, uri: &str, filter: &Value) -> Result<u64, StoreError> {
+            self.inner.count(uri, filter).await
+        }
+
+        async fn find_page(&self, uri: &str, filter: &Value, page: &PageQuery) -> Result<Paged, StoreError> {
+            self.inner.find_page(uri, filter, page).await
+        }
+
+        async fn version(&self, uri: &str) -> Result<String, StoreError> {
+            self.inner.version(uri).await
+        }
+
+        async fn committed(&self, writes: &[Written]) {
+            self.inner.committed(writes).await;
+            if self.node.is_leader() {
+                for write in writes {
+                    self.node.record(&write.uri, write.id).await;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(all(test, feature = "sqlite"))]
+mod tests {
+    use super::*;
+    use crate::testing::TestStore;
+
+    // 节点 a, peers 为其他节点的地址
+    async fn node(test: &TestStore, election_timeout_ms: u64, peers: &[&str]) -> Node {
+        let settings: Cluster = serde_json::from_value(json!({
+            "node_id": "a",
+            "advertise_url": "http://a",
+            "peers": peers,
+            "secret": "s",
+            "election_timeout_ms": election_timeout_ms,
+        }))
+        .unwrap();
+        Node::open(settings, test.pool().clone()).await.unwrap()
+    }
+
+    fn peer(node_id: &str, role: NodeRole, term: u64, seq: i64) -> Status {
+        let leader = None;
+        let url = format!("http://{}", node_id);
+        Status { node_id: node_id.to_string(), url, term, role, leader, position: Position { term, seq } }
+    }
+
+    // 让 election_timeout_ms 为 0 的节点确实超时
+    async fn elapse() {
+        tokio::time::sleep(Duration::from_millis(2)).await;
+    }
+
+    #[tokio::test]
+    async fn a_node_waits_out_the_election_timeout_before_leading() {
+        let test = TestStore::new().await;
+        let patient = node(&test, 60_000, &[]).await;
+        assert!(!patient.observe(&[]));
+        assert_eq!((patient.status().role, patient.status().term), (NodeRole::Follower, 0));
+
+        let node = node(&test, 0, &[]).await;
+        elapse().await;
+        assert!(node.observe(&[]));
+        assert!(node.is_leader());
+        assert_eq!(node.status().term, 1);
+        assert_eq!(node.following(), None);
+        // 已经是 leader 时再观察不会改变 term
+        assert!(!node.observe(&[]));
+        assert_eq!(node.status().term, 1);
+    }
+
+    #[tokio::test]
+    async fn an_election_takes_the_next_term_and_a_restart_keeps_it() {
+        let test = TestStore::new().await;
+        let node = node(&test, 0, &["http://b", "http://c"]).await;
+        elapse().await;
+        // 其他节点见过更高的 term, 但复制的数据不比它多
+        let peers: Vec<Status> = [peer("b", NodeRole::Follower, 4, 0), peer("c", NodeRole::Follower, 2, 0)]
+            .into_iter()
+            .map(|status| Status { position: Position::default(), ..status })
+            .collect();
+        assert!(node.observe(&peers));
+        assert!(node.is_leader());
+        assert_eq!(node.status().term, 5);
+        node.save_term().await;
+
+        let restarted = self::node(&test, 0, &["http://b", "http://c"]).await;
+        assert_eq!((restarted.status().role, restarted.status().term), (NodeRole::Follower, 5));
+    }
+
+    #[tokio::test]
+    async fn no_election_without_a_majority_or_while_a_better_node_is_up() {
+        let test = TestStore::new().await;
+        let node = node(&test, 0, &["http://b", "http://c"]).await;
+        elapse().await;
+        // 只联系到自己, 不是多数
+        assert!(!node.observe(&[]));
+        // 另一个节点还能联系到 leader
+        let mut informed = peer("b", NodeRole::Follower, 1, 0);
+        informed.leader = Some(Peer { node_id: "z".to_string(), url: "http://z".to_string() });
+        assert!(!node.observe(&[informed]));
+        // 另一个节点复制得更多, 由它当选
+        assert!(!node.observe(&[peer("b", NodeRole::Follower, 1, 9)]));
+        assert!(!node.is_leader());
+        assert_eq!(node.status().term, 0);
+    }
+
+    #[tokio::test]
+    async fn a_follower_follows_the_highest_ranked_leader() {
+        let test = TestStore::new().await;
+        let node = node(&test, 60_000, &["http://b", "http://c"]).await;
+        assert!(node.observe(&[peer("b", NodeRole::Leader, 3, 50), peer("c", NodeRole::Leader, 4, 0)]));
+        let c = Peer { node_id: "c".to_string(), url: "http://c".to_string() };
+        assert_eq!(node.following(), Some((c.clone(), 4)));
+        assert_eq!(node.status().leader, Some(c));
+        assert!(!node.observe(&[peer("c", NodeRole::Leader, 4, 10)]));
+        // 同一 term 中数据多者优先, 再是 node_id 小者
+        assert!(!node.observe(&[peer("c", NodeRole::Leader, 4, 1), peer("b", NodeRole::Leader, 4, 2)]));
+        assert_eq!(node.following().map(|(leader, _)| leader.node_id), Some("b".to_string()));
+    }
+
+    #[tokio::test]
+    async fn a_leader_steps_down_for_a_later_term_or_a_lost_majority() {
+        let test = TestStore::new().await;
+        let node = node(&test, 0, &["http://b", "http://c"]).await;
+        elapse().await;
+        assert!(node.observe(&[peer("b", NodeRole::Follower, 0, 0)]));
+        assert_eq!(node.status().term, 1);
+        // 同一 term 中 node_id 更大的 leader 不能取代它
+        assert!(!node.observe(&[peer("b", NodeRole::Leader, 1, 0)]));
+        assert!(node.is_leader());
+
+        assert!(node.observe(&[peer("b", NodeRole::Leader, 2, 0)]));
+        assert!(!node.is_leader());
+        assert_eq!(node.following().map(|(leader, term)| (leader.node_id, term)), Some(("b".to_string(), 2)));
+
+        let lonely = self::node(&test, 0, &["http://b", "http://c"]).await;
+        elapse().await;
+        assert!(lonely.observe(&[peer("b", NodeRole::Follower, 0, 0)]));
+        assert!(lonely.is_leader());
+        elapse().await;
+        assert!(lonely.observe(&[]));
+        assert!(!lonely.is_leader());
+        assert_eq!(lonely.status().term, 1);
+    }
+
+    #[tokio::test]
+    async fn replication_progress_is_waited_for_and_kept() {
+        let test = TestStore::new().await;
+        let node = node(&test, 60_000, &[]).await;
+        node.replicated(Position { term: 2, seq: 5 }).await;
+        assert!(node.wait_applied(Position { term: 2, seq: 3 }, Duration::from_millis(10)).await);
+        assert!(node.wait_applied(Position { term: 1, seq: 90 }, Duration::from_millis(10)).await);
+        assert!(!node.wait_applied(Position { term: 2, seq: 9 }, Duration::from_millis(10)).await);
+        let restarted = self::node(&test, 60_000, &[]).await;
+        assert_eq!(restarted.status().position, Position { term: 2, seq: 5 });
+    }
+
+    #[tokio::test]
+    async fn a_panic_while_holding_the_state_does_not_take_the_node_down() {
+        let test = TestStore::new().await;
+        let node = Arc::new(node(&test, 0, &[]).await);
+        let holder = node.clone();
+        std::thread::spawn(move || {
+            let _state = holder.write_state();
+            panic!("panicked while holding the cluster state");
+        })
+        .join()
+        .unwrap_err();
+        assert!(node.state.is_poisoned());
+        elapse().await;
+        assert!(node.observe(&[]));
+        assert!(node.is_leader());
+        node.save_term().await;
+        assert_eq!(node.status().term, 1);
+    }
+
+    #[cfg(feature = "cluster")]
+    #[actix_web::test]
+    async fn a_follower_sends_writes_to_the_leader() {
+        use actix_web::{App, HttpRequest, HttpServer};
+        use std::sync::Mutex;
+
+        // 转发来的写入和它带的密钥
+        type Received = web::Data<Mutex<Vec<(Option<String>, Value)>>>;
+
+        // 充当 leader 的节点: 记下转发来的写入, 插入回答 id 7, 删除回答版本冲突
+        let received: Received = web::Data::new(Mutex::new(Vec::new()));
+        let recorded = received.clone();
+        let server = HttpServer::new(move || {
+            App::new().app_data(recorded.clone()).route(
+                "/_cluster/write",
+                web::post().to(|req: HttpRequest, body: web::Json<Value>, received: Received| async move {
+                    let secret = req.headers().get(SECRET_HEADER).map(|v| v.to_str().unwrap().to_string());
+                    let op = body["op"].clone();
+                    received.lock().unwrap().push((secret, body.into_inner()));
+                    match op.as_str() {
+                        Some("insert") => HttpResponse::Ok().json(WriteReply { result: json!(7), seq: 3 }),
+                        _ => {
+                            let (status, failure) = WriteFailure::new(StoreError::VersionConflict { current: 4 });
+                            HttpResponse::build(status).json(failure)
+                        }
+                    }
+                }),
+            )
+        })
+        .workers(1)
+        .bind(("127.0.0.1", 0))
+        .unwrap();
+        let url = format!("http://{}", server.addrs()[0]);
+        let handle = server.run();
+        let stop = handle.handle();
+        tokio::spawn(handle);
+
+        let test = TestStore::new().await;
+        let node = Arc::new(node(&test, 60_000, &[&url]).await);
+        let store = remote::ClusterStore::new(test.data().into_inner(), node.clone(), remote::client());
+        assert!(matches!(store.insert("users", &json!({ "name": "a" })).await, Err(StoreError::Unavailable(_))));
+
+        let mut leader = peer("b", NodeRole::Leader, 3, 0);
+        leader.url = url;
+        assert!(node.observe(&[leader]));
+        // 已经复制到了这次写入, 不用等待
+        node.replicated(Position { term: 3, seq: 3 }).await;
+        assert_eq!(store.insert("users", &json!({ "name": "a" })).await.unwrap(), 7);
+        assert!(matches!(store.delete("users", 7, Some(&[2])).await, Err(StoreError::VersionConflict { current: 4 })));
+        // 读取不经过 leader, 本节点也没有写入
+        assert!(test.columns("users").await.is_empty());
+        assert_eq!(
+            *received.lock().unwrap(),
+            [
+                (Some("s".to_string()), json!({ "op": "insert", "collection": "users", "document": { "name": "a" } })),
+                (Some("s".to_string()), json!({ "op": "delete", "collection": "users", "id": 7, "expected": [2] })),
+            ]
+        );
+        stop.stop(false).await;
+    }
+}
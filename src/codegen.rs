@@ -0,0 +1,407 @@
+//! Typed bindings for stored documents.
+//!
+//! `json_storage codegen <collection>` samples the documents of a collection,
+//! infers the shape of each field and prints serde-annotated Rust structs
+//! (or TypeScript interfaces with `--lang typescript`) matching it. Objects
+//! get their own struct; a field that is null or missing in some documents
+//! becomes optional, and one holding values of different kinds falls back to
+//! `serde_json::Value`.
+
+use serde_json::{json, Map, Value};
+use sqlx::SqlitePool;
+use std::collections::{BTreeMap, BTreeSet};
+use std::io;
+
+use crate::database::{collection_exists, init_db, row_to_json, table_name};
+use crate::query::quote;
+use crate::store::VERSION_FIELD;
+
+const DEFAULT_SAMPLE: i64 = 1000;
+
+const USAGE: &str = "usage: json_storage codegen <collection> [--lang rust|typescript] [--sample N]";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Lang {
+    Rust,
+    TypeScript,
+}
+
+/// Everything seen in one field across the sampled documents.
+#[derive(Debug, Default)]
+pub struct Shape {
+    /// Documents (or parent objects) in which the field was present.
+    pub present: usize,
+    pub nulls: usize,
+    pub bools: usize,
+    pub integers: usize,
+    pub floats: usize,
+    pub strings: usize,
+    /// Merged shape of all array elements.
+    pub items: Option<Box<Shape>>,
+    /// Merged fields of all object values; `objects` counts those values.
+    pub fields: Option<BTreeMap<String, Shape>>,
+    pub objects: usize,
+}
+
+impl Shape {
+    pub fn observe(&mut self, value: &Value) {
+        self.present += 1;
+        match value {
+            Value::Null => self.nulls += 1,
+            Value::Bool(_) => self.bools += 1,
+            Value::Number(n) if n.is_f64() => self.floats += 1,
+            Value::Number(_) => self.integers += 1,
+            Value::String(_) => self.strings += 1,
+            Value::Array(items) => {
+                let shape = self.items.get_or_insert_with(Box::default);
+                for item in items {
+                    shape.observe(item);
+                }
+            }
+            Value::Object(map) => {
+                self.objects += 1;
+                let fields = self.fields.get_or_insert_with(BTreeMap::new);
+                for (key, value) in map {
+                    fields.entry(key.clone()).or_default().observe(value);
+                }
+            }
+        }
+    }
+
+    /// A JSON Schema of the values seen: their types, `null` among them
+    /// when some were null, the properties of objects, required when present
+    /// in every object, and the merged items of arrays. A field holding no
+    /// value seen, such as the items of empty arrays, allows anything.
+    pub fn json_schema(&self) -> Value {
+        let numbers = match (self.integers, self.floats) {
+            (_, floats) if floats > 0 => "number",
+            (integers, _) if integers > 0 => "integer",
+            _ => "",
+        };
+        let kinds = [
+            (self.nulls > 0, "null"),
+            (self.bools > 0, "boolean"),
+            (!numbers.is_empty(), numbers),
+            (self.strings > 0, "string"),
+            (self.items.is_some(), "array"),
+            (self.objects > 0, "object"),
+        ];
+        let types: Vec<&str> = kinds.into_iter().filter(|(seen, _)| *seen).map(|(_, name)| name).collect();
+        let mut schema = Map::new();
+        match types.as_slice() {
+            [] => {}
+            [only] => {
+                schema.insert("type".to_string(), json!(only));
+            }
+            types => {
+                schema.insert("type".to_string(), json!(types));
+            }
+        }
+        if let Some(items) = &self.items {
+            schema.insert("items".to_string(), items.json_schema());
+        }
+        if let Some(fields) = &self.fields {
+            let properties: Map<String, Value> = fields.iter().map(|(key, shape)| (key.clone(), shape.json_schema())).collect();
+            let required: Vec<&String> = fields.iter().filter(|(_, shape)| shape.present == self.objects).map(|(key, _)| key).collect();
+            schema.insert("properties".to_string(), Value::Object(properties));
+            schema.insert("required".to_string(), json!(required));
+        }
+        Value::Object(schema)
+    }
+
+    /// Null, or missing from some of the `total` parents.
+    fn optional(&self, total: usize) -> bool {
+        self.nulls > 0 || self.present < total
+    }
+
+    fn kind(&self) -> Kind {
+        let numbers = self.integers + self.floats;
+        let kinds = [self.bools, numbers, self.strings, self.items.is_some() as usize, self.objects];
+        if kinds.iter().filter(|n| **n > 0).count() != 1 {
+            return Kind::Any;
+        }
+        if self.bools > 0 {
+            Kind::Bool
+        } else if self.floats > 0 {
+            Kind::Float
+        } else if self.integers > 0 {
+            Kind::Integer
+        } else if self.strings > 0 {
+            Kind::String
+        } else if self.items.is_some() {
+            Kind::Array
+        } else {
+            Kind::Object
+        }
+    }
+}
+
+enum Kind {
+    Any,
+    Bool,
+    Integer,
+    Float,
+    String,
+    Array,
+    Object,
+}
+
+/// Entry point of the `codegen` subcommand; `args` follow the subcommand name.
+pub async fn run(args: &[String]) -> io::Result<()> {
+    let invalid = |msg: String| io::Error::new(io::ErrorKind::InvalidInput, msg);
+    let mut collection = None;
+    let mut lang = Lang::Rust;
+    let mut sample = DEFAULT_SAMPLE;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--lang" => {
+                lang = match args.next().map(String::as_str) {
+                    Some("rust") => Lang::Rust,
+                    Some("typescript" | "ts") => Lang::TypeScript,
+                    _ => return Err(invalid(USAGE.to_string())),
+                }
+            }
+            "--sample" => {
+                sample = match args.next().and_then(|n| n.parse().ok()) {
+                    Some(n) if n > 0 => n,
+                    _ => return Err(invalid(USAGE.to_string())),
+                }
+            }
+            name if collection.is_none() && !name.starts_with('-') => collection = Some(table_name(name)),
+            _ => return Err(invalid(USAGE.to_string())),
+        }
+    }
+    let Some(collection) = collection else {
+        return Err(invalid(USAGE.to_string()));
+    };
+
+    let pool = init_db().await.map_err(io::Error::other)?;
+    if !collection_exists(&pool, &collection).await.map_err(io::Error::other)? {
+        return Err(invalid(format!("collection '{}' does not exist", collection)));
+    }
+    let (shape, documents) = infer(&pool, &collection, sample).await.map_err(io::Error::other)?;
+    let name = type_name(&collection);
+    let code = match lang {
+        Lang::Rust => rust(&name, &shape, documents),
+        Lang::TypeScript => typescript(&name, &shape, documents),
+    };
+    print!("{}", code);
+    Ok(())
+}
+
+/// Merged shape of up to `sample` documents of `collection`, and how many were read.
+pub async fn infer(pool: &SqlitePool, collection: &str, sample: i64) -> Result<(BTreeMap<String, Shape>, usize), sqlx::Error> {
+    let rows = sqlx::query(&format!("SELECT * FROM {} ORDER BY id LIMIT ?", quote(collection)))
+        .bind(sample)
+        .fetch_all(pool)
+        .await?;
+    let mut shape = Shape::default();
+    for row in &rows {
+        shape.observe(&row_to_json(row));
+    }
+    Ok((shape.fields.unwrap_or_default(), rows.len()))
+}
+
+/// Rust structs for documents with the given fields, the root one named `name`.
+pub fn rust(name: &str, fields: &BTreeMap<String, Shape>, documents: usize) -> String {
+    let mut out = String::from("use serde::{Deserialize, Serialize};\n");
+    let mut taken = BTreeSet::new();
+    rust_struct(name, fields, documents, &mut taken, &mut out);
+    out
+}
+
+// 生成一个结构体; 嵌套对象的结构体追加在其后
+fn rust_struct(name: &str, fields: &BTreeMap<String, Shape>, total: usize, taken: &mut BTreeSet<String>, out: &mut String) {
+    let name = unique(name, taken);
+    let mut body = String::new();
+    let mut nested = Vec::new();
+    for (field, shape) in ordered(fields) {
+        let ident = rust_ident(field);
+        let mut ty = match field.as_str() {
+            "id" | VERSION_FIELD => "i64".to_string(),
+            _ => rust_type(&format!("{}{}", name, type_name(field)), shape, &mut nested),
+        };
+        let mut attrs = Vec::new();
+        if ident.trim_start_matches("r#") != field {
+            attrs.push(format!("rename = \"{}\"", field.escape_default()));
+        }
+        if shape.optional(total) {
+            ty = format!("Option<{}>", ty);
+            attrs.push("default, skip_serializing_if = \"Option::is_none\"".to_string());
+        }
+        if !attrs.is_empty() {
+            body.push_str(&format!("    #[serde({})]\n", attrs.join(", ")));
+        }
+        body.push_str(&format!("    pub {}: {},\n", ident, ty));
+    }
+    out.push_str(&format!(
+        "\n#[derive(Debug, Clone, Serialize, Deserialize)]\npub struct {} {{\n{}}}\n",
+        name, body
+    ));
+    for (name, shape) in nested {
+        if let Some(fields) = &shape.fields {
+            rust_struct(&name, fields, shape.objects, taken, out);
+        }
+    }
+}
+
+fn rust_type<'a>(name: &str, shape: &'a Shape, nested: &mut Vec<(String, &'a Shape)>) -> String {
+    match shape.kind() {
+        Kind::Any => "serde_json::Value".to_string(),
+        Kind::Bool => "bool".to_string(),
+        Kind::Integer => "i64".to_string(),
+        Kind::Float => "f64".to_string(),
+        Kind::String => "String".to_string(),
+        Kind::Array => {
+            let items = shape.items.as_deref().expect("array shape has items");
+            let item = rust_type(&format!("{}Item", name), items, nested);
+            // 元素里出现 null 时用 Option
+            if items.nulls > 0 && items.nulls < items.present {
+                format!("Vec<Option<{}>>", item)
+            } else {
+                format!("Vec<{}>", item)
+            }
+        }
+        Kind::Object => {
+            nested.push((name.to_string(), shape));
+            name.to_string()
+        }
+    }
+}
+
+/// TypeScript interfaces for documents with the given fields, the root one named `name`.
+pub fn typescript(name: &str, fields: &BTreeMap<String, Shape>, documents: usize) -> String {
+    let mut out = String::new();
+    let mut taken = BTreeSet::new();
+    ts_interface(name, fields, documents, &mut taken, &mut out);
+    out
+}
+
+fn ts_interface(name: &str, fields: &BTreeMap<String, Shape>, total: usize, taken: &mut BTreeSet<String>, out: &mut String) {
+    let name = unique(name, taken);
+    let mut body = String::new();
+    let mut nested = Vec::new();
+    for (field, shape) in ordered(fields) {
+        let ty = match field.as_str() {
+            "id" | VERSION_FIELD => "number".to_string(),
+            _ => ts_type(&format!("{}{}", name, type_name(field)), shape, &mut nested),
+        };
+        let key = if is_ts_ident(field) { field.clone() } else { Value::String(field.clone()).to_string() };
+        if shape.optional(total) {
+            body.push_str(&format!("  {}?: {} | null;\n", key, ty));
+        } else {
+            body.push_str(&format!("  {}: {};\n", key, ty));
+        }
+    }
+    if !out.is_empty() {
+        out.push('\n');
+    }
+    out.push_str(&format!("export interface {} {{\n{}}}\n", name, body));
+    for (name, shape) in nested {
+        if let Some(fields) = &shape.fields {
+            ts_interface(&name, fields, shape.objects, taken, out);
+        }
+    }
+}
+
+fn ts_type<'a>(name: &str, shape: &'a Shape, nested: &mut Vec<(String, &'a Shape)>) -> String {
+    match shape.kind() {
+        Kind::Any => "unknown".to_string(),
+        Kind::Bool => "boolean".to_string(),
+        Kind::Integer | Kind::Float => "number".to_string(),
+        Kind::String => "string".to_string(),
+        Kind::Array => {
+            let items = shape.items.as_deref().expect("array shape has items");
+            let item = ts_type(&format!("{}Item", name), items, nested);
+            if items.nulls > 0 && items.nulls < items.present {
+                format!("Array<{} | null>", item)
+            } else {
+                format!("{}[]", item)
+            }
+        }
+        Kind::Object => {
+            nested.push((name.to_string(), shape));
+            name.to_string()
+        }
+    }
+}
+
+// id 和 _version 排在最前, 其余按字段名排序
+fn ordered(fields: &BTreeMap<String, Shape>) -> Vec<(&String, &Shape)> {
+    let mut fields: Vec<_> = fields.iter().collect();
+    fields.sort_by_key(|(name, _)| (name.as_str() != "id", name.as_str() != VERSION_FIELD));
+    fields
+}
+
+// 同名类型加数字后缀
+fn unique(name: &str, taken: &mut BTreeSet<String>) -> String {
+    let mut candidate = name.to_string();
+    let mut n = 2;
+    while !taken.insert(candidate.clone()) {
+        candidate = format!("{}{}", name, n);
+        n += 1;
+    }
+    candidate
+}
+
+/// `order_items` -> `OrderItems`.
+pub fn type_name(name: &str) -> String {
+    let mut out = String::new();
+    for part in name.split(|c: char| !c.is_ascii_alphanumeric()).filter(|p| !p.is_empty()) {
+        let mut chars = part.chars();
+        if let Some(first) = chars.next() {
+            out.push(first.to_ascii_uppercase());
+            out.extend(chars);
+        }
+    }
+    if out.is_empty() || out.starts_with(|c: char| c.is_ascii_digit()) {
+        out.insert(0, 'T');
+    }
+    out
+}
+
+const RUST_KEYWORDS: &[&str] = &[
+    "abstract", "as", "async", "await", "become", "box", "break", "const", "continue", "do", "dyn", "else", "enum",
+    "extern", "false", "final", "fn", "for", "gen", "if", "impl", "in", "let", "loop", "macro", "match", "mod", "move",
+    "mut", "override", "priv", "pub", "ref", "return", "static", "struct", "trait", "true", "try", "type", "typeof",
+    "unsafe", "unsized", "use", "virtual", "where", "while", "yield",
+];
+
+// 字段名转成 snake_case 标识符; 关键字用 r# 前缀
+fn rust_ident(field: &str) -> String {
+    let mut out = String::new();
+    let mut prev_lower = false;
+    for c in field.chars() {
+        if c.is_ascii_uppercase() {
+            if prev_lower {
+                out.push('_');
+            }
+            out.push(c.to_ascii_lowercase());
+            prev_lower = false;
+        } else if c.is_ascii_alphanumeric() {
+            out.push(c);
+            prev_lower = true;
+        } else {
+            out.push('_');
+            prev_lower = false;
+        }
+    }
+    if field == VERSION_FIELD {
+        return "version".to_string();
+    }
+    if out.is_empty() || out.starts_with(|c: char| c.is_ascii_digit()) {
+        out.insert(0, '_');
+    }
+    match out.as_str() {
+        "self" | "super" | "crate" | "_" => format!("{}_", out),
+        kw if RUST_KEYWORDS.contains(&kw) => format!("r#{}", kw),
+        _ => out,
+    }
+}
+
+fn is_ts_ident(field: &str) -> bool {
+    !field.is_empty()
+        && !field.starts_with(|c: char| c.is_ascii_digit())
+        && field.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '$')
+}
@@ -0,0 +1,764 @@
+//! Operations on whole collections.
+//!
+//! `POST /{uri}/_truncate` deletes every document of a collection and keeps
+//! its table, columns and indexes. It takes two calls: the first answers 428
+//! with the number of documents and a `confirm` token, and the second,
+//! carrying `{"confirm": "<token>"}`, empties the collection. The token is
+//! derived from the documents present, so it stops working once documents
+//! are added or deleted in between. Only admins may truncate.
+//!
+//! `POST /_collections/{uri}/rename` with `{"name": "<new>"}` gives a
+//! collection a new name. In one transaction it renames the table, its
+//! full-text index and the indexes named after it, and moves the rows that
+//! history, checksums, deduplication, ACL entries and saved queries keep
+//! under the old name. Sharded, partitioned and nested collections,
+//! collections with settings in the configuration and cluster nodes are
+//! refused: their files, tables, settings or followers would still know the
+//! old name.
+//!
+//! Collections whose tables would start with `_` or `sqlite_` are reserved
+//! for internal tables; [`protect`] refuses document routes naming them.
+//!
+//! `POST /_collections/{uri}/copy` with `{"name": "<new>"}` creates a new
+//! collection with the same columns, indexes and full-text index, and copies
+//! the documents matching an optional `filter`, keeping their ids, versions
+//! and checksums; `"schema_only": true` copies no documents. The same
+//! collections are refused as for renaming, except for configured ones that
+//! are not nested.
+//!
+//! `GET /_collections` lists the collections with their document counts,
+//! a sharded or partitioned collection once. `POST /_collections` creates
+//! an empty collection, optionally declaring typed columns and indexes up
+//! front, instead of leaving it to the first write. `DELETE
+//! /_collections/{uri}` drops a collection with the tables of its nested
+//! objects and arrays, its full-text index and what history, checksums and
+//! deduplication keep about it; like a truncate, it takes a second call with
+//! `?confirm=<token>`. ACL entries and saved queries stay, for a collection
+//! created again under the name. Sharded and partitioned collections and
+//! cluster nodes cannot create or drop collections this way.
+//!
+//! `POST /_collections/{uri}/prune` drops the columns no document of the
+//! collection has a value in, which keys written once and later removed
+//! leave behind. Each table of the collection is rebuilt without them,
+//! dropping the indexes on them too, and the answer lists the columns
+//! dropped from each table; `{"dry_run": true}` only lists them. Documents
+//! no longer carry those fields as `null`, so recorded checksums are
+//! renewed, and filters naming the fields are refused like any unknown one.
+
+use actix_web::body::{EitherBody, MessageBody};
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::middleware::{from_fn, Next};
+use actix_web::{web, Error, HttpResponse};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+use sqlx::SqlitePool;
+#[cfg(feature = "fts")]
+use sqlx::{Sqlite, Transaction};
+use std::collections::{BTreeMap, BTreeSet};
+
+use crate::access_log::annotate;
+use crate::acl;
+use crate::auth;
+use crate::config::ConfigHandle;
+use crate::database::{
+    bind_value, collection_exists, collection_tables, count_rows, create_table_statement, list_collections, prune_columns, schema_changed,
+    table_columns, table_name, uri_of,
+};
+use crate::handlers::route;
+use crate::integrity::Checksums;
+use crate::nested::{chains, nested_uri};
+use crate::partition::Partitions;
+use crate::query::{parse_filter, quote};
+#[cfg(feature = "fts")]
+use crate::search::{fts_table, fts_trigger_names, fts_triggers};
+use crate::sessions::hex;
+use crate::shard::Shards;
+use crate::store::{check_fields, reserved, valid_path, DocumentStore, StoreError, VERSION_FIELD};
+
+// 注册集合管理接口, 只有 admin 可以调用
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(
+        web::scope("/_collections")
+            .wrap(from_fn(auth::require_admin))
+            .route("", web::get().to(all_collections))
+            .route("", web::post().to(create_collection))
+            .route(&route("/{uri}/rename"), web::post().to(rename_collection))
+            .route(&route("/{uri}/copy"), web::post().to(copy_collection))
+            .route(&route("/{uri}/prune"), web::post().to(prune_collection))
+            .route(&route("/{uri}"), web::delete().to(delete_collection)),
+    );
+}
+
+// 注册文档作用域中的集合接口
+pub fn configure_documents(cfg: &mut web::ServiceConfig) {
+    cfg.service(
+        web::resource(route("/{uri}/_truncate"))
+            .wrap(from_fn(auth::require_admin))
+            .route(web::post().to(truncate)),
+    );
+}
+
+/// Refuses document routes naming a collection [`reserved`] for internal
+/// tables, before a write could create one or a read could expose one.
+pub async fn protect(
+    mut req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<EitherBody<impl MessageBody>>, Error> {
+    let collections = match acl::requested(&mut req).await {
+        Ok((_, collections)) => collections,
+        Err(response) => return Ok(req.into_response(response).map_into_right_body()),
+    };
+    if let Some(collection) = collections.iter().find(|c| reserved(c)) {
+        let response = HttpResponse::BadRequest().json(format!("Collection name '{}' is reserved", collection));
+        return Ok(req.into_response(response).map_into_right_body());
+    }
+    if let Some(collection) = collections.iter().find(|c| !valid_path(c)) {
+        let response = HttpResponse::BadRequest().json(format!(
+            "Collection path '{}' has an empty segment, a '__', or a '_' next to a '/'",
+            collection
+        ));
+        return Ok(req.into_response(response).map_into_right_body());
+    }
+    if let Some(collection) = collections.iter().find(|c| c.chars().any(char::is_control)) {
+        let response = HttpResponse::BadRequest().json(format!("Collection path {:?} holds a control character", collection));
+        return Ok(req.into_response(response).map_into_right_body());
+    }
+    next.call(req).await.map(ServiceResponse::map_into_left_body)
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct TruncateRequest {
+    pub confirm: Option<String>,
+}
+
+/// The answer to a truncate without a valid token.
+#[derive(Debug, Serialize)]
+pub struct Confirmation {
+    pub documents: usize,
+    /// Token to send back to go ahead.
+    pub confirm: String,
+}
+
+/// Token confirming `action` (`truncate` or `drop`) on `uri` while it holds
+/// the documents `ids`.
+pub fn confirmation(action: &str, uri: &str, ids: &[i64]) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(format!("{}\n{}\n", action, uri).as_bytes());
+    for id in ids {
+        hasher.update(id.to_be_bytes());
+    }
+    hex(&hasher.finalize()[..8])
+}
+
+// 清空集合; 没有确认令牌时只返回文档数量和令牌
+async fn truncate(
+    uri: web::Path<String>,
+    body: Option<web::Json<TruncateRequest>>,
+    store: web::Data<dyn DocumentStore>,
+) -> HttpResponse {
+    let confirm = body.and_then(|body| body.into_inner().confirm);
+    let everything = json!({});
+    let mut ids = match store.delete_many(&uri, &everything, true).await {
+        Ok(ids) => ids,
+        Err(e) => return failed(&uri, e),
+    };
+    ids.sort_unstable();
+    let token = confirmation("truncate", &uri, &ids);
+    if confirm.as_deref() != Some(token.as_str()) {
+        return HttpResponse::PreconditionRequired().json(Confirmation { documents: ids.len(), confirm: token });
+    }
+
+    match store.delete_many(&uri, &everything, false).await {
+        Ok(deleted) => {
+            log::warn!("truncated collection '{}', {} documents deleted", uri, deleted.len());
+            annotate(HttpResponse::Ok().json(json!({ "deleted": deleted.len() })), &uri, deleted.len())
+        }
+        Err(e) => failed(&uri, e),
+    }
+}
+
+fn failed(uri: &str, e: StoreError) -> HttpResponse {
+    match e {
+        StoreError::NotFound => HttpResponse::NotFound().json(format!("No collection '{}'", uri)),
+        StoreError::Invalid(e) => HttpResponse::BadRequest().json(e),
+        StoreError::Unavailable(e) => HttpResponse::ServiceUnavailable().json(format!("Failed to truncate collection: {}", e)),
+        e => HttpResponse::InternalServerError().json(format!("Failed to truncate collection: {}", e)),
+    }
+}
+
+/// A collection in the answer of `GET /_collections`.
+#[derive(Debug, Serialize)]
+pub struct CollectionSummary {
+    pub name: String,
+    pub documents: i64,
+}
+
+// 列出所有集合及其文档数; 分片和分区的集合只列一次, 嵌套对象的子表不列
+async fn all_collections(
+    pool: web::Data<SqlitePool>,
+    shards: web::Data<Shards>,
+    partitions: web::Data<Partitions>,
+    config: web::Data<ConfigHandle>,
+) -> HttpResponse {
+    let mut uris: BTreeSet<String> = match list_collections(&pool).await {
+        Ok(tables) => tables.iter().map(|table| uri_of(table)).collect(),
+        Err(e) => return HttpResponse::InternalServerError().json(format!("Failed to list collections: {}", e)),
+    };
+    let config = config.get();
+    uris.extend(config.partitioning.keys().cloned());
+    if let Some(sharding) = &config.sharding {
+        uris.extend(sharding.collections.keys().cloned());
+    }
+
+    let mut collections = Vec::new();
+    for uri in uris {
+        let counted = async {
+            let tables = collection_tables(&pool, &shards, &partitions, &uri).await?;
+            let mut documents = 0;
+            for (pool, table) in &tables {
+                documents += count_rows(pool, table).await?;
+            }
+            Ok::<_, sqlx::Error>((tables.len(), documents))
+        };
+        match counted.await {
+            // 配置了分片或分区但还没有写入过
+            Ok((0, _)) => {}
+            Ok((_, documents)) => collections.push(CollectionSummary { name: uri, documents }),
+            Err(e) => return HttpResponse::InternalServerError().json(format!("Failed to list collections: {}", e)),
+        }
+    }
+    HttpResponse::Ok().json(collections)
+}
+
+/// Column types `POST /_collections` can declare.
+pub const COLUMN_TYPES: [&str; 5] = ["INTEGER", "REAL", "NUMERIC", "BOOLEAN", "TEXT"];
+
+#[derive(Debug, Deserialize)]
+pub struct CreateRequest {
+    pub name: String,
+    /// Columns to declare, by field, with one of [`COLUMN_TYPES`].
+    #[serde(default)]
+    pub fields: BTreeMap<String, String>,
+    /// Declared fields to index.
+    #[serde(default)]
+    pub indexes: Vec<String>,
+}
+
+// 显式创建一个空集合, 可以预先声明列的类型和索引
+async fn create_collection(
+    body: web::Json<CreateRequest>,
+    pool: web::Data<SqlitePool>,
+    shards: web::Data<Shards>,
+    partitions: web::Data<Partitions>,
+    config: web::Data<ConfigHandle>,
+) -> HttpResponse {
+    let CreateRequest { name, fields, indexes } = body.into_inner();
+    if reserved(&name) {
+        return HttpResponse::BadRequest().json(format!("Collection name '{}' is reserved", name));
+    }
+    if !valid_name(&name) {
+        return HttpResponse::BadRequest().json("Collection names are segments that start with a letter and hold only letters, digits and underscores");
+    }
+    if let Some(response) = unmanaged(&name, &shards, &partitions, &config).await {
+        return response;
+    }
+
+    let declared: Value = fields.keys().map(|field| (field.clone(), Value::Null)).collect::<serde_json::Map<_, _>>().into();
+    if let Err(StoreError::Invalid(e)) = check_fields(&declared) {
+        return HttpResponse::BadRequest().json(e);
+    }
+    let mut columns = Vec::new();
+    for (field, ty) in &fields {
+        if field == "id" || field == VERSION_FIELD {
+            return HttpResponse::BadRequest().json(format!("Field '{}' cannot be declared", field));
+        }
+        match COLUMN_TYPES.into_iter().find(|known| known.eq_ignore_ascii_case(ty)) {
+            Some(ty) => columns.push((field.as_str(), ty)),
+            None => {
+                return HttpResponse::BadRequest().json(format!("Field '{}' has type '{}', not one of {}", field, ty, COLUMN_TYPES.join(", ")))
+            }
+        }
+    }
+    if let Some(field) = indexes.iter().find(|field| !fields.contains_key(*field)) {
+        return HttpResponse::BadRequest().json(format!("Indexed field '{}' is not declared in fields", field));
+    }
+
+    match create(&pool, &name, &columns, &indexes).await {
+        Ok(true) => {
+            log::info!("created collection '{}'", name);
+            HttpResponse::Created().json(json!({ "collection": name, "fields": fields, "indexes": indexes }))
+        }
+        Ok(false) => HttpResponse::Conflict().json(format!("Collection '{}' already exists", name)),
+        Err(e) => HttpResponse::InternalServerError().json(format!("Failed to create collection: {}", e)),
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct DropParams {
+    pub confirm: Option<String>,
+}
+
+// 删除集合及其嵌套对象的子表; 没有确认令牌时只返回文档数量和令牌
+async fn delete_collection(
+    uri: web::Path<String>,
+    params: web::Query<DropParams>,
+    pool: web::Data<SqlitePool>,
+    shards: web::Data<Shards>,
+    partitions: web::Data<Partitions>,
+    config: web::Data<ConfigHandle>,
+    store: web::Data<dyn DocumentStore>,
+) -> HttpResponse {
+    if reserved(&uri) {
+        return HttpResponse::BadRequest().json(format!("Collection name '{}' is reserved", uri));
+    }
+    if let Some(response) = unmanaged(&uri, &shards, &partitions, &config).await {
+        return response;
+    }
+    match collection_exists(&pool, &table_name(&uri)).await {
+        Ok(true) => {}
+        Ok(false) => return HttpResponse::NotFound().json(format!("No collection '{}'", uri)),
+        Err(e) => return HttpResponse::InternalServerError().json(format!("Failed to read collections: {}", e)),
+    }
+    let mut ids = match store.delete_many(&uri, &json!({}), true).await {
+        Ok(ids) => ids,
+        Err(StoreError::NotFound) => return HttpResponse::NotFound().json(format!("No collection '{}'", uri)),
+        Err(e) => return HttpResponse::InternalServerError().json(format!("Failed to read collection: {}", e)),
+    };
+    ids.sort_unstable();
+    let token = confirmation("drop", &uri, &ids);
+    if params.confirm.as_deref() != Some(token.as_str()) {
+        return HttpResponse::PreconditionRequired().json(Confirmation { documents: ids.len(), confirm: token });
+    }
+
+    match drop_collection(&pool, &uri).await {
+        Ok(tables) => {
+            log::warn!("dropped collection '{}' with {} documents", uri, ids.len());
+            HttpResponse::Ok().json(json!({ "collection": uri.as_str(), "documents": ids.len(), "tables": tables }))
+        }
+        Err(e) => HttpResponse::InternalServerError().json(format!("Failed to drop collection: {}", e)),
+    }
+}
+
+// 分片和分区的集合由写入创建, 集群中的节点各自建表; 不能在这里创建或删除时返回要回复的响应
+async fn unmanaged(uri: &str, shards: &Shards, partitions: &Partitions, config: &ConfigHandle) -> Option<HttpResponse> {
+    let table = table_name(uri);
+    if config.get().cluster.is_some() {
+        return Some(HttpResponse::Conflict().json("Collections cannot be created or dropped in cluster mode"));
+    }
+    if shards.get(&table).is_some() {
+        return Some(HttpResponse::Conflict().json("Sharded collections cannot be created or dropped"));
+    }
+    if partitions.tables(&table, None).await.is_some() {
+        return Some(HttpResponse::Conflict().json("Partitioned collections cannot be created or dropped"));
+    }
+    None
+}
+
+#[derive(Debug, Deserialize)]
+pub struct RenameRequest {
+    pub name: String,
+}
+
+/// Whether `name` can name a new collection: `/`-separated segments that
+/// start with a letter and hold only letters, digits and underscores.
+pub fn valid_name(name: &str) -> bool {
+    valid_path(name)
+        && name.split('/').all(|segment| {
+            segment.starts_with(|c: char| c.is_ascii_alphabetic())
+                && segment.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+        })
+}
+
+// 检查 from 是否可以改名或复制为 to; 不可以时返回要回复的响应
+async fn check(
+    from: &str,
+    to: &str,
+    pool: &SqlitePool,
+    shards: &Shards,
+    partitions: &Partitions,
+    config: &ConfigHandle,
+) -> Result<(), HttpResponse> {
+    if let Some(name) = [from, to].into_iter().find(|name| reserved(name)) {
+        return Err(HttpResponse::BadRequest().json(format!("Collection name '{}' is reserved", name)));
+    }
+    if !valid_name(to) {
+        return Err(HttpResponse::BadRequest().json("Collection names are segments that start with a letter and hold only letters, digits and underscores"));
+    }
+    let (from_table, to_table) = (table_name(from), table_name(to));
+    if config.get().cluster.is_some() {
+        return Err(HttpResponse::Conflict().json("Collections cannot be renamed or copied in cluster mode"));
+    }
+    if shards.get(&from_table).is_some() || shards.get(&to_table).is_some() {
+        return Err(HttpResponse::Conflict().json("Sharded collections cannot be renamed or copied"));
+    }
+    if partitions.tables(&from_table, None).await.is_some() || partitions.tables(&to_table, None).await.is_some() {
+        return Err(HttpResponse::Conflict().json("Partitioned collections cannot be renamed or copied"));
+    }
+    if [from, to].into_iter().any(|name| config.get().collections.get(name).is_some_and(|c| c.splits())) {
+        return Err(HttpResponse::Conflict().json("Nested collections cannot be renamed or copied"));
+    }
+    match collection_exists(pool, &from_table).await {
+        Ok(true) => {}
+        Ok(false) => return Err(HttpResponse::NotFound().json(format!("No collection '{}'", from))),
+        Err(e) => return Err(HttpResponse::InternalServerError().json(format!("Failed to read collections: {}", e))),
+    }
+    match collection_exists(pool, &to_table).await {
+        Ok(false) => Ok(()),
+        Ok(true) => Err(HttpResponse::Conflict().json(format!("Collection '{}' already exists", to))),
+        Err(e) => Err(HttpResponse::InternalServerError().json(format!("Failed to read collections: {}", e))),
+    }
+}
+
+// 重命名集合; 分片, 分区, 有配置的集合以及集群节点不能重命名
+async fn rename_collection(
+    uri: web::Path<String>,
+    body: web::Json<RenameRequest>,
+    pool: web::Data<SqlitePool>,
+    shards: web::Data<Shards>,
+    partitions: web::Data<Partitions>,
+    config: web::Data<ConfigHandle>,
+) -> HttpResponse {
+    let to = body.into_inner().name;
+    if let Err(response) = check(&uri, &to, &pool, &shards, &partitions, &config).await {
+        return response;
+    }
+    if let Some(name) = [uri.as_str(), to.as_str()].into_iter().find(|name| config.get().collections.contains_key(*name)) {
+        return HttpResponse::Conflict().json(format!("Collection '{}' has settings in the configuration", name));
+    }
+
+    match rename(&pool, &uri, &to).await {
+        Ok(()) => {
+            log::warn!("renamed collection '{}' to '{}'", uri, to);
+            HttpResponse::Ok().json(json!({ "from": uri.as_str(), "to": to }))
+        }
+        Err(e) => HttpResponse::InternalServerError().json(format!("Failed to rename collection: {}", e)),
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CopyRequest {
+    pub name: String,
+    /// Only documents matching this filter are copied; all of them without.
+    pub filter: Option<Value>,
+    /// Copy the table and its indexes without documents.
+    #[serde(default)]
+    pub schema_only: bool,
+}
+
+// 复制集合的表结构, 索引和 (可选地) 符合条件的文档
+async fn copy_collection(
+    uri: web::Path<String>,
+    body: web::Json<CopyRequest>,
+    pool: web::Data<SqlitePool>,
+    shards: web::Data<Shards>,
+    partitions: web::Data<Partitions>,
+    config: web::Data<ConfigHandle>,
+) -> HttpResponse {
+    let CopyRequest { name: to, filter, schema_only } = body.into_inner();
+    if let Err(response) = check(&uri, &to, &pool, &shards, &partitions, &config).await {
+        return response;
+    }
+    let filter = match (schema_only, filter) {
+        (true, Some(_)) => return HttpResponse::BadRequest().json("A schema-only copy takes no filter"),
+        (true, None) => None,
+        (false, filter) => Some(filter.unwrap_or_else(|| json!({}))),
+    };
+
+    match copy(&pool, &uri, &to, filter.as_ref()).await {
+        Ok(copied) => {
+            log::info!("copied collection '{}' to '{}' with {} documents", uri, to, copied);
+            annotate(HttpResponse::Ok().json(json!({ "from": uri.as_str(), "to": to, "copied": copied })), &to, copied as usize)
+        }
+        Err(StoreError::Invalid(e)) => HttpResponse::BadRequest().json(e),
+        Err(e) => HttpResponse::InternalServerError().json(format!("Failed to copy collection: {}", e)),
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct PruneRequest {
+    /// Only list the unused columns.
+    #[serde(default)]
+    pub dry_run: bool,
+}
+
+// 删除集合中没有任何值的列, 按表报告删除了哪些列
+async fn prune_collection(
+    uri: web::Path<String>,
+    body: Option<web::Json<PruneRequest>>,
+    pool: web::Data<SqlitePool>,
+    shards: web::Data<Shards>,
+    partitions: web::Data<Partitions>,
+    checksums: web::Data<Checksums>,
+) -> HttpResponse {
+    let dry_run = body.map(|body| body.into_inner().dry_run).unwrap_or_default();
+    let tables = match collection_tables(&pool, &shards, &partitions, &uri).await {
+        Ok(tables) if tables.is_empty() => return HttpResponse::NotFound().json(format!("No collection '{}'", uri)),
+        Ok(tables) => tables,
+        Err(e) => return HttpResponse::InternalServerError().json(format!("Failed to read collections: {}", e)),
+    };
+
+    let mut dropped = BTreeMap::new();
+    for (pool, table) in tables {
+        match prune_columns(&pool, &table, dry_run).await {
+            Ok(columns) => {
+                if !dry_run && !columns.is_empty() {
+                    log::warn!("dropped unused columns {} of '{}' from {}", columns.join(", "), uri, table);
+                }
+                dropped.insert(table, columns);
+            }
+            Err(e) => return HttpResponse::InternalServerError().json(format!("Failed to prune collection: {}", e)),
+        }
+    }
+    if !dry_run && dropped.values().any(|columns| !columns.is_empty()) {
+        if let Err(e) = checksums.refresh(&uri).await {
+            log::warn!("failed to renew checksums of '{}': {}", uri, e);
+        }
+    }
+    HttpResponse::Ok().json(json!({ "collection": uri.as_str(), "dry_run": dry_run, "dropped": dropped }))
+}
+
+/// Renames the collection `from` to `to`, together with its full-text index,
+/// the indexes named after its table and the rows kept about it elsewhere,
+/// in one transaction.
+pub async fn rename(pool: &SqlitePool, from: &str, to: &str) -> Result<(), sqlx::Error> {
+    let (old, new) = (table_name(from), table_name(to));
+    let mut tx = pool.begin().await?;
+    sqlx::query(&format!("ALTER TABLE {} RENAME TO {}", quote(&old), quote(&new))).execute(&mut *tx).await?;
+
+    #[cfg(feature = "fts")]
+    {
+        // FTS5 会随之重命名它的影子表
+        let fts: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM sqlite_master WHERE type = 'table' AND name = ?")
+            .bind(fts_table(&old))
+            .fetch_one(&mut *tx)
+            .await?;
+        if fts > 0 {
+            sqlx::query(&format!("ALTER TABLE {} RENAME TO {}", quote(&fts_table(&old)), quote(&fts_table(&new)))).execute(&mut *tx).await?;
+        }
+        // 触发器跟着表走, 名字里的旧表名要重建才能改掉
+        if let Some(fields) = fts_fields(&mut tx, &new, &fts_trigger_names(&old)[0]).await? {
+            for trigger in fts_trigger_names(&old) {
+                sqlx::query(&format!("DROP TRIGGER {}", quote(&trigger))).execute(&mut *tx).await?;
+            }
+            for trigger in fts_triggers(&new, &fields) {
+                sqlx::query(&trigger).execute(&mut *tx).await?;
+            }
+        }
+    }
+
+    // 索引跟着表走, 但名字里的旧表名要重建索引才能改掉
+    let indexes: Vec<(String, String)> =
+        sqlx::query_as("SELECT name, sql FROM sqlite_master WHERE type = 'index' AND tbl_name = ? AND sql IS NOT NULL")
+            .bind(&new)
+            .fetch_all(&mut *tx)
+            .await?;
+    for (name, sql) in indexes {
+        if !name.contains(&old) {
+            continue;
+        }
+        let renamed = name.replacen(&old, &new, 1);
+        sqlx::query(&format!("DROP INDEX {}", quote(&name))).execute(&mut *tx).await?;
+        sqlx::query(&sql.replacen(&name, &renamed, 1)).execute(&mut *tx).await?;
+    }
+
+    // 新名字下遗留的记录属于早已不存在的文档
+    for table in ["_content_hashes", "_history", "_checksums", "_tenant_collections"] {
+        sqlx::query(&format!("DELETE FROM {} WHERE collection IN (?, ?)", table))
+            .bind(to)
+            .bind(&new)
+            .execute(&mut *tx)
+            .await?;
+    }
+    for table in ["_content_hashes", "_history", "_checksums", "_acl", "_saved_queries", "_tenant_collections"] {
+        sqlx::query(&format!("UPDATE {} SET collection = ? WHERE collection IN (?, ?)", table))
+            .bind(to)
+            .bind(from)
+            .bind(&old)
+            .execute(&mut *tx)
+            .await?;
+    }
+    tx.commit().await
+}
+
+/// Creates the collection `uri` with the columns `fields` and an index on
+/// each of `indexes`, in one transaction. Returns false if it exists.
+pub async fn create(pool: &SqlitePool, uri: &str, fields: &[(&str, &str)], indexes: &[String]) -> Result<bool, sqlx::Error> {
+    let table = table_name(uri);
+    let mut tx = pool.begin().await?;
+    let exists: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM sqlite_master WHERE type = 'table' AND name = ?")
+        .bind(&table)
+        .fetch_one(&mut *tx)
+        .await?;
+    if exists > 0 {
+        return Ok(false);
+    }
+    sqlx::query(&create_table_statement(&table, fields)).execute(&mut *tx).await?;
+    for field in indexes {
+        let index = format!("{}_{}", table, field);
+        sqlx::query(&format!("CREATE INDEX {} ON {} ({})", quote(&index), quote(&table), quote(field))).execute(&mut *tx).await?;
+    }
+    tx.commit().await?;
+    schema_changed();
+    Ok(true)
+}
+
+/// Drops the collection `uri`: its table, the tables of its nested objects
+/// and arrays, their full-text indexes and the rows kept about them
+/// elsewhere, except ACL entries and saved queries, in one transaction.
+/// Returns the tables dropped.
+pub async fn drop_collection(pool: &SqlitePool, uri: &str) -> Result<Vec<String>, sqlx::Error> {
+    let mut uris = vec![uri.to_string()];
+    uris.extend(chains(pool, uri).await?.iter().map(|chain| nested_uri(uri, chain)));
+    let mut tx = pool.begin().await?;
+    let mut dropped = Vec::new();
+    for uri in &uris {
+        // 触发器和自增计数随表删除
+        let table = table_name(uri);
+        #[cfg(feature = "fts")]
+        sqlx::query(&format!("DROP TABLE IF EXISTS {}", quote(&fts_table(&table)))).execute(&mut *tx).await?;
+        sqlx::query(&format!("DROP TABLE IF EXISTS {}", quote(&table))).execute(&mut *tx).await?;
+        for kept in ["_content_hashes", "_history", "_checksums", "_tenant_collections", "_nested_tables"] {
+            sqlx::query(&format!("DELETE FROM {} WHERE collection IN (?, ?)", kept))
+                .bind(uri)
+                .bind(&table)
+                .execute(&mut *tx)
+                .await?;
+        }
+        dropped.push(table);
+    }
+    tx.commit().await?;
+    schema_changed();
+    Ok(dropped)
+}
+
+/// Copies the collection `from` to `to`: its table, full-text index and
+/// indexes, and the documents matching `filter` unless it is `None`, with
+/// their checksums and content hashes, in one transaction. Returns the
+/// number of documents copied.
+pub async fn copy(pool: &SqlitePool, from: &str, to: &str, filter: Option<&Value>) -> Result<u64, StoreError> {
+    let (old, new) = (table_name(from), table_name(to));
+    let filter = match filter {
+        Some(filter) => {
+            let filter = parse_filter(filter).map_err(|e| StoreError::Invalid(format!("Invalid filter: {}", e)))?;
+            let columns: Vec<String> = table_columns(pool, &old).await?.into_iter().map(|(name, _)| name).collect();
+            filter.validate(&columns).map_err(|e| StoreError::Invalid(format!("Invalid filter: {}", e)))?;
+            Some(filter)
+        }
+        None => None,
+    };
+
+    let mut tx = pool.begin().await?;
+    let definition: String = sqlx::query_scalar("SELECT sql FROM sqlite_master WHERE type = 'table' AND name = ?")
+        .bind(&old)
+        .fetch_one(&mut *tx)
+        .await?;
+    let columns = &definition[definition.find('(').unwrap_or(definition.len())..];
+    sqlx::query(&format!("CREATE TABLE {} {}", quote(&new), columns)).execute(&mut *tx).await?;
+
+    // 索引名中的旧表名换成新表名, 其余的加上新表名作前缀以免重名
+    let indexes: Vec<(String, String)> =
+        sqlx::query_as("SELECT name, sql FROM sqlite_master WHERE type = 'index' AND tbl_name = ? AND sql IS NOT NULL")
+            .bind(&old)
+            .fetch_all(&mut *tx)
+            .await?;
+    for (name, sql) in indexes {
+        let renamed = match name.contains(&old) {
+            true => name.replacen(&old, &new, 1),
+            false => format!("{}_{}", new, name),
+        };
+        let (Some(at), Some(on)) = (sql.find(&name), sql.to_ascii_uppercase().find(" ON ")) else { continue };
+        let Some(columns) = sql[on..].find('(').map(|start| &sql[on + start..]) else { continue };
+        let query = format!("{}{} ON {} {}", &sql[..at], quote(&renamed), quote(&new), columns);
+        sqlx::query(&query).execute(&mut *tx).await?;
+    }
+
+    // 新名字下遗留的记录属于早已不存在的文档
+    for table in ["_content_hashes", "_history", "_checksums"] {
+        sqlx::query(&format!("DELETE FROM {} WHERE collection IN (?, ?)", table))
+            .bind(to)
+            .bind(&new)
+            .execute(&mut *tx)
+            .await?;
+    }
+    let mut copied = 0;
+    if let Some(filter) = filter {
+        let mut params = Vec::new();
+        let condition = filter.to_sql(&mut params);
+        let query = format!("INSERT INTO {} SELECT * FROM {} WHERE {}", quote(&new), quote(&old), condition);
+        let mut statement = sqlx::query(&query);
+        for param in &params {
+            statement = bind_value(statement, Some(param));
+        }
+        copied = statement.execute(&mut *tx).await?.rows_affected();
+
+        let copies = format!("document_id IN (SELECT id FROM {})", quote(&new));
+        sqlx::query(&format!(
+            "INSERT INTO _content_hashes (collection, hash, document_id) SELECT ?, hash, document_id FROM _content_hashes WHERE collection = ? AND {}",
+            copies
+        ))
+        .bind(&new)
+        .bind(&old)
+        .execute(&mut *tx)
+        .await?;
+        sqlx::query(&format!(
+            "INSERT INTO _checksums (collection, document_id, version, checksum) SELECT ?, document_id, version, checksum FROM _checksums WHERE collection = ? AND {}",
+            copies
+        ))
+        .bind(to)
+        .bind(from)
+        .execute(&mut *tx)
+        .await?;
+    }
+
+    #[cfg(feature = "fts")]
+    {
+        // 全文索引只收录复制过来的文档
+        let fts: Option<String> = sqlx::query_scalar("SELECT sql FROM sqlite_master WHERE type = 'table' AND name = ?")
+            .bind(fts_table(&old))
+            .fetch_optional(&mut *tx)
+            .await?;
+        if let Some(sql) = fts {
+            let module = &sql[sql.to_ascii_uppercase().find(" USING ").unwrap_or(sql.len())..];
+            sqlx::query(&format!("CREATE VIRTUAL TABLE {}{}", quote(&fts_table(&new)), module)).execute(&mut *tx).await?;
+            let fields: Vec<String> = sqlx::query_scalar("SELECT name FROM pragma_table_info(?)")
+                .bind(fts_table(&old))
+                .fetch_all(&mut *tx)
+                .await?;
+            if copied > 0 && !fields.is_empty() {
+                let fields: Vec<String> = fields.iter().map(|field| quote(field)).collect();
+                let fields = fields.join(", ");
+                let query = format!(
+                    "INSERT INTO {} (rowid, {}) SELECT rowid, {} FROM {} WHERE rowid IN (SELECT id FROM {})",
+                    quote(&fts_table(&new)),
+                    fields,
+                    fields,
+                    quote(&fts_table(&old)),
+                    quote(&new)
+                );
+                sqlx::query(&query).execute(&mut *tx).await?;
+            }
+        }
+        if let Some(fields) = fts_fields(&mut tx, &old, &fts_trigger_names(&old)[0]).await? {
+            for trigger in fts_triggers(&new, &fields) {
+                sqlx::query(&trigger).execute(&mut *tx).await?;
+            }
+        }
+    }
+    tx.commit().await?;
+    Ok(copied)
+}
+
+// 有触发器 trigger 时, table 的全文索引的字段
+#[cfg(feature = "fts")]
+async fn fts_fields(tx: &mut Transaction<'_, Sqlite>, table: &str, trigger: &str) -> Result<Option<Vec<String>>, sqlx::Error> {
+    let triggers: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM sqlite_master WHERE type = 'trigger' AND name = ?")
+        .bind(trigger)
+        .fetch_one(&mut **tx)
+        .await?;
+    if triggers == 0 {
+        return Ok(None);
+    }
+    let fields = sqlx::query_scalar("SELECT name FROM pragma_table_info(?)").bind(fts_table(table)).fetch_all(&mut **tx).await?;
+    Ok(Some(fields))
+}
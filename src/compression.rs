@@ -0,0 +1,63 @@
+//! Transparent compression of large values.
+//!
+//! With `compression` configured, a field value whose stored JSON text is at
+//! least `min_bytes` long is compressed with zstd and stored as a BLOB
+//! starting with [`MARKER`], when that is smaller than the text. Reads turn
+//! such values back into JSON, so clients never see the difference. Filters,
+//! search and sorting compare the stored form, so they do not match inside
+//! compressed values; keep `min_bytes` above the size of values you query on.
+//! Turning compression off only affects later writes.
+
+use std::sync::RwLock;
+
+use crate::config::Compression;
+
+/// Prefix of every compressed value.
+pub const MARKER: &[u8] = b"zstd:";
+
+static SETTINGS: RwLock<Option<Compression>> = RwLock::new(None);
+
+/// Applies the `compression` section to later writes.
+pub fn configure(settings: Option<Compression>) {
+    *SETTINGS.write().unwrap() = settings;
+}
+
+/// How a field value is written.
+pub enum Stored {
+    Text(String),
+    Compressed(Vec<u8>),
+}
+
+// 超过阈值且压缩后更小时才压缩
+pub fn encode(text: String) -> Stored {
+    let Some(settings) = SETTINGS.read().unwrap().clone() else {
+        return Stored::Text(text);
+    };
+    if text.len() < settings.min_bytes {
+        return Stored::Text(text);
+    }
+    match zstd::bulk::compress(text.as_bytes(), settings.level) {
+        Ok(compressed) if MARKER.len() + compressed.len() < text.len() => {
+            let mut stored = MARKER.to_vec();
+            stored.extend(compressed);
+            Stored::Compressed(stored)
+        }
+        Ok(_) => Stored::Text(text),
+        Err(e) => {
+            log::warn!("failed to compress a value, storing it as is: {}", e);
+            Stored::Text(text)
+        }
+    }
+}
+
+/// The text of a value stored by [`encode`], or `None` for other BLOBs.
+pub fn decode(stored: &[u8]) -> Option<String> {
+    let compressed = stored.strip_prefix(MARKER)?;
+    match zstd::decode_all(compressed) {
+        Ok(text) => String::from_utf8(text).ok(),
+        Err(e) => {
+            log::warn!("failed to decompress a stored value: {}", e);
+            None
+        }
+    }
+}
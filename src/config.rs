@@ -0,0 +1,1263 @@
+use log::LevelFilter;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::path::PathBuf;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime};
+
+use crate::cron::Cron;
+use crate::ip_filter::Cidr;
+use crate::store::{reserved, DedupMode, VERSION_FIELD};
+use crate::{access_log, compression, logging};
+
+/// Runtime configuration, read from the JSON file named by `CONFIG_PATH`.
+///
+/// Every field has a default, so a missing file or a partial file is fine.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Config {
+    /// One of off, error, warn, info, debug, trace.
+    pub log_level: String,
+    /// Address the server listens on. Read at startup only.
+    pub listen: String,
+    /// Keys accepted in `Authorization: Bearer` or `X-API-Key`. Empty disables auth.
+    pub api_keys: Vec<ApiKey>,
+    /// Write logs to a rotating file instead of stderr.
+    pub log_file: Option<LogFile>,
+    pub access_log: AccessLog,
+    /// Which requests are traced and written to the access log.
+    pub sampling: Sampling,
+    pub ip_filter: IpFilter,
+    /// Serve HTTPS instead of plain HTTP. Read at startup only.
+    pub tls: Option<Tls>,
+    /// Client certificate subjects accepted as principals, like `api_keys`.
+    pub client_certs: Vec<ClientCert>,
+    /// Single sign-on for the admin UI through an OpenID Connect provider.
+    pub oidc: Option<Oidc>,
+    pub sessions: Sessions,
+    /// Shared secrets for HMAC-signed requests, for callers that cannot hold an API key.
+    pub request_signing: RequestSigning,
+    pub idempotency: Idempotency,
+    /// Transactions opened with `POST /_txn`.
+    pub transactions: Transactions,
+    /// Background jobs, such as imports.
+    pub jobs: Jobs,
+    /// The tasks each collection's writes go through.
+    pub writers: Writers,
+    /// Page sizes of `GET /{uri}`.
+    pub listing: Listing,
+    /// Limits of `POST /_sql`.
+    pub sql: Sql,
+    /// Tasks run on cron expressions, keyed by name.
+    pub schedules: BTreeMap<String, Schedule>,
+    /// Conditions checked every minute, keyed by name.
+    pub alerts: BTreeMap<String, Alert>,
+    /// Limits on what each tenant stores.
+    pub quotas: Quotas,
+    /// Request budgets of callers, by key or tenant.
+    pub rate_limits: RateLimits,
+    /// Per-collection settings, keyed by uri.
+    pub collections: BTreeMap<String, CollectionSettings>,
+    /// Store messages from an MQTT broker as documents. Read at startup only.
+    pub mqtt: Option<Mqtt>,
+    /// Store records of Kafka topics as documents and publish change events. Read at startup only.
+    pub kafka: Option<Kafka>,
+    /// Redis shared by all instances, used as a read cache. Read at startup only.
+    pub redis: Option<Redis>,
+    /// Run as one node of a cluster with a single write leader. Read at startup only.
+    pub cluster: Option<Cluster>,
+    /// Collections spread over several SQLite files. Read at startup only.
+    pub sharding: Option<Sharding>,
+    /// Collections stored in one table per day or month, keyed by uri. Read at startup only.
+    pub partitioning: BTreeMap<String, Partitioning>,
+    /// Store large field values compressed with zstd.
+    pub compression: Option<Compression>,
+    /// Record a checksum of every document written, checked by `POST /_admin/verify`.
+    pub checksums: bool,
+    /// Copy the database's write-ahead log to a directory for point-in-time
+    /// recovery. Read at startup only.
+    pub archive: Option<Archive>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            log_level: "info".to_string(),
+            listen: "127.0.0.1:8080".to_string(),
+            api_keys: Vec::new(),
+            log_file: None,
+            access_log: AccessLog::default(),
+            sampling: Sampling::default(),
+            ip_filter: IpFilter::default(),
+            tls: None,
+            client_certs: Vec::new(),
+            oidc: None,
+            sessions: Sessions::default(),
+            request_signing: RequestSigning::default(),
+            idempotency: Idempotency::default(),
+            transactions: Transactions::default(),
+            jobs: Jobs::default(),
+            writers: Writers::default(),
+            listing: Listing::default(),
+            sql: Sql::default(),
+            schedules: BTreeMap::new(),
+            alerts: BTreeMap::new(),
+            quotas: Quotas::default(),
+            rate_limits: RateLimits::default(),
+            collections: BTreeMap::new(),
+            mqtt: None,
+            kafka: None,
+            redis: None,
+            cluster: None,
+            sharding: None,
+            partitioning: BTreeMap::new(),
+            compression: None,
+            checksums: false,
+            archive: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct LogFile {
+    pub path: PathBuf,
+    /// Rotate once the file reaches this size. 0 disables size-based rotation.
+    #[serde(default = "default_max_bytes")]
+    pub max_bytes: u64,
+    #[serde(default)]
+    pub rotate: Rotation,
+    /// Number of rotated files to keep; `<path>.1` is the most recent.
+    #[serde(default = "default_keep")]
+    pub keep: usize,
+}
+
+fn default_max_bytes() -> u64 {
+    10 * 1024 * 1024
+}
+
+fn default_keep() -> usize {
+    5
+}
+
+/// One line per request, written to stdout or `file`, separately from the application log.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct AccessLog {
+    pub enabled: bool,
+    pub format: AccessLogFormat,
+    pub file: Option<LogFile>,
+}
+
+impl Default for AccessLog {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            format: AccessLogFormat::Combined,
+            file: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AccessLogFormat {
+    /// Apache/nginx combined log format followed by `key=value` extras.
+    #[default]
+    Combined,
+    Json,
+}
+
+/// Shares of requests traced and logged, from 0 to 1, drawn per request.
+/// Metrics count every request regardless.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Sampling {
+    pub reads: f64,
+    pub writes: f64,
+    /// Share of requests answered with a 5xx status.
+    pub errors: f64,
+    /// Requests taking at least this long are always kept.
+    pub slow_ms: Option<u64>,
+}
+
+impl Default for Sampling {
+    fn default() -> Self {
+        Self { reads: 1.0, writes: 1.0, errors: 1.0, slow_ms: None }
+    }
+}
+
+/// Address ranges allowed to reach the server. All lists empty means no filtering.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct IpFilter {
+    /// If non-empty, only these ranges may connect.
+    pub allow: Vec<Cidr>,
+    /// Always rejected, even when also allowed.
+    pub deny: Vec<Cidr>,
+    /// If non-empty, requests other than GET/HEAD/OPTIONS must come from these ranges.
+    pub write_allow: Vec<Cidr>,
+    /// Proxies whose `X-Forwarded-For` header is trusted to name the client.
+    pub trusted_proxies: Vec<Cidr>,
+}
+
+impl IpFilter {
+    pub fn is_active(&self) -> bool {
+        !(self.allow.is_empty() && self.deny.is_empty() && self.write_allow.is_empty())
+    }
+}
+
+/// Time-based rotation, on top of the size limit.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Rotation {
+    #[default]
+    Never,
+    Hourly,
+    Daily,
+}
+
+impl Rotation {
+    /// Length of a rotation period in seconds.
+    pub fn period(self) -> Option<u64> {
+        match self {
+            Rotation::Never => None,
+            Rotation::Hourly => Some(3600),
+            Rotation::Daily => Some(86400),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ApiKey {
+    /// Stable name for the key, used in logs instead of the secret.
+    pub id: String,
+    #[serde(skip_serializing)]
+    pub key: String,
+    #[serde(default)]
+    pub role: Role,
+    /// Tenant the key belongs to, reported in the access log.
+    #[serde(default)]
+    pub tenant: Option<String>,
+}
+
+/// Requests signed with `X-Signature`; see [`crate::auth::authenticate`].
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RequestSigning {
+    pub keys: Vec<SigningKey>,
+    /// How far `X-Signature-Timestamp` may be from the server clock.
+    pub tolerance_secs: u64,
+}
+
+impl Default for RequestSigning {
+    fn default() -> Self {
+        Self { keys: Vec::new(), tolerance_secs: 300 }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SigningKey {
+    /// Sent in `X-Signature-Key`; also the principal id.
+    pub id: String,
+    #[serde(skip_serializing)]
+    pub secret: String,
+    #[serde(default)]
+    pub role: Role,
+    #[serde(default)]
+    pub tenant: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Tls {
+    /// PEM certificate chain.
+    pub cert: PathBuf,
+    /// PEM private key.
+    pub key: PathBuf,
+    /// PEM bundle of CAs that client certificates are verified against.
+    #[serde(default)]
+    pub client_ca: Option<PathBuf>,
+    /// Reject connections without a valid client certificate. Requires `client_ca`.
+    #[serde(default)]
+    pub require_client_cert: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ClientCert {
+    /// Full subject (`CN=ingest,O=Acme`) or just the common name (`ingest`).
+    pub subject: String,
+    #[serde(default)]
+    pub role: Role,
+    #[serde(default)]
+    pub tenant: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Oidc {
+    /// Issuer URL; discovery is read from `<issuer>/.well-known/openid-configuration`.
+    pub issuer: String,
+    pub client_id: String,
+    #[serde(skip_serializing)]
+    pub client_secret: String,
+    /// Must point at `/_auth/callback` on this server.
+    pub redirect_url: String,
+    #[serde(default = "default_scopes")]
+    pub scopes: Vec<String>,
+    /// ID token claim holding the user's groups or roles.
+    #[serde(default = "default_role_claim")]
+    pub role_claim: String,
+    /// Claim value to role; a user gets the highest role any of their values maps to.
+    #[serde(default)]
+    pub roles: BTreeMap<String, Role>,
+    /// Role for users none of whose claim values are mapped. None denies them.
+    #[serde(default)]
+    pub default_role: Option<Role>,
+}
+
+fn default_scopes() -> Vec<String> {
+    vec!["openid".to_string(), "profile".to_string(), "email".to_string()]
+}
+
+fn default_role_claim() -> String {
+    "groups".to_string()
+}
+
+/// Browser sessions of the admin UI (API key or SSO sign-in).
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Sessions {
+    pub ttl_secs: u64,
+    /// Signs session cookies. Read at startup only; if unset a random secret is used.
+    #[serde(skip_serializing)]
+    pub secret: Option<String>,
+}
+
+impl Default for Sessions {
+    fn default() -> Self {
+        Self { ttl_secs: 8 * 3600, secret: None }
+    }
+}
+
+/// Replays of POST requests carrying `Idempotency-Key`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Idempotency {
+    /// How long a key and its response are kept.
+    pub ttl_secs: u64,
+}
+
+impl Default for Idempotency {
+    fn default() -> Self {
+        Self { ttl_secs: 24 * 3600 }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Transactions {
+    /// A transaction without a request for this long is rolled back.
+    pub timeout_secs: u64,
+    /// Transactions open at once; each keeps a connection of the pool.
+    pub max_open: usize,
+}
+
+impl Default for Transactions {
+    fn default() -> Self {
+        Self { timeout_secs: 30, max_open: 2 }
+    }
+}
+
+/// The queue running background jobs.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Jobs {
+    /// Jobs run at once. Read at startup only.
+    pub workers: usize,
+    /// Times a failing job is run before it is given up.
+    pub max_attempts: u32,
+    /// Wait before the first retry, doubled for every further one.
+    pub backoff_secs: u64,
+    /// How long finished jobs are kept.
+    pub keep_secs: u64,
+}
+
+impl Default for Jobs {
+    fn default() -> Self {
+        Self { workers: 2, max_attempts: 3, backoff_secs: 5, keep_secs: 7 * 24 * 3600 }
+    }
+}
+
+/// The task of each collection that runs its writes in order.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Writers {
+    /// Writes a task takes from its queue at a time. Read at startup only.
+    pub batch: usize,
+    /// A task without writes for this long ends. Read at startup only.
+    pub idle_secs: u64,
+}
+
+impl Default for Writers {
+    fn default() -> Self {
+        Self { batch: 64, idle_secs: 60 }
+    }
+}
+
+/// How many documents one page of `GET /{uri}` holds.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Listing {
+    /// Documents returned without `?limit=`.
+    pub default_limit: usize,
+    /// The largest `?limit=` accepted; larger ones are lowered to it.
+    pub max_limit: usize,
+}
+
+impl Default for Listing {
+    fn default() -> Self {
+        Self { default_limit: 1000, max_limit: 10000 }
+    }
+}
+
+/// Limits of the read-only statements of `POST /_sql`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Sql {
+    /// Rows returned; the rest are left out.
+    pub max_rows: usize,
+    /// Time a statement may run before it is interrupted.
+    pub timeout_ms: u64,
+}
+
+impl Default for Sql {
+    fn default() -> Self {
+        Self { max_rows: 1000, timeout_ms: 5000 }
+    }
+}
+
+/// A task run whenever its cron expression matches.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Schedule {
+    /// Minute, hour, day of month, month and day of week, in UTC.
+    pub cron: String,
+    #[serde(flatten)]
+    pub task: ScheduledTask,
+}
+
+/// What a schedule does. In paths, `{time}` is replaced with the time the
+/// run was due, such as `20261016T0300Z`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "task", rename_all = "lowercase")]
+pub enum ScheduledTask {
+    /// Write the documents of a collection to a newline-delimited JSON file.
+    Export { collection: String, path: String },
+    /// Delete the documents whose `field`, a Unix time in seconds, is older
+    /// than `older_than_secs`.
+    Cleanup { collection: String, field: String, older_than_secs: u64 },
+    /// Copy the database to a file.
+    Backup { path: String },
+}
+
+/// Limits of tenants; a tenant's collections are the ones its callers created.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Quotas {
+    /// Limits of tenants without an entry in `tenants`.
+    pub default: Option<Quota>,
+    pub tenants: BTreeMap<String, Quota>,
+}
+
+impl Quotas {
+    /// The limits of `tenant`, if it has any.
+    pub fn of(&self, tenant: &str) -> Option<&Quota> {
+        self.tenants.get(tenant).or(self.default.as_ref())
+    }
+}
+
+/// Limits of one tenant; a missing limit is no limit.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Quota {
+    pub documents: Option<u64>,
+    /// Bytes of the tenant's tables and their indexes in the database files.
+    pub bytes: Option<u64>,
+    pub collections: Option<u64>,
+}
+
+/// Tiers of request budgets and who gets which.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RateLimits {
+    /// Budgets by tier name.
+    pub tiers: BTreeMap<String, Tier>,
+    /// Tier of callers not named in `keys` or `tenants`; callers without a
+    /// key are told apart by address.
+    pub default: Option<String>,
+    /// Tier by principal id, with a budget of its own.
+    pub keys: BTreeMap<String, String>,
+    /// Tier by tenant, with a budget shared by the tenant's callers.
+    pub tenants: BTreeMap<String, String>,
+}
+
+/// Requests allowed per minute; bursts may use a whole minute's budget at
+/// once. A missing budget is no limit.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Tier {
+    pub reads_per_minute: Option<u32>,
+    pub writes_per_minute: Option<u32>,
+}
+
+/// A condition logged, and sent to `webhook`, when it starts and stops
+/// holding.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Alert {
+    #[serde(flatten)]
+    pub condition: Condition,
+    /// URL the alert events are POSTed to.
+    pub webhook: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "when", rename_all = "snake_case")]
+pub enum Condition {
+    /// The collection holds more than `above` documents.
+    Documents { collection: String, above: u64 },
+    /// More than the fraction `above` of the requests of the last
+    /// `window_secs` failed with a 5xx status.
+    ErrorRate { above: f64, window_secs: u64 },
+    /// The schedule has not run successfully for `hours`.
+    ScheduleStale { schedule: String, hours: u64 },
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct CollectionSettings {
+    /// Recognise re-sent documents by a hash of their content.
+    pub dedup: Option<Dedup>,
+    /// Keep every version of the collection's documents.
+    pub history: Option<History>,
+    /// Key casing of the documents read, unless a request asks with `?case=`.
+    pub case: Option<KeyCase>,
+    /// Store the objects in documents in tables of their own, see [`crate::nested`].
+    pub nested: bool,
+    /// Store the elements of arrays in documents in tables of their own, see
+    /// [`crate::nested`].
+    pub arrays: bool,
+}
+
+impl CollectionSettings {
+    /// Whether objects or arrays of the collection's documents are stored in
+    /// tables of their own.
+    pub fn splits(&self) -> bool {
+        self.nested || self.arrays
+    }
+}
+
+/// Casing document keys are renamed to in responses.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum KeyCase {
+    /// `firstName`
+    Camel,
+    /// `first_name`
+    Snake,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct History {
+    /// Versions are stored as changes to the one before, with a full copy
+    /// after this many changes so rebuilding a version stays cheap.
+    #[serde(default = "default_snapshot_every")]
+    pub snapshot_every: u32,
+}
+
+fn default_snapshot_every() -> u32 {
+    20
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Dedup {
+    pub mode: DedupMode,
+    /// Hash only these top-level fields. Empty hashes the whole document.
+    #[serde(default)]
+    pub fields: Vec<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Mqtt {
+    pub host: String,
+    #[serde(default = "default_mqtt_port")]
+    pub port: u16,
+    /// Also names the broker session, which survives reconnects.
+    #[serde(default = "default_mqtt_client_id")]
+    pub client_id: String,
+    #[serde(default)]
+    pub username: Option<String>,
+    #[serde(default, skip_serializing)]
+    pub password: Option<String>,
+    #[serde(default = "default_mqtt_keep_alive")]
+    pub keep_alive_secs: u64,
+    /// Subscription QoS: 0, 1 or 2.
+    #[serde(default = "default_mqtt_qos")]
+    pub qos: u8,
+    /// Checked in order; a message is stored by the first route matching its topic.
+    pub routes: Vec<MqttRoute>,
+}
+
+fn default_mqtt_port() -> u16 {
+    1883
+}
+
+fn default_mqtt_client_id() -> String {
+    "json_storage".to_string()
+}
+
+fn default_mqtt_keep_alive() -> u64 {
+    30
+}
+
+fn default_mqtt_qos() -> u8 {
+    1
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MqttRoute {
+    /// Topic filter, with `+` and `#` wildcards.
+    pub topic: String,
+    /// Defaults to the filter's literal levels joined by `_`, so
+    /// `sensors/+/telemetry` is stored in `sensors_telemetry`.
+    #[serde(default)]
+    pub collection: Option<String>,
+    /// Field that receives the message's actual topic.
+    #[serde(default)]
+    pub topic_field: Option<String>,
+}
+
+impl MqttRoute {
+    pub fn collection(&self) -> String {
+        match &self.collection {
+            Some(collection) => collection.clone(),
+            None => self
+                .topic
+                .split('/')
+                .filter(|level| !level.is_empty() && *level != "+" && *level != "#")
+                .collect::<Vec<_>>()
+                .join("_"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Kafka {
+    /// Comma-separated `host:port` bootstrap list.
+    pub brokers: String,
+    #[serde(default = "default_kafka_group_id")]
+    pub group_id: String,
+    /// Further librdkafka settings, such as `security.protocol` or `sasl.password`.
+    #[serde(default, skip_serializing)]
+    pub properties: BTreeMap<String, String>,
+    #[serde(default)]
+    pub sources: Vec<KafkaSource>,
+    /// Topic receiving the change events of each collection; `*` covers the
+    /// collections without an entry of their own.
+    #[serde(default)]
+    pub changes: BTreeMap<String, String>,
+}
+
+impl Kafka {
+    /// Topic for the change events of `collection`, if it is captured.
+    pub fn change_topic(&self, collection: &str) -> Option<&str> {
+        self.changes.get(collection).or_else(|| self.changes.get("*")).map(String::as_str)
+    }
+}
+
+fn default_kafka_group_id() -> String {
+    "json_storage".to_string()
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct KafkaSource {
+    pub topic: String,
+    /// Defaults to the topic name with `.` and `-` replaced by `_`.
+    #[serde(default)]
+    pub collection: Option<String>,
+    /// Field that receives the record key, as text.
+    #[serde(default)]
+    pub key_field: Option<String>,
+}
+
+impl KafkaSource {
+    pub fn collection(&self) -> String {
+        match &self.collection {
+            Some(collection) => collection.clone(),
+            None => self.topic.replace(['.', '-'], "_"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Redis {
+    /// `redis://[user:password@]host:port[/db]`, or `rediss://` for TLS.
+    #[serde(skip_serializing)]
+    pub url: String,
+    /// Prefix of every key, so several deployments can share one Redis.
+    #[serde(default = "default_redis_prefix")]
+    pub key_prefix: String,
+    /// How long a document fetched by id stays cached. 0 disables it.
+    #[serde(default = "default_document_ttl")]
+    pub document_ttl_secs: u64,
+    /// How long a collection listing stays cached. 0 disables it.
+    #[serde(default = "default_list_ttl")]
+    pub list_ttl_secs: u64,
+    /// Listings with more documents than this are not cached.
+    #[serde(default = "default_list_max_documents")]
+    pub list_max_documents: usize,
+}
+
+fn default_redis_prefix() -> String {
+    "json_storage".to_string()
+}
+
+fn default_document_ttl() -> u64 {
+    60
+}
+
+fn default_list_ttl() -> u64 {
+    5
+}
+
+fn default_list_max_documents() -> usize {
+    1000
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Cluster {
+    /// Name of this node, unique in the cluster. Ties in elections go to the lowest.
+    pub node_id: String,
+    /// Base URL the other nodes reach this one at, like `http://10.0.0.1:8080`.
+    pub advertise_url: String,
+    /// Base URLs of the other nodes.
+    pub peers: Vec<String>,
+    /// Shared by all nodes and sent in `X-Cluster-Secret` on their requests to each other.
+    #[serde(skip_serializing)]
+    pub secret: String,
+    /// How often nodes ask each other for their status.
+    #[serde(default = "default_heartbeat_ms")]
+    pub heartbeat_ms: u64,
+    /// How long a node goes without hearing from a leader (or, as leader,
+    /// from a majority) before it gives up on it.
+    #[serde(default = "default_election_timeout_ms")]
+    pub election_timeout_ms: u64,
+    /// Log entries the leader keeps; followers further behind copy everything again.
+    #[serde(default = "default_log_retention")]
+    pub log_retention: i64,
+}
+
+fn default_heartbeat_ms() -> u64 {
+    1000
+}
+
+fn default_election_timeout_ms() -> u64 {
+    5000
+}
+
+fn default_log_retention() -> i64 {
+    100_000
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Sharding {
+    /// Directory holding the shard files, created if missing.
+    #[serde(default = "default_shard_directory")]
+    pub directory: PathBuf,
+    /// Sharded collections, keyed by uri.
+    pub collections: BTreeMap<String, ShardedCollection>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ShardedCollection {
+    /// Number of shard files. Cannot change once documents are stored.
+    pub shards: u32,
+    /// Field whose value picks the shard of a new document, so documents
+    /// with the same value share a file. Without it they are spread evenly.
+    #[serde(default)]
+    pub key: Option<String>,
+}
+
+fn default_shard_directory() -> PathBuf {
+    PathBuf::from("shards")
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Compression {
+    /// Values whose JSON text is shorter than this are stored as is.
+    #[serde(default = "default_compression_min_bytes")]
+    pub min_bytes: usize,
+    /// zstd level, 1 (fastest) to 22 (smallest).
+    #[serde(default = "default_compression_level")]
+    pub level: i32,
+}
+
+fn default_compression_min_bytes() -> usize {
+    1024
+}
+
+fn default_compression_level() -> i32 {
+    3
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Archive {
+    /// Directory receiving base copies and WAL segments, created if missing.
+    pub directory: PathBuf,
+    /// Seconds between WAL segments, which is how close to a chosen time a
+    /// restore can get.
+    #[serde(default = "default_segment_secs")]
+    pub segment_secs: u64,
+    /// Seconds between base copies of the database file.
+    #[serde(default = "default_base_backup_secs")]
+    pub base_backup_secs: u64,
+    /// Base copies kept, with the segments needed to replay from them.
+    #[serde(default = "default_keep_bases")]
+    pub keep_bases: usize,
+}
+
+fn default_segment_secs() -> u64 {
+    10
+}
+
+fn default_base_backup_secs() -> u64 {
+    86400
+}
+
+fn default_keep_bases() -> usize {
+    7
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Partitioning {
+    /// Numeric field holding the document's time in Unix seconds. Documents
+    /// without it get the time they are stored.
+    pub field: String,
+    #[serde(default)]
+    pub period: Period,
+    /// Periods kept, the current one included; older partitions are dropped.
+    #[serde(default)]
+    pub retention: Option<u32>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Period {
+    #[default]
+    Day,
+    Month,
+}
+
+/// Roles are ordered: every role includes the permissions of the ones before it.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    #[default]
+    Read,
+    Write,
+    Admin,
+}
+
+impl Config {
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        self.level_filter()?;
+        if self.jobs.workers == 0 || self.jobs.max_attempts == 0 {
+            return Err(ConfigError::Invalid("jobs.workers and jobs.max_attempts must be at least 1".to_string()));
+        }
+        if self.writers.batch == 0 {
+            return Err(ConfigError::Invalid("writers.batch must be at least 1".to_string()));
+        }
+        if self.listing.default_limit == 0 || self.listing.default_limit > self.listing.max_limit {
+            return Err(ConfigError::Invalid("listing.default_limit must be between 1 and listing.max_limit".to_string()));
+        }
+        for (name, schedule) in &self.schedules {
+            Cron::parse(&schedule.cron).map_err(|e| ConfigError::Invalid(format!("schedule '{}': {}", name, e)))?;
+            let collection = match &schedule.task {
+                ScheduledTask::Export { collection, .. } | ScheduledTask::Cleanup { collection, .. } => collection,
+                ScheduledTask::Backup { .. } => continue,
+            };
+            if collection.is_empty() || reserved(collection) {
+                return Err(ConfigError::Invalid(format!("schedule '{}' needs a collection that is not reserved", name)));
+            }
+        }
+        let sampling = &self.sampling;
+        if [sampling.reads, sampling.writes, sampling.errors].iter().any(|share| !(0.0..=1.0).contains(share)) {
+            return Err(ConfigError::Invalid("sampling shares must be between 0 and 1".to_string()));
+        }
+        let limits = &self.rate_limits;
+        let mut assigned = limits.default.iter().chain(limits.keys.values()).chain(limits.tenants.values());
+        if let Some(tier) = assigned.find(|tier| !limits.tiers.contains_key(*tier)) {
+            return Err(ConfigError::Invalid(format!("rate_limits names the unknown tier '{}'", tier)));
+        }
+        if let Some(name) = limits.tiers.iter().find(|(_, t)| t.reads_per_minute == Some(0) || t.writes_per_minute == Some(0)).map(|(name, _)| name) {
+            return Err(ConfigError::Invalid(format!("tier '{}' must allow at least one request per minute", name)));
+        }
+        for (name, alert) in &self.alerts {
+            match &alert.condition {
+                Condition::Documents { collection, .. } if collection.is_empty() || reserved(collection) => {
+                    return Err(ConfigError::Invalid(format!("alert '{}' needs a collection that is not reserved", name)));
+                }
+                Condition::ErrorRate { above, window_secs } if !(0.0..=1.0).contains(above) || *window_secs < 60 => {
+                    return Err(ConfigError::Invalid(format!(
+                        "alert '{}' needs a fraction between 0 and 1 and a window of at least 60 seconds",
+                        name
+                    )));
+                }
+                Condition::ScheduleStale { schedule, .. } if !self.schedules.contains_key(schedule) => {
+                    return Err(ConfigError::Invalid(format!("alert '{}' watches the unknown schedule '{}'", name, schedule)));
+                }
+                _ => {}
+            }
+        }
+        if let Some(tls) = &self.tls {
+            if tls.require_client_cert && tls.client_ca.is_none() {
+                return Err(ConfigError::Invalid("tls.require_client_cert needs tls.client_ca".to_string()));
+            }
+        }
+        if let Some(mqtt) = &self.mqtt {
+            if mqtt.qos > 2 {
+                return Err(ConfigError::Invalid(format!("mqtt.qos must be 0, 1 or 2, not {}", mqtt.qos)));
+            }
+            if let Some(route) = mqtt.routes.iter().find(|r| r.collection().is_empty()) {
+                return Err(ConfigError::Invalid(format!("mqtt route '{}' needs a collection", route.topic)));
+            }
+            if let Some(route) = mqtt.routes.iter().find(|r| reserved(&r.collection())) {
+                return Err(ConfigError::Invalid(format!("mqtt route '{}' maps to the reserved collection '{}'", route.topic, route.collection())));
+            }
+        }
+        if let Some(kafka) = &self.kafka {
+            if let Some(source) = kafka.sources.iter().find(|s| s.topic.is_empty() || s.collection().is_empty()) {
+                return Err(ConfigError::Invalid(format!("kafka source '{}' needs a topic and a collection", source.topic)));
+            }
+            if let Some(source) = kafka.sources.iter().find(|s| reserved(&s.collection())) {
+                return Err(ConfigError::Invalid(format!("kafka source '{}' maps to the reserved collection '{}'", source.topic, source.collection())));
+            }
+            if let Some((collection, _)) = kafka.changes.iter().find(|(_, topic)| topic.is_empty()) {
+                return Err(ConfigError::Invalid(format!("kafka changes of '{}' need a topic", collection)));
+            }
+        }
+        if let Some(cluster) = &self.cluster {
+            if cluster.node_id.is_empty() || cluster.secret.is_empty() {
+                return Err(ConfigError::Invalid("cluster needs a node_id and a secret".to_string()));
+            }
+            if cluster.heartbeat_ms == 0 || cluster.election_timeout_ms <= 2 * cluster.heartbeat_ms {
+                return Err(ConfigError::Invalid(
+                    "cluster.election_timeout_ms must be more than twice heartbeat_ms".to_string(),
+                ));
+            }
+        }
+        for (uri, settings) in &self.collections {
+            let Some(history) = &settings.history else { continue };
+            if history.snapshot_every == 0 {
+                return Err(ConfigError::Invalid(format!("history of '{}' needs snapshot_every of at least 1", uri)));
+            }
+            if self.cluster.is_some() {
+                return Err(ConfigError::Invalid("document history is not replicated in cluster mode".to_string()));
+            }
+        }
+        for (uri, _) in self.collections.iter().filter(|(_, settings)| settings.splits()) {
+            if self.cluster.is_some() {
+                return Err(ConfigError::Invalid("nested objects and arrays are not replicated in cluster mode".to_string()));
+            }
+            if self.sharding.as_ref().is_some_and(|s| s.collections.contains_key(uri)) || self.partitioning.contains_key(uri) {
+                return Err(ConfigError::Invalid(format!("nested collection '{}' cannot be sharded or partitioned", uri)));
+            }
+        }
+        if let Some(archive) = &self.archive {
+            if archive.segment_secs == 0 || archive.base_backup_secs == 0 || archive.keep_bases == 0 {
+                return Err(ConfigError::Invalid("archive intervals and keep_bases must be at least 1".to_string()));
+            }
+            if self.sharding.as_ref().is_some_and(|s| !s.collections.is_empty()) {
+                return Err(ConfigError::Invalid("shard files are not archived, archive cannot be combined with sharding".to_string()));
+            }
+        }
+        if let Some(compression) = &self.compression {
+            if !(1..=22).contains(&compression.level) {
+                return Err(ConfigError::Invalid(format!("compression.level must be 1 to 22, not {}", compression.level)));
+            }
+        }
+        for (uri, partitioning) in &self.partitioning {
+            if partitioning.field.is_empty() || partitioning.field == "id" || partitioning.field == VERSION_FIELD {
+                return Err(ConfigError::Invalid(format!("partitioned collection '{}' needs a time field of its own", uri)));
+            }
+            if partitioning.retention == Some(0) {
+                return Err(ConfigError::Invalid(format!("partitioned collection '{}' must keep at least one period", uri)));
+            }
+            if self.cluster.is_some() {
+                return Err(ConfigError::Invalid("partitioned collections are not replicated in cluster mode".to_string()));
+            }
+            if self.sharding.as_ref().is_some_and(|s| s.collections.contains_key(uri)) {
+                return Err(ConfigError::Invalid(format!("collection '{}' cannot be both sharded and partitioned", uri)));
+            }
+        }
+        if let Some(sharding) = &self.sharding {
+            if self.cluster.is_some() && !sharding.collections.is_empty() {
+                return Err(ConfigError::Invalid("sharded collections are not replicated in cluster mode".to_string()));
+            }
+            if let Some((uri, _)) = sharding.collections.iter().find(|(_, c)| c.shards == 0) {
+                return Err(ConfigError::Invalid(format!("sharded collection '{}' needs at least one shard", uri)));
+            }
+            let assigned = |key: &str| key == "id" || key == VERSION_FIELD;
+            if let Some((uri, _)) = sharding.collections.iter().find(|(_, c)| c.key.as_deref().is_some_and(assigned)) {
+                return Err(ConfigError::Invalid(format!("sharded collection '{}' cannot be keyed by a field the server assigns", uri)));
+            }
+        }
+        Ok(())
+    }
+
+    pub fn level_filter(&self) -> Result<LevelFilter, ConfigError> {
+        self.log_level
+            .parse()
+            .map_err(|_| ConfigError::Invalid(format!("unknown log_level '{}'", self.log_level)))
+    }
+
+    /// Whether callers have to identify themselves, i.e. any API key, signing
+    /// key, client certificate or OIDC provider is configured.
+    pub fn auth_enabled(&self) -> bool {
+        !self.api_keys.is_empty()
+            || !self.request_signing.keys.is_empty()
+            || !self.client_certs.is_empty()
+            || self.oidc.is_some()
+    }
+
+    // 切换日志输出; 文件无法打开时保持原来的输出
+    fn open_log_files(&self) -> Result<(), ConfigError> {
+        logging::set_file(self.log_file.as_ref())
+            .map_err(|e| ConfigError::Invalid(format!("cannot open log file: {}", e)))?;
+        access_log::set_file(self.access_log.file.as_ref())
+            .map_err(|e| ConfigError::Invalid(format!("cannot open access log file: {}", e)))
+    }
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Io(std::io::Error),
+    Parse(serde_json::Error),
+    Invalid(String),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::Io(e) => write!(f, "failed to read config: {}", e),
+            ConfigError::Parse(e) => write!(f, "failed to parse config: {}", e),
+            ConfigError::Invalid(msg) => write!(f, "invalid config: {}", msg),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+/// Shared, reloadable view of the configuration.
+///
+/// Readers take a cheap snapshot with [`ConfigHandle::get`]; a reload swaps the
+/// snapshot atomically, so in-flight requests keep the config they started with.
+pub struct ConfigHandle {
+    path: Option<PathBuf>,
+    current: RwLock<Arc<Config>>,
+}
+
+impl ConfigHandle {
+    /// Loads the file named by `CONFIG_PATH`, or the defaults when it is unset.
+    pub fn from_env() -> Result<Self, ConfigError> {
+        let path = std::env::var("CONFIG_PATH").ok().map(PathBuf::from);
+        let config = match &path {
+            Some(path) => read_config(path)?,
+            None => Config::default(),
+        };
+        Ok(Self::new(path, config))
+    }
+
+    pub fn new(path: Option<PathBuf>, config: Config) -> Self {
+        Self {
+            path,
+            current: RwLock::new(Arc::new(config)),
+        }
+    }
+
+    pub fn get(&self) -> Arc<Config> {
+        self.current.read().unwrap().clone()
+    }
+
+    /// Applies settings that take effect outside of the config snapshot itself.
+    pub fn apply(&self) -> Result<(), ConfigError> {
+        let config = self.get();
+        config.validate()?;
+        let level = config.level_filter()?;
+        config.open_log_files()?;
+        logging::set_level(level);
+        compression::configure(config.compression.clone());
+        Ok(())
+    }
+
+    /// Re-reads the config file and applies it. On error the running config is kept.
+    pub fn reload(&self) -> Result<Arc<Config>, ConfigError> {
+        let config = match &self.path {
+            Some(path) => read_config(path)?,
+            None => Config::default(),
+        };
+        config.validate()?;
+        config.open_log_files()?;
+
+        *self.current.write().unwrap() = Arc::new(config);
+        self.apply()?;
+        Ok(self.get())
+    }
+
+    /// Polls the config file and reloads it whenever its modification time changes.
+    pub fn watch(self: Arc<Self>, interval: Duration) {
+        let Some(path) = self.path.clone() else {
+            return;
+        };
+        tokio::spawn(async move {
+            let mut last_modified = modified(&path);
+            loop {
+                tokio::time::sleep(interval).await;
+                let current = modified(&path);
+                if current == last_modified {
+                    continue;
+                }
+                last_modified = current;
+                match self.reload() {
+                    Ok(_) => log::info!("reloaded config from {}", path.display()),
+                    Err(e) => log::error!("keeping previous config: {}", e),
+                }
+            }
+        });
+    }
+}
+
+fn read_config(path: &PathBuf) -> Result<Config, ConfigError> {
+    let content = std::fs::read_to_string(path).map_err(ConfigError::Io)?;
+    serde_json::from_str(&content).map_err(ConfigError::Parse)
+}
+
+fn modified(path: &PathBuf) -> Option<SystemTime> {
+    std::fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::{json, Value};
+
+    fn parse(value: Value) -> Config {
+        serde_json::from_value(value).unwrap()
+    }
+
+    fn cluster() -> Value {
+        json!({ "node_id": "a", "advertise_url": "http://a", "peers": [], "secret": "s" })
+    }
+
+    #[test]
+    fn an_empty_file_gives_the_defaults() {
+        let config = parse(json!({}));
+        config.validate().unwrap();
+        assert_eq!((config.log_level.as_str(), config.listen.as_str()), ("info", "127.0.0.1:8080"));
+        assert_eq!((config.transactions.timeout_secs, config.transactions.max_open), (30, 2));
+        assert_eq!((config.jobs.workers, config.jobs.max_attempts), (2, 3));
+        assert_eq!((config.listing.default_limit, config.listing.max_limit), (1000, 10000));
+        assert_eq!(config.idempotency.ttl_secs, 24 * 3600);
+        assert!(config.access_log.enabled && config.cluster.is_none() && config.archive.is_none());
+        assert!(!config.auth_enabled());
+
+        let config = parse(json!({
+            "collections": { "orders": { "history": {} } },
+            "cluster": cluster(),
+            "archive": { "directory": "archive" },
+            "compression": {},
+        }));
+        assert_eq!(config.collections["orders"].history.as_ref().unwrap().snapshot_every, 20);
+        let cluster = config.cluster.unwrap();
+        assert_eq!((cluster.heartbeat_ms, cluster.election_timeout_ms, cluster.log_retention), (1000, 5000, 100_000));
+        let archive = config.archive.unwrap();
+        assert_eq!((archive.segment_secs, archive.base_backup_secs, archive.keep_bases), (10, 86400, 7));
+        let compression = config.compression.unwrap();
+        assert_eq!((compression.min_bytes, compression.level), (1024, 3));
+    }
+
+    #[test]
+    fn settings_that_cannot_work_are_rejected() {
+        let cases = [
+            (json!({ "log_level": "loud" }), "unknown log_level 'loud'"),
+            (json!({ "jobs": { "workers": 0 } }), "jobs.workers and jobs.max_attempts must be at least 1"),
+            (json!({ "writers": { "batch": 0 } }), "writers.batch must be at least 1"),
+            (json!({ "listing": { "default_limit": 20, "max_limit": 10 } }), "listing.default_limit must be between 1 and listing.max_limit"),
+            (json!({ "sampling": { "reads": 1.5 } }), "sampling shares must be between 0 and 1"),
+            (json!({ "cluster": { "node_id": "", "advertise_url": "", "peers": [], "secret": "s" } }), "cluster needs a node_id and a secret"),
+            (
+                json!({ "cluster": { "node_id": "a", "advertise_url": "", "peers": [], "secret": "s", "election_timeout_ms": 2000 } }),
+                "cluster.election_timeout_ms must be more than twice heartbeat_ms",
+            ),
+            (json!({ "collections": { "orders": { "history": { "snapshot_every": 0 } } } }), "history of 'orders' needs snapshot_every of at least 1"),
+            (json!({ "collections": { "orders": { "history": {} } }, "cluster": cluster() }), "document history is not replicated in cluster mode"),
+            (json!({ "collections": { "orders": { "nested": true } }, "cluster": cluster() }), "nested objects and arrays are not replicated in cluster mode"),
+            (
+                json!({ "collections": { "orders": { "arrays": true } }, "sharding": { "collections": { "orders": { "shards": 2 } } } }),
+                "nested collection 'orders' cannot be sharded or partitioned",
+            ),
+            (
+                json!({ "collections": { "orders": { "nested": true } }, "partitioning": { "orders": { "field": "at" } } }),
+                "nested collection 'orders' cannot be sharded or partitioned",
+            ),
+            (json!({ "archive": { "directory": "a", "keep_bases": 0 } }), "archive intervals and keep_bases must be at least 1"),
+            (
+                json!({ "archive": { "directory": "a" }, "sharding": { "collections": { "orders": { "shards": 2 } } } }),
+                "shard files are not archived, archive cannot be combined with sharding",
+            ),
+            (json!({ "compression": { "level": 23 } }), "compression.level must be 1 to 22, not 23"),
+            (json!({ "partitioning": { "events": { "field": "id" } } }), "partitioned collection 'events' needs a time field of its own"),
+            (json!({ "partitioning": { "events": { "field": "at", "retention": 0 } } }), "partitioned collection 'events' must keep at least one period"),
+            (json!({ "partitioning": { "events": { "field": "at" } }, "cluster": cluster() }), "partitioned collections are not replicated in cluster mode"),
+            (
+                json!({ "partitioning": { "events": { "field": "at" } }, "sharding": { "collections": { "events": { "shards": 2 } } } }),
+                "collection 'events' cannot be both sharded and partitioned",
+            ),
+            (json!({ "sharding": { "collections": { "orders": { "shards": 2 } } }, "cluster": cluster() }), "sharded collections are not replicated in cluster mode"),
+            (json!({ "sharding": { "collections": { "orders": { "shards": 0 } } } }), "sharded collection 'orders' needs at least one shard"),
+            (
+                json!({ "sharding": { "collections": { "orders": { "shards": 2, "key": "_version" } } } }),
+                "sharded collection 'orders' cannot be keyed by a field the server assigns",
+            ),
+        ];
+        for (value, expected) in cases {
+            match parse(value.clone()).validate() {
+                Err(ConfigError::Invalid(message)) => assert_eq!(message, expected, "{}", value),
+                other => panic!("{} gave {:?}", value, other),
+            }
+        }
+    }
+
+    #[test]
+    fn nested_collections_work_with_sharding_and_partitioning_of_other_collections() {
+        let config = parse(json!({
+            "collections": { "orders": { "nested": true, "arrays": true } },
+            "sharding": { "collections": { "users": { "shards": 2 } } },
+            "partitioning": { "events": { "field": "at", "retention": 3 } },
+        }));
+        config.validate().unwrap();
+    }
+
+    #[test]
+    fn a_reload_keeps_the_running_config_when_the_file_is_invalid() {
+        let path = std::env::temp_dir().join(format!("json_storage_config_{}.json", std::process::id()));
+        std::fs::write(&path, json!({ "listing": { "default_limit": 50 } }).to_string()).unwrap();
+        let handle = ConfigHandle::new(Some(path.clone()), Config::default());
+        assert_eq!(handle.reload().unwrap().listing.default_limit, 50);
+
+        std::fs::write(&path, json!({ "listing": { "default_limit": 0 } }).to_string()).unwrap();
+        assert!(matches!(handle.reload(), Err(ConfigError::Invalid(_))));
+        std::fs::write(&path, "{ not json").unwrap();
+        assert!(matches!(handle.reload(), Err(ConfigError::Parse(_))));
+        std::fs::remove_file(&path).unwrap();
+        assert!(matches!(handle.reload(), Err(ConfigError::Io(_))));
+        assert_eq!(handle.get().listing.default_limit, 50);
+    }
+}
@@ -0,0 +1,257 @@
+//! Read-your-writes sessions.
+//!
+//! Every successful request that writes a document answers with an
+//! `X-Session-Token` header. A client that sends the last token it received
+//! back with its next requests sees at least its own writes, even through a
+//! follower or the Redis cache:
+//!
+//! - a cluster follower holds the request until it has replicated the
+//!   leader's log up to the token, and answers 503 when it has not caught up
+//!   within two seconds; a token from an earlier leader counts as replicated
+//!   once the follower copies the current leader's data.
+//! - while a cached value could still predate the write, that is for the
+//!   longer of the Redis TTLs after it, reads carrying the token go to the
+//!   store and refill the cache.
+//!
+//! Tokens are `<term>.<seq>.<milliseconds>` and otherwise opaque to clients.
+//! Requests without a token are served as before.
+
+use actix_web::body::{EitherBody, MessageBody};
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::http::header::{HeaderName, HeaderValue};
+use actix_web::middleware::Next;
+use actix_web::{web, Error, HttpResponse};
+use async_trait::async_trait;
+use serde_json::Value;
+use std::cell::Cell;
+use std::fmt;
+use std::str::FromStr;
+use std::sync::Arc;
+#[cfg(feature = "sqlite")]
+use std::time::Duration;
+
+use crate::cdc::now_millis;
+use crate::config::ConfigHandle;
+use crate::store::{DedupMode, DocumentStore, Inserted, PageQuery, Paged, StoreError, UpdatedMany, Upserted, Written};
+
+pub const HEADER: &str = "X-Session-Token";
+
+/// How long a follower waits to catch up with a token.
+#[cfg(feature = "sqlite")]
+const CATCH_UP: Duration = Duration::from_secs(2);
+
+/// What a session has written: the position of the cluster leader's log its
+/// last write reached, and when it was made.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Token {
+    pub term: u64,
+    pub seq: i64,
+    pub millis: u64,
+}
+
+impl fmt::Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}.{}.{}", self.term, self.seq, self.millis)
+    }
+}
+
+impl FromStr for Token {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, ()> {
+        let mut parts = s.split('.');
+        let (Some(term), Some(seq), Some(millis), None) = (parts.next(), parts.next(), parts.next(), parts.next()) else {
+            return Err(());
+        };
+        Ok(Token {
+            term: term.parse().map_err(|_| ())?,
+            seq: seq.parse().map_err(|_| ())?,
+            millis: millis.parse().map_err(|_| ())?,
+        })
+    }
+}
+
+struct Session {
+    /// Reads have to skip the cache.
+    fresh: bool,
+    wrote: Cell<bool>,
+    /// Log position reached by writes forwarded to the leader.
+    position: Cell<(u64, i64)>,
+}
+
+tokio::task_local! {
+    static SESSION: Session;
+}
+
+/// Whether the current request carries a token recent enough that cached
+/// values may predate its writes.
+pub fn fresh_reads() -> bool {
+    SESSION.try_with(|session| session.fresh).unwrap_or(false)
+}
+
+/// Records that a write of the current request reached `seq` of the log of
+/// the leader at `term`.
+pub fn replicated_to(term: u64, seq: i64) {
+    let _ = SESSION.try_with(|session| session.position.set(session.position.get().max((term, seq))));
+}
+
+fn wrote() {
+    let _ = SESSION.try_with(|session| session.wrote.set(true));
+}
+
+/// Waits for the writes of the session in `X-Session-Token`, and hands out a
+/// new token when the request writes.
+pub async fn track(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<EitherBody<impl MessageBody>>, Error> {
+    let token = match req.headers().get(HEADER).map(|v| v.to_str().ok().and_then(|v| v.parse::<Token>().ok())) {
+        None => None,
+        Some(Some(token)) => Some(token),
+        Some(None) => {
+            let response = HttpResponse::BadRequest().json(format!("{} is not a session token", HEADER));
+            return Ok(req.into_response(response).map_into_right_body());
+        }
+    };
+
+    // 跟随节点先复制到令牌所示的位置
+    #[cfg(feature = "sqlite")]
+    let node = req.app_data::<web::Data<crate::cluster::Node>>().cloned();
+    #[cfg(feature = "sqlite")]
+    if let (Some(node), Some(token)) = (&node, token) {
+        let position = crate::cluster::Position { term: token.term, seq: token.seq };
+        if !node.wait_applied(position, CATCH_UP).await {
+            let response = HttpResponse::ServiceUnavailable().json("This node has not yet replicated the writes of this session");
+            return Ok(req.into_response(response).map_into_right_body());
+        }
+    }
+
+    let ttl = req
+        .app_data::<web::Data<ConfigHandle>>()
+        .and_then(|config| config.get().redis.as_ref().map(|r| r.document_ttl_secs.max(r.list_ttl_secs)))
+        .unwrap_or_default();
+    let fresh = token.is_some_and(|token| token.millis.saturating_add(ttl * 1000) >= now_millis());
+    let session = Session { fresh, wrote: Cell::new(false), position: Cell::new((0, 0)) };
+    let (mut response, session) = SESSION
+        .scope(session, async move {
+            let response = next.call(req).await;
+            (response, SESSION.with(|session| (session.wrote.get(), session.position.get())))
+        })
+        .await;
+    let (wrote, position) = session;
+
+    let Ok(res) = response.as_mut() else {
+        return response.map(ServiceResponse::map_into_left_body);
+    };
+    if wrote && res.status().is_success() {
+        #[cfg(feature = "sqlite")]
+        let position = node.as_ref().map_or(position, |node| {
+            let current = node.status().position;
+            position.max((current.term, current.seq))
+        });
+        let token = Token { term: position.0, seq: position.1, millis: now_millis() };
+        if let Ok(value) = HeaderValue::from_str(&token.to_string()) {
+            res.headers_mut().insert(HeaderName::from_static("x-session-token"), value);
+        }
+    }
+    response.map(ServiceResponse::map_into_left_body)
+}
+
+/// A [`DocumentStore`] that passes every call on to `inner` and notes the
+/// requests that wrote, so [`track`] gives them a token.
+pub struct SessionStore {
+    inner: Arc<dyn DocumentStore>,
+}
+
+#[async_trait]
+impl DocumentStore for SessionStore {
+    async fn insert(&self, uri: &str, doc: &Value) -> Result<i64, StoreError> {
+        let id = self.inner.insert(uri, doc).await?;
+        wrote();
+        Ok(id)
+    }
+
+    async fn list(&self, uri: &str) -> Result<Vec<Value>, StoreError> {
+        self.inner.list(uri).await
+    }
+
+    async fn get(&self, uri: &str, id: i64) -> Result<Option<Value>, StoreError> {
+        self.inner.get(uri, id).await
+    }
+
+    async fn get_many(&self, uri: &str, ids: &[i64]) -> Result<Vec<Value>, StoreError> {
+        self.inner.get_many(uri, ids).await
+    }
+
+    async fn insert_unique(&self, uri: &str, doc: &Value, hash: &str, mode: DedupMode) -> Result<Inserted, StoreError> {
+        let inserted = self.inner.insert_unique(uri, doc, hash, mode).await?;
+        wrote();
+        Ok(inserted)
+    }
+
+    async fn upsert(&self, uri: &str, key: &str, doc: &Value) -> Result<Upserted, StoreError> {
+        let upserted = self.inner.upsert(uri, key, doc).await?;
+        wrote();
+        Ok(upserted)
+    }
+
+    async fn replace(&self, uri: &str, id: i64, doc: &Value, expected: Option<&[i64]>) -> Result<i64, StoreError> {
+        let version = self.inner.replace(uri, id, doc, expected).await?;
+        wrote();
+        Ok(version)
+    }
+
+    async fn update(&self, uri: &str, id: i64, doc: &Value, expected: Option<&[i64]>) -> Result<i64, StoreError> {
+        let version = self.inner.update(uri, id, doc, expected).await?;
+        wrote();
+        Ok(version)
+    }
+
+    async fn delete(&self, uri: &str, id: i64, expected: Option<&[i64]>) -> Result<(), StoreError> {
+        self.inner.delete(uri, id, expected).await?;
+        wrote();
+        Ok(())
+    }
+
+    async fn update_many(&self, uri: &str, filter: &Value, update: &Value) -> Result<UpdatedMany, StoreError> {
+        let updated = self.inner.update_many(uri, filter, update).await?;
+        wrote();
+        Ok(updated)
+    }
+
+    async fn delete_many(&self, uri: &str, filter: &Value, dry_run: bool) -> Result<Vec<i64>, StoreError> {
+        let deleted = self.inner.delete_many(uri, filter, dry_run).await?;
+        if !dry_run {
+            wrote();
+        }
+        Ok(deleted)
+    }
+
+    async fn find(&self, uri: &str, filter: &Value) -> Result<Vec<Value>, StoreError> {
+        self.inner.find(uri, filter).await
+    }
+
+    async fn count(&self, uri: &str, filter: &Value) -> Result<u64, StoreError> {
+        self.inner.count(uri, filter).await
+    }
+
+    async fn find_page(&self, uri: &str, filter: &Value, page: &PageQuery) -> Result<Paged, StoreError> {
+        self.inner.find_page(uri, filter, page).await
+    }
+
+    async fn version(&self, uri: &str) -> Result<String, StoreError> {
+        self.inner.version(uri).await
+    }
+
+    async fn committed(&self, writes: &[Written]) {
+        self.inner.committed(writes).await;
+        if !writes.is_empty() {
+            wrote();
+        }
+    }
+}
+
+/// Wraps `store` in a [`SessionStore`]; it has to be the outermost layer.
+pub fn wrap(store: Arc<dyn DocumentStore>) -> Arc<dyn DocumentStore> {
+    Arc::new(SessionStore { inner: store })
+}
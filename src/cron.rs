@@ -0,0 +1,177 @@
+//! Cron expressions.
+//!
+//! The five classic fields — minute, hour, day of month, month, day of week —
+//! each `*`, a number, a range `a-b`, a step `*/n` or `a-b/n`, or a list of
+//! these separated by commas. Days of the week run from 0 (Sunday) to 6, and
+//! 7 is Sunday too. As in cron, when both day fields are restricted a day
+//! matching either is enough. `@hourly`, `@daily`, `@weekly`, `@monthly` and
+//! `@yearly` stand for the usual expressions. Times are UTC.
+
+use crate::access_log::civil;
+
+/// A parsed cron expression.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Cron {
+    minutes: u64,
+    hours: u64,
+    days: u64,
+    months: u64,
+    weekdays: u64,
+    /// Whether the day-of-month and day-of-week fields are `*`.
+    any_day: bool,
+    any_weekday: bool,
+}
+
+impl Cron {
+    pub fn parse(expr: &str) -> Result<Cron, String> {
+        let expr = match expr.trim() {
+            "@hourly" => "0 * * * *",
+            "@daily" | "@midnight" => "0 0 * * *",
+            "@weekly" => "0 0 * * 0",
+            "@monthly" => "0 0 1 * *",
+            "@yearly" | "@annually" => "0 0 1 1 *",
+            expr => expr,
+        };
+        let fields: Vec<&str> = expr.split_whitespace().collect();
+        let [minute, hour, day, month, weekday] = fields[..] else {
+            return Err(format!("'{}' does not have five fields", expr));
+        };
+        let weekdays = field(weekday, 0, 7, "day of week")?;
+        Ok(Cron {
+            minutes: field(minute, 0, 59, "minute")?,
+            hours: field(hour, 0, 23, "hour")?,
+            days: field(day, 1, 31, "day of month")?,
+            months: field(month, 1, 12, "month")?,
+            // 7 与 0 都表示星期日
+            weekdays: (weekdays | weekdays >> 7) & 0x7f,
+            any_day: day == "*",
+            any_weekday: weekday == "*",
+        })
+    }
+
+    /// Whether the minute holding the Unix time `ts` matches.
+    pub fn matches(&self, ts: u64) -> bool {
+        let (_, _, _, hour, minute, _) = civil(ts);
+        self.matches_day(ts / 86400) && bit(self.hours, hour) && bit(self.minutes, minute)
+    }
+
+    /// The first matching minute after the Unix time `ts`, looking up to
+    /// eight years ahead.
+    pub fn next_after(&self, ts: u64) -> Option<u64> {
+        let start = ts / 60 * 60 + 60;
+        let first_day = start / 86400;
+        (first_day..first_day + 8 * 366).filter(|day| self.matches_day(*day)).find_map(|day| {
+            let from = if day == first_day { start % 86400 / 60 } else { 0 };
+            (from..24 * 60)
+                .find(|minute| bit(self.hours, (minute / 60) as u32) && bit(self.minutes, (minute % 60) as u32))
+                .map(|minute| day * 86400 + minute * 60)
+        })
+    }
+
+    fn matches_day(&self, day: u64) -> bool {
+        let (_, month, day_of_month, ..) = civil(day * 86400);
+        // 1970-01-01 是星期四
+        let weekday = ((day + 4) % 7) as u32;
+        let by_day = bit(self.days, day_of_month);
+        let by_weekday = bit(self.weekdays, weekday);
+        let day_matches = match (self.any_day, self.any_weekday) {
+            (false, false) => by_day || by_weekday,
+            _ => by_day && by_weekday,
+        };
+        day_matches && bit(self.months, month)
+    }
+}
+
+fn bit(set: u64, n: u32) -> bool {
+    set & (1 << n) != 0
+}
+
+// 解析一个字段, 得到允许的取值的位集合
+fn field(text: &str, min: u32, max: u32, name: &str) -> Result<u64, String> {
+    let bad = || format!("bad {} '{}'", name, text);
+    let mut set = 0u64;
+    for item in text.split(',') {
+        let (range, step) = match item.split_once('/') {
+            Some((range, step)) => (range, step.parse::<u32>().ok().filter(|s| *s > 0).ok_or_else(bad)?),
+            None => (item, 1),
+        };
+        let (from, to) = match range {
+            "*" => (min, max),
+            _ => match range.split_once('-') {
+                Some((from, to)) => (from.parse().map_err(|_| bad())?, to.parse().map_err(|_| bad())?),
+                None => {
+                    let n = range.parse().map_err(|_| bad())?;
+                    (n, if step > 1 { max } else { n })
+                }
+            },
+        };
+        if from < min || to > max || from > to {
+            return Err(format!("{} '{}' is outside {}-{}", name, item, min, max));
+        }
+        for n in (from..=to).step_by(step as usize) {
+            set |= 1 << n;
+        }
+    }
+    Ok(set)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// 2024-01-01 00:00 UTC, a Monday.
+    const JAN_1: u64 = 1_704_067_200;
+    const DAY: u64 = 86400;
+
+    fn at(day: u64, hour: u64, minute: u64) -> u64 {
+        JAN_1 + day * DAY + hour * 3600 + minute * 60
+    }
+
+    #[test]
+    fn parse_rejects_malformed_fields() {
+        for (expr, message) in [
+            ("* * *", "'* * *' does not have five fields"),
+            ("60 * * * *", "minute '60' is outside 0-59"),
+            ("5-1 * * * *", "minute '5-1' is outside 0-59"),
+            ("*/0 * * * *", "bad minute '*/0'"),
+            ("* x * * *", "bad hour 'x'"),
+            ("* * 0 * *", "day of month '0' is outside 1-31"),
+            ("* * * * 8", "day of week '8' is outside 0-7"),
+        ] {
+            assert_eq!(Cron::parse(expr), Err(message.to_string()), "{}", expr);
+        }
+        assert_eq!(Cron::parse("@daily"), Cron::parse("0 0 * * *"));
+        assert_eq!(Cron::parse("5/20 * * * *"), Cron::parse("5,25,45 * * * *"));
+    }
+
+    #[test]
+    fn matches_the_minute_of_a_timestamp() {
+        let cron = Cron::parse("30 9 * * 1-5").unwrap();
+        assert!(cron.matches(at(0, 9, 30)));
+        assert!(cron.matches(at(0, 9, 30) + 59));
+        assert!(!cron.matches(at(0, 9, 31)));
+        // 1 月 6 日是星期六
+        assert!(!cron.matches(at(5, 9, 30)));
+        assert!(Cron::parse("0 0 * * 7").unwrap().matches(at(6, 0, 0)));
+    }
+
+    #[test]
+    fn either_restricted_day_field_is_enough() {
+        let cron = Cron::parse("0 0 13 * 5").unwrap();
+        assert!(cron.matches(at(4, 0, 0)));
+        assert!(cron.matches(at(12, 0, 0)));
+        assert!(!cron.matches(at(13, 0, 0)));
+        let cron = Cron::parse("0 0 * 2 5").unwrap();
+        assert!(!cron.matches(at(4, 0, 0)));
+        assert!(cron.matches(at(32, 0, 0)));
+    }
+
+    #[test]
+    fn next_after_finds_the_following_minute() {
+        assert_eq!(Cron::parse("*/15 * * * *").unwrap().next_after(at(0, 10, 7) + 30), Some(at(0, 10, 15)));
+        assert_eq!(Cron::parse("*/15 * * * *").unwrap().next_after(at(0, 10, 15)), Some(at(0, 10, 30)));
+        assert_eq!(Cron::parse("@monthly").unwrap().next_after(JAN_1), Some(at(31, 0, 0)));
+        assert_eq!(Cron::parse("0 0 29 2 *").unwrap().next_after(at(60, 0, 0)), Some(at(1520, 0, 0)));
+        assert_eq!(Cron::parse("0 0 30 2 *").unwrap().next_after(JAN_1), None);
+    }
+}
@@ -1,15 +1,45 @@
-use sqlx::SqlitePool;
-use sqlx::sqlite::SqlitePoolOptions;
+use async_trait::async_trait;
+use serde_json::Value;
+use sqlx::query::Query;
+use sqlx::sqlite::{SqliteArguments, SqliteConnectOptions, SqliteConnection, SqlitePoolOptions, SqliteRow};
+use sqlx::Sqlite;
+use sqlx::{Column, Connection, Row, SqlitePool, TypeInfo, ValueRef};
+use std::collections::{BTreeMap, HashMap};
 use std::env;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::{OwnedRwLockReadGuard, OwnedRwLockWriteGuard, RwLock};
+
+use crate::compression::{self, Stored};
+use crate::partition::Partitions;
+use crate::query::{parse_filter, parse_update, quote, Filter};
+#[cfg(feature = "fts")]
+use crate::search::fts_table;
+use crate::shard::Shards;
+use crate::store::{check_fields, document_fields, DedupMode, DocumentStore, Inserted, PageQuery, Paged, StoreError, UpdatedMany, Upserted, Written, VERSION_FIELD};
+use crate::telemetry::{db_span, Span};
 
 pub async fn init_db() -> Result<SqlitePool, sqlx::Error> {
+    init_db_with(database_options()?).await
+}
+
+/// The connection options given by `DATABASE_URL`.
+pub fn database_options() -> Result<SqliteConnectOptions, sqlx::Error> {
     let database_url = env::var("DATABASE_URL").expect("DATABASE_URL must be set");
-    let pool = SqlitePoolOptions::new()
-        .max_connections(5)
-        .connect(&database_url)
-        .await?;
+    SqliteConnectOptions::from_str(&database_url)
+}
+
+pub async fn init_db_with(options: SqliteConnectOptions) -> Result<SqlitePool, sqlx::Error> {
+    let pool = pool_options().max_connections(5).connect_with(options).await?;
 
-    // Create initial tables if they don't exist
+    create_system_tables(&pool).await?;
+    Ok(pool)
+}
+
+// 创建初始表
+pub async fn create_system_tables(pool: &SqlitePool) -> Result<(), sqlx::Error> {
     sqlx::query(
         r#"
         CREATE TABLE IF NOT EXISTS data (
@@ -19,8 +49,1605 @@ pub async fn init_db() -> Result<SqlitePool, sqlx::Error> {
         )
         "#
     )
-    .execute(&pool)
+    .execute(pool)
     .await?;
-    
-    Ok(pool)
-}
\ No newline at end of file
+
+    // 管理界面保存的查询
+    sqlx::query(
+        r#"
+        CREATE TABLE IF NOT EXISTS _saved_queries (
+            name TEXT PRIMARY KEY,
+            collection TEXT NOT NULL,
+            query TEXT NOT NULL
+        )
+        "#
+    )
+    .execute(pool)
+    .await?;
+
+    // 集合级访问控制列表
+    sqlx::query(
+        r#"
+        CREATE TABLE IF NOT EXISTS _acl (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            principal TEXT NOT NULL,
+            collection TEXT NOT NULL,
+            permissions TEXT NOT NULL,
+            created_by TEXT,
+            created_at INTEGER NOT NULL
+        )
+        "#
+    )
+    .execute(pool)
+    .await?;
+
+    // Idempotency-Key 及其对应的响应; status 为空表示请求仍在处理中
+    sqlx::query(
+        r#"
+        CREATE TABLE IF NOT EXISTS _idempotency_keys (
+            principal TEXT NOT NULL,
+            key TEXT NOT NULL,
+            fingerprint TEXT NOT NULL,
+            status INTEGER,
+            content_type TEXT,
+            body BLOB,
+            document_id INTEGER,
+            created_at INTEGER NOT NULL,
+            PRIMARY KEY (principal, key)
+        )
+        "#
+    )
+    .execute(pool)
+    .await?;
+    sqlx::query("CREATE INDEX IF NOT EXISTS _idempotency_keys_created_at ON _idempotency_keys (created_at)")
+        .execute(pool)
+        .await?;
+
+    // 后台任务; progress 由任务自己更新, run_after 之前不会被执行
+    sqlx::query(
+        r#"
+        CREATE TABLE IF NOT EXISTS _jobs (
+            id TEXT PRIMARY KEY,
+            kind TEXT NOT NULL,
+            collection TEXT,
+            owner TEXT,
+            status TEXT NOT NULL,
+            attempts INTEGER NOT NULL,
+            run_after INTEGER NOT NULL,
+            payload TEXT NOT NULL,
+            progress TEXT NOT NULL,
+            error TEXT,
+            created_at INTEGER NOT NULL,
+            updated_at INTEGER NOT NULL
+        )
+        "#
+    )
+    .execute(pool)
+    .await?;
+    sqlx::query("CREATE INDEX IF NOT EXISTS _jobs_status ON _jobs (status, run_after)")
+        .execute(pool)
+        .await?;
+
+    // 导入任务收到的数据, 每块从第 first 行之后开始, 共 count 行
+    sqlx::query(
+        r#"
+        CREATE TABLE IF NOT EXISTS _job_data (
+            job_id TEXT NOT NULL,
+            first INTEGER NOT NULL,
+            count INTEGER NOT NULL,
+            lines TEXT NOT NULL,
+            PRIMARY KEY (job_id, first)
+        )
+        "#
+    )
+    .execute(pool)
+    .await?;
+
+    // 计划任务的运行历史, 每个计划任务保留最近的若干次
+    sqlx::query(
+        r#"
+        CREATE TABLE IF NOT EXISTS _schedule_runs (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            schedule TEXT NOT NULL,
+            task TEXT NOT NULL,
+            due_at INTEGER NOT NULL,
+            job_id TEXT,
+            status TEXT NOT NULL,
+            started_at INTEGER,
+            finished_at INTEGER,
+            error TEXT,
+            result TEXT,
+            UNIQUE (schedule, due_at)
+        )
+        "#
+    )
+    .execute(pool)
+    .await?;
+
+    // 由租户的调用者建立的集合及其所属租户
+    sqlx::query(
+        r#"
+        CREATE TABLE IF NOT EXISTS _tenant_collections (
+            collection TEXT PRIMARY KEY,
+            tenant TEXT NOT NULL,
+            created_at INTEGER NOT NULL
+        )
+        "#
+    )
+    .execute(pool)
+    .await?;
+
+    // 按天、集合、调用者和操作统计的请求数和字节数
+    sqlx::query(
+        r#"
+        CREATE TABLE IF NOT EXISTS _usage (
+            day TEXT NOT NULL,
+            collection TEXT NOT NULL,
+            key TEXT NOT NULL,
+            operation TEXT NOT NULL,
+            requests INTEGER NOT NULL,
+            bytes_in INTEGER NOT NULL,
+            bytes_out INTEGER NOT NULL,
+            PRIMARY KEY (day, collection, key, operation)
+        )
+        "#
+    )
+    .execute(pool)
+    .await?;
+
+    // 开启去重的集合中每个文档的内容哈希
+    sqlx::query(
+        r#"
+        CREATE TABLE IF NOT EXISTS _content_hashes (
+            collection TEXT NOT NULL,
+            hash TEXT NOT NULL,
+            document_id INTEGER NOT NULL,
+            PRIMARY KEY (collection, hash)
+        )
+        "#
+    )
+    .execute(pool)
+    .await?;
+
+    // Kafka 数据源已写入的最后一个 offset
+    sqlx::query(
+        r#"
+        CREATE TABLE IF NOT EXISTS _kafka_offsets (
+            topic TEXT NOT NULL,
+            partition INTEGER NOT NULL,
+            "offset" INTEGER NOT NULL,
+            updated_at INTEGER NOT NULL,
+            PRIMARY KEY (topic, partition)
+        )
+        "#
+    )
+    .execute(pool)
+    .await?;
+
+    // 集群 leader 上被写过的文档, follower 按 seq 顺序拉取
+    sqlx::query(
+        r#"
+        CREATE TABLE IF NOT EXISTS _cluster_log (
+            seq INTEGER PRIMARY KEY AUTOINCREMENT,
+            collection TEXT NOT NULL,
+            document_id INTEGER NOT NULL
+        )
+        "#
+    )
+    .execute(pool)
+    .await?;
+
+    // 本节点的 term 和复制进度, 重启后继续使用
+    sqlx::query(
+        r#"
+        CREATE TABLE IF NOT EXISTS _cluster_state (
+            key TEXT PRIMARY KEY,
+            value INTEGER NOT NULL
+        )
+        "#
+    )
+    .execute(pool)
+    .await?;
+
+    // 开启历史的集合中文档的各个版本; kind 为 full (完整文档), delta (相对上一版本的变化)
+    // 或 deleted, base 是 delta 所依据的最近一个完整版本
+    sqlx::query(
+        r#"
+        CREATE TABLE IF NOT EXISTS _history (
+            collection TEXT NOT NULL,
+            document_id INTEGER NOT NULL,
+            version INTEGER NOT NULL,
+            op TEXT NOT NULL,
+            kind TEXT NOT NULL,
+            base INTEGER NOT NULL,
+            body TEXT,
+            timestamp INTEGER NOT NULL,
+            PRIMARY KEY (collection, document_id, version)
+        )
+        "#
+    )
+    .execute(pool)
+    .await?;
+
+    // 文档写入后的校验和, 由 /_admin/verify 重新计算比对
+    sqlx::query(
+        r#"
+        CREATE TABLE IF NOT EXISTS _checksums (
+            collection TEXT NOT NULL,
+            document_id INTEGER NOT NULL,
+            version INTEGER NOT NULL,
+            checksum TEXT NOT NULL,
+            PRIMARY KEY (collection, document_id)
+        )
+        "#
+    )
+    .execute(pool)
+    .await?;
+
+    // 嵌套集合拆出对象和数组的子表
+    sqlx::query(
+        r#"
+        CREATE TABLE IF NOT EXISTS _nested_tables (
+            collection TEXT NOT NULL,
+            chain TEXT NOT NULL,
+            table_name TEXT NOT NULL,
+            PRIMARY KEY (collection, chain)
+        )
+        "#
+    )
+    .execute(pool)
+    .await?;
+
+    // 按时间分区的集合已有的分区表
+    sqlx::query(
+        r#"
+        CREATE TABLE IF NOT EXISTS _partitions (
+            collection TEXT NOT NULL,
+            period INTEGER NOT NULL,
+            table_name TEXT NOT NULL,
+            PRIMARY KEY (collection, period)
+        )
+        "#
+    )
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+// 列出所有用户集合 (以 _ 或 sqlite_ 开头的内部表和嵌套集合的子表除外)
+pub async fn list_collections(pool: &SqlitePool) -> Result<Vec<String>, sqlx::Error> {
+    sqlx::query_scalar(
+        r#"
+        SELECT name FROM sqlite_master
+        WHERE type = 'table' AND name NOT LIKE '\_%' ESCAPE '\' AND name NOT LIKE 'sqlite_%'
+          AND name NOT IN (SELECT table_name FROM _nested_tables)
+        ORDER BY name
+        "#
+    )
+    .fetch_all(pool)
+    .await
+}
+
+pub async fn collection_exists(pool: &SqlitePool, table_name: &str) -> Result<bool, sqlx::Error> {
+    let count: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM sqlite_master WHERE type = 'table' AND name = ?")
+        .bind(table_name)
+        .fetch_one(pool)
+        .await?;
+    Ok(count > 0)
+}
+
+// 返回表的 (列名, 声明类型)
+pub async fn table_columns(pool: &SqlitePool, table_name: &str) -> Result<Vec<(String, String)>, sqlx::Error> {
+    let rows = sqlx::query(&format!("PRAGMA table_info({})", quote(table_name)))
+        .fetch_all(pool)
+        .await?;
+    Ok(rows
+        .iter()
+        .map(|row| (row.get::<String, _>("name"), row.get::<String, _>("type")))
+        .collect())
+}
+
+pub async fn count_rows(pool: &SqlitePool, table_name: &str) -> Result<i64, sqlx::Error> {
+    sqlx::query_scalar(&format!("SELECT COUNT(*) FROM {}", quote(table_name)))
+        .fetch_one(pool)
+        .await
+}
+
+/// The tables holding the documents of collection `uri`, with the pool
+/// each is in: one per shard file of a sharded collection, every partition
+/// of a partitioned one, otherwise its table if it exists.
+pub async fn collection_tables(
+    pool: &SqlitePool,
+    shards: &Shards,
+    partitions: &Partitions,
+    uri: &str,
+) -> Result<Vec<(SqlitePool, String)>, sqlx::Error> {
+    let table = table_name(uri);
+    let mut tables = Vec::new();
+    if let Some(collection) = shards.get(&table) {
+        for pool in collection.pools() {
+            if collection_exists(&pool, &table).await? {
+                tables.push((pool, table.clone()));
+            }
+        }
+        return Ok(tables);
+    }
+    match partitions.tables(&table, None).await {
+        Some(names) => tables.extend(names.into_iter().map(|name| (pool.clone(), name))),
+        None if collection_exists(pool, &table).await? => tables.push((pool.clone(), table)),
+        None => {}
+    }
+    Ok(tables)
+}
+
+// uri 到表名的映射; 多段路径的各段以 __ 相连
+pub fn table_name(uri: &str) -> String {
+    uri.replace('/', "__")
+}
+
+// 表名到 uri 的映射
+pub fn uri_of(table: &str) -> String {
+    table.replace("__", "/")
+}
+
+/// Bumped by every schema change made by this process.
+static SCHEMA_GENERATION: AtomicU64 = AtomicU64::new(0);
+
+/// The generation each connection last saw, by its SQLite handle.
+static SEEN_GENERATION: Mutex<BTreeMap<usize, u64>> = Mutex::new(BTreeMap::new());
+
+/// Pool options for the document databases. A connection's cached
+/// statements and its copy of the schema remember the columns of `SELECT *`,
+/// so both are renewed when it is next acquired after a schema change.
+pub fn pool_options() -> SqlitePoolOptions {
+    SqlitePoolOptions::new().before_acquire(|conn, _| {
+        Box::pin(async move {
+            let generation = SCHEMA_GENERATION.load(Ordering::Acquire);
+            if generation == 0 {
+                return Ok(true);
+            }
+            let handle = conn.lock_handle().await?.as_raw_handle().as_ptr() as usize;
+            let seen = {
+                let mut seen = SEEN_GENERATION.lock().unwrap();
+                // 关闭的连接留下的记录一并清掉, 之后每个连接多清一次缓存而已
+                if seen.len() > 256 {
+                    seen.clear();
+                }
+                seen.insert(handle, generation)
+            };
+            if seen != Some(generation) {
+                conn.clear_cached_statements().await?;
+                // 读一次 sqlite_master, 连接才会重新载入表结构, 之后准备的语句才有新的列
+                sqlx::query("SELECT COUNT(*) FROM sqlite_master").execute(&mut *conn).await?;
+            }
+            Ok(true)
+        })
+    })
+}
+
+/// Records that a table was created or altered, see [`pool_options`].
+pub fn schema_changed() {
+    SCHEMA_GENERATION.fetch_add(1, Ordering::AcqRel);
+}
+
+/// One lock per table name: schema changes hold it exclusively, so
+/// concurrent writes with new keys do not race to create or alter the table,
+/// and `SELECT *` reads share it, so their columns do not change under them.
+static SCHEMA_LOCKS: Mutex<BTreeMap<String, Arc<RwLock<()>>>> = Mutex::new(BTreeMap::new());
+
+/// Attempts at a schema change that another connection or process races.
+const SCHEMA_ATTEMPTS: u32 = 5;
+
+fn table_lock(table_name: &str) -> Arc<RwLock<()>> {
+    SCHEMA_LOCKS.lock().unwrap().entry(table_name.to_string()).or_default().clone()
+}
+
+async fn schema_lock(table_name: &str) -> OwnedRwLockWriteGuard<()> {
+    table_lock(table_name).write_owned().await
+}
+
+// 读取期间表结构不变; 须在取得连接之前持有
+pub(crate) async fn columns_lock(table_name: &str) -> OwnedRwLockReadGuard<()> {
+    table_lock(table_name).read_owned().await
+}
+
+// 字段值对应的列类型
+pub(crate) fn column_type(value: &Value) -> &'static str {
+    match value {
+        Value::String(_) => "TEXT",
+        Value::Number(n) if n.is_i64() => "INTEGER",
+        Value::Number(n) if n.is_f64() => "REAL",
+        // 超出 i64 的整数存为 REAL 会丢失精度
+        Value::Number(_) => "TEXT",
+        Value::Bool(_) => "BOOLEAN",
+        Value::Object(_) => "TEXT", // 嵌套对象存储为 JSON 字符串
+        _ => "TEXT",
+    }
+}
+
+/// The type a column declared as `declared` is widened to so that `value`
+/// reads back as written and compares with its peers, or `None` if it
+/// already does. Values are stored as JSON text and SQLite converts
+/// numeric-looking text by the declared type: a `REAL` column would store
+/// `3` as `3.0`, and a `TEXT` column keeps numbers as text, which compares
+/// `"100" < "30"`. Columns holding values of another kind become `NUMERIC`,
+/// which stores numbers as numbers (`1.0` as `1`) and keeps strings,
+/// booleans, objects and arrays as their text, since no JSON text of those
+/// looks numeric. `TEXT` columns only widen for numbers.
+pub(crate) fn widened_type(declared: &str, value: &Value) -> Option<&'static str> {
+    if value.is_null() {
+        return None;
+    }
+    let declared = declared.to_ascii_uppercase();
+    match (declared.as_str(), column_type(value)) {
+        ("NUMERIC", _) => None,
+        (declared, kind) if declared == kind => None,
+        // 超出 i64 的整数在 TEXT 列中才能原样保存
+        ("TEXT", kind) if kind != "INTEGER" && kind != "REAL" => None,
+        ("INTEGER" | "REAL" | "BOOLEAN" | "TEXT", _) => Some("NUMERIC"),
+        // 其他声明类型原样保存 JSON 文本
+        _ => None,
+    }
+}
+
+/// The columns of `columns` that cannot hold the values of `fields` as they
+/// are, with the types they are widened to.
+pub(crate) fn widenings(columns: &[(String, String)], fields: &[(&str, Option<&Value>)]) -> Vec<(String, &'static str)> {
+    let mut widened: Vec<(String, &'static str)> = Vec::new();
+    for (key, value) in fields {
+        let Some((name, declared)) = columns.iter().find(|(name, _)| name.eq_ignore_ascii_case(key)) else {
+            continue;
+        };
+        let Some(wider) = value.and_then(|value| widened_type(declared, value)) else {
+            continue;
+        };
+        if !widened.iter().any(|(column, _)| column == name) {
+            widened.push((name.clone(), wider));
+        }
+    }
+    widened
+}
+
+// 字段对应的列定义; 没有值的键建为 TEXT 列
+pub(crate) fn field_types<'a>(fields: &[(&'a str, Option<&Value>)]) -> Vec<(&'a str, &'static str)> {
+    fields.iter().map(|(key, value)| (*key, value.map_or("TEXT", column_type))).collect()
+}
+
+// 动态创建表, 文档中新出现的键补为新列, 存不下新值的列放宽类型; 返回的读锁持有期间这些列不会被清理掉
+pub async fn create_table(pool: &SqlitePool, table_name: &str, data: &Value) -> Result<OwnedRwLockReadGuard<()>, StoreError> {
+    let fields: Vec<(&str, Option<&Value>)> = document_fields(data)?
+        .iter()
+        .filter(|(key, _)| *key != "id" && *key != VERSION_FIELD)
+        .map(|(key, value)| (key.as_str(), Some(value)))
+        .collect();
+    ensure_columns(pool, table_name, &fields).await.map_err(StoreError::Schema)
+}
+
+// 确保表存在且有 fields 中的列, 各列能存下其中的值, 返回该表的读锁; 结构变更持有该表的锁, 失败时重新读取表结构再试
+async fn ensure_columns(
+    pool: &SqlitePool,
+    table_name: &str,
+    fields: &[(&str, Option<&Value>)],
+) -> Result<OwnedRwLockReadGuard<()>, sqlx::Error> {
+    let missing = |columns: &[(String, String)]| fields.iter().any(|(key, _)| !columns.iter().any(|(name, _)| name.eq_ignore_ascii_case(key)));
+    loop {
+        let columns_lock = columns_lock(table_name).await;
+        let columns = table_columns(pool, table_name).await?;
+        if !columns.is_empty() && !missing(&columns) && widenings(&columns, fields).is_empty() {
+            return Ok(columns_lock);
+        }
+        drop(columns_lock);
+
+        // 变更之后再加读锁之前, 列可能又被 prune_columns 删掉, 所以回到开头重新检查
+        let _lock = schema_lock(table_name).await;
+        let mut attempt = 1;
+        loop {
+            match change_schema(pool, table_name, fields).await {
+                Ok(()) => break,
+                Err(e) if attempt < SCHEMA_ATTEMPTS => {
+                    log::debug!("retrying schema change of {}: {}", table_name, e);
+                    tokio::time::sleep(Duration::from_millis(20 * attempt as u64)).await;
+                    attempt += 1;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
+
+// 建表, 补上缺少的列或放宽列的类型; 已由别人完成的变更不再重复
+async fn change_schema(pool: &SqlitePool, table_name: &str, fields: &[(&str, Option<&Value>)]) -> Result<(), sqlx::Error> {
+    let mut columns = table_columns(pool, table_name).await?;
+    if columns.is_empty() {
+        let query = create_table_statement(table_name, &field_types(fields));
+        let mut span = db_span(&query, table_name);
+        sqlx::query(&query).execute(pool).await.map_err(|e| failed(&mut span, e))?;
+        schema_changed();
+        // 表可能刚由另一个进程以别的列建好
+        columns = table_columns(pool, table_name).await?;
+    }
+    // 列名不区分大小写, 只差大小写的键写入已有的列
+    for (key, column_type) in field_types(fields) {
+        if columns.iter().any(|(name, _)| name.eq_ignore_ascii_case(key)) {
+            continue;
+        }
+        let query = format!("ALTER TABLE {} ADD COLUMN {} {}", quote(table_name), quote(key), column_type);
+        let mut span = db_span(&query, table_name);
+        sqlx::query(&query).execute(pool).await.map_err(|e| failed(&mut span, e))?;
+        schema_changed();
+    }
+    let widened = widenings(&table_columns(pool, table_name).await?, fields);
+    if !widened.is_empty() {
+        let mut tx = pool.begin().await?;
+        rebuild_table(&mut tx, table_name, &[], &widened).await?;
+        tx.commit().await?;
+        schema_changed();
+        for (column, column_type) in &widened {
+            log::info!("widened column {} of {} to {}", column, table_name, column_type);
+        }
+    }
+    Ok(())
+}
+
+/// `CREATE TABLE` for a collection table with an id, a version and `fields`.
+pub(crate) fn create_table_statement(table_name: &str, fields: &[(&str, &str)]) -> String {
+    let mut definitions = vec!["id INTEGER PRIMARY KEY AUTOINCREMENT".to_string(), format!("{} INTEGER NOT NULL DEFAULT 1", VERSION_FIELD)];
+    definitions.extend(fields.iter().map(|(key, column_type)| format!("{} {}", quote(key), column_type)));
+    format!("CREATE TABLE IF NOT EXISTS {} ({})", quote(table_name), definitions.join(", "))
+}
+
+// 以已有表的定义创建一张新表, 列和类型都相同
+pub async fn copy_table(pool: &SqlitePool, from: &str, to: &str) -> Result<(), sqlx::Error> {
+    let definition: String = sqlx::query_scalar("SELECT sql FROM sqlite_master WHERE type = 'table' AND name = ?")
+        .bind(from)
+        .fetch_one(pool)
+        .await?;
+    let columns = &definition[definition.find('(').unwrap_or(definition.len())..];
+    let query = format!("CREATE TABLE IF NOT EXISTS {} {}", quote(to), columns);
+    let mut span = db_span(&query, to);
+    sqlx::query(&query).execute(pool).await.map_err(|e| failed(&mut span, e))?;
+    Ok(())
+}
+
+/// Drops the columns of `table_name` that no row holds a value in, other
+/// than `id` and the version, and returns them; with `dry_run` only finds
+/// them. The table is rebuilt without them in one transaction that keeps its
+/// rows, ids, autoincrement counter and the indexes on the remaining columns.
+pub async fn prune_columns(pool: &SqlitePool, table_name: &str, dry_run: bool) -> Result<Vec<String>, sqlx::Error> {
+    let _lock = schema_lock(table_name).await;
+    // 在事务中统计, 统计之后写入的值不会被删掉
+    let mut tx = pool.begin().await?;
+    let columns: Vec<(String, String, bool, Option<String>, bool)> =
+        sqlx::query_as("SELECT name, type, \"notnull\", dflt_value, pk FROM pragma_table_info(?)")
+            .bind(table_name)
+            .fetch_all(&mut *tx)
+            .await?;
+    // 全文索引的触发器引用的列不能删
+    #[cfg(feature = "fts")]
+    let indexed: Vec<String> = sqlx::query_scalar("SELECT name FROM pragma_table_info(?)")
+        .bind(fts_table(table_name))
+        .fetch_all(&mut *tx)
+        .await?;
+    #[cfg(not(feature = "fts"))]
+    let indexed: Vec<String> = Vec::new();
+    let candidates: Vec<&str> = columns
+        .iter()
+        .filter(|(name, _, _, _, pk)| !pk && name != "id" && name != VERSION_FIELD)
+        .filter(|(name, ..)| !indexed.iter().any(|field| field.eq_ignore_ascii_case(name)))
+        .map(|(name, ..)| name.as_str())
+        .collect();
+    if candidates.is_empty() {
+        return Ok(Vec::new());
+    }
+    let counts: Vec<String> = candidates.iter().map(|name| format!("COUNT({})", quote(name))).collect();
+    let row = sqlx::query(&format!("SELECT {} FROM {}", counts.join(", "), quote(table_name))).fetch_one(&mut *tx).await?;
+    let unused: Vec<String> =
+        candidates.iter().enumerate().filter(|(i, _)| row.get::<i64, _>(*i) == 0).map(|(_, name)| name.to_string()).collect();
+    if dry_run || unused.is_empty() {
+        return Ok(unused);
+    }
+
+    rebuild_table(&mut tx, table_name, &unused, &[]).await?;
+    tx.commit().await?;
+    schema_changed();
+    Ok(unused)
+}
+
+/// Rebuilds `table_name` in the transaction on `conn` without the columns
+/// in `dropped` and with the declared types of the columns in `retyped`,
+/// keeping its rows, ids, autoincrement counter, triggers and the indexes
+/// on the remaining columns. SQLite can neither drop a column some index
+/// uses nor change a column's type in place.
+pub(crate) async fn rebuild_table(
+    conn: &mut SqliteConnection,
+    table_name: &str,
+    dropped: &[String],
+    retyped: &[(String, &'static str)],
+) -> Result<(), sqlx::Error> {
+    let definition: String = sqlx::query_scalar("SELECT sql FROM sqlite_master WHERE type = 'table' AND name = ?")
+        .bind(table_name)
+        .fetch_one(&mut *conn)
+        .await?;
+    let autoincrement = definition.to_ascii_uppercase().contains("AUTOINCREMENT");
+    let columns: Vec<(String, String, bool, Option<String>, bool)> =
+        sqlx::query_as("SELECT name, type, \"notnull\", dflt_value, pk FROM pragma_table_info(?)")
+            .bind(table_name)
+            .fetch_all(&mut *conn)
+            .await?;
+    let kept: Vec<(&str, &str, bool, Option<&str>, bool)> = columns
+        .iter()
+        .filter(|(name, ..)| !dropped.contains(name))
+        .map(|(name, declared, not_null, default, pk)| {
+            let column_type = retyped.iter().find(|(column, _)| column == name).map_or(declared.as_str(), |(_, column_type)| column_type);
+            (name.as_str(), column_type, *not_null, default.as_deref(), *pk)
+        })
+        .collect();
+    let definitions: Vec<String> = kept
+        .iter()
+        .map(|(name, column_type, not_null, default, pk)| match (pk, autoincrement) {
+            (true, true) => format!("{} {} PRIMARY KEY AUTOINCREMENT", quote(name), column_type),
+            (true, false) => format!("{} {} PRIMARY KEY", quote(name), column_type),
+            _ => {
+                let mut definition = format!("{} {}", quote(name), column_type);
+                if *not_null {
+                    definition.push_str(" NOT NULL");
+                }
+                if let Some(default) = default {
+                    definition.push_str(&format!(" DEFAULT {}", default));
+                }
+                definition
+            }
+        })
+        .collect();
+    let names: Vec<String> = kept.iter().map(|(name, ..)| quote(name)).collect();
+
+    // 删除旧表前记下索引和自增计数, 涉及被删列的索引不再重建
+    let indexes: Vec<(String, String)> =
+        sqlx::query_as("SELECT name, sql FROM sqlite_master WHERE type = 'index' AND tbl_name = ? AND sql IS NOT NULL")
+            .bind(table_name)
+            .fetch_all(&mut *conn)
+            .await?;
+    let mut recreated = Vec::new();
+    for (name, sql) in indexes {
+        let indexed: Vec<String> = sqlx::query_scalar("SELECT name FROM pragma_index_info(?)")
+            .bind(&name)
+            .fetch_all(&mut *conn)
+            .await?;
+        if indexed.iter().any(|column| dropped.contains(column)) {
+            log::info!("dropping index {} of {} with its dropped columns", name, table_name);
+        } else {
+            recreated.push(sql);
+        }
+    }
+    // 触发器随旧表删除, 新表改名后照原样重建
+    let triggers: Vec<String> = sqlx::query_scalar("SELECT sql FROM sqlite_master WHERE type = 'trigger' AND tbl_name = ? AND sql IS NOT NULL")
+        .bind(table_name)
+        .fetch_all(&mut *conn)
+        .await?;
+    let sequence: Option<i64> = match autoincrement {
+        true => sqlx::query_scalar("SELECT seq FROM sqlite_sequence WHERE name = ?").bind(table_name).fetch_optional(&mut *conn).await?,
+        false => None,
+    };
+
+    let rebuilt = quote(&format!("_rebuild_{}", table_name));
+    let query = format!("CREATE TABLE {} ({})", rebuilt, definitions.join(", "));
+    let mut span = db_span(&query, table_name);
+    sqlx::query(&query).execute(&mut *conn).await.map_err(|e| failed(&mut span, e))?;
+    let query = format!("INSERT INTO {0} ({1}) SELECT {1} FROM {2}", rebuilt, names.join(", "), quote(table_name));
+    let mut span = db_span(&query, table_name);
+    sqlx::query(&query).execute(&mut *conn).await.map_err(|e| failed(&mut span, e))?;
+    sqlx::query(&format!("DROP TABLE {}", quote(table_name))).execute(&mut *conn).await?;
+    sqlx::query(&format!("ALTER TABLE {} RENAME TO {}", rebuilt, quote(table_name))).execute(&mut *conn).await?;
+    for sql in recreated.into_iter().chain(triggers) {
+        sqlx::query(&sql).execute(&mut *conn).await?;
+    }
+    if let Some(sequence) = sequence {
+        sqlx::query("UPDATE sqlite_sequence SET seq = ? WHERE name = ?").bind(sequence).bind(table_name).execute(&mut *conn).await?;
+        sqlx::query("INSERT INTO sqlite_sequence (name, seq) SELECT ?, ? WHERE NOT EXISTS (SELECT 1 FROM sqlite_sequence WHERE name = ?)")
+            .bind(table_name)
+            .bind(sequence)
+            .bind(table_name)
+            .execute(&mut *conn)
+            .await?;
+    }
+    Ok(())
+}
+
+// 值在数据库中以 JSON 文本保存, 查询时绑定的参数也使用同样的编码
+pub fn encode_value(value: &Value) -> String {
+    value.to_string()
+}
+
+// 插入一行数据, 返回新行的 id; 版本号由列的默认值给出
+pub async fn insert_row(pool: &SqlitePool, table_name: &str, data: &Value) -> Result<i64, StoreError> {
+    let entries: Vec<(&String, &Value)> = document_fields(data)?.iter().filter(|(k, _)| *k != VERSION_FIELD).collect();
+    let fields = entries.iter().map(|(k, _)| quote(k)).collect::<Vec<_>>().join(", ");
+
+    let query = if entries.is_empty() {
+        format!("INSERT INTO {} DEFAULT VALUES", quote(table_name))
+    } else {
+        format!("INSERT INTO {} ({}) VALUES ({})", quote(table_name), fields, vec!["?"; entries.len()].join(", "))
+    };
+
+    let mut span = db_span(&query, table_name);
+    let mut statement = sqlx::query(&query);
+    for (_, value) in &entries {
+        statement = bind_value(statement, Some(value));
+    }
+    let result = statement.execute(pool).await.map_err(|e| failed(&mut span, e))?;
+    span.set_i64("db.response.affected_rows", result.rows_affected() as i64);
+    Ok(result.last_insert_rowid())
+}
+
+/// Writes `data` into the row of `table_name` whose column `key` holds the
+/// same value, or inserts it, with `INSERT ... ON CONFLICT DO UPDATE` on a
+/// unique index of `key`, created by the first upsert. Inserted rows take
+/// their ids from `ids` as in [`insert_row_from`], if given.
+pub async fn upsert_row(
+    pool: &SqlitePool,
+    table_name: &str,
+    key: &str,
+    data: &Value,
+    ids: Option<(i64, i64)>,
+) -> Result<Upserted, StoreError> {
+    if key == "id" || key == VERSION_FIELD {
+        return Err(StoreError::Invalid(format!("'{}' cannot key upserts", key)));
+    }
+    let entries: Vec<(&String, &Value)> =
+        document_fields(data)?.iter().filter(|(k, _)| *k != VERSION_FIELD && *k != "id").collect();
+    let Some((_, value)) = entries.iter().find(|(k, v)| *k == key && !v.is_null()) else {
+        return Err(StoreError::Invalid(format!("Document has no '{}'", key)));
+    };
+
+    let index = format!("{}_{}_unique", table_name, key);
+    let _columns = loop {
+        let columns = create_table(pool, table_name, data).await?;
+        let indexed: bool = sqlx::query_scalar("SELECT COUNT(*) > 0 FROM sqlite_master WHERE type = 'index' AND name = ?")
+            .bind(&index)
+            .fetch_one(pool)
+            .await?;
+        if indexed {
+            break columns;
+        }
+        drop(columns);
+        let _lock = schema_lock(table_name).await;
+        let query = format!("CREATE UNIQUE INDEX IF NOT EXISTS {} ON {} ({})", quote(&index), quote(table_name), quote(key));
+        let mut span = db_span(&query, table_name);
+        sqlx::query(&query).execute(pool).await.map_err(|e| match e.as_database_error().is_some_and(|e| e.is_unique_violation()) {
+            true => StoreError::Invalid(format!("'{}' cannot key upserts: some documents share a value", key)),
+            false => StoreError::Schema(failed(&mut span, e)),
+        })?;
+        schema_changed();
+    };
+
+    let mut fields: Vec<&str> = entries.iter().map(|(k, _)| k.as_str()).collect();
+    let mut values = vec!["?"; entries.len()].join(", ");
+    if ids.is_some() {
+        fields.insert(0, "id");
+        values = format!("COALESCE((SELECT seq FROM sqlite_sequence WHERE name = ?), ?) + ?, {}", values);
+    }
+    // 值没有变化的文档不写入, 版本号也不变
+    let changed: Vec<String> = fields.iter().copied().filter(|field| *field != key && *field != "id").map(quote).collect();
+    let conflict = match changed.is_empty() {
+        true => "DO NOTHING".to_string(),
+        false => format!(
+            "DO UPDATE SET {}, {2} = {2} + 1 WHERE {1}",
+            changed.iter().map(|field| format!("{0} = excluded.{0}", field)).collect::<Vec<_>>().join(", "),
+            changed.iter().map(|field| format!("{0} IS NOT excluded.{0}", field)).collect::<Vec<_>>().join(" OR "),
+            VERSION_FIELD
+        ),
+    };
+    let query = format!(
+        "INSERT INTO {} ({}) VALUES ({}) ON CONFLICT ({}) {} RETURNING id, {}",
+        quote(table_name),
+        fields.iter().map(|field| quote(field)).collect::<Vec<_>>().join(", "),
+        values,
+        quote(key),
+        conflict,
+        VERSION_FIELD
+    );
+
+    let mut span = db_span(&query, table_name);
+    let mut statement = sqlx::query_as::<_, (i64, i64)>(&query);
+    if let Some((first, step)) = ids {
+        statement = statement.bind(table_name).bind(first - step).bind(step);
+    }
+    for (_, value) in &entries {
+        statement = match compression::encode(encode_value(value)) {
+            Stored::Text(text) => statement.bind(text),
+            Stored::Compressed(bytes) => statement.bind(bytes),
+        };
+    }
+    match statement.fetch_optional(pool).await.map_err(|e| failed(&mut span, e))? {
+        Some((id, 1)) => Ok(Upserted { id, existed: false, changed: true }),
+        Some((id, _)) => {
+            forget_hashes(pool, table_name, id).await?;
+            Ok(Upserted { id, existed: true, changed: true })
+        }
+        None => {
+            let query = format!("SELECT id FROM {} WHERE {} = ?", quote(table_name), quote(key));
+            let id = bind_value(sqlx::query(&query), Some(value)).fetch_one(pool).await?.get(0);
+            Ok(Upserted { id, existed: true, changed: false })
+        }
+    }
+}
+
+/// Binds a field value as the write path stores it: JSON text, compressed
+/// when it is large (see [`compression`]), or NULL.
+pub fn bind_value<'q>(statement: Query<'q, Sqlite, SqliteArguments<'q>>, value: Option<&Value>) -> Query<'q, Sqlite, SqliteArguments<'q>> {
+    match value.map(|v| compression::encode(encode_value(v))) {
+        Some(Stored::Text(text)) => statement.bind(text),
+        Some(Stored::Compressed(bytes)) => statement.bind(bytes),
+        None => statement.bind(None::<String>),
+    }
+}
+
+// 插入一行数据, id 依次取 first, first + step, ...
+pub async fn insert_row_from(pool: &SqlitePool, table_name: &str, data: &Value, first: i64, step: i64) -> Result<i64, StoreError> {
+    let entries: Vec<(&String, &Value)> =
+        document_fields(data)?.iter().filter(|(k, _)| *k != VERSION_FIELD && *k != "id").collect();
+    let mut fields = vec!["id".to_string()];
+    fields.extend(entries.iter().map(|(k, _)| quote(k)));
+    let query = format!(
+        "INSERT INTO {} ({}) VALUES (COALESCE((SELECT seq FROM sqlite_sequence WHERE name = ?), ?) + ?{})",
+        quote(table_name),
+        fields.join(", "),
+        ", ?".repeat(entries.len())
+    );
+
+    let mut span = db_span(&query, table_name);
+    let mut statement = sqlx::query(&query).bind(table_name).bind(first - step).bind(step);
+    for (_, value) in &entries {
+        statement = bind_value(statement, Some(value));
+    }
+    let result = statement.execute(pool).await.map_err(|e| failed(&mut span, e))?;
+    span.set_i64("db.response.affected_rows", result.rows_affected() as i64);
+    Ok(result.last_insert_rowid())
+}
+
+// 旧版本创建的表没有 _version 列, 第一次按版本写入时补上
+pub async fn ensure_version_column(pool: &SqlitePool, table_name: &str) -> Result<(), sqlx::Error> {
+    let has_version = |columns: &[(String, String)]| columns.iter().any(|(name, _)| name == VERSION_FIELD);
+    if has_version(&table_columns(pool, table_name).await?) {
+        return Ok(());
+    }
+    let _lock = schema_lock(table_name).await;
+    let query = format!("ALTER TABLE {} ADD COLUMN {} INTEGER NOT NULL DEFAULT 1", quote(table_name), VERSION_FIELD);
+    match sqlx::query(&query).execute(pool).await {
+        Ok(_) => {
+            schema_changed();
+            Ok(())
+        }
+        // 可能同时被另一个进程加上了
+        Err(e) if has_version(&table_columns(pool, table_name).await?) => {
+            log::debug!("{} already has {}: {}", table_name, VERSION_FIELD, e);
+            Ok(())
+        }
+        Err(e) => Err(e),
+    }
+}
+
+/// Version requirement of a conditional write, as SQL appended to `WHERE id = ?`.
+pub(crate) fn version_condition(expected: Option<&[i64]>) -> String {
+    match expected {
+        Some([]) => " AND 0".to_string(),
+        Some(versions) => format!(" AND {} IN ({})", VERSION_FIELD, vec!["?"; versions.len()].join(", ")),
+        None => String::new(),
+    }
+}
+
+// 条件不满足时区分文档不存在和版本不一致
+async fn write_failure(pool: &SqlitePool, table_name: &str, id: i64) -> StoreError {
+    let query = format!("SELECT {} FROM {} WHERE id = ?", VERSION_FIELD, quote(table_name));
+    match sqlx::query_scalar::<_, i64>(&query).bind(id).fetch_optional(pool).await {
+        Ok(Some(current)) => StoreError::VersionConflict { current },
+        Ok(None) => StoreError::NotFound,
+        Err(e) => StoreError::Database(e),
+    }
+}
+
+/// Writes `assignments` to row `id` and bumps its version, if it is at one of
+/// the `expected` versions. Returns the new version.
+pub async fn update_row(
+    pool: &SqlitePool,
+    table_name: &str,
+    id: i64,
+    assignments: &[(String, Option<Value>)],
+    expected: Option<&[i64]>,
+) -> Result<i64, StoreError> {
+    if !collection_exists(pool, table_name).await? {
+        return Err(StoreError::NotFound);
+    }
+    ensure_version_column(pool, table_name).await.map_err(StoreError::Schema)?;
+    // 写入新的键时补上列
+    let fields: Vec<(&str, Option<&Value>)> = assignments.iter().map(|(column, value)| (column.as_str(), value.as_ref())).collect();
+    let _columns = ensure_columns(pool, table_name, &fields).await.map_err(StoreError::Schema)?;
+
+    let mut sets: Vec<String> = assignments.iter().map(|(column, _)| format!("{} = ?", quote(column))).collect();
+    sets.push(format!("{0} = {0} + 1", VERSION_FIELD));
+    let query = format!(
+        "UPDATE {} SET {} WHERE id = ?{} RETURNING {}",
+        quote(table_name),
+        sets.join(", "),
+        version_condition(expected),
+        VERSION_FIELD
+    );
+
+    let mut span = db_span(&query, table_name);
+    let mut statement = sqlx::query_scalar::<_, i64>(&query);
+    for (_, value) in assignments {
+        statement = match value.as_ref().map(|v| compression::encode(encode_value(v))) {
+            Some(Stored::Text(text)) => statement.bind(text),
+            Some(Stored::Compressed(bytes)) => statement.bind(bytes),
+            None => statement.bind(None::<String>),
+        };
+    }
+    statement = statement.bind(id);
+    for version in expected.unwrap_or_default() {
+        statement = statement.bind(*version);
+    }
+    let version = statement.fetch_optional(pool).await.map_err(|e| failed(&mut span, e))?;
+    span.set_i64("db.response.affected_rows", version.is_some() as i64);
+    match version {
+        Some(version) => Ok(version),
+        None => Err(write_failure(pool, table_name, id).await),
+    }
+}
+
+/// Deletes row `id` if it is at one of the `expected` versions.
+pub async fn delete_row(pool: &SqlitePool, table_name: &str, id: i64, expected: Option<&[i64]>) -> Result<(), StoreError> {
+    if !collection_exists(pool, table_name).await? {
+        return Err(StoreError::NotFound);
+    }
+    if expected.is_some() {
+        ensure_version_column(pool, table_name).await.map_err(StoreError::Schema)?;
+    }
+
+    let query = format!("DELETE FROM {} WHERE id = ?{}", quote(table_name), version_condition(expected));
+    let mut span = db_span(&query, table_name);
+    let mut statement = sqlx::query(&query).bind(id);
+    for version in expected.unwrap_or_default() {
+        statement = statement.bind(*version);
+    }
+    let result = statement.execute(pool).await.map_err(|e| failed(&mut span, e))?;
+    span.set_i64("db.response.affected_rows", result.rows_affected() as i64);
+    if result.rows_affected() == 0 {
+        return Err(write_failure(pool, table_name, id).await);
+    }
+    forget_hashes(pool, table_name, id).await?;
+    Ok(())
+}
+
+/// Applies the update document `update` to the rows of `tables` matching the
+/// filter document `filter`, all in one transaction, and bumps the version of
+/// each row it changes.
+pub async fn update_where(pool: &SqlitePool, tables: &[String], filter: &Value, update: &Value) -> Result<UpdatedMany, StoreError> {
+    let filter = parse_filter(filter).map_err(|e| StoreError::Invalid(format!("Invalid filter: {}", e)))?;
+    let update = parse_update(update).map_err(|e| StoreError::Invalid(format!("Invalid update: {}", e)))?;
+    for table in tables {
+        ensure_version_column(pool, table).await.map_err(StoreError::Schema)?;
+        let columns: Vec<String> = table_columns(pool, table).await?.into_iter().map(|(name, _)| name).collect();
+        filter.validate(&columns).map_err(|e| StoreError::Invalid(format!("Invalid filter: {}", e)))?;
+        update.validate(&columns, VERSION_FIELD).map_err(|e| StoreError::Invalid(format!("Invalid update: {}", e)))?;
+    }
+
+    let mut tx = pool.begin().await?;
+    let mut updated = UpdatedMany::default();
+    for table in tables {
+        let mut params = Vec::new();
+        let condition = filter.to_sql(&mut params);
+        let count = format!("SELECT COUNT(*) FROM {} WHERE {}", quote(table), condition);
+        let mut statement = sqlx::query(&count);
+        for param in &params {
+            statement = bind_value(statement, Some(param));
+        }
+        updated.matched += statement.fetch_one(&mut *tx).await?.get::<i64, _>(0) as u64;
+
+        // 只修改内容会变化的行, 它们的版本号加一
+        let mut params = Vec::new();
+        let mut sets = update.assignments(&mut params);
+        sets.push(format!("{0} = {0} + 1", VERSION_FIELD));
+        let condition = filter.to_sql(&mut params);
+        let changes = update.changes(&mut params);
+        let query = format!("UPDATE {} SET {} WHERE {} AND {} RETURNING id", quote(table), sets.join(", "), condition, changes);
+        let mut span = db_span(&query, table);
+        let mut statement = sqlx::query(&query);
+        for param in &params {
+            statement = bind_value(statement, Some(param));
+        }
+        let rows = statement.fetch_all(&mut *tx).await.map_err(|e| failed(&mut span, e))?;
+        let modified: Vec<i64> = rows.iter().map(|row| row.get(0)).collect();
+        span.set_i64("db.response.affected_rows", modified.len() as i64);
+
+        sqlx::query("DELETE FROM _content_hashes WHERE collection = ? AND document_id IN (SELECT value FROM json_each(?))")
+            .bind(table)
+            .bind(serde_json::to_string(&modified).unwrap_or_default())
+            .execute(&mut *tx)
+            .await?;
+        updated.modified.extend(modified);
+    }
+    tx.commit().await?;
+    Ok(updated)
+}
+
+/// Deletes the rows of `tables` matching the filter document `filter`, all
+/// in one transaction, and returns their ids; with `dry_run` only selects them.
+pub async fn delete_where(pool: &SqlitePool, tables: &[String], filter: &Value, dry_run: bool) -> Result<Vec<i64>, StoreError> {
+    let filter = parse_filter(filter).map_err(|e| StoreError::Invalid(format!("Invalid filter: {}", e)))?;
+    for table in tables {
+        let columns: Vec<String> = table_columns(pool, table).await?.into_iter().map(|(name, _)| name).collect();
+        filter.validate(&columns).map_err(|e| StoreError::Invalid(format!("Invalid filter: {}", e)))?;
+    }
+
+    let mut tx = pool.begin().await?;
+    let mut deleted = Vec::new();
+    for table in tables {
+        let mut params = Vec::new();
+        let condition = filter.to_sql(&mut params);
+        let query = match dry_run {
+            true => format!("SELECT id FROM {} WHERE {}", quote(table), condition),
+            false => format!("DELETE FROM {} WHERE {} RETURNING id", quote(table), condition),
+        };
+        let mut span = db_span(&query, table);
+        let mut statement = sqlx::query(&query);
+        for param in &params {
+            statement = bind_value(statement, Some(param));
+        }
+        let rows = statement.fetch_all(&mut *tx).await.map_err(|e| failed(&mut span, e))?;
+        let ids: Vec<i64> = rows.iter().map(|row| row.get(0)).collect();
+        span.set_i64("db.response.affected_rows", if dry_run { 0 } else { ids.len() as i64 });
+
+        if !dry_run {
+            sqlx::query("DELETE FROM _content_hashes WHERE collection = ? AND document_id IN (SELECT value FROM json_each(?))")
+                .bind(table)
+                .bind(serde_json::to_string(&ids).unwrap_or_default())
+                .execute(&mut *tx)
+                .await?;
+        }
+        deleted.extend(ids);
+    }
+    tx.commit().await?;
+    Ok(deleted)
+}
+
+// 在各表中查找符合过滤条件的文档
+pub async fn find_where(pool: &SqlitePool, tables: &[String], filter: &Value) -> Result<Vec<Value>, StoreError> {
+    let filter = parse_filter(filter).map_err(|e| StoreError::Invalid(format!("Invalid filter: {}", e)))?;
+    let mut docs = Vec::new();
+    for table in tables {
+        let columns: Vec<String> = table_columns(pool, table).await?.into_iter().map(|(name, _)| name).collect();
+        filter.validate(&columns).map_err(|e| StoreError::Invalid(format!("Invalid filter: {}", e)))?;
+        docs.extend(find_matching(pool, table, &Condition::of(&filter)).await?);
+    }
+    Ok(docs)
+}
+
+/// A `WHERE` condition and the values bound to its placeholders, in order.
+/// Values are bound in the JSON text form the write path stores them in.
+#[derive(Debug, Clone, Default)]
+pub struct Condition {
+    pub sql: String,
+    pub params: Vec<Value>,
+}
+
+impl Condition {
+    pub fn of(filter: &Filter) -> Self {
+        let mut params = Vec::new();
+        let sql = filter.to_sql(&mut params);
+        Condition { sql, params }
+    }
+}
+
+/// The rows of `table` matching `condition`.
+pub async fn find_matching(pool: &SqlitePool, table: &str, condition: &Condition) -> Result<Vec<Value>, StoreError> {
+    let query = format!("SELECT * FROM {} WHERE {}", quote(table), condition.sql);
+    let _columns = columns_lock(table).await;
+    let mut span = db_span(&query, table);
+    let mut statement = sqlx::query(&query);
+    for param in &condition.params {
+        statement = bind_value(statement, Some(param));
+    }
+    let rows = statement.fetch_all(pool).await.map_err(|e| failed(&mut span, e))?;
+    span.set_i64("db.response.returned_rows", rows.len() as i64);
+    Ok(rows.iter().map(row_to_json).collect())
+}
+
+/// The page `page` of the rows of `tables` matching the filter document
+/// `filter`, with the number of matching rows. Each table is sorted and cut
+/// in SQL; the leading rows of several tables are merged by
+/// [`PageQuery::apply`]. Sorting by or returning a field that is a column of
+/// none of the tables fails.
+pub async fn find_page_where(pool: &SqlitePool, tables: &[String], filter: &Value, page: &PageQuery) -> Result<Paged, StoreError> {
+    let parsed = parse_filter(filter).map_err(|e| StoreError::Invalid(format!("Invalid filter: {}", e)))?;
+    let mut columns = Vec::new();
+    for table in tables {
+        let names: Vec<String> = table_columns(pool, table).await?.into_iter().map(|(name, _)| name).collect();
+        parsed.validate(&names).map_err(|e| StoreError::Invalid(format!("Invalid filter: {}", e)))?;
+        columns.push(names);
+    }
+    check_page(page, &columns.concat())?;
+
+    let query = match tables.len() {
+        1 => page.clone(),
+        _ => page.leading(),
+    };
+    let condition = Condition::of(&parsed);
+    let mut docs = Vec::new();
+    let mut total = 0;
+    for (table, names) in tables.iter().zip(&columns) {
+        docs.extend(read_page(pool, table, names, &condition, &query).await?);
+        total += count_matching(pool, table, &condition).await?;
+    }
+    let docs = match tables.len() {
+        1 => docs,
+        _ => page.apply(docs),
+    };
+    Ok(Paged { docs, total })
+}
+
+/// The page `page` of the rows of `table` matching `condition`, with the
+/// number of matching rows.
+pub async fn find_page_matching(pool: &SqlitePool, table: &str, condition: &Condition, page: &PageQuery) -> Result<Paged, StoreError> {
+    let columns: Vec<String> = table_columns(pool, table).await?.into_iter().map(|(name, _)| name).collect();
+    check_page(page, &columns)?;
+    let docs = read_page(pool, table, &columns, condition, page).await?;
+    let total = count_matching(pool, table, condition).await?;
+    Ok(Paged { docs, total })
+}
+
+// 排序和返回的字段须是某张表的列
+fn check_page(page: &PageQuery, columns: &[String]) -> Result<(), StoreError> {
+    let known = |field: &str| columns.iter().any(|name| name == field);
+    if let Some((field, _)) = page.sort.iter().find(|(field, _)| !known(field)) {
+        return Err(StoreError::Invalid(format!("Cannot sort by '{}': no such field", field)));
+    }
+    if let Some(field) = page.fields.iter().flatten().find(|field| !known(field)) {
+        return Err(StoreError::Invalid(format!("No field '{}' to return", field)));
+    }
+    Ok(())
+}
+
+// 读取一张表中的一页; 表中没有的排序字段对这张表的行都是 null, 不影响顺序
+async fn read_page(pool: &SqlitePool, table: &str, columns: &[String], condition: &Condition, page: &PageQuery) -> Result<Vec<Value>, StoreError> {
+    let selected = match &page.fields {
+        None => "*".to_string(),
+        Some(fields) => std::iter::once("id")
+            .chain(fields.iter().map(String::as_str).filter(|field| *field != "id" && columns.iter().any(|c| c == field)))
+            .map(quote)
+            .collect::<Vec<_>>()
+            .join(", "),
+    };
+    let mut order: Vec<String> = page
+        .sort
+        .iter()
+        .filter(|(field, _)| columns.contains(field))
+        .flat_map(|(field, descending)| sort_keys(field, *descending))
+        .collect();
+    order.push("id".to_string());
+    let query = format!("SELECT {} FROM {} WHERE {} ORDER BY {} LIMIT ? OFFSET ?", selected, quote(table), condition.sql, order.join(", "));
+    let _columns = columns_lock(table).await;
+    let mut span = db_span(&query, table);
+    let mut statement = sqlx::query(&query);
+    for param in &condition.params {
+        statement = bind_value(statement, Some(param));
+    }
+    let limit = page.limit.map_or(-1, |limit| i64::try_from(limit).unwrap_or(i64::MAX));
+    let offset = i64::try_from(page.offset).unwrap_or(i64::MAX);
+    let rows = statement.bind(limit).bind(offset).fetch_all(pool).await.map_err(|e| failed(&mut span, e))?;
+    span.set_i64("db.response.returned_rows", rows.len() as i64);
+    Ok(rows.iter().map(row_to_json).collect())
+}
+
+// 与 $orderby 的顺序一致: null 在前, 然后是布尔值、数字、字符串, 对象和数组在最后; 同类的值按 JSON 中的取值比较
+fn sort_keys(field: &str, descending: bool) -> [String; 2] {
+    let column = quote(field);
+    let direction = if descending { " DESC" } else { "" };
+    let rank = format!(
+        "CASE WHEN {0} IS NULL THEN 0 WHEN typeof({0}) = 'blob' OR NOT json_valid({0}) THEN 4 \
+         ELSE CASE json_type({0}) WHEN 'null' THEN 0 WHEN 'true' THEN 1 WHEN 'false' THEN 1 \
+         WHEN 'integer' THEN 2 WHEN 'real' THEN 2 WHEN 'text' THEN 3 ELSE 4 END END{1}",
+        column, direction
+    );
+    let value = format!(
+        "CASE WHEN {0} IS NULL OR typeof({0}) = 'blob' OR NOT json_valid({0}) THEN NULL \
+         WHEN json_type({0}) IN ('true', 'false', 'integer', 'real', 'text') THEN json_extract({0}, '$') END{1}",
+        column, direction
+    );
+    [rank, value]
+}
+
+/// The number of rows of `tables` matching the filter document `filter`.
+pub async fn count_where(pool: &SqlitePool, tables: &[String], filter: &Value) -> Result<u64, StoreError> {
+    let filter = parse_filter(filter).map_err(|e| StoreError::Invalid(format!("Invalid filter: {}", e)))?;
+    let mut count = 0;
+    for table in tables {
+        let columns: Vec<String> = table_columns(pool, table).await?.into_iter().map(|(name, _)| name).collect();
+        filter.validate(&columns).map_err(|e| StoreError::Invalid(format!("Invalid filter: {}", e)))?;
+        count += count_matching(pool, table, &Condition::of(&filter)).await?;
+    }
+    Ok(count)
+}
+
+/// The number of rows of `table` matching `condition`.
+pub async fn count_matching(pool: &SqlitePool, table: &str, condition: &Condition) -> Result<u64, StoreError> {
+    let query = format!("SELECT COUNT(*) FROM {} WHERE {}", quote(table), condition.sql);
+    let _columns = columns_lock(table).await;
+    let mut span = db_span(&query, table);
+    let mut statement = sqlx::query_scalar::<_, i64>(&query);
+    for param in &condition.params {
+        statement = match compression::encode(encode_value(param)) {
+            Stored::Text(text) => statement.bind(text),
+            Stored::Compressed(bytes) => statement.bind(bytes),
+        };
+    }
+    Ok(statement.fetch_one(pool).await.map_err(|e| failed(&mut span, e))? as u64)
+}
+
+/// The version of the collection stored in `tables`: the number of rows,
+/// the highest id and the sum of the document versions of each. Ids are
+/// never reused, so inserts, updates and deletes all change it.
+pub async fn version_of(pool: &SqlitePool, tables: &[String]) -> Result<String, StoreError> {
+    let mut parts = Vec::new();
+    for table in tables {
+        let query = format!("SELECT COUNT(*), COALESCE(MAX(id), 0), CAST(TOTAL({}) AS INTEGER) FROM {}", VERSION_FIELD, quote(table));
+        let mut span = db_span(&query, table);
+        let (count, max, versions): (i64, i64, i64) =
+            sqlx::query_as(&query).fetch_one(pool).await.map_err(|e| failed(&mut span, e))?;
+        parts.push(format!("{}-{}-{}", count, max, versions));
+    }
+    Ok(parts.join("."))
+}
+
+// 文档内容变了或被删除后, 原来的内容哈希不再指向它
+async fn forget_hashes(pool: &SqlitePool, table_name: &str, id: i64) -> Result<(), sqlx::Error> {
+    sqlx::query("DELETE FROM _content_hashes WHERE collection = ? AND document_id = ?")
+        .bind(table_name)
+        .bind(id)
+        .execute(pool)
+        .await?;
+    Ok(())
+}
+
+// 文档中的字段 (id 和 _version 除外) 对应的赋值
+pub(crate) fn field_assignments(doc: &Value) -> Vec<(String, Option<Value>)> {
+    doc.as_object()
+        .map(|fields| {
+            fields
+                .iter()
+                .filter(|(k, _)| *k != "id" && *k != VERSION_FIELD)
+                .map(|(k, v)| (k.clone(), Some(v.clone()).filter(|v| !v.is_null())))
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+pub(crate) async fn hashed_document(pool: &SqlitePool, table_name: &str, hash: &str) -> Result<Option<i64>, sqlx::Error> {
+    sqlx::query_scalar("SELECT document_id FROM _content_hashes WHERE collection = ? AND hash = ?")
+        .bind(table_name)
+        .bind(hash)
+        .fetch_optional(pool)
+        .await
+}
+
+// 在 span 上记录错误并原样返回
+fn failed(span: &mut Span, e: sqlx::Error) -> sqlx::Error {
+    span.error(&e);
+    e
+}
+
+// 将一行数据转换为 JSON 对象, 以列名为键
+pub fn row_to_json(row: &SqliteRow) -> Value {
+    let mut map = serde_json::Map::new();
+    for column in row.columns() {
+        let i = column.ordinal();
+        // 按值实际的存储类型解码, 而不是列声明的类型
+        let type_name = match row.try_get_raw(i) {
+            Ok(raw) if !raw.is_null() => raw.type_info().name().to_string(),
+            _ => "NULL".to_string(),
+        };
+
+        let value = match type_name.as_str() {
+            "NULL" => Value::Null,
+            "INTEGER" => row.try_get::<i64, _>(i).map(Value::from).unwrap_or(Value::Null),
+            "REAL" => row.try_get::<f64, _>(i).map(Value::from).unwrap_or(Value::Null),
+            // 压缩保存的大字段
+            "BLOB" => match row.try_get::<Vec<u8>, _>(i).ok().as_deref().and_then(compression::decode) {
+                Some(text) => serde_json::from_str(&text).unwrap_or(Value::String(text)),
+                None => Value::Null,
+            },
+            // 写入时值以 JSON 文本保存, 读取时还原; 无法解析的按普通字符串返回
+            _ => match row.try_get::<String, _>(i) {
+                Ok(text) => serde_json::from_str(&text).unwrap_or(Value::String(text)),
+                Err(_) => Value::Null,
+            },
+        };
+        map.insert(column.name().to_string(), value);
+    }
+    Value::Object(map)
+}
+
+/// SQLite implementation of [`DocumentStore`], one table per uri.
+#[derive(Clone)]
+pub struct SqliteStore {
+    pool: SqlitePool,
+    /// First id and step between ids, when not 1, 2, ...
+    ids: Option<(i64, i64)>,
+}
+
+impl SqliteStore {
+    pub fn new(pool: SqlitePool) -> Self {
+        Self { pool, ids: None }
+    }
+
+    /// A store that gives the documents of each table the ids `first`,
+    /// `first + step`, `first + 2 * step`, ...
+    pub fn with_ids(pool: SqlitePool, first: i64, step: i64) -> Self {
+        Self { pool, ids: Some((first, step)) }
+    }
+
+    pub fn pool(&self) -> &SqlitePool {
+        &self.pool
+    }
+}
+
+#[async_trait]
+impl DocumentStore for SqliteStore {
+    async fn insert(&self, uri: &str, doc: &Value) -> Result<i64, StoreError> {
+        check_fields(doc)?;
+        let table_name = table_name(uri);
+
+        // 动态创建表
+        let _columns = create_table(&self.pool, &table_name, doc).await?;
+
+        match self.ids {
+            Some((first, step)) => insert_row_from(&self.pool, &table_name, doc, first, step).await,
+            None => insert_row(&self.pool, &table_name, doc).await,
+        }
+    }
+
+    async fn list(&self, uri: &str) -> Result<Vec<Value>, StoreError> {
+        let table_name = table_name(uri);
+        let query = format!("SELECT * FROM {}", quote(&table_name));
+        let _columns = columns_lock(&table_name).await;
+        let mut span = db_span(&query, &table_name);
+        let rows = sqlx::query(&query)
+            .fetch_all(&self.pool)
+            .await
+            .map_err(|e| failed(&mut span, e))?;
+        span.set_i64("db.response.returned_rows", rows.len() as i64);
+        Ok(rows.iter().map(row_to_json).collect())
+    }
+
+    async fn get(&self, uri: &str, id: i64) -> Result<Option<Value>, StoreError> {
+        let table_name = table_name(uri);
+        let query = format!("SELECT * FROM {} WHERE id = $1", quote(&table_name));
+        let _columns = columns_lock(&table_name).await;
+        let mut span = db_span(&query, &table_name);
+        let row = match sqlx::query(&query).bind(id).fetch_optional(&self.pool).await {
+            Ok(row) => row,
+            // 查询出错时再确认集合是否存在, 不给每次读取多加一次查询
+            Err(e) if !collection_exists(&self.pool, &table_name).await? => {
+                span.error(&e);
+                return Err(StoreError::NotFound);
+            }
+            Err(e) => return Err(failed(&mut span, e).into()),
+        };
+        span.set_i64("db.response.returned_rows", row.is_some() as i64);
+        Ok(row.as_ref().map(row_to_json))
+    }
+
+    async fn get_many(&self, uri: &str, ids: &[i64]) -> Result<Vec<Value>, StoreError> {
+        let table_name = table_name(uri);
+        if !collection_exists(&self.pool, &table_name).await? {
+            return Err(StoreError::NotFound);
+        }
+        if ids.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        let query = format!("SELECT * FROM {} WHERE id IN ({})", quote(&table_name), vec!["?"; ids.len()].join(", "));
+        let _columns = columns_lock(&table_name).await;
+        let mut span = db_span(&query, &table_name);
+        let mut statement = sqlx::query(&query);
+        for id in ids {
+            statement = statement.bind(*id);
+        }
+        let rows = statement.fetch_all(&self.pool).await.map_err(|e| failed(&mut span, e))?;
+        span.set_i64("db.response.returned_rows", rows.len() as i64);
+
+        // 按请求中 id 的顺序返回
+        let mut found: HashMap<i64, Value> = rows
+            .iter()
+            .map(row_to_json)
+            .filter_map(|doc| Some((doc.get("id")?.as_i64()?, doc)))
+            .collect();
+        Ok(ids.iter().filter_map(|id| found.remove(id)).collect())
+    }
+
+    async fn insert_unique(&self, uri: &str, doc: &Value, hash: &str, mode: DedupMode) -> Result<Inserted, StoreError> {
+        check_fields(doc)?;
+        let table_name = table_name(uri);
+        if let Some(id) = hashed_document(&self.pool, &table_name, hash).await? {
+            if mode == DedupMode::Upsert {
+                update_row(&self.pool, &table_name, id, &field_assignments(doc), None).await?;
+            }
+            return Ok(Inserted { id, duplicate: true });
+        }
+
+        let id = self.insert(uri, doc).await?;
+        let recorded = sqlx::query("INSERT OR IGNORE INTO _content_hashes (collection, hash, document_id) VALUES (?, ?, ?)")
+            .bind(&table_name)
+            .bind(hash)
+            .bind(id)
+            .execute(&self.pool)
+            .await?;
+        if recorded.rows_affected() == 1 {
+            return Ok(Inserted { id, duplicate: false });
+        }
+
+        // 并发插入了相同内容: 保留先登记的文档, 删除刚插入的这一行
+        sqlx::query(&format!("DELETE FROM {} WHERE id = ?", quote(&table_name)))
+            .bind(id)
+            .execute(&self.pool)
+            .await?;
+        let existing = hashed_document(&self.pool, &table_name, hash).await?.unwrap_or(id);
+        if mode == DedupMode::Upsert {
+            update_row(&self.pool, &table_name, existing, &field_assignments(doc), None).await?;
+        }
+        Ok(Inserted { id: existing, duplicate: true })
+    }
+
+    async fn upsert(&self, uri: &str, key: &str, doc: &Value) -> Result<Upserted, StoreError> {
+        check_fields(doc)?;
+        upsert_row(&self.pool, &table_name(uri), key, doc, self.ids).await
+    }
+
+    async fn replace(&self, uri: &str, id: i64, doc: &Value, expected: Option<&[i64]>) -> Result<i64, StoreError> {
+        check_fields(doc)?;
+        let table_name = table_name(uri);
+        // 文档中没有的列置为 NULL
+        let mut assignments = field_assignments(doc);
+        for (column, _) in table_columns(&self.pool, &table_name).await? {
+            if column != "id" && column != VERSION_FIELD && !assignments.iter().any(|(c, _)| c.eq_ignore_ascii_case(&column)) {
+                assignments.push((column, None));
+            }
+        }
+        let version = update_row(&self.pool, &table_name, id, &assignments, expected).await?;
+        forget_hashes(&self.pool, &table_name, id).await?;
+        Ok(version)
+    }
+
+    async fn update(&self, uri: &str, id: i64, doc: &Value, expected: Option<&[i64]>) -> Result<i64, StoreError> {
+        check_fields(doc)?;
+        let table_name = table_name(uri);
+        let version = update_row(&self.pool, &table_name, id, &field_assignments(doc), expected).await?;
+        forget_hashes(&self.pool, &table_name, id).await?;
+        Ok(version)
+    }
+
+    async fn delete(&self, uri: &str, id: i64, expected: Option<&[i64]>) -> Result<(), StoreError> {
+        delete_row(&self.pool, &table_name(uri), id, expected).await
+    }
+
+    async fn update_many(&self, uri: &str, filter: &Value, update: &Value) -> Result<UpdatedMany, StoreError> {
+        let table_name = table_name(uri);
+        if !collection_exists(&self.pool, &table_name).await? {
+            return Err(StoreError::NotFound);
+        }
+        update_where(&self.pool, &[table_name], filter, update).await
+    }
+
+    async fn delete_many(&self, uri: &str, filter: &Value, dry_run: bool) -> Result<Vec<i64>, StoreError> {
+        let table_name = table_name(uri);
+        if !collection_exists(&self.pool, &table_name).await? {
+            return Err(StoreError::NotFound);
+        }
+        delete_where(&self.pool, &[table_name], filter, dry_run).await
+    }
+
+    async fn find(&self, uri: &str, filter: &Value) -> Result<Vec<Value>, StoreError> {
+        let table_name = table_name(uri);
+        if !collection_exists(&self.pool, &table_name).await? {
+            return Err(StoreError::NotFound);
+        }
+        find_where(&self.pool, &[table_name], filter).await
+    }
+
+    async fn count(&self, uri: &str, filter: &Value) -> Result<u64, StoreError> {
+        let table_name = table_name(uri);
+        if !collection_exists(&self.pool, &table_name).await? {
+            return Err(StoreError::NotFound);
+        }
+        count_where(&self.pool, &[table_name], filter).await
+    }
+
+    async fn find_page(&self, uri: &str, filter: &Value, page: &PageQuery) -> Result<Paged, StoreError> {
+        let table_name = table_name(uri);
+        if !collection_exists(&self.pool, &table_name).await? {
+            return Err(StoreError::NotFound);
+        }
+        find_page_where(&self.pool, &[table_name], filter, page).await
+    }
+
+    async fn version(&self, uri: &str) -> Result<String, StoreError> {
+        let table_name = table_name(uri);
+        if !collection_exists(&self.pool, &table_name).await? {
+            return Err(StoreError::NotFound);
+        }
+        version_of(&self.pool, &[table_name]).await
+    }
+
+    async fn committed(&self, _writes: &[Written]) {}
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TestStore;
+    use serde_json::json;
+
+    fn columns(list: &[(&str, &str)]) -> Vec<(String, String)> {
+        list.iter().map(|(name, ty)| (name.to_string(), ty.to_string())).collect()
+    }
+
+    #[test]
+    fn widened_type_keeps_values_as_written() {
+        assert_eq!(widened_type("INTEGER", &json!(3)), None);
+        assert_eq!(widened_type("integer", &json!(3.5)), Some("NUMERIC"));
+        assert_eq!(widened_type("REAL", &json!(3)), Some("NUMERIC"));
+        assert_eq!(widened_type("INTEGER", &json!("x")), Some("NUMERIC"));
+        assert_eq!(widened_type("BOOLEAN", &json!(1)), Some("NUMERIC"));
+        assert_eq!(widened_type("TEXT", &json!(100)), Some("NUMERIC"));
+        assert_eq!(widened_type("TEXT", &json!(true)), None);
+        assert_eq!(widened_type("TEXT", &json!({ "a": 1 })), None);
+        assert_eq!(widened_type("TEXT", &json!(u64::MAX)), None);
+        assert_eq!(widened_type("NUMERIC", &json!("x")), None);
+        assert_eq!(widened_type("BLOB", &json!(1)), None);
+        assert_eq!(widened_type("INTEGER", &Value::Null), None);
+    }
+
+    #[test]
+    fn widenings_name_each_column_once() {
+        let table = columns(&[("id", "INTEGER"), ("Age", "INTEGER"), ("name", "TEXT")]);
+        let (age, text, number) = (json!(1.5), json!("x"), json!(7));
+        let fields = [("age", Some(&age)), ("AGE", Some(&text)), ("name", Some(&text)), ("missing", Some(&number)), ("name", None)];
+        assert_eq!(widenings(&table, &fields), vec![("Age".to_string(), "NUMERIC")]);
+    }
+
+    #[tokio::test]
+    async fn mixed_values_read_back_as_written() {
+        let store = TestStore::new().await;
+        let docs = [json!({ "n": 3, "s": "a" }), json!({ "n": 2.5, "s": 100 }), json!({ "n": "x", "s": 30 }), json!({ "n": true, "s": "7" })];
+        store.load_fixtures("mixed", &docs).await.unwrap();
+        store.assert_column_type("mixed", "n", "NUMERIC").await;
+        store.assert_column_type("mixed", "s", "NUMERIC").await;
+
+        let read = store.data().list("mixed").await.unwrap();
+        let values: Vec<(Value, Value)> = read.iter().map(|doc| (doc["n"].clone(), doc["s"].clone())).collect();
+        assert_eq!(
+            values,
+            vec![(json!(3), json!("a")), (json!(2.5), json!(100)), (json!("x"), json!(30)), (json!(true), json!("7"))]
+        );
+        // 数字按数值比较, 作为文本时 "100" < "50"
+        let found = store.data().find("mixed", &json!({ "s": { "$lt": 50 } })).await.unwrap();
+        assert_eq!(found.iter().map(|doc| doc["id"].clone()).collect::<Vec<_>>(), vec![json!(3)]);
+    }
+
+    #[tokio::test]
+    async fn widening_keeps_rows_ids_and_indexes() {
+        let store = TestStore::new().await;
+        store.load_fixtures("users", &[json!({ "age": 3 }), json!({ "age": 4 })]).await.unwrap();
+        sqlx::query("CREATE INDEX users_age ON users (age)").execute(store.pool()).await.unwrap();
+        store.load_fixtures("users", &[json!({ "age": 4.5 })]).await.unwrap();
+        store.assert_column_type("users", "age", "NUMERIC").await;
+        assert_eq!(store.row_count("users").await, 3);
+        let indexes: Vec<String> = sqlx::query_scalar("SELECT name FROM sqlite_master WHERE type = 'index' AND tbl_name = 'users'")
+            .fetch_all(store.pool())
+            .await
+            .unwrap();
+        assert_eq!(indexes, vec!["users_age".to_string()]);
+        store.load_fixtures("users", &[json!({ "age": 5 })]).await.unwrap();
+        let ids: Vec<i64> = sqlx::query_scalar("SELECT id FROM users ORDER BY id").fetch_all(store.pool()).await.unwrap();
+        assert_eq!(ids, vec![1, 2, 3, 4]);
+    }
+}
@@ -0,0 +1,150 @@
+//! Change fan-out through Redis.
+//!
+//! With the `redis` feature and a `redis` section, the [`ChangeFeed`] does
+//! not deliver changes to its own subscribers directly. It publishes them on
+//! the `<key_prefix>:changes` channel instead, and every instance delivers
+//! what arrives there to its WebSocket subscribers. Subscribers connected to
+//! any instance therefore see the changes made through all of them, each
+//! once. Events from one instance arrive in the order they were published.
+//!
+//! Redis pub/sub does not keep messages: changes published while Redis or an
+//! instance's subscription is down never reach that instance's subscribers,
+//! who get no `lagged` notice for them.
+
+use std::sync::Arc;
+
+use crate::cdc::ChangeFeed;
+use crate::config::ConfigHandle;
+use crate::metrics::Metrics;
+
+/// The change feed, relayed through Redis when `redis` is configured and
+/// reachable at startup.
+#[cfg(feature = "redis")]
+pub async fn feed(config: &ConfigHandle, metrics: Arc<Metrics>) -> Arc<ChangeFeed> {
+    let Some(settings) = config.get().redis.clone() else {
+        return Arc::new(ChangeFeed::new(None));
+    };
+    match relay::connect(&settings, metrics).await {
+        Ok((relay, listener)) => {
+            let feed = Arc::new(ChangeFeed::new(Some(Box::new(relay))));
+            tokio::spawn(listener.run(feed.clone()));
+            feed
+        }
+        Err(e) => {
+            log::error!("redis could not be reached, changes are only sent to this instance's subscribers: {}", e);
+            Arc::new(ChangeFeed::new(None))
+        }
+    }
+}
+
+#[cfg(not(feature = "redis"))]
+pub async fn feed(_config: &ConfigHandle, _metrics: Arc<Metrics>) -> Arc<ChangeFeed> {
+    Arc::new(ChangeFeed::new(None))
+}
+
+#[cfg(feature = "redis")]
+mod relay {
+    use futures_util::StreamExt;
+    use redis::aio::{ConnectionManager, ConnectionManagerConfig};
+    use std::sync::Arc;
+    use std::time::Duration;
+    use tokio::sync::mpsc;
+
+    use crate::cdc::{Change, ChangeFeed, ChangeSink};
+    use crate::config::Redis;
+    use crate::metrics::Metrics;
+
+    const TIMEOUT: Duration = Duration::from_millis(500);
+    const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+    const RETRY_DELAY: Duration = Duration::from_secs(5);
+    /// Changes waiting to be published; more are dropped while Redis is slow.
+    const BACKLOG: usize = 1024;
+
+    /// Publishes changes in order from a single task.
+    pub struct Relay {
+        queue: mpsc::Sender<String>,
+        metrics: Arc<Metrics>,
+    }
+
+    /// Receives the changes of every instance.
+    pub struct Listener {
+        client: redis::Client,
+        channel: String,
+    }
+
+    pub async fn connect(settings: &Redis, metrics: Arc<Metrics>) -> Result<(Relay, Listener), String> {
+        let client = redis::Client::open(settings.url.as_str()).map_err(|e| e.to_string())?;
+        let config = ConnectionManagerConfig::new()
+            .set_connection_timeout(TIMEOUT)
+            .set_response_timeout(TIMEOUT);
+        let redis = match tokio::time::timeout(CONNECT_TIMEOUT, ConnectionManager::new_with_config(client.clone(), config)).await {
+            Ok(redis) => redis.map_err(|e| e.to_string())?,
+            Err(_) => return Err("timed out".to_string()),
+        };
+        let channel = format!("{}:changes", settings.key_prefix);
+        let (queue, pending) = mpsc::channel(BACKLOG);
+        tokio::spawn(publish(redis, channel.clone(), pending, metrics.clone()));
+        Ok((Relay { queue, metrics }, Listener { client, channel }))
+    }
+
+    impl ChangeSink for Relay {
+        fn captures(&self, _collection: &str) -> bool {
+            true
+        }
+
+        fn publish(&self, change: &Change) {
+            let message = serde_json::to_string(change).unwrap_or_default();
+            if self.queue.try_send(message).is_err() {
+                log::warn!("redis relay is behind, dropping change of {}/{}", change.collection, change.id);
+                self.metrics.incr("changes_relayed_total", vec![("status", "dropped".to_string())], 1.0);
+            }
+        }
+    }
+
+    async fn publish(mut redis: ConnectionManager, channel: String, mut pending: mpsc::Receiver<String>, metrics: Arc<Metrics>) {
+        while let Some(message) = pending.recv().await {
+            let cmd = redis::cmd("PUBLISH").arg(&channel).arg(message).to_owned();
+            let status = match tokio::time::timeout(TIMEOUT, cmd.query_async::<()>(&mut redis)).await {
+                Ok(Ok(())) => "published",
+                Ok(Err(e)) => {
+                    log::warn!("redis failed to publish a change: {}", e);
+                    "failed"
+                }
+                Err(_) => {
+                    log::warn!("redis failed to publish a change: timed out");
+                    "failed"
+                }
+            };
+            metrics.incr("changes_relayed_total", vec![("status", status.to_string())], 1.0);
+        }
+    }
+
+    impl Listener {
+        // 订阅断开后重新连接
+        pub async fn run(self, feed: Arc<ChangeFeed>) {
+            loop {
+                if let Err(e) = self.listen(&feed).await {
+                    log::warn!("redis change subscription on '{}' failed: {}", self.channel, e);
+                }
+                tokio::time::sleep(RETRY_DELAY).await;
+            }
+        }
+
+        async fn listen(&self, feed: &ChangeFeed) -> Result<(), String> {
+            let mut pubsub = match tokio::time::timeout(CONNECT_TIMEOUT, self.client.get_async_pubsub()).await {
+                Ok(pubsub) => pubsub.map_err(|e| e.to_string())?,
+                Err(_) => return Err("timed out".to_string()),
+            };
+            pubsub.subscribe(&self.channel).await.map_err(|e| e.to_string())?;
+            log::info!("redis relaying changes on '{}'", self.channel);
+            let mut messages = pubsub.on_message();
+            while let Some(message) = messages.next().await {
+                match serde_json::from_slice::<Change>(message.get_payload_bytes()) {
+                    Ok(change) => feed.deliver(change),
+                    Err(e) => log::debug!("redis ignored a malformed change on '{}': {}", self.channel, e),
+                }
+            }
+            Err("connection closed".to_string())
+        }
+    }
+}
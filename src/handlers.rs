@@ -1,112 +1,1243 @@
-use actix_web::{web, HttpResponse};
-use serde_json::Value;
-use sqlx::{SqlitePool, Row};
+use actix_web::http::header::{HeaderValue, CONTENT_TYPE, ETAG, IF_MATCH, IF_NONE_MATCH, LOCATION};
+use actix_web::{web, HttpRequest, HttpResponse};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::collections::{BTreeMap, HashSet};
+use std::time::Instant;
+use crate::access_log::annotate;
+use crate::casing;
+use crate::config::{ConfigHandle, Dedup, KeyCase};
+use crate::jsonapi;
 use crate::models::JsonData;
+use crate::odata;
+use crate::patch::{self, PatchError};
+use crate::query::Filter;
+use crate::store::{content_hash, DocumentStore, Inserted, PageQuery, Paged, StoreError, Upserted, VERSION_FIELD};
 
-// 动态创建表
-async fn create_table(pool: &SqlitePool, table_name: &str, data: &Value) -> Result<(), sqlx::Error> {
-    let mut fields = Vec::new();
-    for (key, value) in data.as_object().unwrap() {
-        let field_type = match value {
-            Value::String(_) => "TEXT",
-            Value::Number(_) => "INTEGER",
-            Value::Bool(_) => "BOOLEAN",
-            Value::Object(_) => "TEXT", // 嵌套对象存储为 JSON 字符串
-            _ => "TEXT",
-        };
-        fields.push(format!("{} {}", key, field_type));
+/// Id of the document a request created, attached to its response.
+#[derive(Debug, Clone, Copy)]
+pub struct CreatedId(pub i64);
+
+/// Route segment capturing a collection path such as `api/v1/users`: the
+/// first segment, then every following one that neither starts with `_` nor
+/// is a number, so `/api/v1/users/7/_history` is document 7 of `api/v1/users`.
+pub const URI: &str = r"{uri:[^/]+(?:/(?:[^/_0-9][^/]*|[0-9]+[^/0-9][^/]*))*}";
+
+/// `pattern` with `{uri}` standing for [`URI`].
+pub fn route(pattern: &str) -> String {
+    pattern.replace("{uri}", URI)
+}
+
+// 注册所有路由
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.route("/_mget", web::post().to(get_many_collections))
+        .route(&route("/{uri}/_mget"), web::post().to(get_many_json))
+        .route(&route("/{uri}/_find"), web::post().to(find_json))
+        .route(&route("/{uri}/_count"), web::get().to(count_json))
+        .route(&route("/{uri}/_upsert"), web::post().to(upsert_json))
+        .route(&route("/{uri}/_update_many"), web::post().to(update_many_json))
+        .route(&route("/{uri}/_delete_many"), web::post().to(delete_many_json))
+        .route(&route("/{uri}"), web::post().to(insert_json))
+        .route(&route("/{uri}"), web::get().to(get_all_json))
+        .route(&route("/{uri}/{id}"), web::get().to(get_json_by_id))
+        .route(&route("/{uri}/{id}"), web::put().to(replace_json))
+        .route(&route("/{uri}/{id}"), web::patch().to(update_json))
+        .route(&route("/{uri}/{id}"), web::delete().to(delete_json));
+}
+
+/// Strong entity tag of a document version.
+pub fn etag(version: i64) -> String {
+    format!("\"{}\"", version)
+}
+
+/// Weak entity tag of a listing of a collection at `version`; JSON:API
+/// output is tagged apart.
+fn listing_etag(req: &HttpRequest, version: &str) -> String {
+    match jsonapi::requested(req) {
+        true => format!("W/\"{}-jsonapi\"", version),
+        false => format!("W/\"{}\"", version),
     }
+}
+
+/// Whether the `If-None-Match` header of `req` names `tag`, compared
+/// weakly, or is `*`.
+fn if_none_match(req: &HttpRequest, tag: &str) -> bool {
+    let opaque = |tag: &str| tag.trim().trim_start_matches("W/").to_string();
+    let Some(header) = req.headers().get(IF_NONE_MATCH).and_then(|v| v.to_str().ok()) else {
+        return false;
+    };
+    header.trim() == "*" || header.split(',').any(|candidate| opaque(candidate) == opaque(tag))
+}
 
-    let query = format!(
-        "CREATE TABLE IF NOT EXISTS {} (id INTEGER PRIMARY KEY AUTOINCREMENT, {})",
-        table_name,
-        fields.join(", ")
-    );
+/// Versions an `If-Match` header accepts: `None` without the header, or for
+/// `*`. Besides entity tags it takes bare `_version` numbers; weak and
+/// unparseable tags never match.
+fn if_match(req: &HttpRequest) -> Option<Vec<i64>> {
+    let header = req.headers().get(IF_MATCH)?.to_str().unwrap_or_default().trim();
+    if header == "*" {
+        return None;
+    }
+    let version = |tag: &str| tag.strip_prefix('"').and_then(|tag| tag.strip_suffix('"')).unwrap_or(tag).parse().ok();
+    Some(header.split(',').filter_map(|tag| version(tag.trim())).collect())
+}
 
-    sqlx::query(&query).execute(pool).await?;
-    Ok(())
+#[derive(Debug, Deserialize)]
+pub struct InsertParams {
+    /// Id of the document to write, see [`CLIENT_ID`].
+    pub id: Option<i64>,
 }
 
-// 插入 JSON 数据
+/// Field of an inserted document giving the id to write it under: the
+/// document with that id is replaced, or created if there is none.
+pub const CLIENT_ID: &str = "_id";
+
+// 插入 JSON 数据; 开启去重的集合返回文档 id 以及是否重复
 pub async fn insert_json(
+    req: HttpRequest,
     data: web::Json<JsonData>,
-    pool: web::Data<SqlitePool>,
+    params: web::Query<InsertParams>,
+    store: web::Data<dyn DocumentStore>,
+    config: Option<web::Data<ConfigHandle>>,
 ) -> HttpResponse {
-    let json_data = data.into_inner();
-    let table_name = json_data.uri.replace("/", "_");
+    let JsonData { uri, mut data } = data.into_inner();
+    if !data.is_object() {
+        return HttpResponse::BadRequest().json("Document must be a JSON object");
+    }
 
-    // 动态创建表
-    if let Err(e) = create_table(&pool, &table_name, &json_data.data).await {
-        return HttpResponse::InternalServerError().json(format!("Failed to create table: {}", e));
+    // 客户端给出 id 时按 id 写入: 文档存在则替换, 否则以该 id 新建
+    let client_id = match data.as_object_mut().and_then(|doc| doc.remove(CLIENT_ID)) {
+        Some(id) => match id.as_i64().filter(|id| *id > 0) {
+            Some(id) if params.id.is_some_and(|param| param != id) => {
+                return HttpResponse::BadRequest().json(format!("{} and ?id= name different documents", CLIENT_ID))
+            }
+            Some(id) => Some(id),
+            None => return HttpResponse::BadRequest().json(format!("{} must be a positive integer", CLIENT_ID)),
+        },
+        None => params.id,
+    };
+    if let Some(id) = client_id {
+        if id <= 0 {
+            return HttpResponse::BadRequest().json("id must be a positive integer");
+        }
+        // 分片和分区集合的 id 指明文档所在的文件或表, 由存储分配
+        let settings = config.as_ref().map(|c| c.get());
+        let sharded = settings.as_ref().and_then(|s| s.sharding.as_ref()).is_some_and(|s| s.collections.contains_key(&uri));
+        if sharded || settings.is_some_and(|s| s.partitioning.contains_key(&uri)) {
+            return HttpResponse::BadRequest().json(format!("Collection '{}' assigns its own ids", uri));
+        }
+        return match put_document(store.get_ref(), &uri, id, &data).await {
+            Ok(None) => created(annotate(HttpResponse::Ok().json("Data inserted successfully"), &uri, 1), &uri, id),
+            Ok(Some(version)) => {
+                annotate(HttpResponse::Ok().insert_header((ETAG, etag(version))).json("Data replaced successfully"), &uri, 1)
+            }
+            Err(StoreError::Invalid(e)) => HttpResponse::BadRequest().json(e),
+            Err(e) => write_error(&req, e, id),
+        };
     }
 
-    // 插入数据
-    let fields = json_data.data.as_object().unwrap().keys().map(|k| k.as_str()).collect::<Vec<_>>().join(", ");
-    let values = json_data.data.as_object().unwrap().values().map(|v| format!("'{}'", v)).collect::<Vec<_>>().join(", ");
+    let dedup = config.and_then(|c| c.get().collections.get(&uri).and_then(|c| c.dedup.clone()));
+    let inserted = match store_document(store.get_ref(), dedup.as_ref(), &uri, &data).await {
+        Ok(inserted) => inserted,
+        Err(StoreError::Schema(e)) => {
+            return HttpResponse::InternalServerError().json(format!("Failed to create table: {}", e))
+        }
+        Err(StoreError::Unavailable(e)) => {
+            return HttpResponse::ServiceUnavailable().json(format!("Failed to insert data: {}", e))
+        }
+        Err(StoreError::Database(e)) if unique_violation(&e) => {
+            return HttpResponse::Conflict().json(format!("Failed to insert data: {}", e))
+        }
+        Err(StoreError::Invalid(e)) => return HttpResponse::BadRequest().json(e),
+        Err(e) => return HttpResponse::InternalServerError().json(format!("Failed to insert data: {}", e)),
+    };
+    let response = match dedup {
+        Some(_) => HttpResponse::Ok().json(inserted),
+        None => HttpResponse::Ok().json("Data inserted successfully"),
+    };
+    created(annotate(response, &uri, 1), &uri, inserted.id)
+}
 
-    let query = format!(
-        "INSERT INTO {} ({}) VALUES ({})",
-        table_name, fields, values
-    );
+/// Stores `doc` under `uri`, going through the content hash when the
+/// collection has `dedup` settings.
+pub async fn store_document(
+    store: &dyn DocumentStore,
+    dedup: Option<&Dedup>,
+    uri: &str,
+    doc: &Value,
+) -> Result<Inserted, StoreError> {
+    match dedup {
+        Some(dedup) => {
+            let hash = content_hash(doc, &dedup.fields);
+            store.insert_unique(uri, doc, &hash, dedup.mode).await
+        }
+        None => Ok(Inserted { id: store.insert(uri, doc).await?, duplicate: false }),
+    }
+}
 
-    if let Err(e) = sqlx::query(&query).execute(&**pool).await {
-        return HttpResponse::InternalServerError().json(format!("Failed to insert data: {}", e));
+/// Replaces document `id` of `uri` with `doc`, or creates it under that id
+/// if there is none. Returns the new version of a replaced document, `None`
+/// for a created one.
+pub async fn put_document(store: &dyn DocumentStore, uri: &str, id: i64, doc: &Value) -> Result<Option<i64>, StoreError> {
+    match store.replace(uri, id, doc, None).await {
+        Err(StoreError::NotFound) => {}
+        result => return result.map(Some),
     }
+    let mut created = doc.clone();
+    created["id"] = json!(id);
+    match store.insert(uri, &created).await {
+        Ok(_) => Ok(None),
+        // 可能同时有另一个请求以该 id 建了文档, 改为替换它; 仍然没有时是别的唯一键冲突
+        Err(StoreError::Database(e)) if unique_violation(&e) => match store.replace(uri, id, doc, None).await {
+            Err(StoreError::NotFound) => Err(StoreError::Database(e)),
+            result => result.map(Some),
+        },
+        Err(e) => Err(e),
+    }
+}
 
-    HttpResponse::Ok().json("Data inserted successfully")
+/// Marks `response` as having created document `id`, with a `Location` header pointing at it.
+pub fn created(mut response: HttpResponse, uri: &str, id: i64) -> HttpResponse {
+    if let Ok(location) = HeaderValue::from_str(&format!("/{}/{}", uri, id)) {
+        response.headers_mut().insert(LOCATION, location);
+    }
+    response.extensions_mut().insert(CreatedId(id));
+    response
 }
 
 // 查询所有 JSON 数据
 pub async fn get_all_json(
+    req: HttpRequest,
     uri: web::Path<String>,
-    pool: web::Data<SqlitePool>,
-) -> HttpResponse {
-    let table_name = uri.replace("/", "_");
-
-    let rows = sqlx::query(&format!("SELECT * FROM {}", table_name))
-        .fetch_all(&**pool)
-        .await;
-
-    match rows {
-        Ok(rows) => {
-            let result: Vec<serde_json::Value> = rows.iter()
-                .map(|row| {
-                    let mut map = serde_json::Map::new();
-                    for i in 0..row.len() {
-                        let value: Value = row.try_get(i).unwrap();
-                        map.insert(i.to_string(), value);
-                    }
-                    Value::Object(map)
-                })
-                .collect();
-            HttpResponse::Ok().json(result)
+    params: web::Query<Vec<(String, String)>>,
+    store: web::Data<dyn DocumentStore>,
+    config: Option<web::Data<ConfigHandle>>,
+) -> HttpResponse {
+    listing(&req, &uri, &params, None, store.get_ref(), config.as_ref()).await
+}
+
+#[derive(Debug, Deserialize)]
+pub struct FindRequest {
+    /// Filter document; `{}` matches every document.
+    #[serde(default)]
+    pub filter: Value,
+}
+
+// 按过滤文档查询, 查询参数与列表相同
+pub async fn find_json(
+    req: HttpRequest,
+    uri: web::Path<String>,
+    params: web::Query<Vec<(String, String)>>,
+    body: web::Json<FindRequest>,
+    store: web::Data<dyn DocumentStore>,
+    config: Option<web::Data<ConfigHandle>>,
+) -> HttpResponse {
+    let FindRequest { filter } = body.into_inner();
+    listing(&req, &uri, &params, Some(filter), store.get_ref(), config.as_ref()).await
+}
+
+#[derive(Debug, Serialize)]
+pub struct CountResponse {
+    pub count: u64,
+}
+
+// 统计匹配的文档数量; 过滤条件与列表相同, 另可用 ?filter= 传过滤文档
+pub async fn count_json(
+    uri: web::Path<String>,
+    params: web::Query<Vec<(String, String)>>,
+    store: web::Data<dyn DocumentStore>,
+) -> HttpResponse {
+    let (documents, params): (Vec<_>, Vec<_>) = params.into_inner().into_iter().partition(|(key, _)| key == "filter");
+    let options = match odata::parse(&params) {
+        Ok(options) => options,
+        Err(e) => return HttpResponse::BadRequest().json(e.to_string()),
+    };
+    let mut filters: Vec<Value> = options.filter.iter().map(Filter::to_document).collect();
+    let equal = params_filter(&params);
+    if equal.as_object().is_some_and(|f| !f.is_empty()) {
+        filters.insert(0, equal);
+    }
+    for (_, document) in documents {
+        match serde_json::from_str(&document) {
+            Ok(filter) => filters.insert(0, filter),
+            Err(e) => return HttpResponse::BadRequest().json(format!("Invalid filter: {}", e)),
         }
+    }
+    let filter = match filters.len() {
+        0 => json!({}),
+        1 => filters.remove(0),
+        _ => json!({ "$and": filters }),
+    };
+    match store.count(&uri, &filter).await {
+        Ok(count) => HttpResponse::Ok().json(CountResponse { count }),
+        Err(StoreError::NotFound) => HttpResponse::NotFound().json(format!("No collection '{}'", uri)),
+        Err(StoreError::Invalid(e)) => HttpResponse::BadRequest().json(e),
+        Err(e) => HttpResponse::InternalServerError().json(format!("Failed to count documents: {}", e)),
+    }
+}
+
+/// Answers `req` with the documents of `uri` matching `filter` as well as the
+/// filters, page, sort and other options of `params`.
+async fn listing(
+    req: &HttpRequest,
+    uri: &str,
+    params: &[(String, String)],
+    filter: Option<Value>,
+    store: &dyn DocumentStore,
+    config: Option<&web::Data<ConfigHandle>>,
+) -> HttpResponse {
+    let started = Instant::now();
+    let envelope = match wants_envelope(params) {
+        Ok(envelope) => envelope,
+        Err(response) => return response,
+    };
+    let case = match key_case(params, config, uri) {
+        Ok(case) => case,
+        Err(response) => return response,
+    };
+    let options = match odata::parse(params) {
+        Ok(options) => options,
+        Err(e) => return HttpResponse::BadRequest().json(e.to_string()),
+    };
+    let page = match Page::from_params(params, config) {
+        Ok(page) => page,
+        Err(response) => return response,
+    };
+    let sort = match sort_order(params) {
+        Ok(sort) if !sort.is_empty() && !options.orderby.is_empty() => {
+            return HttpResponse::BadRequest().json("sort and $orderby cannot be combined")
+        }
+        Ok(sort) => sort,
+        Err(e) => return HttpResponse::BadRequest().json(e),
+    };
+    let fields = match projection(params) {
+        Ok(fields) => fields,
+        Err(response) => return response,
+    };
+    // 集合没有变化时客户端可以沿用已有的列表
+    let tag = store.version(uri).await.ok().map(|version| listing_etag(req, &version));
+    if let Some(tag) = tag.as_deref().filter(|tag| if_none_match(req, tag)) {
+        return HttpResponse::NotModified().insert_header((ETAG, tag)).finish();
+    }
+    let mut filters: Vec<Value> = options.filter.iter().map(Filter::to_document).collect();
+    let equal = params_filter(params);
+    if equal.as_object().is_some_and(|f| !f.is_empty()) {
+        filters.insert(0, equal);
+    }
+    match filter {
+        None => {}
+        Some(filter) if filter.is_null() || filter.as_object().is_some_and(|f| f.is_empty()) => {}
+        Some(filter) => filters.insert(0, filter),
+    }
+    let filter = match filters.len() {
+        0 => json!({}),
+        1 => filters.remove(0),
+        _ => json!({ "$and": filters }),
+    };
+    // $skip 和 $top 先从匹配的文档中取出一段, offset 和 limit 再从中分页
+    let offset = options.skip.saturating_add(page.offset);
+    let limit = match options.top {
+        Some(top) => page.limit.min(top.saturating_sub(page.offset)),
+        None => page.limit,
+    };
+    let query = PageQuery {
+        sort: if sort.is_empty() { options.orderby } else { sort },
+        fields,
+        offset: offset as u64,
+        limit: Some(limit as u64),
+    };
+    match store.find_page(uri, &filter, &query).await {
+        Ok(Paged { docs: mut result, total }) => {
+            let total = usize::try_from(total).unwrap_or(usize::MAX);
+            if let Some(case) = case {
+                result = result.into_iter().map(|doc| casing::convert(doc, case)).collect();
+            }
+            let rows = result.len();
+            let mut response = HttpResponse::Ok();
+            if let Some(tag) = tag {
+                response.insert_header((ETAG, tag));
+            }
+            response.insert_header((TOTAL_COUNT, total));
+            let response = match envelope {
+                _ if jsonapi::requested(req) => {
+                    let data = result.into_iter().map(|doc| jsonapi::resource(uri, doc)).collect();
+                    jsonapi::respond(&mut response, req, data, json!({ "total": total }))
+                }
+                true => {
+                    let history = has_history(config, uri);
+                    result.iter_mut().for_each(|doc| add_links(doc, uri, history));
+                    response.json(Envelope::new(req, result, total, started).paged(req, &page, total))
+                }
+                false => response.json(result),
+            };
+            annotate(response, uri, rows)
+        }
+        Err(StoreError::NotFound) => HttpResponse::NotFound().json(format!("No collection '{}'", uri)),
+        Err(StoreError::Invalid(e)) => HttpResponse::BadRequest().json(e),
         Err(e) => HttpResponse::InternalServerError().json(format!("Failed to query data: {}", e)),
     }
 }
 
+/// Header of a listing counting the documents that match it on all pages.
+pub const TOTAL_COUNT: &str = "X-Total-Count";
+
+/// The page of a listing asked for with `?limit=` and `?offset=`.
+#[derive(Debug, Clone, Copy)]
+pub struct Page {
+    pub limit: usize,
+    pub offset: usize,
+}
+
+impl Page {
+    /// The page in `params`: `limit` defaults to `listing.default_limit` and
+    /// is lowered to `listing.max_limit`, `offset` defaults to 0. Values that
+    /// are not whole numbers, and a `limit` of 0, get 400.
+    pub fn from_params(params: &[(String, String)], config: Option<&web::Data<ConfigHandle>>) -> Result<Self, HttpResponse> {
+        let listing = config.map(|config| config.get().listing.clone()).unwrap_or_default();
+        let number = |name: &str| -> Result<Option<usize>, HttpResponse> {
+            match params.iter().rev().find(|(key, _)| key == name) {
+                Some((_, value)) => value
+                    .parse()
+                    .map(Some)
+                    .map_err(|_| HttpResponse::BadRequest().json(format!("{} must be a whole number, not '{}'", name, value))),
+                None => Ok(None),
+            }
+        };
+        let limit = match number("limit")? {
+            Some(0) => return Err(HttpResponse::BadRequest().json("limit must be at least 1")),
+            Some(limit) => limit.min(listing.max_limit),
+            None => listing.default_limit,
+        };
+        Ok(Page { limit, offset: number("offset")?.unwrap_or(0) })
+    }
+
+    /// The documents of this page among `docs`.
+    pub fn apply(&self, docs: Vec<Value>) -> Vec<Value> {
+        docs.into_iter().skip(self.offset).take(self.limit).collect()
+    }
+
+}
+
+/// Query parameters of `GET /{uri}` that never filter.
+pub const RESERVED_PARAMS: &[&str] = &["limit", "offset", "sort", "order", "fields", "envelope", "case"];
+
+/// The fields to sort by, and whether descending, from `?sort=` and
+/// `?order=`: `sort` lists fields separated by commas, and a field with a
+/// leading `-` sorts descending; `order=desc` makes the others descending too.
+pub fn sort_order(params: &[(String, String)]) -> Result<Vec<(String, bool)>, String> {
+    let last = |name: &str| params.iter().rev().find(|(key, _)| key == name).map(|(_, value)| value.as_str());
+    let descending = match last("order") {
+        None | Some("asc") => false,
+        Some("desc") => true,
+        Some(other) => return Err(format!("order must be asc or desc, not '{}'", other)),
+    };
+    let Some(sort) = last("sort") else {
+        return Ok(Vec::new());
+    };
+    sort.split(',')
+        .map(|item| {
+            let item = item.trim();
+            let (field, descending) = match item.strip_prefix('-') {
+                Some(field) => (field, true),
+                None => (item, descending),
+            };
+            match odata::identifier(field) {
+                true => Ok((field.to_string(), descending)),
+                false => Err(format!("Cannot sort by '{}'", item)),
+            }
+        })
+        .collect()
+}
+
+/// The fields asked for with `?fields=`, separated by commas, or `None`
+/// for whole documents.
+pub fn projection(params: &[(String, String)]) -> Result<Option<Vec<String>>, HttpResponse> {
+    let Some((_, value)) = params.iter().rev().find(|(key, _)| key == "fields") else {
+        return Ok(None);
+    };
+    value
+        .split(',')
+        .map(str::trim)
+        .map(|field| match odata::identifier(field) {
+            true => Ok(field.to_string()),
+            false => Err(HttpResponse::BadRequest().json(format!("Invalid field name '{}' in fields", field))),
+        })
+        .collect::<Result<_, _>>()
+        .map(Some)
+}
+
+/// The filter document for the query parameters of `GET /{uri}`: every
+/// parameter but the [`RESERVED_PARAMS`] and the OData options starting with
+/// `$` asks for documents whose field of that name equals the value. A repeated parameter matches any of its
+/// values, and a value that reads as a number or boolean also matches it as
+/// one, so `?age=30` finds both `30` and `"30"`.
+pub fn params_filter(params: &[(String, String)]) -> Value {
+    let mut fields: BTreeMap<String, Vec<Value>> = BTreeMap::new();
+    for (field, value) in params.iter().filter(|(field, _)| !field.starts_with('$') && !RESERVED_PARAMS.contains(&field.as_str())) {
+        let values = fields.entry(field.clone()).or_default();
+        values.push(Value::String(value.clone()));
+        if let Ok(typed @ (Value::Number(_) | Value::Bool(_))) = serde_json::from_str(value) {
+            values.push(typed);
+        }
+    }
+    let filter = fields.into_iter().map(|(field, values)| (field, json!({ "$in": values })));
+    Value::Object(filter.collect())
+}
+
+/// Metadata of an enveloped response.
+#[derive(Debug, Serialize)]
+pub struct Meta {
+    /// Documents matching the request.
+    pub total: usize,
+    /// The page returned, counting from 1, of `limit` documents each.
+    pub page: usize,
+    /// Time spent answering, in milliseconds.
+    pub took_ms: f64,
+}
+
+/// A response body wrapped by `?envelope=true`.
+#[derive(Debug, Serialize)]
+pub struct Envelope<T> {
+    pub data: T,
+    pub meta: Meta,
+    pub links: BTreeMap<&'static str, String>,
+}
+
+impl<T> Envelope<T> {
+    /// Wraps `data`, the whole answer to `req` of `total` documents.
+    pub fn new(req: &HttpRequest, data: T, total: usize, started: Instant) -> Self {
+        let links = BTreeMap::from([("self", req.uri().to_string())]);
+        let meta = Meta { total, page: 1, took_ms: started.elapsed().as_secs_f64() * 1000.0 };
+        Envelope { data, meta, links }
+    }
+
+    /// Sets the page number and links the pages before and after `page` of
+    /// the `total` documents, if there are any.
+    pub fn paged(mut self, req: &HttpRequest, page: &Page, total: usize) -> Self {
+        self.meta.page = page.offset / page.limit + 1;
+        if page.offset > 0 {
+            self.links.insert("prev", page_link(req, page.offset.saturating_sub(page.limit)));
+        }
+        let next = page.offset.saturating_add(page.limit);
+        if next < total {
+            self.links.insert("next", page_link(req, next));
+        }
+        self
+    }
+}
+
+// 当前请求换成另一个 offset 的地址
+fn page_link(req: &HttpRequest, offset: usize) -> String {
+    let mut query: Vec<&str> = req
+        .query_string()
+        .split('&')
+        .filter(|pair| !pair.is_empty() && pair.split('=').next() != Some("offset"))
+        .collect();
+    let offset = format!("offset={}", offset);
+    query.push(&offset);
+    format!("{}?{}", req.path(), query.join("&"))
+}
+
+/// Field of an enveloped document holding the paths related to it.
+pub const LINKS_FIELD: &str = "_links";
+
+/// Adds to `doc` of collection `uri` its [`LINKS_FIELD`]: the document
+/// itself, its collection and, when `history` is kept, its versions.
+pub fn add_links(doc: &mut Value, uri: &str, history: bool) {
+    let Some(id) = doc.get("id").and_then(Value::as_i64) else { return };
+    let Some(fields) = doc.as_object_mut() else { return };
+    let mut links = json!({
+        "self": format!("/{}/{}", uri, id),
+        "collection": format!("/{}", uri),
+    });
+    if history {
+        links["history"] = json!(format!("/{}/{}/_history", uri, id));
+    }
+    fields.insert(LINKS_FIELD.to_string(), links);
+}
+
+/// The key casing asked for with `?case=`, or else the collection's; other
+/// values of `case` get 400.
+fn key_case(
+    params: &[(String, String)],
+    config: Option<&web::Data<ConfigHandle>>,
+    uri: &str,
+) -> Result<Option<KeyCase>, HttpResponse> {
+    match params.iter().rev().find(|(name, _)| name == "case") {
+        Some((_, value)) => serde_json::from_value(Value::String(value.clone()))
+            .map(Some)
+            .map_err(|_| HttpResponse::BadRequest().json(format!("case must be camel or snake, not '{}'", value))),
+        None => Ok(config.and_then(|config| config.get().collections.get(uri).and_then(|c| c.case))),
+    }
+}
+
+fn has_history(config: Option<&web::Data<ConfigHandle>>, uri: &str) -> bool {
+    config.is_some_and(|config| config.get().collections.get(uri).is_some_and(|c| c.history.is_some()))
+}
+
+/// Whether the query parameters ask for an [`Envelope`]; a value of
+/// `envelope` other than `true` or `false` gets 400.
+fn wants_envelope(params: &[(String, String)]) -> Result<bool, HttpResponse> {
+    let mut envelope = false;
+    for (_, value) in params.iter().filter(|(name, _)| name == "envelope") {
+        envelope = value
+            .parse()
+            .map_err(|_| HttpResponse::BadRequest().json(format!("envelope must be true or false, not '{}'", value)))?;
+    }
+    Ok(envelope)
+}
+
 // 查询特定 JSON 数据
 pub async fn get_json_by_id(
-    path: web::Path<(String, i32)>,
-    pool: web::Data<SqlitePool>,
+    req: HttpRequest,
+    path: web::Path<(String, i64)>,
+    params: web::Query<Vec<(String, String)>>,
+    store: web::Data<dyn DocumentStore>,
+    config: Option<web::Data<ConfigHandle>>,
 ) -> HttpResponse {
+    let started = Instant::now();
     let (uri, id) = path.into_inner();
-    let table_name = uri.replace("/", "_");
-
-    let row = sqlx::query(&format!("SELECT * FROM {} WHERE id = $1", table_name))
-        .bind(id)
-        .fetch_one(&**pool)
-        .await;
-
-    match row {
-        Ok(row) => {
-            let mut map = serde_json::Map::new();
-            for i in 0..row.len() {
-                let value: Value = row.try_get(i).unwrap();
-                map.insert(i.to_string(), value);
+    let envelope = match wants_envelope(&params) {
+        Ok(envelope) => envelope,
+        Err(response) => return response,
+    };
+    let case = match key_case(&params, config.as_ref(), &uri) {
+        Ok(case) => case,
+        Err(response) => return response,
+    };
+    let fields = match projection(&params) {
+        Ok(fields) => fields,
+        Err(response) => return response,
+    };
+
+    // 与列表相同, 要返回的字段由存储按表的列检查并只读出它们; 另外读出版本作为 ETag
+    let found = match &fields {
+        None => store.get(&uri, id).await,
+        Some(fields) => {
+            let mut read = fields.clone();
+            if !read.iter().any(|field| field == VERSION_FIELD) {
+                read.push(VERSION_FIELD.to_string());
             }
-            HttpResponse::Ok().json(Value::Object(map))
+            let page = PageQuery { fields: Some(read), limit: Some(1), ..PageQuery::default() };
+            store.find_page(&uri, &json!({ "id": id }), &page).await.map(|paged| paged.docs.into_iter().next())
         }
+    };
+    let projection = PageQuery { fields, ..PageQuery::default() };
+    match found {
+        Ok(Some(doc)) => {
+            let mut response = HttpResponse::Ok();
+            if let Some(version) = doc.get(VERSION_FIELD).and_then(Value::as_i64) {
+                response.insert_header((ETAG, etag(version)));
+            }
+            let mut doc = projection.project(doc);
+            if let Some(case) = case {
+                doc = casing::convert(doc, case);
+            }
+            let response = match envelope {
+                _ if jsonapi::requested(&req) => jsonapi::respond(&mut response, &req, jsonapi::resource(&uri, doc), Value::Null),
+                true => {
+                    add_links(&mut doc, &uri, has_history(config.as_ref(), &uri));
+                    response.json(Envelope::new(&req, doc, 1, started))
+                }
+                false => response.json(doc),
+            };
+            annotate(response, &uri, 1)
+        }
+        Ok(None) => HttpResponse::NotFound().json(format!("No document with id {}", id)),
+        Err(StoreError::NotFound) => HttpResponse::NotFound().json(format!("No collection '{}'", uri)),
+        Err(StoreError::Invalid(e)) => HttpResponse::BadRequest().json(e),
         Err(e) => HttpResponse::InternalServerError().json(format!("Failed to query data: {}", e)),
     }
-}
\ No newline at end of file
+}
+
+/// Most ids a single `_mget` may ask for.
+pub const MAX_MGET_IDS: usize = 1000;
+
+#[derive(Debug, Deserialize)]
+pub struct MgetRequest {
+    pub ids: Vec<i64>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct MgetResponse {
+    /// Found documents, in the order they were asked for.
+    pub documents: Vec<Value>,
+    /// Requested ids with no document.
+    pub missing: Vec<i64>,
+}
+
+// 一次取回多个文档, 并列出不存在的 id
+pub async fn get_many_json(
+    uri: web::Path<String>,
+    body: web::Json<MgetRequest>,
+    store: web::Data<dyn DocumentStore>,
+) -> HttpResponse {
+    let ids = body.into_inner().ids;
+    if ids.len() > MAX_MGET_IDS {
+        return HttpResponse::BadRequest().json(format!("At most {} ids per request", MAX_MGET_IDS));
+    }
+
+    match fetch_many(store.get_ref(), &uri, ids).await {
+        Ok(result) => {
+            let rows = result.documents.len();
+            annotate(HttpResponse::Ok().json(result), &uri, rows)
+        }
+        Err(StoreError::NotFound) => HttpResponse::NotFound().json(format!("No collection '{}'", uri)),
+        Err(e) => HttpResponse::InternalServerError().json(format!("Failed to query data: {}", e)),
+    }
+}
+
+// 从多个集合取回文档, 按集合分组返回; 不存在的集合其 id 全部算作缺失
+pub async fn get_many_collections(
+    body: web::Json<BTreeMap<String, Vec<i64>>>,
+    store: web::Data<dyn DocumentStore>,
+) -> HttpResponse {
+    let requested = body.into_inner();
+    if requested.values().map(Vec::len).sum::<usize>() > MAX_MGET_IDS {
+        return HttpResponse::BadRequest().json(format!("At most {} ids per request", MAX_MGET_IDS));
+    }
+
+    let mut results = BTreeMap::new();
+    for (uri, ids) in requested {
+        let result = match fetch_many(store.get_ref(), &uri, ids.clone()).await {
+            Ok(result) => result,
+            Err(StoreError::NotFound) => MgetResponse { documents: Vec::new(), missing: ids },
+            Err(e) => {
+                return HttpResponse::InternalServerError().json(format!("Failed to query '{}': {}", uri, e));
+            }
+        };
+        results.insert(uri, result);
+    }
+    HttpResponse::Ok().json(results)
+}
+
+pub(crate) async fn fetch_many(store: &dyn DocumentStore, uri: &str, mut ids: Vec<i64>) -> Result<MgetResponse, StoreError> {
+    // 重复的 id 只返回一次
+    let mut seen = HashSet::new();
+    ids.retain(|id| seen.insert(*id));
+
+    let documents = store.get_many(uri, &ids).await?;
+    let found: HashSet<i64> = documents.iter().filter_map(|doc| doc.get("id").and_then(Value::as_i64)).collect();
+    let missing = ids.into_iter().filter(|id| !found.contains(id)).collect();
+    Ok(MgetResponse { documents, missing })
+}
+
+#[derive(Debug, Deserialize)]
+pub struct UpdateManyRequest {
+    /// Filter document; `{}` matches every document.
+    pub filter: Value,
+    pub update: Value,
+}
+
+#[derive(Debug, Serialize)]
+pub struct UpdateManyResponse {
+    pub matched: u64,
+    pub modified: usize,
+}
+
+// 按过滤条件在一个事务中修改多个文档
+pub async fn update_many_json(
+    uri: web::Path<String>,
+    body: web::Json<UpdateManyRequest>,
+    store: web::Data<dyn DocumentStore>,
+) -> HttpResponse {
+    let UpdateManyRequest { filter, update } = body.into_inner();
+    match store.update_many(&uri, &filter, &update).await {
+        Ok(updated) => {
+            let modified = updated.modified.len();
+            annotate(HttpResponse::Ok().json(UpdateManyResponse { matched: updated.matched, modified }), &uri, modified)
+        }
+        Err(StoreError::NotFound) => HttpResponse::NotFound().json(format!("No collection '{}'", uri)),
+        Err(StoreError::Invalid(e)) => HttpResponse::BadRequest().json(e),
+        Err(StoreError::Unavailable(e)) => HttpResponse::ServiceUnavailable().json(format!("Failed to update documents: {}", e)),
+        Err(e) => HttpResponse::InternalServerError().json(format!("Failed to update documents: {}", e)),
+    }
+}
+
+/// Most documents a single `_upsert` may carry.
+pub const MAX_UPSERT_DOCUMENTS: usize = 1000;
+
+#[derive(Debug, Deserialize)]
+pub struct UpsertParams {
+    /// Field identifying documents, kept unique in the collection.
+    pub key: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct UpsertResponse {
+    pub inserted: usize,
+    pub updated: usize,
+    pub unchanged: usize,
+    /// The outcome of each document, in the order sent.
+    pub documents: Vec<Upserted>,
+}
+
+// 按 key 字段批量写入: 已有相同 key 的文档写入新的字段值, 其余插入
+pub async fn upsert_json(
+    uri: web::Path<String>,
+    params: web::Query<UpsertParams>,
+    docs: web::Json<Vec<Value>>,
+    store: web::Data<dyn DocumentStore>,
+) -> HttpResponse {
+    let key = params.key.as_str();
+    if !odata::identifier(key) || key == "id" || key == VERSION_FIELD {
+        return HttpResponse::BadRequest().json(format!("'{}' cannot key upserts", key));
+    }
+    if docs.len() > MAX_UPSERT_DOCUMENTS {
+        return HttpResponse::BadRequest().json(format!("At most {} documents per request", MAX_UPSERT_DOCUMENTS));
+    }
+    if let Some(i) = docs.iter().position(|doc| !doc.is_object() || doc.get(key).is_none_or(Value::is_null)) {
+        return HttpResponse::BadRequest().json(format!("Document {} is not an object with a '{}'", i, key));
+    }
+
+    let mut documents = Vec::new();
+    for (i, doc) in docs.iter().enumerate() {
+        // 之前的文档已经写入, 重新发送整批是安全的
+        let message = |e| format!("Failed to upsert document {}: {}", i, e);
+        match store.upsert(&uri, key, doc).await {
+            Ok(upserted) => documents.push(upserted),
+            Err(StoreError::Invalid(e)) => return HttpResponse::BadRequest().json(message(e)),
+            Err(StoreError::Unavailable(e)) => return HttpResponse::ServiceUnavailable().json(message(e)),
+            Err(e) => return HttpResponse::InternalServerError().json(message(e.to_string())),
+        }
+    }
+    let inserted = documents.iter().filter(|d| !d.existed).count();
+    let updated = documents.iter().filter(|d| d.existed && d.changed).count();
+    let unchanged = documents.len() - inserted - updated;
+    let response = UpsertResponse { inserted, updated, unchanged, documents };
+    annotate(HttpResponse::Ok().json(response), &uri, inserted + updated)
+}
+
+#[derive(Debug, Deserialize)]
+pub struct DeleteManyRequest {
+    /// Filter document; `{}` matches every document.
+    pub filter: Value,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct DeleteManyParams {
+    /// Present without a value, `true` or `1` to only count the documents.
+    pub dry_run: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct DeleteManyResponse {
+    /// Documents deleted, or that would be with `dry_run`.
+    pub deleted: usize,
+    pub dry_run: bool,
+}
+
+// 按过滤条件在一个事务中删除多个文档; dry_run 只返回会删除的数量
+pub async fn delete_many_json(
+    uri: web::Path<String>,
+    body: web::Json<DeleteManyRequest>,
+    params: web::Query<DeleteManyParams>,
+    store: web::Data<dyn DocumentStore>,
+) -> HttpResponse {
+    let dry_run = matches!(params.dry_run.as_deref(), Some("" | "true" | "1"));
+    match store.delete_many(&uri, &body.filter, dry_run).await {
+        Ok(ids) => {
+            let deleted = ids.len();
+            annotate(HttpResponse::Ok().json(DeleteManyResponse { deleted, dry_run }), &uri, deleted)
+        }
+        Err(StoreError::NotFound) => HttpResponse::NotFound().json(format!("No collection '{}'", uri)),
+        Err(StoreError::Invalid(e)) => HttpResponse::BadRequest().json(e),
+        Err(StoreError::Unavailable(e)) => HttpResponse::ServiceUnavailable().json(format!("Failed to delete documents: {}", e)),
+        Err(e) => HttpResponse::InternalServerError().json(format!("Failed to delete documents: {}", e)),
+    }
+}
+
+// 整体替换文档, 支持 If-Match
+pub async fn replace_json(
+    req: HttpRequest,
+    path: web::Path<(String, i64)>,
+    doc: web::Json<Value>,
+    store: web::Data<dyn DocumentStore>,
+) -> HttpResponse {
+    let (uri, id) = path.into_inner();
+    if !doc.is_object() {
+        return HttpResponse::BadRequest().json("Document must be a JSON object");
+    }
+    let expected = if_match(&req);
+    let result = store.replace(&uri, id, &doc, expected.as_deref()).await;
+    written(&req, store.get_ref(), &uri, id, result).await
+}
+
+// 按 JSON Merge Patch 修改文档中的部分字段, 支持 If-Match
+pub async fn update_json(
+    req: HttpRequest,
+    path: web::Path<(String, i64)>,
+    doc: web::Json<Value>,
+    store: web::Data<dyn DocumentStore>,
+) -> HttpResponse {
+    let (uri, id) = path.into_inner();
+    let expected = if_match(&req);
+    if is_json_patch(&req) {
+        return json_patch(&req, store.get_ref(), &uri, id, &doc, expected.as_deref()).await;
+    }
+    if !doc.is_object() {
+        return HttpResponse::BadRequest().json("Document must be a JSON object");
+    }
+    let result = patch::merge_update(store.get_ref(), &uri, id, &doc, expected.as_deref()).await;
+    written(&req, store.get_ref(), &uri, id, result).await
+}
+
+fn is_json_patch(req: &HttpRequest) -> bool {
+    req.headers()
+        .get(CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.split(';').next().unwrap_or_default().trim().eq_ignore_ascii_case("application/json-patch+json"))
+}
+
+// 按 JSON Patch 修改文档; 任何一个操作失败都不写入, test 不满足时返回 409
+async fn json_patch(
+    req: &HttpRequest,
+    store: &dyn DocumentStore,
+    uri: &str,
+    id: i64,
+    operations: &Value,
+    expected: Option<&[i64]>,
+) -> HttpResponse {
+    let result = match patch::parse_json_patch(operations) {
+        Ok(operations) => patch::patch_update(store, uri, id, &operations, expected).await,
+        Err(e) => Err(e),
+    };
+    match result {
+        Ok(version) => written(req, store, uri, id, Ok(version)).await,
+        Err(PatchError::Invalid(e)) => HttpResponse::BadRequest().json(e),
+        Err(PatchError::Failed(e)) => HttpResponse::Conflict().json(e),
+        Err(PatchError::Store(e)) => write_error(req, e, id),
+    }
+}
+
+// 删除文档, 支持 If-Match; 返回删除的行数
+pub async fn delete_json(
+    req: HttpRequest,
+    path: web::Path<(String, i64)>,
+    store: web::Data<dyn DocumentStore>,
+) -> HttpResponse {
+    let (uri, id) = path.into_inner();
+    let expected = if_match(&req);
+    match store.delete(&uri, id, expected.as_deref()).await {
+        Ok(()) => annotate(HttpResponse::Ok().json(json!({ "deleted": 1 })), &uri, 1),
+        Err(e) => write_error(&req, e, id),
+    }
+}
+
+// 写入成功后返回新的文档和 ETag
+async fn written(
+    req: &HttpRequest,
+    store: &dyn DocumentStore,
+    uri: &str,
+    id: i64,
+    result: Result<i64, StoreError>,
+) -> HttpResponse {
+    let version = match result {
+        Ok(version) => version,
+        Err(e) => return write_error(req, e, id),
+    };
+    match store.get(uri, id).await {
+        Ok(Some(doc)) => annotate(HttpResponse::Ok().insert_header((ETAG, etag(version))).json(doc), uri, 1),
+        Ok(None) => HttpResponse::NotFound().json(format!("No document with id {}", id)),
+        Err(e) => HttpResponse::InternalServerError().json(format!("Failed to query data: {}", e)),
+    }
+}
+
+// 带 If-Match 的请求, 文档不存在时也返回 412
+fn write_error(req: &HttpRequest, e: StoreError, id: i64) -> HttpResponse {
+    match e {
+        StoreError::NotFound if req.headers().contains_key(IF_MATCH) => {
+            HttpResponse::PreconditionFailed().json(format!("No document with id {}", id))
+        }
+        StoreError::NotFound => HttpResponse::NotFound().json(format!("No document with id {}", id)),
+        StoreError::VersionConflict { current } => HttpResponse::PreconditionFailed()
+            .insert_header((ETAG, etag(current)))
+            .json(format!("Document {} is at version {}", id, current)),
+        StoreError::Unavailable(e) => HttpResponse::ServiceUnavailable().json(format!("Failed to write data: {}", e)),
+        StoreError::Invalid(message) => HttpResponse::BadRequest().json(message),
+        StoreError::Database(e) if unique_violation(&e) => HttpResponse::Conflict().json(format!("Failed to write data: {}", e)),
+        e => HttpResponse::InternalServerError().json(format!("Failed to write data: {}", e)),
+    }
+}
+
+/// Whether `e` is a write repeating the value of a key kept unique for
+/// [`DocumentStore::upsert`].
+fn unique_violation(e: &sqlx::Error) -> bool {
+    e.as_database_error().is_some_and(|e| e.is_unique_violation())
+}
+
+#[cfg(all(test, feature = "sqlite"))]
+mod tests {
+    use super::*;
+    use crate::testing::{Call, MockStore};
+    use actix_web::http::StatusCode;
+    use actix_web::{test, App};
+    use std::sync::Arc;
+
+    async fn send(store: &Arc<MockStore>, req: test::TestRequest) -> (StatusCode, Option<String>, Value) {
+        let app = test::init_service(App::new().app_data(store.data()).configure(configure)).await;
+        let response = test::call_service(&app, req.to_request()).await;
+        let status = response.status();
+        let tag = response.headers().get(ETAG).map(|v| v.to_str().unwrap().to_string());
+        (status, tag, test::read_body_json(response).await)
+    }
+
+    #[actix_web::test]
+    async fn get_returns_the_document_with_its_etag() {
+        let store = MockStore::new();
+        store.on_get(Ok(Some(json!({ "id": 7, "name": "a", "_version": 3 }))));
+        let (status, tag, body) = send(&store, test::TestRequest::get().uri("/api/v1/users/7")).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(tag.as_deref(), Some("\"3\""));
+        assert_eq!(body["name"], "a");
+        assert_eq!(store.calls(), vec![Call::Get { uri: "api/v1/users".to_string(), id: 7 }]);
+    }
+
+    #[actix_web::test]
+    async fn get_of_a_missing_document_or_collection_is_404() {
+        let store = MockStore::new();
+        store.on_get(Ok(None)).on_get(Err(StoreError::NotFound));
+        let (status, _, body) = send(&store, test::TestRequest::get().uri("/users/7")).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        assert_eq!(body, "No document with id 7");
+        let (status, _, body) = send(&store, test::TestRequest::get().uri("/users/7")).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        assert_eq!(body, "No collection 'users'");
+    }
+
+    #[actix_web::test]
+    async fn get_with_fields_reads_only_those_columns() {
+        let store = MockStore::new();
+        store
+            .on_find_page(Ok(Paged { docs: vec![json!({ "id": 7, "name": "a", "_version": 3 })], total: 1 }))
+            .on_find_page(Err(StoreError::Invalid("No field 'age' to return".to_string())))
+            .on_find_page(Ok(Paged::default()));
+        let (status, tag, body) = send(&store, test::TestRequest::get().uri("/users/7?fields=name")).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(tag.as_deref(), Some("\"3\""));
+        assert_eq!(body, json!({ "id": 7, "name": "a" }));
+        let page = PageQuery { fields: Some(vec!["name".to_string(), VERSION_FIELD.to_string()]), limit: Some(1), ..PageQuery::default() };
+        assert_eq!(store.calls(), vec![Call::FindPage { uri: "users".to_string(), filter: json!({ "id": 7 }), page }]);
+
+        let (status, _, body) = send(&store, test::TestRequest::get().uri("/users/7?fields=age")).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(body, "No field 'age' to return");
+        let (status, _, body) = send(&store, test::TestRequest::get().uri("/users/8?fields=name")).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        assert_eq!(body, "No document with id 8");
+    }
+
+    #[actix_web::test]
+    async fn get_checks_fields_against_the_collection_not_the_document() {
+        let test_store = crate::testing::TestStore::new().await;
+        test_store.load_fixtures("users", &[json!({ "name": "a" }), json!({ "name": "b", "age": 30 })]).await.unwrap();
+        let app = test::init_service(App::new().app_data(test_store.data()).configure(configure)).await;
+        // 第一个文档没有写过 age, 但集合有这一列
+        let response = test::call_service(&app, test::TestRequest::get().uri("/users/1?fields=age,name").to_request()).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(response.headers().contains_key(ETAG));
+        assert_eq!(test::read_body_json::<Value, _>(response).await, json!({ "id": 1, "name": "a", "age": null }));
+        let response = test::call_service(&app, test::TestRequest::get().uri("/users/2?fields=age,_version").to_request()).await;
+        assert_eq!(test::read_body_json::<Value, _>(response).await, json!({ "id": 2, "age": 30, "_version": 1 }));
+        let response = test::call_service(&app, test::TestRequest::get().uri("/users/2?fields=email").to_request()).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(test::read_body_json::<Value, _>(response).await, "No field 'email' to return");
+    }
+
+    #[actix_web::test]
+    async fn insert_stores_the_document_and_points_at_it() {
+        let store = MockStore::new();
+        let req = test::TestRequest::post().uri("/users").set_json(json!({ "uri": "users", "data": { "name": "a" } }));
+        let app = test::init_service(App::new().app_data(store.data()).configure(configure)).await;
+        let response = test::call_service(&app, req.to_request()).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers().get(LOCATION).unwrap(), "/users/1");
+        assert_eq!(store.calls(), vec![Call::Insert { uri: "users".to_string(), doc: json!({ "name": "a" }) }]);
+    }
+
+    #[actix_web::test]
+    async fn insert_rejects_bad_documents_before_the_store() {
+        let store = MockStore::new();
+        let (status, _, _) = send(&store, test::TestRequest::post().uri("/users").set_json(json!({ "uri": "users", "data": [1] }))).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        let body = json!({ "uri": "users", "data": { "_id": "x" } });
+        let (status, _, _) = send(&store, test::TestRequest::post().uri("/users").set_json(body)).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert!(store.calls().is_empty());
+    }
+
+    #[actix_web::test]
+    async fn insert_maps_store_errors() {
+        let store = MockStore::new();
+        store
+            .on_insert(Err(StoreError::Invalid("Field 'a' is not a number".to_string())))
+            .on_insert(Err(StoreError::Unavailable("leader unreachable".to_string())));
+        let body = json!({ "uri": "users", "data": { "a": "x" } });
+        let (status, _, message) = send(&store, test::TestRequest::post().uri("/users").set_json(&body)).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(message, "Field 'a' is not a number");
+        let (status, _, _) = send(&store, test::TestRequest::post().uri("/users").set_json(&body)).await;
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+    }
+
+    #[actix_web::test]
+    async fn insert_with_a_client_id_creates_the_missing_document() {
+        let store = MockStore::new();
+        store.on_replace(Err(StoreError::NotFound));
+        let body = json!({ "uri": "users", "data": { "_id": 5, "name": "a" } });
+        let (status, _, _) = send(&store, test::TestRequest::post().uri("/users").set_json(body)).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(
+            store.calls(),
+            vec![
+                Call::Replace { uri: "users".to_string(), id: 5, doc: json!({ "name": "a" }), expected: None },
+                Call::Insert { uri: "users".to_string(), doc: json!({ "name": "a", "id": 5 }) },
+            ]
+        );
+    }
+
+    #[actix_web::test]
+    async fn replace_passes_if_match_and_maps_conflicts_to_412() {
+        let store = MockStore::new();
+        store.on_replace(Err(StoreError::VersionConflict { current: 4 }));
+        let req = test::TestRequest::put().uri("/users/7").insert_header((IF_MATCH, "\"2\", 3")).set_json(json!({ "name": "b" }));
+        let (status, tag, _) = send(&store, req).await;
+        assert_eq!(status, StatusCode::PRECONDITION_FAILED);
+        assert_eq!(tag.as_deref(), Some("\"4\""));
+        assert_eq!(
+            store.calls(),
+            vec![Call::Replace { uri: "users".to_string(), id: 7, doc: json!({ "name": "b" }), expected: Some(vec![2, 3]) }]
+        );
+    }
+
+    #[actix_web::test]
+    async fn replace_answers_with_the_written_document() {
+        let store = MockStore::new();
+        store.on_replace(Ok(5)).on_get(Ok(Some(json!({ "id": 7, "name": "b", "_version": 5 }))));
+        let (status, tag, body) = send(&store, test::TestRequest::put().uri("/users/7").set_json(json!({ "name": "b" }))).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(tag.as_deref(), Some("\"5\""));
+        assert_eq!(body["name"], "b");
+    }
+
+    #[actix_web::test]
+    async fn delete_of_a_missing_document_is_404_or_412_with_if_match() {
+        let store = MockStore::new();
+        store.on_delete(Err(StoreError::NotFound)).on_delete(Err(StoreError::NotFound));
+        let (status, _, _) = send(&store, test::TestRequest::delete().uri("/users/7")).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        let (status, _, _) = send(&store, test::TestRequest::delete().uri("/users/7").insert_header((IF_MATCH, "*"))).await;
+        assert_eq!(status, StatusCode::PRECONDITION_FAILED);
+        assert_eq!(
+            store.calls(),
+            vec![
+                Call::Delete { uri: "users".to_string(), id: 7, expected: None },
+                Call::Delete { uri: "users".to_string(), id: 7, expected: None },
+            ]
+        );
+    }
+
+    #[actix_web::test]
+    async fn merge_patch_of_nested_objects_reads_before_it_writes() {
+        let store = MockStore::new();
+        store
+            .on_get(Ok(Some(json!({ "id": 7, "address": { "city": "a", "zip": "1" }, "_version": 2 }))))
+            .on_get(Ok(Some(json!({ "id": 7, "address": { "city": "b", "zip": "1" }, "_version": 3 }))));
+        let req = test::TestRequest::patch().uri("/users/7").set_json(json!({ "address": { "city": "b" } }));
+        let (status, _, body) = send(&store, req).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["address"]["city"], "b");
+        assert_eq!(
+            store.calls()[1],
+            Call::Update {
+                uri: "users".to_string(),
+                id: 7,
+                doc: json!({ "address": { "city": "b", "zip": "1" } }),
+                expected: Some(vec![2]),
+            }
+        );
+    }
+
+    #[actix_web::test]
+    async fn failed_json_patch_test_is_409_and_writes_nothing() {
+        let store = MockStore::new();
+        store.on_get(Ok(Some(json!({ "id": 7, "name": "a", "_version": 2 }))));
+        let req = test::TestRequest::patch()
+            .uri("/users/7")
+            .insert_header((CONTENT_TYPE, "application/json-patch+json"))
+            .set_payload(json!([{ "op": "test", "path": "/name", "value": "b" }]).to_string());
+        let (status, _, _) = send(&store, req).await;
+        assert_eq!(status, StatusCode::CONFLICT);
+        assert!(!store.calls().iter().any(|call| matches!(call, Call::Update { .. } | Call::Replace { .. })));
+    }
+
+    #[actix_web::test]
+    async fn bulk_writes_map_invalid_to_400_and_missing_collections_to_404() {
+        let store = MockStore::new();
+        store
+            .on_update_many(Err(StoreError::Invalid("No field 'age'".to_string())))
+            .on_delete_many(Err(StoreError::NotFound));
+        let body = json!({ "filter": { "age": 1 }, "update": { "name": "a" } });
+        let (status, _, message) = send(&store, test::TestRequest::post().uri("/users/_update_many").set_json(body)).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(message, "No field 'age'");
+        let req = test::TestRequest::post().uri("/users/_delete_many?dry_run").set_json(json!({ "filter": {} }));
+        let (status, _, message) = send(&store, req).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        assert_eq!(message, "No collection 'users'");
+        assert_eq!(store.calls()[1], Call::DeleteMany { uri: "users".to_string(), filter: json!({}), dry_run: true });
+    }
+
+    #[actix_web::test]
+    async fn upsert_checks_its_key_before_the_store() {
+        let store = MockStore::new();
+        let req = test::TestRequest::post().uri("/users/_upsert?key=id").set_json(json!([{ "id": 1 }]));
+        let (status, _, _) = send(&store, req).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        let req = test::TestRequest::post().uri("/users/_upsert?key=email").set_json(json!([{ "name": "a" }]));
+        let (status, _, message) = send(&store, req).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(message, "Document 0 is not an object with a 'email'");
+        assert!(store.calls().is_empty());
+    }
+
+    #[actix_web::test]
+    async fn mget_across_collections_counts_a_missing_collection_as_missing_ids() {
+        let store = MockStore::new();
+        store.on_get_many(Err(StoreError::NotFound)).on_get_many(Ok(vec![json!({ "id": 1 })]));
+        let req = test::TestRequest::post().uri("/_mget").set_json(json!({ "orders": [3, 3, 4], "users": [1, 2] }));
+        let (status, _, body) = send(&store, req).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["orders"]["missing"], json!([3, 3, 4]));
+        assert_eq!(body["users"], json!({ "documents": [{ "id": 1 }], "missing": [2] }));
+    }
+
+    #[actix_web::test]
+    async fn keywords_and_punctuation_in_names_are_quoted_into_sqlite() {
+        let test_store = crate::testing::TestStore::new().await;
+        let app = test::init_service(App::new().app_data(test_store.data()).configure(configure)).await;
+        let doc = json!({ "order": 1, "group": "a", "user-name": "x", "unit price": 2.5, "say \"hi\"": true, "select": null });
+        let req = test::TestRequest::post().uri("/order/group").set_json(json!({ "uri": "order/group", "data": doc }));
+        assert_eq!(test::call_service(&app, req.to_request()).await.status(), StatusCode::OK);
+        test_store.assert_columns("order/group", &["id", "_version", "order", "group", "user-name", "unit price", "say \"hi\"", "select"]).await;
+
+        let mut read: Value = test::call_and_read_body_json(&app, test::TestRequest::get().uri("/order/group/1").to_request()).await;
+        read.as_object_mut().unwrap().retain(|key, _| key != "id" && key != VERSION_FIELD);
+        assert_eq!(read, doc);
+        let req = test::TestRequest::post().uri("/order/group/_find").set_json(json!({ "filter": { "user-name": "x", "say \"hi\"": true } }));
+        let found: Value = test::call_and_read_body_json(&app, req.to_request()).await;
+        assert_eq!(found.as_array().map(Vec::len), Some(1));
+
+        for (data, message) in [
+            (json!({ "": 1 }), "Field names cannot be empty"),
+            (json!({ "City": 1, "city": 2 }), "Fields 'City' and 'city' differ only in case"),
+            (json!({ "ID": 1 }), "Field 'ID' differs from 'id' only in case"),
+            (json!({ "a\u{1}": 1 }), "Field name \"a\\u{1}\" holds a control character"),
+        ] {
+            let req = test::TestRequest::post().uri("/order/group").set_json(json!({ "uri": "order/group", "data": data }));
+            let response = test::call_service(&app, req.to_request()).await;
+            assert_eq!(response.status(), StatusCode::BAD_REQUEST, "{}", data);
+            assert_eq!(test::read_body_json::<Value, _>(response).await, message);
+        }
+        assert_eq!(test_store.row_count("order/group").await, 1);
+    }
+}
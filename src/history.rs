@@ -0,0 +1,429 @@
+//! Document history.
+//!
+//! A collection with `history` in its `collections` settings keeps every
+//! version of its documents in the `_history` table. Only the first version
+//! of a document is stored whole; each later one is stored as its change to
+//! the version before:
+//!
+//! ```text
+//! {"set": {"name": "Anne", "_version": 2}, "unset": ["nickname"]}
+//! ```
+//!
+//! Every `snapshot_every` changes a full copy is stored again, so rebuilding
+//! a version applies at most that many changes. Deleting a document records
+//! a `deleted` entry and keeps its history.
+//!
+//! Versions are read after the write, under one lock for all collections;
+//! when writes to the same document race, a version overwritten before it
+//! is read is skipped and the next entry holds both changes.
+//!
+//! | Endpoint                           | Purpose                               |
+//! |------------------------------------|---------------------------------------|
+//! | `GET /{uri}/{id}/_history`         | the recorded versions, oldest first   |
+//! | `GET /{uri}/{id}/_history/{version}` | the document as it was at `version` |
+
+use actix_web::{web, HttpResponse};
+use async_trait::async_trait;
+use serde::Serialize;
+use serde_json::{json, Map, Value};
+use sqlx::SqlitePool;
+use std::sync::Arc;
+use tokio::sync::Mutex;
+
+use crate::access_log::annotate;
+use crate::cdc::now_millis;
+use crate::config::{ConfigHandle, History};
+use crate::database::{bind_value, row_to_json};
+use crate::handlers::route;
+use crate::store::{DedupMode, DocumentStore, Inserted, PageQuery, Paged, StoreError, UpdatedMany, Upserted, Written, VERSION_FIELD};
+
+/// One recorded version, as listed by `GET /{uri}/{id}/_history`.
+#[derive(Debug, Clone, Serialize)]
+pub struct Entry {
+    pub version: i64,
+    /// `insert`, `replace`, `update` or `delete`.
+    pub op: String,
+    /// `full`, `delta` or `deleted`.
+    pub kind: String,
+    /// Milliseconds since the Unix epoch.
+    pub timestamp: i64,
+}
+
+/// The change turning `from` into `to`, field by field.
+pub fn diff(from: &Value, to: &Value) -> Value {
+    let empty = Map::new();
+    let from = from.as_object().unwrap_or(&empty);
+    let to = to.as_object().unwrap_or(&empty);
+    let set: Map<String, Value> = to.iter().filter(|(k, v)| from.get(*k) != Some(*v)).map(|(k, v)| (k.clone(), v.clone())).collect();
+    let unset: Vec<&String> = from.keys().filter(|k| !to.contains_key(*k)).collect();
+    json!({ "set": set, "unset": unset })
+}
+
+/// Applies a change made by [`diff`] to `doc`.
+pub fn apply(doc: &mut Value, delta: &Value) {
+    let Some(fields) = doc.as_object_mut() else { return };
+    for name in delta.get("unset").and_then(Value::as_array).into_iter().flatten() {
+        if let Some(name) = name.as_str() {
+            fields.remove(name);
+        }
+    }
+    for (name, value) in delta.get("set").and_then(Value::as_object).into_iter().flatten() {
+        fields.insert(name.clone(), value.clone());
+    }
+}
+
+/// The recorded versions of document `id` of `uri`, oldest first.
+pub async fn versions(pool: &SqlitePool, uri: &str, id: i64) -> Result<Vec<Entry>, sqlx::Error> {
+    let rows: Vec<(i64, String, String, i64)> = sqlx::query_as(
+        "SELECT version, op, kind, timestamp FROM _history WHERE collection = ? AND document_id = ? ORDER BY version",
+    )
+    .bind(uri)
+    .bind(id)
+    .fetch_all(pool)
+    .await?;
+    Ok(rows.into_iter().map(|(version, op, kind, timestamp)| Entry { version, op, kind, timestamp }).collect())
+}
+
+// 从最近的完整版本开始依次应用变化, 重建指定版本; 已删除或没有记录时返回 None
+pub async fn version(pool: &SqlitePool, uri: &str, id: i64, version: i64) -> Result<Option<Value>, sqlx::Error> {
+    let rows = sqlx::query(
+        r#"
+        SELECT kind, body FROM _history
+        WHERE collection = ? AND document_id = ? AND version <= ?
+          AND version >= (SELECT base FROM _history WHERE collection = ? AND document_id = ? AND version = ?)
+        ORDER BY version
+        "#,
+    )
+    .bind(uri)
+    .bind(id)
+    .bind(version)
+    .bind(uri)
+    .bind(id)
+    .bind(version)
+    .fetch_all(pool)
+    .await?;
+    let mut doc = None;
+    for row in rows {
+        let entry = row_to_json(&row);
+        match entry["kind"].as_str() {
+            Some("full") => doc = Some(entry["body"].clone()),
+            Some("delta") => {
+                if let Some(doc) = doc.as_mut() {
+                    apply(doc, &entry["body"]);
+                }
+            }
+            _ => doc = None,
+        }
+    }
+    Ok(doc)
+}
+
+/// A [`DocumentStore`] that passes every call on to `inner` and records the
+/// versions written to collections with history.
+pub struct HistoryStore {
+    inner: Arc<dyn DocumentStore>,
+    pool: SqlitePool,
+    config: Arc<ConfigHandle>,
+    lock: Mutex<()>,
+}
+
+impl HistoryStore {
+    pub fn new(inner: Arc<dyn DocumentStore>, pool: SqlitePool, config: Arc<ConfigHandle>) -> Self {
+        HistoryStore { inner, pool, config, lock: Mutex::new(()) }
+    }
+
+    fn settings(&self, uri: &str) -> Option<History> {
+        self.config.get().collections.get(uri).and_then(|c| c.history.clone())
+    }
+
+    // 历史写入失败只记录日志, 不影响已经成功的写入
+    async fn record(&self, uri: &str, id: i64, op: &str) {
+        let Some(settings) = self.settings(uri) else { return };
+        let _guard = self.lock.lock().await;
+        if let Err(e) = self.save(uri, id, op, &settings).await {
+            log::warn!("failed to record history of {}/{}: {}", uri, id, e);
+        }
+    }
+
+    async fn save(&self, uri: &str, id: i64, op: &str, settings: &History) -> Result<(), StoreError> {
+        let last: Option<(i64, String, i64)> = sqlx::query_as(
+            "SELECT version, kind, base FROM _history WHERE collection = ? AND document_id = ? ORDER BY version DESC LIMIT 1",
+        )
+        .bind(uri)
+        .bind(id)
+        .fetch_optional(&self.pool)
+        .await?;
+
+        let current = match op {
+            "delete" => None,
+            _ => self.inner.get(uri, id).await?,
+        };
+        let (version, kind, base, body) = match (current, &last) {
+            (None, Some((_, kind, _))) if kind == "deleted" => return Ok(()),
+            (None, None) => return Ok(()),
+            (None, Some((version, _, _))) => (version + 1, "deleted", version + 1, None),
+            (Some(doc), last) => {
+                let version = doc.get(VERSION_FIELD).and_then(Value::as_i64).unwrap_or_default();
+                match last {
+                    // 之前的写入已经记下了这个版本
+                    Some((recorded, _, _)) if *recorded >= version => return Ok(()),
+                    Some((recorded, kind, base)) if kind != "deleted" && self.changes_since(uri, id, *base).await? < settings.snapshot_every as i64 => {
+                        let previous = self::version(&self.pool, uri, id, *recorded).await?.unwrap_or_default();
+                        (version, "delta", *base, Some(diff(&previous, &doc)))
+                    }
+                    _ => (version, "full", version, Some(doc)),
+                }
+            }
+        };
+        let statement = sqlx::query(
+            "INSERT OR IGNORE INTO _history (collection, document_id, version, op, kind, base, body, timestamp) VALUES (?, ?, ?, ?, ?, ?, ?, ?)",
+        )
+        .bind(uri)
+        .bind(id)
+        .bind(version)
+        .bind(op)
+        .bind(kind)
+        .bind(base);
+        bind_value(statement, body.as_ref()).bind(now_millis() as i64).execute(&self.pool).await?;
+        Ok(())
+    }
+
+    async fn changes_since(&self, uri: &str, id: i64, base: i64) -> Result<i64, sqlx::Error> {
+        sqlx::query_scalar("SELECT COUNT(*) FROM _history WHERE collection = ? AND document_id = ? AND version > ?")
+            .bind(uri)
+            .bind(id)
+            .bind(base)
+            .fetch_one(&self.pool)
+            .await
+    }
+}
+
+#[async_trait]
+impl DocumentStore for HistoryStore {
+    async fn insert(&self, uri: &str, doc: &Value) -> Result<i64, StoreError> {
+        let id = self.inner.insert(uri, doc).await?;
+        self.record(uri, id, "insert").await;
+        Ok(id)
+    }
+
+    async fn list(&self, uri: &str) -> Result<Vec<Value>, StoreError> {
+        self.inner.list(uri).await
+    }
+
+    async fn get(&self, uri: &str, id: i64) -> Result<Option<Value>, StoreError> {
+        self.inner.get(uri, id).await
+    }
+
+    async fn get_many(&self, uri: &str, ids: &[i64]) -> Result<Vec<Value>, StoreError> {
+        self.inner.get_many(uri, ids).await
+    }
+
+    async fn insert_unique(&self, uri: &str, doc: &Value, hash: &str, mode: DedupMode) -> Result<Inserted, StoreError> {
+        let inserted = self.inner.insert_unique(uri, doc, hash, mode).await?;
+        match (inserted.duplicate, mode) {
+            (false, _) => self.record(uri, inserted.id, "insert").await,
+            (true, DedupMode::Upsert) => self.record(uri, inserted.id, "update").await,
+            (true, DedupMode::Skip) => {}
+        }
+        Ok(inserted)
+    }
+
+    async fn upsert(&self, uri: &str, key: &str, doc: &Value) -> Result<Upserted, StoreError> {
+        let upserted = self.inner.upsert(uri, key, doc).await?;
+        match (upserted.changed, upserted.existed) {
+            (false, _) => {}
+            (true, false) => self.record(uri, upserted.id, "insert").await,
+            (true, true) => self.record(uri, upserted.id, "update").await,
+        }
+        Ok(upserted)
+    }
+
+    async fn replace(&self, uri: &str, id: i64, doc: &Value, expected: Option<&[i64]>) -> Result<i64, StoreError> {
+        let version = self.inner.replace(uri, id, doc, expected).await?;
+        self.record(uri, id, "replace").await;
+        Ok(version)
+    }
+
+    async fn update(&self, uri: &str, id: i64, doc: &Value, expected: Option<&[i64]>) -> Result<i64, StoreError> {
+        let version = self.inner.update(uri, id, doc, expected).await?;
+        self.record(uri, id, "update").await;
+        Ok(version)
+    }
+
+    async fn delete(&self, uri: &str, id: i64, expected: Option<&[i64]>) -> Result<(), StoreError> {
+        self.inner.delete(uri, id, expected).await?;
+        self.record(uri, id, "delete").await;
+        Ok(())
+    }
+
+    async fn update_many(&self, uri: &str, filter: &Value, update: &Value) -> Result<UpdatedMany, StoreError> {
+        let updated = self.inner.update_many(uri, filter, update).await?;
+        for id in &updated.modified {
+            self.record(uri, *id, "update").await;
+        }
+        Ok(updated)
+    }
+
+    async fn delete_many(&self, uri: &str, filter: &Value, dry_run: bool) -> Result<Vec<i64>, StoreError> {
+        let deleted = self.inner.delete_many(uri, filter, dry_run).await?;
+        if !dry_run {
+            for id in &deleted {
+                self.record(uri, *id, "delete").await;
+            }
+        }
+        Ok(deleted)
+    }
+
+    async fn find(&self, uri: &str, filter: &Value) -> Result<Vec<Value>, StoreError> {
+        self.inner.find(uri, filter).await
+    }
+
+    async fn count(&self, uri: &str, filter: &Value) -> Result<u64, StoreError> {
+        self.inner.count(uri, filter).await
+    }
+
+    async fn find_page(&self, uri: &str, filter: &Value, page: &PageQuery) -> Result<Paged, StoreError> {
+        self.inner.find_page(uri, filter, page).await
+    }
+
+    async fn version(&self, uri: &str) -> Result<String, StoreError> {
+        self.inner.version(uri).await
+    }
+
+    async fn committed(&self, writes: &[Written]) {
+        self.inner.committed(writes).await;
+        for write in writes {
+            self.record(&write.uri, write.id, write.op.as_str()).await;
+        }
+    }
+}
+
+/// Wraps `store` in a [`HistoryStore`]. Whether a collection keeps history
+/// is read from the configuration on every write.
+pub fn wrap(config: Arc<ConfigHandle>, store: Arc<dyn DocumentStore>, pool: SqlitePool) -> Arc<dyn DocumentStore> {
+    Arc::new(HistoryStore::new(store, pool, config))
+}
+
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.route(&route("/{uri}/{id}/_history"), web::get().to(list_versions))
+        .route(&route("/{uri}/{id}/_history/{version}"), web::get().to(get_version));
+}
+
+// 列出文档记录下的所有版本
+async fn list_versions(path: web::Path<(String, i64)>, pool: web::Data<SqlitePool>) -> HttpResponse {
+    let (uri, id) = path.into_inner();
+    match versions(&pool, &uri, id).await {
+        Ok(entries) if entries.is_empty() => HttpResponse::NotFound().json(format!("No history for document {}", id)),
+        Ok(entries) => {
+            let rows = entries.len();
+            annotate(HttpResponse::Ok().json(entries), &uri, rows)
+        }
+        Err(e) => HttpResponse::InternalServerError().json(format!("Failed to query history: {}", e)),
+    }
+}
+
+// 重建文档的某个历史版本
+async fn get_version(path: web::Path<(String, i64, i64)>, pool: web::Data<SqlitePool>) -> HttpResponse {
+    let (uri, id, number) = path.into_inner();
+    match version(&pool, &uri, id, number).await {
+        Ok(Some(doc)) => annotate(HttpResponse::Ok().json(doc), &uri, 1),
+        Ok(None) => HttpResponse::NotFound().json(format!("No version {} of document {}", number, id)),
+        Err(e) => HttpResponse::InternalServerError().json(format!("Failed to query history: {}", e)),
+    }
+}
+
+#[cfg(all(test, feature = "sqlite"))]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use crate::testing::TestStore;
+
+    #[test]
+    fn diff_and_apply_round_trip_set_and_unset_fields() {
+        let cases = [
+            (json!({}), json!({ "a": 1 })),
+            (json!({ "a": 1, "b": "x" }), json!({ "a": 2, "b": "x" })),
+            (json!({ "a": 1, "b": "x", "c": [1] }), json!({ "a": 1 })),
+            (json!({ "a": 1 }), json!({ "a": null })),
+            (json!({ "a": null }), json!({})),
+            (json!({ "a": { "x": 1, "y": 2 } }), json!({ "a": { "x": 1 }, "d": [] })),
+            (json!({ "a": 1 }), json!({})),
+        ];
+        for (from, to) in cases {
+            let delta = diff(&from, &to);
+            let mut rebuilt = from.clone();
+            apply(&mut rebuilt, &delta);
+            assert_eq!(rebuilt, to, "{} -> {} by {}", from, to, delta);
+        }
+        let delta = diff(&json!({ "a": 1, "b": 2, "c": 3 }), &json!({ "a": 1, "b": null, "d": 4 }));
+        assert_eq!(delta, json!({ "set": { "b": null, "d": 4 }, "unset": ["c"] }));
+        assert_eq!(diff(&json!({ "a": 1 }), &json!({ "a": 1 })), json!({ "set": {}, "unset": [] }));
+        // 不是对象的文档没有字段可改
+        let mut scalar = json!(5);
+        apply(&mut scalar, &json!({ "set": { "a": 1 } }));
+        assert_eq!(scalar, json!(5));
+    }
+
+    #[tokio::test]
+    async fn every_stored_version_is_rebuilt_across_snapshots() {
+        let test = TestStore::new().await;
+        let config: Config = serde_json::from_value(json!({ "collections": { "notes": { "history": { "snapshot_every": 3 } } } })).unwrap();
+        let store = wrap(Arc::new(ConfigHandle::new(None, config)), test.data().into_inner(), test.pool().clone());
+        let pool = test.pool();
+
+        let id = store.insert("notes", &json!({ "title": "a", "tags": ["x"], "draft": true })).await.unwrap();
+        let mut stored = vec![store.get("notes", id).await.unwrap().unwrap()];
+        for n in 1..8 {
+            // 替换写入时没有的字段被去掉, 更新只改给出的字段
+            match n % 2 {
+                0 => store.replace("notes", id, &json!({ "title": format!("t{}", n), "count": n }), None).await.unwrap(),
+                _ => store.update("notes", id, &json!({ "count": n, "note": null, "tags": [n] }), None).await.unwrap(),
+            };
+            stored.push(store.get("notes", id).await.unwrap().unwrap());
+        }
+        let entries = versions(pool, "notes", id).await.unwrap();
+        assert_eq!(entries.iter().map(|e| e.version).collect::<Vec<_>>(), (1..=8).collect::<Vec<_>>());
+        let kinds: Vec<&str> = entries.iter().map(|e| e.kind.as_str()).collect();
+        assert_eq!(kinds, ["full", "delta", "delta", "delta", "full", "delta", "delta", "delta"]);
+        assert_eq!(entries[1].op, "update");
+        assert_eq!(entries[2].op, "replace");
+        for (n, doc) in stored.iter().enumerate() {
+            let v = n as i64 + 1;
+            assert_eq!(doc[VERSION_FIELD], json!(v));
+            assert_eq!(version(pool, "notes", id, v).await.unwrap().as_ref(), Some(doc), "version {}", v);
+        }
+        // 替换后表中还留着 draft 列, 读出为 null
+        assert_eq!((&stored[1]["draft"], &stored[2]["draft"]), (&json!(true), &Value::Null));
+
+        store.delete("notes", id, None).await.unwrap();
+        let entries = versions(pool, "notes", id).await.unwrap();
+        assert_eq!((entries[8].version, entries[8].op.as_str(), entries[8].kind.as_str()), (9, "delete", "deleted"));
+        assert_eq!(version(pool, "notes", id, 9).await.unwrap(), None);
+        assert_eq!(version(pool, "notes", id, 7).await.unwrap().as_ref(), Some(&stored[6]));
+        assert_eq!(version(pool, "notes", id, 10).await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn versions_are_rebuilt_from_the_nearest_full_copy() {
+        let test = TestStore::new().await;
+        let pool = test.pool();
+        let v1 = json!({ "id": 1, "_version": 1, "a": 1, "b": 2 });
+        let v2 = json!({ "id": 1, "_version": 2, "a": 1, "c": 3 });
+        let v3 = json!({ "id": 1, "_version": 3, "a": 5, "c": 3 });
+        let v4 = json!({ "id": 1, "_version": 4, "d": 4 });
+        let rows = [(1, "full", 1, v1.clone()), (2, "delta", 1, diff(&v1, &v2)), (3, "delta", 1, diff(&v2, &v3)), (4, "full", 4, v4.clone())];
+        for (version, kind, base, body) in rows {
+            let statement = sqlx::query(
+                "INSERT INTO _history (collection, document_id, version, op, kind, base, body, timestamp) VALUES ('notes', 1, ?, 'update', ?, ?, ?, 0)",
+            )
+            .bind(version)
+            .bind(kind)
+            .bind(base);
+            bind_value(statement, Some(&body)).execute(pool).await.unwrap();
+        }
+        for (number, expected) in [(1, &v1), (2, &v2), (3, &v3), (4, &v4)] {
+            assert_eq!(version(pool, "notes", 1, number).await.unwrap().as_ref(), Some(expected), "version {}", number);
+        }
+        assert_eq!(version(pool, "notes", 2, 1).await.unwrap(), None);
+    }
+}
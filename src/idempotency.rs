@@ -0,0 +1,225 @@
+//! Idempotency keys for POST requests.
+//!
+//! A POST carrying `Idempotency-Key` is recorded in `_idempotency_keys` together
+//! with the caller and a hash of the request, and once the handler is done,
+//! with its response and the id of the document it created. Retrying the key
+//! within `idempotency.ttl_secs` returns the stored response, marked with
+//! `Idempotent-Replayed: true`, without running the handler again.
+//!
+//! Keys are scoped to the principal. Reusing a key for a different request
+//! gets 422, and a retry while the first attempt is still running gets 409.
+//! Server errors are not stored, so those requests can be retried.
+
+use actix_web::body::{self, BoxBody, MessageBody};
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::http::header::CONTENT_TYPE;
+use actix_web::http::{Method, StatusCode};
+use actix_web::middleware::Next;
+use actix_web::{web, Error, HttpMessage, HttpResponse};
+use sha2::{Digest, Sha256};
+use sqlx::{Row, SqlitePool};
+
+use crate::auth::{buffer_body, Principal};
+use crate::config::ConfigHandle;
+use crate::handlers::{created, CreatedId};
+use crate::models::JsonData;
+use crate::logging::now;
+use crate::sessions::hex;
+
+pub const HEADER: &str = "Idempotency-Key";
+pub const REPLAYED_HEADER: &str = "Idempotent-Replayed";
+
+const MAX_KEY_LEN: usize = 255;
+
+struct StoredResponse {
+    status: u16,
+    content_type: Option<String>,
+    body: Vec<u8>,
+    document_id: Option<i64>,
+}
+
+enum Reservation {
+    /// First use of the key; the request goes ahead.
+    Reserved,
+    Replay(StoredResponse),
+    InProgress,
+    Mismatch,
+}
+
+/// Replays the stored response for a repeated `Idempotency-Key`.
+pub async fn enforce(
+    mut req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<BoxBody>, Error> {
+    let key = req.headers().get(HEADER).and_then(|v| v.to_str().ok()).map(str::to_string);
+    let pool = req.app_data::<web::Data<SqlitePool>>().cloned();
+    let config = req.app_data::<web::Data<ConfigHandle>>().map(|c| c.get());
+    let (Some(key), Some(pool), Some(config)) = (key.filter(|_| req.method() == Method::POST), pool, config) else {
+        return next.call(req).await.map(ServiceResponse::map_into_boxed_body);
+    };
+    if key.is_empty() || key.len() > MAX_KEY_LEN {
+        let response = HttpResponse::BadRequest().json(format!("{} must be 1 to {} characters", HEADER, MAX_KEY_LEN));
+        return Ok(req.into_response(response));
+    }
+
+    let principal = req.extensions().get::<Principal>().map(|p| p.id.clone()).unwrap_or_default();
+    let body = match buffer_body(&mut req).await {
+        Ok(body) => body,
+        Err(response) => return Ok(req.into_response(response)),
+    };
+    let target = req.uri().path_and_query().map_or("/", |p| p.as_str());
+    let mut hasher = Sha256::new();
+    hasher.update(format!("{}\n{}\n", req.method(), target).as_bytes());
+    hasher.update(&body);
+    let fingerprint = hex(&hasher.finalize());
+
+    match reserve(&pool, &principal, &key, &fingerprint, config.idempotency.ttl_secs).await {
+        Ok(Reservation::Reserved) => {}
+        Ok(Reservation::Replay(stored)) => {
+            let uri = serde_json::from_slice::<JsonData>(&body).map(|data| data.uri).ok();
+            return Ok(req.into_response(replay(stored, uri.as_deref())));
+        }
+        Ok(Reservation::InProgress) => {
+            let response = HttpResponse::Conflict().json("A request with this Idempotency-Key is still in progress");
+            return Ok(req.into_response(response));
+        }
+        Ok(Reservation::Mismatch) => {
+            let response =
+                HttpResponse::UnprocessableEntity().json("Idempotency-Key was already used for a different request");
+            return Ok(req.into_response(response));
+        }
+        Err(e) => {
+            let response = HttpResponse::InternalServerError().json(format!("Failed to check Idempotency-Key: {}", e));
+            return Ok(req.into_response(response));
+        }
+    }
+
+    let res = match next.call(req).await {
+        Ok(res) if !res.status().is_server_error() => res,
+        result => {
+            release(&pool, &principal, &key).await;
+            return result.map(ServiceResponse::map_into_boxed_body);
+        }
+    };
+
+    // 读出响应体保存下来, 再原样返回给客户端
+    let (req, res) = res.into_parts();
+    let status = res.status();
+    let document_id = res.extensions().get::<CreatedId>().map(|c| c.0);
+    let content_type = res.headers().get(CONTENT_TYPE).and_then(|v| v.to_str().ok()).map(str::to_string);
+    let (res, body) = res.into_parts();
+    let bytes = match body::to_bytes(body).await {
+        Ok(bytes) => bytes,
+        Err(e) => {
+            release(&pool, &principal, &key).await;
+            let e: Box<dyn std::error::Error> = e.into();
+            return Err(actix_web::error::ErrorInternalServerError(e.to_string()));
+        }
+    };
+
+    let stored = StoredResponse { status: status.as_u16(), content_type, body: bytes.to_vec(), document_id };
+    if let Err(e) = complete(&pool, &principal, &key, &stored).await {
+        log::warn!("failed to store response for Idempotency-Key '{}': {}", key, e);
+        release(&pool, &principal, &key).await;
+    }
+    Ok(ServiceResponse::new(req, res.set_body(BoxBody::new(bytes))))
+}
+
+// 登记 key; 已存在时根据记录决定重放、冲突或拒绝
+async fn reserve(
+    pool: &SqlitePool,
+    principal: &str,
+    key: &str,
+    fingerprint: &str,
+    ttl_secs: u64,
+) -> Result<Reservation, sqlx::Error> {
+    let now = now() as i64;
+    sqlx::query("DELETE FROM _idempotency_keys WHERE created_at < ?")
+        .bind(now - ttl_secs as i64)
+        .execute(pool)
+        .await?;
+
+    let inserted = sqlx::query(
+        "INSERT OR IGNORE INTO _idempotency_keys (principal, key, fingerprint, created_at) VALUES (?, ?, ?, ?)",
+    )
+    .bind(principal)
+    .bind(key)
+    .bind(fingerprint)
+    .bind(now)
+    .execute(pool)
+    .await?;
+    if inserted.rows_affected() == 1 {
+        return Ok(Reservation::Reserved);
+    }
+
+    let row = sqlx::query(
+        "SELECT fingerprint, status, content_type, body, document_id FROM _idempotency_keys WHERE principal = ? AND key = ?",
+    )
+    .bind(principal)
+    .bind(key)
+    .fetch_optional(pool)
+    .await?;
+    let Some(row) = row else {
+        // 刚好被另一个请求释放, 当作仍在处理中, 由客户端重试
+        return Ok(Reservation::InProgress);
+    };
+    if row.get::<String, _>("fingerprint") != fingerprint {
+        return Ok(Reservation::Mismatch);
+    }
+    match row.get::<Option<i64>, _>("status") {
+        Some(status) => Ok(Reservation::Replay(StoredResponse {
+            status: status as u16,
+            content_type: row.get("content_type"),
+            body: row.get::<Option<Vec<u8>>, _>("body").unwrap_or_default(),
+            document_id: row.get("document_id"),
+        })),
+        None => Ok(Reservation::InProgress),
+    }
+}
+
+async fn complete(pool: &SqlitePool, principal: &str, key: &str, stored: &StoredResponse) -> Result<(), sqlx::Error> {
+    sqlx::query(
+        "UPDATE _idempotency_keys SET status = ?, content_type = ?, body = ?, document_id = ? WHERE principal = ? AND key = ?",
+    )
+    .bind(stored.status as i64)
+    .bind(&stored.content_type)
+    .bind(&stored.body)
+    .bind(stored.document_id)
+    .bind(principal)
+    .bind(key)
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+// 请求失败时删除登记, 让客户端可以用同一个 key 重试
+async fn release(pool: &SqlitePool, principal: &str, key: &str) {
+    let result = sqlx::query("DELETE FROM _idempotency_keys WHERE principal = ? AND key = ?")
+        .bind(principal)
+        .bind(key)
+        .execute(pool)
+        .await;
+    if let Err(e) = result {
+        log::warn!("failed to release Idempotency-Key '{}': {}", key, e);
+    }
+}
+
+// uri 是请求体中的集合, 用来还原 Location
+fn replay(stored: StoredResponse, uri: Option<&str>) -> HttpResponse {
+    let status = StatusCode::from_u16(stored.status).unwrap_or(StatusCode::OK);
+    let mut builder = HttpResponse::build(status);
+    builder.insert_header((REPLAYED_HEADER, "true"));
+    if let Some(content_type) = stored.content_type {
+        builder.insert_header((CONTENT_TYPE, content_type));
+    }
+    let response = builder.body(stored.body);
+    match (stored.document_id, uri) {
+        (Some(id), Some(uri)) => created(response, uri, id),
+        (Some(id), None) => {
+            let mut response = response;
+            response.extensions_mut().insert(CreatedId(id));
+            response
+        }
+        (None, _) => response,
+    }
+}
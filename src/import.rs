@@ -0,0 +1,294 @@
+//! Import jobs.
+//!
+//! `POST /{uri}/_import` takes newline-delimited JSON, one document per line,
+//! and answers 202 with a [job](crate::jobs) as soon as the body is stored;
+//! the documents are inserted by the job queue, through deduplication like
+//! `POST /{uri}`. A large file can be sent in chunks of whole lines: the
+//! first with `?last=false`, the following to `POST /_jobs/{id}/data?from={line}`,
+//! the final one without `last=false`. `from` has to be the number of lines
+//! received so far; a chunk lost with a dropped connection is sent again from
+//! the `received` reported by `GET /_jobs/{id}`:
+//!
+//! ```json
+//! { "id": "9f2c…", "kind": "import", "collection": "users", "status": "running",
+//!   "attempts": 1, "error": null, "created_at": 1760000000000, "updated_at": 1760000004000,
+//!   "progress": { "received": 50000, "processed": 20000, "inserted": 19998, "failed": 2,
+//!                 "errors": [{ "line": 17, "error": "expected value at line 1 column 1" }],
+//!                 "eta_secs": 4.5 } }
+//! ```
+//!
+//! Chunks are kept in `_job_data` until the job finishes, and a job that
+//! waits an hour for its next chunk is dropped. An attempt that fails or is
+//! interrupted is resumed from the last saved `processed`, so lines handled
+//! in the second before may be inserted again unless the collection uses
+//! `dedup`. Blank lines are counted and skipped; only the first 100 errors
+//! are listed.
+
+use actix_web::{web, HttpMessage, HttpRequest, HttpResponse};
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use sqlx::Row;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::access_log::annotate;
+use crate::auth::Principal;
+use crate::cdc::now_millis;
+use crate::config::ConfigHandle;
+use crate::handlers::{route, store_document};
+use crate::jobs::{Context, Queue, Status, Task};
+use crate::store::{DocumentStore, StoreError};
+
+/// Kind of the import jobs.
+pub const KIND: &str = "import";
+
+/// Largest chunk of lines a request may carry.
+pub const MAX_CHUNK: usize = 2 * 1024 * 1024;
+
+/// Errors listed in a job's progress.
+const MAX_ERRORS: usize = 100;
+
+/// How often a running import saves its progress.
+const SAVE_EVERY: Duration = Duration::from_secs(1);
+
+/// A line that could not be imported.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LineError {
+    pub line: u64,
+    pub error: String,
+}
+
+/// The progress of an import job.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Progress {
+    /// Lines received.
+    pub received: u64,
+    /// Lines handled, inserted or not.
+    pub processed: u64,
+    pub inserted: u64,
+    pub failed: u64,
+    pub errors: Vec<LineError>,
+    /// Estimated seconds until the lines received are processed.
+    pub eta_secs: Option<f64>,
+}
+
+/// Runs import jobs; register it with the job queue under [`KIND`].
+pub struct ImportTask {
+    store: Arc<dyn DocumentStore>,
+    config: Arc<ConfigHandle>,
+}
+
+impl ImportTask {
+    pub fn new(store: Arc<dyn DocumentStore>, config: Arc<ConfigHandle>) -> Self {
+        Self { store, config }
+    }
+}
+
+#[async_trait]
+impl Task for ImportTask {
+    // 从上次保存的进度开始, 依次写入每一块中的文档
+    async fn run(&self, job: &Context<'_>) -> Result<(), String> {
+        let uri = job.job.collection.clone().unwrap_or_default();
+        let dedup = self.config.get().collections.get(&uri).and_then(|c| c.dedup.clone());
+        let mut progress: Progress = serde_json::from_value(job.job.progress.clone()).map_err(|e| e.to_string())?;
+        let (started, resumed_from) = (Instant::now(), progress.processed);
+        let mut saved = Instant::now();
+        let save = |progress: &mut Progress, done: bool| {
+            // 按本次运行的处理速度估计剩余时间
+            let rate = (progress.processed - resumed_from) as f64 / started.elapsed().as_secs_f64();
+            progress.eta_secs = (!done && rate > 0.0).then(|| (progress.received - progress.processed) as f64 / rate);
+            serde_json::to_value(&*progress).unwrap_or_default()
+        };
+        loop {
+            let chunk = sqlx::query("SELECT first, lines FROM _job_data WHERE job_id = ? AND first + count > ? ORDER BY first LIMIT 1")
+                .bind(&job.job.id)
+                .bind(progress.processed as i64)
+                .fetch_optional(job.pool())
+                .await
+                .map_err(|e| format!("Failed to read import data: {}", e))?;
+            let Some(chunk) = chunk else { break };
+            let first = chunk.get::<i64, _>("first") as u64;
+            let lines: Vec<String> = serde_json::from_str(chunk.get("lines")).map_err(|e| e.to_string())?;
+            let skip = progress.processed.saturating_sub(first) as usize;
+            for (line, text) in (first + 1..).zip(lines).skip(skip) {
+                if job.cancelled() {
+                    let _ = job.save_progress(&save(&mut progress, true)).await;
+                    return Err("cancelled".to_string());
+                }
+                let result = match serde_json::from_str::<Value>(&text) {
+                    _ if text.trim().is_empty() => None,
+                    Ok(doc @ Value::Object(_)) => Some(store_document(self.store.as_ref(), dedup.as_ref(), &uri, &doc).await),
+                    Ok(_) => Some(Err(StoreError::Invalid("a document has to be an object".to_string()))),
+                    Err(e) => Some(Err(StoreError::Invalid(e.to_string()))),
+                };
+                match result {
+                    // 写入节点不可用时保存进度, 稍后重试
+                    Some(Err(e @ StoreError::Unavailable(_))) => {
+                        let _ = job.save_progress(&save(&mut progress, false)).await;
+                        return Err(e.to_string());
+                    }
+                    None => {}
+                    Some(Ok(_)) => progress.inserted += 1,
+                    Some(Err(e)) => {
+                        progress.failed += 1;
+                        if progress.errors.len() < MAX_ERRORS {
+                            progress.errors.push(LineError { line, error: e.to_string() });
+                        }
+                    }
+                }
+                progress.processed += 1;
+                if saved.elapsed() >= SAVE_EVERY {
+                    job.save_progress(&save(&mut progress, false)).await.map_err(|e| format!("Failed to save progress: {}", e))?;
+                    saved = Instant::now();
+                }
+            }
+        }
+        job.save_progress(&save(&mut progress, true)).await.map_err(|e| format!("Failed to save progress: {}", e))?;
+        sqlx::query("DELETE FROM _job_data WHERE job_id = ?")
+            .bind(&job.job.id)
+            .execute(job.pool())
+            .await
+            .map_err(|e| format!("Failed to delete import data: {}", e))?;
+        log::info!("import {} into '{}' done: {} inserted, {} failed", job.job.id, uri, progress.inserted, progress.failed);
+        Ok(())
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ChunkParams {
+    /// Lines received before this chunk, as the sender counts them.
+    pub from: Option<u64>,
+    /// Whether this is the final chunk.
+    #[serde(default = "default_last")]
+    pub last: bool,
+}
+
+fn default_last() -> bool {
+    true
+}
+
+// 注册集合下的导入接口, 与其他文档接口一样检查 ACL
+pub fn configure_documents(cfg: &mut web::ServiceConfig) {
+    cfg.service(
+        web::resource(route("/{uri}/_import"))
+            .app_data(web::PayloadConfig::new(MAX_CHUNK))
+            .route(web::post().to(start_import)),
+    );
+}
+
+// 注册续传接口
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(
+        web::resource("/_jobs/{id}/data")
+            .app_data(web::PayloadConfig::new(MAX_CHUNK))
+            .route(web::post().to(append_chunk)),
+    );
+}
+
+// 创建导入任务, 请求体是第一块数据
+async fn start_import(
+    req: HttpRequest,
+    uri: web::Path<String>,
+    params: web::Query<ChunkParams>,
+    body: web::Bytes,
+    queue: web::Data<Queue>,
+) -> HttpResponse {
+    let lines = match split_lines(&body) {
+        Ok(lines) => lines,
+        Err(response) => return response,
+    };
+    let owner = req.extensions().get::<Principal>().map(|p| p.id.clone());
+    let progress = serde_json::to_value(Progress::default()).unwrap_or_default();
+    let rows = lines.len();
+    let created = queue.create(KIND, Some(&uri), owner.as_deref(), &Value::Null, &progress, Status::Receiving).await;
+    let job = match created {
+        Ok(job) => job,
+        Err(e) => return HttpResponse::InternalServerError().json(format!("Failed to create import job: {}", e)),
+    };
+    if let Err(e) = receive(&queue, &job.id, None, &lines, params.last).await {
+        return HttpResponse::InternalServerError().json(format!("Failed to store import data: {}", e));
+    }
+    match queue.get(&job.id).await {
+        Ok(Some(job)) => annotate(HttpResponse::Accepted().json(job), &uri, rows),
+        Ok(None) => HttpResponse::NotFound().json(format!("No import job '{}'", job.id)),
+        Err(e) => HttpResponse::InternalServerError().json(format!("Failed to read import job: {}", e)),
+    }
+}
+
+// 续传一块数据
+async fn append_chunk(
+    req: HttpRequest,
+    id: web::Path<String>,
+    params: web::Query<ChunkParams>,
+    body: web::Bytes,
+    queue: web::Data<Queue>,
+) -> HttpResponse {
+    match queue.get(&id).await {
+        Ok(Some(job)) if job.kind == KIND && job.visible_to(&req) => {}
+        Ok(_) => return HttpResponse::NotFound().json(format!("No import job '{}'", id)),
+        Err(e) => return HttpResponse::InternalServerError().json(format!("Failed to read import job: {}", e)),
+    }
+    let lines = match split_lines(&body) {
+        Ok(lines) => lines,
+        Err(response) => return response,
+    };
+    let accepted = match receive(&queue, &id, params.from, &lines, params.last).await {
+        Ok(accepted) => accepted,
+        Err(e) => return HttpResponse::InternalServerError().json(format!("Failed to store import data: {}", e)),
+    };
+    match queue.get(&id).await {
+        Ok(Some(job)) if accepted => HttpResponse::Accepted().json(job),
+        Ok(Some(job)) => HttpResponse::Conflict().json(job),
+        Ok(None) => HttpResponse::NotFound().json(format!("No import job '{}'", id)),
+        Err(e) => HttpResponse::InternalServerError().json(format!("Failed to read import job: {}", e)),
+    }
+}
+
+// 保存一块数据, 最后一块之后排队执行; 任务已收完或起始行对不上时不接收
+async fn receive(queue: &Queue, id: &str, from: Option<u64>, lines: &[String], last: bool) -> Result<bool, sqlx::Error> {
+    let count = lines.len() as i64;
+    let mut tx = queue.pool().begin().await?;
+    let received: Option<i64> = sqlx::query_scalar(
+        r#"
+        UPDATE _jobs
+        SET progress = json_set(progress, '$.received', json_extract(progress, '$.received') + ?),
+            status = CASE WHEN ? THEN 'queued' ELSE status END,
+            updated_at = ?
+        WHERE id = ? AND status = 'receiving' AND (? IS NULL OR json_extract(progress, '$.received') = ?)
+        RETURNING json_extract(progress, '$.received')
+        "#,
+    )
+    .bind(count)
+    .bind(last)
+    .bind(now_millis() as i64)
+    .bind(id)
+    .bind(from.map(|from| from as i64))
+    .bind(from.map(|from| from as i64))
+    .fetch_optional(&mut *tx)
+    .await?;
+    let Some(received) = received else { return Ok(false) };
+    if count > 0 {
+        sqlx::query("INSERT INTO _job_data (job_id, first, count, lines) VALUES (?, ?, ?, ?)")
+            .bind(id)
+            .bind(received - count)
+            .bind(count)
+            .bind(serde_json::to_string(lines).unwrap_or_default())
+            .execute(&mut *tx)
+            .await?;
+    }
+    tx.commit().await?;
+    if last {
+        queue.notify();
+    }
+    Ok(true)
+}
+
+fn split_lines(body: &[u8]) -> Result<Vec<String>, HttpResponse> {
+    let text = std::str::from_utf8(body).map_err(|_| HttpResponse::BadRequest().json("Import data must be UTF-8"))?;
+    let text = text.strip_suffix('\n').unwrap_or(text);
+    if text.is_empty() {
+        return Ok(Vec::new());
+    }
+    Ok(text.split('\n').map(|line| line.strip_suffix('\r').unwrap_or(line).to_string()).collect())
+}
@@ -0,0 +1,296 @@
+//! Per-document checksums.
+//!
+//! With `checksums` on, every write records the SHA-256 of the document's
+//! canonical JSON, as read back after the write, in the `_checksums` table.
+//! `POST /_admin/verify` reads every checksummed collection again through
+//! the store below the cache, so shard files, partitions and compressed
+//! values are reassembled the way requests see them, and hashes each
+//! document again. A document whose hash differs, a checksum whose document
+//! is gone and a document without a checksum are reported separately; the
+//! last are usually documents written before checksums were turned on.
+//!
+//! Checksums are written after the document, so a crash in between shows up
+//! as a mismatch, as does a write made to the database directly.
+
+use actix_web::{web, HttpResponse};
+use async_trait::async_trait;
+use serde::Serialize;
+use serde_json::Value;
+use sqlx::SqlitePool;
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use crate::config::ConfigHandle;
+use crate::store::{content_hash, DedupMode, DocumentStore, Inserted, PageQuery, Paged, StoreError, UpdatedMany, Upserted, WriteOp, Written, VERSION_FIELD};
+
+/// The checksum of `doc`.
+pub fn checksum(doc: &Value) -> String {
+    content_hash(doc, &[])
+}
+
+/// A document that failed verification.
+#[derive(Debug, Clone, Serialize)]
+pub struct Mismatch {
+    pub collection: String,
+    pub id: i64,
+    /// The recorded checksum.
+    pub expected: String,
+    /// The checksum of the document as read now, null when it is gone.
+    pub actual: Option<String>,
+}
+
+/// The outcome of `POST /_admin/verify`.
+#[derive(Debug, Default, Serialize)]
+pub struct Report {
+    pub collections: usize,
+    /// Documents read and hashed.
+    pub documents: usize,
+    /// Documents whose checksum matched.
+    pub verified: usize,
+    /// Documents without a recorded checksum.
+    pub unchecked: usize,
+    pub mismatched: Vec<Mismatch>,
+}
+
+/// Re-reads and re-hashes the checksummed documents.
+pub struct Checksums {
+    store: Arc<dyn DocumentStore>,
+    pool: SqlitePool,
+}
+
+impl Checksums {
+    pub async fn verify(&self) -> Result<Report, StoreError> {
+        let collections: Vec<String> = sqlx::query_scalar("SELECT DISTINCT collection FROM _checksums ORDER BY collection")
+            .fetch_all(&self.pool)
+            .await?;
+        let mut report = Report { collections: collections.len(), ..Report::default() };
+        for collection in collections {
+            let rows: Vec<(i64, String)> = sqlx::query_as("SELECT document_id, checksum FROM _checksums WHERE collection = ?")
+                .bind(&collection)
+                .fetch_all(&self.pool)
+                .await?;
+            let mut expected: HashMap<i64, String> = rows.into_iter().collect();
+            let docs = match self.store.list(&collection).await {
+                Ok(docs) => docs,
+                // 集合的表整个不见了: 所有校验和都对不上
+                Err(StoreError::NotFound) => Vec::new(),
+                Err(StoreError::Database(sqlx::Error::Database(e))) if e.message().contains("no such table") => Vec::new(),
+                Err(e) => return Err(e),
+            };
+            for doc in docs {
+                report.documents += 1;
+                let Some(id) = doc.get("id").and_then(Value::as_i64) else { continue };
+                let actual = checksum(&doc);
+                match expected.remove(&id) {
+                    Some(recorded) if recorded == actual => report.verified += 1,
+                    Some(recorded) => {
+                        report.mismatched.push(Mismatch { collection: collection.clone(), id, expected: recorded, actual: Some(actual) })
+                    }
+                    None => report.unchecked += 1,
+                }
+            }
+            for (id, recorded) in expected {
+                report.mismatched.push(Mismatch { collection: collection.clone(), id, expected: recorded, actual: None });
+            }
+        }
+        report.mismatched.sort_by(|a, b| (&a.collection, a.id).cmp(&(&b.collection, b.id)));
+        Ok(report)
+    }
+
+    /// Records the checksums of the documents of `collection` that have one
+    /// again, after their stored form changed without a write to them, as
+    /// when unused columns are dropped.
+    pub async fn refresh(&self, collection: &str) -> Result<(), StoreError> {
+        for doc in self.store.list(collection).await? {
+            let Some(id) = doc.get("id").and_then(Value::as_i64) else { continue };
+            let version = doc.get(VERSION_FIELD).and_then(Value::as_i64).unwrap_or_default();
+            sqlx::query("UPDATE _checksums SET checksum = ? WHERE collection = ? AND document_id = ? AND version = ?")
+                .bind(checksum(&doc))
+                .bind(collection)
+                .bind(id)
+                .bind(version)
+                .execute(&self.pool)
+                .await?;
+        }
+        Ok(())
+    }
+}
+
+/// A [`DocumentStore`] that passes every call on to `inner` and records the
+/// checksum of each document written while `checksums` is on.
+pub struct ChecksumStore {
+    inner: Arc<dyn DocumentStore>,
+    pool: SqlitePool,
+    config: Arc<ConfigHandle>,
+}
+
+impl ChecksumStore {
+    // 校验和写入失败只记录日志, 校验时会报告为不一致
+    async fn record(&self, uri: &str, id: i64) {
+        if !self.config.get().checksums {
+            return;
+        }
+        if let Err(e) = self.save(uri, id).await {
+            log::warn!("failed to record checksum of {}/{}: {}", uri, id, e);
+        }
+    }
+
+    // 并发写入时只保留较新版本的校验和
+    async fn save(&self, uri: &str, id: i64) -> Result<(), StoreError> {
+        let Some(doc) = self.inner.get(uri, id).await? else {
+            return self.forget(uri, id).await;
+        };
+        let version = doc.get(VERSION_FIELD).and_then(Value::as_i64).unwrap_or_default();
+        sqlx::query(
+            r#"
+            INSERT INTO _checksums (collection, document_id, version, checksum) VALUES (?, ?, ?, ?)
+            ON CONFLICT (collection, document_id) DO UPDATE SET version = excluded.version, checksum = excluded.checksum
+            WHERE excluded.version >= _checksums.version
+            "#,
+        )
+        .bind(uri)
+        .bind(id)
+        .bind(version)
+        .bind(checksum(&doc))
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    async fn forget(&self, uri: &str, id: i64) -> Result<(), StoreError> {
+        sqlx::query("DELETE FROM _checksums WHERE collection = ? AND document_id = ?")
+            .bind(uri)
+            .bind(id)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl DocumentStore for ChecksumStore {
+    async fn insert(&self, uri: &str, doc: &Value) -> Result<i64, StoreError> {
+        let id = self.inner.insert(uri, doc).await?;
+        self.record(uri, id).await;
+        Ok(id)
+    }
+
+    async fn list(&self, uri: &str) -> Result<Vec<Value>, StoreError> {
+        self.inner.list(uri).await
+    }
+
+    async fn get(&self, uri: &str, id: i64) -> Result<Option<Value>, StoreError> {
+        self.inner.get(uri, id).await
+    }
+
+    async fn get_many(&self, uri: &str, ids: &[i64]) -> Result<Vec<Value>, StoreError> {
+        self.inner.get_many(uri, ids).await
+    }
+
+    async fn insert_unique(&self, uri: &str, doc: &Value, hash: &str, mode: DedupMode) -> Result<Inserted, StoreError> {
+        let inserted = self.inner.insert_unique(uri, doc, hash, mode).await?;
+        if !inserted.duplicate || mode == DedupMode::Upsert {
+            self.record(uri, inserted.id).await;
+        }
+        Ok(inserted)
+    }
+
+    async fn upsert(&self, uri: &str, key: &str, doc: &Value) -> Result<Upserted, StoreError> {
+        let upserted = self.inner.upsert(uri, key, doc).await?;
+        if upserted.changed {
+            self.record(uri, upserted.id).await;
+        }
+        Ok(upserted)
+    }
+
+    async fn replace(&self, uri: &str, id: i64, doc: &Value, expected: Option<&[i64]>) -> Result<i64, StoreError> {
+        let version = self.inner.replace(uri, id, doc, expected).await?;
+        self.record(uri, id).await;
+        Ok(version)
+    }
+
+    async fn update(&self, uri: &str, id: i64, doc: &Value, expected: Option<&[i64]>) -> Result<i64, StoreError> {
+        let version = self.inner.update(uri, id, doc, expected).await?;
+        self.record(uri, id).await;
+        Ok(version)
+    }
+
+    async fn delete(&self, uri: &str, id: i64, expected: Option<&[i64]>) -> Result<(), StoreError> {
+        self.inner.delete(uri, id, expected).await?;
+        // 关闭 checksums 后删除的文档也不再校验
+        if let Err(e) = self.forget(uri, id).await {
+            log::warn!("failed to remove checksum of {}/{}: {}", uri, id, e);
+        }
+        Ok(())
+    }
+
+    async fn update_many(&self, uri: &str, filter: &Value, update: &Value) -> Result<UpdatedMany, StoreError> {
+        let updated = self.inner.update_many(uri, filter, update).await?;
+        for id in &updated.modified {
+            self.record(uri, *id).await;
+        }
+        Ok(updated)
+    }
+
+    async fn delete_many(&self, uri: &str, filter: &Value, dry_run: bool) -> Result<Vec<i64>, StoreError> {
+        let deleted = self.inner.delete_many(uri, filter, dry_run).await?;
+        if !dry_run {
+            for id in &deleted {
+                if let Err(e) = self.forget(uri, *id).await {
+                    log::warn!("failed to remove checksum of {}/{}: {}", uri, id, e);
+                }
+            }
+        }
+        Ok(deleted)
+    }
+
+    async fn find(&self, uri: &str, filter: &Value) -> Result<Vec<Value>, StoreError> {
+        self.inner.find(uri, filter).await
+    }
+
+    async fn count(&self, uri: &str, filter: &Value) -> Result<u64, StoreError> {
+        self.inner.count(uri, filter).await
+    }
+
+    async fn find_page(&self, uri: &str, filter: &Value, page: &PageQuery) -> Result<Paged, StoreError> {
+        self.inner.find_page(uri, filter, page).await
+    }
+
+    async fn version(&self, uri: &str) -> Result<String, StoreError> {
+        self.inner.version(uri).await
+    }
+
+    async fn committed(&self, writes: &[Written]) {
+        self.inner.committed(writes).await;
+        for write in writes {
+            match write.op {
+                WriteOp::Delete => {
+                    if let Err(e) = self.forget(&write.uri, write.id).await {
+                        log::warn!("failed to remove checksum of {}/{}: {}", write.uri, write.id, e);
+                    }
+                }
+                _ => self.record(&write.uri, write.id).await,
+            }
+        }
+    }
+}
+
+/// Wraps `store` in a [`ChecksumStore`], and returns the [`Checksums`] that
+/// verify the documents through `store` itself.
+pub fn wrap(config: Arc<ConfigHandle>, store: Arc<dyn DocumentStore>, pool: SqlitePool) -> (Arc<dyn DocumentStore>, Arc<Checksums>) {
+    let checksums = Arc::new(Checksums { store: store.clone(), pool: pool.clone() });
+    (Arc::new(ChecksumStore { inner: store, pool, config }), checksums)
+}
+
+// 重新读取并计算所有记录了校验和的文档
+pub async fn verify(checksums: web::Data<Checksums>) -> HttpResponse {
+    match checksums.verify().await {
+        Ok(report) => {
+            if !report.mismatched.is_empty() {
+                log::error!("integrity check found {} damaged or missing documents", report.mismatched.len());
+            }
+            HttpResponse::Ok().json(report)
+        }
+        Err(e) => HttpResponse::InternalServerError().json(format!("Failed to verify documents: {}", e)),
+    }
+}
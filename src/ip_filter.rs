@@ -0,0 +1,219 @@
+//! Client address filtering by CIDR lists.
+//!
+//! The client address is the TCP peer, unless the peer is a trusted proxy, in
+//! which case `X-Forwarded-For` is walked from the right, skipping further
+//! trusted proxies, and the first untrusted hop is the client.
+
+use actix_web::body::{EitherBody, MessageBody};
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::http::Method;
+use actix_web::middleware::Next;
+use actix_web::{web, Error, HttpResponse};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::net::IpAddr;
+
+use crate::config::{ConfigHandle, IpFilter};
+
+/// An address range such as `10.0.0.0/8` or `fd00::/8`. A bare address is a single host.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct Cidr {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl Cidr {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.addr, canonical(ip)) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix as u32).unwrap_or(0);
+                u32::from(net) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix as u32).unwrap_or(0);
+                u128::from(net) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+impl std::str::FromStr for Cidr {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        let (addr, prefix) = match s.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (s, None),
+        };
+        let addr: IpAddr = addr.trim().parse().map_err(|_| format!("invalid address in '{}'", s))?;
+        let addr = canonical(addr);
+        let max = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(p) => p.trim().parse::<u8>().ok().filter(|p| *p <= max).ok_or_else(|| format!("invalid prefix in '{}'", s))?,
+            None => max,
+        };
+        Ok(Self { addr, prefix })
+    }
+}
+
+impl TryFrom<String> for Cidr {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, String> {
+        s.parse()
+    }
+}
+
+impl From<Cidr> for String {
+    fn from(cidr: Cidr) -> String {
+        cidr.to_string()
+    }
+}
+
+impl fmt::Display for Cidr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix)
+    }
+}
+
+// IPv4 映射的 IPv6 地址 (::ffff:a.b.c.d) 按 IPv4 处理
+fn canonical(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V6(v6) => v6.to_ipv4_mapped().map(IpAddr::V4).unwrap_or(ip),
+        ip => ip,
+    }
+}
+
+fn matches(list: &[Cidr], ip: IpAddr) -> bool {
+    list.iter().any(|cidr| cidr.contains(ip))
+}
+
+/// The originating client address, honouring `X-Forwarded-For` from trusted proxies.
+pub fn client_ip(req: &ServiceRequest, filter: &IpFilter) -> Option<IpAddr> {
+    let peer = canonical(req.peer_addr()?.ip());
+    if !matches(&filter.trusted_proxies, peer) {
+        return Some(peer);
+    }
+
+    let forwarded: Vec<IpAddr> = req
+        .headers()
+        .get_all("X-Forwarded-For")
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .filter_map(|hop| hop.trim().parse().ok())
+        .map(canonical)
+        .collect();
+    // 从右往左跳过可信代理, 第一个不可信的地址就是客户端
+    let mut client = peer;
+    for hop in forwarded.into_iter().rev() {
+        client = hop;
+        if !matches(&filter.trusted_proxies, hop) {
+            break;
+        }
+    }
+    Some(client)
+}
+
+// 判断地址是否被允许: deny 优先, allow 为空表示不限制; 写请求还需满足 write_allow
+fn allowed(filter: &IpFilter, ip: IpAddr, write: bool) -> bool {
+    if matches(&filter.deny, ip) {
+        return false;
+    }
+    if !filter.allow.is_empty() && !matches(&filter.allow, ip) {
+        return false;
+    }
+    !write || filter.write_allow.is_empty() || matches(&filter.write_allow, ip)
+}
+
+/// Rejects requests from addresses outside the configured ranges with 403.
+pub async fn enforce(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<EitherBody<impl MessageBody>>, Error> {
+    let config = req.app_data::<web::Data<ConfigHandle>>().map(|c| c.get());
+    if let Some(config) = config {
+        let filter = &config.ip_filter;
+        if filter.is_active() {
+            let write = !matches!(*req.method(), Method::GET | Method::HEAD | Method::OPTIONS);
+            let permitted = client_ip(&req, filter).is_some_and(|ip| allowed(filter, ip, write));
+            if !permitted {
+                let response = HttpResponse::Forbidden().json("Address not allowed");
+                return Ok(req.into_response(response).map_into_right_body());
+            }
+        }
+    }
+    next.call(req).await.map(ServiceResponse::map_into_left_body)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::TestRequest;
+
+    fn cidrs(list: &[&str]) -> Vec<Cidr> {
+        list.iter().map(|c| c.parse().unwrap()).collect()
+    }
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn cidr_parses_ranges_and_single_hosts() {
+        assert_eq!("10.0.0.0/8".parse::<Cidr>().unwrap().to_string(), "10.0.0.0/8");
+        assert_eq!("192.168.1.5".parse::<Cidr>().unwrap().to_string(), "192.168.1.5/32");
+        assert_eq!("::ffff:10.1.2.3/24".parse::<Cidr>().unwrap().to_string(), "10.1.2.3/24");
+        assert_eq!("fd00::/8".parse::<Cidr>().unwrap().to_string(), "fd00::/8");
+        assert_eq!("10.0.0.0/33".parse::<Cidr>(), Err("invalid prefix in '10.0.0.0/33'".to_string()));
+        assert_eq!("10.0.0/8".parse::<Cidr>(), Err("invalid address in '10.0.0/8'".to_string()));
+        assert!(serde_json::from_str::<Cidr>("\"fd00::/129\"").is_err());
+    }
+
+    #[test]
+    fn cidr_contains_addresses_in_its_range() {
+        let net: Cidr = "10.1.0.0/16".parse().unwrap();
+        assert!(net.contains(ip("10.1.255.1")));
+        assert!(net.contains(ip("::ffff:10.1.0.9")));
+        assert!(!net.contains(ip("10.2.0.1")));
+        assert!(!net.contains(ip("fd00::1")));
+        assert!("0.0.0.0/0".parse::<Cidr>().unwrap().contains(ip("203.0.113.7")));
+        assert!("fd00::/8".parse::<Cidr>().unwrap().contains(ip("fdab::1")));
+        assert!(!"fd00::/8".parse::<Cidr>().unwrap().contains(ip("fe80::1")));
+    }
+
+    #[test]
+    fn deny_wins_and_writes_need_write_allow() {
+        let filter = IpFilter {
+            allow: cidrs(&["10.0.0.0/8"]),
+            deny: cidrs(&["10.0.0.13"]),
+            write_allow: cidrs(&["10.9.0.0/16"]),
+            trusted_proxies: Vec::new(),
+        };
+        assert!(allowed(&filter, ip("10.1.1.1"), false));
+        assert!(!allowed(&filter, ip("10.1.1.1"), true));
+        assert!(allowed(&filter, ip("10.9.1.1"), true));
+        assert!(!allowed(&filter, ip("10.0.0.13"), false));
+        assert!(!allowed(&filter, ip("192.0.2.1"), false));
+        let open = IpFilter { deny: cidrs(&["192.0.2.0/24"]), ..IpFilter::default() };
+        assert!(allowed(&open, ip("198.51.100.1"), true));
+    }
+
+    #[test]
+    fn forwarded_for_is_only_read_from_trusted_proxies() {
+        let filter = IpFilter { trusted_proxies: cidrs(&["10.0.0.0/8"]), ..IpFilter::default() };
+        let request = |peer: &str, forwarded: &str| {
+            TestRequest::default()
+                .peer_addr(format!("{}:4000", peer).parse().unwrap())
+                .insert_header(("X-Forwarded-For", forwarded))
+                .to_srv_request()
+        };
+        let client = |peer, forwarded| client_ip(&request(peer, forwarded), &filter).unwrap();
+        assert_eq!(client("203.0.113.7", "198.51.100.1"), ip("203.0.113.7"));
+        assert_eq!(client("10.0.0.1", "198.51.100.1, 192.0.2.9, 10.0.0.2"), ip("192.0.2.9"));
+        assert_eq!(client("10.0.0.1", "10.0.0.3, 10.0.0.2"), ip("10.0.0.3"));
+        assert_eq!(client("10.0.0.1", "not an address"), ip("10.0.0.1"));
+        assert_eq!(client_ip(&TestRequest::default().to_srv_request(), &filter), None);
+    }
+}
@@ -0,0 +1,438 @@
+//! Background jobs.
+//!
+//! Work that outlives a request is queued in the `_jobs` table and run by a
+//! pool of `jobs.workers` workers. Each job has a `kind`, naming the
+//! [`Task`] that runs it, a payload and a progress document the task keeps
+//! up to date. A task that fails is retried, after `jobs.backoff_secs`
+//! doubling with every attempt, until it has run `jobs.max_attempts` times;
+//! jobs left running by a crash are queued again at startup, so tasks have
+//! to pick up from their saved progress. Finished jobs are deleted after
+//! `jobs.keep_secs`.
+//!
+//! | Endpoint                    | Purpose                                        |
+//! |-----------------------------|------------------------------------------------|
+//! | `GET /_jobs`                | the newest 100 jobs, by `status`, `kind` or `collection` |
+//! | `GET /_jobs/{id}`           | one job and its progress                       |
+//! | `POST /_jobs/{id}/cancel`   | cancel a job that has not finished             |
+//!
+//! Callers see the jobs they created; admins see all of them. Jobs belong to
+//! the node that queued them and are not replicated.
+
+use actix_web::{web, HttpMessage, HttpRequest, HttpResponse};
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use sqlx::sqlite::SqliteRow;
+use sqlx::{Row, SqlitePool};
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::Notify;
+
+use crate::auth::Principal;
+use crate::cdc::now_millis;
+use crate::config::{ConfigHandle, Role};
+use crate::sessions::random_token;
+
+/// How often idle workers look for jobs that became due.
+const POLL: Duration = Duration::from_secs(1);
+
+/// How long a job may wait for the rest of its input.
+const RECEIVING_TIMEOUT: Duration = Duration::from_secs(3600);
+
+/// Jobs listed by `GET /_jobs`.
+const MAX_LISTED: i64 = 100;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Status {
+    /// Waiting for the rest of its input; not run yet.
+    Receiving,
+    Queued,
+    Running,
+    Done,
+    Failed,
+    Cancelled,
+}
+
+impl Status {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Status::Receiving => "receiving",
+            Status::Queued => "queued",
+            Status::Running => "running",
+            Status::Done => "done",
+            Status::Failed => "failed",
+            Status::Cancelled => "cancelled",
+        }
+    }
+
+    fn parse(s: &str) -> Status {
+        serde_json::from_value(Value::String(s.to_string())).unwrap_or(Status::Failed)
+    }
+}
+
+/// A job as stored in `_jobs`.
+#[derive(Debug, Clone, Serialize)]
+pub struct Job {
+    pub id: String,
+    pub kind: String,
+    pub collection: Option<String>,
+    #[serde(skip)]
+    pub owner: Option<String>,
+    pub status: Status,
+    /// Times the job has been started.
+    pub attempts: i64,
+    /// Why the last attempt failed.
+    pub error: Option<String>,
+    #[serde(skip)]
+    pub payload: Value,
+    pub progress: Value,
+    pub created_at: i64,
+    pub updated_at: i64,
+}
+
+impl Job {
+    fn from_row(row: &SqliteRow) -> Job {
+        let json = |column: &str| {
+            row.get::<Option<String>, _>(column).and_then(|text| serde_json::from_str(&text).ok()).unwrap_or(Value::Null)
+        };
+        Job {
+            id: row.get("id"),
+            kind: row.get("kind"),
+            collection: row.get("collection"),
+            owner: row.get("owner"),
+            status: Status::parse(row.get("status")),
+            attempts: row.get("attempts"),
+            error: row.get("error"),
+            payload: json("payload"),
+            progress: json("progress"),
+            created_at: row.get("created_at"),
+            updated_at: row.get("updated_at"),
+        }
+    }
+
+    /// Whether the caller of `req` may see the job: its creator and admins
+    /// can, and everyone while authentication is off. Jobs the server
+    /// queued itself have no creator.
+    pub fn visible_to(&self, req: &HttpRequest) -> bool {
+        match (&self.owner, req.extensions().get::<Principal>()) {
+            (_, None) => self.owner.is_none(),
+            (Some(owner), Some(principal)) => *owner == principal.id || principal.role == Role::Admin,
+            (None, Some(principal)) => principal.role == Role::Admin,
+        }
+    }
+}
+
+/// Runs the jobs of one kind.
+#[async_trait]
+pub trait Task: Send + Sync {
+    /// Runs `job` from its saved progress. An error is retried.
+    async fn run(&self, job: &Context<'_>) -> Result<(), String>;
+}
+
+/// A running job, as handed to its [`Task`].
+pub struct Context<'a> {
+    pub job: Job,
+    queue: &'a Queue,
+}
+
+impl Context<'_> {
+    pub fn pool(&self) -> &SqlitePool {
+        &self.queue.pool
+    }
+
+    /// Whether the job has been cancelled; the task should stop soon after.
+    pub fn cancelled(&self) -> bool {
+        self.queue.cancelled.lock().unwrap().contains(&self.job.id)
+    }
+
+    pub async fn save_progress(&self, progress: &Value) -> Result<(), sqlx::Error> {
+        sqlx::query("UPDATE _jobs SET progress = ?, updated_at = ? WHERE id = ?")
+            .bind(progress.to_string())
+            .bind(now_millis() as i64)
+            .bind(&self.job.id)
+            .execute(&self.queue.pool)
+            .await?;
+        Ok(())
+    }
+}
+
+/// The job queue and its tasks; build it in `main`, register the tasks,
+/// [`start`] it and register it as app data.
+pub struct Queue {
+    pool: SqlitePool,
+    config: Arc<ConfigHandle>,
+    tasks: HashMap<&'static str, Arc<dyn Task>>,
+    wake: Notify,
+    /// Running jobs asked to stop.
+    cancelled: Mutex<HashSet<String>>,
+    purged: Mutex<Option<Instant>>,
+}
+
+impl Queue {
+    pub fn new(pool: SqlitePool, config: Arc<ConfigHandle>) -> Self {
+        Self {
+            pool,
+            config,
+            tasks: HashMap::new(),
+            wake: Notify::new(),
+            cancelled: Mutex::new(HashSet::new()),
+            purged: Mutex::new(None),
+        }
+    }
+
+    /// Runs the jobs of `kind` with `task`.
+    pub fn register(mut self, kind: &'static str, task: Arc<dyn Task>) -> Self {
+        self.tasks.insert(kind, task);
+        self
+    }
+
+    pub fn pool(&self) -> &SqlitePool {
+        &self.pool
+    }
+
+    /// Queues a job, or with [`Status::Receiving`] only records it until
+    /// [`Queue::notify`] follows the update that queues it.
+    pub async fn create(
+        &self,
+        kind: &str,
+        collection: Option<&str>,
+        owner: Option<&str>,
+        payload: &Value,
+        progress: &Value,
+        status: Status,
+    ) -> Result<Job, sqlx::Error> {
+        let now = now_millis() as i64;
+        let row = sqlx::query(
+            r#"
+            INSERT INTO _jobs (id, kind, collection, owner, status, attempts, run_after, payload, progress, created_at, updated_at)
+            VALUES (?, ?, ?, ?, ?, 0, 0, ?, ?, ?, ?)
+            RETURNING *
+            "#,
+        )
+        .bind(random_token(16))
+        .bind(kind)
+        .bind(collection)
+        .bind(owner)
+        .bind(status.as_str())
+        .bind(payload.to_string())
+        .bind(progress.to_string())
+        .bind(now)
+        .bind(now)
+        .fetch_one(&self.pool)
+        .await?;
+        if status == Status::Queued {
+            self.notify();
+        }
+        Ok(Job::from_row(&row))
+    }
+
+    /// Wakes an idle worker for a job just queued.
+    pub fn notify(&self) {
+        self.wake.notify_one();
+    }
+
+    pub async fn get(&self, id: &str) -> Result<Option<Job>, sqlx::Error> {
+        let row = sqlx::query("SELECT * FROM _jobs WHERE id = ?").bind(id).fetch_optional(&self.pool).await?;
+        Ok(row.as_ref().map(Job::from_row))
+    }
+
+    /// Cancels a job that has not finished: one not started at once, a
+    /// running one when its task next checks.
+    pub async fn cancel(&self, id: &str) -> Result<Option<Job>, sqlx::Error> {
+        sqlx::query("UPDATE _jobs SET status = 'cancelled', updated_at = ? WHERE id = ? AND status IN ('receiving', 'queued')")
+            .bind(now_millis() as i64)
+            .bind(id)
+            .execute(&self.pool)
+            .await?;
+        let job = self.get(id).await?;
+        if job.as_ref().is_some_and(|job| job.status == Status::Running) {
+            self.cancelled.lock().unwrap().insert(id.to_string());
+        }
+        Ok(job)
+    }
+
+    // 取出一个到期的任务并标记为运行中
+    async fn claim(&self) -> Result<Option<Job>, sqlx::Error> {
+        let now = now_millis() as i64;
+        let row = sqlx::query(
+            r#"
+            UPDATE _jobs SET status = 'running', attempts = attempts + 1, updated_at = ?
+            WHERE id = (SELECT id FROM _jobs WHERE status = 'queued' AND run_after <= ? ORDER BY created_at LIMIT 1)
+              AND status = 'queued'
+            RETURNING *
+            "#,
+        )
+        .bind(now)
+        .bind(now)
+        .fetch_optional(&self.pool)
+        .await?;
+        Ok(row.as_ref().map(Job::from_row))
+    }
+
+    // 运行任务, 失败时按退避时间重新排队
+    async fn run(&self, job: Job) {
+        let (id, kind, attempts) = (job.id.clone(), job.kind.clone(), job.attempts);
+        let result = match self.tasks.get(kind.as_str()) {
+            Some(task) => task.run(&Context { job, queue: self }).await,
+            None => Err(format!("no task runs jobs of kind '{}'", kind)),
+        };
+        let cancelled = self.cancelled.lock().unwrap().remove(&id);
+        let settings = self.config.get().jobs.clone();
+        let (status, error, delay) = match result {
+            Ok(()) => (Status::Done, None, 0),
+            Err(_) if cancelled => (Status::Cancelled, None, 0),
+            Err(e) if attempts < settings.max_attempts as i64 => {
+                let delay = settings.backoff_secs.saturating_mul(1 << (attempts - 1).min(16)) * 1000;
+                log::warn!("job {} ({}) failed, retrying in {} ms: {}", id, kind, delay, e);
+                (Status::Queued, Some(e), delay)
+            }
+            Err(e) => {
+                log::error!("job {} ({}) failed after {} attempts: {}", id, kind, attempts, e);
+                (Status::Failed, Some(e), 0)
+            }
+        };
+        let now = now_millis() as i64;
+        let updated = sqlx::query("UPDATE _jobs SET status = ?, error = ?, run_after = ?, updated_at = ? WHERE id = ?")
+            .bind(status.as_str())
+            .bind(error)
+            .bind(now + delay as i64)
+            .bind(now)
+            .bind(&id)
+            .execute(&self.pool)
+            .await;
+        if let Err(e) = updated {
+            log::error!("failed to record the outcome of job {}: {}", id, e);
+        }
+    }
+
+    // 删除保留期已过的任务和等待输入超时的任务, 每分钟最多一次
+    async fn purge(&self) -> Result<(), sqlx::Error> {
+        {
+            let mut purged = self.purged.lock().unwrap();
+            if purged.is_some_and(|at| at.elapsed() < Duration::from_secs(60)) {
+                return Ok(());
+            }
+            *purged = Some(Instant::now());
+        }
+        let now = now_millis() as i64;
+        let finished_before = now - self.config.get().jobs.keep_secs.saturating_mul(1000) as i64;
+        let stalled_before = now - RECEIVING_TIMEOUT.as_millis() as i64;
+        let mut tx = self.pool.begin().await?;
+        sqlx::query(
+            r#"
+            DELETE FROM _jobs
+            WHERE (status IN ('done', 'failed', 'cancelled') AND updated_at < ?)
+               OR (status = 'receiving' AND updated_at < ?)
+            "#,
+        )
+        .bind(finished_before)
+        .bind(stalled_before)
+        .execute(&mut *tx)
+        .await?;
+        sqlx::query("DELETE FROM _job_data WHERE job_id NOT IN (SELECT id FROM _jobs WHERE status IN ('receiving', 'queued', 'running'))")
+            .execute(&mut *tx)
+            .await?;
+        tx.commit().await
+    }
+}
+
+/// Queues again the jobs a crash left running, and starts the workers.
+pub fn start(queue: Arc<Queue>) {
+    tokio::spawn(async move {
+        let requeued = sqlx::query("UPDATE _jobs SET status = 'queued', run_after = 0 WHERE status = 'running'")
+            .execute(&queue.pool)
+            .await;
+        match requeued {
+            Ok(result) if result.rows_affected() > 0 => log::info!("queued {} interrupted jobs again", result.rows_affected()),
+            Ok(_) => {}
+            Err(e) => log::error!("failed to queue interrupted jobs again: {}", e),
+        }
+        for _ in 0..queue.config.get().jobs.workers.max(1) {
+            tokio::spawn(work(queue.clone()));
+        }
+    });
+}
+
+async fn work(queue: Arc<Queue>) {
+    loop {
+        match queue.claim().await {
+            Ok(Some(job)) => queue.run(job).await,
+            Ok(None) => {
+                if let Err(e) = queue.purge().await {
+                    log::warn!("failed to purge old jobs: {}", e);
+                }
+                let _ = tokio::time::timeout(POLL, queue.wake.notified()).await;
+            }
+            Err(e) => {
+                log::warn!("failed to fetch a job: {}", e);
+                tokio::time::sleep(POLL).await;
+            }
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ListParams {
+    pub status: Option<Status>,
+    pub kind: Option<String>,
+    pub collection: Option<String>,
+}
+
+// 注册任务接口
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.route("/_jobs", web::get().to(list_jobs))
+        .route("/_jobs/{id}", web::get().to(get_job))
+        .route("/_jobs/{id}/cancel", web::post().to(cancel_job));
+}
+
+// 列出调用者可见的任务, 最新的在前
+async fn list_jobs(req: HttpRequest, params: web::Query<ListParams>, queue: web::Data<Queue>) -> HttpResponse {
+    let rows = sqlx::query(
+        r#"
+        SELECT * FROM _jobs
+        WHERE (? IS NULL OR status = ?) AND (? IS NULL OR kind = ?) AND (? IS NULL OR collection = ?)
+        ORDER BY created_at DESC
+        "#,
+    )
+    .bind(params.status.map(Status::as_str))
+    .bind(params.status.map(Status::as_str))
+    .bind(&params.kind)
+    .bind(&params.kind)
+    .bind(&params.collection)
+    .bind(&params.collection)
+    .fetch_all(&queue.pool)
+    .await;
+    match rows {
+        Ok(rows) => {
+            let jobs = rows.iter().map(Job::from_row).filter(|job| job.visible_to(&req));
+            HttpResponse::Ok().json(jobs.take(MAX_LISTED as usize).collect::<Vec<_>>())
+        }
+        Err(e) => HttpResponse::InternalServerError().json(format!("Failed to list jobs: {}", e)),
+    }
+}
+
+// 查看任务及其进度
+async fn get_job(req: HttpRequest, id: web::Path<String>, queue: web::Data<Queue>) -> HttpResponse {
+    match queue.get(&id).await {
+        Ok(Some(job)) if job.visible_to(&req) => HttpResponse::Ok().json(job),
+        Ok(_) => HttpResponse::NotFound().json(format!("No job '{}'", id)),
+        Err(e) => HttpResponse::InternalServerError().json(format!("Failed to read job: {}", e)),
+    }
+}
+
+// 取消尚未结束的任务
+async fn cancel_job(req: HttpRequest, id: web::Path<String>, queue: web::Data<Queue>) -> HttpResponse {
+    match queue.get(&id).await {
+        Ok(Some(job)) if job.visible_to(&req) => {}
+        Ok(_) => return HttpResponse::NotFound().json(format!("No job '{}'", id)),
+        Err(e) => return HttpResponse::InternalServerError().json(format!("Failed to read job: {}", e)),
+    }
+    match queue.cancel(&id).await {
+        Ok(Some(job)) if matches!(job.status, Status::Done | Status::Failed) => HttpResponse::Conflict().json(job),
+        Ok(Some(job)) => HttpResponse::Ok().json(job),
+        Ok(None) => HttpResponse::NotFound().json(format!("No job '{}'", id)),
+        Err(e) => HttpResponse::InternalServerError().json(format!("Failed to cancel job: {}", e)),
+    }
+}
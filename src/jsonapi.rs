@@ -0,0 +1,59 @@
+//! JSON:API output.
+//!
+//! A read of `GET /{uri}` or `GET /{uri}/{id}` whose `Accept` header names
+//! `application/vnd.api+json` is answered as a JSON:API document: each
+//! document becomes a resource object whose `type` is its collection, whose
+//! `id` is its id as a string and whose `attributes` are its other fields.
+//! The document version moves to the resource's `meta`. Documents refer to
+//! nothing, so resources have no `relationships`.
+//!
+//! ```json
+//! {
+//!   "data": { "type": "users", "id": "7", "attributes": { "name": "Anne" }, "meta": { "version": 2 }, "links": { "self": "/users/7" } },
+//!   "links": { "self": "/users/7" }
+//! }
+//! ```
+
+use actix_web::http::header::ACCEPT;
+use actix_web::{HttpRequest, HttpResponse, HttpResponseBuilder};
+use serde_json::{json, Value};
+
+use crate::store::VERSION_FIELD;
+
+pub const MEDIA_TYPE: &str = "application/vnd.api+json";
+
+/// Whether `req` accepts JSON:API documents.
+pub fn requested(req: &HttpRequest) -> bool {
+    req.headers()
+        .get_all(ACCEPT)
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .any(|media| media.split(';').next().unwrap_or_default().trim().eq_ignore_ascii_case(MEDIA_TYPE))
+}
+
+/// The resource object of `doc` of collection `uri`.
+pub fn resource(uri: &str, doc: Value) -> Value {
+    let Value::Object(mut attributes) = doc else { return doc };
+    let id = attributes.remove("id").unwrap_or_default();
+    let id = id.as_i64().map_or_else(|| id.to_string(), |id| id.to_string());
+    let mut resource = json!({
+        "type": uri,
+        "id": id,
+        "links": { "self": format!("/{}/{}", uri, id) },
+    });
+    if let Some(version) = attributes.remove(VERSION_FIELD) {
+        resource["meta"] = json!({ "version": version });
+    }
+    resource["attributes"] = Value::Object(attributes);
+    resource
+}
+
+/// Finishes `response` to `req` with the JSON:API document holding `data`,
+/// and `meta` unless it is null.
+pub fn respond(response: &mut HttpResponseBuilder, req: &HttpRequest, data: Value, meta: Value) -> HttpResponse {
+    let mut document = json!({ "data": data, "links": { "self": req.uri().to_string() } });
+    if !meta.is_null() {
+        document["meta"] = meta;
+    }
+    response.content_type(MEDIA_TYPE).json(document)
+}
@@ -0,0 +1,621 @@
+//! JSONPath queries.
+//!
+//! `GET /{uri}/_jsonpath?expr=$.user.address.city` evaluates a JSONPath
+//! expression against every document of a collection, with `$` the
+//! document, and lists the values it selects in each document that has
+//! any: `[{"id": 1, "values": ["Oslo"]}]`. With `?documents=true` the
+//! documents themselves are listed instead, so an expression can filter
+//! them: `$[?(@.age >= 18 && @.city == 'Oslo')]`. `?limit=` and `?offset=`
+//! page the list as in `GET /{uri}`, and `X-Total-Count` counts all of it.
+//!
+//! Documents are read through the store, so objects and arrays kept in
+//! tables of their own by [`crate::nested`] are seen in place. Keys are the
+//! stored names whatever the collection's `case`.
+//!
+//! | Syntax                   | Selects                                        |
+//! |--------------------------|------------------------------------------------|
+//! | `.name`, `['name']`      | the member `name`                              |
+//! | `.*`, `[*]`              | every member or element                        |
+//! | `..name`, `..*`          | `name`, or everything, at any depth            |
+//! | `[0]`, `[-1]`, `[0,2]`   | elements by position, from the end when negative |
+//! | `[1:5]`, `[::2]`         | slices, with an optional step                  |
+//! | `[?(@.price < 10)]`      | the elements of an array, or an object itself, for which the filter holds |
+//!
+//! Filters compare `@` (the value tested) or `$` paths with `==`, `!=`,
+//! `<`, `<=`, `>`, `>=` and literals: numbers, strings in single or double
+//! quotes, `true`, `false` and `null`. They combine with `&&`, `||`, `!`
+//! and parentheses, and a bare path is true when it selects anything.
+//! Script expressions and functions are not supported.
+
+use actix_web::{web, HttpResponse};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::cmp::Ordering;
+use std::collections::HashSet;
+
+use crate::config::ConfigHandle;
+use crate::handlers::{route, Page, TOTAL_COUNT};
+use crate::query::QueryError;
+use crate::store::{DocumentStore, StoreError};
+
+/// A parsed JSONPath expression.
+#[derive(Debug, Clone, PartialEq)]
+pub struct JsonPath {
+    segments: Vec<Segment>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Segment {
+    Child(Vec<Selector>),
+    Descendant(Vec<Selector>),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Selector {
+    Name(String),
+    Wildcard,
+    Index(i64),
+    Slice(Option<i64>, Option<i64>, i64),
+    Filter(Expr),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Expr {
+    Exists(Operand),
+    Compare(Operand, Op, Operand),
+    And(Box<Expr>, Box<Expr>),
+    Or(Box<Expr>, Box<Expr>),
+    Not(Box<Expr>),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Operand {
+    /// A path from `@` (`false`) or from `$` (`true`).
+    Path(bool, JsonPath),
+    Literal(Value),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Op {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+impl JsonPath {
+    /// Parses `expr`, which starts with `$`.
+    pub fn parse(expr: &str) -> Result<Self, QueryError> {
+        let mut parser = Parser { chars: expr.chars().collect(), at: 0 };
+        parser.skip_space();
+        if !parser.eat('$') {
+            return Err(QueryError("a JSONPath expression starts with '$'".to_string()));
+        }
+        let path = parser.segments()?;
+        parser.skip_space();
+        match parser.peek() {
+            None => Ok(path),
+            Some(c) => Err(parser.error(&format!("unexpected '{}'", c))),
+        }
+    }
+
+    /// The values the expression selects in `root`, in document order.
+    pub fn select<'a>(&self, root: &'a Value) -> Vec<&'a Value> {
+        self.select_from(root, root)
+    }
+
+    fn select_from<'a>(&self, root: &'a Value, start: &'a Value) -> Vec<&'a Value> {
+        let mut nodes = vec![start];
+        for segment in &self.segments {
+            let mut next = Vec::new();
+            match segment {
+                Segment::Child(selectors) => {
+                    for node in nodes {
+                        for selector in selectors {
+                            selector.apply(root, node, &mut next);
+                        }
+                    }
+                }
+                Segment::Descendant(selectors) => {
+                    let mut found = Vec::new();
+                    for node in nodes {
+                        for node in descendants(node) {
+                            for selector in selectors {
+                                selector.apply(root, node, &mut found);
+                            }
+                        }
+                    }
+                    // 过滤器对数组和对象都会检查同一个对象
+                    let mut seen = HashSet::new();
+                    next.extend(found.into_iter().filter(|value| seen.insert(*value as *const Value)));
+                }
+            }
+            nodes = next;
+        }
+        nodes
+    }
+}
+
+// value 本身和它之下的所有值, 先父后子
+fn descendants(value: &Value) -> Vec<&Value> {
+    let mut all = Vec::new();
+    let mut pending = vec![value];
+    while let Some(value) = pending.pop() {
+        all.push(value);
+        match value {
+            Value::Object(fields) => pending.extend(fields.values().rev()),
+            Value::Array(elements) => pending.extend(elements.iter().rev()),
+            _ => {}
+        }
+    }
+    all
+}
+
+impl Selector {
+    fn apply<'a>(&self, root: &'a Value, node: &'a Value, out: &mut Vec<&'a Value>) {
+        match (self, node) {
+            (Selector::Name(name), Value::Object(fields)) => out.extend(fields.get(name)),
+            (Selector::Wildcard, Value::Object(fields)) => out.extend(fields.values()),
+            (Selector::Wildcard, Value::Array(elements)) => out.extend(elements),
+            (Selector::Index(index), Value::Array(elements)) => {
+                let index = if *index < 0 { elements.len() as i64 + index } else { *index };
+                out.extend(usize::try_from(index).ok().and_then(|index| elements.get(index)));
+            }
+            (Selector::Slice(start, end, step), Value::Array(elements)) => {
+                out.extend(slice(elements.len() as i64, *start, *end, *step).map(|i| &elements[i as usize]))
+            }
+            (Selector::Filter(expr), Value::Array(elements)) => {
+                out.extend(elements.iter().filter(|element| expr.holds(root, element)))
+            }
+            (Selector::Filter(expr), Value::Object(_)) if expr.holds(root, node) => out.push(node),
+            _ => {}
+        }
+    }
+}
+
+// 切片的下标, 与 Python 相同: 负数从末尾算起, 超出范围的被截断
+fn slice(len: i64, start: Option<i64>, end: Option<i64>, step: i64) -> Box<dyn Iterator<Item = i64>> {
+    let bound = |i: i64| if i < 0 { (len + i).max(0) } else { i.min(len) };
+    if step > 0 {
+        let (start, end) = (start.map_or(0, bound), end.map_or(len, bound));
+        Box::new((start..end).step_by(step as usize))
+    } else {
+        let bound = |i: i64| if i < 0 { (len + i).max(-1) } else { i.min(len - 1) };
+        let (start, end) = (start.map_or(len - 1, bound), end.map_or(-1, bound));
+        Box::new((end + 1..=start).rev().step_by(step.unsigned_abs() as usize))
+    }
+}
+
+impl Expr {
+    fn holds(&self, root: &Value, current: &Value) -> bool {
+        match self {
+            Expr::Exists(operand) => operand.value(root, current).is_some(),
+            Expr::Compare(left, op, right) => compare(left.value(root, current), *op, right.value(root, current)),
+            Expr::And(a, b) => a.holds(root, current) && b.holds(root, current),
+            Expr::Or(a, b) => a.holds(root, current) || b.holds(root, current),
+            Expr::Not(expr) => !expr.holds(root, current),
+        }
+    }
+}
+
+impl Operand {
+    // 路径取第一个选中的值
+    fn value<'a>(&'a self, root: &'a Value, current: &'a Value) -> Option<&'a Value> {
+        match self {
+            Operand::Path(false, path) => path.select_from(root, current).into_iter().next(),
+            Operand::Path(true, path) => path.select(root).into_iter().next(),
+            Operand::Literal(value) => Some(value),
+        }
+    }
+}
+
+// 没有值的一方只等于另一个没有值的一方; 大小只在数字之间或字符串之间比较
+fn compare(left: Option<&Value>, op: Op, right: Option<&Value>) -> bool {
+    let order = match (left, right) {
+        (None, None) => Some(Ordering::Equal),
+        (Some(Value::Number(a)), Some(Value::Number(b))) => a.as_f64().partial_cmp(&b.as_f64()),
+        (Some(Value::String(a)), Some(Value::String(b))) => Some(a.cmp(b)),
+        (Some(a), Some(b)) if a == b => Some(Ordering::Equal),
+        _ => None,
+    };
+    match op {
+        Op::Eq => order == Some(Ordering::Equal),
+        Op::Ne => order != Some(Ordering::Equal),
+        Op::Lt => order == Some(Ordering::Less),
+        Op::Le => matches!(order, Some(Ordering::Less | Ordering::Equal)),
+        Op::Gt => order == Some(Ordering::Greater),
+        Op::Ge => matches!(order, Some(Ordering::Greater | Ordering::Equal)),
+    }
+}
+
+struct Parser {
+    chars: Vec<char>,
+    at: usize,
+}
+
+impl Parser {
+    fn error(&self, message: &str) -> QueryError {
+        QueryError(format!("{} at position {} of the JSONPath expression", message, self.at))
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.at).copied()
+    }
+
+    fn eat(&mut self, c: char) -> bool {
+        let found = self.peek() == Some(c);
+        if found {
+            self.at += 1;
+        }
+        found
+    }
+
+    fn eat_str(&mut self, s: &str) -> bool {
+        let found = s.chars().enumerate().all(|(i, c)| self.chars.get(self.at + i) == Some(&c));
+        if found {
+            self.at += s.chars().count();
+        }
+        found
+    }
+
+    fn skip_space(&mut self) {
+        while self.peek().is_some_and(char::is_whitespace) {
+            self.at += 1;
+        }
+    }
+
+    fn expect(&mut self, c: char) -> Result<(), QueryError> {
+        self.skip_space();
+        match self.eat(c) {
+            true => Ok(()),
+            false => Err(self.error(&format!("expected '{}'", c))),
+        }
+    }
+
+    // '$' 或 '@' 之后的各级
+    fn segments(&mut self) -> Result<JsonPath, QueryError> {
+        let mut segments = Vec::new();
+        loop {
+            if self.eat_str("..") {
+                let selectors = match self.peek() {
+                    Some('[') => self.bracket()?,
+                    _ => vec![self.dotted()?],
+                };
+                segments.push(Segment::Descendant(selectors));
+            } else if self.eat('.') {
+                segments.push(Segment::Child(vec![self.dotted()?]));
+            } else if self.peek() == Some('[') {
+                segments.push(Segment::Child(self.bracket()?));
+            } else {
+                return Ok(JsonPath { segments });
+            }
+        }
+    }
+
+    // '.' 之后的名字或 '*'
+    fn dotted(&mut self) -> Result<Selector, QueryError> {
+        if self.eat('*') {
+            return Ok(Selector::Wildcard);
+        }
+        let start = self.at;
+        while self.peek().is_some_and(|c| c.is_alphanumeric() || c == '_' || c == '-') {
+            self.at += 1;
+        }
+        match self.at > start {
+            true => Ok(Selector::Name(self.chars[start..self.at].iter().collect())),
+            false => Err(self.error("expected a name or '*'")),
+        }
+    }
+
+    fn bracket(&mut self) -> Result<Vec<Selector>, QueryError> {
+        self.eat('[');
+        let mut selectors = Vec::new();
+        loop {
+            self.skip_space();
+            let selector = match self.peek() {
+                Some('*') => {
+                    self.at += 1;
+                    Selector::Wildcard
+                }
+                Some('\'' | '"') => Selector::Name(self.string()?),
+                Some('?') => {
+                    self.at += 1;
+                    Selector::Filter(self.or()?)
+                }
+                _ => self.index_or_slice()?,
+            };
+            selectors.push(selector);
+            self.skip_space();
+            if self.eat(']') {
+                return Ok(selectors);
+            }
+            self.expect(',')?;
+        }
+    }
+
+    fn index_or_slice(&mut self) -> Result<Selector, QueryError> {
+        let start = self.integer()?;
+        self.skip_space();
+        if !self.eat(':') {
+            return start.map(Selector::Index).ok_or_else(|| self.error("expected an index, a name or a filter"));
+        }
+        let end = self.integer()?;
+        self.skip_space();
+        let step = match self.eat(':') {
+            true => self.integer()?.unwrap_or(1),
+            false => 1,
+        };
+        match step {
+            0 => Err(self.error("a slice step cannot be 0")),
+            step => Ok(Selector::Slice(start, end, step)),
+        }
+    }
+
+    fn integer(&mut self) -> Result<Option<i64>, QueryError> {
+        self.skip_space();
+        let start = self.at;
+        self.eat('-');
+        while self.peek().is_some_and(|c| c.is_ascii_digit()) {
+            self.at += 1;
+        }
+        match self.at > start {
+            true => {
+                let text: String = self.chars[start..self.at].iter().collect();
+                text.parse().map(Some).map_err(|_| self.error(&format!("bad integer '{}'", text)))
+            }
+            false => Ok(None),
+        }
+    }
+
+    // 单引号或双引号中的字符串, 反斜杠转义下一个字符
+    fn string(&mut self) -> Result<String, QueryError> {
+        let quote = self.peek().unwrap_or('\'');
+        self.at += 1;
+        let mut text = String::new();
+        loop {
+            match self.peek() {
+                None => return Err(self.error("unterminated string")),
+                Some(c) if c == quote => {
+                    self.at += 1;
+                    return Ok(text);
+                }
+                Some('\\') => {
+                    self.at += 1;
+                    let escaped = self.peek().ok_or_else(|| self.error("unterminated string"))?;
+                    text.push(match escaped {
+                        'n' => '\n',
+                        't' => '\t',
+                        c => c,
+                    });
+                    self.at += 1;
+                }
+                Some(c) => {
+                    text.push(c);
+                    self.at += 1;
+                }
+            }
+        }
+    }
+
+    fn or(&mut self) -> Result<Expr, QueryError> {
+        let mut expr = self.and()?;
+        loop {
+            self.skip_space();
+            if !self.eat_str("||") {
+                return Ok(expr);
+            }
+            expr = Expr::Or(Box::new(expr), Box::new(self.and()?));
+        }
+    }
+
+    fn and(&mut self) -> Result<Expr, QueryError> {
+        let mut expr = self.unary()?;
+        loop {
+            self.skip_space();
+            if !self.eat_str("&&") {
+                return Ok(expr);
+            }
+            expr = Expr::And(Box::new(expr), Box::new(self.unary()?));
+        }
+    }
+
+    fn unary(&mut self) -> Result<Expr, QueryError> {
+        self.skip_space();
+        if self.peek() == Some('!') && self.chars.get(self.at + 1) != Some(&'=') {
+            self.at += 1;
+            return Ok(Expr::Not(Box::new(self.unary()?)));
+        }
+        if self.eat('(') {
+            let expr = self.or()?;
+            self.expect(')')?;
+            return Ok(expr);
+        }
+        let left = self.operand()?;
+        self.skip_space();
+        let op = [("==", Op::Eq), ("!=", Op::Ne), ("<=", Op::Le), (">=", Op::Ge), ("<", Op::Lt), (">", Op::Gt)]
+            .into_iter()
+            .find(|(text, _)| self.eat_str(text))
+            .map(|(_, op)| op);
+        match op {
+            Some(op) => Ok(Expr::Compare(left, op, self.operand()?)),
+            None if matches!(left, Operand::Path(..)) => Ok(Expr::Exists(left)),
+            None => Err(self.error("expected a comparison")),
+        }
+    }
+
+    fn operand(&mut self) -> Result<Operand, QueryError> {
+        self.skip_space();
+        match self.peek() {
+            Some('@') => {
+                self.at += 1;
+                Ok(Operand::Path(false, self.segments()?))
+            }
+            Some('$') => {
+                self.at += 1;
+                Ok(Operand::Path(true, self.segments()?))
+            }
+            Some('\'' | '"') => Ok(Operand::Literal(Value::String(self.string()?))),
+            Some(c) if c == '-' || c.is_ascii_digit() => {
+                let start = self.at;
+                self.at += 1;
+                while self.peek().is_some_and(|c| c.is_ascii_digit() || matches!(c, '.' | 'e' | 'E' | '+' | '-')) {
+                    self.at += 1;
+                }
+                let text: String = self.chars[start..self.at].iter().collect();
+                serde_json::from_str::<serde_json::Number>(&text)
+                    .map(|number| Operand::Literal(Value::Number(number)))
+                    .map_err(|_| self.error(&format!("bad number '{}'", text)))
+            }
+            _ => {
+                for (word, value) in [("true", Value::Bool(true)), ("false", Value::Bool(false)), ("null", Value::Null)] {
+                    if self.eat_str(word) {
+                        return Ok(Operand::Literal(value));
+                    }
+                }
+                Err(self.error("expected '@', '$' or a literal"))
+            }
+        }
+    }
+}
+
+// 注册集合下的 JSONPath 接口, 与其他文档接口一样检查 ACL
+pub fn configure_documents(cfg: &mut web::ServiceConfig) {
+    cfg.route(&route("/{uri}/_jsonpath"), web::get().to(query_collection));
+}
+
+#[derive(Debug, Deserialize)]
+pub struct JsonPathParams {
+    pub expr: String,
+    pub documents: Option<String>,
+}
+
+/// The values an expression selects in one document.
+#[derive(Debug, Serialize)]
+pub struct Selected {
+    pub id: Value,
+    pub values: Vec<Value>,
+}
+
+// 对集合的每个文档求 JSONPath 表达式
+async fn query_collection(
+    uri: web::Path<String>,
+    params: web::Query<JsonPathParams>,
+    page: web::Query<Vec<(String, String)>>,
+    store: web::Data<dyn DocumentStore>,
+    config: Option<web::Data<ConfigHandle>>,
+) -> HttpResponse {
+    let path = match JsonPath::parse(&params.expr) {
+        Ok(path) => path,
+        Err(e) => return HttpResponse::BadRequest().json(e.to_string()),
+    };
+    let documents = matches!(params.documents.as_deref(), Some("" | "true" | "1"));
+    let page = match Page::from_params(&page, config.as_ref()) {
+        Ok(page) => page,
+        Err(response) => return response,
+    };
+    let docs = match store.list(&uri).await {
+        Ok(docs) => docs,
+        Err(StoreError::NotFound) => return HttpResponse::NotFound().json(format!("No collection '{}'", uri)),
+        Err(e) => return HttpResponse::InternalServerError().json(format!("Failed to query data: {}", e)),
+    };
+    let result: Vec<Value> = match documents {
+        true => docs.into_iter().filter(|doc| !path.select(doc).is_empty()).collect(),
+        false => docs
+            .iter()
+            .filter_map(|doc| {
+                let values: Vec<Value> = path.select(doc).into_iter().cloned().collect();
+                let id = doc.get("id").cloned().unwrap_or_default();
+                (!values.is_empty()).then(|| serde_json::to_value(Selected { id, values }).unwrap_or_default())
+            })
+            .collect(),
+    };
+    let total = result.len();
+    HttpResponse::Ok().insert_header((TOTAL_COUNT, total)).json(page.apply(result))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn select(expr: &str, doc: &Value) -> Vec<Value> {
+        JsonPath::parse(expr).unwrap().select(doc).into_iter().cloned().collect()
+    }
+
+    fn store() -> Value {
+        json!({
+            "owner": "ann",
+            "book": [
+                { "title": "A", "price": 8, "author": "ann", "tags": ["x"] },
+                { "title": "B", "price": 12.5, "isbn": "1" },
+                { "title": "C", "price": 30, "author": "bob" },
+            ],
+            "bicycle": { "color": "red", "price": 20 },
+        })
+    }
+
+    #[test]
+    fn selects_members_and_elements() {
+        let doc = store();
+        assert_eq!(select("$.bicycle.color", &doc), vec![json!("red")]);
+        assert_eq!(select("$['bicycle']['price']", &doc), vec![json!(20)]);
+        assert_eq!(select("$.book[*].title", &doc), vec![json!("A"), json!("B"), json!("C")]);
+        assert_eq!(select("$.book[-1].title", &doc), vec![json!("C")]);
+        assert_eq!(select("$.book[0,2].title", &doc), vec![json!("A"), json!("C")]);
+        assert_eq!(select("$.book[5]", &doc), Vec::<Value>::new());
+        assert_eq!(select("$", &doc), vec![doc.clone()]);
+    }
+
+    #[test]
+    fn slices_work_like_python() {
+        let doc = json!([0, 1, 2, 3, 4, 5]);
+        assert_eq!(select("$[1:3]", &doc), vec![json!(1), json!(2)]);
+        assert_eq!(select("$[::2]", &doc), vec![json!(0), json!(2), json!(4)]);
+        assert_eq!(select("$[-2:]", &doc), vec![json!(4), json!(5)]);
+        assert_eq!(select("$[::-2]", &doc), vec![json!(5), json!(3), json!(1)]);
+        assert_eq!(select("$[4:1:-1]", &doc), vec![json!(4), json!(3), json!(2)]);
+        assert_eq!(select("$[10:20]", &doc), Vec::<Value>::new());
+    }
+
+    #[test]
+    fn descendants_are_found_once_in_document_order() {
+        let doc = store();
+        assert_eq!(select("$..price", &doc), vec![json!(20), json!(8), json!(12.5), json!(30)]);
+        assert_eq!(select("$..tags[0]", &doc), vec![json!("x")]);
+        assert_eq!(select("$..[?(@.price > 15)].price", &doc), vec![json!(20), json!(30)]);
+    }
+
+    #[test]
+    fn filters_compare_paths_and_literals() {
+        let doc = store();
+        assert_eq!(select("$.book[?(@.price < 10)].title", &doc), vec![json!("A")]);
+        assert_eq!(select("$.book[?(@.isbn)].title", &doc), vec![json!("B")]);
+        assert_eq!(select("$.book[?(!@.author)].title", &doc), vec![json!("B")]);
+        assert_eq!(select("$.book[?(@.author == $.owner)].title", &doc), vec![json!("A")]);
+        assert_eq!(select("$.book[?(@.author != 'ann')].title", &doc), vec![json!("B"), json!("C")]);
+        assert_eq!(
+            select("$.book[?(@.price >= 12.5 && (@.author == \"bob\" || @.isbn == '1'))].title", &doc),
+            vec![json!("B"), json!("C")]
+        );
+        // 数字和字符串之间不比较大小
+        assert_eq!(select("$.book[?(@.title > 1)]", &doc), Vec::<Value>::new());
+        assert_eq!(select("$.bicycle[?(@.color == 'red')].price", &doc), vec![json!(20)]);
+    }
+
+    #[test]
+    fn malformed_expressions_are_rejected() {
+        for (expr, message) in [
+            ("book", "a JSONPath expression starts with '$'"),
+            ("$.", "expected a name or '*' at position 2 of the JSONPath expression"),
+            ("$[1:2:0]", "a slice step cannot be 0 at position 7 of the JSONPath expression"),
+            ("$['a]", "unterminated string at position 5 of the JSONPath expression"),
+            ("$[?(@.a == )]", "expected '@', '$' or a literal at position 11 of the JSONPath expression"),
+            ("$[?(1)]", "expected a comparison at position 5 of the JSONPath expression"),
+            ("$.a b", "unexpected 'b' at position 4 of the JSONPath expression"),
+        ] {
+            assert_eq!(JsonPath::parse(expr), Err(QueryError(message.to_string())), "{}", expr);
+        }
+    }
+}
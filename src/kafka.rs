@@ -0,0 +1,207 @@
+//! Kafka ingestion.
+//!
+//! With `kafka.sources` configured, the server consumes every partition of
+//! each source topic and stores the records as documents, the payload
+//! converted like an MQTT message. The offset of the last record handled in
+//! each partition is kept in `_kafka_offsets`, and consumption resumes after
+//! it on restart; Kafka's own group offsets are not used. A crash between an
+//! insert and its checkpoint stores that record again, so collections that
+//! must not see duplicates should enable `dedup`.
+//!
+//! Partitions are read when the server starts; partitions added to a topic
+//! later are picked up on the next restart. The consumer needs the `kafka`
+//! cargo feature; without it [`start`] only warns that sources are configured.
+
+use serde_json::Value;
+use sqlx::SqlitePool;
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use crate::config::{ConfigHandle, KafkaSource};
+use crate::logging::now;
+use crate::metrics::Metrics;
+use crate::models::payload_document;
+use crate::store::DocumentStore;
+
+/// Starts consuming the configured sources, if any.
+#[cfg(feature = "kafka")]
+pub fn start(config: Arc<ConfigHandle>, store: Arc<dyn DocumentStore>, pool: SqlitePool, metrics: Arc<Metrics>) {
+    let Some(settings) = config.get().kafka.clone().filter(|k| !k.sources.is_empty()) else {
+        return;
+    };
+    source::spawn(settings, config, store, pool, metrics);
+}
+
+#[cfg(not(feature = "kafka"))]
+pub fn start(config: Arc<ConfigHandle>, _store: Arc<dyn DocumentStore>, _pool: SqlitePool, _metrics: Arc<Metrics>) {
+    if config.get().kafka.as_ref().is_some_and(|k| !k.sources.is_empty()) {
+        log::warn!("kafka sources are configured but the server was built without the `kafka` feature");
+    }
+}
+
+/// The document stored for a record.
+pub fn document(source: &KafkaSource, key: Option<&[u8]>, payload: &[u8]) -> Value {
+    let mut doc = payload_document(payload);
+    if let (Some(field), Some(key)) = (&source.key_field, key) {
+        doc[field.as_str()] = Value::String(String::from_utf8_lossy(key).into_owned());
+    }
+    doc
+}
+
+/// Last checkpointed offset of each partition of `topic`.
+pub async fn offsets(pool: &SqlitePool, topic: &str) -> Result<HashMap<i32, i64>, sqlx::Error> {
+    let rows: Vec<(i32, i64)> = sqlx::query_as(r#"SELECT partition, "offset" FROM _kafka_offsets WHERE topic = ?"#)
+        .bind(topic)
+        .fetch_all(pool)
+        .await?;
+    Ok(rows.into_iter().collect())
+}
+
+// 记录某个分区已处理到的 offset
+pub async fn checkpoint(pool: &SqlitePool, topic: &str, partition: i32, offset: i64) -> Result<(), sqlx::Error> {
+    sqlx::query(
+        r#"
+        INSERT INTO _kafka_offsets (topic, partition, "offset", updated_at) VALUES (?, ?, ?, ?)
+        ON CONFLICT (topic, partition) DO UPDATE SET "offset" = excluded."offset", updated_at = excluded.updated_at
+        "#,
+    )
+    .bind(topic)
+    .bind(partition)
+    .bind(offset)
+    .bind(now() as i64)
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+#[cfg(feature = "kafka")]
+mod source {
+    use rdkafka::consumer::{Consumer, StreamConsumer};
+    use rdkafka::{ClientConfig, Message, Offset, TopicPartitionList};
+    use sqlx::SqlitePool;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use super::{checkpoint, document, offsets};
+    use crate::config::{ConfigHandle, Kafka};
+    use crate::handlers::store_document;
+    use crate::metrics::Metrics;
+    use crate::store::DocumentStore;
+
+    const METADATA_TIMEOUT: Duration = Duration::from_secs(10);
+    const RETRY_DELAY: Duration = Duration::from_secs(5);
+
+    pub fn spawn(
+        settings: Kafka,
+        config: Arc<ConfigHandle>,
+        store: Arc<dyn DocumentStore>,
+        pool: SqlitePool,
+        metrics: Arc<Metrics>,
+    ) {
+        let mut client = ClientConfig::new();
+        client
+            .set("bootstrap.servers", &settings.brokers)
+            .set("group.id", &settings.group_id)
+            .set("enable.auto.commit", "false");
+        for (key, value) in &settings.properties {
+            client.set(key, value);
+        }
+        let consumer: Arc<StreamConsumer> = match client.create() {
+            Ok(consumer) => Arc::new(consumer),
+            Err(e) => {
+                log::error!("kafka consumer could not be created: {}", e);
+                return;
+            }
+        };
+
+        tokio::spawn(async move {
+            // broker 不可用时一直重试, 直到分配好所有分区
+            loop {
+                match assignment(&settings, &consumer, &pool).await {
+                    Ok(assignment) => match consumer.assign(&assignment) {
+                        Ok(()) => break,
+                        Err(e) => log::warn!("kafka failed to assign partitions: {}", e),
+                    },
+                    Err(e) => log::warn!("kafka failed to read partitions: {}", e),
+                }
+                tokio::time::sleep(RETRY_DELAY).await;
+            }
+            log::info!("kafka consuming {} topic(s) from {}", settings.sources.len(), settings.brokers);
+
+            loop {
+                let message = match consumer.recv().await {
+                    Ok(message) => message,
+                    Err(e) => {
+                        log::warn!("kafka receive failed: {}", e);
+                        tokio::time::sleep(RETRY_DELAY).await;
+                        continue;
+                    }
+                };
+                let Some(source) = settings.sources.iter().find(|s| s.topic == message.topic()) else {
+                    continue;
+                };
+                let collection = source.collection();
+                let doc = document(source, message.key(), message.payload().unwrap_or_default());
+                let dedup = config.get().collections.get(&collection).and_then(|c| c.dedup.clone());
+                let status = match store_document(store.as_ref(), dedup.as_ref(), &collection, &doc).await {
+                    Ok(_) => "stored",
+                    Err(e) => {
+                        log::warn!(
+                            "kafka failed to store {}/{}@{} in '{}': {}",
+                            message.topic(),
+                            message.partition(),
+                            message.offset(),
+                            collection,
+                            e
+                        );
+                        "failed"
+                    }
+                };
+                metrics.incr(
+                    "kafka_records_total",
+                    vec![("collection", collection), ("status", status.to_string())],
+                    1.0,
+                );
+                if let Err(e) = checkpoint(&pool, message.topic(), message.partition(), message.offset()).await {
+                    log::warn!("kafka failed to checkpoint {}/{}: {}", message.topic(), message.partition(), e);
+                }
+            }
+        });
+    }
+
+    // 每个分区从上次记录的 offset 之后开始, 没有记录的从头开始
+    async fn assignment(
+        settings: &Kafka,
+        consumer: &Arc<StreamConsumer>,
+        pool: &SqlitePool,
+    ) -> Result<TopicPartitionList, Box<dyn std::error::Error + Send + Sync>> {
+        let mut list = TopicPartitionList::new();
+        for source in &settings.sources {
+            let topic = source.topic.clone();
+            let metadata_consumer = consumer.clone();
+            let metadata = tokio::task::spawn_blocking(move || {
+                metadata_consumer.fetch_metadata(Some(&topic), METADATA_TIMEOUT)
+            })
+            .await??;
+            let partitions: Vec<i32> = metadata
+                .topics()
+                .iter()
+                .filter(|t| t.name() == source.topic && t.error().is_none())
+                .flat_map(|t| t.partitions().iter().map(|p| p.id()))
+                .collect();
+            if partitions.is_empty() {
+                return Err(format!("topic '{}' has no partitions", source.topic).into());
+            }
+
+            let done = offsets(pool, &source.topic).await?;
+            for partition in partitions {
+                let offset = match done.get(&partition) {
+                    Some(offset) => Offset::Offset(offset + 1),
+                    None => Offset::Beginning,
+                };
+                list.add_partition_offset(&source.topic, partition, offset)?;
+            }
+        }
+        Ok(list)
+    }
+}
@@ -0,0 +1,93 @@
+pub mod access_log;
+#[cfg(feature = "sqlite")]
+pub mod aggregate;
+#[cfg(feature = "sqlite")]
+pub mod acl;
+#[cfg(feature = "sqlite")]
+pub mod admin;
+#[cfg(feature = "sqlite")]
+pub mod alerts;
+#[cfg(feature = "sqlite")]
+pub mod analytics;
+#[cfg(feature = "sqlite")]
+pub mod archive;
+pub mod auth;
+pub mod cache;
+pub mod casing;
+pub mod cdc;
+#[cfg(feature = "sqlite")]
+pub mod cluster;
+#[cfg(feature = "sqlite")]
+pub mod codegen;
+#[cfg(feature = "sqlite")]
+pub mod collections;
+pub mod compression;
+pub mod config;
+pub mod consistency;
+pub mod cron;
+#[cfg(feature = "sqlite")]
+pub mod database;
+pub mod fanout;
+pub mod handlers;
+#[cfg(feature = "sqlite")]
+pub mod history;
+#[cfg(feature = "sqlite")]
+pub mod idempotency;
+#[cfg(feature = "sqlite")]
+pub mod integrity;
+#[cfg(feature = "sqlite")]
+pub mod import;
+pub mod ip_filter;
+#[cfg(feature = "sqlite")]
+pub mod jobs;
+pub mod jsonapi;
+pub mod jsonpath;
+#[cfg(feature = "sqlite")]
+pub mod kafka;
+pub mod logging;
+pub mod metrics;
+pub mod models;
+pub mod mqtt;
+#[cfg(feature = "sqlite")]
+pub mod nested;
+pub mod odata;
+#[cfg(feature = "oidc")]
+pub mod oidc;
+#[cfg(feature = "sqlite")]
+pub mod partition;
+pub mod patch;
+pub mod query;
+#[cfg(feature = "sqlite")]
+pub mod profile;
+#[cfg(feature = "sqlite")]
+pub mod quota;
+pub mod rate_limit;
+pub mod reporting;
+#[cfg(feature = "sqlite")]
+pub mod rpc;
+pub mod sampling;
+#[cfg(feature = "sqlite")]
+pub mod schedule;
+#[cfg(feature = "sqlite")]
+pub mod schema;
+#[cfg(feature = "sqlite")]
+pub mod search;
+pub mod sessions;
+#[cfg(feature = "sqlite")]
+pub mod shard;
+#[cfg(feature = "sqlite")]
+pub mod snapshot;
+#[cfg(feature = "sqlite")]
+pub mod sql;
+pub mod store;
+pub mod telemetry;
+#[cfg(feature = "sqlite")]
+pub mod transaction;
+#[cfg(feature = "tls")]
+pub mod tls;
+#[cfg(feature = "sqlite")]
+pub mod webhook;
+pub mod writer;
+
+#[cfg(any(all(test, feature = "sqlite"), feature = "test-support"))]
+pub mod testing;
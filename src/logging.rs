@@ -0,0 +1,154 @@
+use log::{LevelFilter, Log, Metadata, Record};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::config::LogFile;
+
+// 简单日志器, 默认输出到 stderr, 配置了 log_file 时写入文件; 级别和输出都可在运行时修改
+struct Logger {
+    file: Mutex<Option<FileSink>>,
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= log::max_level()
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let ts = now();
+        let line = format!("[{} {} {}] {}\n", ts, record.level(), record.target(), record.args());
+
+        let mut file = self.file.lock().unwrap_or_else(|e| e.into_inner());
+        match file.as_mut() {
+            Some(sink) => {
+                if let Err(e) = sink.write(&line, ts) {
+                    eprint!("failed to write log file {}: {}\n{}", sink.settings.path.display(), e, line);
+                }
+            }
+            None => eprint!("{}", line),
+        }
+    }
+
+    fn flush(&self) {
+        if let Some(sink) = self.file.lock().unwrap_or_else(|e| e.into_inner()).as_mut() {
+            let _ = sink.file.flush();
+        }
+    }
+}
+
+static LOGGER: Logger = Logger { file: Mutex::new(None) };
+
+/// Installs the global logger. Calling it more than once is harmless.
+pub fn init(level: LevelFilter) {
+    let _ = log::set_logger(&LOGGER);
+    set_level(level);
+}
+
+pub fn set_level(level: LevelFilter) {
+    log::set_max_level(level);
+}
+
+/// Sends log output to `settings`, or back to stderr with `None`.
+///
+/// Unchanged settings keep the open file. If the new file cannot be opened the
+/// current output stays in place.
+pub fn set_file(settings: Option<&LogFile>) -> io::Result<()> {
+    let mut current = LOGGER.file.lock().unwrap_or_else(|e| e.into_inner());
+    if current.as_ref().map(|sink| &sink.settings) == settings {
+        return Ok(());
+    }
+    *current = match settings {
+        Some(settings) => Some(FileSink::open(settings.clone())?),
+        None => None,
+    };
+    Ok(())
+}
+
+/// Append-only log file with size and time based rotation.
+pub(crate) struct FileSink {
+    pub(crate) settings: LogFile,
+    file: File,
+    size: u64,
+    /// Rotation period the current file belongs to.
+    period: Option<u64>,
+}
+
+impl FileSink {
+    pub(crate) fn open(settings: LogFile) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(&settings.path)?;
+        let metadata = file.metadata()?;
+        // 按文件最后修改时间计算所属周期, 重启后跨周期的文件也会被轮转
+        let modified = metadata
+            .modified()
+            .ok()
+            .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+            .map(|d| d.as_secs())
+            .unwrap_or_else(now);
+        let period = settings.rotate.period().map(|p| modified / p);
+        Ok(Self { settings, file, size: metadata.len(), period })
+    }
+
+    pub(crate) fn write(&mut self, line: &str, ts: u64) -> io::Result<()> {
+        let period = self.settings.rotate.period().map(|p| ts / p);
+        let max = self.settings.max_bytes;
+        if period != self.period || (max > 0 && self.size > 0 && self.size + line.len() as u64 > max) {
+            self.rotate()?;
+            self.period = period;
+        }
+        self.file.write_all(line.as_bytes())?;
+        self.size += line.len() as u64;
+        Ok(())
+    }
+
+    // app.log -> app.log.1 -> app.log.2 ...; 超出 keep 的最旧文件被覆盖或删除
+    fn rotate(&mut self) -> io::Result<()> {
+        let path = &self.settings.path;
+        let keep = self.settings.keep;
+        if keep == 0 {
+            remove_if_exists(path)?;
+        } else {
+            for i in (1..keep).rev() {
+                rename_if_exists(&numbered(path, i), &numbered(path, i + 1))?;
+            }
+            rename_if_exists(path, &numbered(path, 1))?;
+        }
+        remove_if_exists(&numbered(path, keep + 1))?;
+
+        self.file = OpenOptions::new().create(true).append(true).open(path)?;
+        self.size = 0;
+        Ok(())
+    }
+}
+
+fn numbered(path: &Path, n: usize) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(format!(".{}", n));
+    PathBuf::from(name)
+}
+
+fn rename_if_exists(from: &Path, to: &Path) -> io::Result<()> {
+    match fs::rename(from, to) {
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+        result => result,
+    }
+}
+
+fn remove_if_exists(path: &Path) -> io::Result<()> {
+    match fs::remove_file(path) {
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+        result => result,
+    }
+}
+
+pub(crate) fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
@@ -1,25 +1,184 @@
+use actix_web::middleware::from_fn;
 use actix_web::{web, App, HttpServer};
 use dotenv::dotenv;
-use crate::database::init_db;
-use crate::handlers::{insert_json, get_all_json, get_json_by_id};
-
-mod database;
-mod models;
-mod handlers;
+use json_storage::config::ConfigHandle;
+use json_storage::database::{database_options, init_db_with, SqliteStore};
+use json_storage::store::DocumentStore;
+use json_storage::metrics::Metrics;
+use json_storage::{
+    access_log, acl, admin, aggregate, alerts, analytics, archive, auth, cache, cdc, cluster, codegen, collections, consistency, fanout, handlers, history, idempotency, import, integrity, ip_filter, jobs, jsonpath, kafka, logging, metrics, mqtt, nested, partition, profile, quota, rate_limit, reporting, rpc, schedule, schema, search, sessions, shard, snapshot, sql,
+    telemetry, transaction, webhook, writer,
+};
+use std::sync::Arc;
+use std::time::Duration;
 
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
     dotenv().ok();
-    let pool = init_db().await.expect("Failed to initialize database");
+    // 子命令: codegen 生成类型定义后退出
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if args.first().map(String::as_str) == Some("codegen") {
+        if let Err(e) = codegen::run(&args[1..]).await {
+            eprintln!("{}", e);
+            std::process::exit(2);
+        }
+        return Ok(());
+    }
+    // 子命令: restore 从 WAL 归档恢复数据库后退出
+    if args.first().map(String::as_str) == Some("restore") {
+        if let Err(e) = archive::run(&args[1..]).await {
+            eprintln!("{}", e);
+            std::process::exit(2);
+        }
+        return Ok(());
+    }
+    let config = Arc::new(ConfigHandle::from_env().expect("Failed to load config"));
+    logging::init(config.get().level_filter().expect("Invalid log level"));
+    config.apply().expect("Invalid config");
+    config.clone().watch(Duration::from_secs(2));
+    let telemetry = telemetry::init();
+    let _reporting = reporting::init();
+
+    let mut options = database_options().expect("Invalid DATABASE_URL");
+    if config.get().archive.is_some() {
+        options = archive::connect_options(options);
+    }
+    let pool = init_db_with(options.clone()).await.expect("Failed to initialize database");
+    let readonly = web::Data::new(sql::ReadOnlyDatabase::new(&options));
+    let metrics = web::Data::new(Metrics::new());
+    let archiver = archive::start(&config, &options, metrics.clone().into_inner())
+        .await
+        .expect("Failed to start WAL archiving");
+    let shards = Arc::new(shard::Shards::open(&config).await.expect("Failed to open shards"));
+    let store: Arc<dyn DocumentStore> = Arc::new(SqliteStore::new(pool.clone()));
+    let store: Arc<dyn DocumentStore> = Arc::new(shard::ShardedStore::new(store, shards.clone()));
+    let partitions = Arc::new(partition::Partitions::open(&config, pool.clone()).await.expect("Failed to load partitions"));
+    let store: Arc<dyn DocumentStore> = Arc::new(partition::PartitionedStore::new(store, partitions.clone()));
+    partition::start(partitions.clone(), metrics.clone().into_inner());
+    let store = nested::wrap(config.clone(), store, pool.clone());
+    let store = history::wrap(config.clone(), store, pool.clone());
+    let (store, checksums) = integrity::wrap(config.clone(), store, pool.clone());
+    let feed = fanout::feed(&config, metrics.clone().into_inner()).await;
+    let store = cdc::wrap(&config, store, feed.clone(), metrics.clone().into_inner());
+    let store = writer::wrap(&config, store, metrics.clone().into_inner());
+    let (store, node) = cluster::start(&config, store, pool.clone(), feed.clone(), metrics.clone().into_inner())
+        .await
+        .expect("Failed to start cluster");
+    let store = cache::wrap(&config, store, metrics.clone().into_inner()).await;
+    let store = consistency::wrap(store);
+    mqtt::start(config.clone(), store.clone(), metrics.clone().into_inner());
+    kafka::start(config.clone(), store.clone(), pool.clone(), metrics.clone().into_inner());
+    let sessions = web::Data::new(sessions::SessionStore::new(config.get().sessions.secret.as_deref()));
+    let queue = jobs::Queue::new(pool.clone(), config.clone())
+        .register(import::KIND, Arc::new(import::ImportTask::new(store.clone(), config.clone())))
+        .register(schedule::KIND, Arc::new(schedule::ScheduleTask::new(store.clone(), config.clone())))
+        .register(webhook::KIND, Arc::new(webhook::WebhookTask::new()));
+    let queue = Arc::new(queue);
+    jobs::start(queue.clone());
+    schedule::start(queue.clone(), config.clone());
+    let alerts = Arc::new(alerts::Alerts::new(
+        config.clone(),
+        pool.clone(),
+        shards.clone(),
+        partitions.clone(),
+        metrics.clone().into_inner(),
+        queue.clone(),
+    ));
+    alerts::start(alerts.clone());
+    let usage = web::Data::new(quota::UsageCache::new());
+    let limiter = web::Data::new(rate_limit::RateLimiter::new());
+    let analytics = Arc::new(analytics::Analytics::new(pool.clone()));
+    analytics::start(analytics.clone());
+    let recorder = web::Data::from(analytics.clone());
+    let transactions = Arc::new(transaction::Transactions::new());
+    transaction::start(transactions.clone());
 
-    HttpServer::new(move || {
+    let tls = config.get().tls.clone();
+    let addr = config.get().listen.clone();
+    let server = HttpServer::new(move || {
         App::new()
             .app_data(web::Data::new(pool.clone()))
-            .route("/{uri}", web::post().to(insert_json))
-            .route("/{uri}", web::get().to(get_all_json))
-            .route("/{uri}/{id}", web::get().to(get_json_by_id))
-    })
-    .bind("127.0.0.1:8080")?
-    .run()
-    .await
-}
\ No newline at end of file
+            .app_data(readonly.clone())
+            .app_data(web::Data::from(store.clone()))
+            .app_data(web::Data::from(feed.clone()))
+            .app_data(web::Data::from(shards.clone()))
+            .app_data(web::Data::from(partitions.clone()))
+            .app_data(web::Data::from(checksums.clone()))
+            .app_data(web::Data::from(config.clone()))
+            .app_data(metrics.clone())
+            .app_data(sessions.clone())
+            .app_data(web::Data::from(queue.clone()))
+            .app_data(web::Data::from(alerts.clone()))
+            .app_data(usage.clone())
+            .app_data(limiter.clone())
+            .app_data(recorder.clone())
+            .app_data(web::Data::from(transactions.clone()))
+            .wrap(from_fn(rate_limit::enforce))
+            .wrap(from_fn(auth::authenticate))
+            .wrap(from_fn(ip_filter::enforce))
+            .wrap(from_fn(metrics::track))
+            .wrap(from_fn(access_log::log_request))
+            .wrap(from_fn(reporting::capture))
+            .wrap(from_fn(telemetry::trace))
+            .configure(metrics::configure)
+            .configure(sessions::configure)
+            .configure(admin::configure)
+            .configure(acl::configure)
+            .configure(collections::configure)
+            .configure(search::configure)
+            .configure(sql::configure)
+            .configure(rpc::configure)
+            .configure(jobs::configure)
+            .configure(quota::configure)
+            .configure(import::configure)
+            .configure(|cfg| cluster::configure(cfg, node.clone()))
+            // 文档接口先拒绝保留的集合名, 再记录用量, 再按集合检查 ACL 和租户限额, 再等待会话令牌, 之后处理 Idempotency-Key
+            .service(
+                web::scope("")
+                    .wrap(from_fn(idempotency::enforce))
+                    .wrap(from_fn(consistency::track))
+                    .wrap(from_fn(quota::enforce))
+                    .wrap(from_fn(acl::enforce))
+                    .wrap(from_fn(analytics::record))
+                    .wrap(from_fn(collections::protect))
+                    .configure(snapshot::configure)
+                    .configure(transaction::configure)
+                    .configure(collections::configure_documents)
+                    .configure(import::configure_documents)
+                    .configure(profile::configure_documents)
+                    .configure(jsonpath::configure_documents)
+                    .configure(search::configure_documents)
+                    .configure(schema::configure_documents)
+                    .configure(aggregate::configure_documents)
+                    .configure(handlers::configure)
+                    .configure(history::configure),
+            )
+    });
+    #[cfg(feature = "tls")]
+    let server = server.on_connect(json_storage::tls::on_connect);
+
+    // 配置了 tls 时以 HTTPS 监听
+    let server = match &tls {
+        #[cfg(feature = "tls")]
+        Some(settings) => server.bind_openssl(&addr, json_storage::tls::acceptor(settings)?)?,
+        #[cfg(not(feature = "tls"))]
+        Some(_) => panic!("tls is configured but the server was built without the `tls` feature"),
+        None => server.bind(&addr)?,
+    };
+    log::info!("listening on {}://{}", if tls.is_some() { "https" } else { "http" }, addr);
+    server.run().await?;
+
+    if let Err(e) = analytics.flush().await {
+        log::error!("failed to write usage analytics at shutdown: {}", e);
+    }
+
+    // 退出前归档最后一段 WAL
+    if let Some(archiver) = archiver {
+        if let Err(e) = archiver.archive().await {
+            log::error!("failed to archive the WAL at shutdown: {}", e);
+        }
+    }
+
+    telemetry.shutdown();
+    Ok(())
+}
@@ -0,0 +1,164 @@
+use actix_web::body::MessageBody;
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::middleware::Next;
+use actix_web::{web, Error, HttpResponse};
+use serde_json::{json, Map, Value};
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::Mutex;
+use std::time::Instant;
+
+type Labels = Vec<(&'static str, String)>;
+type Series = BTreeMap<(&'static str, Labels), (Kind, f64)>;
+
+#[derive(Clone, Copy, PartialEq)]
+enum Kind {
+    Counter,
+    Gauge,
+}
+
+/// In-process metrics registry: counters and gauges keyed by name and labels.
+pub struct Metrics {
+    started: Instant,
+    series: Mutex<Series>,
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        Self {
+            started: Instant::now(),
+            series: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    pub fn incr(&self, name: &'static str, labels: Labels, by: f64) {
+        self.add(Kind::Counter, name, labels, by);
+    }
+
+    pub fn gauge_add(&self, name: &'static str, labels: Labels, delta: f64) {
+        self.add(Kind::Gauge, name, labels, delta);
+    }
+
+    /// Sum of the series of `name` whose labels satisfy `matches`.
+    pub fn sum(&self, name: &str, matches: impl Fn(&[(&'static str, String)]) -> bool) -> f64 {
+        let series = self.series.lock().unwrap();
+        series.iter().filter(|((n, labels), _)| *n == name && matches(labels)).map(|(_, (_, value))| value).sum()
+    }
+
+    fn add(&self, kind: Kind, name: &'static str, labels: Labels, delta: f64) {
+        let mut series = self.series.lock().unwrap();
+        series.entry((name, labels)).or_insert((kind, 0.0)).1 += delta;
+    }
+
+    /// Current values grouped by metric name, e.g.
+    /// `{"counters": {"http_requests_total": [{"labels": {...}, "value": 3}]}, "gauges": {...}}`.
+    pub fn snapshot(&self) -> Value {
+        let mut counters = Map::new();
+        let mut gauges = Map::new();
+        for ((name, labels), (kind, value)) in self.series.lock().unwrap().iter() {
+            let labels: Map<String, Value> = labels
+                .iter()
+                .map(|(k, v)| (k.to_string(), Value::String(v.clone())))
+                .collect();
+            let target = if *kind == Kind::Counter { &mut counters } else { &mut gauges };
+            target
+                .entry(name.to_string())
+                .or_insert_with(|| Value::Array(Vec::new()))
+                .as_array_mut()
+                .unwrap()
+                .push(json!({ "labels": labels, "value": value }));
+        }
+        json!({
+            "uptime_seconds": self.started.elapsed().as_secs_f64(),
+            "counters": counters,
+            "gauges": gauges,
+        })
+    }
+
+    /// Prometheus text exposition format.
+    pub fn render_prometheus(&self) -> String {
+        let mut out = String::new();
+        let mut last_name = "";
+        let _ = writeln!(out, "# TYPE uptime_seconds gauge");
+        let _ = writeln!(out, "uptime_seconds {}", self.started.elapsed().as_secs_f64());
+        for ((name, labels), (kind, value)) in self.series.lock().unwrap().iter() {
+            if *name != last_name {
+                let kind = if *kind == Kind::Counter { "counter" } else { "gauge" };
+                let _ = writeln!(out, "# TYPE {} {}", name, kind);
+                last_name = name;
+            }
+            let labels: Vec<String> = labels
+                .iter()
+                .map(|(k, v)| format!("{}=\"{}\"", k, v.replace('\\', "\\\\").replace('"', "\\\"")))
+                .collect();
+            if labels.is_empty() {
+                let _ = writeln!(out, "{} {}", name, value);
+            } else {
+                let _ = writeln!(out, "{}{{{}}} {}", name, labels.join(","), value);
+            }
+        }
+        out
+    }
+}
+
+// 注册指标接口
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.route("/_metrics", web::get().to(prometheus))
+        .route("/_metrics.json", web::get().to(snapshot));
+}
+
+pub async fn prometheus(metrics: web::Data<Metrics>) -> HttpResponse {
+    HttpResponse::Ok()
+        .content_type("text/plain; version=0.0.4")
+        .body(metrics.render_prometheus())
+}
+
+pub async fn snapshot(metrics: web::Data<Metrics>) -> HttpResponse {
+    HttpResponse::Ok().json(metrics.snapshot())
+}
+
+/// Records request counts, latency and in-flight requests per route pattern.
+pub async fn track(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<impl MessageBody>, Error> {
+    let Some(metrics) = req.app_data::<web::Data<Metrics>>().cloned() else {
+        return next.call(req).await;
+    };
+    let method = req.method().to_string();
+    let started = Instant::now();
+
+    metrics.gauge_add("http_requests_in_flight", Vec::new(), 1.0);
+    let result = next.call(req).await;
+    metrics.gauge_add("http_requests_in_flight", Vec::new(), -1.0);
+
+    let (route, status) = match &result {
+        Ok(res) => (
+            res.request().match_pattern().unwrap_or_else(|| "unmatched".to_string()),
+            res.status().as_u16().to_string(),
+        ),
+        Err(e) => ("unmatched".to_string(), e.as_response_error().status_code().as_u16().to_string()),
+    };
+    metrics.incr(
+        "http_requests_total",
+        vec![("method", method.clone()), ("route", route.clone()), ("status", status)],
+        1.0,
+    );
+    metrics.incr(
+        "http_request_duration_seconds_sum",
+        vec![("method", method.clone()), ("route", route.clone())],
+        started.elapsed().as_secs_f64(),
+    );
+    metrics.incr(
+        "http_request_duration_seconds_count",
+        vec![("method", method), ("route", route)],
+        1.0,
+    );
+    result
+}
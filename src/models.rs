@@ -1,8 +1,17 @@
 use serde::{Deserialize, Serialize};
-use serde_json::Value;
+use serde_json::{json, Value};
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct JsonData {
     pub uri: String,
     pub data: Value,
-}
\ No newline at end of file
+}
+/// The document stored for a message payload from a broker: a JSON object as
+/// is, any other JSON value or non-JSON text wrapped as `{"value": ...}`.
+pub fn payload_document(payload: &[u8]) -> Value {
+    match serde_json::from_slice::<Value>(payload) {
+        Ok(Value::Object(map)) => Value::Object(map),
+        Ok(value) => json!({ "value": value }),
+        Err(_) => json!({ "value": String::from_utf8_lossy(payload) }),
+    }
+}
@@ -0,0 +1,146 @@
+//! MQTT ingestion.
+//!
+//! With `mqtt` configured, the server subscribes to every route's topic filter
+//! and stores each message as a document in the route's collection. A JSON
+//! object payload is stored as is; any other payload is wrapped as
+//! `{"value": ...}`, as JSON if it parses and as text otherwise. Messages
+//! are acknowledged only after the insert was attempted, so with QoS 1 or 2 a
+//! crash mid-insert leads to redelivery rather than loss. Collection
+//! deduplication applies as for HTTP inserts.
+//!
+//! The bridge needs the `mqtt` cargo feature; without it [`start`] only
+//! warns that `mqtt` is configured.
+
+use serde_json::Value;
+use std::sync::Arc;
+
+use crate::config::{ConfigHandle, MqttRoute};
+use crate::metrics::Metrics;
+use crate::models::payload_document;
+use crate::store::DocumentStore;
+
+/// Connects to the configured broker, if any, and ingests messages until shutdown.
+#[cfg(feature = "mqtt")]
+pub fn start(config: Arc<ConfigHandle>, store: Arc<dyn DocumentStore>, metrics: Arc<Metrics>) {
+    let Some(settings) = config.get().mqtt.clone() else {
+        return;
+    };
+    bridge::spawn(settings, config, store, metrics);
+}
+
+#[cfg(not(feature = "mqtt"))]
+pub fn start(config: Arc<ConfigHandle>, _store: Arc<dyn DocumentStore>, _metrics: Arc<Metrics>) {
+    if config.get().mqtt.is_some() {
+        log::warn!("mqtt is configured but the server was built without the `mqtt` feature");
+    }
+}
+
+/// Whether `topic` matches the MQTT topic `filter`.
+pub fn topic_matches(filter: &str, topic: &str) -> bool {
+    let mut levels = topic.split('/');
+    for pattern in filter.split('/') {
+        match (pattern, levels.next()) {
+            ("#", _) => return true,
+            ("+", Some(_)) => {}
+            (pattern, Some(level)) if pattern == level => {}
+            _ => return false,
+        }
+    }
+    levels.next().is_none()
+}
+
+/// The first route whose filter matches `topic`.
+pub fn route_for<'a>(routes: &'a [MqttRoute], topic: &str) -> Option<&'a MqttRoute> {
+    routes.iter().find(|route| topic_matches(&route.topic, topic))
+}
+
+/// The document stored for a message.
+pub fn document(route: &MqttRoute, topic: &str, payload: &[u8]) -> Value {
+    let mut doc = payload_document(payload);
+    if let Some(field) = &route.topic_field {
+        doc[field.as_str()] = Value::String(topic.to_string());
+    }
+    doc
+}
+
+#[cfg(feature = "mqtt")]
+mod bridge {
+    use rumqttc::{AsyncClient, Event, MqttOptions, Packet, Publish, QoS};
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use super::{document, route_for};
+    use crate::config::{ConfigHandle, Mqtt};
+    use crate::handlers::store_document;
+    use crate::metrics::Metrics;
+    use crate::store::DocumentStore;
+
+    /// Wait before polling again after a connection error; the event loop reconnects on the next poll.
+    const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+
+    pub fn spawn(settings: Mqtt, config: Arc<ConfigHandle>, store: Arc<dyn DocumentStore>, metrics: Arc<Metrics>) {
+        let mut options = MqttOptions::new(&settings.client_id, &settings.host, settings.port);
+        options.set_keep_alive(Duration::from_secs(settings.keep_alive_secs));
+        options.set_clean_session(false);
+        options.set_manual_acks(true);
+        if let Some(username) = &settings.username {
+            options.set_credentials(username, settings.password.clone().unwrap_or_default());
+        }
+        let qos = match settings.qos {
+            0 => QoS::AtMostOnce,
+            1 => QoS::AtLeastOnce,
+            _ => QoS::ExactlyOnce,
+        };
+        let (client, mut eventloop) = AsyncClient::new(options, 64);
+
+        tokio::spawn(async move {
+            loop {
+                match eventloop.poll().await {
+                    // 每次 (重新) 连接后订阅
+                    Ok(Event::Incoming(Packet::ConnAck(_))) => {
+                        log::info!("mqtt connected to {}:{}", settings.host, settings.port);
+                        for route in &settings.routes {
+                            if let Err(e) = client.try_subscribe(route.topic.as_str(), qos) {
+                                log::warn!("mqtt failed to subscribe to '{}': {}", route.topic, e);
+                            }
+                        }
+                    }
+                    Ok(Event::Incoming(Packet::Publish(publish))) => {
+                        ingest(&settings, &config, store.as_ref(), &metrics, &publish).await;
+                        if let Err(e) = client.try_ack(&publish) {
+                            log::warn!("mqtt failed to acknowledge message on '{}': {}", publish.topic, e);
+                        }
+                    }
+                    Ok(_) => {}
+                    Err(e) => {
+                        log::warn!("mqtt connection to {}:{} failed: {}", settings.host, settings.port, e);
+                        tokio::time::sleep(RECONNECT_DELAY).await;
+                    }
+                }
+            }
+        });
+    }
+
+    async fn ingest(settings: &Mqtt, config: &ConfigHandle, store: &dyn DocumentStore, metrics: &Metrics, publish: &Publish) {
+        let Some(route) = route_for(&settings.routes, &publish.topic) else {
+            log::debug!("mqtt message on '{}' matches no route", publish.topic);
+            return;
+        };
+        let collection = route.collection();
+        let doc = document(route, &publish.topic, &publish.payload);
+        let config = config.get();
+        let dedup = config.collections.get(&collection).and_then(|c| c.dedup.as_ref());
+        let status = match store_document(store, dedup, &collection, &doc).await {
+            Ok(_) => "stored",
+            Err(e) => {
+                log::warn!("mqtt failed to store message from '{}' in '{}': {}", publish.topic, collection, e);
+                "failed"
+            }
+        };
+        metrics.incr(
+            "mqtt_messages_total",
+            vec![("collection", collection), ("status", status.to_string())],
+            1.0,
+        );
+    }
+}
@@ -0,0 +1,1272 @@
+//! Nested objects and arrays in tables of their own.
+//!
+//! A collection with `"nested": true` in its `collections` settings stores
+//! the objects in its documents as rows of tables of their own instead of
+//! JSON text: the object under `address` in a document of `orders` is a row
+//! of `orders__address` whose `_parent` is the document's id, and the object
+//! under `geo` in that one a row of `orders__address__geo`. The document's
+//! own column for the field holds `null`. Reads through the store put the
+//! objects back, one query per table for a whole listing. Every document has
+//! a row and child rows of its own, so listings, `GET /{uri}/{id}` and
+//! deletes see each one apart from the others.
+//!
+//! With `"arrays": true`, the elements of non-empty arrays are rows as well:
+//! those under `tags` are rows of `orders__tags_items`, whose `idx` keeps
+//! their order. An element that is an object has its fields as columns and
+//! `{}` in `_value`; any other element is held in `_value`. Objects and
+//! arrays inside the elements follow the same settings, so the objects under
+//! `geo` in the elements are rows of `orders__tags_items__geo`.
+//!
+//! Filters reach the fields of split-off objects by dotted paths
+//! (`address.city`), compiled to `EXISTS` subqueries on their tables. A
+//! split-off array matches when one of its elements does, and `tags.sku`
+//! reaches the fields of object elements. The objects themselves only take
+//! `$exists`; sorting by split-off values, or filtering on them to update or
+//! delete documents, is rejected.
+//!
+//! The tables are recorded in `_nested_tables`, which keeps them out of the
+//! collection listings. A field is split off only when its table is not
+//! some other collection already.
+//!
+//! The document's own row is written first and the split-off rows after it.
+//! When they cannot be written, the write is undone: an inserted document is
+//! deleted again, and a replaced or updated one is written back as it was
+//! read before, under a new version.
+//!
+//! Keys that are identifiers without `__` and not ending in `_` or `_items`
+//! (`address`, `ship_to`) name their tables as they are. Other keys are
+//! written as `x`, the hex of their UTF-8 bytes and `_` (`ship to` is
+//! `x7368697020746f_`), which neither takes `__` nor ends like a plain key,
+//! so every chain maps to one table and back to its keys.
+//!
+//! Only objects without an `id`, `_version`, `_parent`, `idx` or `_value`
+//! of their own are split off; others stay JSON text, as do arrays inside
+//! arrays and the values written by `POST /{uri}/_update_many`, which
+//! replace the split-off ones. The aggregation, search, profile, snapshot and admin routes read
+//! the collection's table itself and see `null` for split-off values.
+
+use async_trait::async_trait;
+use serde_json::{json, Map, Value};
+use sqlx::sqlite::SqliteRow;
+use sqlx::{Column, Row, SqlitePool, ValueRef};
+use std::collections::{BTreeSet, HashMap};
+use std::sync::Arc;
+
+use crate::config::ConfigHandle;
+use crate::database::{
+    collection_exists, columns_lock, count_matching, find_matching, find_page_matching, hashed_document, row_to_json, table_columns, table_name,
+    Condition,
+};
+use crate::query::{parse_filter, parse_update, quote, CompareOp, Filter};
+use crate::sessions::hex;
+use crate::store::{DedupMode, DocumentStore, Inserted, PageQuery, Paged, StoreError, UpdatedMany, Upserted, Written, VERSION_FIELD};
+
+/// Column of a nested row holding the id of the row it is in.
+pub const PARENT_FIELD: &str = "_parent";
+
+/// Column of an array element's row holding its position in the array.
+pub const INDEX_FIELD: &str = "idx";
+
+/// Column of an array element's row holding the element, or `{}` when the
+/// element is an object stored in the row's other columns.
+pub const VALUE_FIELD: &str = "_value";
+
+/// Ids bound to one `$in` when reading or deleting nested rows.
+const CHUNK: usize = 500;
+
+/// Suffix of the key of an array in the chain of its elements.
+const ARRAY: &str = "_items";
+
+/// The collection holding the values found at `chain` (`address`,
+/// `address/geo`, `tags_items`) in the documents of `uri`.
+pub fn nested_uri(uri: &str, chain: &str) -> String {
+    format!("{}/{}", uri, chain)
+}
+
+/// The table holding the values found at `chain` in the documents of `uri`;
+/// the chain `""` is the collection's own table. Writes and reads both name
+/// the tables through this, and the chains through [`segment`] and
+/// [`child_chain`].
+pub fn chain_table(uri: &str, chain: &str) -> String {
+    match chain {
+        "" => table_name(uri),
+        chain => table_name(&nested_uri(uri, chain)),
+    }
+}
+
+// 可以原样用在 chain 和表名中的键: 不含 "__"、不以 "_" 或 "_items" 结尾的标识符
+fn plain_key(key: &str) -> bool {
+    key.starts_with(|c: char| c.is_ascii_alphabetic())
+        && key.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+        && !key.contains("__")
+        && !key.ends_with('_')
+        && !key.ends_with(ARRAY)
+}
+
+// 一级 chain 中 key 下的部分: 对象是 key 本身, 数组的元素是 key 加 "_items";
+// 其他的键写成 "x" 加 UTF-8 的十六进制, 以 "_" 结尾, 与原样的键和 "__" 分隔都不会混淆
+fn segment(key: &str, array: bool) -> String {
+    let suffix = if array { ARRAY } else { "" };
+    match plain_key(key) {
+        true => format!("{}{}", key, suffix),
+        false => format!("x{}{}_", hex(key.as_bytes()), suffix),
+    }
+}
+
+// segment 的反面: 文档中的键, 以及其下是不是数组的元素
+fn key_of(segment: &str) -> (String, bool) {
+    let Some(encoded) = segment.strip_suffix('_').and_then(|s| s.strip_prefix('x')) else {
+        return match segment.strip_suffix(ARRAY) {
+            Some(key) => (key.to_string(), true),
+            None => (segment.to_string(), false),
+        };
+    };
+    let (encoded, array) = match encoded.strip_suffix(ARRAY) {
+        Some(encoded) => (encoded, true),
+        None => (encoded, false),
+    };
+    let bytes: Option<Vec<u8>> =
+        (0..encoded.len()).step_by(2).map(|i| encoded.get(i..i + 2).and_then(|b| u8::from_str_radix(b, 16).ok())).collect();
+    match bytes.and_then(|bytes| String::from_utf8(bytes).ok()) {
+        Some(key) => (key, array),
+        None => (segment.to_string(), false),
+    }
+}
+
+// parent ("" 为文档本身) 中 segment 下拆出部分的 chain
+fn child_chain(parent: &str, segment: &str) -> String {
+    match parent {
+        "" => segment.to_string(),
+        parent => format!("{}/{}", parent, segment),
+    }
+}
+
+// chain 的上一级, 文档本身时为 None; 以及它在上一级中的 segment
+fn parent_of(chain: &str) -> (Option<&str>, &str) {
+    match chain.rsplit_once('/') {
+        Some((parent, segment)) => (Some(parent), segment),
+        None => (None, chain),
+    }
+}
+
+// chain 最上一级在文档中的键
+fn root_key(chain: &str) -> String {
+    key_of(chain.split('/').next().unwrap_or_default()).0
+}
+
+// chain 的行是不是数组的元素
+fn holds_elements(chain: &str) -> bool {
+    key_of(parent_of(chain).1).1
+}
+
+/// The chains of the tables holding values split off the documents of
+/// `uri`, those of upper levels before those below them.
+pub async fn chains(pool: &SqlitePool, uri: &str) -> Result<Vec<String>, sqlx::Error> {
+    let mut chains: Vec<String> = sqlx::query_scalar(
+        "SELECT chain FROM _nested_tables WHERE collection = ? AND table_name IN (SELECT name FROM sqlite_master WHERE type = 'table')",
+    )
+    .bind(uri)
+    .fetch_all(pool)
+    .await?;
+    chains.sort_by_key(|chain| chain.matches('/').count());
+    Ok(chains)
+}
+
+/// What a collection splits off into tables of its own.
+#[derive(Debug, Clone, Copy)]
+struct Split {
+    objects: bool,
+    arrays: bool,
+}
+
+// 可以拆到子表的字段: 文档的 id 和版本之外的键
+fn splittable_key(key: &str) -> bool {
+    key != "id" && key != VERSION_FIELD
+}
+
+// 对象中没有子表自己用的键时才能拆成一行
+fn splittable_object(value: &Value) -> bool {
+    value.as_object().is_some_and(|fields| {
+        !fields.keys().any(|k| ["id", VERSION_FIELD, PARENT_FIELD, INDEX_FIELD, VALUE_FIELD].contains(&k.as_str()))
+    })
+}
+
+// 拆出放进子表的对象和数组, 它们在文档自己的列中为 NULL; 返回的键对应子表的 chain
+fn split(doc: &Value, split: Split) -> (Value, Vec<(String, Value)>) {
+    let Some(fields) = doc.as_object() else {
+        return (doc.clone(), Vec::new());
+    };
+    let mut own = Map::new();
+    let mut parts = Vec::new();
+    for (key, value) in fields {
+        let chain = match value {
+            _ if !splittable_key(key) => None,
+            Value::Object(_) if split.objects && splittable_object(value) => Some(segment(key, false)),
+            Value::Array(elements) if split.arrays && !elements.is_empty() => Some(segment(key, true)),
+            _ => None,
+        };
+        match chain {
+            Some(chain) => {
+                own.insert(key.clone(), Value::Null);
+                parts.push((chain, value.clone()));
+            }
+            None => {
+                own.insert(key.clone(), value.clone());
+            }
+        }
+    }
+    (Value::Object(own), parts)
+}
+
+// 拆出的部分和其中再拆出的部分所在的 chain
+fn chains_of(split: Split, parts: &[(String, Value)], chains: &mut BTreeSet<String>) {
+    for (chain, value) in parts {
+        chains.insert(chain.clone());
+        let inner: Vec<(String, Value)> = match value {
+            Value::Array(elements) => elements.iter().filter(|e| splittable_object(e)).flat_map(|e| self::split(e, split).1).collect(),
+            object => self::split(object, split).1,
+        };
+        let inner: Vec<(String, Value)> = inner.into_iter().map(|(segment, value)| (child_chain(chain, &segment), value)).collect();
+        chains_of(split, &inner, chains);
+    }
+}
+
+fn id_of(doc: &Value) -> Option<i64> {
+    doc.get("id").and_then(Value::as_i64)
+}
+
+// 子表的行带着其他行留下的列; 这些列为 SQL NULL, 写入的 null 则是 JSON 文本, 只取后者
+fn present_fields(row: &SqliteRow) -> Value {
+    let mut value = row_to_json(row);
+    if let Some(fields) = value.as_object_mut() {
+        for column in row.columns() {
+            if row.try_get_raw(column.ordinal()).map_or(true, |raw| raw.is_null()) {
+                fields.remove(column.name());
+            }
+        }
+    }
+    value
+}
+
+/// A [`DocumentStore`] that stores the objects and arrays in the documents
+/// of nested collections in tables of their own, through `inner`, and passes
+/// every other call on.
+pub struct NestedStore {
+    inner: Arc<dyn DocumentStore>,
+    pool: SqlitePool,
+    config: Arc<ConfigHandle>,
+}
+
+impl NestedStore {
+    pub fn new(inner: Arc<dyn DocumentStore>, pool: SqlitePool, config: Arc<ConfigHandle>) -> Self {
+        NestedStore { inner, pool, config }
+    }
+
+    fn split(&self, uri: &str) -> Option<Split> {
+        let config = self.config.get();
+        let settings = config.collections.get(uri)?;
+        let split = Split { objects: settings.nested, arrays: settings.arrays };
+        (split.objects || split.arrays).then_some(split)
+    }
+
+    fn enabled(&self, uri: &str) -> bool {
+        self.split(uri).is_some()
+    }
+
+    async fn chains(&self, uri: &str) -> Result<Vec<String>, StoreError> {
+        Ok(chains(&self.pool, uri).await?)
+    }
+
+    // 过滤条件用到拆出的字段时编译成条件; 用不到时为 None, 交给下一层
+    async fn condition(&self, uri: &str, filter: &Value) -> Result<Option<Condition>, StoreError> {
+        let Ok(filter) = parse_filter(filter) else {
+            return Ok(None);
+        };
+        let chains = self.chains(uri).await?;
+        if split_field(&filter, &chains).is_none() {
+            return Ok(None);
+        }
+        let mut columns = HashMap::new();
+        for chain in std::iter::once("").chain(chains.iter().map(String::as_str)) {
+            let names = table_columns(&self.pool, &chain_table(uri, chain)).await?.into_iter().map(|(name, _)| name).collect();
+            columns.insert(chain.to_string(), names);
+        }
+        let mut params = Vec::new();
+        let sql = Tables { uri, columns }.compile(&filter, &mut params)?;
+        Ok(Some(Condition { sql, params }))
+    }
+
+    // 更新和删除交给下一层, 条件不能用到拆出的字段
+    async fn check_filter(&self, uri: &str, filter: &Value) -> Result<(), StoreError> {
+        let Ok(filter) = parse_filter(filter) else {
+            return Ok(());
+        };
+        match split_field(&filter, &self.chains(uri).await?) {
+            Some(field) => Err(invalid_filter(format!("'{}' is stored in a table of its own and cannot select documents to change", field))),
+            None => Ok(()),
+        }
+    }
+
+    // 拆出的字段在文档自己的列中都是 null, 不能用来排序
+    async fn check_sort(&self, uri: &str, page: &PageQuery) -> Result<(), StoreError> {
+        let chains = self.chains(uri).await?;
+        match page.sort.iter().find(|(field, _)| chains.iter().any(|chain| root_key(chain) == *field)) {
+            Some((field, _)) => Err(StoreError::Invalid(format!("Cannot sort by '{}': it is stored in a table of its own", field))),
+            None => Ok(()),
+        }
+    }
+
+    // 记下拆出的部分要写入的子表; 子表的名字已是别的集合时不拆
+    async fn register(&self, uri: &str, split: Split, parts: &[(String, Value)]) -> Result<(), StoreError> {
+        // 写入失败时记下的子表可能没有建出来
+        sqlx::query("DELETE FROM _nested_tables WHERE collection = ? AND table_name NOT IN (SELECT name FROM sqlite_master WHERE type = 'table')")
+            .bind(uri)
+            .execute(&self.pool)
+            .await?;
+        let mut found = BTreeSet::new();
+        chains_of(split, parts, &mut found);
+        for chain in found {
+            let table = chain_table(uri, &chain);
+            let added = sqlx::query("INSERT OR IGNORE INTO _nested_tables (collection, chain, table_name) VALUES (?, ?, ?)")
+                .bind(uri)
+                .bind(&chain)
+                .bind(&table)
+                .execute(&self.pool)
+                .await?
+                .rows_affected();
+            // 刚记下的子表还没有写入过, 已经存在的表属于别的集合
+            if added > 0 && collection_exists(&self.pool, &table).await? {
+                sqlx::query("DELETE FROM _nested_tables WHERE collection = ? AND chain = ?")
+                    .bind(uri)
+                    .bind(&chain)
+                    .execute(&self.pool)
+                    .await?;
+                return Err(StoreError::Invalid(format!(
+                    "Cannot split off '{}': collection '{}' already exists",
+                    chain,
+                    nested_uri(uri, &chain)
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    // 子表中属于 parents 的行
+    async fn children(&self, uri: &str, chain: &str, parents: &[i64]) -> Result<Vec<Value>, StoreError> {
+        let table = chain_table(uri, chain);
+        let mut rows = Vec::new();
+        for ids in parents.chunks(CHUNK) {
+            let query = format!("SELECT * FROM {} WHERE {} IN ({})", quote(&table), quote(PARENT_FIELD), vec!["?"; ids.len()].join(", "));
+            let _columns = columns_lock(&table).await;
+            let mut statement = sqlx::query(&query);
+            for id in ids {
+                statement = statement.bind(*id);
+            }
+            rows.extend(statement.fetch_all(&self.pool).await?.iter().map(present_fields));
+        }
+        Ok(rows)
+    }
+
+    // 从子表读出对象和数组放回文档
+    async fn attach(&self, uri: &str, docs: &mut [Value]) -> Result<(), StoreError> {
+        let chains = self.chains(uri).await?;
+        if chains.is_empty() || docs.is_empty() {
+            return Ok(());
+        }
+        let mut rows: HashMap<&str, Vec<Value>> = HashMap::new();
+        for chain in &chains {
+            let parents: Vec<i64> = match parent_of(chain).0 {
+                None => docs.iter().filter_map(id_of).collect(),
+                Some(parent) => rows.get(parent).map(|rows| rows.iter().filter_map(id_of).collect()).unwrap_or_default(),
+            };
+            let found = match parents.is_empty() {
+                true => Vec::new(),
+                false => self.children(uri, chain, &parents).await?,
+            };
+            rows.insert(chain, found);
+        }
+        // 从最深的一级开始, 行放进上一级之后才去掉自己的 id
+        for chain in chains.iter().rev() {
+            let (key, array) = key_of(parent_of(chain).1);
+            let mut values: HashMap<i64, Value> = HashMap::new();
+            match array {
+                false => {
+                    for mut row in rows.remove(chain.as_str()).unwrap_or_default() {
+                        let Some(fields) = row.as_object_mut() else { continue };
+                        let Some(parent_id) = fields.remove(PARENT_FIELD).and_then(|v| v.as_i64()) else { continue };
+                        fields.remove("id");
+                        fields.remove(VERSION_FIELD);
+                        values.insert(parent_id, row);
+                    }
+                }
+                true => {
+                    let mut elements: HashMap<i64, Vec<(i64, Value)>> = HashMap::new();
+                    for mut row in rows.remove(chain.as_str()).unwrap_or_default() {
+                        let Some(fields) = row.as_object_mut() else { continue };
+                        let Some(parent_id) = fields.remove(PARENT_FIELD).and_then(|v| v.as_i64()) else { continue };
+                        let index = fields.remove(INDEX_FIELD).and_then(|v| v.as_i64()).unwrap_or_default();
+                        let element = match fields.remove(VALUE_FIELD) {
+                            Some(Value::Object(marker)) if marker.is_empty() => {
+                                fields.remove("id");
+                                fields.remove(VERSION_FIELD);
+                                row
+                            }
+                            Some(value) => value,
+                            None => Value::Null,
+                        };
+                        elements.entry(parent_id).or_default().push((index, element));
+                    }
+                    for (parent_id, mut elements) in elements {
+                        elements.sort_by_key(|(index, _)| *index);
+                        values.insert(parent_id, Value::Array(elements.into_iter().map(|(_, element)| element).collect()));
+                    }
+                }
+            }
+            let targets: &mut [Value] = match parent_of(chain).0 {
+                None => &mut *docs,
+                Some(parent) => rows.get_mut(parent).map(Vec::as_mut_slice).unwrap_or_default(),
+            };
+            for target in targets.iter_mut() {
+                if let Some(value) = id_of(target).and_then(|id| values.remove(&id)) {
+                    target[key.as_str()] = value;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // 把对象和数组的元素写进子表, 其中拆出的部分再写进下一级
+    async fn store(&self, uri: &str, split: Split, id: i64, parts: Vec<(String, Value)>) -> Result<(), StoreError> {
+        let mut pending: Vec<(String, i64, Value)> = parts.into_iter().map(|(chain, value)| (chain, id, value)).collect();
+        while let Some((chain, parent, value)) = pending.pop() {
+            let rows: Vec<(Value, Vec<(String, Value)>)> = match value {
+                Value::Array(elements) => elements
+                    .into_iter()
+                    .enumerate()
+                    .map(|(index, element)| {
+                        let (mut row, parts) = match splittable_object(&element) {
+                            true => {
+                                let (mut row, parts) = self::split(&element, split);
+                                row[VALUE_FIELD] = json!({});
+                                (row, parts)
+                            }
+                            false => (json!({ VALUE_FIELD: element }), Vec::new()),
+                        };
+                        row[INDEX_FIELD] = json!(index);
+                        (row, parts)
+                    })
+                    .collect(),
+                object => vec![self::split(&object, split)],
+            };
+            for (mut row, parts) in rows {
+                row[PARENT_FIELD] = json!(parent);
+                let id = self.inner.insert(&nested_uri(uri, &chain), &row).await?;
+                pending.extend(parts.into_iter().map(|(segment, value)| (child_chain(&chain, &segment), id, value)));
+            }
+        }
+        Ok(())
+    }
+
+    // 删除文档 ids 在 keys 下 (None 时为全部) 的对象和数组, 连同其中拆出的部分
+    async fn remove(&self, uri: &str, ids: &[i64], keys: Option<&[&str]>) -> Result<(), StoreError> {
+        let chains = self.chains(uri).await?;
+        let mut removed: HashMap<&str, Vec<i64>> = HashMap::new();
+        for chain in &chains {
+            if keys.is_some_and(|keys| !keys.contains(&root_key(chain).as_str())) {
+                continue;
+            }
+            let parents = match parent_of(chain).0 {
+                None => ids.to_vec(),
+                Some(parent) => removed.get(parent).cloned().unwrap_or_default(),
+            };
+            let mut deleted = Vec::new();
+            for parents in parents.chunks(CHUNK) {
+                let filter = json!({ PARENT_FIELD: { "$in": parents } });
+                deleted.extend(self.inner.delete_many(&nested_uri(uri, chain), &filter, false).await?);
+            }
+            removed.insert(chain, deleted);
+        }
+        Ok(())
+    }
+
+    // 文档的 keys 有了新值: 删除原来拆出的部分, 写入新的
+    async fn rewrite(&self, uri: &str, split: Split, id: i64, keys: Option<&[&str]>, parts: Vec<(String, Value)>) -> Result<(), StoreError> {
+        self.remove(uri, &[id], keys).await?;
+        self.store(uri, split, id, parts).await
+    }
+
+    // 内容哈希为 hash 的文档
+    async fn hashed(&self, uri: &str, hash: &str) -> Result<Option<Value>, StoreError> {
+        match hashed_document(&self.pool, &table_name(uri), hash).await? {
+            Some(id) => self.get(uri, id).await,
+            None => Ok(None),
+        }
+    }
+
+    // 子表写入失败时撤销对文档 id 的写入: 恢复成 previous, 没有 previous 的是新插入的文档, 删除它
+    async fn undo(&self, uri: &str, split: Split, id: i64, previous: Option<Value>, error: StoreError) -> StoreError {
+        let restored = match previous {
+            None => match self.remove(uri, &[id], None).await {
+                Ok(()) => self.inner.delete(uri, id, None).await,
+                Err(e) => Err(e),
+            },
+            Some(mut previous) => {
+                if let Some(fields) = previous.as_object_mut() {
+                    fields.remove("id");
+                    fields.remove(VERSION_FIELD);
+                }
+                let (own, parts) = self::split(&previous, split);
+                match self.inner.replace(uri, id, &own, None).await {
+                    Ok(_) => self.rewrite(uri, split, id, None, parts).await,
+                    Err(e) => Err(e),
+                }
+            }
+        };
+        if let Err(e) = restored {
+            log::error!("failed to undo the write of document {} of {}: {}", id, uri, e);
+        }
+        error
+    }
+
+    // upsert 到已有文档时, 拆出的部分变了才改写, 只有它们变了的文档也换一个版本
+    async fn reupsert(&self, uri: &str, split: Split, doc: &Value, parts: Vec<(String, Value)>, upserted: &mut Upserted) -> Result<(), StoreError> {
+        if !self.parts_differ(uri, upserted.id, doc, &parts).await? {
+            return Ok(());
+        }
+        self.rewrite(uri, split, upserted.id, Some(&keys(doc)), parts).await?;
+        if !upserted.changed {
+            self.inner.update(uri, upserted.id, &json!({}), None).await?;
+            upserted.changed = true;
+        }
+        Ok(())
+    }
+
+    // 文档 id 中 doc 的键现有的拆出部分是否与 parts 不同
+    async fn parts_differ(&self, uri: &str, id: i64, doc: &Value, parts: &[(String, Value)]) -> Result<bool, StoreError> {
+        let mut current = [json!({ "id": id })];
+        self.attach(uri, &mut current).await?;
+        let new: HashMap<String, &Value> = parts.iter().map(|(chain, value)| (root_key(chain), value)).collect();
+        let keys = doc.as_object().map(|fields| fields.keys().collect::<Vec<_>>()).unwrap_or_default();
+        Ok(keys.into_iter().any(|key| current[0].get(key) != new.get(key.as_str()).copied()))
+    }
+}
+
+// 过滤条件中第一个要到子表中查的字段; 对拆出的字段本身只查 $exists 时看文档自己的列即可
+fn split_field<'a>(filter: &'a Filter, chains: &[String]) -> Option<&'a str> {
+    match filter {
+        Filter::And(items) | Filter::Or(items) => items.iter().find_map(|item| split_field(item, chains)),
+        Filter::Exists { field, .. } if chains.iter().any(|chain| root_key(chain) == *field) => None,
+        Filter::Compare { field, .. } | Filter::In { field, .. } | Filter::Exists { field, .. } => {
+            let root = field.split('.').next().unwrap_or_default();
+            chains.iter().any(|chain| root_key(chain) == root).then_some(field.as_str())
+        }
+    }
+}
+
+// 同一个条件, 换成另一个字段
+fn with_field(filter: &Filter, field: &str) -> Filter {
+    match filter.clone() {
+        Filter::Compare { op, value, .. } => Filter::Compare { field: field.to_string(), op, value },
+        Filter::In { values, negated, .. } => Filter::In { field: field.to_string(), values, negated },
+        Filter::Exists { exists, .. } => Filter::Exists { field: field.to_string(), exists },
+        other => other,
+    }
+}
+
+fn invalid_filter(message: String) -> StoreError {
+    StoreError::Invalid(format!("Invalid filter: {}", message))
+}
+
+/// The tables of a nested collection a filter is compiled against: the
+/// columns of the collection's own table, under the chain `""`, and those
+/// of the tables holding the objects split off its documents.
+struct Tables<'a> {
+    uri: &'a str,
+    columns: HashMap<String, Vec<String>>,
+}
+
+impl Tables<'_> {
+    fn table(&self, chain: &str) -> String {
+        chain_table(self.uri, chain)
+    }
+
+    fn has_column(&self, chain: &str, column: &str) -> bool {
+        self.columns.get(chain).is_some_and(|columns| columns.iter().any(|c| c == column))
+    }
+
+    // chain 的表中有属于 parent 的表当前行、满足 sql 的行
+    fn exists(&self, chain: &str, parent: &str, sql: &str) -> String {
+        let table = quote(&self.table(chain));
+        format!("EXISTS (SELECT 1 FROM {0} WHERE {0}.{1} = {2}.id AND {3})", table, quote(PARENT_FIELD), quote(&self.table(parent)), sql)
+    }
+
+    // 编译成 SQL; "address.city" 和数组 "tags" 这样的字段成为对子表的 EXISTS 子查询
+    fn compile(&self, filter: &Filter, params: &mut Vec<Value>) -> Result<String, StoreError> {
+        if let Filter::And(items) | Filter::Or(items) = filter {
+            let (separator, empty) = match filter {
+                Filter::And(_) => (" AND ", "1 = 1"),
+                _ => (" OR ", "1 = 0"),
+            };
+            let mut parts = items.iter().map(|item| self.compile(item, params)).collect::<Result<Vec<_>, _>>()?;
+            return Ok(match parts.len() {
+                0 => empty.to_string(),
+                1 => parts.remove(0),
+                _ => format!("({})", parts.join(separator)),
+            });
+        }
+        let field = filter.fields()[0];
+        // 键中本身带 "." 的列优先
+        let segments: Vec<&str> = match self.has_column("", field) {
+            true => vec![field],
+            false => field.split('.').collect(),
+        };
+        let Some((column, path)) = segments.split_last() else {
+            return Err(invalid_filter(format!("unknown field '{}'", field)));
+        };
+        let unknown = || invalid_filter(format!("unknown field '{}'", field));
+        let mut chains: Vec<String> = Vec::new();
+        for segment in path {
+            let parent = chains.last().map(String::as_str).unwrap_or_default();
+            let object = child_chain(parent, &self::segment(segment, false));
+            let array = child_chain(parent, &self::segment(segment, true));
+            let chain = match () {
+                _ if self.columns.contains_key(&object) => object,
+                _ if self.columns.contains_key(&array) => array,
+                _ => return Err(unknown()),
+            };
+            chains.push(chain);
+        }
+        let chain = chains.last().map(String::as_str).unwrap_or_default();
+        if !self.has_column(chain, column) {
+            return Err(unknown());
+        }
+        let exists = matches!(filter, Filter::Exists { .. });
+        if self.columns.contains_key(&child_chain(chain, &segment(column, false))) && !exists {
+            return Err(invalid_filter(format!(
+                "'{0}' is stored in a table of its own; filter on its fields, such as '{0}.<field>'",
+                field
+            )));
+        }
+        // 数组的元素在子表中, 条件对任一元素成立即可
+        let array = child_chain(chain, &segment(column, true));
+        let elements = self.columns.contains_key(&array) && !exists;
+        let whole = |value: &Value| value.is_array() || value.is_object();
+        let compares_whole = match filter {
+            Filter::Compare { value, .. } => whole(value),
+            Filter::In { values, .. } => values.iter().any(whole),
+            _ => false,
+        };
+        if elements && compares_whole {
+            return Err(invalid_filter(format!("'{}' holds its elements in a table of their own; compare them with single values", field)));
+        }
+        let through_array = elements || chains.iter().any(|chain| holds_elements(chain));
+
+        // 没有这个字段的文档也算字段不存在、等于 null; 经过数组时 $ne 和 $nin 要求没有一个元素满足
+        let condition = with_field(filter, if elements { VALUE_FIELD } else { column });
+        let (mut negated, condition) = match condition {
+            Filter::Exists { field, exists: false } => (true, Filter::Exists { field, exists: true }),
+            Filter::Compare { field, op: CompareOp::Eq, value: Value::Null } if !elements => {
+                (true, Filter::Compare { field, op: CompareOp::Ne, value: Value::Null })
+            }
+            Filter::Compare { field, op: CompareOp::Ne, value } if through_array => (true, Filter::Compare { field, op: CompareOp::Eq, value }),
+            Filter::In { field, values, negated: true } if through_array => (true, Filter::In { field, values, negated: false }),
+            condition => (false, condition),
+        };
+        let mut sql = match elements {
+            false => condition.to_sql(params),
+            // 数组没有拆出的文档 (空数组、设置之前写入的) 比较自己的列
+            true => {
+                let within = |sql: &str| self.exists(&array, chain, sql);
+                let matching = within(&condition.to_sql(params));
+                let own = with_field(filter, column).to_sql(params);
+                let split = within("1 = 1");
+                match std::mem::take(&mut negated) {
+                    false => format!("({} OR (NOT {} AND {}))", matching, split, own),
+                    true => format!("(NOT {} AND ({} OR {}))", matching, split, own),
+                }
+            }
+        };
+        for (level, chain) in chains.iter().enumerate().rev() {
+            let parent = match level {
+                0 => "",
+                _ => chains[level - 1].as_str(),
+            };
+            sql = self.exists(chain, parent, &sql);
+        }
+        Ok(if negated { format!("NOT {}", sql) } else { sql })
+    }
+}
+
+// 文档中的键, 写入时它们原来拆出的部分被替换
+fn keys(doc: &Value) -> Vec<&str> {
+    doc.as_object().map(|fields| fields.keys().map(String::as_str).collect()).unwrap_or_default()
+}
+
+#[async_trait]
+impl DocumentStore for NestedStore {
+    async fn insert(&self, uri: &str, doc: &Value) -> Result<i64, StoreError> {
+        let Some(split) = self.split(uri) else {
+            return self.inner.insert(uri, doc).await;
+        };
+        let (own, parts) = self::split(doc, split);
+        self.register(uri, split, &parts).await?;
+        let id = self.inner.insert(uri, &own).await?;
+        match self.store(uri, split, id, parts).await {
+            Ok(()) => Ok(id),
+            Err(e) => Err(self.undo(uri, split, id, None, e).await),
+        }
+    }
+
+    async fn list(&self, uri: &str) -> Result<Vec<Value>, StoreError> {
+        let mut docs = self.inner.list(uri).await?;
+        if self.enabled(uri) {
+            self.attach(uri, &mut docs).await?;
+        }
+        Ok(docs)
+    }
+
+    async fn get(&self, uri: &str, id: i64) -> Result<Option<Value>, StoreError> {
+        let Some(doc) = self.inner.get(uri, id).await? else {
+            return Ok(None);
+        };
+        let mut docs = [doc];
+        if self.enabled(uri) {
+            self.attach(uri, &mut docs).await?;
+        }
+        let [doc] = docs;
+        Ok(Some(doc))
+    }
+
+    async fn get_many(&self, uri: &str, ids: &[i64]) -> Result<Vec<Value>, StoreError> {
+        let mut docs = self.inner.get_many(uri, ids).await?;
+        if self.enabled(uri) {
+            self.attach(uri, &mut docs).await?;
+        }
+        Ok(docs)
+    }
+
+    async fn insert_unique(&self, uri: &str, doc: &Value, hash: &str, mode: DedupMode) -> Result<Inserted, StoreError> {
+        let Some(split) = self.split(uri) else {
+            return self.inner.insert_unique(uri, doc, hash, mode).await;
+        };
+        let (own, parts) = self::split(doc, split);
+        self.register(uri, split, &parts).await?;
+        // 重复的文档会被改写, 先记下它原来的样子
+        let previous = match mode {
+            DedupMode::Upsert => self.hashed(uri, hash).await?,
+            DedupMode::Skip => None,
+        };
+        let inserted = self.inner.insert_unique(uri, &own, hash, mode).await?;
+        let written = match (inserted.duplicate, mode) {
+            (false, _) => self.store(uri, split, inserted.id, parts).await,
+            (true, DedupMode::Upsert) => self.rewrite(uri, split, inserted.id, Some(&keys(doc)), parts).await,
+            (true, DedupMode::Skip) => Ok(()),
+        };
+        match written {
+            Ok(()) => Ok(inserted),
+            Err(e) => Err(self.undo(uri, split, inserted.id, previous.filter(|_| inserted.duplicate), e).await),
+        }
+    }
+
+    async fn upsert(&self, uri: &str, key: &str, doc: &Value) -> Result<Upserted, StoreError> {
+        let Some(split) = self.split(uri) else {
+            return self.inner.upsert(uri, key, doc).await;
+        };
+        let (own, parts) = self::split(doc, split);
+        self.register(uri, split, &parts).await?;
+        let previous = match doc.get(key) {
+            Some(value) => self.find(uri, &json!({ key: value })).await?.into_iter().next(),
+            None => None,
+        };
+        let mut upserted = self.inner.upsert(uri, key, &own).await?;
+        let written = match upserted.existed {
+            false => self.store(uri, split, upserted.id, parts).await,
+            true => self.reupsert(uri, split, doc, parts, &mut upserted).await,
+        };
+        match written {
+            Ok(()) => Ok(upserted),
+            Err(e) => Err(self.undo(uri, split, upserted.id, previous.filter(|_| upserted.existed), e).await),
+        }
+    }
+
+    async fn replace(&self, uri: &str, id: i64, doc: &Value, expected: Option<&[i64]>) -> Result<i64, StoreError> {
+        let Some(split) = self.split(uri) else {
+            return self.inner.replace(uri, id, doc, expected).await;
+        };
+        let (own, parts) = self::split(doc, split);
+        self.register(uri, split, &parts).await?;
+        let previous = self.get(uri, id).await?;
+        let version = self.inner.replace(uri, id, &own, expected).await?;
+        match self.rewrite(uri, split, id, None, parts).await {
+            Ok(()) => Ok(version),
+            Err(e) => Err(self.undo(uri, split, id, previous, e).await),
+        }
+    }
+
+    async fn update(&self, uri: &str, id: i64, doc: &Value, expected: Option<&[i64]>) -> Result<i64, StoreError> {
+        let Some(split) = self.split(uri) else {
+            return self.inner.update(uri, id, doc, expected).await;
+        };
+        let (own, parts) = self::split(doc, split);
+        self.register(uri, split, &parts).await?;
+        let previous = self.get(uri, id).await?;
+        let version = self.inner.update(uri, id, &own, expected).await?;
+        match self.rewrite(uri, split, id, Some(&keys(doc)), parts).await {
+            Ok(()) => Ok(version),
+            Err(e) => Err(self.undo(uri, split, id, previous, e).await),
+        }
+    }
+
+    async fn delete(&self, uri: &str, id: i64, expected: Option<&[i64]>) -> Result<(), StoreError> {
+        self.inner.delete(uri, id, expected).await?;
+        if self.enabled(uri) {
+            self.remove(uri, &[id], None).await?;
+        }
+        Ok(())
+    }
+
+    // 按条件写入的值以 JSON 文本保存, 替换掉原来拆出的部分
+    async fn update_many(&self, uri: &str, filter: &Value, update: &Value) -> Result<UpdatedMany, StoreError> {
+        if self.enabled(uri) {
+            self.check_filter(uri, filter).await?;
+        }
+        let updated = self.inner.update_many(uri, filter, update).await?;
+        if self.enabled(uri) && !updated.modified.is_empty() {
+            if let Ok(update) = parse_update(update) {
+                self.remove(uri, &updated.modified, Some(&update.fields())).await?;
+            }
+        }
+        Ok(updated)
+    }
+
+    async fn delete_many(&self, uri: &str, filter: &Value, dry_run: bool) -> Result<Vec<i64>, StoreError> {
+        if self.enabled(uri) {
+            self.check_filter(uri, filter).await?;
+        }
+        let deleted = self.inner.delete_many(uri, filter, dry_run).await?;
+        if self.enabled(uri) && !dry_run && !deleted.is_empty() {
+            self.remove(uri, &deleted, None).await?;
+        }
+        Ok(deleted)
+    }
+
+    async fn find(&self, uri: &str, filter: &Value) -> Result<Vec<Value>, StoreError> {
+        if !self.enabled(uri) {
+            return self.inner.find(uri, filter).await;
+        }
+        let mut docs = match self.condition(uri, filter).await? {
+            Some(condition) => find_matching(&self.pool, &table_name(uri), &condition).await?,
+            None => self.inner.find(uri, filter).await?,
+        };
+        self.attach(uri, &mut docs).await?;
+        Ok(docs)
+    }
+
+    async fn count(&self, uri: &str, filter: &Value) -> Result<u64, StoreError> {
+        if !self.enabled(uri) {
+            return self.inner.count(uri, filter).await;
+        }
+        match self.condition(uri, filter).await? {
+            Some(condition) => count_matching(&self.pool, &table_name(uri), &condition).await,
+            None => self.inner.count(uri, filter).await,
+        }
+    }
+
+    async fn find_page(&self, uri: &str, filter: &Value, page: &PageQuery) -> Result<Paged, StoreError> {
+        if !self.enabled(uri) {
+            return self.inner.find_page(uri, filter, page).await;
+        }
+        self.check_sort(uri, page).await?;
+        let mut paged = match self.condition(uri, filter).await? {
+            Some(condition) => find_page_matching(&self.pool, &table_name(uri), &condition, page).await?,
+            None => self.inner.find_page(uri, filter, page).await?,
+        };
+        self.attach(uri, &mut paged.docs).await?;
+        paged.docs = paged.docs.into_iter().map(|doc| page.project(doc)).collect();
+        Ok(paged)
+    }
+
+    async fn version(&self, uri: &str) -> Result<String, StoreError> {
+        self.inner.version(uri).await
+    }
+
+    async fn committed(&self, writes: &[Written]) {
+        self.inner.committed(writes).await;
+    }
+}
+
+/// Wraps `store` in a [`NestedStore`]. What a collection splits off is read
+/// from the configuration on every call.
+pub fn wrap(config: Arc<ConfigHandle>, store: Arc<dyn DocumentStore>, pool: SqlitePool) -> Arc<dyn DocumentStore> {
+    Arc::new(NestedStore::new(store, pool, config))
+}
+
+#[cfg(all(test, feature = "sqlite"))]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use crate::testing::TestStore;
+
+    async fn nested(collection: Value) -> (TestStore, Arc<dyn DocumentStore>) {
+        let test = TestStore::new().await;
+        let config: Config = serde_json::from_value(json!({ "collections": { "orders": collection } })).unwrap();
+        let store = wrap(Arc::new(ConfigHandle::new(None, config)), test.data().into_inner(), test.pool().clone());
+        (test, store)
+    }
+
+    // 读回的文档去掉 id 和版本
+    async fn read(store: &Arc<dyn DocumentStore>, id: i64) -> Value {
+        let mut doc = store.get("orders", id).await.unwrap().expect("document");
+        let fields = doc.as_object_mut().unwrap();
+        fields.remove("id");
+        fields.remove(VERSION_FIELD);
+        doc
+    }
+
+    fn ids(docs: &[Value]) -> Vec<i64> {
+        docs.iter().filter_map(id_of).collect()
+    }
+
+    #[tokio::test]
+    async fn objects_round_trip_through_their_tables() {
+        let (test, store) = nested(json!({ "nested": true })).await;
+        let doc = json!({ "name": "a", "address": { "city": "Oslo", "geo": { "lat": 59.9, "lon": 10.7 } }, "tags": ["x"] });
+        let id = store.insert("orders", &doc).await.unwrap();
+        assert_eq!(read(&store, id).await, doc);
+        assert_eq!(test.row_count("orders/address").await, 1);
+        assert_eq!(test.row_count("orders/address/geo").await, 1);
+        test.assert_columns("orders/address", &["id", VERSION_FIELD, PARENT_FIELD, "city", "geo"]).await;
+        let own: Option<String> = sqlx::query_scalar("SELECT address FROM orders").fetch_one(test.pool()).await.unwrap();
+        assert_eq!(own.as_deref(), Some("null"));
+        let collections = crate::database::list_collections(test.pool()).await.unwrap();
+        assert!(collections.contains(&"orders".to_string()) && !collections.iter().any(|c| c.starts_with("orders/")));
+    }
+
+    #[tokio::test]
+    async fn replace_and_update_rewrite_the_split_off_parts() {
+        let (test, store) = nested(json!({ "nested": true })).await;
+        let id = store.insert("orders", &json!({ "name": "a", "address": { "city": "Oslo", "geo": { "lat": 1 } } })).await.unwrap();
+
+        store.replace("orders", id, &json!({ "name": "b", "address": { "zip": "0150" } }), None).await.unwrap();
+        assert_eq!(read(&store, id).await, json!({ "name": "b", "address": { "zip": "0150" } }));
+        assert_eq!(test.row_count("orders/address").await, 1);
+        assert_eq!(test.row_count("orders/address/geo").await, 0);
+
+        store.update("orders", id, &json!({ "address": { "city": "Bergen" }, "billing": { "city": "Oslo" } }), None).await.unwrap();
+        assert_eq!(read(&store, id).await, json!({ "name": "b", "address": { "city": "Bergen" }, "billing": { "city": "Oslo" } }));
+
+        store.update("orders", id, &json!({ "address": "gone" }), None).await.unwrap();
+        assert_eq!(read(&store, id).await, json!({ "name": "b", "address": "gone", "billing": { "city": "Oslo" } }));
+        assert_eq!(test.row_count("orders/address").await, 0);
+    }
+
+    #[tokio::test]
+    async fn delete_removes_the_rows_of_every_level() {
+        let (test, store) = nested(json!({ "nested": true, "arrays": true })).await;
+        let doc = json!({ "address": { "geo": { "lat": 1 } }, "lines": [{ "sku": "a", "dims": { "w": 1 } }, 2] });
+        let first = store.insert("orders", &doc).await.unwrap();
+        let second = store.insert("orders", &doc).await.unwrap();
+        store.delete("orders", first, None).await.unwrap();
+        for (chain, rows) in [("address", 1), ("address/geo", 1), ("lines_items", 2), ("lines_items/dims", 1)] {
+            assert_eq!(test.row_count(&nested_uri("orders", chain)).await, rows, "{}", chain);
+        }
+        assert_eq!(read(&store, second).await, doc);
+        store.delete_many("orders", &json!({}), false).await.unwrap();
+        for chain in ["address", "address/geo", "lines_items", "lines_items/dims"] {
+            assert_eq!(test.row_count(&nested_uri("orders", chain)).await, 0, "{}", chain);
+        }
+    }
+
+    #[tokio::test]
+    async fn filters_reach_the_fields_of_split_off_objects() {
+        let (_test, store) = nested(json!({ "nested": true })).await;
+        for (name, city, lat) in [("a", "Oslo", 59), ("b", "Bergen", 60), ("c", "Oslo", 61)] {
+            store.insert("orders", &json!({ "name": name, "address": { "city": city, "geo": { "lat": lat } } })).await.unwrap();
+        }
+        store.insert("orders", &json!({ "name": "d" })).await.unwrap();
+
+        let oslo = json!({ "address.city": "Oslo" });
+        let found = store.find("orders", &oslo).await.unwrap();
+        assert_eq!(ids(&found), vec![1, 3]);
+        assert_eq!(found[0]["address"], json!({ "city": "Oslo", "geo": { "lat": 59 } }));
+        assert_eq!(store.count("orders", &oslo).await.unwrap(), 2);
+        assert_eq!(ids(&store.find("orders", &json!({ "address.geo.lat": { "$gt": 59 }, "name": { "$ne": "c" } })).await.unwrap()), vec![2]);
+        // 与文档自己的列一样, $ne 不匹配没有这个字段的文档
+        assert_eq!(ids(&store.find("orders", &json!({ "address.city": { "$ne": "Oslo" } })).await.unwrap()), vec![2]);
+        assert_eq!(ids(&store.find("orders", &json!({ "address": { "$exists": false } })).await.unwrap()), vec![4]);
+
+        let page = PageQuery { sort: vec![("name".to_string(), true)], fields: Some(vec!["address".to_string()]), offset: 1, limit: Some(1) };
+        let paged = store.find_page("orders", &oslo, &page).await.unwrap();
+        assert_eq!(paged.total, 2);
+        assert_eq!(paged.docs, vec![json!({ "id": 1, "address": { "city": "Oslo", "geo": { "lat": 59 } } })]);
+
+        let sorted = PageQuery { sort: vec![("address".to_string(), false)], ..PageQuery::default() };
+        assert!(matches!(store.find_page("orders", &oslo, &sorted).await, Err(StoreError::Invalid(_))));
+        assert!(matches!(store.find("orders", &json!({ "address.zip": "1" })).await, Err(StoreError::Invalid(_))));
+        assert!(matches!(store.find("orders", &json!({ "address": { "city": "Oslo" } })).await, Err(StoreError::Invalid(_))));
+        assert!(matches!(store.delete_many("orders", &oslo, true).await, Err(StoreError::Invalid(_))));
+    }
+
+    #[tokio::test]
+    async fn a_failed_nested_write_leaves_the_document_as_it_was() {
+        let (test, store) = nested(json!({ "nested": true })).await;
+        // SQLite 分不清只差大小写的列, 子表的这一行写不进去
+        let clash = json!({ "name": "x", "address": { "City": 1, "city": 2 } });
+        assert!(matches!(store.insert("orders", &clash).await, Err(StoreError::Invalid(_))));
+        assert_eq!(test.row_count("orders").await, 0);
+
+        let doc = json!({ "name": "a", "address": { "city": "Oslo" } });
+        let id = store.insert("orders", &doc).await.unwrap();
+        assert!(matches!(store.replace("orders", id, &clash, None).await, Err(StoreError::Invalid(_))));
+        assert_eq!(read(&store, id).await, doc);
+        assert!(matches!(store.update("orders", id, &json!({ "name": "y", "address": { "Zip": 1, "zip": 2 } }), None).await, Err(StoreError::Invalid(_))));
+        assert_eq!(read(&store, id).await, doc);
+        let clash = json!({ "name": "a", "address": { "Zip": 1, "zip": 2 } });
+        assert!(matches!(store.upsert("orders", "name", &clash).await, Err(StoreError::Invalid(_))));
+        assert_eq!(read(&store, id).await, doc);
+        assert_eq!(store.list("orders").await.unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn arrays_round_trip_with_every_kind_of_element() {
+        let (test, store) = nested(json!({ "nested": true, "arrays": true })).await;
+        let doc = json!({
+            "tags": ["b", 1, null, true, 2.5],
+            "lines": [{ "sku": "A", "dims": { "w": 2 }, "codes": ["x", "y"] }, { "sku": "B" }, [1, 2], { "id": 5 }],
+            "empty": [],
+            "meta": { "labels": ["a"] },
+        });
+        let id = store.insert("orders", &doc).await.unwrap();
+        assert_eq!(read(&store, id).await, doc);
+        assert_eq!(test.row_count("orders/tags_items").await, 5);
+        assert_eq!(test.row_count("orders/lines_items").await, 4);
+        assert_eq!(test.row_count("orders/lines_items/dims").await, 1);
+        assert_eq!(test.row_count("orders/lines_items/codes_items").await, 2);
+        assert_eq!(test.row_count("orders/meta/labels_items").await, 1);
+        assert!(test.columns("orders/empty_items").await.is_empty());
+        let empty: Option<String> = sqlx::query_scalar("SELECT empty FROM orders").fetch_one(test.pool()).await.unwrap();
+        assert_eq!(empty.as_deref(), Some("[]"));
+    }
+
+    #[tokio::test]
+    async fn elements_come_back_in_idx_order() {
+        let (test, store) = nested(json!({ "arrays": true })).await;
+        let id = store.insert("orders", &json!({ "tags": ["a", { "k": 1 }, "c", "d"] })).await.unwrap();
+        // 让 id 的顺序与 idx 相反
+        sqlx::query("UPDATE orders__tags_items SET idx = 3 - idx").execute(test.pool()).await.unwrap();
+        assert_eq!(read(&store, id).await, json!({ "tags": ["d", "c", { "k": 1 }, "a"] }));
+
+        store.update("orders", id, &json!({ "tags": ["z", "y"] }), None).await.unwrap();
+        assert_eq!(read(&store, id).await, json!({ "tags": ["z", "y"] }));
+        assert_eq!(test.row_count("orders/tags_items").await, 2);
+        store.update("orders", id, &json!({ "tags": [] }), None).await.unwrap();
+        assert_eq!(read(&store, id).await, json!({ "tags": [] }));
+        assert_eq!(test.row_count("orders/tags_items").await, 0);
+    }
+
+    #[tokio::test]
+    async fn arrays_match_by_their_elements() {
+        let (_test, store) = nested(json!({ "nested": true, "arrays": true })).await;
+        for doc in [
+            json!({ "tags": ["rust", "go"] }),
+            json!({ "tags": ["go"] }),
+            json!({ "tags": [] }),
+            json!({ "name": "no tags" }),
+            json!({ "lines": [{ "sku": "A1", "qty": 2 }, { "sku": "B", "qty": 5, "dims": { "w": 3 } }] }),
+        ] {
+            store.insert("orders", &doc).await.unwrap();
+        }
+        let found = |filter: Value| {
+            let store = store.clone();
+            async move { ids(&store.find("orders", &filter).await.unwrap()) }
+        };
+        assert_eq!(found(json!({ "tags": "rust" })).await, vec![1]);
+        assert_eq!(found(json!({ "tags": { "$in": ["go", "java"] } })).await, vec![1, 2]);
+        // 没有一个元素是 "rust"; 空数组比较自己的列
+        assert_eq!(found(json!({ "tags": { "$ne": "rust" } })).await, vec![2, 3]);
+        assert_eq!(found(json!({ "tags": { "$nin": ["rust", "go"] } })).await, vec![3]);
+        assert_eq!(found(json!({ "tags": { "$exists": false } })).await, vec![4, 5]);
+        assert_eq!(found(json!({ "lines.sku": "B" })).await, vec![5]);
+        assert_eq!(found(json!({ "lines.qty": { "$gte": 3 }, "lines.sku": "A1" })).await, vec![5]);
+        assert_eq!(found(json!({ "lines.dims.w": 3 })).await, vec![5]);
+        assert_eq!(found(json!({ "lines.sku": { "$ne": "A1" } })).await, vec![1, 2, 3, 4]);
+        assert_eq!(found(json!({ "lines.sku": { "$nin": ["A1", "B"] } })).await, vec![1, 2, 3, 4]);
+        assert_eq!(store.count("orders", &json!({ "$or": [{ "tags": "go" }, { "lines.qty": 2 }] })).await.unwrap(), 3);
+        assert!(matches!(store.find("orders", &json!({ "tags": ["go"] })).await, Err(StoreError::Invalid(_))));
+        assert!(matches!(store.find("orders", &json!({ "lines": { "$in": [{ "sku": "B" }] } })).await, Err(StoreError::Invalid(_))));
+    }
+
+    #[test]
+    fn chains_map_back_to_the_keys_they_came_from() {
+        for (path, chain, table) in [
+            (vec![("address", false)], "address", "orders__address"),
+            (vec![("address", false), ("geo", false)], "address/geo", "orders__address__geo"),
+            (vec![("tags", true)], "tags_items", "orders__tags_items"),
+            (vec![("a", false), ("a", true), ("a", false)], "a/a_items/a", "orders__a__a_items__a"),
+        ] {
+            let built = path.iter().fold(String::new(), |parent, (key, array)| child_chain(&parent, &segment(key, *array)));
+            assert_eq!(built, chain);
+            assert_eq!(chain_table("orders", chain), table);
+            assert_eq!(root_key(chain), path[0].0);
+            assert_eq!(holds_elements(chain), path.last().unwrap().1);
+            // 从最后一级往上拆回每一级的键
+            let mut rest = Some(chain);
+            for (key, array) in path.iter().rev() {
+                let (parent, last) = parent_of(rest.unwrap());
+                assert_eq!(key_of(last), (key.to_string(), *array));
+                rest = parent;
+            }
+            assert_eq!(rest, None);
+        }
+        assert_eq!(chain_table("orders", ""), "orders");
+    }
+
+    #[tokio::test]
+    async fn same_keys_at_different_depths_keep_tables_of_their_own() {
+        let (test, store) = nested(json!({ "nested": true, "arrays": true })).await;
+        let doc = json!({
+            "a": { "a": { "a": { "a": 1, "b": "deep" } }, "b": { "a": 2 } },
+            "b": { "a": [{ "a": { "a": 3 } }, { "b": 4 }] },
+            "c": [{ "a": [5, 6] }],
+        });
+        let id = store.insert("orders", &doc).await.unwrap();
+        assert_eq!(read(&store, id).await, doc);
+        let expected = ["a", "b", "c_items", "a/a", "a/b", "b/a_items", "c_items/a_items", "a/a/a", "b/a_items/a"];
+        assert_eq!(chains(test.pool(), "orders").await.unwrap(), expected);
+        for chain in expected {
+            assert!(!test.columns(&nested_uri("orders", chain)).await.is_empty(), "{}", chain);
+        }
+        assert_eq!(test.row_count("orders/a/a/a").await, 1);
+        assert_eq!(test.row_count("orders/b/a_items").await, 2);
+        assert_eq!(test.row_count("orders/c_items/a_items").await, 2);
+
+        let other = store.insert("orders", &json!({ "a": { "b": { "a": 1 } } })).await.unwrap();
+        for (filter, found) in [
+            (json!({ "a.a.a.a": 1 }), vec![id]),
+            (json!({ "a.a.a.b": "deep" }), vec![id]),
+            (json!({ "a.b.a": 1 }), vec![other]),
+            (json!({ "a.b.a": 2 }), vec![id]),
+            (json!({ "b.a.a.a": 3 }), vec![id]),
+            (json!({ "b.a.b": 4 }), vec![id]),
+            (json!({ "c.a": 6 }), vec![id]),
+            (json!({ "a.a": { "$exists": true } }), vec![id]),
+        ] {
+            assert_eq!(ids(&store.find("orders", &filter).await.unwrap()), found, "{}", filter);
+        }
+        // 文档自己的表里别的文档加出的列为 null
+        assert_eq!(read(&store, other).await, json!({ "a": { "b": { "a": 1 } }, "b": null, "c": null }));
+    }
+
+    #[test]
+    fn keys_that_are_not_identifiers_are_encoded_reversibly() {
+        assert_eq!(segment("ship to", false), "x7368697020746f_");
+        assert_eq!(segment("ship to", true), "x7368697020746f_items_");
+        for key in ["ship to", "a.b", "user-name", "say \"hi\"", "it's", "", "_private", "tags_items", "a__b", "trailing_", "日本", "x61_", "Ünïcode"] {
+            for array in [false, true] {
+                let encoded = segment(key, array);
+                assert!(encoded.starts_with('x') && encoded.ends_with('_'), "{}", encoded);
+                assert!(encoded.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') && !encoded.contains("__"), "{}", encoded);
+                assert_eq!(key_of(&encoded), (key.to_string(), array));
+            }
+        }
+        for key in ["address", "ship_to", "x61", "Geo2"] {
+            assert_eq!(segment(key, false), key);
+            assert_eq!(key_of(&segment(key, true)), (key.to_string(), true));
+        }
+        assert_eq!(chain_table("orders", &child_chain(&segment("a b", false), &segment("c", true))), "orders__x612062___c_items");
+    }
+
+    #[tokio::test]
+    async fn any_key_round_trips_through_its_table() {
+        let (test, store) = nested(json!({ "nested": true, "arrays": true })).await;
+        let doc = json!({
+            "ship to": { "street name": "Main", "no.": 5, "geo-point": { "lat \"N\"": 1.5 } },
+            "a.b": { "c": 1 },
+            "it's": [{ "x-y": 1 }, "two"],
+            "tags_items": ["kept apart from tags"],
+            "tags": ["a"],
+            "__proto__": { "-": "dash" },
+            "日本": { "東京": true },
+        });
+        let id = store.insert("orders", &doc).await.unwrap();
+        assert_eq!(read(&store, id).await, doc);
+        assert_eq!(read(&store, id).await["tags_items"], json!(["kept apart from tags"]));
+
+        // 表名只由字母、数字和 "_" 组成, _nested_tables 中的 chain 能还原出键
+        let recorded: Vec<(String, String)> = sqlx::query_as("SELECT chain, table_name FROM _nested_tables WHERE collection = 'orders'")
+            .fetch_all(test.pool())
+            .await
+            .unwrap();
+        assert_eq!(recorded.len(), 8);
+        for (chain, table) in &recorded {
+            assert_eq!(&chain_table("orders", chain), table);
+            assert!(table.chars().all(|c| c.is_ascii_alphanumeric() || c == '_'), "{}", table);
+        }
+        let mut roots: Vec<String> = recorded.iter().filter(|(chain, _)| !chain.contains('/')).map(|(chain, _)| root_key(chain)).collect();
+        roots.sort();
+        assert_eq!(roots, ["__proto__", "a.b", "it's", "ship to", "tags", "tags_items", "日本"]);
+
+        for (filter, found) in [
+            (json!({ "ship to.street name": "Main" }), vec![id]),
+            (json!({ "ship to.geo-point.lat \"N\"": { "$gt": 1 } }), vec![id]),
+            (json!({ "it's.x-y": 1 }), vec![id]),
+            (json!({ "it's": "two" }), vec![id]),
+            (json!({ "tags": "kept apart from tags" }), vec![]),
+            (json!({ "tags_items": "kept apart from tags" }), vec![id]),
+        ] {
+            assert_eq!(ids(&store.find("orders", &filter).await.unwrap()), found, "{}", filter);
+        }
+
+        store.update("orders", id, &json!({ "ship to": { "street name": "High" } }), None).await.unwrap();
+        assert_eq!(read(&store, id).await["ship to"], json!({ "street name": "High" }));
+        store.delete("orders", id, None).await.unwrap();
+        for (chain, _) in &recorded {
+            assert_eq!(test.row_count(&nested_uri("orders", chain)).await, 0, "{}", chain);
+        }
+    }
+
+    #[tokio::test]
+    async fn every_document_keeps_rows_of_its_own() {
+        let (test, store) = nested(json!({ "nested": true, "arrays": true })).await;
+        let docs: Vec<Value> = (1..=3)
+            .map(|n| json!({ "n": n, "address": { "city": format!("city {}", n) }, "lines": (0..n).map(|i| json!({ "qty": i })).collect::<Vec<_>>() }))
+            .collect();
+        let mut inserted = Vec::new();
+        for doc in &docs {
+            inserted.push(store.insert("orders", doc).await.unwrap());
+        }
+        assert_eq!(inserted, [1, 2, 3]);
+        assert_eq!(test.row_count("orders").await, 3);
+        assert_eq!(test.row_count("orders/address").await, 3);
+        assert_eq!(test.row_count("orders/lines_items").await, 6);
+        // 每一行子表都挂在自己的文档下
+        let parents: Vec<(i64, i64)> = sqlx::query_as("SELECT _parent, COUNT(*) FROM orders__lines_items GROUP BY _parent ORDER BY _parent")
+            .fetch_all(test.pool())
+            .await
+            .unwrap();
+        assert_eq!(parents, [(1, 1), (2, 2), (3, 3)]);
+
+        for (id, doc) in inserted.iter().zip(&docs) {
+            assert_eq!(&read(&store, *id).await, doc);
+        }
+        let listed = store.list("orders").await.unwrap();
+        assert_eq!(ids(&listed), inserted);
+        for (listed, doc) in listed.iter().zip(&docs) {
+            assert_eq!(listed["address"], doc["address"]);
+            assert_eq!(listed["lines"], doc["lines"]);
+        }
+        let page = PageQuery { sort: vec![("n".to_string(), true)], limit: Some(2), ..PageQuery::default() };
+        let paged = store.find_page("orders", &json!({}), &page).await.unwrap();
+        assert_eq!((ids(&paged.docs), paged.total), (vec![3, 2], 3));
+        assert_eq!(paged.docs[1]["lines"], docs[1]["lines"]);
+        let many = store.get_many("orders", &[3, 1]).await.unwrap();
+        assert_eq!(many.iter().map(|doc| doc["address"]["city"].clone()).collect::<Vec<_>>(), [json!("city 3"), json!("city 1")]);
+
+        // 删除一个文档只删它自己的行
+        store.delete("orders", 2, None).await.unwrap();
+        assert_eq!(test.row_count("orders/address").await, 2);
+        assert_eq!(test.row_count("orders/lines_items").await, 4);
+        assert_eq!(read(&store, 1).await, docs[0]);
+        assert_eq!(read(&store, 3).await, docs[2]);
+    }
+}
@@ -0,0 +1,390 @@
+//! OData query options.
+//!
+//! `GET /{uri}` understands a subset of the OData system query options, so
+//! tools that only speak OData can read collections:
+//!
+//! | Option     | Example                                      |
+//! |------------|----------------------------------------------|
+//! | `$filter`  | `age ge 18 and (city eq 'Oslo' or not vip)`  |
+//! | `$orderby` | `age desc,name`                              |
+//! | `$top`     | `10`                                         |
+//! | `$skip`    | `20`                                         |
+//!
+//! `$filter` is translated to the same [`Filter`] tree as filter documents;
+//! it knows `eq`, `ne`, `gt`, `ge`, `lt`, `le`, `in`, `and`, `or`, `not`
+//! and parentheses, string literals in single quotes, numbers, `true`,
+//! `false` and `null`. A bare field name is true when the field is.
+//! Functions such as `contains` are not supported.
+
+use serde_json::{Number, Value};
+use std::cmp::Ordering;
+
+use crate::query::{CompareOp, Filter, QueryError};
+
+/// The OData options of a request.
+#[derive(Debug, Default, PartialEq)]
+pub struct Options {
+    pub filter: Option<Filter>,
+    /// Fields to sort by, and whether descending.
+    pub orderby: Vec<(String, bool)>,
+    pub top: Option<usize>,
+    pub skip: usize,
+}
+
+/// Reads the query parameters starting with `$`; others are left alone.
+pub fn parse(params: &[(String, String)]) -> Result<Options, QueryError> {
+    let mut options = Options::default();
+    for (name, value) in params.iter().filter(|(name, _)| name.starts_with('$')) {
+        match name.as_str() {
+            "$filter" => options.filter = Some(parse_filter(value)?),
+            "$orderby" => options.orderby = parse_orderby(value)?,
+            "$top" => options.top = Some(count(name, value)?),
+            "$skip" => options.skip = count(name, value)?,
+            other => return Err(QueryError(format!("unsupported query option '{}'", other))),
+        }
+    }
+    Ok(options)
+}
+
+fn count(name: &str, value: &str) -> Result<usize, QueryError> {
+    value.trim().parse().map_err(|_| QueryError(format!("'{}' expects a non-negative integer", name)))
+}
+
+fn parse_orderby(value: &str) -> Result<Vec<(String, bool)>, QueryError> {
+    value
+        .split(',')
+        .map(|item| {
+            let mut words = item.split_whitespace();
+            let field = words.next().filter(|f| identifier(f)).ok_or_else(|| QueryError(format!("bad $orderby item '{}'", item.trim())))?;
+            let descending = match words.next() {
+                None | Some("asc") => false,
+                Some("desc") => true,
+                Some(other) => return Err(QueryError(format!("unknown $orderby direction '{}'", other))),
+            };
+            match words.next() {
+                None => Ok((field.to_string(), descending)),
+                Some(_) => Err(QueryError(format!("bad $orderby item '{}'", item.trim()))),
+            }
+        })
+        .collect()
+}
+
+impl Options {
+    /// Sorts `docs` by `$orderby`, then applies `$skip` and `$top`.
+    pub fn apply(&self, mut docs: Vec<Value>) -> Vec<Value> {
+        if !self.orderby.is_empty() {
+            docs.sort_by(|a, b| {
+                self.orderby
+                    .iter()
+                    .map(|(field, descending)| {
+                        let order = compare(a.get(field).unwrap_or(&Value::Null), b.get(field).unwrap_or(&Value::Null));
+                        if *descending { order.reverse() } else { order }
+                    })
+                    .find(|order| order.is_ne())
+                    .unwrap_or(Ordering::Equal)
+            });
+        }
+        docs.into_iter().skip(self.skip).take(self.top.unwrap_or(usize::MAX)).collect()
+    }
+}
+
+// 排序时 null 在前, 然后是布尔值、数字、字符串
+fn compare(a: &Value, b: &Value) -> Ordering {
+    fn rank(value: &Value) -> u8 {
+        match value {
+            Value::Null => 0,
+            Value::Bool(_) => 1,
+            Value::Number(_) => 2,
+            Value::String(_) => 3,
+            _ => 4,
+        }
+    }
+    match (a, b) {
+        (Value::Bool(a), Value::Bool(b)) => a.cmp(b),
+        (Value::Number(a), Value::Number(b)) => a.as_f64().partial_cmp(&b.as_f64()).unwrap_or(Ordering::Equal),
+        (Value::String(a), Value::String(b)) => a.cmp(b),
+        _ => rank(a).cmp(&rank(b)),
+    }
+}
+
+/// Whether `word` can name a field: a letter or `_`, then letters, digits and `_`.
+pub fn identifier(word: &str) -> bool {
+    word.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_') && word.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Word(String),
+    Literal(Value),
+    Open,
+    Close,
+    Comma,
+}
+
+fn tokenize(expr: &str) -> Result<Vec<Token>, QueryError> {
+    let mut tokens = Vec::new();
+    let mut chars = expr.chars().peekable();
+    while let Some(&c) = chars.peek() {
+        match c {
+            c if c.is_whitespace() => {
+                chars.next();
+            }
+            '(' | ')' | ',' => {
+                chars.next();
+                tokens.push(match c {
+                    '(' => Token::Open,
+                    ')' => Token::Close,
+                    _ => Token::Comma,
+                });
+            }
+            // 字符串中的 '' 表示一个单引号
+            '\'' => {
+                chars.next();
+                let mut text = String::new();
+                loop {
+                    match chars.next() {
+                        Some('\'') if chars.peek() == Some(&'\'') => {
+                            chars.next();
+                            text.push('\'');
+                        }
+                        Some('\'') => break,
+                        Some(c) => text.push(c),
+                        None => return Err(QueryError("unterminated string in $filter".to_string())),
+                    }
+                }
+                tokens.push(Token::Literal(Value::String(text)));
+            }
+            _ => {
+                let mut word = String::new();
+                while let Some(&c) = chars.peek().filter(|c| !c.is_whitespace() && !"(),'".contains(**c)) {
+                    word.push(c);
+                    chars.next();
+                }
+                tokens.push(match word.as_str() {
+                    "true" => Token::Literal(Value::Bool(true)),
+                    "false" => Token::Literal(Value::Bool(false)),
+                    "null" => Token::Literal(Value::Null),
+                    _ if identifier(&word) => Token::Word(word),
+                    _ => Token::Literal(number(&word)?),
+                });
+            }
+        }
+    }
+    Ok(tokens)
+}
+
+fn number(word: &str) -> Result<Value, QueryError> {
+    let bad = || QueryError(format!("unexpected '{}' in $filter", word));
+    if let Ok(n) = word.parse::<i64>() {
+        return Ok(Value::Number(n.into()));
+    }
+    let n: f64 = word.parse().map_err(|_| bad())?;
+    Number::from_f64(n).map(Value::Number).ok_or_else(bad)
+}
+
+/// Translates a `$filter` expression into a [`Filter`].
+pub fn parse_filter(expr: &str) -> Result<Filter, QueryError> {
+    let mut parser = Parser { tokens: tokenize(expr)?, at: 0 };
+    let filter = parser.or()?;
+    match parser.tokens.get(parser.at) {
+        None => Ok(filter),
+        Some(token) => Err(QueryError(format!("unexpected {} in $filter", describe(token)))),
+    }
+}
+
+fn describe(token: &Token) -> String {
+    match token {
+        Token::Word(word) => format!("'{}'", word),
+        Token::Literal(value) => value.to_string(),
+        Token::Open => "'('".to_string(),
+        Token::Close => "')'".to_string(),
+        Token::Comma => "','".to_string(),
+    }
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    at: usize,
+}
+
+impl Parser {
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.at).cloned();
+        self.at += 1;
+        token
+    }
+
+    fn keyword(&mut self, keyword: &str) -> bool {
+        let found = matches!(self.tokens.get(self.at), Some(Token::Word(word)) if word == keyword);
+        if found {
+            self.at += 1;
+        }
+        found
+    }
+
+    fn expect(&mut self, expected: Token) -> Result<(), QueryError> {
+        match self.next() {
+            Some(token) if token == expected => Ok(()),
+            Some(token) => Err(QueryError(format!("expected {} in $filter, found {}", describe(&expected), describe(&token)))),
+            None => Err(QueryError(format!("expected {} at the end of $filter", describe(&expected)))),
+        }
+    }
+
+    fn or(&mut self) -> Result<Filter, QueryError> {
+        let mut items = vec![self.and()?];
+        while self.keyword("or") {
+            items.push(self.and()?);
+        }
+        Ok(if items.len() == 1 { items.remove(0) } else { Filter::Or(items) })
+    }
+
+    fn and(&mut self) -> Result<Filter, QueryError> {
+        let mut items = vec![self.unary()?];
+        while self.keyword("and") {
+            items.push(self.unary()?);
+        }
+        Ok(if items.len() == 1 { items.remove(0) } else { Filter::And(items) })
+    }
+
+    fn unary(&mut self) -> Result<Filter, QueryError> {
+        if self.keyword("not") {
+            return Ok(self.unary()?.negate());
+        }
+        match self.next() {
+            Some(Token::Open) => {
+                let filter = self.or()?;
+                self.expect(Token::Close)?;
+                Ok(filter)
+            }
+            Some(Token::Word(function)) if self.tokens.get(self.at) == Some(&Token::Open) => {
+                Err(QueryError(format!("unsupported function '{}' in $filter", function)))
+            }
+            Some(Token::Word(field)) => self.comparison(field),
+            Some(token) => Err(QueryError(format!("expected a field in $filter, found {}", describe(&token)))),
+            None => Err(QueryError("$filter ends too early".to_string())),
+        }
+    }
+
+    fn comparison(&mut self, field: String) -> Result<Filter, QueryError> {
+        let op = match self.tokens.get(self.at) {
+            Some(Token::Word(op)) => op.clone(),
+            // 单独的字段名表示该字段为真
+            _ => return Ok(Filter::Compare { field, op: CompareOp::Eq, value: Value::Bool(true) }),
+        };
+        let op = match op.as_str() {
+            "eq" => CompareOp::Eq,
+            "ne" => CompareOp::Ne,
+            "gt" => CompareOp::Gt,
+            "ge" => CompareOp::Gte,
+            "lt" => CompareOp::Lt,
+            "le" => CompareOp::Lte,
+            "in" => {
+                self.at += 1;
+                return Ok(Filter::In { field, values: self.list()?, negated: false });
+            }
+            "and" | "or" => return Ok(Filter::Compare { field, op: CompareOp::Eq, value: Value::Bool(true) }),
+            other => return Err(QueryError(format!("unsupported operator '{}' in $filter", other))),
+        };
+        self.at += 1;
+        Ok(Filter::Compare { field, op, value: self.literal()? })
+    }
+
+    fn literal(&mut self) -> Result<Value, QueryError> {
+        match self.next() {
+            Some(Token::Literal(value)) => Ok(value),
+            Some(token) => Err(QueryError(format!("expected a value in $filter, found {}", describe(&token)))),
+            None => Err(QueryError("$filter ends too early".to_string())),
+        }
+    }
+
+    fn list(&mut self) -> Result<Vec<Value>, QueryError> {
+        self.expect(Token::Open)?;
+        let mut values = vec![self.literal()?];
+        while let Some(Token::Comma) = self.tokens.get(self.at) {
+            self.at += 1;
+            values.push(self.literal()?);
+        }
+        self.expect(Token::Close)?;
+        Ok(values)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn filter(expr: &str) -> Value {
+        parse_filter(expr).unwrap().to_document()
+    }
+
+    fn options(params: &[(&str, &str)]) -> Result<Options, QueryError> {
+        parse(&params.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect::<Vec<_>>())
+    }
+
+    #[test]
+    fn filter_respects_precedence_and_parentheses() {
+        assert_eq!(
+            filter("age ge 18 and (city eq 'Oslo' or not vip)"),
+            json!({ "$and": [
+                { "age": { "$gte": 18 } },
+                { "$or": [{ "city": { "$eq": "Oslo" } }, { "vip": { "$ne": true } }] },
+            ] })
+        );
+        assert_eq!(
+            filter("a eq 1 or b eq 2 and c eq 3"),
+            json!({ "$or": [{ "a": { "$eq": 1 } }, { "$and": [{ "b": { "$eq": 2 } }, { "c": { "$eq": 3 } }] }] })
+        );
+        assert_eq!(
+            filter("not (a lt 1 or b in ('x', 'y'))"),
+            json!({ "$and": [{ "a": { "$gte": 1 } }, { "b": { "$nin": ["x", "y"] } }] })
+        );
+    }
+
+    #[test]
+    fn filter_reads_literals() {
+        assert_eq!(filter("name eq 'O''Brien'"), json!({ "name": { "$eq": "O'Brien" } }));
+        assert_eq!(filter("score le -1.5"), json!({ "score": { "$lte": -1.5 } }));
+        assert_eq!(filter("deleted eq null"), json!({ "deleted": { "$eq": null } }));
+        assert_eq!(filter("active ne false"), json!({ "active": { "$ne": false } }));
+        assert_eq!(filter("vip and active"), json!({ "$and": [{ "vip": { "$eq": true } }, { "active": { "$eq": true } }] }));
+    }
+
+    #[test]
+    fn malformed_filters_are_rejected() {
+        for (expr, message) in [
+            ("contains(name, 'a')", "unsupported function 'contains' in $filter"),
+            ("name like 'a'", "unsupported operator 'like' in $filter"),
+            ("name eq 'a", "unterminated string in $filter"),
+            ("(a eq 1", "expected ')' at the end of $filter"),
+            ("a eq 1 b", "unexpected 'b' in $filter"),
+            ("a eq", "$filter ends too early"),
+            ("a in (1 2)", "expected ')' in $filter, found 2"),
+            ("a gt #", "unexpected '#' in $filter"),
+            ("1 eq a", "expected a field in $filter, found 1"),
+        ] {
+            assert_eq!(parse_filter(expr), Err(QueryError(message.to_string())), "{}", expr);
+        }
+    }
+
+    #[test]
+    fn options_are_read_from_dollar_parameters() {
+        let parsed = options(&[("$orderby", "age desc, name"), ("$top", "10"), ("$skip", "5"), ("name", "x")]).unwrap();
+        assert_eq!(parsed.orderby, vec![("age".to_string(), true), ("name".to_string(), false)]);
+        assert_eq!((parsed.top, parsed.skip, parsed.filter), (Some(10), 5, None));
+        assert!(options(&[("$top", "-1")]).is_err());
+        assert!(options(&[("$orderby", "age up")]).is_err());
+        assert!(options(&[("$orderby", "age desc x")]).is_err());
+        assert!(options(&[("$expand", "x")]).is_err());
+    }
+
+    #[test]
+    fn apply_sorts_nulls_first_then_pages() {
+        let docs = vec![json!({ "a": 2, "b": "x" }), json!({ "b": "y" }), json!({ "a": 1, "b": "z" }), json!({ "a": 2, "b": "w" })];
+        let parsed = options(&[("$orderby", "a desc,b")]).unwrap();
+        let b = |docs: Vec<Value>| docs.into_iter().map(|d| d["b"].clone()).collect::<Vec<_>>();
+        assert_eq!(b(parsed.apply(docs.clone())), vec![json!("w"), json!("x"), json!("z"), json!("y")]);
+        let parsed = options(&[("$orderby", "a"), ("$skip", "1"), ("$top", "2")]).unwrap();
+        assert_eq!(b(parsed.apply(docs)), vec![json!("z"), json!("x")]);
+    }
+}
@@ -0,0 +1,301 @@
+//! OpenID Connect login for the admin UI (`oidc` feature).
+//!
+//! `GET /_auth/login` redirects to the provider using the authorization code
+//! flow with PKCE; `GET /_auth/callback` exchanges the code, validates the ID
+//! token against the provider's JWKS (signature, issuer, audience, expiry and
+//! nonce), maps the configured claim to a role and starts a session.
+
+use actix_web::{web, HttpRequest, HttpResponse};
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use jsonwebtoken::jwk::JwkSet;
+use jsonwebtoken::{DecodingKey, Validation};
+use serde::Deserialize;
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, Instant};
+
+use crate::auth::Principal;
+use crate::config::{ConfigHandle, Oidc};
+use crate::sessions::{self, random_token, SessionStore};
+
+/// How long a user has to complete the login at the provider.
+const LOGIN_TIMEOUT: Duration = Duration::from_secs(600);
+
+#[derive(Debug, Clone, Deserialize)]
+struct Discovery {
+    issuer: String,
+    authorization_endpoint: String,
+    token_endpoint: String,
+    jwks_uri: String,
+}
+
+struct Pending {
+    nonce: String,
+    verifier: String,
+    next: String,
+    started: Instant,
+}
+
+#[derive(Default)]
+struct Provider {
+    /// Discovery document and keys, per issuer URL.
+    discovery: Mutex<Option<(String, Discovery, JwkSet)>>,
+    /// Logins in progress, keyed by `state`.
+    pending: Mutex<HashMap<String, Pending>>,
+}
+
+// 登录流程的状态在所有 worker 之间共享
+fn provider() -> &'static Provider {
+    static PROVIDER: OnceLock<Provider> = OnceLock::new();
+    PROVIDER.get_or_init(Provider::default)
+}
+
+fn client() -> &'static reqwest::Client {
+    static CLIENT: OnceLock<reqwest::Client> = OnceLock::new();
+    CLIENT.get_or_init(reqwest::Client::new)
+}
+
+#[derive(Debug)]
+pub enum OidcError {
+    NotConfigured,
+    Provider(String),
+    InvalidState,
+    InvalidToken(String),
+    NoAccess(String),
+}
+
+impl fmt::Display for OidcError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            OidcError::NotConfigured => write!(f, "OIDC login is not configured"),
+            OidcError::Provider(msg) => write!(f, "identity provider error: {}", msg),
+            OidcError::InvalidState => write!(f, "login expired or was not started here"),
+            OidcError::InvalidToken(msg) => write!(f, "invalid ID token: {}", msg),
+            OidcError::NoAccess(user) => write!(f, "{} has no access", user),
+        }
+    }
+}
+
+impl std::error::Error for OidcError {}
+
+impl From<reqwest::Error> for OidcError {
+    fn from(e: reqwest::Error) -> Self {
+        OidcError::Provider(e.to_string())
+    }
+}
+
+impl OidcError {
+    fn response(&self) -> HttpResponse {
+        match self {
+            OidcError::NotConfigured => HttpResponse::NotFound().json(self.to_string()),
+            OidcError::Provider(_) => HttpResponse::BadGateway().json(self.to_string()),
+            OidcError::InvalidState | OidcError::InvalidToken(_) => HttpResponse::BadRequest().json(self.to_string()),
+            OidcError::NoAccess(_) => HttpResponse::Forbidden().json(self.to_string()),
+        }
+    }
+}
+
+// 注册登录接口
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.route("/_auth/login", web::get().to(login))
+        .route("/_auth/callback", web::get().to(callback));
+}
+
+#[derive(Debug, Deserialize)]
+pub struct LoginParams {
+    pub next: Option<String>,
+}
+
+// 跳转到身份提供方登录
+pub async fn login(params: web::Query<LoginParams>, config: web::Data<ConfigHandle>) -> HttpResponse {
+    let Some(settings) = config.get().oidc.clone() else {
+        return OidcError::NotConfigured.response();
+    };
+    let discovery = match discover(&settings, false).await {
+        Ok((discovery, _)) => discovery,
+        Err(e) => return e.response(),
+    };
+
+    let state = random_token(16);
+    let nonce = random_token(16);
+    let verifier = random_token(32);
+    let challenge = URL_SAFE_NO_PAD.encode(Sha256::digest(verifier.as_bytes()));
+    let next = params.next.clone().filter(|n| is_local_path(n)).unwrap_or_else(|| "/_ui".to_string());
+
+    {
+        let mut pending = provider().pending.lock().unwrap();
+        pending.retain(|_, p| p.started.elapsed() < LOGIN_TIMEOUT);
+        pending.insert(state.clone(), Pending { nonce: nonce.clone(), verifier, next, started: Instant::now() });
+    }
+
+    let mut url = match reqwest::Url::parse(&discovery.authorization_endpoint) {
+        Ok(url) => url,
+        Err(e) => return OidcError::Provider(format!("bad authorization_endpoint: {}", e)).response(),
+    };
+    url.query_pairs_mut()
+        .append_pair("response_type", "code")
+        .append_pair("client_id", &settings.client_id)
+        .append_pair("redirect_uri", &settings.redirect_url)
+        .append_pair("scope", &settings.scopes.join(" "))
+        .append_pair("state", &state)
+        .append_pair("nonce", &nonce)
+        .append_pair("code_challenge", &challenge)
+        .append_pair("code_challenge_method", "S256");
+
+    HttpResponse::Found().insert_header(("Location", url.to_string())).finish()
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CallbackParams {
+    pub code: Option<String>,
+    pub state: Option<String>,
+    pub error: Option<String>,
+    pub error_description: Option<String>,
+}
+
+// 身份提供方回调: 换取并校验 ID token, 创建会话
+pub async fn callback(
+    req: HttpRequest,
+    params: web::Query<CallbackParams>,
+    config: web::Data<ConfigHandle>,
+    sessions: web::Data<SessionStore>,
+) -> HttpResponse {
+    let Some(settings) = config.get().oidc.clone() else {
+        return OidcError::NotConfigured.response();
+    };
+    if let Some(error) = &params.error {
+        let description = params.error_description.as_deref().unwrap_or_default();
+        return OidcError::Provider(format!("{} {}", error, description)).response();
+    }
+
+    let pending = params
+        .state
+        .as_ref()
+        .and_then(|state| provider().pending.lock().unwrap().remove(state))
+        .filter(|p| p.started.elapsed() < LOGIN_TIMEOUT);
+    let (Some(pending), Some(code)) = (pending, params.code.as_ref()) else {
+        return OidcError::InvalidState.response();
+    };
+
+    let principal = match complete_login(&settings, code, &pending).await {
+        Ok(principal) => principal,
+        Err(e) => {
+            log::warn!("OIDC login failed: {}", e);
+            return e.response();
+        }
+    };
+
+    log::info!("{} signed in via OIDC as {:?}", principal.id, principal.role);
+    let ttl = Duration::from_secs(config.get().sessions.ttl_secs);
+    let value = sessions.create(principal, ttl);
+    HttpResponse::Found()
+        .insert_header(("Location", pending.next))
+        .cookie(sessions::cookie(&req, value, ttl))
+        .finish()
+}
+
+#[derive(Debug, Deserialize)]
+struct TokenResponse {
+    id_token: String,
+}
+
+async fn complete_login(settings: &Oidc, code: &str, pending: &Pending) -> Result<Principal, OidcError> {
+    let (discovery, _) = discover(settings, false).await?;
+    let response = client()
+        .post(&discovery.token_endpoint)
+        .form(&[
+            ("grant_type", "authorization_code"),
+            ("code", code),
+            ("redirect_uri", &settings.redirect_url),
+            ("client_id", &settings.client_id),
+            ("client_secret", &settings.client_secret),
+            ("code_verifier", &pending.verifier),
+        ])
+        .send()
+        .await?;
+    if !response.status().is_success() {
+        let status = response.status();
+        let body = response.text().await.unwrap_or_default();
+        return Err(OidcError::Provider(format!("token endpoint returned {}: {}", status, body)));
+    }
+    let tokens: TokenResponse = response.json().await?;
+
+    let claims = validate(settings, &tokens.id_token).await?;
+    if claims.get("nonce").and_then(Value::as_str) != Some(pending.nonce.as_str()) {
+        return Err(OidcError::InvalidToken("nonce mismatch".to_string()));
+    }
+    principal(settings, &claims)
+}
+
+// 用提供方的公钥校验签名、issuer、audience 和过期时间
+async fn validate(settings: &Oidc, token: &str) -> Result<Value, OidcError> {
+    let header = jsonwebtoken::decode_header(token).map_err(|e| OidcError::InvalidToken(e.to_string()))?;
+    let kid = header.kid.ok_or_else(|| OidcError::InvalidToken("missing kid".to_string()))?;
+
+    let (discovery, mut keys) = discover(settings, false).await?;
+    if keys.find(&kid).is_none() {
+        // 提供方可能轮换了密钥, 重新获取一次
+        keys = discover(settings, true).await?.1;
+    }
+    let jwk = keys
+        .find(&kid)
+        .ok_or_else(|| OidcError::InvalidToken(format!("unknown key '{}'", kid)))?;
+    let key = DecodingKey::from_jwk(jwk).map_err(|e| OidcError::InvalidToken(e.to_string()))?;
+
+    let mut validation = Validation::new(header.alg);
+    validation.set_audience(&[&settings.client_id]);
+    validation.set_issuer(&[&discovery.issuer]);
+    jsonwebtoken::decode::<Value>(token, &key, &validation)
+        .map(|data| data.claims)
+        .map_err(|e| OidcError::InvalidToken(e.to_string()))
+}
+
+// 根据 role_claim 的取值映射角色, 取最高的一个
+fn principal(settings: &Oidc, claims: &Value) -> Result<Principal, OidcError> {
+    let id = ["email", "preferred_username", "sub"]
+        .iter()
+        .find_map(|c| claims.get(*c).and_then(Value::as_str))
+        .unwrap_or_default()
+        .to_string();
+
+    let values: Vec<&str> = match claims.get(&settings.role_claim) {
+        Some(Value::String(s)) => vec![s.as_str()],
+        Some(Value::Array(items)) => items.iter().filter_map(Value::as_str).collect(),
+        _ => Vec::new(),
+    };
+    let role = values
+        .iter()
+        .filter_map(|v| settings.roles.get(*v).copied())
+        .max()
+        .or(settings.default_role);
+
+    match role {
+        Some(role) => Ok(Principal { id, role, tenant: None }),
+        None => Err(OidcError::NoAccess(id)),
+    }
+}
+
+async fn discover(settings: &Oidc, refresh: bool) -> Result<(Discovery, JwkSet), OidcError> {
+    if !refresh {
+        if let Some((issuer, discovery, keys)) = provider().discovery.lock().unwrap().as_ref() {
+            if *issuer == settings.issuer {
+                return Ok((discovery.clone(), keys.clone()));
+            }
+        }
+    }
+
+    let url = format!("{}/.well-known/openid-configuration", settings.issuer.trim_end_matches('/'));
+    let discovery: Discovery = client().get(&url).send().await?.error_for_status()?.json().await?;
+    let keys: JwkSet = client().get(&discovery.jwks_uri).send().await?.error_for_status()?.json().await?;
+    *provider().discovery.lock().unwrap() = Some((settings.issuer.clone(), discovery.clone(), keys.clone()));
+    Ok((discovery, keys))
+}
+
+// 只允许跳转到本站路径, 避免开放重定向
+fn is_local_path(path: &str) -> bool {
+    path.starts_with('/') && !path.starts_with("//") && !path.contains('\\')
+}
@@ -0,0 +1,443 @@
+//! Time-partitioned collections.
+//!
+//! A collection listed in `partitioning` keeps its documents in one table
+//! per day or month, `_part_<collection>_<YYYYMMDD>` or
+//! `_part_<collection>_<YYYYMM>`, chosen by the Unix time in its `field`.
+//! The ids of a partition start at `period * 10^10 + 1`, where `period` counts
+//! days or months since 1970, so reads and writes by id go to one table.
+//! Listings read every partition in order; console filters that bound
+//! `field` only read the partitions in that range. With `retention` set, a
+//! background task drops partitions older than that many periods, which is
+//! a `DROP TABLE` instead of a `DELETE` through the whole collection.
+//!
+//! A new partition copies the columns of the newest one. Documents stay in
+//! their partition when `field` changes, and dedup only finds duplicates in
+//! the same partition. Documents stored before the collection was
+//! partitioned stay in its original table and are still read and written.
+
+use async_trait::async_trait;
+use serde_json::Value;
+use sqlx::SqlitePool;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::RwLock;
+
+use crate::access_log::civil;
+use crate::config::{ConfigHandle, Partitioning, Period};
+use crate::database::{collection_exists, copy_table, count_where, create_table, delete_where, find_page_where, find_where, table_name, update_where, version_of, SqliteStore};
+use crate::logging::now;
+use crate::metrics::Metrics;
+use crate::query::{parse_filter, quote, Filter};
+use crate::store::{DedupMode, DocumentStore, Inserted, PageQuery, Paged, StoreError, UpdatedMany, Upserted, Written};
+
+/// Ids each partition can hand out.
+pub const PARTITION_IDS: i64 = 10_000_000_000;
+
+/// How often expired partitions are looked for.
+const RETENTION_INTERVAL: Duration = Duration::from_secs(600);
+
+/// Days or months between 1970 and the Unix time `ts`.
+pub fn period_of(period: Period, ts: f64) -> i64 {
+    let secs = ts.max(0.0) as u64;
+    match period {
+        Period::Day => (secs / 86400) as i64,
+        Period::Month => {
+            let (year, month, ..) = civil(secs);
+            (year - 1970) * 12 + month as i64 - 1
+        }
+    }
+}
+
+// 分区表名带上日期, 以 _ 开头不会被当作集合列出
+fn partition_table(table: &str, period: Period, n: i64) -> String {
+    match period {
+        Period::Day => {
+            let (year, month, day, ..) = civil(n as u64 * 86400);
+            format!("_part_{}_{:04}{:02}{:02}", table, year, month, day)
+        }
+        Period::Month => format!("_part_{}_{:04}{:02}", table, 1970 + n / 12, n % 12 + 1),
+    }
+}
+
+// 某个时间段的分区表, 以及分配该时间段 id 的 store
+fn partition_store(table: &str, period: Period, n: i64, pool: &SqlitePool) -> (String, SqliteStore) {
+    (partition_table(table, period, n), SqliteStore::with_ids(pool.clone(), n * PARTITION_IDS + 1, 1))
+}
+
+struct Collection {
+    table: String,
+    settings: Partitioning,
+    /// Partitions by period, each a store handing out that period's ids.
+    partitions: RwLock<BTreeMap<i64, (String, SqliteStore)>>,
+}
+
+impl Collection {
+    // 文档没有时间字段时填上当前时间
+    fn place(&self, doc: &Value) -> (Value, i64) {
+        let mut doc = doc.clone();
+        let ts = match doc.get(&self.settings.field).and_then(Value::as_f64) {
+            Some(ts) => ts,
+            None => {
+                let ts = now();
+                doc[self.settings.field.as_str()] = Value::from(ts);
+                ts as f64
+            }
+        };
+        (doc, period_of(self.settings.period, ts))
+    }
+}
+
+/// The partitioned collections, keyed by table name.
+pub struct Partitions {
+    pool: SqlitePool,
+    collections: HashMap<String, Collection>,
+}
+
+impl Partitions {
+    /// Loads the partitions created so far from `_partitions`.
+    pub async fn open(config: &ConfigHandle, pool: SqlitePool) -> Result<Self, sqlx::Error> {
+        let mut collections = HashMap::new();
+        for (uri, settings) in &config.get().partitioning {
+            let table = table_name(uri);
+            let periods: Vec<i64> = sqlx::query_scalar("SELECT period FROM _partitions WHERE collection = ? ORDER BY period")
+                .bind(&table)
+                .fetch_all(&pool)
+                .await?;
+            let partitions = periods.into_iter().map(|n| (n, partition_store(&table, settings.period, n, &pool))).collect();
+            let collection = Collection { table: table.clone(), settings: settings.clone(), partitions: RwLock::new(partitions) };
+            collections.insert(table, collection);
+        }
+        Ok(Partitions { pool, collections })
+    }
+
+    fn get(&self, uri: &str) -> Option<&Collection> {
+        self.collections.get(&table_name(uri))
+    }
+
+    /// Tables of the partitioned collection `table` that can hold documents
+    /// matching `filter`, oldest first, or every table without a filter.
+    /// `None` when the collection is not partitioned.
+    pub async fn tables(&self, table: &str, filter: Option<&Filter>) -> Option<Vec<String>> {
+        let collection = self.collections.get(table)?;
+        let (from, to) = match filter.map(|f| f.range(&collection.settings.field)) {
+            Some((from, to)) => (
+                from.map_or(i64::MIN, |ts| period_of(collection.settings.period, ts)),
+                to.map_or(i64::MAX, |ts| period_of(collection.settings.period, ts)),
+            ),
+            None => (i64::MIN, i64::MAX),
+        };
+        let mut tables = Vec::new();
+        // 分区之前写入的文档没有时间范围可言, 总要查询
+        if collection_exists(&self.pool, table).await.unwrap_or(false) {
+            tables.push(table.to_string());
+        }
+        if from <= to {
+            tables.extend(collection.partitions.read().await.range(from..=to).map(|(_, (name, _))| name.clone()));
+        }
+        Some(tables)
+    }
+
+    // 取得某个时间段的分区, 不存在时以最新分区的列建表
+    async fn partition(&self, collection: &Collection, n: i64, doc: &Value) -> Result<(String, SqliteStore), StoreError> {
+        if let Some(partition) = collection.partitions.read().await.get(&n) {
+            return Ok(partition.clone());
+        }
+        let mut partitions = collection.partitions.write().await;
+        if let Some(partition) = partitions.get(&n) {
+            return Ok(partition.clone());
+        }
+        let (name, store) = partition_store(&collection.table, collection.settings.period, n, &self.pool);
+        match partitions.values().next_back() {
+            Some((newest, _)) => copy_table(&self.pool, newest, &name).await.map_err(StoreError::Schema)?,
+            None => drop(create_table(&self.pool, &name, doc).await?),
+        }
+        sqlx::query("INSERT OR IGNORE INTO _partitions (collection, period, table_name) VALUES (?, ?, ?)")
+            .bind(&collection.table)
+            .bind(n)
+            .bind(&name)
+            .execute(&self.pool)
+            .await?;
+        log::info!("created partition {} of '{}'", name, collection.table);
+        partitions.insert(n, (name.clone(), store.clone()));
+        Ok((name, store))
+    }
+
+    // 删除超出保留期的分区
+    async fn expire(&self, metrics: &Metrics) {
+        for collection in self.collections.values() {
+            let Some(retention) = collection.settings.retention else {
+                continue;
+            };
+            let oldest_kept = period_of(collection.settings.period, now() as f64) - retention as i64 + 1;
+            let mut partitions = collection.partitions.write().await;
+            let expired: Vec<i64> = partitions.range(..oldest_kept).map(|(n, _)| *n).collect();
+            for n in expired {
+                let name = partitions[&n].0.clone();
+                match self.drop_partition(&collection.table, n, &name).await {
+                    Ok(()) => {
+                        partitions.remove(&n);
+                        log::info!("dropped partition {} of '{}'", name, collection.table);
+                        metrics.incr("partitions_dropped_total", vec![("collection", collection.table.clone())], 1.0);
+                    }
+                    Err(e) => log::warn!("failed to drop partition {} of '{}': {}", name, collection.table, e),
+                }
+            }
+        }
+    }
+
+    async fn drop_partition(&self, table: &str, n: i64, name: &str) -> Result<(), sqlx::Error> {
+        let mut tx = self.pool.begin().await?;
+        sqlx::query(&format!("DROP TABLE IF EXISTS {}", quote(name))).execute(&mut *tx).await?;
+        sqlx::query("DELETE FROM _partitions WHERE collection = ? AND period = ?")
+            .bind(table)
+            .bind(n)
+            .execute(&mut *tx)
+            .await?;
+        sqlx::query("DELETE FROM _content_hashes WHERE collection = ?").bind(name).execute(&mut *tx).await?;
+        tx.commit().await
+    }
+}
+
+/// Drops expired partitions now and every few minutes from then on.
+pub fn start(partitions: Arc<Partitions>, metrics: Arc<Metrics>) {
+    if !partitions.collections.values().any(|c| c.settings.retention.is_some()) {
+        return;
+    }
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(RETENTION_INTERVAL);
+        loop {
+            ticker.tick().await;
+            partitions.expire(&metrics).await;
+        }
+    });
+}
+
+/// Sends the calls for partitioned collections to their partitions and the
+/// others to `inner`.
+pub struct PartitionedStore {
+    inner: Arc<dyn DocumentStore>,
+    partitions: Arc<Partitions>,
+}
+
+impl PartitionedStore {
+    pub fn new(inner: Arc<dyn DocumentStore>, partitions: Arc<Partitions>) -> Self {
+        PartitionedStore { inner, partitions }
+    }
+
+    // id 决定文档所在的分区; 分区之前的文档在原来的表中
+    async fn by_id(&self, collection: &Collection, id: i64) -> Option<(String, SqliteStore)> {
+        collection.partitions.read().await.get(&id.div_euclid(PARTITION_IDS)).cloned()
+    }
+
+    async fn unpartitioned(&self, uri: &str) -> Result<bool, StoreError> {
+        Ok(collection_exists(&self.partitions.pool, &table_name(uri)).await?)
+    }
+}
+
+#[async_trait]
+impl DocumentStore for PartitionedStore {
+    async fn insert(&self, uri: &str, doc: &Value) -> Result<i64, StoreError> {
+        let Some(collection) = self.partitions.get(uri) else {
+            return self.inner.insert(uri, doc).await;
+        };
+        let (doc, n) = collection.place(doc);
+        let (name, store) = self.partitions.partition(collection, n, &doc).await?;
+        store.insert(&name, &doc).await
+    }
+
+    async fn list(&self, uri: &str) -> Result<Vec<Value>, StoreError> {
+        let Some(collection) = self.partitions.get(uri) else {
+            return self.inner.list(uri).await;
+        };
+        let partitions: Vec<_> = collection.partitions.read().await.values().cloned().collect();
+        let unpartitioned = self.unpartitioned(uri).await?;
+        // 没有任何数据时与未分区的集合一样报错
+        let mut docs = match unpartitioned || partitions.is_empty() {
+            true => self.inner.list(uri).await?,
+            false => Vec::new(),
+        };
+        for (name, store) in partitions {
+            docs.extend(store.list(&name).await?);
+        }
+        Ok(docs)
+    }
+
+    async fn get(&self, uri: &str, id: i64) -> Result<Option<Value>, StoreError> {
+        let Some(collection) = self.partitions.get(uri) else {
+            return self.inner.get(uri, id).await;
+        };
+        if id < PARTITION_IDS {
+            return match self.unpartitioned(uri).await? {
+                true => self.inner.get(uri, id).await,
+                false => Ok(None),
+            };
+        }
+        match self.by_id(collection, id).await {
+            Some((name, store)) => store.get(&name, id).await,
+            None => Ok(None),
+        }
+    }
+
+    async fn get_many(&self, uri: &str, ids: &[i64]) -> Result<Vec<Value>, StoreError> {
+        let Some(collection) = self.partitions.get(uri) else {
+            return self.inner.get_many(uri, ids).await;
+        };
+        let unpartitioned = self.unpartitioned(uri).await?;
+        let partitions = collection.partitions.read().await.clone();
+        if !unpartitioned && partitions.is_empty() {
+            return Err(StoreError::NotFound);
+        }
+        let mut by_period: BTreeMap<i64, Vec<i64>> = BTreeMap::new();
+        for id in ids {
+            by_period.entry(id.div_euclid(PARTITION_IDS)).or_default().push(*id);
+        }
+        let mut found = HashMap::new();
+        for (n, ids) in by_period {
+            let docs = match partitions.get(&n) {
+                Some((name, store)) => store.get_many(name, &ids).await?,
+                None if n == 0 && unpartitioned => self.inner.get_many(uri, &ids).await?,
+                None => continue,
+            };
+            found.extend(docs.into_iter().filter_map(|doc| Some((doc.get("id")?.as_i64()?, doc))));
+        }
+        Ok(ids.iter().filter_map(|id| found.remove(id)).collect())
+    }
+
+    async fn insert_unique(&self, uri: &str, doc: &Value, hash: &str, mode: DedupMode) -> Result<Inserted, StoreError> {
+        let Some(collection) = self.partitions.get(uri) else {
+            return self.inner.insert_unique(uri, doc, hash, mode).await;
+        };
+        let (doc, n) = collection.place(doc);
+        let (name, store) = self.partitions.partition(collection, n, &doc).await?;
+        store.insert_unique(&name, &doc, hash, mode).await
+    }
+
+    // 分区各自的唯一索引不能保证整个集合中 key 唯一
+    async fn upsert(&self, uri: &str, key: &str, doc: &Value) -> Result<Upserted, StoreError> {
+        match self.partitions.get(uri) {
+            Some(_) => Err(StoreError::Invalid(format!("Partitioned collection '{}' cannot be upserted by key", uri))),
+            None => self.inner.upsert(uri, key, doc).await,
+        }
+    }
+
+    async fn replace(&self, uri: &str, id: i64, doc: &Value, expected: Option<&[i64]>) -> Result<i64, StoreError> {
+        let Some(collection) = self.partitions.get(uri) else {
+            return self.inner.replace(uri, id, doc, expected).await;
+        };
+        if id < PARTITION_IDS {
+            return self.inner.replace(uri, id, doc, expected).await;
+        }
+        match self.by_id(collection, id).await {
+            Some((name, store)) => store.replace(&name, id, doc, expected).await,
+            None => Err(StoreError::NotFound),
+        }
+    }
+
+    async fn update(&self, uri: &str, id: i64, doc: &Value, expected: Option<&[i64]>) -> Result<i64, StoreError> {
+        let Some(collection) = self.partitions.get(uri) else {
+            return self.inner.update(uri, id, doc, expected).await;
+        };
+        if id < PARTITION_IDS {
+            return self.inner.update(uri, id, doc, expected).await;
+        }
+        match self.by_id(collection, id).await {
+            Some((name, store)) => store.update(&name, id, doc, expected).await,
+            None => Err(StoreError::NotFound),
+        }
+    }
+
+    async fn delete(&self, uri: &str, id: i64, expected: Option<&[i64]>) -> Result<(), StoreError> {
+        let Some(collection) = self.partitions.get(uri) else {
+            return self.inner.delete(uri, id, expected).await;
+        };
+        if id < PARTITION_IDS {
+            return self.inner.delete(uri, id, expected).await;
+        }
+        match self.by_id(collection, id).await {
+            Some((name, store)) => store.delete(&name, id, expected).await,
+            None => Err(StoreError::NotFound),
+        }
+    }
+
+    // 所有分区在同一个数据库中, 可以在一个事务里修改; 只修改过滤条件可能命中的分区
+    async fn update_many(&self, uri: &str, filter: &Value, update: &Value) -> Result<UpdatedMany, StoreError> {
+        let table = table_name(uri);
+        let Some(all) = self.partitions.tables(&table, None).await else {
+            return self.inner.update_many(uri, filter, update).await;
+        };
+        if all.is_empty() {
+            return Err(StoreError::NotFound);
+        }
+        let parsed = parse_filter(filter).ok();
+        let tables = self.partitions.tables(&table, parsed.as_ref()).await.unwrap_or(all);
+        update_where(&self.partitions.pool, &tables, filter, update).await
+    }
+
+    async fn delete_many(&self, uri: &str, filter: &Value, dry_run: bool) -> Result<Vec<i64>, StoreError> {
+        let table = table_name(uri);
+        let Some(all) = self.partitions.tables(&table, None).await else {
+            return self.inner.delete_many(uri, filter, dry_run).await;
+        };
+        if all.is_empty() {
+            return Err(StoreError::NotFound);
+        }
+        let parsed = parse_filter(filter).ok();
+        let tables = self.partitions.tables(&table, parsed.as_ref()).await.unwrap_or(all);
+        delete_where(&self.partitions.pool, &tables, filter, dry_run).await
+    }
+
+    async fn find(&self, uri: &str, filter: &Value) -> Result<Vec<Value>, StoreError> {
+        let table = table_name(uri);
+        let Some(all) = self.partitions.tables(&table, None).await else {
+            return self.inner.find(uri, filter).await;
+        };
+        if all.is_empty() {
+            return Err(StoreError::NotFound);
+        }
+        let parsed = parse_filter(filter).ok();
+        let tables = self.partitions.tables(&table, parsed.as_ref()).await.unwrap_or(all);
+        find_where(&self.partitions.pool, &tables, filter).await
+    }
+
+    async fn count(&self, uri: &str, filter: &Value) -> Result<u64, StoreError> {
+        let table = table_name(uri);
+        let Some(all) = self.partitions.tables(&table, None).await else {
+            return self.inner.count(uri, filter).await;
+        };
+        if all.is_empty() {
+            return Err(StoreError::NotFound);
+        }
+        let parsed = parse_filter(filter).ok();
+        let tables = self.partitions.tables(&table, parsed.as_ref()).await.unwrap_or(all);
+        count_where(&self.partitions.pool, &tables, filter).await
+    }
+
+    async fn find_page(&self, uri: &str, filter: &Value, page: &PageQuery) -> Result<Paged, StoreError> {
+        let table = table_name(uri);
+        let Some(all) = self.partitions.tables(&table, None).await else {
+            return self.inner.find_page(uri, filter, page).await;
+        };
+        if all.is_empty() {
+            return Err(StoreError::NotFound);
+        }
+        let parsed = parse_filter(filter).ok();
+        let tables = self.partitions.tables(&table, parsed.as_ref()).await.unwrap_or(all);
+        find_page_where(&self.partitions.pool, &tables, filter, page).await
+    }
+
+    async fn version(&self, uri: &str) -> Result<String, StoreError> {
+        let Some(tables) = self.partitions.tables(&table_name(uri), None).await else {
+            return self.inner.version(uri).await;
+        };
+        if tables.is_empty() {
+            return Err(StoreError::NotFound);
+        }
+        version_of(&self.partitions.pool, &tables).await
+    }
+
+    // 事务不写分区集合
+    async fn committed(&self, writes: &[Written]) {
+        self.inner.committed(writes).await
+    }
+}
@@ -0,0 +1,400 @@
+//! Partial document updates.
+//!
+//! `PATCH /{uri}/{id}` and the WebSocket `update` command take a JSON Merge
+//! Patch (RFC 7386): the fields a patch names are set, a `null` clears its
+//! field, and an object is merged into the object stored in its field the
+//! same way, key by key, so `{"address": {"city": "Oslo"}}` keeps the other
+//! keys of `address`. Patches without objects are written as they are;
+//! others are merged into the document as read and written on condition
+//! that it is still at that version, and merged again when another write
+//! got in between.
+//!
+//! A `PATCH` sent as `application/json-patch+json` is a JSON Patch (RFC
+//! 6902) instead: a list of `add`, `remove`, `replace`, `move`, `copy` and
+//! `test` operations on JSON Pointers into the document. The operations are
+//! applied in order to the document as read, and the result replaces it on
+//! the same condition; if any operation fails, including a `test`, nothing
+//! is written.
+
+use serde::Deserialize;
+use serde_json::{Map, Value};
+use std::fmt;
+use std::time::Duration;
+
+use crate::store::{DocumentStore, StoreError, VERSION_FIELD};
+
+/// Attempts at applying a patch to a document that other writes keep changing.
+const PATCH_ATTEMPTS: u32 = 10;
+
+/// Applies the merge patch `patch` to `target`.
+pub fn merge_patch(target: &mut Value, patch: &Value) {
+    let Value::Object(patch) = patch else {
+        *target = patch.clone();
+        return;
+    };
+    if !target.is_object() {
+        *target = Value::Object(Map::new());
+    }
+    if let Value::Object(target) = target {
+        for (key, value) in patch {
+            match value {
+                Value::Null => {
+                    target.remove(key);
+                }
+                value => merge_patch(target.entry(key.clone()).or_insert(Value::Null), value),
+            }
+        }
+    }
+}
+
+/// Applies the merge patch `patch` to document `id` of `uri` if it is at one
+/// of the `expected` versions, and returns the new version.
+pub async fn merge_update(
+    store: &dyn DocumentStore,
+    uri: &str,
+    id: i64,
+    patch: &Value,
+    expected: Option<&[i64]>,
+) -> Result<i64, StoreError> {
+    let Some(fields) = patch.as_object().filter(|fields| fields.values().any(Value::is_object)) else {
+        return store.update(uri, id, patch, expected).await;
+    };
+
+    let mut attempt = 1;
+    loop {
+        let current = store.get(uri, id).await?.ok_or(StoreError::NotFound)?;
+        let version = current.get(VERSION_FIELD).and_then(Value::as_i64).unwrap_or(1);
+        if expected.is_some_and(|versions| !versions.contains(&version)) {
+            return Err(StoreError::VersionConflict { current: version });
+        }
+
+        // 顶层的 null 仍然交给 update 清空列
+        let mut merged = fields.clone();
+        for (key, value) in merged.iter_mut().filter(|(_, value)| value.is_object()) {
+            let mut field = current.get(key).cloned().unwrap_or(Value::Null);
+            merge_patch(&mut field, value);
+            *value = field;
+        }
+        match store.update(uri, id, &Value::Object(merged), Some(&[version])).await {
+            Err(StoreError::VersionConflict { .. }) if expected.is_none() && attempt < PATCH_ATTEMPTS => {
+                tokio::time::sleep(Duration::from_millis(5 * attempt as u64)).await;
+                attempt += 1;
+            }
+            result => return result,
+        }
+    }
+}
+
+/// One operation of a JSON Patch.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(tag = "op", rename_all = "lowercase")]
+pub enum Operation {
+    Add { path: String, value: Value },
+    Remove { path: String },
+    Replace { path: String, value: Value },
+    Move { from: String, path: String },
+    Copy { from: String, path: String },
+    Test { path: String, value: Value },
+}
+
+/// Why a JSON Patch was not applied.
+#[derive(Debug)]
+pub enum PatchError {
+    /// The patch is malformed or names a location the document does not have.
+    Invalid(String),
+    /// A `test` operation did not match.
+    Failed(String),
+    Store(StoreError),
+}
+
+impl fmt::Display for PatchError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PatchError::Invalid(reason) | PatchError::Failed(reason) => write!(f, "{}", reason),
+            PatchError::Store(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl From<StoreError> for PatchError {
+    fn from(e: StoreError) -> Self {
+        PatchError::Store(e)
+    }
+}
+
+/// Parses the JSON Patch `patch`, a list of operations.
+pub fn parse_json_patch(patch: &Value) -> Result<Vec<Operation>, PatchError> {
+    serde_json::from_value(patch.clone()).map_err(|e| PatchError::Invalid(format!("Invalid JSON Patch: {}", e)))
+}
+
+/// Applies `operations` to `target` in order, stopping at the first that fails.
+pub fn apply_json_patch(target: &mut Value, operations: &[Operation]) -> Result<(), PatchError> {
+    for operation in operations {
+        match operation {
+            Operation::Add { path, value } => add(target, path, value.clone())?,
+            Operation::Remove { path } => {
+                remove(target, path)?;
+            }
+            Operation::Replace { path, value } => {
+                remove(target, path)?;
+                add(target, path, value.clone())?;
+            }
+            Operation::Move { from, path } => {
+                if path.starts_with(&format!("{}/", from)) {
+                    return Err(PatchError::Invalid(format!("Cannot move '{}' into itself", from)));
+                }
+                let value = remove(target, from)?;
+                add(target, path, value)?;
+            }
+            Operation::Copy { from, path } => {
+                let value = target.pointer(from).cloned().ok_or_else(|| missing(from))?;
+                add(target, path, value)?;
+            }
+            Operation::Test { path, value } => {
+                if target.pointer(path) != Some(value) {
+                    return Err(PatchError::Failed(format!("Test of '{}' failed", path)));
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+fn missing(path: &str) -> PatchError {
+    PatchError::Invalid(format!("No value at '{}'", path))
+}
+
+// 拆成父节点的 JSON Pointer 和最后一段 (已反转义)
+fn split(path: &str) -> Result<(&str, String), PatchError> {
+    let at = path.rfind('/').ok_or_else(|| PatchError::Invalid(format!("Invalid JSON Pointer '{}'", path)))?;
+    Ok((&path[..at], path[at + 1..].replace("~1", "/").replace("~0", "~")))
+}
+
+// 数组下标: 不带前导零的非负整数
+fn index(token: &str, len: usize, path: &str) -> Result<usize, PatchError> {
+    match token.parse::<usize>() {
+        Ok(i) if i <= len && (token == "0" || !token.starts_with('0')) => Ok(i),
+        _ => Err(PatchError::Invalid(format!("Invalid array index in '{}'", path))),
+    }
+}
+
+fn add(target: &mut Value, path: &str, value: Value) -> Result<(), PatchError> {
+    if path.is_empty() {
+        *target = value;
+        return Ok(());
+    }
+    let (parent, token) = split(path)?;
+    match target.pointer_mut(parent).ok_or_else(|| missing(parent))? {
+        Value::Object(map) => {
+            map.insert(token, value);
+        }
+        Value::Array(items) if token == "-" => items.push(value),
+        Value::Array(items) => {
+            let i = index(&token, items.len(), path)?;
+            items.insert(i, value);
+        }
+        _ => return Err(missing(path)),
+    }
+    Ok(())
+}
+
+fn remove(target: &mut Value, path: &str) -> Result<Value, PatchError> {
+    if path.is_empty() {
+        return Err(PatchError::Invalid("The whole document cannot be removed".to_string()));
+    }
+    let (parent, token) = split(path)?;
+    match target.pointer_mut(parent).ok_or_else(|| missing(parent))? {
+        Value::Object(map) => map.remove(&token).ok_or_else(|| missing(path)),
+        Value::Array(items) => match index(&token, items.len(), path)? {
+            i if i < items.len() => Ok(items.remove(i)),
+            _ => Err(missing(path)),
+        },
+        _ => Err(missing(path)),
+    }
+}
+
+/// Applies the JSON Patch `operations` to document `id` of `uri` if it is
+/// at one of the `expected` versions, and returns the new version.
+pub async fn patch_update(
+    store: &dyn DocumentStore,
+    uri: &str,
+    id: i64,
+    operations: &[Operation],
+    expected: Option<&[i64]>,
+) -> Result<i64, PatchError> {
+    let mut attempt = 1;
+    loop {
+        let current = store.get(uri, id).await?.ok_or(StoreError::NotFound)?;
+        let version = current.get(VERSION_FIELD).and_then(Value::as_i64).unwrap_or(1);
+        if expected.is_some_and(|versions| !versions.contains(&version)) {
+            return Err(StoreError::VersionConflict { current: version }.into());
+        }
+
+        let mut patched = current.clone();
+        apply_json_patch(&mut patched, operations)?;
+        if !patched.is_object() {
+            return Err(PatchError::Invalid("Document must be a JSON object".to_string()));
+        }
+        if ["id", VERSION_FIELD].iter().any(|field| patched.get(field) != current.get(field)) {
+            return Err(PatchError::Invalid(format!("id and {} cannot be patched", VERSION_FIELD)));
+        }
+        match store.replace(uri, id, &patched, Some(&[version])).await {
+            Err(StoreError::VersionConflict { .. }) if expected.is_none() && attempt < PATCH_ATTEMPTS => {
+                tokio::time::sleep(Duration::from_millis(5 * attempt as u64)).await;
+                attempt += 1;
+            }
+            result => return Ok(result?),
+        }
+    }
+}
+
+#[cfg(all(test, feature = "sqlite"))]
+mod tests {
+    use super::*;
+    use crate::testing::{Call, MockStore};
+    use serde_json::json;
+
+    #[test]
+    fn merge_patch_follows_rfc_7386() {
+        let mut doc = json!({ "a": "b", "c": { "d": "e", "f": "g" }, "h": [1] });
+        merge_patch(&mut doc, &json!({ "a": "z", "c": { "f": null }, "h": { "i": 1 } }));
+        assert_eq!(doc, json!({ "a": "z", "c": { "d": "e" }, "h": { "i": 1 } }));
+
+        let mut doc = json!({ "a": [1, 2] });
+        merge_patch(&mut doc, &json!({ "a": [3] }));
+        assert_eq!(doc, json!({ "a": [3] }));
+
+        let mut doc = json!("text");
+        merge_patch(&mut doc, &json!({ "a": { "b": null } }));
+        assert_eq!(doc, json!({ "a": {} }));
+    }
+
+    #[tokio::test]
+    async fn flat_patches_are_written_as_they_are() {
+        let store = MockStore::new();
+        let patch = json!({ "name": "b", "age": null });
+        assert_eq!(merge_update(store.as_ref(), "users", 7, &patch, Some(&[2])).await.unwrap(), 2);
+        assert_eq!(
+            store.calls(),
+            vec![Call::Update { uri: "users".to_string(), id: 7, doc: patch, expected: Some(vec![2]) }]
+        );
+    }
+
+    #[tokio::test]
+    async fn nested_patches_are_merged_into_the_stored_fields() {
+        let store = MockStore::new();
+        store.on_get(Ok(Some(json!({ "id": 7, "address": { "city": "a", "zip": "1" }, "_version": 4 }))));
+        let patch = json!({ "address": { "zip": null, "street": "s" }, "name": null });
+        merge_update(store.as_ref(), "users", 7, &patch, None).await.unwrap();
+        assert_eq!(
+            store.calls()[1],
+            Call::Update {
+                uri: "users".to_string(),
+                id: 7,
+                doc: json!({ "address": { "city": "a", "street": "s" }, "name": null }),
+                expected: Some(vec![4]),
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn nested_patches_check_the_expected_version_and_retry_races() {
+        let store = MockStore::new();
+        store.on_get(Ok(Some(json!({ "id": 7, "_version": 4 }))));
+        let patch = json!({ "address": { "city": "b" } });
+        let result = merge_update(store.as_ref(), "users", 7, &patch, Some(&[3])).await;
+        assert!(matches!(result, Err(StoreError::VersionConflict { current: 4 })));
+        assert_eq!(store.calls().len(), 1);
+
+        let store = MockStore::new();
+        store
+            .on_get(Ok(Some(json!({ "id": 7, "_version": 4 }))))
+            .on_update(Err(StoreError::VersionConflict { current: 5 }))
+            .on_get(Ok(Some(json!({ "id": 7, "_version": 5 }))))
+            .on_update(Ok(6));
+        assert_eq!(merge_update(store.as_ref(), "users", 7, &patch, None).await.unwrap(), 6);
+        assert!(matches!(store.calls()[3], Call::Update { expected: Some(ref v), .. } if v == &[5]));
+
+        let store = MockStore::new();
+        let result = merge_update(store.as_ref(), "users", 7, &patch, None).await;
+        assert!(matches!(result, Err(StoreError::NotFound)));
+    }
+
+    fn apply(doc: Value, patch: Value) -> Result<Value, PatchError> {
+        let mut doc = doc;
+        apply_json_patch(&mut doc, &parse_json_patch(&patch)?)?;
+        Ok(doc)
+    }
+
+    #[test]
+    fn json_patch_applies_each_operation() {
+        let doc = json!({ "a": { "b": 1 }, "list": [1, 2], "x~y/z": 0 });
+        let patch = json!([
+            { "op": "add", "path": "/a/c", "value": 2 },
+            { "op": "add", "path": "/list/1", "value": 9 },
+            { "op": "add", "path": "/list/-", "value": 3 },
+            { "op": "remove", "path": "/x~0y~1z" },
+            { "op": "replace", "path": "/a/b", "value": "one" },
+            { "op": "copy", "from": "/a", "path": "/copy" },
+            { "op": "move", "from": "/list/0", "path": "/first" },
+            { "op": "test", "path": "/copy/c", "value": 2 },
+        ]);
+        assert_eq!(
+            apply(doc, patch).unwrap(),
+            json!({ "a": { "b": "one", "c": 2 }, "list": [9, 2, 3], "copy": { "b": "one", "c": 2 }, "first": 1 })
+        );
+    }
+
+    #[test]
+    fn json_patch_rejects_what_the_document_does_not_have() {
+        let doc = json!({ "a": { "b": 1 }, "list": [1] });
+        for patch in [
+            json!({ "op": "add", "path": "/a/b", "value": 1 }),
+            json!([{ "op": "inc", "path": "/a" }]),
+            json!([{ "op": "remove", "path": "/missing" }]),
+            json!([{ "op": "replace", "path": "/list/1", "value": 2 }]),
+            json!([{ "op": "add", "path": "/list/01", "value": 2 }]),
+            json!([{ "op": "add", "path": "/none/b", "value": 2 }]),
+            json!([{ "op": "move", "from": "/a", "path": "/a/b" }]),
+            json!([{ "op": "remove", "path": "" }]),
+            json!([{ "op": "copy", "from": "a", "path": "/b" }]),
+        ] {
+            assert!(matches!(apply(doc.clone(), patch.clone()), Err(PatchError::Invalid(_))), "{}", patch);
+        }
+        let patch = json!([{ "op": "test", "path": "/a/b", "value": "1" }]);
+        assert!(matches!(apply(doc, patch), Err(PatchError::Failed(_))));
+    }
+
+    #[tokio::test]
+    async fn json_patches_replace_the_document_as_read() {
+        let store = MockStore::new();
+        store.on_get(Ok(Some(json!({ "id": 7, "tags": ["a"], "_version": 4 }))));
+        let operations = parse_json_patch(&json!([{ "op": "add", "path": "/tags/-", "value": "b" }])).unwrap();
+        assert_eq!(patch_update(store.as_ref(), "users", 7, &operations, None).await.unwrap(), 2);
+        assert_eq!(
+            store.calls()[1],
+            Call::Replace {
+                uri: "users".to_string(),
+                id: 7,
+                doc: json!({ "id": 7, "tags": ["a", "b"], "_version": 4 }),
+                expected: Some(vec![4]),
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn json_patches_cannot_touch_id_or_version_nor_replace_the_document() {
+        for patch in [
+            json!([{ "op": "replace", "path": "/id", "value": 8 }]),
+            json!([{ "op": "remove", "path": "/_version" }]),
+            json!([{ "op": "add", "path": "", "value": [1] }]),
+        ] {
+            let store = MockStore::new();
+            store.on_get(Ok(Some(json!({ "id": 7, "_version": 4 }))));
+            let operations = parse_json_patch(&patch).unwrap();
+            let result = patch_update(store.as_ref(), "users", 7, &operations, None).await;
+            assert!(matches!(result, Err(PatchError::Invalid(_))), "{}", patch);
+            assert_eq!(store.calls().len(), 1);
+        }
+    }
+}
@@ -0,0 +1,188 @@
+//! Field statistics.
+//!
+//! `GET /{uri}/_profile` describes every field of a collection, computed in
+//! SQL over all its documents: how many hold a value and the share that do
+//! not, the number of distinct values, the smallest and largest value, the
+//! most frequent values and the average length of string values. Values
+//! compare the way SQLite orders them, numbers before strings, and objects
+//! and arrays count as their JSON text; compressed values are left out of
+//! the minimum and maximum.
+//!
+//! `?top=` sets how many frequent values are listed, 5 by default. The
+//! partitions of a partitioned collection are profiled together in one read
+//! transaction; sharded collections live in several files and are refused.
+
+use actix_web::{web, HttpResponse};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use sqlx::{Sqlite, SqlitePool, Transaction};
+use std::collections::BTreeMap;
+
+use crate::database::{collection_tables, row_to_json, table_columns, table_name};
+use crate::handlers::route;
+use crate::partition::Partitions;
+use crate::query::quote;
+use crate::shard::Shards;
+use crate::store::VERSION_FIELD;
+use crate::telemetry::db_span;
+
+const DEFAULT_TOP: i64 = 5;
+const MAX_TOP: i64 = 100;
+
+// 注册集合下的统计接口, 与其他文档接口一样检查 ACL
+pub fn configure_documents(cfg: &mut web::ServiceConfig) {
+    cfg.route(&route("/{uri}/_profile"), web::get().to(profile_collection));
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ProfileParams {
+    pub top: Option<i64>,
+}
+
+/// A value and the number of documents holding it.
+#[derive(Debug, Serialize)]
+pub struct TopValue {
+    pub value: Value,
+    pub count: i64,
+}
+
+/// The statistics of one field.
+#[derive(Debug, Serialize)]
+pub struct FieldProfile {
+    /// Declared column type.
+    #[serde(rename = "type")]
+    pub ty: String,
+    pub non_null: i64,
+    /// Share of the documents without a value, 0 for an empty collection.
+    pub null_rate: f64,
+    pub distinct: i64,
+    pub min: Value,
+    pub max: Value,
+    /// Average length in characters of the string values, null without any.
+    pub avg_length: Option<f64>,
+    pub top: Vec<TopValue>,
+}
+
+/// The answer of `GET /{uri}/_profile`.
+#[derive(Debug, Serialize)]
+pub struct Profile {
+    pub collection: String,
+    pub documents: i64,
+    pub fields: BTreeMap<String, FieldProfile>,
+}
+
+// 计算集合各字段的统计
+async fn profile_collection(
+    uri: web::Path<String>,
+    params: web::Query<ProfileParams>,
+    pool: web::Data<SqlitePool>,
+    shards: web::Data<Shards>,
+    partitions: web::Data<Partitions>,
+) -> HttpResponse {
+    if shards.get(&table_name(&uri)).is_some() {
+        return HttpResponse::BadRequest().json(format!("Sharded collection '{}' cannot be profiled", uri));
+    }
+    let top = params.top.unwrap_or(DEFAULT_TOP).clamp(0, MAX_TOP);
+    let tables: Vec<String> = match collection_tables(&pool, &shards, &partitions, &uri).await {
+        Ok(tables) if tables.is_empty() => return HttpResponse::NotFound().json(format!("No collection '{}'", uri)),
+        Ok(tables) => tables.into_iter().map(|(_, table)| table).collect(),
+        Err(e) => return HttpResponse::InternalServerError().json(format!("Failed to read collections: {}", e)),
+    };
+
+    match profile(&pool, &uri, &tables, top).await {
+        Ok(profile) => HttpResponse::Ok().json(profile),
+        Err(e) => HttpResponse::InternalServerError().json(format!("Failed to profile collection: {}", e)),
+    }
+}
+
+/// Profiles the collection `uri` stored in `tables`, listing the `top` most
+/// frequent values of each field, in one read transaction.
+pub async fn profile(pool: &SqlitePool, uri: &str, tables: &[String], top: i64) -> Result<Profile, sqlx::Error> {
+    // 各分区的列可能不同, 取所有列的并集
+    let mut columns: BTreeMap<String, String> = BTreeMap::new();
+    let mut table_fields = Vec::new();
+    for table in tables {
+        let fields = table_columns(pool, table).await?;
+        for (name, ty) in &fields {
+            if name != "id" && name != VERSION_FIELD {
+                columns.entry(name.clone()).or_insert_with(|| ty.clone());
+            }
+        }
+        table_fields.push((table, fields));
+    }
+
+    let mut tx = pool.begin().await?;
+    let mut documents = 0;
+    for table in tables {
+        documents += sqlx::query_scalar::<_, i64>(&format!("SELECT COUNT(*) FROM {}", quote(table))).fetch_one(&mut *tx).await?;
+    }
+    let mut fields = BTreeMap::new();
+    for (column, ty) in columns {
+        // 没有该列的分区按 NULL 参与统计
+        let source: Vec<String> = table_fields
+            .iter()
+            .map(|(table, fields)| match fields.iter().any(|(name, _)| *name == column) {
+                true => format!("SELECT {} AS v FROM {}", quote(&column), quote(table)),
+                false => format!("SELECT NULL AS v FROM {}", quote(table)),
+            })
+            .collect();
+        let source = source.join(" UNION ALL ");
+        fields.insert(column, field_profile(&mut tx, uri, &source, ty, documents, top).await?);
+    }
+    tx.commit().await?;
+    Ok(Profile { collection: uri.to_string(), documents, fields })
+}
+
+// 单个字段的统计; source 是只有一列 v 的查询
+async fn field_profile(
+    tx: &mut Transaction<'_, Sqlite>,
+    uri: &str,
+    source: &str,
+    ty: String,
+    documents: i64,
+    top: i64,
+) -> Result<FieldProfile, sqlx::Error> {
+    // 值以 JSON 文本保存: 字符串的长度按解码后的字符计算, 不是 JSON 的文本按原样计算
+    let query = format!(
+        r#"
+        SELECT COUNT(v) AS non_null, COUNT(DISTINCT v) AS "distinct",
+            MIN(CASE WHEN typeof(v) != 'blob' THEN v END) AS min,
+            MAX(CASE WHEN typeof(v) != 'blob' THEN v END) AS max,
+            AVG(CASE WHEN typeof(v) = 'text' THEN
+                CASE WHEN NOT json_valid(v) THEN length(v) WHEN json_type(v) = 'text' THEN length(json_extract(v, '$')) END
+            END) AS avg_length
+        FROM ({})
+        "#,
+        source
+    );
+    let mut span = db_span(&query, &table_name(uri));
+    let row = sqlx::query(&query).fetch_one(&mut **tx).await.inspect_err(|e| span.error(e))?;
+    let stats = row_to_json(&row);
+    let non_null = stats["non_null"].as_i64().unwrap_or_default();
+
+    let query = format!(
+        "SELECT v AS value, COUNT(*) AS count FROM ({}) WHERE v IS NOT NULL GROUP BY v ORDER BY count DESC, v LIMIT ?",
+        source
+    );
+    let mut span = db_span(&query, &table_name(uri));
+    let rows = sqlx::query(&query).bind(top).fetch_all(&mut **tx).await.inspect_err(|e| span.error(e))?;
+    let top = rows
+        .iter()
+        .map(row_to_json)
+        .map(|row| TopValue { count: row["count"].as_i64().unwrap_or_default(), value: row["value"].clone() })
+        .collect();
+
+    Ok(FieldProfile {
+        ty,
+        non_null,
+        null_rate: match documents {
+            0 => 0.0,
+            documents => (documents - non_null) as f64 / documents as f64,
+        },
+        distinct: stats["distinct"].as_i64().unwrap_or_default(),
+        min: stats["min"].clone(),
+        max: stats["max"].clone(),
+        avg_length: stats["avg_length"].as_f64(),
+        top,
+    })
+}
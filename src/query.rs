@@ -0,0 +1,542 @@
+//! MongoDB-style filter and update documents.
+//!
+//! A filter such as `{"age": {"$gte": 18}, "$or": [{"name": "John"}, {"active": true}]}`
+//! is parsed into a [`Filter`] tree and compiled to a parameterized SQL
+//! `WHERE` clause. An update such as `{"$set": {"plan": "pro"}, "$inc": {"logins": 1}}`
+//! is parsed into an [`Update`] and compiled to SQL assignments. Field names
+//! are checked against the table's columns before they are written into SQL;
+//! values are always bound as parameters.
+
+use serde_json::{json, Map, Value};
+use std::fmt;
+
+/// `name` quoted as an SQL identifier. Table and column names come from
+/// collection paths and document keys, which may be keywords such as
+/// `order` or hold any character.
+pub fn quote(name: &str) -> String {
+    format!("\"{}\"", name.replace('"', "\"\""))
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Filter {
+    Compare { field: String, op: CompareOp, value: Value },
+    In { field: String, values: Vec<Value>, negated: bool },
+    Exists { field: String, exists: bool },
+    And(Vec<Filter>),
+    Or(Vec<Filter>),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CompareOp {
+    Eq,
+    Ne,
+    Gt,
+    Gte,
+    Lt,
+    Lte,
+}
+
+impl CompareOp {
+    fn sql(self) -> &'static str {
+        match self {
+            CompareOp::Eq => "=",
+            CompareOp::Ne => "<>",
+            CompareOp::Gt => ">",
+            CompareOp::Gte => ">=",
+            CompareOp::Lt => "<",
+            CompareOp::Lte => "<=",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct QueryError(pub String);
+
+impl fmt::Display for QueryError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for QueryError {}
+
+fn error<T>(msg: impl Into<String>) -> Result<T, QueryError> {
+    Err(QueryError(msg.into()))
+}
+
+/// Parses a filter document. `null` and `{}` match everything.
+pub fn parse_filter(value: &Value) -> Result<Filter, QueryError> {
+    match value {
+        Value::Null => Ok(Filter::And(Vec::new())),
+        Value::Object(obj) => parse_document(obj),
+        _ => error("filter must be an object"),
+    }
+}
+
+fn parse_document(obj: &Map<String, Value>) -> Result<Filter, QueryError> {
+    let mut clauses = Vec::new();
+    for (key, value) in obj {
+        match key.as_str() {
+            "$and" => clauses.push(Filter::And(parse_list(key, value)?)),
+            "$or" => clauses.push(Filter::Or(parse_list(key, value)?)),
+            op if op.starts_with('$') => return error(format!("unknown top-level operator '{}'", op)),
+            field => clauses.extend(parse_field(field, value)?),
+        }
+    }
+    Ok(if clauses.len() == 1 { clauses.remove(0) } else { Filter::And(clauses) })
+}
+
+fn parse_list(op: &str, value: &Value) -> Result<Vec<Filter>, QueryError> {
+    match value {
+        Value::Array(items) if !items.is_empty() => items.iter().map(parse_filter).collect(),
+        _ => error(format!("'{}' expects a non-empty array of filters", op)),
+    }
+}
+
+fn parse_field(field: &str, value: &Value) -> Result<Vec<Filter>, QueryError> {
+    let ops = match value {
+        Value::Object(ops) if ops.keys().any(|k| k.starts_with('$')) => ops,
+        // 普通值表示相等比较
+        _ => {
+            return Ok(vec![Filter::Compare {
+                field: field.to_string(),
+                op: CompareOp::Eq,
+                value: value.clone(),
+            }])
+        }
+    };
+
+    let mut clauses = Vec::new();
+    for (op, operand) in ops {
+        let compare = |op| Filter::Compare { field: field.to_string(), op, value: operand.clone() };
+        let clause = match op.as_str() {
+            "$eq" => compare(CompareOp::Eq),
+            "$ne" => compare(CompareOp::Ne),
+            "$gt" => compare(CompareOp::Gt),
+            "$gte" => compare(CompareOp::Gte),
+            "$lt" => compare(CompareOp::Lt),
+            "$lte" => compare(CompareOp::Lte),
+            "$in" | "$nin" => match operand {
+                Value::Array(values) => Filter::In {
+                    field: field.to_string(),
+                    values: values.clone(),
+                    negated: op == "$nin",
+                },
+                _ => return error(format!("'{}' on '{}' expects an array", op, field)),
+            },
+            "$exists" => match operand {
+                Value::Bool(exists) => Filter::Exists { field: field.to_string(), exists: *exists },
+                _ => return error(format!("'$exists' on '{}' expects a boolean", field)),
+            },
+            other => return error(format!("unknown operator '{}' on '{}'", other, field)),
+        };
+        clauses.push(clause);
+    }
+    Ok(clauses)
+}
+
+impl Filter {
+    /// Every field name referenced by the filter.
+    pub fn fields(&self) -> Vec<&str> {
+        let mut fields = Vec::new();
+        self.collect_fields(&mut fields);
+        fields
+    }
+
+    fn collect_fields<'a>(&'a self, fields: &mut Vec<&'a str>) {
+        match self {
+            Filter::Compare { field, .. } | Filter::In { field, .. } | Filter::Exists { field, .. } => {
+                if !fields.contains(&field.as_str()) {
+                    fields.push(field);
+                }
+            }
+            Filter::And(items) | Filter::Or(items) => items.iter().for_each(|f| f.collect_fields(fields)),
+        }
+    }
+
+    /// Lowest and highest numeric value of `field` a matching document can
+    /// have, as far as the filter says; `None` is unbounded.
+    pub fn range(&self, field: &str) -> (Option<f64>, Option<f64>) {
+        match self {
+            Filter::Compare { field: f, op, value } if f == field => match (op, value.as_f64()) {
+                (CompareOp::Eq, Some(v)) => (Some(v), Some(v)),
+                (CompareOp::Gt | CompareOp::Gte, Some(v)) => (Some(v), None),
+                (CompareOp::Lt | CompareOp::Lte, Some(v)) => (None, Some(v)),
+                _ => (None, None),
+            },
+            Filter::In { field: f, values, negated: false } if f == field => {
+                let numbers: Option<Vec<f64>> = values.iter().map(Value::as_f64).collect();
+                match numbers {
+                    Some(numbers) if !numbers.is_empty() => (
+                        numbers.iter().copied().reduce(f64::min),
+                        numbers.iter().copied().reduce(f64::max),
+                    ),
+                    _ => (None, None),
+                }
+            }
+            // 所有条件都要满足: 取范围的交集
+            Filter::And(items) => items.iter().map(|f| f.range(field)).fold((None, None), |(lo, hi), (l, h)| {
+                (tighter(lo, l, f64::max), tighter(hi, h, f64::min))
+            }),
+            // 满足任一条件即可: 取范围的并集, 有一个无界则无界
+            Filter::Or(items) if !items.is_empty() => {
+                let ranges: Vec<_> = items.iter().map(|f| f.range(field)).collect();
+                let lo = ranges.iter().map(|r| r.0).reduce(|a, b| Some(a?.min(b?))).flatten();
+                let hi = ranges.iter().map(|r| r.1).reduce(|a, b| Some(a?.max(b?))).flatten();
+                (lo, hi)
+            }
+            _ => (None, None),
+        }
+    }
+
+    /// Rejects fields that are not columns of the target table.
+    pub fn validate(&self, columns: &[String]) -> Result<(), QueryError> {
+        for field in self.fields() {
+            if !columns.iter().any(|c| c == field) {
+                return error(format!("unknown field '{}'", field));
+            }
+        }
+        Ok(())
+    }
+
+    /// Compiles the filter to a SQL boolean expression, appending bound values to `params`.
+    ///
+    /// Values are bound in the same JSON text form the write path stores them in.
+    pub fn to_sql(&self, params: &mut Vec<Value>) -> String {
+        match self {
+            Filter::Compare { field, op: CompareOp::Eq, value: Value::Null } => {
+                params.push(Value::Null);
+                format!("({0} IS NULL OR {0} = ?)", quote(field))
+            }
+            Filter::Compare { field, op: CompareOp::Ne, value: Value::Null } => {
+                params.push(Value::Null);
+                format!("({0} IS NOT NULL AND {0} <> ?)", quote(field))
+            }
+            Filter::Compare { field, op, value } => {
+                params.push(value.clone());
+                format!("{} {} ?", quote(field), op.sql())
+            }
+            Filter::In { values, negated, .. } if values.is_empty() => {
+                if *negated { "1 = 1" } else { "1 = 0" }.to_string()
+            }
+            Filter::In { field, values, negated } => {
+                params.extend(values.iter().cloned());
+                let placeholders = vec!["?"; values.len()].join(", ");
+                let not = if *negated { "NOT " } else { "" };
+                format!("{} {}IN ({})", quote(field), not, placeholders)
+            }
+            Filter::Exists { field, exists: true } => format!("{} IS NOT NULL", quote(field)),
+            Filter::Exists { field, exists: false } => format!("{} IS NULL", quote(field)),
+            Filter::And(items) => join(items, " AND ", "1 = 1", params),
+            Filter::Or(items) => join(items, " OR ", "1 = 0", params),
+        }
+    }
+}
+
+impl Filter {
+    /// The filter matching the documents this one does not; SQL `NULL`
+    /// comparisons aside.
+    pub fn negate(self) -> Filter {
+        match self {
+            Filter::Compare { field, op, value } => {
+                let op = match op {
+                    CompareOp::Eq => CompareOp::Ne,
+                    CompareOp::Ne => CompareOp::Eq,
+                    CompareOp::Gt => CompareOp::Lte,
+                    CompareOp::Gte => CompareOp::Lt,
+                    CompareOp::Lt => CompareOp::Gte,
+                    CompareOp::Lte => CompareOp::Gt,
+                };
+                Filter::Compare { field, op, value }
+            }
+            Filter::In { field, values, negated } => Filter::In { field, values, negated: !negated },
+            Filter::Exists { field, exists } => Filter::Exists { field, exists: !exists },
+            Filter::And(items) => Filter::Or(items.into_iter().map(Filter::negate).collect()),
+            Filter::Or(items) => Filter::And(items.into_iter().map(Filter::negate).collect()),
+        }
+    }
+
+    /// The filter document [`parse_filter`] reads back as this filter.
+    pub fn to_document(&self) -> Value {
+        let operator = |field: &str, op: &str, operand: Value| json!({ field: { op: operand } });
+        match self {
+            Filter::Compare { field, op, value } => {
+                let op = match op {
+                    CompareOp::Eq => "$eq",
+                    CompareOp::Ne => "$ne",
+                    CompareOp::Gt => "$gt",
+                    CompareOp::Gte => "$gte",
+                    CompareOp::Lt => "$lt",
+                    CompareOp::Lte => "$lte",
+                };
+                operator(field, op, value.clone())
+            }
+            Filter::In { field, values, negated } => operator(field, if *negated { "$nin" } else { "$in" }, json!(values)),
+            Filter::Exists { field, exists } => operator(field, "$exists", json!(exists)),
+            Filter::And(items) if items.is_empty() => json!({}),
+            // 空的 $or 不匹配任何文档
+            Filter::Or(items) if items.is_empty() => json!({ "id": { "$in": [] } }),
+            Filter::And(items) => json!({ "$and": items.iter().map(Filter::to_document).collect::<Vec<_>>() }),
+            Filter::Or(items) => json!({ "$or": items.iter().map(Filter::to_document).collect::<Vec<_>>() }),
+        }
+    }
+}
+
+// 两个界中更严格的一个
+fn tighter(a: Option<f64>, b: Option<f64>, pick: fn(f64, f64) -> f64) -> Option<f64> {
+    match (a, b) {
+        (Some(a), Some(b)) => Some(pick(a, b)),
+        (a, b) => a.or(b),
+    }
+}
+
+fn join(items: &[Filter], separator: &str, empty: &str, params: &mut Vec<Value>) -> String {
+    match items {
+        [] => empty.to_string(),
+        [single] => single.to_sql(params),
+        _ => {
+            let parts: Vec<String> = items.iter().map(|f| f.to_sql(params)).collect();
+            format!("({})", parts.join(separator))
+        }
+    }
+}
+
+/// The changes of an update document. A field set to `null` is unset.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Update {
+    pub set: Map<String, Value>,
+    pub unset: Vec<String>,
+    /// Numbers to add to fields; a missing value counts as 0.
+    pub inc: Map<String, Value>,
+}
+
+/// Parses an update document: either operators (`$set`, `$unset` with an
+/// object or an array of names, `$inc`) or plain fields, which are set.
+pub fn parse_update(value: &Value) -> Result<Update, QueryError> {
+    let Value::Object(obj) = value else {
+        return error("update must be an object");
+    };
+    let mut update = Update::default();
+    match obj.keys().find(|k| !k.starts_with('$')) {
+        Some(field) if obj.keys().any(|k| k.starts_with('$')) => {
+            return error(format!("update mixes operators with the field '{}'", field));
+        }
+        Some(_) => update.set = obj.clone(),
+        None => {}
+    }
+    for (op, operand) in obj.iter().filter(|(k, _)| k.starts_with('$')) {
+        match (op.as_str(), operand) {
+            ("$set", Value::Object(fields)) => update.set.extend(fields.clone()),
+            ("$unset", Value::Object(fields)) => update.unset.extend(fields.keys().cloned()),
+            ("$unset", Value::Array(names)) => {
+                for name in names {
+                    match name.as_str() {
+                        Some(name) => update.unset.push(name.to_string()),
+                        None => return error("'$unset' expects field names"),
+                    }
+                }
+            }
+            ("$inc", Value::Object(fields)) => {
+                if let Some((field, _)) = fields.iter().find(|(_, by)| !by.is_number()) {
+                    return error(format!("'$inc' on '{}' expects a number", field));
+                }
+                update.inc.extend(fields.clone());
+            }
+            ("$set" | "$inc", _) => return error(format!("'{}' expects an object", op)),
+            ("$unset", _) => return error("'$unset' expects an object or an array"),
+            (other, _) => return error(format!("unknown update operator '{}'", other)),
+        }
+    }
+
+    // set 为 null 等同于 unset
+    let nulls: Vec<String> = update.set.iter().filter(|(_, v)| v.is_null()).map(|(k, _)| k.clone()).collect();
+    for field in nulls {
+        update.set.remove(&field);
+        update.unset.push(field);
+    }
+    let fields = update.fields();
+    if fields.is_empty() {
+        return error("update changes no fields");
+    }
+    if let Some(field) = fields.iter().enumerate().find(|(i, f)| fields[..*i].contains(f)).map(|(_, f)| f) {
+        return error(format!("'{}' is updated more than once", field));
+    }
+    Ok(update)
+}
+
+impl Update {
+    /// Every field the update changes, in the order set, unset, inc.
+    pub fn fields(&self) -> Vec<&str> {
+        let set = self.set.keys().map(String::as_str);
+        let unset = self.unset.iter().map(String::as_str);
+        set.chain(unset).chain(self.inc.keys().map(String::as_str)).collect()
+    }
+
+    /// Rejects fields that are not columns of the target table, and the
+    /// `id` and version columns, which updates cannot change.
+    pub fn validate(&self, columns: &[String], version: &str) -> Result<(), QueryError> {
+        for field in self.fields() {
+            if field == "id" || field == version {
+                return error(format!("'{}' cannot be updated", field));
+            }
+            if !columns.iter().any(|c| c == field) {
+                return error(format!("unknown field '{}'", field));
+            }
+        }
+        Ok(())
+    }
+
+    /// Compiles the update to a SQL `SET` list, appending bound values to `params`.
+    pub fn assignments(&self, params: &mut Vec<Value>) -> Vec<String> {
+        let mut assignments = Vec::new();
+        for (field, value) in &self.set {
+            params.push(value.clone());
+            assignments.push(format!("{} = ?", quote(field)));
+        }
+        assignments.extend(self.unset.iter().map(|field| format!("{} = NULL", quote(field))));
+        for (field, by) in &self.inc {
+            params.push(by.clone());
+            assignments.push(format!("{0} = COALESCE({0}, 0) + ?", quote(field)));
+        }
+        assignments
+    }
+
+    /// A SQL condition holding for rows the update would change, in the same
+    /// parameter order as [`Update::assignments`].
+    pub fn changes(&self, params: &mut Vec<Value>) -> String {
+        let mut changes = Vec::new();
+        for (field, value) in &self.set {
+            params.push(value.clone());
+            changes.push(format!("{} IS NOT ?", quote(field)));
+        }
+        changes.extend(self.unset.iter().map(|field| format!("{} IS NOT NULL", quote(field))));
+        for (field, by) in &self.inc {
+            params.push(by.clone());
+            changes.push(format!("{0} IS NOT COALESCE({0}, 0) + ?", quote(field)));
+        }
+        format!("({})", changes.join(" OR "))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn compile(filter: Value) -> (String, Vec<Value>) {
+        let mut params = Vec::new();
+        let sql = parse_filter(&filter).unwrap().to_sql(&mut params);
+        (sql, params)
+    }
+
+    #[test]
+    fn filters_compile_to_bound_sql() {
+        assert_eq!(compile(json!({})), ("1 = 1".to_string(), vec![]));
+        assert_eq!(compile(json!({ "name": "a" })), ("\"name\" = ?".to_string(), vec![json!("a")]));
+        assert_eq!(
+            compile(json!({ "age": { "$gte": 18, "$lt": 65 } })),
+            ("(\"age\" >= ? AND \"age\" < ?)".to_string(), vec![json!(18), json!(65)])
+        );
+        assert_eq!(
+            compile(json!({ "$or": [{ "plan": { "$in": ["pro", "team"] } }, { "trial": { "$exists": false } }] })),
+            ("(\"plan\" IN (?, ?) OR \"trial\" IS NULL)".to_string(), vec![json!("pro"), json!("team")])
+        );
+        assert_eq!(compile(json!({ "tag": { "$nin": [] } })).0, "1 = 1");
+        assert_eq!(compile(json!({ "tag": { "$in": [] } })).0, "1 = 0");
+        assert_eq!(compile(json!({ "a\"b": null })).0, "(\"a\"\"b\" IS NULL OR \"a\"\"b\" = ?)");
+    }
+
+    #[test]
+    fn malformed_filters_are_rejected() {
+        for filter in [
+            json!([]),
+            json!({ "$nor": [] }),
+            json!({ "$or": [] }),
+            json!({ "age": { "$gt": 1, "$regex": "x" } }),
+            json!({ "tag": { "$in": "a" } }),
+            json!({ "tag": { "$exists": 1 } }),
+        ] {
+            assert!(parse_filter(&filter).is_err(), "{}", filter);
+        }
+        // 不含运算符的对象是相等比较
+        assert!(parse_filter(&json!({ "address": { "city": "a" } })).is_ok());
+    }
+
+    #[test]
+    fn filters_are_validated_against_columns() {
+        let filter = parse_filter(&json!({ "$and": [{ "name": "a" }, { "age": 3 }], "name": { "$ne": "b" } })).unwrap();
+        assert_eq!(filter.fields(), vec!["name", "age"]);
+        let columns = vec!["id".to_string(), "name".to_string()];
+        assert_eq!(filter.validate(&columns), Err(QueryError("unknown field 'age'".to_string())));
+    }
+
+    #[test]
+    fn range_intersects_and_and_unites_or() {
+        let filter = parse_filter(&json!({ "t": { "$gte": 10, "$lt": 20 }, "$or": [{ "t": 12 }, { "t": { "$in": [15, 30] } }] })).unwrap();
+        assert_eq!(filter.range("t"), (Some(12.0), Some(20.0)));
+        assert_eq!(filter.range("u"), (None, None));
+        let filter = parse_filter(&json!({ "$or": [{ "t": { "$gt": 1 } }, { "t": 5 }] })).unwrap();
+        assert_eq!(filter.range("t"), (Some(1.0), None));
+    }
+
+    #[test]
+    fn to_document_reads_back_as_the_same_filter() {
+        for filter in [
+            json!({ "age": { "$gte": 18 }, "$or": [{ "name": "a" }, { "tag": { "$nin": [1, 2] } }] }),
+            json!({ "a": { "$exists": true }, "b": { "$ne": null } }),
+            json!({}),
+        ] {
+            let parsed = parse_filter(&filter).unwrap();
+            assert_eq!(parse_filter(&parsed.to_document()).unwrap(), parsed);
+        }
+        assert_eq!(Filter::Or(Vec::new()).to_document(), json!({ "id": { "$in": [] } }));
+    }
+
+    #[test]
+    fn negate_flips_comparisons_and_applies_de_morgan() {
+        let filter = parse_filter(&json!({ "a": { "$gt": 1 }, "b": { "$in": [2] } })).unwrap();
+        assert_eq!(
+            filter.negate().to_document(),
+            json!({ "$or": [{ "a": { "$lte": 1 } }, { "b": { "$nin": [2] } }] })
+        );
+    }
+
+    #[test]
+    fn updates_compile_to_assignments() {
+        let update = parse_update(&json!({ "$set": { "plan": "pro", "note": null }, "$unset": ["trial"], "$inc": { "logins": 1 } })).unwrap();
+        assert_eq!(update.fields(), vec!["plan", "trial", "note", "logins"]);
+        let mut params = Vec::new();
+        assert_eq!(
+            update.assignments(&mut params),
+            vec!["\"plan\" = ?", "\"trial\" = NULL", "\"note\" = NULL", "\"logins\" = COALESCE(\"logins\", 0) + ?"]
+        );
+        assert_eq!(params, vec![json!("pro"), json!(1)]);
+        let mut params = Vec::new();
+        assert_eq!(
+            update.changes(&mut params),
+            "(\"plan\" IS NOT ? OR \"trial\" IS NOT NULL OR \"note\" IS NOT NULL OR \"logins\" IS NOT COALESCE(\"logins\", 0) + ?)"
+        );
+        assert_eq!(parse_update(&json!({ "plan": "pro" })).unwrap().set, json!({ "plan": "pro" }).as_object().unwrap().clone());
+    }
+
+    #[test]
+    fn malformed_updates_are_rejected() {
+        for update in [
+            json!("x"),
+            json!({}),
+            json!({ "$set": { "a": 1 }, "b": 2 }),
+            json!({ "$set": [1] }),
+            json!({ "$unset": [1] }),
+            json!({ "$inc": { "a": "1" } }),
+            json!({ "$push": { "a": 1 } }),
+            json!({ "$set": { "a": 1 }, "$inc": { "a": 1 } }),
+        ] {
+            assert!(parse_update(&update).is_err(), "{}", update);
+        }
+        let columns = vec!["id".to_string(), "_version".to_string(), "a".to_string()];
+        let check = |update: Value| parse_update(&update).unwrap().validate(&columns, "_version");
+        assert!(check(json!({ "a": 1 })).is_ok());
+        assert_eq!(check(json!({ "id": 2 })), Err(QueryError("'id' cannot be updated".to_string())));
+        assert_eq!(check(json!({ "$inc": { "_version": 1 } })), Err(QueryError("'_version' cannot be updated".to_string())));
+        assert_eq!(check(json!({ "b": 1 })), Err(QueryError("unknown field 'b'".to_string())));
+    }
+}
@@ -0,0 +1,225 @@
+//! Tenant quotas.
+//!
+//! A collection created by a caller whose API key, certificate or session
+//! carries a `tenant` belongs to that tenant, as recorded in
+//! `_tenant_collections`. With limits in `quotas`, a write by a tenant's
+//! caller is refused with 403 and the tenant's usage once the tenant holds
+//! as many documents or bytes as allowed, and so is a write that would create
+//! a collection beyond the limit. Deletes are always allowed. Document and
+//! byte counts are cached for a few seconds, so a burst of writes can go a
+//! little past a limit.
+//!
+//! `GET /_tenants/{tenant}/usage` reports the usage of a tenant to its own
+//! callers and to admins.
+
+use actix_web::body::{EitherBody, MessageBody};
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::http::Method;
+use actix_web::middleware::Next;
+use actix_web::{web, Error, HttpMessage, HttpRequest, HttpResponse};
+use serde::Serialize;
+use serde_json::{json, Value};
+use sqlx::SqlitePool;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::acl::{requested, Permission};
+use crate::auth::{buffer_body, Principal};
+use crate::cdc::now_millis;
+use crate::config::{ConfigHandle, Quota, Role};
+use crate::database::{collection_exists, collection_tables, count_rows, table_name};
+use crate::partition::Partitions;
+use crate::shard::Shards;
+
+/// How long a tenant's usage is reused for checking writes.
+const USAGE_TTL: Duration = Duration::from_secs(5);
+
+/// What a tenant stores.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct Usage {
+    pub collections: u64,
+    pub documents: u64,
+    pub bytes: u64,
+}
+
+/// Recent usage of the tenants; create one in `main` and register it as app
+/// data.
+#[derive(Default)]
+pub struct UsageCache {
+    usage: Mutex<HashMap<String, (Instant, Usage)>>,
+}
+
+impl UsageCache {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+// 统计租户的集合数、文档数和占用的字节数
+async fn usage(pool: &SqlitePool, shards: &Shards, partitions: &Partitions, tenant: &str) -> Result<Usage, sqlx::Error> {
+    let collections: Vec<String> = sqlx::query_scalar("SELECT collection FROM _tenant_collections WHERE tenant = ?")
+        .bind(tenant)
+        .fetch_all(pool)
+        .await?;
+    let mut usage = Usage::default();
+    for collection in collections {
+        let tables = collection_tables(pool, shards, partitions, &collection).await?;
+        if tables.is_empty() {
+            continue;
+        }
+        usage.collections += 1;
+        for (pool, table) in tables {
+            usage.documents += count_rows(&pool, &table).await? as u64;
+            // 表及其索引在数据库文件中所占的页
+            let bytes: i64 = sqlx::query_scalar(
+                "SELECT COALESCE(SUM(pgsize), 0) FROM dbstat WHERE name IN (SELECT name FROM sqlite_master WHERE tbl_name = ?)",
+            )
+            .bind(&table)
+            .fetch_one(&pool)
+            .await?;
+            usage.bytes += bytes as u64;
+        }
+    }
+    Ok(usage)
+}
+
+fn over(usage: &Usage, quota: &Quota) -> Option<&'static str> {
+    let exceeds = |used: u64, limit: Option<u64>| limit.is_some_and(|limit| used >= limit);
+    if exceeds(usage.documents, quota.documents) {
+        Some("documents")
+    } else if exceeds(usage.bytes, quota.bytes) {
+        Some("bytes")
+    } else {
+        None
+    }
+}
+
+fn refusal(tenant: &str, limit: &str, usage: &Usage, quota: &Quota) -> HttpResponse {
+    HttpResponse::Forbidden().json(json!({
+        "error": format!("Tenant '{}' has reached its {} quota", tenant, limit),
+        "tenant": tenant,
+        "usage": usage,
+        "limits": quota,
+    }))
+}
+
+/// Refuses the writes of a tenant's callers that go beyond the tenant's
+/// quota, and records the collections they create.
+pub async fn enforce(
+    mut req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<EitherBody<impl MessageBody>>, Error> {
+    let tenant = req.extensions().get::<Principal>().and_then(|p| p.tenant.clone());
+    let config = req.app_data::<web::Data<ConfigHandle>>().map(|c| c.get());
+    let quota = tenant.as_deref().zip(config.as_ref()).and_then(|(tenant, config)| config.quotas.of(tenant).cloned());
+    let (Some(tenant), Some(quota)) = (tenant, quota) else {
+        return next.call(req).await.map(ServiceResponse::map_into_left_body);
+    };
+    let path = req.path().to_string();
+    if req.method() == Method::DELETE || path.ends_with("/_delete_many") || path.ends_with("/_truncate") {
+        return next.call(req).await.map(ServiceResponse::map_into_left_body);
+    }
+    let (permission, mut collections) = match requested(&mut req).await {
+        Ok(requested) => requested,
+        Err(response) => return Ok(req.into_response(response).map_into_right_body()),
+    };
+    if permission == Permission::Read {
+        return next.call(req).await.map(ServiceResponse::map_into_left_body);
+    }
+    // 复制集合会以请求体中的 name 建立新集合
+    if path.ends_with("/copy") {
+        let body = match buffer_body(&mut req).await {
+            Ok(body) => serde_json::from_slice::<Value>(&body).unwrap_or(Value::Null),
+            Err(response) => return Ok(req.into_response(response).map_into_right_body()),
+        };
+        collections.extend(body.get("name").and_then(Value::as_str).map(str::to_string));
+    }
+
+    let (Some(pool), Some(shards), Some(partitions), Some(cache)) = (
+        req.app_data::<web::Data<SqlitePool>>().cloned(),
+        req.app_data::<web::Data<Shards>>().cloned(),
+        req.app_data::<web::Data<Partitions>>().cloned(),
+        req.app_data::<web::Data<UsageCache>>().cloned(),
+    ) else {
+        return next.call(req).await.map(ServiceResponse::map_into_left_body);
+    };
+    let fail = |e: sqlx::Error| HttpResponse::InternalServerError().json(format!("Failed to check the quota: {}", e));
+
+    let cached = cache.usage.lock().unwrap().get(&tenant).filter(|(at, _)| at.elapsed() < USAGE_TTL).map(|(_, usage)| usage.clone());
+    let usage = match cached {
+        Some(usage) => usage,
+        None => match self::usage(&pool, &shards, &partitions, &tenant).await {
+            Ok(usage) => {
+                cache.usage.lock().unwrap().insert(tenant.clone(), (Instant::now(), usage.clone()));
+                usage
+            }
+            Err(e) => return Ok(req.into_response(fail(e)).map_into_right_body()),
+        },
+    };
+    if let Some(limit) = over(&usage, &quota) {
+        return Ok(req.into_response(refusal(&tenant, limit, &usage, &quota)).map_into_right_body());
+    }
+
+    // 尚不存在的集合由这次写入建立, 归属于调用者的租户
+    let mut created = Vec::new();
+    for collection in collections {
+        let table = table_name(&collection);
+        let configured = shards.get(&table).is_some() || partitions.tables(&table, None).await.is_some();
+        match collection_exists(&pool, &table).await {
+            Ok(false) if !configured && !created.contains(&collection) => created.push(collection),
+            Ok(_) => {}
+            Err(e) => return Ok(req.into_response(fail(e)).map_into_right_body()),
+        }
+    }
+    if !created.is_empty() {
+        if quota.collections.is_some_and(|limit| usage.collections + created.len() as u64 > limit) {
+            return Ok(req.into_response(refusal(&tenant, "collections", &usage, &quota)).map_into_right_body());
+        }
+        for collection in &created {
+            let recorded = sqlx::query("INSERT OR REPLACE INTO _tenant_collections (collection, tenant, created_at) VALUES (?, ?, ?)")
+                .bind(collection)
+                .bind(&tenant)
+                .bind(now_millis() as i64)
+                .execute(pool.get_ref())
+                .await;
+            if let Err(e) = recorded {
+                return Ok(req.into_response(fail(e)).map_into_right_body());
+            }
+        }
+        cache.usage.lock().unwrap().remove(&tenant);
+    }
+    next.call(req).await.map(ServiceResponse::map_into_left_body)
+}
+
+// 注册用量查询接口
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.route("/_tenants/{tenant}/usage", web::get().to(tenant_usage));
+}
+
+// 租户的用量和限额, 只有该租户的调用者和 admin 能看到
+async fn tenant_usage(
+    req: HttpRequest,
+    tenant: web::Path<String>,
+    config: web::Data<ConfigHandle>,
+    pool: web::Data<SqlitePool>,
+    shards: web::Data<Shards>,
+    partitions: web::Data<Partitions>,
+    cache: web::Data<UsageCache>,
+) -> HttpResponse {
+    let allowed = match req.extensions().get::<Principal>() {
+        Some(principal) => principal.role == Role::Admin || principal.tenant.as_deref() == Some(tenant.as_str()),
+        None => !config.get().auth_enabled(),
+    };
+    if !allowed {
+        return HttpResponse::Forbidden().json(format!("No access to the usage of tenant '{}'", tenant));
+    }
+    match usage(&pool, &shards, &partitions, &tenant).await {
+        Ok(usage) => {
+            cache.usage.lock().unwrap().insert(tenant.to_string(), (Instant::now(), usage.clone()));
+            let limits = config.get().quotas.of(&tenant).cloned();
+            HttpResponse::Ok().json(json!({ "tenant": tenant.as_str(), "usage": usage, "limits": limits }))
+        }
+        Err(e) => HttpResponse::InternalServerError().json(format!("Failed to read usage: {}", e)),
+    }
+}
@@ -0,0 +1,121 @@
+//! Request rate limits.
+//!
+//! `rate_limits.tiers` defines budgets of reads and writes per minute, and
+//! callers get a tier by principal id (`keys`), by tenant (`tenants`) or by
+//! `default`. A key named in `keys` has a budget of its own, a tenant's
+//! callers share the tenant's, and everybody else has their own under
+//! `default`, callers without a key one per address. Budgets refill
+//! continuously, so a caller may spend a minute's budget at once and then
+//! continue at the steady rate. A request over budget gets 429 with
+//! `Retry-After`. Changes to the tiers apply as soon as the configuration is
+//! reloaded.
+
+use actix_web::body::{EitherBody, MessageBody};
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::http::header::RETRY_AFTER;
+use actix_web::middleware::Next;
+use actix_web::{web, Error, HttpMessage, HttpResponse};
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::Instant;
+
+use crate::auth::{reads_only, Principal};
+use crate::config::{ConfigHandle, RateLimits};
+use crate::ip_filter::client_ip;
+use crate::metrics::Metrics;
+
+/// Budgets kept before the full ones are dropped.
+const MAX_BUCKETS: usize = 10_000;
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+/// The budgets left; create one in `main` and register it as app data.
+#[derive(Default)]
+pub struct RateLimiter {
+    /// By whose budget it is and whether it is for writes.
+    buckets: Mutex<HashMap<(String, bool), Bucket>>,
+}
+
+impl RateLimiter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // 按速率补充后取走一个令牌; 不够时返回需要等待的秒数
+    fn take(&self, subject: String, write: bool, per_minute: u32) -> Result<(), u64> {
+        let capacity = per_minute as f64;
+        let rate = capacity / 60.0;
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock().unwrap();
+        if buckets.len() >= MAX_BUCKETS {
+            buckets.retain(|_, bucket| bucket.tokens + now.duration_since(bucket.updated).as_secs_f64() * rate < capacity);
+        }
+        let bucket = buckets.entry((subject, write)).or_insert(Bucket { tokens: capacity, updated: now });
+        bucket.tokens = (bucket.tokens + now.duration_since(bucket.updated).as_secs_f64() * rate).min(capacity);
+        bucket.updated = now;
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(((1.0 - bucket.tokens) / rate).ceil() as u64)
+        }
+    }
+}
+
+// 调用者的等级以及共用同一份额度的对象
+fn subject(limits: &RateLimits, principal: Option<&Principal>, address: Option<String>) -> Option<(String, String)> {
+    if let Some(principal) = principal {
+        if let Some(tier) = limits.keys.get(&principal.id) {
+            return Some((tier.clone(), format!("key:{}", principal.id)));
+        }
+        if let Some(tenant) = &principal.tenant {
+            if let Some(tier) = limits.tenants.get(tenant) {
+                return Some((tier.clone(), format!("tenant:{}", tenant)));
+            }
+        }
+    }
+    let tier = limits.default.clone()?;
+    match principal {
+        Some(principal) => Some((tier, format!("key:{}", principal.id))),
+        None => Some((tier, format!("address:{}", address.unwrap_or_default()))),
+    }
+}
+
+/// Answers 429 to requests beyond the caller's budget.
+pub async fn enforce(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<EitherBody<impl MessageBody>>, Error> {
+    let (Some(config), Some(limiter)) =
+        (req.app_data::<web::Data<ConfigHandle>>().map(|c| c.get()), req.app_data::<web::Data<RateLimiter>>().cloned())
+    else {
+        return next.call(req).await.map(ServiceResponse::map_into_left_body);
+    };
+    let limits = &config.rate_limits;
+    let principal = req.extensions().get::<Principal>().cloned();
+    let address = client_ip(&req, &config.ip_filter).map(|ip| ip.to_string());
+    let Some((tier, subject)) = subject(limits, principal.as_ref(), address) else {
+        return next.call(req).await.map(ServiceResponse::map_into_left_body);
+    };
+    let write = !reads_only(&req);
+    let budget = limits.tiers.get(&tier).and_then(|t| if write { t.writes_per_minute } else { t.reads_per_minute });
+    let Some(per_minute) = budget else {
+        return next.call(req).await.map(ServiceResponse::map_into_left_body);
+    };
+
+    if let Err(wait) = limiter.take(subject.clone(), write, per_minute) {
+        let kind = if write { "writes" } else { "reads" };
+        log::debug!("rate limited {} ({} tier, {})", subject, tier, kind);
+        if let Some(metrics) = req.app_data::<web::Data<Metrics>>() {
+            metrics.incr("rate_limited_total", vec![("tier", tier.clone()), ("kind", kind.to_string())], 1.0);
+        }
+        let response = HttpResponse::TooManyRequests()
+            .insert_header((RETRY_AFTER, wait.to_string()))
+            .json(format!("Rate limit of {} {} per minute exceeded", per_minute, kind));
+        return Ok(req.into_response(response).map_into_right_body());
+    }
+    next.call(req).await.map(ServiceResponse::map_into_left_body)
+}
@@ -0,0 +1,124 @@
+//! Error reporting to a Sentry-compatible endpoint.
+//!
+//! With the `sentry` feature, [`init`] reads the DSN from `SENTRY_DSN` (and
+//! `SENTRY_ENVIRONMENT` / `SENTRY_RELEASE` if set). Panics and every 5xx
+//! response are then reported with the request's method, URL, route and API
+//! key id attached. Without the feature, or without a DSN, nothing is sent.
+
+use actix_web::body::MessageBody;
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::middleware::Next;
+use actix_web::Error;
+
+#[cfg(feature = "sentry")]
+use actix_web::body::{self, BoxBody};
+#[cfg(feature = "sentry")]
+use actix_web::HttpMessage;
+#[cfg(feature = "sentry")]
+use sentry::{protocol, Hub, Level, SentryFutureExt};
+#[cfg(feature = "sentry")]
+use std::sync::Arc;
+
+/// Keeps the client alive; pending events are flushed when it is dropped.
+pub struct Reporting {
+    #[cfg(feature = "sentry")]
+    _guard: sentry::ClientInitGuard,
+}
+
+// 初始化错误上报; 没有配置 SENTRY_DSN 时不发送任何事件
+#[cfg(feature = "sentry")]
+pub fn init() -> Reporting {
+    let guard = sentry::init(sentry::ClientOptions {
+        release: sentry::release_name!(),
+        ..Default::default()
+    });
+    if guard.is_enabled() {
+        log::info!("reporting errors to Sentry");
+    }
+    Reporting { _guard: guard }
+}
+
+#[cfg(not(feature = "sentry"))]
+pub fn init() -> Reporting {
+    Reporting {}
+}
+
+/// Runs each request on its own hub so that panics carry the request context,
+/// and reports responses with a 5xx status, using the response body as the message.
+#[cfg(feature = "sentry")]
+pub async fn capture(
+    req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<BoxBody>, Error> {
+    let hub = Arc::new(Hub::new_from_top(Hub::main()));
+    let request = request_context(&req);
+    hub.configure_scope(|scope| {
+        scope.add_event_processor(move |mut event| {
+            if event.request.is_none() {
+                event.request = Some(request.clone());
+            }
+            Some(event)
+        });
+    });
+
+    let res = next.call(req).bind_hub(hub.clone()).await?;
+    if !res.status().is_server_error() || hub.client().is_none() {
+        return Ok(res.map_into_boxed_body());
+    }
+
+    let (req, res) = res.into_parts();
+    let status = res.status();
+    let (res, body) = res.into_parts();
+    // 读出响应体作为错误信息, 再原样返回给客户端
+    let bytes = body::to_bytes(body).await.unwrap_or_default();
+    let message = serde_json::from_slice::<String>(&bytes)
+        .unwrap_or_else(|_| String::from_utf8_lossy(&bytes).into_owned());
+
+    hub.with_scope(
+        |scope| {
+            scope.set_tag("http.status_code", status.as_u16());
+            if let Some(route) = req.match_pattern() {
+                scope.set_tag("http.route", route);
+            }
+            if let Some(principal) = req.extensions().get::<crate::auth::Principal>() {
+                scope.set_user(Some(protocol::User {
+                    id: Some(principal.id.clone()),
+                    ..Default::default()
+                }));
+            }
+        },
+        || hub.capture_message(&message, Level::Error),
+    );
+
+    let res = res.set_body(BoxBody::new(bytes));
+    Ok(ServiceResponse::new(req, res))
+}
+
+#[cfg(not(feature = "sentry"))]
+pub async fn capture(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<impl MessageBody>, Error> {
+    next.call(req).await
+}
+
+// 请求信息; 不包含认证相关的头
+#[cfg(feature = "sentry")]
+fn request_context(req: &ServiceRequest) -> protocol::Request {
+    let info = req.connection_info();
+    let url = format!("{}://{}{}", info.scheme(), info.host(), req.uri());
+    let headers = req
+        .headers()
+        .iter()
+        .filter(|(name, _)| !matches!(name.as_str(), "authorization" | "x-api-key" | "cookie"))
+        .filter_map(|(name, value)| Some((name.to_string(), value.to_str().ok()?.to_string())))
+        .collect();
+
+    protocol::Request {
+        url: url.parse().ok(),
+        method: Some(req.method().to_string()),
+        query_string: Some(req.query_string().to_string()).filter(|q| !q.is_empty()),
+        headers,
+        ..Default::default()
+    }
+}
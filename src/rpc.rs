@@ -0,0 +1,377 @@
+//! Document commands over a WebSocket.
+//!
+//! `GET /_ws` upgrades to a WebSocket on which clients send commands as JSON
+//! text frames and get one reply frame per command, carrying the command's
+//! `id` so replies can be matched to requests:
+//!
+//! ```text
+//! > {"id": 1, "op": "insert", "collection": "users", "document": {"name": "Ann"}}
+//! < {"id": 1, "ok": true, "result": {"id": 7, "duplicate": false}}
+//! > {"id": 2, "op": "get", "collection": "users", "document_id": 99}
+//! < {"id": 2, "ok": false, "status": 404, "error": "No document with id 99"}
+//! ```
+//!
+//! The connection is authenticated once, by the upgrade request; every
+//! command is then checked against the caller's ACL entries like the
+//! matching HTTP request. Commands on one connection run in the order they
+//! arrive.
+//!
+//! `subscribe` (and `unsubscribe`) with a `collection` needs read access and
+//! makes the socket also send every change to that collection, as frames
+//! without an `id`:
+//!
+//! ```text
+//! < {"event": "change", "change": {"op": "insert", "collection": "users", "id": 8, ...}}
+//! < {"event": "lagged", "missed": 12}
+//! ```
+//!
+//! `lagged` means the connection fell too far behind and missed some changes.
+//! The socket needs the `websocket` cargo feature; without it [`configure`]
+//! registers nothing.
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use sqlx::SqlitePool;
+use std::collections::HashSet;
+use std::sync::Mutex;
+
+use crate::acl::{self, Permission};
+use crate::auth::Principal;
+use crate::config::ConfigHandle;
+use crate::handlers::{fetch_many, store_document, MAX_MGET_IDS};
+use crate::patch;
+use crate::store::{reserved, DocumentStore, StoreError};
+
+/// Largest command frame accepted, same as the HTTP body limit.
+#[cfg(feature = "websocket")]
+const MAX_FRAME: usize = 2 * 1024 * 1024;
+
+/// One command frame. `id` is echoed back in the reply.
+#[derive(Debug, Deserialize)]
+pub struct Request {
+    #[serde(default)]
+    pub id: Value,
+    #[serde(flatten)]
+    pub command: Command,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub enum Command {
+    Insert { collection: String, document: Value },
+    Get { collection: String, document_id: i64 },
+    List { collection: String },
+    GetMany { collection: String, ids: Vec<i64> },
+    Replace { collection: String, document_id: i64, document: Value, if_version: Option<i64> },
+    Update { collection: String, document_id: i64, document: Value, if_version: Option<i64> },
+    Delete { collection: String, document_id: i64, if_version: Option<i64> },
+    Subscribe { collection: String },
+    Unsubscribe { collection: String },
+}
+
+impl Command {
+    fn collection(&self) -> &str {
+        match self {
+            Command::Insert { collection, .. }
+            | Command::Get { collection, .. }
+            | Command::List { collection }
+            | Command::GetMany { collection, .. }
+            | Command::Replace { collection, .. }
+            | Command::Update { collection, .. }
+            | Command::Delete { collection, .. }
+            | Command::Subscribe { collection }
+            | Command::Unsubscribe { collection } => collection,
+        }
+    }
+
+    fn permission(&self) -> Permission {
+        match self {
+            Command::Get { .. }
+            | Command::List { .. }
+            | Command::GetMany { .. }
+            | Command::Subscribe { .. }
+            | Command::Unsubscribe { .. } => Permission::Read,
+            _ => Permission::Write,
+        }
+    }
+}
+
+/// Reply to one command; `status` uses the HTTP status codes of the matching
+/// HTTP request.
+#[derive(Debug, Serialize)]
+pub struct Reply {
+    pub id: Value,
+    pub ok: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub result: Option<Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub status: Option<u16>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl Reply {
+    fn ok(id: Value, result: Value) -> Self {
+        Reply { id, ok: true, result: Some(result), status: None, error: None }
+    }
+
+    fn error(id: Value, status: u16, error: impl Into<String>) -> Self {
+        Reply { id, ok: false, result: None, status: Some(status), error: Some(error.into()) }
+    }
+}
+
+/// Everything a command needs, taken from the upgrade request.
+pub struct Context {
+    pub store: std::sync::Arc<dyn DocumentStore>,
+    pub pool: SqlitePool,
+    pub config: std::sync::Arc<ConfigHandle>,
+    pub principal: Option<Principal>,
+    /// Collections whose changes are sent on this connection.
+    pub subscriptions: Mutex<HashSet<String>>,
+}
+
+/// Parses and runs one text frame.
+pub async fn handle_frame(ctx: &Context, text: &str) -> Reply {
+    let raw: Value = match serde_json::from_str(text) {
+        Ok(raw) => raw,
+        Err(e) => return Reply::error(Value::Null, 400, format!("Invalid JSON: {}", e)),
+    };
+    let id = raw.get("id").cloned().unwrap_or(Value::Null);
+    match serde_json::from_value::<Request>(raw) {
+        Ok(request) => execute(ctx, request.id, request.command).await,
+        Err(e) => Reply::error(id, 400, format!("Invalid command: {}", e)),
+    }
+}
+
+// 检查 ACL 后执行命令
+pub async fn execute(ctx: &Context, id: Value, command: Command) -> Reply {
+    let grants = match acl::grants(&ctx.pool, ctx.principal.as_ref()).await {
+        Ok(grants) => grants,
+        Err(e) => return Reply::error(id, 500, format!("Failed to read ACL: {}", e)),
+    };
+    let (collection, permission) = (command.collection(), command.permission());
+    if reserved(collection) {
+        return Reply::error(id, 400, format!("Collection name '{}' is reserved", collection));
+    }
+    if !grants.allows(collection, permission) {
+        return Reply::error(id, 403, format!("No {} access to collection '{}'", permission.as_str(), collection));
+    }
+
+    let store = ctx.store.as_ref();
+    match command {
+        Command::Insert { collection, document } => {
+            if !document.is_object() {
+                return Reply::error(id, 400, "Document must be a JSON object");
+            }
+            let config = ctx.config.get();
+            let dedup = config.collections.get(&collection).and_then(|c| c.dedup.as_ref());
+            match store_document(store, dedup, &collection, &document).await {
+                Ok(inserted) => Reply::ok(id, serde_json::to_value(inserted).unwrap_or_default()),
+                Err(StoreError::Unavailable(e)) => Reply::error(id, 503, format!("Failed to insert data: {}", e)),
+                Err(e) => Reply::error(id, 500, format!("Failed to insert data: {}", e)),
+            }
+        }
+        Command::Get { collection, document_id } => match store.get(&collection, document_id).await {
+            Ok(Some(doc)) => Reply::ok(id, doc),
+            Ok(None) => Reply::error(id, 404, format!("No document with id {}", document_id)),
+            Err(e) => Reply::error(id, 500, format!("Failed to query data: {}", e)),
+        },
+        Command::List { collection } => match store.list(&collection).await {
+            Ok(docs) => Reply::ok(id, Value::Array(docs)),
+            Err(e) => Reply::error(id, 500, format!("Failed to query data: {}", e)),
+        },
+        Command::GetMany { collection, ids } => {
+            if ids.len() > MAX_MGET_IDS {
+                return Reply::error(id, 400, format!("At most {} ids per request", MAX_MGET_IDS));
+            }
+            match fetch_many(store, &collection, ids).await {
+                Ok(result) => Reply::ok(id, serde_json::to_value(result).unwrap_or_default()),
+                Err(StoreError::NotFound) => Reply::error(id, 404, format!("No collection '{}'", collection)),
+                Err(e) => Reply::error(id, 500, format!("Failed to query data: {}", e)),
+            }
+        }
+        Command::Replace { collection, document_id, document, if_version } => {
+            if !document.is_object() {
+                return Reply::error(id, 400, "Document must be a JSON object");
+            }
+            let expected = if_version.as_ref().map(std::slice::from_ref);
+            let result = store.replace(&collection, document_id, &document, expected).await;
+            written(store, id, &collection, document_id, if_version.is_some(), result).await
+        }
+        Command::Update { collection, document_id, document, if_version } => {
+            if !document.is_object() {
+                return Reply::error(id, 400, "Document must be a JSON object");
+            }
+            let expected = if_version.as_ref().map(std::slice::from_ref);
+            let result = patch::merge_update(store, &collection, document_id, &document, expected).await;
+            written(store, id, &collection, document_id, if_version.is_some(), result).await
+        }
+        Command::Delete { collection, document_id, if_version } => {
+            let expected = if_version.as_ref().map(std::slice::from_ref);
+            match store.delete(&collection, document_id, expected).await {
+                Ok(()) => Reply::ok(id, Value::Null),
+                Err(e) => write_error(id, e, document_id, if_version.is_some()),
+            }
+        }
+        Command::Subscribe { collection } => {
+            ctx.subscriptions.lock().unwrap().insert(collection);
+            Reply::ok(id, Value::Null)
+        }
+        Command::Unsubscribe { collection } => {
+            ctx.subscriptions.lock().unwrap().remove(&collection);
+            Reply::ok(id, Value::Null)
+        }
+    }
+}
+
+// 写入成功后返回新的文档, 与 HTTP 接口一致
+async fn written(
+    store: &dyn DocumentStore,
+    id: Value,
+    collection: &str,
+    document_id: i64,
+    conditional: bool,
+    result: Result<i64, StoreError>,
+) -> Reply {
+    if let Err(e) = result {
+        return write_error(id, e, document_id, conditional);
+    }
+    match store.get(collection, document_id).await {
+        Ok(Some(doc)) => Reply::ok(id, doc),
+        Ok(None) => Reply::error(id, 404, format!("No document with id {}", document_id)),
+        Err(e) => Reply::error(id, 500, format!("Failed to query data: {}", e)),
+    }
+}
+
+fn write_error(id: Value, e: StoreError, document_id: i64, conditional: bool) -> Reply {
+    match e {
+        StoreError::NotFound if conditional => Reply::error(id, 412, format!("No document with id {}", document_id)),
+        StoreError::NotFound => Reply::error(id, 404, format!("No document with id {}", document_id)),
+        StoreError::VersionConflict { current } => {
+            Reply::error(id, 412, format!("Document {} is at version {}", document_id, current))
+        }
+        StoreError::Unavailable(e) => Reply::error(id, 503, format!("Failed to write data: {}", e)),
+        e => Reply::error(id, 500, format!("Failed to write data: {}", e)),
+    }
+}
+
+// 注册 WebSocket 接口
+#[cfg(feature = "websocket")]
+pub fn configure(cfg: &mut actix_web::web::ServiceConfig) {
+    cfg.route("/_ws", actix_web::web::get().to(socket::upgrade));
+}
+
+#[cfg(not(feature = "websocket"))]
+pub fn configure(_cfg: &mut actix_web::web::ServiceConfig) {}
+
+#[cfg(feature = "websocket")]
+mod socket {
+    use actix_web::{web, HttpMessage, HttpRequest, HttpResponse};
+    use actix_ws::AggregatedMessage;
+    use serde_json::json;
+    use std::sync::Arc;
+    use tokio::sync::broadcast::{self, error::RecvError};
+
+    use super::{handle_frame, Context, MAX_FRAME};
+    use crate::auth::Principal;
+    use crate::cdc::{Change, ChangeFeed};
+    use crate::config::ConfigHandle;
+    use crate::metrics::Metrics;
+    use crate::store::DocumentStore;
+
+    // 升级为 WebSocket, 之后逐个处理命令帧
+    pub async fn upgrade(
+        req: HttpRequest,
+        body: web::Payload,
+        store: web::Data<dyn DocumentStore>,
+        pool: web::Data<sqlx::SqlitePool>,
+        config: web::Data<ConfigHandle>,
+    ) -> Result<HttpResponse, actix_web::Error> {
+        let (response, mut session, stream) = actix_ws::handle(&req, body)?;
+        let ctx = Context {
+            store: store.into_inner(),
+            pool: pool.get_ref().clone(),
+            config: config.into_inner(),
+            principal: req.extensions().get::<Principal>().cloned(),
+            subscriptions: Default::default(),
+        };
+        let metrics = req.app_data::<web::Data<Metrics>>().cloned();
+        let feed = req.app_data::<web::Data<ChangeFeed>>().cloned();
+        let mut stream = stream.max_frame_size(MAX_FRAME).aggregate_continuations().max_continuation_size(MAX_FRAME);
+
+        actix_web::rt::spawn(async move {
+            // 只在有订阅时接收变更, 没有订阅的连接不会让写入多读文档
+            let mut changes: Option<broadcast::Receiver<Arc<Change>>> = None;
+            loop {
+                let message = tokio::select! {
+                    message = stream.recv() => message,
+                    change = next_change(&mut changes) => {
+                        let frame = match change {
+                            Ok(change) if ctx.subscriptions.lock().unwrap().contains(&change.collection) => {
+                                json!({ "event": "change", "change": change })
+                            }
+                            Ok(_) => continue,
+                            Err(RecvError::Lagged(missed)) => json!({ "event": "lagged", "missed": missed }),
+                            Err(RecvError::Closed) => {
+                                changes = None;
+                                continue;
+                            }
+                        };
+                        if session.text(frame.to_string()).await.is_err() {
+                            return;
+                        }
+                        continue;
+                    }
+                };
+                let Some(message) = message else {
+                    break;
+                };
+                let sent = match message {
+                    Ok(AggregatedMessage::Text(text)) => {
+                        let reply = handle_frame(&ctx, &text).await;
+                        if let Some(metrics) = &metrics {
+                            let status = reply.status.unwrap_or(200).to_string();
+                            metrics.incr("rpc_commands_total", vec![("status", status)], 1.0);
+                        }
+                        let subscribed = !ctx.subscriptions.lock().unwrap().is_empty();
+                        match (&feed, subscribed, changes.is_some()) {
+                            (Some(feed), true, false) => changes = Some(feed.subscribe()),
+                            (_, false, true) => changes = None,
+                            _ => {}
+                        }
+                        let text = serde_json::to_string(&reply).unwrap_or_default();
+                        session.text(text).await
+                    }
+                    Ok(AggregatedMessage::Ping(bytes)) => session.pong(&bytes).await,
+                    Ok(AggregatedMessage::Close(reason)) => {
+                        let _ = session.close(reason).await;
+                        return;
+                    }
+                    Ok(AggregatedMessage::Binary(_)) => session.text(binary_rejected()).await,
+                    Ok(AggregatedMessage::Pong(_)) => Ok(()),
+                    Err(e) => {
+                        log::debug!("websocket protocol error: {}", e);
+                        break;
+                    }
+                };
+                if sent.is_err() {
+                    return;
+                }
+            }
+            let _ = session.close(None).await;
+        });
+        Ok(response)
+    }
+
+    async fn next_change(changes: &mut Option<broadcast::Receiver<Arc<Change>>>) -> Result<Arc<Change>, RecvError> {
+        match changes {
+            Some(changes) => changes.recv().await,
+            None => std::future::pending().await,
+        }
+    }
+
+    fn binary_rejected() -> String {
+        let reply = super::Reply::error(serde_json::Value::Null, 400, "Commands must be sent as text frames");
+        serde_json::to_string(&reply).unwrap_or_default()
+    }
+}
@@ -0,0 +1,44 @@
+//! Request sampling for traces and the access log.
+//!
+//! Every request draws a number once, shared by the tracing and access log
+//! middleware so a logged request also has its trace. It is kept when the
+//! number falls within `sampling.reads` or `sampling.writes`, within
+//! `sampling.errors` if answered with 5xx, or when it took at least
+//! `sampling.slow_ms`. Only requests drawn up front get storage spans; errors
+//! and slow requests kept afterwards get their server span alone.
+
+use actix_web::dev::ServiceRequest;
+use actix_web::{HttpMessage, HttpRequest};
+use std::time::Duration;
+
+use crate::auth::reads_only;
+use crate::config::Sampling;
+
+/// The number a request drew, and the share of its kind of request.
+#[derive(Debug, Clone, Copy)]
+struct Draw {
+    value: f64,
+    share: f64,
+}
+
+/// Whether `req` is kept whatever its outcome; draws on the first call.
+pub fn drawn(req: &ServiceRequest, sampling: &Sampling) -> bool {
+    let draw = req.extensions().get::<Draw>().copied();
+    let draw = draw.unwrap_or_else(|| {
+        let share = if reads_only(req) { sampling.reads } else { sampling.writes };
+        let draw = Draw { value: rand::random(), share };
+        req.extensions_mut().insert(draw);
+        draw
+    });
+    draw.value < draw.share
+}
+
+/// Whether a finished request is kept; requests that never drew are.
+pub fn keep(req: &HttpRequest, sampling: &Sampling, status: u16, elapsed: Duration) -> bool {
+    let Some(draw) = req.extensions().get::<Draw>().copied() else {
+        return true;
+    };
+    draw.value < draw.share
+        || (status >= 500 && draw.value < sampling.errors)
+        || sampling.slow_ms.is_some_and(|slow| elapsed >= Duration::from_millis(slow))
+}
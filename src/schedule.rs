@@ -0,0 +1,310 @@
+//! Scheduled tasks.
+//!
+//! Every minute, each schedule in `schedules` whose cron expression matches
+//! is queued as a job of kind `schedule`, so it is retried and cancelled
+//! like any other [job](crate::jobs). A schedule runs one of:
+//!
+//! | `task`    | Does                                                          |
+//! |-----------|---------------------------------------------------------------|
+//! | `export`  | writes `collection` to `path` as newline-delimited JSON       |
+//! | `cleanup` | deletes documents of `collection` whose `field` is too old    |
+//! | `backup`  | copies the database to `path` with `VACUUM INTO`              |
+//!
+//! Each run is recorded in `_schedule_runs`, which keeps the last 100 runs
+//! of every schedule. Runs missed while the server was down are not caught
+//! up. `GET /_admin/schedules` lists the schedules with their next and last
+//! runs, `GET /_admin/schedules/{name}/runs` the history of one, and
+//! `POST /_admin/schedules/{name}/run` queues a run at once.
+
+use actix_web::{web, HttpResponse};
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use sqlx::sqlite::SqliteRow;
+use sqlx::{Row, SqlitePool};
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::access_log::civil;
+use crate::cdc::now_millis;
+use crate::config::{ConfigHandle, Schedule, ScheduledTask};
+use crate::cron::Cron;
+use crate::jobs::{Context, Queue, Status, Task};
+use crate::store::DocumentStore;
+
+/// Kind of the jobs running schedules.
+pub const KIND: &str = "schedule";
+
+/// Runs kept per schedule.
+const KEEP_RUNS: i64 = 100;
+
+/// Runs with their status; a run whose job was cancelled before it started
+/// is cancelled too.
+const SELECT_RUNS: &str = r#"
+    SELECT r.id, r.schedule, r.task, r.due_at, r.job_id, r.started_at, r.finished_at, r.error, r.result,
+           CASE WHEN j.status = 'cancelled' THEN 'cancelled' ELSE r.status END AS status
+    FROM _schedule_runs r LEFT JOIN _jobs j ON j.id = r.job_id
+"#;
+
+/// Minutes looked back for due runs after a late wake-up.
+const MAX_CATCH_UP: u64 = 60;
+
+/// One run of a schedule, as stored in `_schedule_runs`.
+#[derive(Debug, Clone, Serialize)]
+pub struct Run {
+    pub id: i64,
+    pub schedule: String,
+    pub task: String,
+    /// When the run was due, in milliseconds.
+    pub due_at: i64,
+    pub job_id: Option<String>,
+    /// `queued`, `running`, `retrying`, `done`, `failed` or `cancelled`.
+    pub status: String,
+    pub started_at: Option<i64>,
+    pub finished_at: Option<i64>,
+    pub error: Option<String>,
+    /// What the task reports, such as the documents written.
+    pub result: Value,
+}
+
+impl Run {
+    fn from_row(row: &SqliteRow) -> Run {
+        let result = row.get::<Option<String>, _>("result").and_then(|text| serde_json::from_str(&text).ok());
+        Run {
+            id: row.get("id"),
+            schedule: row.get("schedule"),
+            task: row.get("task"),
+            due_at: row.get("due_at"),
+            job_id: row.get("job_id"),
+            status: row.get("status"),
+            started_at: row.get("started_at"),
+            finished_at: row.get("finished_at"),
+            error: row.get("error"),
+            result: result.unwrap_or(Value::Null),
+        }
+    }
+}
+
+/// What a `schedule` job carries.
+#[derive(Debug, Serialize, Deserialize)]
+struct Payload {
+    run: i64,
+    schedule: String,
+    due_at: i64,
+    task: ScheduledTask,
+}
+
+fn task_name(task: &ScheduledTask) -> &'static str {
+    match task {
+        ScheduledTask::Export { .. } => "export",
+        ScheduledTask::Cleanup { .. } => "cleanup",
+        ScheduledTask::Backup { .. } => "backup",
+    }
+}
+
+/// Starts queueing the configured schedules when they are due.
+pub fn start(queue: Arc<Queue>, config: Arc<ConfigHandle>) {
+    tokio::spawn(async move {
+        let mut last = now_millis() / 60_000;
+        loop {
+            // 等到下一分钟开始
+            let now = now_millis();
+            tokio::time::sleep(Duration::from_millis(60_000 - now % 60_000)).await;
+            let minute = now_millis() / 60_000;
+            let schedules = config.get().schedules.clone();
+            for (name, schedule) in &schedules {
+                let Ok(cron) = Cron::parse(&schedule.cron) else { continue };
+                // 醒得晚了也只补一次
+                let due = (last + 1..=minute).rev().take(MAX_CATCH_UP as usize).find(|m| cron.matches(m * 60));
+                if let Some(due) = due {
+                    if let Err(e) = enqueue(&queue, name, schedule, (due * 60_000) as i64).await {
+                        log::error!("failed to queue schedule '{}': {}", name, e);
+                    }
+                }
+            }
+            last = minute;
+        }
+    });
+}
+
+// 记录一次运行并排队; 同一时刻的运行只记录一次
+async fn enqueue(queue: &Queue, name: &str, schedule: &Schedule, due_at: i64) -> Result<Option<i64>, sqlx::Error> {
+    let pool = queue.pool();
+    let run: Option<i64> = sqlx::query_scalar(
+        "INSERT OR IGNORE INTO _schedule_runs (schedule, task, due_at, status) VALUES (?, ?, ?, 'queued') RETURNING id",
+    )
+    .bind(name)
+    .bind(task_name(&schedule.task))
+    .bind(due_at)
+    .fetch_optional(pool)
+    .await?;
+    let Some(run) = run else { return Ok(None) };
+    sqlx::query("DELETE FROM _schedule_runs WHERE schedule = ? AND id NOT IN (SELECT id FROM _schedule_runs WHERE schedule = ? ORDER BY id DESC LIMIT ?)")
+        .bind(name)
+        .bind(name)
+        .bind(KEEP_RUNS)
+        .execute(pool)
+        .await?;
+
+    let collection = match &schedule.task {
+        ScheduledTask::Export { collection, .. } | ScheduledTask::Cleanup { collection, .. } => Some(collection.as_str()),
+        ScheduledTask::Backup { .. } => None,
+    };
+    let payload = Payload { run, schedule: name.to_string(), due_at, task: schedule.task.clone() };
+    let payload = serde_json::to_value(&payload).unwrap_or_default();
+    let job = queue.create(KIND, collection, None, &payload, &json!({}), Status::Queued).await?;
+    sqlx::query("UPDATE _schedule_runs SET job_id = ? WHERE id = ?").bind(&job.id).bind(run).execute(pool).await?;
+    Ok(Some(run))
+}
+
+/// Runs `schedule` jobs; register it with the job queue under [`KIND`].
+pub struct ScheduleTask {
+    store: Arc<dyn DocumentStore>,
+    config: Arc<ConfigHandle>,
+}
+
+impl ScheduleTask {
+    pub fn new(store: Arc<dyn DocumentStore>, config: Arc<ConfigHandle>) -> Self {
+        Self { store, config }
+    }
+
+    async fn execute(&self, pool: &SqlitePool, payload: &Payload) -> Result<Value, String> {
+        match &payload.task {
+            ScheduledTask::Export { collection, path } => {
+                let path = expand(path, payload.due_at);
+                let docs = self.store.list(collection).await.map_err(|e| format!("Failed to read '{}': {}", collection, e))?;
+                let mut text = String::new();
+                for doc in &docs {
+                    text.push_str(&doc.to_string());
+                    text.push('\n');
+                }
+                // 先写临时文件再改名, 不留下写了一半的导出
+                let partial = format!("{}.partial", path);
+                tokio::fs::write(&partial, text).await.map_err(|e| format!("Failed to write {}: {}", partial, e))?;
+                tokio::fs::rename(&partial, &path).await.map_err(|e| format!("Failed to rename {}: {}", partial, e))?;
+                Ok(json!({ "path": path, "documents": docs.len() }))
+            }
+            ScheduledTask::Cleanup { collection, field, older_than_secs } => {
+                let cutoff = now_millis() as f64 / 1000.0 - *older_than_secs as f64;
+                let filter = json!({ field.as_str(): { "$lt": cutoff } });
+                let deleted = self
+                    .store
+                    .delete_many(collection, &filter, false)
+                    .await
+                    .map_err(|e| format!("Failed to clean up '{}': {}", collection, e))?;
+                Ok(json!({ "deleted": deleted.len() }))
+            }
+            ScheduledTask::Backup { path } => {
+                let path = expand(path, payload.due_at);
+                sqlx::query("VACUUM INTO ?")
+                    .bind(&path)
+                    .execute(pool)
+                    .await
+                    .map_err(|e| format!("Failed to back up to {}: {}", path, e))?;
+                Ok(json!({ "path": path }))
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl Task for ScheduleTask {
+    // 执行任务并把结果记入运行历史
+    async fn run(&self, job: &Context<'_>) -> Result<(), String> {
+        let payload: Payload = serde_json::from_value(job.job.payload.clone()).map_err(|e| e.to_string())?;
+        let pool = job.pool();
+        sqlx::query("UPDATE _schedule_runs SET status = 'running', started_at = ?, error = NULL WHERE id = ?")
+            .bind(now_millis() as i64)
+            .bind(payload.run)
+            .execute(pool)
+            .await
+            .map_err(|e| e.to_string())?;
+
+        let result = self.execute(pool, &payload).await;
+        let status = match &result {
+            Ok(_) => "done",
+            Err(_) if job.cancelled() => "cancelled",
+            Err(_) if job.job.attempts < self.config.get().jobs.max_attempts as i64 => "retrying",
+            Err(_) => "failed",
+        };
+        let (outcome, error) = match &result {
+            Ok(outcome) => (Some(outcome.to_string()), None),
+            Err(e) => (None, Some(e.clone())),
+        };
+        let recorded = sqlx::query("UPDATE _schedule_runs SET status = ?, finished_at = ?, error = ?, result = ? WHERE id = ?")
+            .bind(status)
+            .bind(now_millis() as i64)
+            .bind(error)
+            .bind(outcome)
+            .bind(payload.run)
+            .execute(pool)
+            .await;
+        if let Err(e) = recorded {
+            log::warn!("failed to record run {} of schedule '{}': {}", payload.run, payload.schedule, e);
+        }
+        if let Ok(outcome) = &result {
+            log::info!("schedule '{}' done: {}", payload.schedule, outcome);
+        }
+        result.map(|_| ())
+    }
+}
+
+// 路径中的 {time} 换成应运行的时间
+fn expand(path: &str, due_at: i64) -> String {
+    let (year, month, day, hour, minute, _) = civil(due_at as u64 / 1000);
+    path.replace("{time}", &format!("{:04}{:02}{:02}T{:02}{:02}Z", year, month, day, hour, minute))
+}
+
+// 列出计划任务及其下次和最近一次运行
+pub async fn list_schedules(config: web::Data<ConfigHandle>, pool: web::Data<SqlitePool>) -> HttpResponse {
+    let now = now_millis() / 1000;
+    let mut schedules = Vec::new();
+    for (name, schedule) in &config.get().schedules {
+        let last = sqlx::query(&format!("{} WHERE r.schedule = ? ORDER BY r.id DESC LIMIT 1", SELECT_RUNS))
+            .bind(name)
+            .fetch_optional(pool.get_ref())
+            .await;
+        let last = match last {
+            Ok(row) => row.as_ref().map(Run::from_row),
+            Err(e) => return HttpResponse::InternalServerError().json(format!("Failed to read schedule runs: {}", e)),
+        };
+        let next = Cron::parse(&schedule.cron).ok().and_then(|cron| cron.next_after(now)).map(|next| next * 1000);
+        let mut entry = serde_json::to_value(schedule).unwrap_or_default();
+        entry["name"] = json!(name);
+        entry["next_at"] = json!(next);
+        entry["last_run"] = json!(last);
+        schedules.push(entry);
+    }
+    HttpResponse::Ok().json(schedules)
+}
+
+// 某个计划任务的运行历史, 最新的在前
+pub async fn list_runs(name: web::Path<String>, config: web::Data<ConfigHandle>, pool: web::Data<SqlitePool>) -> HttpResponse {
+    if !config.get().schedules.contains_key(name.as_str()) {
+        return HttpResponse::NotFound().json(format!("No schedule '{}'", name));
+    }
+    let rows = sqlx::query(&format!("{} WHERE r.schedule = ? ORDER BY r.id DESC", SELECT_RUNS))
+        .bind(name.as_str())
+        .fetch_all(pool.get_ref())
+        .await;
+    match rows {
+        Ok(rows) => HttpResponse::Ok().json(rows.iter().map(Run::from_row).collect::<Vec<_>>()),
+        Err(e) => HttpResponse::InternalServerError().json(format!("Failed to read schedule runs: {}", e)),
+    }
+}
+
+// 立即运行一次计划任务
+pub async fn run_now(name: web::Path<String>, config: web::Data<ConfigHandle>, queue: web::Data<Queue>) -> HttpResponse {
+    let Some(schedule) = config.get().schedules.get(name.as_str()).cloned() else {
+        return HttpResponse::NotFound().json(format!("No schedule '{}'", name));
+    };
+    let run = match enqueue(&queue, &name, &schedule, now_millis() as i64).await {
+        Ok(Some(run)) => run,
+        Ok(None) => return HttpResponse::Conflict().json(format!("Schedule '{}' was just queued", name)),
+        Err(e) => return HttpResponse::InternalServerError().json(format!("Failed to queue schedule: {}", e)),
+    };
+    match sqlx::query(&format!("{} WHERE r.id = ?", SELECT_RUNS)).bind(run).fetch_one(queue.pool()).await {
+        Ok(row) => HttpResponse::Accepted().json(Run::from_row(&row)),
+        Err(e) => HttpResponse::InternalServerError().json(format!("Failed to read schedule run: {}", e)),
+    }
+}
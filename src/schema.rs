@@ -0,0 +1,123 @@
+//! Inferred document schemas.
+//!
+//! `GET /{uri}/_schema` describes what the documents of a collection hold,
+//! so clients can discover its fields without reading documents
+//! themselves. Up to `?sample=` documents (1000 by default), the first by
+//! id, are read through the store, so objects and arrays that nested
+//! collections keep in tables of their own are seen in place, and their
+//! merged shape is answered as a JSON Schema, the way `codegen` infers it.
+//! The tables holding the collection, its child tables included, are
+//! listed with their columns and declared types.
+
+use actix_web::{web, HttpResponse};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use sqlx::SqlitePool;
+
+use crate::codegen::Shape;
+use crate::database::{collection_tables, count_rows, table_columns};
+use crate::handlers::route;
+use crate::nested::{self, chain_table};
+use crate::partition::Partitions;
+use crate::query::quote;
+use crate::shard::Shards;
+use crate::store::DocumentStore;
+
+const DEFAULT_SAMPLE: i64 = 1000;
+const MAX_SAMPLE: i64 = 10000;
+
+// 注册集合下的结构接口, 与其他文档接口一样检查 ACL
+pub fn configure_documents(cfg: &mut web::ServiceConfig) {
+    cfg.route(&route("/{uri}/_schema"), web::get().to(collection_schema));
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SchemaParams {
+    pub sample: Option<i64>,
+}
+
+/// A column of a table holding the collection.
+#[derive(Debug, Serialize)]
+pub struct Column {
+    pub name: String,
+    /// Declared column type.
+    #[serde(rename = "type")]
+    pub ty: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Table {
+    pub table: String,
+    pub columns: Vec<Column>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct SchemaReport {
+    pub collection: String,
+    pub documents: i64,
+    /// Documents the schema was inferred from.
+    pub sampled: usize,
+    pub schema: Value,
+    pub tables: Vec<Table>,
+}
+
+// 按抽样的文档推断集合的结构, 并列出存放它的表
+async fn collection_schema(
+    uri: web::Path<String>,
+    params: web::Query<SchemaParams>,
+    pool: web::Data<SqlitePool>,
+    shards: web::Data<Shards>,
+    partitions: web::Data<Partitions>,
+    store: web::Data<dyn DocumentStore>,
+) -> HttpResponse {
+    let sample = params.sample.unwrap_or(DEFAULT_SAMPLE).clamp(1, MAX_SAMPLE);
+    let tables = match collection_tables(&pool, &shards, &partitions, &uri).await {
+        Ok(tables) if tables.is_empty() => return HttpResponse::NotFound().json(format!("No collection '{}'", uri)),
+        Ok(tables) => tables,
+        Err(e) => return HttpResponse::InternalServerError().json(format!("Failed to read collection: {}", e)),
+    };
+    let sampled = async {
+        let mut documents = 0;
+        let mut ids: Vec<i64> = Vec::new();
+        for (pool, table) in &tables {
+            documents += count_rows(pool, table).await?;
+            let query = format!("SELECT id FROM {} ORDER BY id LIMIT ?", quote(table));
+            ids.extend(sqlx::query_scalar::<_, i64>(&query).bind(sample).fetch_all(pool).await?);
+        }
+        ids.sort_unstable();
+        ids.truncate(sample as usize);
+        Ok::<_, sqlx::Error>((documents, ids))
+    };
+    let (documents, ids) = match sampled.await {
+        Ok(sampled) => sampled,
+        Err(e) => return HttpResponse::InternalServerError().json(format!("Failed to read collection: {}", e)),
+    };
+    let docs = match store.get_many(&uri, &ids).await {
+        Ok(docs) => docs,
+        Err(e) => return HttpResponse::InternalServerError().json(format!("Failed to read documents: {}", e)),
+    };
+    let mut shape = Shape::default();
+    for doc in &docs {
+        shape.observe(doc);
+    }
+    let schema = match docs.is_empty() {
+        true => json!({ "type": "object", "properties": {}, "required": [] }),
+        false => shape.json_schema(),
+    };
+
+    let mut described = Vec::new();
+    let children = match nested::chains(&pool, &uri).await {
+        Ok(chains) => chains.into_iter().map(|chain| (pool.get_ref().clone(), chain_table(&uri, &chain))),
+        Err(e) => return HttpResponse::InternalServerError().json(format!("Failed to read collection: {}", e)),
+    };
+    for (pool, table) in tables.into_iter().chain(children) {
+        match table_columns(&pool, &table).await {
+            Ok(columns) => described.push(Table {
+                table,
+                columns: columns.into_iter().map(|(name, ty)| Column { name, ty }).collect(),
+            }),
+            Err(e) => return HttpResponse::InternalServerError().json(format!("Failed to read collection: {}", e)),
+        }
+    }
+    HttpResponse::Ok().json(SchemaReport { collection: uri.into_inner(), documents, sampled: docs.len(), schema, tables: described })
+}
@@ -0,0 +1,657 @@
+//! Search and field discovery across all collections.
+//!
+//! `GET /_search?q=...` looks for the text in every collection the caller may
+//! read. A collection with a full-text index (an FTS5 table named
+//! `_fts_<collection>` whose rowids are document ids) is searched through it;
+//! the others are scanned for column values containing the text.
+//!
+//! `POST /{uri}/_index/fts` with `{"fields": ["title", "body"]}` gives a
+//! collection that full-text index, filled from its documents and kept up
+//! to date by triggers on its table, so every write path updates it.
+//! `DELETE /{uri}/_index/fts` drops it. `GET /{uri}/_search?q=` runs `q` as
+//! an FTS5 query against the index, best matches first by bm25 rank, and
+//! reads the documents through the store.
+//!
+//! `GET /_keys` lists the fields of those collections, and `GET /_keys?name=`
+//! reports where one field occurs, with value counts.
+//!
+//! `GET /_schema/{uri}/inferred` samples the first documents of a collection
+//! and answers a JSON Schema of them: the types of each field, how often it
+//! is present (`x-frequency`), which fields every sampled document has
+//! (`required`), and, for strings and integers taking a few repeated values,
+//! the values as `enum` candidates.
+//!
+//! `GET /_paths?prefix=api/v1` lists the paths one level below the prefix
+//! that lead to collections the caller may read, for browsing collections
+//! named by multi-segment paths.
+
+#[cfg(feature = "fts")]
+use actix_web::middleware::from_fn;
+use actix_web::{web, HttpMessage, HttpRequest, HttpResponse};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Map, Value};
+use sqlx::SqlitePool;
+use std::collections::{BTreeMap, BTreeSet};
+#[cfg(feature = "fts")]
+use std::collections::HashMap;
+
+use crate::acl::{self, Permission};
+#[cfg(feature = "fts")]
+use crate::auth;
+use crate::auth::Principal;
+#[cfg(feature = "fts")]
+use crate::database::create_table;
+use crate::database::{collection_exists, count_rows, list_collections, row_to_json, table_columns, table_name, uri_of};
+use crate::handlers::route;
+#[cfg(feature = "fts")]
+use crate::partition::Partitions;
+use crate::query::quote;
+#[cfg(feature = "fts")]
+use crate::shard::Shards;
+#[cfg(feature = "fts")]
+use crate::store::{check_fields, DocumentStore, StoreError};
+use crate::store::VERSION_FIELD;
+use crate::telemetry::db_span;
+
+const DEFAULT_LIMIT: i64 = 20;
+const MAX_LIMIT: i64 = 100;
+const DEFAULT_SAMPLE: i64 = 1000;
+const MAX_SAMPLE: i64 = 10000;
+/// Most distinct values a field may take in the sample to get `enum` candidates.
+const ENUM_CANDIDATES: usize = 10;
+
+// 注册搜索接口
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.route("/_search", web::get().to(search))
+        .route("/_keys", web::get().to(keys))
+        .route("/_paths", web::get().to(paths))
+        .route(&route("/_schema/{uri}/inferred"), web::get().to(inferred_schema));
+}
+
+// 注册集合下的全文索引接口; 建立和删除索引仅限管理员
+#[cfg(feature = "fts")]
+pub fn configure_documents(cfg: &mut web::ServiceConfig) {
+    cfg.route(&route("/{uri}/_search"), web::get().to(search_fts)).service(
+        web::resource(route("/{uri}/_index/fts"))
+            .wrap(from_fn(auth::require_admin))
+            .route(web::post().to(create_fts_index))
+            .route(web::delete().to(drop_fts_index)),
+    );
+}
+
+#[cfg(not(feature = "fts"))]
+pub fn configure_documents(_cfg: &mut web::ServiceConfig) {}
+
+#[derive(Debug, Deserialize)]
+pub struct SearchParams {
+    pub q: String,
+    pub limit: Option<i64>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Hit {
+    pub collection: String,
+    pub id: Option<i64>,
+    pub document: Value,
+}
+
+#[derive(Debug, Serialize)]
+pub struct SearchResult {
+    pub hits: Vec<Hit>,
+    /// More documents matched than `limit`.
+    pub truncated: bool,
+}
+
+/// Name of the full-text index of `collection`.
+#[cfg(feature = "fts")]
+pub fn fts_table(collection: &str) -> String {
+    format!("_fts_{}", collection)
+}
+
+/// Statements creating the triggers that copy `fields` of the rows of
+/// `table` into its full-text index. String values are indexed as their
+/// text and other values as their JSON; compressed values are left out.
+#[cfg(feature = "fts")]
+pub fn fts_triggers(table: &str, fields: &[String]) -> Vec<String> {
+    let fts = quote(&fts_table(table));
+    let columns: Vec<String> = fields.iter().map(|field| quote(field)).collect();
+    let new: Vec<String> = fields.iter().map(|field| format!("NEW.{}", quote(field))).collect();
+    let insert = format!("INSERT INTO {} (rowid, {}) VALUES (NEW.id, {});", fts, columns.join(", "), fts_values(&new));
+    let delete = format!("DELETE FROM {} WHERE rowid = OLD.id;", fts);
+    let [on_insert, on_update, on_delete] = fts_trigger_names(table);
+    vec![
+        format!("CREATE TRIGGER {} AFTER INSERT ON {} BEGIN {} END", quote(&on_insert), quote(table), insert),
+        format!("CREATE TRIGGER {} AFTER UPDATE ON {} BEGIN {} {} END", quote(&on_update), quote(table), delete, insert),
+        format!("CREATE TRIGGER {} AFTER DELETE ON {} BEGIN {} END", quote(&on_delete), quote(table), delete),
+    ]
+}
+
+// 值以 JSON 文本保存, 字符串取出其中的文本, 转义不会变成词的一部分
+#[cfg(feature = "fts")]
+fn fts_values(columns: &[String]) -> String {
+    let text = |column: &String| {
+        format!(
+            "CASE WHEN typeof({0}) <> 'text' THEN NULL WHEN NOT json_valid({0}) THEN {0} WHEN json_type({0}) = 'text' THEN json_extract({0}, '$') ELSE {0} END",
+            column
+        )
+    };
+    columns.iter().map(text).collect::<Vec<_>>().join(", ")
+}
+
+/// Names of the triggers of [`fts_triggers`].
+#[cfg(feature = "fts")]
+pub fn fts_trigger_names(table: &str) -> [String; 3] {
+    ["insert", "update", "delete"].map(|event| format!("{}_{}", fts_table(table), event))
+}
+
+#[cfg(feature = "fts")]
+#[derive(Debug, Deserialize)]
+pub struct FtsRequest {
+    pub fields: Vec<String>,
+}
+
+// 为集合建立全文索引, 替换已有的索引; 已有的文档在同一事务中写入索引
+#[cfg(feature = "fts")]
+async fn create_fts_index(
+    uri: web::Path<String>,
+    body: web::Json<FtsRequest>,
+    pool: web::Data<SqlitePool>,
+    shards: web::Data<Shards>,
+    partitions: web::Data<Partitions>,
+) -> HttpResponse {
+    let table = table_name(&uri);
+    let mut fields: Vec<String> = Vec::new();
+    for field in body.into_inner().fields {
+        if !fields.contains(&field) {
+            fields.push(field);
+        }
+    }
+    if fields.is_empty() {
+        return HttpResponse::BadRequest().json("fields must name at least one field");
+    }
+    if let Some(field) = fields.iter().find(|field| field.eq_ignore_ascii_case("id") || field.eq_ignore_ascii_case(VERSION_FIELD)) {
+        return HttpResponse::BadRequest().json(format!("'{}' cannot be indexed", field));
+    }
+    let doc: Map<String, Value> = fields.iter().map(|field| (field.clone(), json!(""))).collect();
+    let doc = Value::Object(doc);
+    if let Err(StoreError::Invalid(e)) = check_fields(&doc) {
+        return HttpResponse::BadRequest().json(e);
+    }
+    if shards.get(&table).is_some() || partitions.tables(&uri, None).await.is_some() {
+        return HttpResponse::BadRequest().json(format!("Sharded or partitioned collection '{}' cannot have a full-text index", uri));
+    }
+    // 触发器引用的列必须存在; 持有读锁期间这些列不会被清理掉
+    let _columns = match create_table(&pool, &table, &doc).await {
+        Ok(lock) => lock,
+        Err(e) => return HttpResponse::InternalServerError().json(format!("Failed to create full-text index: {}", e)),
+    };
+    match build_fts_index(&pool, &table, &fields).await {
+        Ok(documents) => HttpResponse::Ok().json(json!({ "collection": uri.as_str(), "fields": fields, "documents": documents })),
+        Err(e) => HttpResponse::InternalServerError().json(format!("Failed to create full-text index: {}", e)),
+    }
+}
+
+#[cfg(feature = "fts")]
+async fn build_fts_index(pool: &SqlitePool, table: &str, fields: &[String]) -> Result<u64, sqlx::Error> {
+    let fts = fts_table(table);
+    let mut tx = pool.begin().await?;
+    for trigger in fts_trigger_names(table) {
+        sqlx::query(&format!("DROP TRIGGER IF EXISTS {}", quote(&trigger))).execute(&mut *tx).await?;
+    }
+    sqlx::query(&format!("DROP TABLE IF EXISTS {}", quote(&fts))).execute(&mut *tx).await?;
+    let columns: Vec<String> = fields.iter().map(|field| quote(field)).collect();
+    sqlx::query(&format!("CREATE VIRTUAL TABLE {} USING fts5({})", quote(&fts), columns.join(", "))).execute(&mut *tx).await?;
+    let query = format!("INSERT INTO {} (rowid, {}) SELECT id, {} FROM {}", quote(&fts), columns.join(", "), fts_values(&columns), quote(table));
+    let mut span = db_span(&query, table);
+    let documents = sqlx::query(&query).execute(&mut *tx).await.inspect_err(|e| span.error(e))?.rows_affected();
+    for trigger in fts_triggers(table, fields) {
+        sqlx::query(&trigger).execute(&mut *tx).await?;
+    }
+    tx.commit().await?;
+    Ok(documents)
+}
+
+// 删除集合的全文索引和它的触发器
+#[cfg(feature = "fts")]
+async fn drop_fts_index(uri: web::Path<String>, pool: web::Data<SqlitePool>) -> HttpResponse {
+    let table = table_name(&uri);
+    let dropped = async {
+        let mut tx = pool.begin().await?;
+        for trigger in fts_trigger_names(&table) {
+            sqlx::query(&format!("DROP TRIGGER IF EXISTS {}", quote(&trigger))).execute(&mut *tx).await?;
+        }
+        let exists: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM sqlite_master WHERE type = 'table' AND name = ?")
+            .bind(fts_table(&table))
+            .fetch_one(&mut *tx)
+            .await?;
+        sqlx::query(&format!("DROP TABLE IF EXISTS {}", quote(&fts_table(&table)))).execute(&mut *tx).await?;
+        tx.commit().await?;
+        Ok::<_, sqlx::Error>(exists > 0)
+    };
+    match dropped.await {
+        Ok(true) => HttpResponse::NoContent().finish(),
+        Ok(false) => HttpResponse::NotFound().json(format!("Collection '{}' has no full-text index", uri)),
+        Err(e) => HttpResponse::InternalServerError().json(format!("Failed to drop full-text index: {}", e)),
+    }
+}
+
+/// A document found by `GET /{uri}/_search`, with its bm25 score; higher
+/// scores match better.
+#[cfg(feature = "fts")]
+#[derive(Debug, Serialize)]
+pub struct RankedHit {
+    pub id: i64,
+    pub score: f64,
+    pub document: Value,
+}
+
+// 用集合的全文索引搜索, 按相关度排序; 文档经由存储层读出
+#[cfg(feature = "fts")]
+async fn search_fts(
+    uri: web::Path<String>,
+    params: web::Query<SearchParams>,
+    pool: web::Data<SqlitePool>,
+    store: web::Data<dyn DocumentStore>,
+) -> HttpResponse {
+    let q = params.q.trim();
+    if q.is_empty() {
+        return HttpResponse::BadRequest().json("q must not be empty");
+    }
+    let limit = params.limit.unwrap_or(DEFAULT_LIMIT).clamp(1, MAX_LIMIT);
+    let fts = fts_table(&table_name(&uri));
+    match collection_exists(&pool, &fts).await {
+        Ok(true) => {}
+        Ok(false) => return HttpResponse::NotFound().json(format!("Collection '{}' has no full-text index", uri)),
+        Err(e) => return HttpResponse::InternalServerError().json(format!("Failed to search '{}': {}", uri, e)),
+    }
+    // 多取一条, 用来判断结果是否被截断
+    let query = format!("SELECT rowid, bm25({0}) FROM {0} WHERE {0} MATCH ? ORDER BY bm25({0}) LIMIT ?", quote(&fts));
+    let mut span = db_span(&query, &fts);
+    let mut ranked: Vec<(i64, f64)> = match sqlx::query_as(&query).bind(q).bind(limit + 1).fetch_all(&**pool).await {
+        Ok(ranked) => ranked,
+        Err(e) => {
+            span.error(&e);
+            return HttpResponse::BadRequest().json(format!("Invalid full-text query: {}", e));
+        }
+    };
+    let truncated = ranked.len() as i64 > limit;
+    ranked.truncate(limit as usize);
+    let ids: Vec<i64> = ranked.iter().map(|(id, _)| *id).collect();
+    let mut documents: HashMap<i64, Value> = match store.get_many(&uri, &ids).await {
+        Ok(docs) => docs.into_iter().filter_map(|doc| Some((doc.get("id")?.as_i64()?, doc))).collect(),
+        Err(e) => return HttpResponse::InternalServerError().json(format!("Failed to read documents: {}", e)),
+    };
+    let hits: Vec<RankedHit> = ranked
+        .into_iter()
+        .filter_map(|(id, rank)| Some(RankedHit { id, score: -rank, document: documents.remove(&id)? }))
+        .collect();
+    HttpResponse::Ok().json(json!({ "hits": hits, "truncated": truncated }))
+}
+
+// 在调用方可读的所有集合中搜索
+pub async fn search(req: HttpRequest, params: web::Query<SearchParams>, pool: web::Data<SqlitePool>) -> HttpResponse {
+    let q = params.q.trim();
+    if q.is_empty() {
+        return HttpResponse::BadRequest().json("q must not be empty");
+    }
+    let limit = params.limit.unwrap_or(DEFAULT_LIMIT).clamp(1, MAX_LIMIT);
+
+    let principal = req.extensions().get::<Principal>().cloned();
+    let grants = match acl::grants(&pool, principal.as_ref()).await {
+        Ok(grants) => grants,
+        Err(e) => return HttpResponse::InternalServerError().json(format!("Failed to read ACL: {}", e)),
+    };
+    let collections = match list_collections(&pool).await {
+        Ok(names) => names,
+        Err(e) => return HttpResponse::InternalServerError().json(format!("Failed to list collections: {}", e)),
+    };
+
+    let mut hits = Vec::new();
+    let mut truncated = false;
+    for collection in collections.iter().filter(|c| grants.allows(&uri_of(c), Permission::Read)) {
+        // 多取一条, 用来判断结果是否被截断
+        let remaining = limit - hits.len() as i64;
+        let found = match search_collection(&pool, collection, q, remaining + 1).await {
+            Ok(found) => found,
+            Err(e) => {
+                return HttpResponse::InternalServerError().json(format!("Failed to search '{}': {}", collection, e))
+            }
+        };
+        for document in found {
+            if hits.len() as i64 == limit {
+                truncated = true;
+                break;
+            }
+            hits.push(Hit {
+                collection: uri_of(collection),
+                id: document.get("id").and_then(Value::as_i64),
+                document,
+            });
+        }
+        if truncated {
+            break;
+        }
+    }
+    HttpResponse::Ok().json(SearchResult { hits, truncated })
+}
+
+async fn search_collection(pool: &SqlitePool, collection: &str, q: &str, limit: i64) -> Result<Vec<Value>, sqlx::Error> {
+    let (query, pattern) = if let Some(indexed) = indexed_query(pool, collection, q).await? {
+        indexed
+    } else {
+        let columns: Vec<String> = table_columns(pool, collection)
+            .await?
+            .into_iter()
+            .map(|(name, _)| name)
+            .filter(|name| name != "id" && name != VERSION_FIELD)
+            .collect();
+        if columns.is_empty() {
+            return Ok(Vec::new());
+        }
+        let conditions: Vec<String> = columns
+            .iter()
+            .map(|c| format!("CAST({} AS TEXT) LIKE ?1 ESCAPE '\\'", quote(c)))
+            .collect();
+        let query = format!("SELECT * FROM {} WHERE {} LIMIT ?2", quote(collection), conditions.join(" OR "));
+        (query, format!("%{}%", q.replace('\\', "\\\\").replace('%', "\\%").replace('_', "\\_")))
+    };
+    let mut span = db_span(&query, collection);
+    let rows = sqlx::query(&query)
+        .bind(pattern)
+        .bind(limit)
+        .fetch_all(pool)
+        .await
+        .inspect_err(|e| span.error(e))?;
+    span.set_i64("db.response.returned_rows", rows.len() as i64);
+    Ok(rows.iter().map(row_to_json).collect())
+}
+
+// 集合有全文索引时, 经由索引搜索的查询和它的参数
+#[cfg(feature = "fts")]
+async fn indexed_query(pool: &SqlitePool, collection: &str, q: &str) -> Result<Option<(String, String)>, sqlx::Error> {
+    let fts = fts_table(collection);
+    if !collection_exists(pool, &fts).await? {
+        return Ok(None);
+    }
+    let query = format!(
+        "SELECT * FROM {} WHERE id IN (SELECT rowid FROM {} WHERE {} MATCH ?1) LIMIT ?2",
+        quote(collection),
+        quote(&fts),
+        quote(&fts)
+    );
+    // 整个查询作为一个短语, 避免 FTS 语法错误
+    Ok(Some((query, format!("\"{}\"", q.replace('"', "\"\"")))))
+}
+
+#[cfg(not(feature = "fts"))]
+async fn indexed_query(_pool: &SqlitePool, _collection: &str, _q: &str) -> Result<Option<(String, String)>, sqlx::Error> {
+    Ok(None)
+}
+
+#[derive(Debug, Deserialize)]
+pub struct KeyParams {
+    pub name: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct KeyUsage {
+    pub collection: String,
+    /// Declared column type.
+    #[serde(rename = "type")]
+    pub ty: String,
+    pub documents: i64,
+    pub non_null: i64,
+    pub distinct: i64,
+}
+
+// 字段出现在哪些集合中; 指定 name 时附带该字段的取值统计
+pub async fn keys(req: HttpRequest, params: web::Query<KeyParams>, pool: web::Data<SqlitePool>) -> HttpResponse {
+    let principal = req.extensions().get::<Principal>().cloned();
+    let grants = match acl::grants(&pool, principal.as_ref()).await {
+        Ok(grants) => grants,
+        Err(e) => return HttpResponse::InternalServerError().json(format!("Failed to read ACL: {}", e)),
+    };
+    let collections = match list_collections(&pool).await {
+        Ok(names) => names,
+        Err(e) => return HttpResponse::InternalServerError().json(format!("Failed to list collections: {}", e)),
+    };
+
+    let mut fields: BTreeMap<String, Vec<String>> = BTreeMap::new();
+    let mut usages = Vec::new();
+    for collection in collections.iter().filter(|c| grants.allows(&uri_of(c), Permission::Read)) {
+        let columns = match table_columns(&pool, collection).await {
+            Ok(columns) => columns,
+            Err(e) => return HttpResponse::InternalServerError().json(format!("Failed to read '{}': {}", collection, e)),
+        };
+        for (column, ty) in columns {
+            if column == "id" || column == VERSION_FIELD {
+                continue;
+            }
+            match &params.name {
+                Some(name) if *name == column => match key_usage(&pool, collection, &column, ty).await {
+                    Ok(usage) => usages.push(usage),
+                    Err(e) => {
+                        return HttpResponse::InternalServerError().json(format!("Failed to read '{}': {}", collection, e))
+                    }
+                },
+                Some(_) => {}
+                None => fields.entry(column).or_default().push(uri_of(collection)),
+            }
+        }
+    }
+
+    match &params.name {
+        Some(name) => HttpResponse::Ok().json(json!({ "name": name, "collections": usages })),
+        None => HttpResponse::Ok().json(fields),
+    }
+}
+
+async fn key_usage(pool: &SqlitePool, collection: &str, column: &str, ty: String) -> Result<KeyUsage, sqlx::Error> {
+    let query = format!("SELECT COUNT({0}), COUNT(DISTINCT {0}) FROM {1}", quote(column), quote(collection));
+    let mut span = db_span(&query, collection);
+    let (non_null, distinct): (i64, i64) = sqlx::query_as(&query)
+        .fetch_one(pool)
+        .await
+        .inspect_err(|e| span.error(e))?;
+    Ok(KeyUsage {
+        collection: uri_of(collection),
+        ty,
+        documents: count_rows(pool, collection).await?,
+        non_null,
+        distinct,
+    })
+}
+
+#[derive(Debug, Deserialize)]
+pub struct PathParams {
+    pub prefix: Option<String>,
+}
+
+/// A path one level below the prefix of `GET /_paths`.
+#[derive(Debug, Serialize)]
+pub struct PathEntry {
+    pub path: String,
+    /// A collection lives at this path itself.
+    pub collection: bool,
+    /// Readable collections at or below this path.
+    pub collections: usize,
+}
+
+// 列出前缀下一层的路径, 只统计调用方可读的集合
+pub async fn paths(req: HttpRequest, params: web::Query<PathParams>, pool: web::Data<SqlitePool>) -> HttpResponse {
+    let prefix = params.prefix.as_deref().unwrap_or_default().trim_matches('/');
+    let principal = req.extensions().get::<Principal>().cloned();
+    let grants = match acl::grants(&pool, principal.as_ref()).await {
+        Ok(grants) => grants,
+        Err(e) => return HttpResponse::InternalServerError().json(format!("Failed to read ACL: {}", e)),
+    };
+    let collections = match list_collections(&pool).await {
+        Ok(names) => names,
+        Err(e) => return HttpResponse::InternalServerError().json(format!("Failed to list collections: {}", e)),
+    };
+
+    let mut entries: BTreeMap<String, PathEntry> = BTreeMap::new();
+    for uri in collections.iter().map(|c| uri_of(c)).filter(|uri| grants.allows(uri, Permission::Read)) {
+        let rest = match prefix {
+            "" => uri.as_str(),
+            prefix => match uri.strip_prefix(prefix).and_then(|rest| rest.strip_prefix('/')) {
+                Some(rest) => rest,
+                None => continue,
+            },
+        };
+        let segment = rest.split('/').next().unwrap_or_default();
+        let path = match prefix {
+            "" => segment.to_string(),
+            prefix => format!("{}/{}", prefix, segment),
+        };
+        let entry = entries.entry(path.clone()).or_insert(PathEntry { path, collection: false, collections: 0 });
+        entry.collection |= rest == segment;
+        entry.collections += 1;
+    }
+    HttpResponse::Ok().json(json!({ "prefix": prefix, "paths": entries.into_values().collect::<Vec<_>>() }))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct InferParams {
+    pub sample: Option<i64>,
+}
+
+/// What the sampled values found at one place in the documents have in common.
+#[derive(Debug, Default)]
+struct Inferred {
+    /// Values seen, nulls of object fields aside.
+    present: usize,
+    types: BTreeSet<&'static str>,
+    /// Distinct strings and integers seen, in the order first seen.
+    values: Vec<Value>,
+    /// Other values or more than [`ENUM_CANDIDATES`] distinct ones were seen.
+    not_enum: bool,
+    /// Objects seen, and what their fields hold.
+    objects: usize,
+    properties: BTreeMap<String, Inferred>,
+    items: Option<Box<Inferred>>,
+}
+
+impl Inferred {
+    fn observe(&mut self, value: &Value) {
+        self.present += 1;
+        let ty = match value {
+            Value::Null => "null",
+            Value::Bool(_) => "boolean",
+            Value::Number(n) if n.is_i64() || n.is_u64() => "integer",
+            Value::Number(_) => "number",
+            Value::String(_) => "string",
+            Value::Array(_) => "array",
+            Value::Object(_) => "object",
+        };
+        self.types.insert(ty);
+        match ty {
+            "string" | "integer" if !self.not_enum => {
+                if !self.values.contains(value) {
+                    self.values.push(value.clone());
+                }
+                self.not_enum = self.values.len() > ENUM_CANDIDATES;
+            }
+            _ => self.not_enum = true,
+        }
+        match value {
+            // 值为 null 的字段与没有该字段相同
+            Value::Object(fields) => {
+                self.objects += 1;
+                for (key, value) in fields.iter().filter(|(_, value)| !value.is_null()) {
+                    self.properties.entry(key.clone()).or_default().observe(value);
+                }
+            }
+            Value::Array(items) => {
+                let inferred = self.items.get_or_insert_with(Box::default);
+                for item in items {
+                    inferred.observe(item);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn schema(&self) -> Value {
+        let mut schema = Map::new();
+        // integer 包含在 number 中
+        let types: Vec<&str> =
+            self.types.iter().copied().filter(|ty| *ty != "integer" || !self.types.contains("number")).collect();
+        match types.as_slice() {
+            [] => {}
+            [ty] => {
+                schema.insert("type".to_string(), json!(ty));
+            }
+            types => {
+                schema.insert("type".to_string(), json!(types));
+            }
+        }
+        if self.objects > 0 {
+            let mut properties = Map::new();
+            for (key, property) in &self.properties {
+                let mut inferred = property.schema();
+                let frequency = property.present as f64 / self.objects as f64;
+                inferred["x-frequency"] = json!((frequency * 1000.0).round() / 1000.0);
+                properties.insert(key.clone(), inferred);
+            }
+            let required: Vec<&String> =
+                self.properties.iter().filter(|(_, property)| property.present == self.objects).map(|(key, _)| key).collect();
+            schema.insert("properties".to_string(), Value::Object(properties));
+            schema.insert("required".to_string(), json!(required));
+        }
+        if let Some(items) = &self.items {
+            schema.insert("items".to_string(), items.schema());
+        }
+        // 取值有重复才像枚举, 否则只是恰好抽到的几个值
+        if !self.not_enum && !self.values.is_empty() && self.values.len() < self.present {
+            schema.insert("enum".to_string(), Value::Array(self.values.clone()));
+        }
+        Value::Object(schema)
+    }
+}
+
+// 抽样集合中的文档推断 JSON Schema, 附带字段出现的比例和可能的枚举值
+pub async fn inferred_schema(
+    req: HttpRequest,
+    uri: web::Path<String>,
+    params: web::Query<InferParams>,
+    pool: web::Data<SqlitePool>,
+) -> HttpResponse {
+    let principal = req.extensions().get::<Principal>().cloned();
+    match acl::grants(&pool, principal.as_ref()).await {
+        Ok(grants) if grants.allows(&uri, Permission::Read) => {}
+        Ok(_) => return HttpResponse::Forbidden().json(format!("No read access to collection '{}'", uri)),
+        Err(e) => return HttpResponse::InternalServerError().json(format!("Failed to read ACL: {}", e)),
+    }
+    let table = table_name(&uri);
+    match collection_exists(&pool, &table).await {
+        Ok(true) => {}
+        Ok(false) => return HttpResponse::NotFound().json(format!("No collection '{}'", uri)),
+        Err(e) => return HttpResponse::InternalServerError().json(format!("Failed to read collection: {}", e)),
+    }
+    let sample = params.sample.unwrap_or(DEFAULT_SAMPLE).clamp(1, MAX_SAMPLE);
+
+    let query = format!("SELECT * FROM {} ORDER BY id LIMIT ?", quote(&table));
+    let mut span = db_span(&query, &table);
+    let rows = match sqlx::query(&query).bind(sample).fetch_all(&**pool).await {
+        Ok(rows) => rows,
+        Err(e) => {
+            span.error(&e);
+            return HttpResponse::InternalServerError().json(format!("Failed to read collection: {}", e));
+        }
+    };
+    let mut inferred = Inferred::default();
+    for row in &rows {
+        let mut document = row_to_json(row);
+        if let Some(fields) = document.as_object_mut() {
+            fields.remove(VERSION_FIELD);
+        }
+        inferred.observe(&document);
+    }
+    let mut schema = match rows.is_empty() {
+        true => json!({ "type": "object", "properties": {}, "required": [] }),
+        false => inferred.schema(),
+    };
+    schema["$schema"] = json!("https://json-schema.org/draft/2020-12/schema");
+    HttpResponse::Ok().json(json!({ "collection": uri.as_str(), "sampled": rows.len(), "schema": schema }))
+}
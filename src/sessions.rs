@@ -0,0 +1,192 @@
+//! Browser sessions for the admin UI.
+//!
+//! A session is held server-side; the browser only gets its id in the
+//! `session` cookie, signed with HMAC-SHA256 so that forged or tampered ids
+//! are rejected before any lookup. Every session carries a CSRF token that
+//! must accompany mutating requests authenticated by the cookie (see
+//! [`crate::auth::authenticate`]).
+
+use actix_web::cookie::{Cookie, SameSite};
+use actix_web::{web, HttpMessage, HttpRequest, HttpResponse};
+use hmac::{Hmac, Mac};
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::auth::{constant_time_eq, find_api_key, Principal};
+use crate::config::ConfigHandle;
+
+pub const COOKIE: &str = "session";
+pub const CSRF_HEADER: &str = "X-CSRF-Token";
+
+struct Session {
+    principal: Principal,
+    csrf_token: String,
+    expires: Instant,
+}
+
+/// A live session, as resolved from the request cookie.
+#[derive(Debug, Clone)]
+pub struct ActiveSession {
+    pub id: String,
+    pub principal: Principal,
+    pub csrf_token: String,
+}
+
+/// Shared across workers; create one in `main` and register it as app data.
+pub struct SessionStore {
+    secret: Vec<u8>,
+    sessions: Mutex<HashMap<String, Session>>,
+}
+
+impl SessionStore {
+    /// `secret` signs the cookies; without one a random secret is generated.
+    pub fn new(secret: Option<&str>) -> Self {
+        let secret = match secret {
+            Some(secret) => secret.as_bytes().to_vec(),
+            None => random_bytes(32),
+        };
+        Self { secret, sessions: Mutex::new(HashMap::new()) }
+    }
+
+    /// Starts a session and returns the signed cookie value.
+    pub fn create(&self, principal: Principal, ttl: Duration) -> String {
+        let id = random_token(32);
+        let mut sessions = self.sessions.lock().unwrap();
+        // 顺便清理过期的会话
+        let now = Instant::now();
+        sessions.retain(|_, s| s.expires > now);
+        sessions.insert(
+            id.clone(),
+            Session { principal, csrf_token: random_token(32), expires: now + ttl },
+        );
+        format!("{}.{}", id, self.signature(&id))
+    }
+
+    /// Resolves a signed cookie value to its session, if it is genuine and not expired.
+    pub fn get(&self, cookie: &str) -> Option<ActiveSession> {
+        let (id, signature) = cookie.split_once('.')?;
+        if !constant_time_eq(self.signature(id).as_bytes(), signature.as_bytes()) {
+            return None;
+        }
+
+        let mut sessions = self.sessions.lock().unwrap();
+        match sessions.get(id) {
+            Some(s) if s.expires > Instant::now() => Some(ActiveSession {
+                id: id.to_string(),
+                principal: s.principal.clone(),
+                csrf_token: s.csrf_token.clone(),
+            }),
+            Some(_) => {
+                sessions.remove(id);
+                None
+            }
+            None => None,
+        }
+    }
+
+    pub fn remove(&self, id: &str) {
+        self.sessions.lock().unwrap().remove(id);
+    }
+
+    fn signature(&self, id: &str) -> String {
+        let mut mac = Hmac::<Sha256>::new_from_slice(&self.secret).expect("HMAC accepts any key length");
+        mac.update(id.as_bytes());
+        hex(&mac.finalize().into_bytes())
+    }
+}
+
+fn random_bytes(n: usize) -> Vec<u8> {
+    let mut buf = vec![0u8; n];
+    rand::thread_rng().fill_bytes(&mut buf);
+    buf
+}
+
+pub(crate) fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Hex encoding of `bytes` random bytes.
+pub fn random_token(bytes: usize) -> String {
+    hex(&random_bytes(bytes))
+}
+
+/// The session cookie, `Secure` when the request came in over HTTPS.
+pub fn cookie(req: &HttpRequest, value: String, max_age: Duration) -> Cookie<'static> {
+    Cookie::build(COOKIE, value)
+        .path("/")
+        .http_only(true)
+        .same_site(SameSite::Lax)
+        .secure(req.connection_info().scheme() == "https")
+        .max_age(actix_web::cookie::time::Duration::seconds(max_age.as_secs() as i64))
+        .finish()
+}
+
+// 注册会话接口
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.route("/_auth/session", web::get().to(current_session))
+        .route("/_auth/session", web::post().to(login))
+        .route("/_auth/logout", web::post().to(logout));
+    #[cfg(feature = "oidc")]
+    crate::oidc::configure(cfg);
+}
+
+#[derive(Debug, Serialize)]
+pub struct SessionInfo {
+    pub id: String,
+    pub role: crate::config::Role,
+    pub csrf_token: String,
+}
+
+// 当前会话的用户和 CSRF token
+pub async fn current_session(req: HttpRequest) -> HttpResponse {
+    match req.extensions().get::<ActiveSession>() {
+        Some(session) => HttpResponse::Ok().json(SessionInfo {
+            id: session.principal.id.clone(),
+            role: session.principal.role,
+            csrf_token: session.csrf_token.clone(),
+        }),
+        None => HttpResponse::Unauthorized().json("No session"),
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct LoginRequest {
+    pub api_key: String,
+}
+
+// 用 API key 换取会话 cookie, 浏览器之后不再需要保存 key
+pub async fn login(
+    req: HttpRequest,
+    body: web::Json<LoginRequest>,
+    config: web::Data<ConfigHandle>,
+    sessions: web::Data<SessionStore>,
+) -> HttpResponse {
+    let config = config.get();
+    let Some(key) = find_api_key(&config, &body.api_key) else {
+        return HttpResponse::Unauthorized().json("Invalid API key");
+    };
+    let principal = Principal { id: key.id.clone(), role: key.role, tenant: key.tenant.clone() };
+
+    let ttl = Duration::from_secs(config.sessions.ttl_secs);
+    let value = sessions.create(principal, ttl);
+    let session = sessions.get(&value).expect("session was just created");
+    HttpResponse::Ok().cookie(cookie(&req, value, ttl)).json(SessionInfo {
+        id: session.principal.id,
+        role: session.principal.role,
+        csrf_token: session.csrf_token,
+    })
+}
+
+// 退出登录: 删除会话并清除 cookie
+pub async fn logout(req: HttpRequest, sessions: web::Data<SessionStore>) -> HttpResponse {
+    if let Some(session) = req.extensions().get::<ActiveSession>() {
+        sessions.remove(&session.id);
+    }
+    let mut removal = cookie(&req, String::new(), Duration::ZERO);
+    removal.make_removal();
+    HttpResponse::NoContent().cookie(removal).finish()
+}
@@ -0,0 +1,352 @@
+//! Sharded collections.
+//!
+//! A collection listed in `sharding.collections` is kept in `shards`
+//! separate SQLite files, `<directory>/<collection>.<n>.db`, instead of the
+//! main database, so writes to different shards do not wait on one file's
+//! lock. The id of a document names its shard: shard `n` of `count` only
+//! hands out ids with `(id - 1) % count == n`, so reads and writes by id go
+//! straight to one file. A new document goes to the shard picked by a hash
+//! of its `key` field, or to the next shard in turn without a key;
+//! deduplicated inserts without a key are placed by their content hash, so
+//! duplicates always meet in the same file. Listings and console filters
+//! query every shard and merge the results by id.
+//!
+//! A document stays where it was first stored when its key changes. Shard
+//! files only hold the collection's documents and content hashes; search,
+//! the admin collection pages and cluster replication only see the main
+//! database.
+
+use async_trait::async_trait;
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use sqlx::sqlite::SqliteConnectOptions;
+use sqlx::SqlitePool;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use tokio::task::JoinSet;
+
+use crate::config::{ConfigHandle, ShardedCollection};
+use crate::database::{collection_exists, create_system_tables, create_table, encode_value, pool_options, table_name, SqliteStore};
+use crate::store::{content_hash, DedupMode, DocumentStore, Inserted, PageQuery, Paged, StoreError, UpdatedMany, Upserted, Written};
+
+/// The shard files of one collection.
+pub struct Collection {
+    key: Option<String>,
+    stores: Vec<SqliteStore>,
+    /// Shard of the next unkeyed insert.
+    next: AtomicUsize,
+}
+
+impl Collection {
+    fn count(&self) -> usize {
+        self.stores.len()
+    }
+
+    // id 决定文档所在的分片
+    fn by_id(&self, id: i64) -> &SqliteStore {
+        &self.stores[(id - 1).rem_euclid(self.count() as i64) as usize]
+    }
+
+    // 新文档按分片键的哈希放置, 没有分片键时轮流放置
+    fn for_insert(&self, doc: &Value) -> usize {
+        match &self.key {
+            Some(key) => {
+                let digest = Sha256::digest(encode_value(doc.get(key).unwrap_or(&Value::Null)).as_bytes());
+                let prefix: [u8; 8] = digest[..8].try_into().expect("SHA-256 digests are 32 bytes");
+                (u64::from_be_bytes(prefix) % self.count() as u64) as usize
+            }
+            None => self.next.fetch_add(1, Ordering::Relaxed) % self.count(),
+        }
+    }
+
+    // 去重插入没有分片键时按内容哈希放置, 相同内容总在同一个分片中比较
+    fn for_unique(&self, doc: &Value, hash: &str) -> usize {
+        if self.key.is_some() {
+            return self.for_insert(doc);
+        }
+        let prefix = hash.get(..16).and_then(|h| u64::from_str_radix(h, 16).ok()).unwrap_or_default();
+        (prefix % self.count() as u64) as usize
+    }
+
+    /// The pools of every shard.
+    pub fn pools(&self) -> Vec<SqlitePool> {
+        self.stores.iter().map(|store| store.pool().clone()).collect()
+    }
+}
+
+/// The sharded collections, keyed by table name.
+#[derive(Default)]
+pub struct Shards {
+    collections: HashMap<String, Collection>,
+}
+
+impl Shards {
+    /// Opens, creating if needed, the shard files of every sharded collection.
+    pub async fn open(config: &ConfigHandle) -> Result<Self, sqlx::Error> {
+        let Some(sharding) = config.get().sharding.clone() else {
+            return Ok(Shards::default());
+        };
+        std::fs::create_dir_all(&sharding.directory)?;
+        let mut collections = HashMap::new();
+        for (uri, settings) in &sharding.collections {
+            let table = table_name(uri);
+            let mut stores = Vec::new();
+            for index in 0..settings.shards as i64 {
+                let path = sharding.directory.join(format!("{}.{}.db", table, index));
+                let pool = open_shard(&path, index, settings).await?;
+                stores.push(SqliteStore::with_ids(pool, index + 1, settings.shards as i64));
+            }
+            log::info!("collection '{}' sharded over {} files in {}", uri, settings.shards, sharding.directory.display());
+            collections.insert(table, Collection { key: settings.key.clone(), stores, next: AtomicUsize::new(0) });
+        }
+        Ok(Shards { collections })
+    }
+
+    /// The shards of the collection stored in `table`, if it is sharded.
+    pub fn get(&self, table: &str) -> Option<&Collection> {
+        self.collections.get(table)
+    }
+}
+
+// 每个分片文件记下自己的序号和分片数, 分片数改变后拒绝启动
+async fn open_shard(path: &std::path::Path, index: i64, settings: &ShardedCollection) -> Result<SqlitePool, sqlx::Error> {
+    let options = SqliteConnectOptions::new().filename(path).create_if_missing(true);
+    let pool = pool_options().max_connections(5).connect_with(options).await?;
+    create_system_tables(&pool).await?;
+    sqlx::query("CREATE TABLE IF NOT EXISTS _shard (shard INTEGER NOT NULL, shards INTEGER NOT NULL)")
+        .execute(&pool)
+        .await?;
+    let layout: Option<(i64, i64)> = sqlx::query_as("SELECT shard, shards FROM _shard").fetch_optional(&pool).await?;
+    match layout {
+        Some(layout) if layout == (index, settings.shards as i64) => {}
+        Some((shard, shards)) => {
+            return Err(sqlx::Error::Configuration(
+                format!("{} holds shard {} of {}, not {} of {}", path.display(), shard, shards, index, settings.shards).into(),
+            ))
+        }
+        None => {
+            sqlx::query("INSERT INTO _shard (shard, shards) VALUES (?, ?)")
+                .bind(index)
+                .bind(settings.shards as i64)
+                .execute(&pool)
+                .await?;
+        }
+    }
+    Ok(pool)
+}
+
+/// Sends the calls for sharded collections to their shards and the others
+/// to `inner`.
+pub struct ShardedStore {
+    inner: Arc<dyn DocumentStore>,
+    shards: Arc<Shards>,
+}
+
+impl ShardedStore {
+    pub fn new(inner: Arc<dyn DocumentStore>, shards: Arc<Shards>) -> Self {
+        ShardedStore { inner, shards }
+    }
+
+    fn sharded(&self, uri: &str) -> Option<&Collection> {
+        self.shards.get(&table_name(uri))
+    }
+
+    // 第一个文档在所有分片中以同样的列建表, 之后各分片的列保持一致
+    async fn prepare(&self, collection: &Collection, uri: &str, doc: &Value, shard: usize) -> Result<(), StoreError> {
+        let table = table_name(uri);
+        if collection_exists(collection.stores[shard].pool(), &table).await? {
+            return Ok(());
+        }
+        for store in &collection.stores {
+            create_table(store.pool(), &table, doc).await?;
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl DocumentStore for ShardedStore {
+    async fn insert(&self, uri: &str, doc: &Value) -> Result<i64, StoreError> {
+        let Some(collection) = self.sharded(uri) else {
+            return self.inner.insert(uri, doc).await;
+        };
+        let shard = collection.for_insert(doc);
+        self.prepare(collection, uri, doc, shard).await?;
+        collection.stores[shard].insert(uri, doc).await
+    }
+
+    // 并发查询所有分片, 按 id 合并
+    async fn list(&self, uri: &str) -> Result<Vec<Value>, StoreError> {
+        let Some(collection) = self.sharded(uri) else {
+            return self.inner.list(uri).await;
+        };
+        let mut queries = JoinSet::new();
+        for store in collection.stores.clone() {
+            let uri = uri.to_string();
+            queries.spawn(async move { store.list(&uri).await });
+        }
+        let mut docs = Vec::new();
+        while let Some(listed) = queries.join_next().await {
+            docs.extend(listed.map_err(|e| StoreError::Unavailable(format!("shard query failed: {}", e)))??);
+        }
+        docs.sort_by_key(|doc| doc.get("id").and_then(Value::as_i64));
+        Ok(docs)
+    }
+
+    async fn get(&self, uri: &str, id: i64) -> Result<Option<Value>, StoreError> {
+        match self.sharded(uri) {
+            Some(collection) => collection.by_id(id).get(uri, id).await,
+            None => self.inner.get(uri, id).await,
+        }
+    }
+
+    async fn get_many(&self, uri: &str, ids: &[i64]) -> Result<Vec<Value>, StoreError> {
+        let Some(collection) = self.sharded(uri) else {
+            return self.inner.get_many(uri, ids).await;
+        };
+        let mut by_shard: HashMap<usize, Vec<i64>> = HashMap::new();
+        for id in ids {
+            let shard = (id - 1).rem_euclid(collection.count() as i64) as usize;
+            by_shard.entry(shard).or_default().push(*id);
+        }
+        if by_shard.is_empty() {
+            // 集合不存在时与未分片时一样返回 NotFound
+            return collection.stores[0].get_many(uri, ids).await;
+        }
+        let mut found = HashMap::new();
+        for (shard, ids) in by_shard {
+            for doc in collection.stores[shard].get_many(uri, &ids).await? {
+                found.extend(doc.get("id").and_then(Value::as_i64).map(|id| (id, doc)));
+            }
+        }
+        Ok(ids.iter().filter_map(|id| found.remove(id)).collect())
+    }
+
+    async fn insert_unique(&self, uri: &str, doc: &Value, hash: &str, mode: DedupMode) -> Result<Inserted, StoreError> {
+        let Some(collection) = self.sharded(uri) else {
+            return self.inner.insert_unique(uri, doc, hash, mode).await;
+        };
+        let shard = collection.for_unique(doc, hash);
+        self.prepare(collection, uri, doc, shard).await?;
+        collection.stores[shard].insert_unique(uri, doc, hash, mode).await
+    }
+
+    // 相同 key 的文档总在同一个分片中; 有分片键的集合只能按分片键写入
+    async fn upsert(&self, uri: &str, key: &str, doc: &Value) -> Result<Upserted, StoreError> {
+        let Some(collection) = self.sharded(uri) else {
+            return self.inner.upsert(uri, key, doc).await;
+        };
+        if let Some(shard_key) = collection.key.as_deref().filter(|shard_key| *shard_key != key) {
+            return Err(StoreError::Invalid(format!("'{}' can only be upserted by its shard key '{}'", uri, shard_key)));
+        }
+        let shard = collection.for_unique(doc, &content_hash(doc, &[key.to_string()]));
+        self.prepare(collection, uri, doc, shard).await?;
+        collection.stores[shard].upsert(uri, key, doc).await
+    }
+
+    async fn replace(&self, uri: &str, id: i64, doc: &Value, expected: Option<&[i64]>) -> Result<i64, StoreError> {
+        match self.sharded(uri) {
+            Some(collection) => collection.by_id(id).replace(uri, id, doc, expected).await,
+            None => self.inner.replace(uri, id, doc, expected).await,
+        }
+    }
+
+    async fn update(&self, uri: &str, id: i64, doc: &Value, expected: Option<&[i64]>) -> Result<i64, StoreError> {
+        match self.sharded(uri) {
+            Some(collection) => collection.by_id(id).update(uri, id, doc, expected).await,
+            None => self.inner.update(uri, id, doc, expected).await,
+        }
+    }
+
+    async fn delete(&self, uri: &str, id: i64, expected: Option<&[i64]>) -> Result<(), StoreError> {
+        match self.sharded(uri) {
+            Some(collection) => collection.by_id(id).delete(uri, id, expected).await,
+            None => self.inner.delete(uri, id, expected).await,
+        }
+    }
+
+    // 分片在不同的数据库文件中, 无法放进一个事务
+    async fn update_many(&self, uri: &str, filter: &Value, update: &Value) -> Result<UpdatedMany, StoreError> {
+        match self.sharded(uri) {
+            Some(_) => Err(StoreError::Invalid(format!("Sharded collection '{}' cannot be updated in one transaction", uri))),
+            None => self.inner.update_many(uri, filter, update).await,
+        }
+    }
+
+    async fn delete_many(&self, uri: &str, filter: &Value, dry_run: bool) -> Result<Vec<i64>, StoreError> {
+        match self.sharded(uri) {
+            Some(_) => Err(StoreError::Invalid(format!("Sharded collection '{}' cannot be deleted from in one transaction", uri))),
+            None => self.inner.delete_many(uri, filter, dry_run).await,
+        }
+    }
+
+    async fn find(&self, uri: &str, filter: &Value) -> Result<Vec<Value>, StoreError> {
+        let Some(collection) = self.sharded(uri) else {
+            return self.inner.find(uri, filter).await;
+        };
+        let mut queries = JoinSet::new();
+        for store in collection.stores.clone() {
+            let (uri, filter) = (uri.to_string(), filter.clone());
+            queries.spawn(async move { store.find(&uri, &filter).await });
+        }
+        let mut docs = Vec::new();
+        while let Some(found) = queries.join_next().await {
+            docs.extend(found.map_err(|e| StoreError::Unavailable(format!("shard query failed: {}", e)))??);
+        }
+        docs.sort_by_key(|doc| doc.get("id").and_then(Value::as_i64));
+        Ok(docs)
+    }
+
+    async fn count(&self, uri: &str, filter: &Value) -> Result<u64, StoreError> {
+        let Some(collection) = self.sharded(uri) else {
+            return self.inner.count(uri, filter).await;
+        };
+        let mut queries = JoinSet::new();
+        for store in collection.stores.clone() {
+            let (uri, filter) = (uri.to_string(), filter.clone());
+            queries.spawn(async move { store.count(&uri, &filter).await });
+        }
+        let mut count = 0;
+        while let Some(counted) = queries.join_next().await {
+            count += counted.map_err(|e| StoreError::Unavailable(format!("shard query failed: {}", e)))??;
+        }
+        Ok(count)
+    }
+
+    // 各分片取前 offset + limit 个文档, 合并后再排序分页
+    async fn find_page(&self, uri: &str, filter: &Value, page: &PageQuery) -> Result<Paged, StoreError> {
+        let Some(collection) = self.sharded(uri) else {
+            return self.inner.find_page(uri, filter, page).await;
+        };
+        let mut queries = JoinSet::new();
+        for store in collection.stores.clone() {
+            let (uri, filter, leading) = (uri.to_string(), filter.clone(), page.leading());
+            queries.spawn(async move { store.find_page(&uri, &filter, &leading).await });
+        }
+        let (mut docs, mut total) = (Vec::new(), 0);
+        while let Some(found) = queries.join_next().await {
+            let paged = found.map_err(|e| StoreError::Unavailable(format!("shard query failed: {}", e)))??;
+            docs.extend(paged.docs);
+            total += paged.total;
+        }
+        Ok(Paged { docs: page.apply(docs), total })
+    }
+
+    async fn version(&self, uri: &str) -> Result<String, StoreError> {
+        let Some(collection) = self.sharded(uri) else {
+            return self.inner.version(uri).await;
+        };
+        // 各分片的版本按分片顺序拼接
+        let mut versions = Vec::new();
+        for store in &collection.stores {
+            versions.push(store.version(uri).await?);
+        }
+        Ok(versions.join("/"))
+    }
+
+    // 事务不写分片集合
+    async fn committed(&self, writes: &[Written]) {
+        self.inner.committed(writes).await
+    }
+}
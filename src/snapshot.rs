@@ -0,0 +1,91 @@
+//! Snapshot reads.
+//!
+//! `POST /_snapshot` takes the same body as the multi-collection `POST
+//! /_mget` and answers the same way, but reads every collection in one
+//! SQLite read transaction: no write lands between two of its reads, so the
+//! documents returned are the ones that existed together at one moment.
+//! Partitioned collections are read from all their partitions inside the
+//! same transaction. Sharded collections live in other files and are
+//! refused. Snapshot reads skip the Redis cache.
+
+use actix_web::{web, HttpResponse};
+use serde_json::Value;
+use sqlx::SqlitePool;
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+use crate::database::{row_to_json, table_name};
+use crate::handlers::{MgetResponse, MAX_MGET_IDS};
+use crate::partition::Partitions;
+use crate::query::quote;
+use crate::shard::Shards;
+
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.route("/_snapshot", web::post().to(read_snapshot));
+}
+
+// 在一个读事务中取回多个集合的文档
+async fn read_snapshot(
+    body: web::Json<BTreeMap<String, Vec<i64>>>,
+    pool: web::Data<SqlitePool>,
+    shards: web::Data<Shards>,
+    partitions: web::Data<Partitions>,
+) -> HttpResponse {
+    let requested = body.into_inner();
+    if requested.values().map(Vec::len).sum::<usize>() > MAX_MGET_IDS {
+        return HttpResponse::BadRequest().json(format!("At most {} ids per request", MAX_MGET_IDS));
+    }
+    if let Some(uri) = requested.keys().find(|uri| shards.get(&table_name(uri)).is_some()) {
+        return HttpResponse::BadRequest().json(format!("Sharded collection '{}' cannot be read in a snapshot", uri));
+    }
+
+    let mut reads = Vec::new();
+    for (uri, ids) in requested {
+        let table = table_name(&uri);
+        let tables = partitions.tables(&table, None).await.unwrap_or_else(|| vec![table]);
+        reads.push((uri, tables, ids));
+    }
+    match read(&pool, reads).await {
+        Ok(results) => HttpResponse::Ok().json(results),
+        Err(e) => HttpResponse::InternalServerError().json(format!("Failed to read snapshot: {}", e)),
+    }
+}
+
+/// Reads the documents `ids` of each collection from its `tables`, all in
+/// one read transaction. Ids in tables that do not exist are missing.
+pub async fn read(
+    pool: &SqlitePool,
+    reads: Vec<(String, Vec<String>, Vec<i64>)>,
+) -> Result<BTreeMap<String, MgetResponse>, sqlx::Error> {
+    let mut tx = pool.begin().await?;
+    let mut results = BTreeMap::new();
+    for (uri, tables, mut ids) in reads {
+        // 重复的 id 只返回一次
+        let mut seen = HashSet::new();
+        ids.retain(|id| seen.insert(*id));
+
+        let mut found: HashMap<i64, Value> = HashMap::new();
+        for table in tables {
+            let exists: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM sqlite_master WHERE type = 'table' AND name = ?")
+                .bind(&table)
+                .fetch_one(&mut *tx)
+                .await?;
+            if exists == 0 || ids.is_empty() {
+                continue;
+            }
+            let query = format!("SELECT * FROM {} WHERE id IN ({})", quote(&table), vec!["?"; ids.len()].join(", "));
+            let mut statement = sqlx::query(&query);
+            for id in &ids {
+                statement = statement.bind(*id);
+            }
+            for row in statement.fetch_all(&mut *tx).await? {
+                let doc = row_to_json(&row);
+                found.extend(doc.get("id").and_then(Value::as_i64).map(|id| (id, doc)));
+            }
+        }
+        let missing = ids.iter().copied().filter(|id| !found.contains_key(id)).collect();
+        let documents = ids.iter().filter_map(|id| found.remove(id)).collect();
+        results.insert(uri, MgetResponse { documents, missing });
+    }
+    tx.commit().await?;
+    Ok(results)
+}
@@ -0,0 +1,174 @@
+//! Read-only SQL for administrators.
+//!
+//! `POST /_sql` runs one `SELECT` statement against the database and
+//! answers its rows:
+//!
+//! ```json
+//! { "sql": "SELECT city, COUNT(*) AS n FROM orders WHERE status = ? GROUP BY city", "params": ["paid"] }
+//! ```
+//!
+//! The statement is read as a subquery and prepared before it runs: unless
+//! the whole text is one `SELECT`, `WITH` or `VALUES` statement that only
+//! reads, it gets 400 without running. Each request opens a connection of
+//! its own, read only, and closes it afterwards, so nothing a statement
+//! leaves on its connection outlives the request.
+//! Values are stored as JSON text, so parameters are bound the same way as
+//! by filters: `"paid"` matches a stored string and `3` a stored number.
+//! Column values are read back like documents.
+//!
+//! At most `sql.max_rows` rows are returned, and `truncated` is true when
+//! there were more. A statement still running after `sql.timeout_ms` is
+//! interrupted. Collection tables are the `__` names of their uris; sharded
+//! collections live in other files and are not seen.
+
+use actix_web::middleware::from_fn;
+use actix_web::{web, HttpResponse};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use sqlx::sqlite::{SqliteConnectOptions, SqliteConnection};
+use sqlx::{Column, Connection, Row};
+use std::ffi::{c_char, CStr, CString};
+use std::ptr;
+use std::time::Duration;
+
+use crate::auth;
+use crate::config::{ConfigHandle, Sql};
+use crate::database::{encode_value, row_to_json};
+use crate::telemetry::db_span;
+
+/// Where `POST /_sql` opens its read-only connections.
+#[derive(Clone)]
+pub struct ReadOnlyDatabase(SqliteConnectOptions);
+
+impl ReadOnlyDatabase {
+    /// Reads the database of `options`, which must already exist when the
+    /// first statement runs.
+    pub fn new(options: &SqliteConnectOptions) -> Self {
+        ReadOnlyDatabase(options.clone().read_only(true))
+    }
+
+    async fn connect(&self) -> Result<SqliteConnection, sqlx::Error> {
+        SqliteConnection::connect_with(&self.0).await
+    }
+}
+
+// 注册只读 SQL 接口, 仅限管理员
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(web::resource("/_sql").wrap(from_fn(auth::require_admin)).route(web::post().to(run_sql)));
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SqlRequest {
+    pub sql: String,
+    /// Values bound to the `?` placeholders in order.
+    #[serde(default)]
+    pub params: Vec<Value>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct SqlResponse {
+    /// Column names in the order of the statement.
+    pub columns: Vec<String>,
+    pub rows: Vec<Value>,
+    /// Whether rows beyond `sql.max_rows` were left out.
+    pub truncated: bool,
+}
+
+// 执行一条只读查询; 超时后中断连接上正在执行的语句
+async fn run_sql(request: web::Json<SqlRequest>, database: web::Data<ReadOnlyDatabase>, config: web::Data<ConfigHandle>) -> HttpResponse {
+    let SqlRequest { sql, params } = request.into_inner();
+    let limits = config.get().sql.clone();
+    let statement = sql.trim().trim_end_matches(';').trim_end();
+    if statement.is_empty() {
+        return HttpResponse::BadRequest().json("sql must hold a SELECT statement");
+    }
+    // 换行使语句末尾的 -- 注释不会注释掉外层的括号
+    let wrapped = format!("SELECT * FROM (\n{}\n) LIMIT {}", statement, limits.max_rows + 1);
+
+    let mut conn = match database.connect().await {
+        Ok(conn) => conn,
+        Err(e) => return HttpResponse::ServiceUnavailable().json(format!("Failed to open a read-only connection: {}", e)),
+    };
+    let response = run_on(&mut conn, statement, &wrapped, &params, &limits).await;
+    // 连接不复用, 语句留下的临时表或视图随之消失
+    if let Err(e) = conn.close().await {
+        log::warn!("failed to close read-only connection: {}", e);
+    }
+    response
+}
+
+async fn run_on(conn: &mut SqliteConnection, statement: &str, wrapped: &str, params: &[Value], limits: &Sql) -> HttpResponse {
+    let handle = match conn.lock_handle().await {
+        Ok(mut handle) => {
+            let raw = handle.as_raw_handle().as_ptr();
+            if let Err(e) = check_statement(raw, wrapped) {
+                return HttpResponse::BadRequest().json(e);
+            }
+            raw as usize
+        }
+        Err(e) => return HttpResponse::InternalServerError().json(format!("Failed to run query: {}", e)),
+    };
+    let mut span = db_span(statement, "");
+    let mut query = sqlx::query(wrapped).persistent(false);
+    for param in params {
+        query = query.bind(encode_value(param));
+    }
+    let rows = query.fetch_all(&mut *conn);
+    tokio::pin!(rows);
+    let mut timed_out = false;
+    let rows = tokio::select! {
+        rows = &mut rows => rows,
+        _ = tokio::time::sleep(Duration::from_millis(limits.timeout_ms)) => {
+            timed_out = true;
+            // SAFETY: 连接在语句结束前一直由 conn 持有; sqlite3_interrupt 可以从其他线程调用
+            unsafe { libsqlite3_sys::sqlite3_interrupt(handle as *mut libsqlite3_sys::sqlite3) };
+            rows.await
+        }
+    };
+    match rows {
+        Ok(mut rows) => {
+            let truncated = rows.len() > limits.max_rows;
+            rows.truncate(limits.max_rows);
+            let columns = rows.first().map(|row| row.columns().iter().map(|c| c.name().to_string()).collect()).unwrap_or_default();
+            span.set_i64("db.response.returned_rows", rows.len() as i64);
+            HttpResponse::Ok().json(SqlResponse { columns, rows: rows.iter().map(row_to_json).collect(), truncated })
+        }
+        Err(e) => {
+            span.error(&e);
+            match timed_out {
+                true => HttpResponse::RequestTimeout().json(format!("Query interrupted after {} ms", limits.timeout_ms)),
+                false => HttpResponse::BadRequest().json(format!("Failed to run query: {}", e)),
+            }
+        }
+    }
+}
+
+/// Prepares `sql` on the connection `db` without running it, to check that
+/// it is exactly one statement and that the statement only reads.
+fn check_statement(db: *mut libsqlite3_sys::sqlite3, sql: &str) -> Result<(), String> {
+    let text = CString::new(sql).map_err(|_| "sql cannot hold NUL characters".to_string())?;
+    let mut stmt = ptr::null_mut();
+    let mut tail: *const c_char = ptr::null();
+    // SAFETY: 调用方持有连接的锁; 准备出的语句在返回前释放, tail 指向 text 内部
+    unsafe {
+        let rc = libsqlite3_sys::sqlite3_prepare_v2(db, text.as_ptr(), -1, &mut stmt, &mut tail);
+        if rc != libsqlite3_sys::SQLITE_OK {
+            let message = CStr::from_ptr(libsqlite3_sys::sqlite3_errmsg(db)).to_string_lossy().into_owned();
+            libsqlite3_sys::sqlite3_finalize(stmt);
+            return Err(format!("Failed to run query: {}", message));
+        }
+        let readonly = libsqlite3_sys::sqlite3_stmt_readonly(stmt) != 0;
+        libsqlite3_sys::sqlite3_finalize(stmt);
+        let rest = match tail.is_null() {
+            true => String::new(),
+            false => CStr::from_ptr(tail).to_string_lossy().into_owned(),
+        };
+        if !rest.trim().is_empty() {
+            return Err("sql must hold a single statement".to_string());
+        }
+        if !readonly {
+            return Err("sql must only read".to_string());
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,354 @@
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use sha2::{Digest, Sha256};
+use std::fmt;
+
+use crate::odata;
+use crate::sessions::hex;
+
+/// Storage backend behind the HTTP handlers.
+///
+/// Handlers only talk to `web::Data<dyn DocumentStore>`, so a different
+/// backend (or a test double) can be swapped in without touching them.
+#[async_trait]
+pub trait DocumentStore: Send + Sync {
+    /// Stores `doc` under `uri` and returns the id of the new document.
+    async fn insert(&self, uri: &str, doc: &Value) -> Result<i64, StoreError>;
+
+    async fn list(&self, uri: &str) -> Result<Vec<Value>, StoreError>;
+
+    async fn get(&self, uri: &str, id: i64) -> Result<Option<Value>, StoreError>;
+
+    /// The documents among `ids` that exist, in the order of `ids`.
+    async fn get_many(&self, uri: &str, ids: &[i64]) -> Result<Vec<Value>, StoreError>;
+
+    /// Stores `doc` under `uri` unless a document with the same content
+    /// `hash` is already there; `mode` decides what happens to a duplicate.
+    async fn insert_unique(&self, uri: &str, doc: &Value, hash: &str, mode: DedupMode) -> Result<Inserted, StoreError>;
+
+    /// Writes the fields of `doc` into the document of `uri` whose field `key`
+    /// holds the same value, or inserts `doc` if there is none. A unique
+    /// index keeps `key` unique; a document the write would not change keeps
+    /// its version.
+    async fn upsert(&self, uri: &str, key: &str, doc: &Value) -> Result<Upserted, StoreError>;
+
+    /// Replaces document `id` with `doc`; fields missing from `doc` are cleared.
+    /// Returns the new version.
+    ///
+    /// With `expected` set, the write only happens while the document's
+    /// `_version` is one of them, otherwise it fails with [`StoreError::VersionConflict`].
+    async fn replace(&self, uri: &str, id: i64, doc: &Value, expected: Option<&[i64]>) -> Result<i64, StoreError>;
+
+    /// Sets the fields of `doc` on document `id`, leaving the others alone.
+    /// Returns the new version; `expected` works as for [`DocumentStore::replace`].
+    async fn update(&self, uri: &str, id: i64, doc: &Value, expected: Option<&[i64]>) -> Result<i64, StoreError>;
+
+    /// Deletes document `id`; `expected` works as for [`DocumentStore::replace`].
+    async fn delete(&self, uri: &str, id: i64, expected: Option<&[i64]>) -> Result<(), StoreError>;
+
+    /// Applies the update document `update` to every document of `uri`
+    /// matching the filter document `filter`, in one transaction. See
+    /// [`crate::query`] for both.
+    async fn update_many(&self, uri: &str, filter: &Value, update: &Value) -> Result<UpdatedMany, StoreError>;
+
+    /// Deletes every document of `uri` matching the filter document `filter`,
+    /// in one transaction, and returns their ids. With `dry_run` the
+    /// documents are only looked up.
+    async fn delete_many(&self, uri: &str, filter: &Value, dry_run: bool) -> Result<Vec<i64>, StoreError>;
+
+    /// The documents of `uri` matching the filter document `filter`; see
+    /// [`crate::query`].
+    async fn find(&self, uri: &str, filter: &Value) -> Result<Vec<Value>, StoreError>;
+
+    /// The number of documents of `uri` matching the filter document
+    /// `filter`, counted without reading them.
+    async fn count(&self, uri: &str, filter: &Value) -> Result<u64, StoreError>;
+
+    /// The page `page` of the documents of `uri` matching the filter
+    /// document `filter`, with the number of matching documents on all
+    /// pages. Sorting, paging and picking the fields happen in the query,
+    /// and sorting or returning a field the collection has no column for
+    /// fails with [`StoreError::Invalid`].
+    async fn find_page(&self, uri: &str, filter: &Value, page: &PageQuery) -> Result<Paged, StoreError>;
+
+    /// A tag of the current state of `uri` that every write to it changes,
+    /// cheaper to get than the documents themselves.
+    async fn version(&self, uri: &str) -> Result<String, StoreError>;
+
+    /// Catches up on `writes`, which an HTTP transaction (see
+    /// [`crate::transaction`]) made straight on the database and has just
+    /// committed: each layer does what it does after its own writes.
+    async fn committed(&self, writes: &[Written]);
+}
+
+/// Field holding a document's version, bumped by every write.
+pub const VERSION_FIELD: &str = "_version";
+
+/// Whether the table of collection `uri` would start with `_` or `sqlite_`,
+/// the prefixes of the server's and SQLite's own tables. Such collections
+/// cannot be read or written.
+pub fn reserved(uri: &str) -> bool {
+    let table = uri.replace('/', "__");
+    table.starts_with('_') || table.starts_with("sqlite_")
+}
+
+/// The fields of `doc`, which has to be a JSON object to be stored.
+pub fn document_fields(doc: &Value) -> Result<&Map<String, Value>, StoreError> {
+    doc.as_object().ok_or_else(|| StoreError::Invalid("Document must be a JSON object".to_string()))
+}
+
+/// Whether the collection path `uri` maps to a table name that maps back to
+/// it: segments of a path become `__`-separated parts of the name, so no
+/// segment may be empty or hold `__`, and none but the last may end in `_`.
+pub fn valid_path(uri: &str) -> bool {
+    let segments: Vec<&str> = uri.split('/').collect();
+    segments.iter().all(|s| !s.is_empty() && !s.contains("__"))
+        && segments[1..].iter().all(|s| !s.starts_with('_'))
+        && segments[..segments.len() - 1].iter().all(|s| !s.ends_with('_'))
+}
+
+/// Checks that the fields of `doc` can be columns of a table: SQLite
+/// compares column names ignoring ASCII case, so no two fields may differ
+/// only in case, nor may one differ from `id` or [`VERSION_FIELD`] only in
+/// case. Names may hold any other character but control characters.
+/// Documents that are not JSON objects have no fields and are rejected.
+pub fn check_fields(doc: &Value) -> Result<(), StoreError> {
+    let fields = document_fields(doc)?;
+    let names: Vec<&String> = fields.keys().collect();
+    for (i, name) in names.iter().enumerate() {
+        if name.is_empty() {
+            return Err(StoreError::Invalid("Field names cannot be empty".to_string()));
+        }
+        if name.chars().any(char::is_control) {
+            return Err(StoreError::Invalid(format!("Field name {:?} holds a control character", name)));
+        }
+        if let Some(own) = ["id", VERSION_FIELD].into_iter().find(|own| name.eq_ignore_ascii_case(own) && name != own) {
+            return Err(StoreError::Invalid(format!("Field '{}' differs from '{}' only in case", name, own)));
+        }
+        if let Some(other) = names[..i].iter().find(|other| other.eq_ignore_ascii_case(name)) {
+            return Err(StoreError::Invalid(format!("Fields '{}' and '{}' differ only in case", other, name)));
+        }
+    }
+    Ok(())
+}
+
+/// What an insert with a duplicate content hash does.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DedupMode {
+    /// Leave the existing document alone.
+    Skip,
+    /// Write the new field values into the existing document.
+    Upsert,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Inserted {
+    pub id: i64,
+    /// The hash matched an existing document, whose id is `id`.
+    pub duplicate: bool,
+}
+
+/// The outcome of [`DocumentStore::upsert`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Upserted {
+    pub id: i64,
+    /// A document with the key existed, whose id is `id`.
+    pub existed: bool,
+    /// The document was inserted, or some of its fields took new values.
+    pub changed: bool,
+}
+
+/// A write committed by an HTTP transaction, see [`DocumentStore::committed`].
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Written {
+    pub uri: String,
+    pub id: i64,
+    pub op: WriteOp,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum WriteOp {
+    Insert,
+    Replace,
+    Update,
+    Delete,
+}
+
+impl WriteOp {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            WriteOp::Insert => "insert",
+            WriteOp::Replace => "replace",
+            WriteOp::Update => "update",
+            WriteOp::Delete => "delete",
+        }
+    }
+}
+
+/// What [`DocumentStore::find_page`] reads of the matching documents.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PageQuery {
+    /// Fields to sort by, and whether descending; `id` breaks ties. Values
+    /// sort as in `$orderby`: `null` first, then booleans, numbers and
+    /// strings, with objects and arrays last.
+    pub sort: Vec<(String, bool)>,
+    /// Fields to return besides `id`, or `None` for whole documents.
+    pub fields: Option<Vec<String>>,
+    /// Matching documents to skip.
+    pub offset: u64,
+    /// Most documents to return, or `None` for all.
+    pub limit: Option<u64>,
+}
+
+impl PageQuery {
+    /// The fields a query has to read to sort the documents and return them.
+    pub fn read_fields(&self) -> Option<Vec<String>> {
+        let mut fields = self.fields.clone()?;
+        for (field, _) in &self.sort {
+            if !fields.contains(field) {
+                fields.push(field.clone());
+            }
+        }
+        Some(fields)
+    }
+
+    /// Sorts `docs`, cuts this page from them and keeps only its fields, for
+    /// stores that merge the pages of several tables.
+    pub fn apply(&self, docs: Vec<Value>) -> Vec<Value> {
+        let mut orderby = self.sort.clone();
+        orderby.push(("id".to_string(), false));
+        let options = odata::Options {
+            orderby,
+            skip: usize::try_from(self.offset).unwrap_or(usize::MAX),
+            top: self.limit.map(|limit| usize::try_from(limit).unwrap_or(usize::MAX)),
+            ..Default::default()
+        };
+        options.apply(docs).into_iter().map(|doc| self.project(doc)).collect()
+    }
+
+    /// `doc` with only its `id` and the fields asked for.
+    pub fn project(&self, doc: Value) -> Value {
+        match (doc, &self.fields) {
+            (Value::Object(map), Some(fields)) => {
+                Value::Object(map.into_iter().filter(|(key, _)| key == "id" || fields.contains(key)).collect())
+            }
+            (doc, _) => doc,
+        }
+    }
+
+    /// The query for the first `offset + limit` documents, which hold this
+    /// page whichever table of several they come from.
+    pub fn leading(&self) -> PageQuery {
+        PageQuery {
+            sort: self.sort.clone(),
+            fields: self.read_fields(),
+            offset: 0,
+            limit: self.limit.map(|limit| limit.saturating_add(self.offset)),
+        }
+    }
+}
+
+/// The outcome of [`DocumentStore::find_page`].
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Paged {
+    pub docs: Vec<Value>,
+    /// Documents matching the filter on all pages.
+    pub total: u64,
+}
+
+/// The outcome of [`DocumentStore::update_many`].
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct UpdatedMany {
+    /// Documents matching the filter.
+    pub matched: u64,
+    /// Ids of the matching documents the update changed.
+    pub modified: Vec<i64>,
+}
+
+/// SHA-256 of the canonical JSON of `doc`, or of just `fields` if given.
+///
+/// Object keys are sorted at every level, so key order does not matter.
+pub fn content_hash(doc: &Value, fields: &[String]) -> String {
+    let mut canonical = String::new();
+    if fields.is_empty() {
+        write_canonical(doc, &mut canonical);
+    } else {
+        for field in fields {
+            write_canonical(&Value::String(field.clone()), &mut canonical);
+            canonical.push(':');
+            write_canonical(doc.get(field).unwrap_or(&Value::Null), &mut canonical);
+            canonical.push(',');
+        }
+    }
+    hex(&Sha256::digest(canonical.as_bytes()))
+}
+
+fn write_canonical(value: &Value, out: &mut String) {
+    match value {
+        Value::Object(map) => {
+            let mut keys: Vec<&String> = map.keys().collect();
+            keys.sort();
+            out.push('{');
+            for (i, key) in keys.into_iter().enumerate() {
+                if i > 0 {
+                    out.push(',');
+                }
+                write_canonical(&Value::String(key.clone()), out);
+                out.push(':');
+                write_canonical(&map[key], out);
+            }
+            out.push('}');
+        }
+        Value::Array(items) => {
+            out.push('[');
+            for (i, item) in items.iter().enumerate() {
+                if i > 0 {
+                    out.push(',');
+                }
+                write_canonical(item, out);
+            }
+            out.push(']');
+        }
+        scalar => out.push_str(&scalar.to_string()),
+    }
+}
+
+#[derive(Debug)]
+pub enum StoreError {
+    /// Creating or altering the table for a document failed.
+    Schema(sqlx::Error),
+    Database(sqlx::Error),
+    /// The collection or document does not exist.
+    NotFound,
+    /// A conditional write found the document at another version.
+    VersionConflict { current: i64 },
+    /// The write has to go to another node, which cannot be reached.
+    Unavailable(String),
+    /// The request does not fit the collection, such as a filter on a field
+    /// it does not have.
+    Invalid(String),
+}
+
+impl fmt::Display for StoreError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StoreError::Schema(e) => write!(f, "{}", e),
+            StoreError::Database(e) => write!(f, "{}", e),
+            StoreError::NotFound => write!(f, "not found"),
+            StoreError::VersionConflict { current } => write!(f, "document is at version {}", current),
+            StoreError::Unavailable(reason) => write!(f, "{}", reason),
+            StoreError::Invalid(reason) => write!(f, "{}", reason),
+        }
+    }
+}
+
+impl std::error::Error for StoreError {}
+
+impl From<sqlx::Error> for StoreError {
+    fn from(e: sqlx::Error) -> Self {
+        StoreError::Database(e)
+    }
+}
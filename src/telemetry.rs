@@ -0,0 +1,244 @@
+//! Request and storage tracing.
+//!
+//! With the `otel` feature, [`init`] installs an OTLP/HTTP span exporter
+//! configured by the standard `OTEL_*` environment variables
+//! (`OTEL_EXPORTER_OTLP_ENDPOINT`, `OTEL_SERVICE_NAME`, `OTEL_RESOURCE_ATTRIBUTES`,
+//! `OTEL_SDK_DISABLED`, ...). Without the feature every [`Span`] is a no-op, so
+//! call sites don't need their own `cfg`.
+
+use actix_web::body::MessageBody;
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::middleware::Next;
+#[cfg(feature = "otel")]
+use actix_web::web;
+use actix_web::Error;
+use std::future::Future;
+
+#[cfg(feature = "otel")]
+use opentelemetry::global::{self, BoxedSpan};
+#[cfg(feature = "otel")]
+use opentelemetry::trace::{FutureExt, SamplingDecision, SamplingResult, Span as _, SpanKind, Status, TraceContextExt, Tracer};
+#[cfg(feature = "otel")]
+use opentelemetry::{Context, KeyValue};
+#[cfg(feature = "otel")]
+use std::time::SystemTime;
+
+#[cfg(feature = "otel")]
+use crate::config::ConfigHandle;
+#[cfg(feature = "otel")]
+use crate::sampling;
+
+#[cfg(feature = "otel")]
+const TRACER: &str = "json_storage";
+
+/// Keeps the exporter alive; call [`Telemetry::shutdown`] before exiting to flush pending spans.
+pub struct Telemetry {
+    #[cfg(feature = "otel")]
+    provider: Option<opentelemetry_sdk::trace::SdkTracerProvider>,
+}
+
+impl Telemetry {
+    pub fn shutdown(self) {
+        #[cfg(feature = "otel")]
+        if let Some(provider) = self.provider {
+            if let Err(e) = provider.shutdown() {
+                log::warn!("failed to flush spans: {}", e);
+            }
+        }
+    }
+}
+
+// 初始化 OTLP 导出; 未启用 otel 特性时什么也不做
+#[cfg(feature = "otel")]
+pub fn init() -> Telemetry {
+    use opentelemetry_otlp::SpanExporter;
+    use opentelemetry_sdk::propagation::TraceContextPropagator;
+    use opentelemetry_sdk::trace::SdkTracerProvider;
+    use opentelemetry_sdk::Resource;
+
+    if std::env::var("OTEL_SDK_DISABLED").is_ok_and(|v| v.eq_ignore_ascii_case("true")) {
+        return Telemetry { provider: None };
+    }
+
+    let exporter = match SpanExporter::builder().with_http().build() {
+        Ok(exporter) => exporter,
+        Err(e) => {
+            log::error!("failed to create OTLP exporter, tracing disabled: {}", e);
+            return Telemetry { provider: None };
+        }
+    };
+
+    let mut resource = Resource::builder();
+    if std::env::var_os("OTEL_SERVICE_NAME").is_none() {
+        resource = resource.with_service_name(TRACER);
+    }
+    let provider = SdkTracerProvider::builder()
+        .with_batch_exporter(exporter)
+        .with_resource(resource.build())
+        .build();
+
+    global::set_text_map_propagator(TraceContextPropagator::new());
+    global::set_tracer_provider(provider.clone());
+    log::info!("exporting traces over OTLP");
+    Telemetry { provider: Some(provider) }
+}
+
+#[cfg(not(feature = "otel"))]
+pub fn init() -> Telemetry {
+    Telemetry {}
+}
+
+/// An in-progress span; ended when dropped.
+pub struct Span {
+    #[cfg(feature = "otel")]
+    inner: BoxedSpan,
+}
+
+impl Span {
+    pub fn set_str(&mut self, key: &'static str, value: impl Into<String>) {
+        #[cfg(feature = "otel")]
+        self.inner.set_attribute(KeyValue::new(key, value.into()));
+        #[cfg(not(feature = "otel"))]
+        let _ = (key, value);
+    }
+
+    pub fn set_i64(&mut self, key: &'static str, value: i64) {
+        #[cfg(feature = "otel")]
+        self.inner.set_attribute(KeyValue::new(key, value));
+        #[cfg(not(feature = "otel"))]
+        let _ = (key, value);
+    }
+
+    /// Marks the span as failed.
+    pub fn error(&mut self, error: &dyn std::fmt::Display) {
+        #[cfg(feature = "otel")]
+        self.inner.set_status(Status::error(error.to_string()));
+        #[cfg(not(feature = "otel"))]
+        let _ = error;
+    }
+}
+
+/// Starts a span for a SQL statement against `table`, tagged with the statement kind
+/// (`SELECT`, `INSERT`, `CREATE`, ...). The caller records row counts on it.
+pub fn db_span(sql: &str, table: &str) -> Span {
+    let kind = statement_kind(sql);
+
+    #[cfg(feature = "otel")]
+    let mut span = {
+        let tracer = global::tracer(TRACER);
+        Span {
+            inner: tracer
+                .span_builder(format!("{} {}", kind, table))
+                .with_kind(SpanKind::Client)
+                .start(&tracer),
+        }
+    };
+    #[cfg(not(feature = "otel"))]
+    let mut span = Span {};
+
+    span.set_str("db.system.name", "sqlite");
+    span.set_str("db.operation.name", kind);
+    span.set_str("db.collection.name", table);
+    span
+}
+
+/// `future` under the trace of the caller, for work handed over to another
+/// task, so the storage spans it starts still belong to the request.
+#[cfg(feature = "otel")]
+pub fn in_current_trace<F: Future>(future: F) -> impl Future<Output = F::Output> {
+    future.with_context(Context::current())
+}
+
+#[cfg(not(feature = "otel"))]
+pub fn in_current_trace<F: Future>(future: F) -> F {
+    future
+}
+
+// SQL 语句的第一个关键字, 例如 SELECT / INSERT
+pub fn statement_kind(sql: &str) -> &str {
+    sql.split_whitespace().next().unwrap_or("")
+}
+
+/// Wraps each request in a server span, continuing a trace from an incoming
+/// `traceparent` header. Storage spans started while handling the request
+/// become its children. Requests left out by [`sampling`](crate::sampling)
+/// run under a span that is not recorded, unless the incoming trace is
+/// sampled; if they fail or are slow their server span is recorded after all.
+#[cfg(feature = "otel")]
+pub async fn trace(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<impl MessageBody>, Error> {
+    let settings = req.app_data::<web::Data<ConfigHandle>>().map(|c| c.get().sampling.clone()).unwrap_or_default();
+    let parent = global::get_text_map_propagator(|p| p.extract(&HeaderExtractor(req.headers())));
+    let method = req.method().to_string();
+    let attributes = [
+        KeyValue::new("http.request.method", method.clone()),
+        KeyValue::new("url.path", req.path().to_string()),
+    ];
+    let sampled = parent.span().span_context().is_sampled() || sampling::drawn(&req, &settings);
+    let tracer = global::tracer(TRACER);
+    let mut builder = tracer.span_builder(method.clone()).with_kind(SpanKind::Server).with_attributes(attributes.clone());
+    if !sampled {
+        builder = builder.with_sampling_result(SamplingResult {
+            decision: SamplingDecision::Drop,
+            attributes: Vec::new(),
+            trace_state: parent.span().span_context().trace_state().clone(),
+        });
+    }
+    let started = SystemTime::now();
+    let cx = Context::current_with_span(builder.start_with_context(&tracer, &parent));
+
+    let result = next.call(req).with_context(cx.clone()).await;
+
+    // 未抽中的请求出错或较慢时, 补记一个没有子 span 的服务端 span
+    let cx = match &result {
+        _ if sampled => cx,
+        Ok(res) if !sampling::keep(res.request(), &settings, res.status().as_u16(), started.elapsed().unwrap_or_default()) => {
+            return result;
+        }
+        _ => {
+            let builder = tracer.span_builder(method.clone()).with_kind(SpanKind::Server).with_attributes(attributes);
+            Context::current_with_span(builder.with_start_time(started).start_with_context(&tracer, &parent))
+        }
+    };
+    let span = cx.span();
+    match &result {
+        Ok(res) => {
+            if let Some(route) = res.request().match_pattern() {
+                span.update_name(format!("{} {}", method, route));
+                span.set_attribute(KeyValue::new("http.route", route));
+            }
+            let status = res.status();
+            span.set_attribute(KeyValue::new("http.response.status_code", status.as_u16() as i64));
+            if status.is_server_error() {
+                span.set_status(Status::error(status.to_string()));
+            }
+        }
+        Err(e) => span.set_status(Status::error(e.to_string())),
+    }
+    span.end();
+    result
+}
+
+#[cfg(not(feature = "otel"))]
+pub async fn trace(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<impl MessageBody>, Error> {
+    next.call(req).await
+}
+
+#[cfg(feature = "otel")]
+struct HeaderExtractor<'a>(&'a actix_web::http::header::HeaderMap);
+
+#[cfg(feature = "otel")]
+impl opentelemetry::propagation::Extractor for HeaderExtractor<'_> {
+    fn get(&self, key: &str) -> Option<&str> {
+        self.0.get(key).and_then(|v| v.to_str().ok())
+    }
+
+    fn keys(&self) -> Vec<&str> {
+        self.0.keys().map(|k| k.as_str()).collect()
+    }
+}
@@ -0,0 +1,414 @@
+//! Test support for downstream integration tests.
+//!
+//! Enabled with the `test-support` feature, and in this crate's own unit
+//! tests. [`TestStore`] runs against a private in-memory SQLite database, so
+//! every test gets a clean store that disappears when it is dropped.
+//! [`MockStore`] replaces SQLite entirely for handler tests that only care
+//! about status codes and error mapping.
+
+use actix_web::web;
+use async_trait::async_trait;
+use serde_json::Value;
+use sqlx::SqlitePool;
+use std::collections::{BTreeMap, VecDeque};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+use crate::database::{count_rows, create_system_tables, pool_options, table_columns, table_name, SqliteStore};
+use crate::store::{DedupMode, DocumentStore, Inserted, PageQuery, Paged, StoreError, UpdatedMany, Upserted, Written};
+
+pub struct TestStore {
+    pool: SqlitePool,
+    store: Arc<SqliteStore>,
+}
+
+impl TestStore {
+    /// Opens a fresh in-memory store with the initial tables created.
+    pub async fn new() -> Self {
+        // An in-memory database lives as long as its connection, so the pool
+        // is pinned to a single connection that is never recycled.
+        let pool = pool_options()
+            .max_connections(1)
+            .idle_timeout(None)
+            .max_lifetime(None)
+            .connect("sqlite::memory:")
+            .await
+            .expect("Failed to open in-memory database");
+
+        create_system_tables(&pool)
+            .await
+            .expect("Failed to create initial tables");
+
+        let store = Arc::new(SqliteStore::new(pool.clone()));
+        Self { pool, store }
+    }
+
+    pub fn pool(&self) -> &SqlitePool {
+        &self.pool
+    }
+
+    /// The store as handler app data, e.g. `App::new().app_data(test_store.data())`.
+    pub fn data(&self) -> web::Data<dyn DocumentStore> {
+        web::Data::from(self.store.clone() as Arc<dyn DocumentStore>)
+    }
+
+    /// Stores each document under `uri` through the same path as the HTTP insert handler.
+    pub async fn load_fixtures(&self, uri: &str, docs: &[Value]) -> Result<(), StoreError> {
+        for doc in docs {
+            self.store.insert(uri, doc).await?;
+        }
+        Ok(())
+    }
+
+    /// Loads a fixture file of the form `{"<uri>": [doc, ...], ...}`.
+    pub async fn load_fixture_file(&self, path: impl AsRef<Path>) -> Result<(), Box<dyn std::error::Error>> {
+        let content = std::fs::read_to_string(path)?;
+        let fixtures: BTreeMap<String, Vec<Value>> = serde_json::from_str(&content)?;
+        for (uri, docs) in &fixtures {
+            self.load_fixtures(uri, docs).await?;
+        }
+        Ok(())
+    }
+
+    /// Returns `(column name, declared type)` pairs for the table behind `uri`.
+    pub async fn columns(&self, uri: &str) -> Vec<(String, String)> {
+        table_columns(&self.pool, &table_name(uri))
+            .await
+            .expect("Failed to read table info")
+    }
+
+    pub async fn row_count(&self, uri: &str) -> i64 {
+        count_rows(&self.pool, &table_name(uri))
+            .await
+            .expect("Failed to count rows")
+    }
+
+    /// Asserts that the table behind `uri` has exactly the given columns, in any order.
+    pub async fn assert_columns(&self, uri: &str, expected: &[&str]) {
+        let mut actual: Vec<String> = self.columns(uri).await.into_iter().map(|(name, _)| name).collect();
+        let mut expected: Vec<String> = expected.iter().map(|c| c.to_string()).collect();
+        actual.sort();
+        expected.sort();
+        assert_eq!(actual, expected, "unexpected columns for '{}'", uri);
+    }
+
+    /// Asserts that `column` exists on the table behind `uri` with the given declared type.
+    pub async fn assert_column_type(&self, uri: &str, column: &str, expected: &str) {
+        let columns = self.columns(uri).await;
+        match columns.iter().find(|(name, _)| name == column) {
+            Some((_, ty)) => assert!(
+                ty.eq_ignore_ascii_case(expected),
+                "column '{}' of '{}' has type {}, expected {}",
+                column, uri, ty, expected
+            ),
+            None => panic!("column '{}' not found on '{}'", column, uri),
+        }
+    }
+}
+
+/// A call received by [`MockStore`].
+#[derive(Debug, Clone, PartialEq)]
+pub enum Call {
+    Insert { uri: String, doc: Value },
+    InsertUnique { uri: String, doc: Value, hash: String, mode: DedupMode },
+    Upsert { uri: String, key: String, doc: Value },
+    List { uri: String },
+    Get { uri: String, id: i64 },
+    GetMany { uri: String, ids: Vec<i64> },
+    Replace { uri: String, id: i64, doc: Value, expected: Option<Vec<i64>> },
+    Update { uri: String, id: i64, doc: Value, expected: Option<Vec<i64>> },
+    Delete { uri: String, id: i64, expected: Option<Vec<i64>> },
+    UpdateMany { uri: String, filter: Value, update: Value },
+    DeleteMany { uri: String, filter: Value, dry_run: bool },
+    Find { uri: String, filter: Value },
+    Count { uri: String, filter: Value },
+    FindPage { uri: String, filter: Value, page: PageQuery },
+    Version { uri: String },
+    Committed { writes: Vec<Written> },
+}
+
+/// Scripted [`DocumentStore`] that records every call.
+///
+/// Replies queued with `on_*` are returned in order; once a queue is empty the
+/// mock falls back to an empty success (`insert` returns ids counting from 1,
+/// `insert_unique` and `upsert` the same ids and never find an existing
+/// document, `replace` and `update` return version 2, `update_many`,
+/// `delete_many`, `find`, `count` and `find_page` match nothing, and
+/// `version` is `"0"`).
+#[derive(Default)]
+pub struct MockStore {
+    calls: Mutex<Vec<Call>>,
+    inserts: Mutex<VecDeque<Result<i64, StoreError>>>,
+    unique_inserts: Mutex<VecDeque<Result<Inserted, StoreError>>>,
+    upserts: Mutex<VecDeque<Result<Upserted, StoreError>>>,
+    lists: Mutex<VecDeque<Result<Vec<Value>, StoreError>>>,
+    gets: Mutex<VecDeque<Result<Option<Value>, StoreError>>>,
+    get_manys: Mutex<VecDeque<Result<Vec<Value>, StoreError>>>,
+    replaces: Mutex<VecDeque<Result<i64, StoreError>>>,
+    updates: Mutex<VecDeque<Result<i64, StoreError>>>,
+    deletes: Mutex<VecDeque<Result<(), StoreError>>>,
+    update_manys: Mutex<VecDeque<Result<UpdatedMany, StoreError>>>,
+    delete_manys: Mutex<VecDeque<Result<Vec<i64>, StoreError>>>,
+    finds: Mutex<VecDeque<Result<Vec<Value>, StoreError>>>,
+    counts: Mutex<VecDeque<Result<u64, StoreError>>>,
+    pages: Mutex<VecDeque<Result<Paged, StoreError>>>,
+    versions: Mutex<VecDeque<Result<String, StoreError>>>,
+}
+
+impl MockStore {
+    pub fn new() -> Arc<Self> {
+        Arc::new(Self::default())
+    }
+
+    pub fn on_insert(&self, reply: Result<i64, StoreError>) -> &Self {
+        self.inserts.lock().unwrap().push_back(reply);
+        self
+    }
+
+    pub fn on_insert_unique(&self, reply: Result<Inserted, StoreError>) -> &Self {
+        self.unique_inserts.lock().unwrap().push_back(reply);
+        self
+    }
+
+    pub fn on_upsert(&self, reply: Result<Upserted, StoreError>) -> &Self {
+        self.upserts.lock().unwrap().push_back(reply);
+        self
+    }
+
+    pub fn on_list(&self, reply: Result<Vec<Value>, StoreError>) -> &Self {
+        self.lists.lock().unwrap().push_back(reply);
+        self
+    }
+
+    pub fn on_get(&self, reply: Result<Option<Value>, StoreError>) -> &Self {
+        self.gets.lock().unwrap().push_back(reply);
+        self
+    }
+
+    pub fn on_get_many(&self, reply: Result<Vec<Value>, StoreError>) -> &Self {
+        self.get_manys.lock().unwrap().push_back(reply);
+        self
+    }
+
+    pub fn on_replace(&self, reply: Result<i64, StoreError>) -> &Self {
+        self.replaces.lock().unwrap().push_back(reply);
+        self
+    }
+
+    pub fn on_update(&self, reply: Result<i64, StoreError>) -> &Self {
+        self.updates.lock().unwrap().push_back(reply);
+        self
+    }
+
+    pub fn on_delete(&self, reply: Result<(), StoreError>) -> &Self {
+        self.deletes.lock().unwrap().push_back(reply);
+        self
+    }
+
+    pub fn on_update_many(&self, reply: Result<UpdatedMany, StoreError>) -> &Self {
+        self.update_manys.lock().unwrap().push_back(reply);
+        self
+    }
+
+    pub fn on_delete_many(&self, reply: Result<Vec<i64>, StoreError>) -> &Self {
+        self.delete_manys.lock().unwrap().push_back(reply);
+        self
+    }
+
+    pub fn on_find(&self, reply: Result<Vec<Value>, StoreError>) -> &Self {
+        self.finds.lock().unwrap().push_back(reply);
+        self
+    }
+
+    pub fn on_count(&self, reply: Result<u64, StoreError>) -> &Self {
+        self.counts.lock().unwrap().push_back(reply);
+        self
+    }
+
+    pub fn on_find_page(&self, reply: Result<Paged, StoreError>) -> &Self {
+        self.pages.lock().unwrap().push_back(reply);
+        self
+    }
+
+    pub fn on_version(&self, reply: Result<String, StoreError>) -> &Self {
+        self.versions.lock().unwrap().push_back(reply);
+        self
+    }
+
+    /// Every call received so far, oldest first.
+    pub fn calls(&self) -> Vec<Call> {
+        self.calls.lock().unwrap().clone()
+    }
+
+    /// The mock as handler app data; keep the `Arc` around to inspect calls.
+    pub fn data(self: &Arc<Self>) -> web::Data<dyn DocumentStore> {
+        web::Data::from(self.clone() as Arc<dyn DocumentStore>)
+    }
+
+    fn record(&self, call: Call) {
+        self.calls.lock().unwrap().push(call);
+    }
+}
+
+#[async_trait]
+impl DocumentStore for MockStore {
+    async fn insert(&self, uri: &str, doc: &Value) -> Result<i64, StoreError> {
+        self.record(Call::Insert { uri: uri.to_string(), doc: doc.clone() });
+        let inserted = self
+            .calls()
+            .iter()
+            .filter(|c| matches!(c, Call::Insert { .. }))
+            .count() as i64;
+        self.inserts.lock().unwrap().pop_front().unwrap_or(Ok(inserted))
+    }
+
+    async fn insert_unique(&self, uri: &str, doc: &Value, hash: &str, mode: DedupMode) -> Result<Inserted, StoreError> {
+        self.record(Call::InsertUnique { uri: uri.to_string(), doc: doc.clone(), hash: hash.to_string(), mode });
+        let inserted = self
+            .calls()
+            .iter()
+            .filter(|c| matches!(c, Call::Insert { .. } | Call::InsertUnique { .. }))
+            .count() as i64;
+        let fallback = Ok(Inserted { id: inserted, duplicate: false });
+        self.unique_inserts.lock().unwrap().pop_front().unwrap_or(fallback)
+    }
+
+    async fn upsert(&self, uri: &str, key: &str, doc: &Value) -> Result<Upserted, StoreError> {
+        self.record(Call::Upsert { uri: uri.to_string(), key: key.to_string(), doc: doc.clone() });
+        let inserted = self
+            .calls()
+            .iter()
+            .filter(|c| matches!(c, Call::Insert { .. } | Call::InsertUnique { .. } | Call::Upsert { .. }))
+            .count() as i64;
+        let fallback = Ok(Upserted { id: inserted, existed: false, changed: true });
+        self.upserts.lock().unwrap().pop_front().unwrap_or(fallback)
+    }
+
+    async fn list(&self, uri: &str) -> Result<Vec<Value>, StoreError> {
+        self.record(Call::List { uri: uri.to_string() });
+        self.lists.lock().unwrap().pop_front().unwrap_or(Ok(Vec::new()))
+    }
+
+    async fn get(&self, uri: &str, id: i64) -> Result<Option<Value>, StoreError> {
+        self.record(Call::Get { uri: uri.to_string(), id });
+        self.gets.lock().unwrap().pop_front().unwrap_or(Ok(None))
+    }
+
+    async fn get_many(&self, uri: &str, ids: &[i64]) -> Result<Vec<Value>, StoreError> {
+        self.record(Call::GetMany { uri: uri.to_string(), ids: ids.to_vec() });
+        self.get_manys.lock().unwrap().pop_front().unwrap_or(Ok(Vec::new()))
+    }
+
+    async fn replace(&self, uri: &str, id: i64, doc: &Value, expected: Option<&[i64]>) -> Result<i64, StoreError> {
+        self.record(Call::Replace { uri: uri.to_string(), id, doc: doc.clone(), expected: expected.map(<[i64]>::to_vec) });
+        self.replaces.lock().unwrap().pop_front().unwrap_or(Ok(2))
+    }
+
+    async fn update(&self, uri: &str, id: i64, doc: &Value, expected: Option<&[i64]>) -> Result<i64, StoreError> {
+        self.record(Call::Update { uri: uri.to_string(), id, doc: doc.clone(), expected: expected.map(<[i64]>::to_vec) });
+        self.updates.lock().unwrap().pop_front().unwrap_or(Ok(2))
+    }
+
+    async fn delete(&self, uri: &str, id: i64, expected: Option<&[i64]>) -> Result<(), StoreError> {
+        self.record(Call::Delete { uri: uri.to_string(), id, expected: expected.map(<[i64]>::to_vec) });
+        self.deletes.lock().unwrap().pop_front().unwrap_or(Ok(()))
+    }
+
+    async fn update_many(&self, uri: &str, filter: &Value, update: &Value) -> Result<UpdatedMany, StoreError> {
+        self.record(Call::UpdateMany { uri: uri.to_string(), filter: filter.clone(), update: update.clone() });
+        self.update_manys.lock().unwrap().pop_front().unwrap_or(Ok(UpdatedMany::default()))
+    }
+
+    async fn delete_many(&self, uri: &str, filter: &Value, dry_run: bool) -> Result<Vec<i64>, StoreError> {
+        self.record(Call::DeleteMany { uri: uri.to_string(), filter: filter.clone(), dry_run });
+        self.delete_manys.lock().unwrap().pop_front().unwrap_or(Ok(Vec::new()))
+    }
+
+    async fn find(&self, uri: &str, filter: &Value) -> Result<Vec<Value>, StoreError> {
+        self.record(Call::Find { uri: uri.to_string(), filter: filter.clone() });
+        self.finds.lock().unwrap().pop_front().unwrap_or(Ok(Vec::new()))
+    }
+
+    async fn count(&self, uri: &str, filter: &Value) -> Result<u64, StoreError> {
+        self.record(Call::Count { uri: uri.to_string(), filter: filter.clone() });
+        self.counts.lock().unwrap().pop_front().unwrap_or(Ok(0))
+    }
+
+    async fn find_page(&self, uri: &str, filter: &Value, page: &PageQuery) -> Result<Paged, StoreError> {
+        self.record(Call::FindPage { uri: uri.to_string(), filter: filter.clone(), page: page.clone() });
+        self.pages.lock().unwrap().pop_front().unwrap_or(Ok(Paged::default()))
+    }
+
+    async fn version(&self, uri: &str) -> Result<String, StoreError> {
+        self.record(Call::Version { uri: uri.to_string() });
+        self.versions.lock().unwrap().pop_front().unwrap_or_else(|| Ok("0".to_string()))
+    }
+
+    async fn committed(&self, writes: &[Written]) {
+        self.record(Call::Committed { writes: writes.to_vec() });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::handlers;
+    use actix_web::http::StatusCode;
+    use actix_web::{test, App};
+    use serde_json::json;
+
+    #[tokio::test]
+    async fn fixtures_create_typed_columns() {
+        let store = TestStore::new().await;
+        let docs = [json!({ "name": "a", "age": 3, "score": 1.5, "vip": true }), json!({ "name": "b", "tags": ["x"] })];
+        store.load_fixtures("api/v1/users", &docs).await.unwrap();
+        assert_eq!(store.row_count("api/v1/users").await, 2);
+        store.assert_columns("api/v1/users", &["id", "_version", "name", "age", "score", "vip", "tags"]).await;
+        store.assert_column_type("api/v1/users", "age", "integer").await;
+        store.assert_column_type("api/v1/users", "score", "REAL").await;
+        store.assert_column_type("api/v1/users", "vip", "BOOLEAN").await;
+        store.assert_column_type("api/v1/users", "tags", "TEXT").await;
+    }
+
+    #[tokio::test]
+    async fn every_store_starts_empty() {
+        let first = TestStore::new().await;
+        first.load_fixtures("users", &[json!({ "name": "a" })]).await.unwrap();
+        let second = TestStore::new().await;
+        assert!(second.columns("users").await.is_empty());
+        assert_eq!(first.row_count("users").await, 1);
+    }
+
+    #[tokio::test]
+    async fn fixture_files_load_every_collection() {
+        let path = std::env::temp_dir().join(format!("json_storage_fixtures_{}.json", std::process::id()));
+        std::fs::write(&path, r#"{"users": [{"name": "a"}, {"name": "b"}], "orders": [{"total": 5}]}"#).unwrap();
+        let store = TestStore::new().await;
+        let loaded = store.load_fixture_file(&path).await;
+        std::fs::remove_file(&path).unwrap();
+        loaded.unwrap();
+        assert_eq!(store.row_count("users").await, 2);
+        assert_eq!(store.row_count("orders").await, 1);
+        assert!(store.load_fixture_file(path).await.is_err());
+    }
+
+    #[actix_web::test]
+    async fn handlers_serve_the_store() {
+        let store = TestStore::new().await;
+        store.load_fixtures("users", &[json!({ "name": "a" })]).await.unwrap();
+        let app = test::init_service(App::new().app_data(store.data()).configure(handlers::configure)).await;
+
+        let req = test::TestRequest::post().uri("/users").set_json(json!({ "uri": "users", "data": { "name": "b", "age": 4 } }));
+        assert_eq!(test::call_service(&app, req.to_request()).await.status(), StatusCode::OK);
+        let response = test::call_service(&app, test::TestRequest::get().uri("/users/2").to_request()).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let doc: Value = test::read_body_json(response).await;
+        assert_eq!((doc["name"].clone(), doc["age"].clone()), (json!("b"), json!(4)));
+        store.assert_column_type("users", "age", "INTEGER").await;
+
+        let response = test::call_service(&app, test::TestRequest::get().uri("/users/9").to_request()).await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        let response = test::call_service(&app, test::TestRequest::get().uri("/orders/1").to_request()).await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+}
@@ -0,0 +1,69 @@
+//! HTTPS listener with client certificate verification (`tls` feature).
+
+use actix_tls::accept::openssl::TlsStream;
+use actix_web::dev::Extensions;
+use actix_web::rt::net::TcpStream;
+use openssl::nid::Nid;
+use openssl::ssl::{SslAcceptor, SslAcceptorBuilder, SslFiletype, SslMethod, SslVerifyMode};
+use openssl::x509::{X509NameRef, X509};
+use std::any::Any;
+use std::io;
+
+use crate::auth::ClientCertificate;
+use crate::config::Tls;
+
+/// Builds the acceptor for `HttpServer::bind_openssl`. Client certificates are
+/// requested when `client_ca` is set and required with `require_client_cert`.
+pub fn acceptor(settings: &Tls) -> io::Result<SslAcceptorBuilder> {
+    let mut builder = SslAcceptor::mozilla_intermediate_v5(SslMethod::tls()).map_err(io::Error::other)?;
+    builder
+        .set_certificate_chain_file(&settings.cert)
+        .map_err(io::Error::other)?;
+    builder
+        .set_private_key_file(&settings.key, SslFiletype::PEM)
+        .map_err(io::Error::other)?;
+
+    if let Some(ca) = &settings.client_ca {
+        builder.set_ca_file(ca).map_err(io::Error::other)?;
+        let mut mode = SslVerifyMode::PEER;
+        if settings.require_client_cert {
+            mode |= SslVerifyMode::FAIL_IF_NO_PEER_CERT;
+        }
+        builder.set_verify(mode);
+    }
+    Ok(builder)
+}
+
+/// `HttpServer::on_connect` hook: stores the verified peer certificate, if any,
+/// as [`ClientCertificate`] connection data for the auth middleware.
+pub fn on_connect(conn: &dyn Any, data: &mut Extensions) {
+    let Some(stream) = conn.downcast_ref::<TlsStream<TcpStream>>() else {
+        return;
+    };
+    if let Some(cert) = stream.ssl().peer_certificate() {
+        data.insert(client_certificate(&cert));
+    }
+}
+
+fn client_certificate(cert: &X509) -> ClientCertificate {
+    let name = cert.subject_name();
+    ClientCertificate {
+        subject: subject(name),
+        common_name: name
+            .entries_by_nid(Nid::COMMONNAME)
+            .next()
+            .and_then(|e| e.data().to_string().ok()),
+    }
+}
+
+// 主题格式化为 CN=...,O=...
+fn subject(name: &X509NameRef) -> String {
+    name.entries()
+        .filter_map(|entry| {
+            let key = entry.object().nid().short_name().ok()?;
+            let value = entry.data().to_string().ok()?;
+            Some(format!("{}={}", key, value))
+        })
+        .collect::<Vec<_>>()
+        .join(",")
+}
@@ -0,0 +1,663 @@
+//! Transactions over HTTP.
+//!
+//! `POST /_txn` opens a transaction on a connection taken from the pool, and
+//! the connection stays with it until `POST /_txn/{id}/commit` or `POST
+//! /_txn/{id}/rollback`. In between, every `POST /_txn/{id}` runs one write
+//! on that connection, so writes to several collections land together or not
+//! at all. A transaction without a request for `transactions.timeout_secs`
+//! is rolled back.
+//!
+//! The writes go straight to SQLite; the layers of the store only hear of
+//! them once they are committed, see [`DocumentStore::committed`]. Sharded,
+//! partitioned, nested and deduplicated collections cannot be written this
+//! way, and cluster nodes open no transactions. SQLite has one writer at a
+//! time, so from its first write until it ends a transaction holds up every
+//! other write.
+
+use actix_web::{web, HttpMessage, HttpRequest, HttpResponse};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use sqlx::sqlite::SqliteConnection;
+use sqlx::{Row, Sqlite, SqlitePool, Transaction};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::auth::Principal;
+use crate::config::ConfigHandle;
+use crate::database::{
+    bind_value, create_table_statement, field_assignments, field_types, rebuild_table, schema_changed, table_name, version_condition, widenings,
+};
+use crate::partition::Partitions;
+use crate::query::quote;
+use crate::sessions::random_token;
+use crate::shard::Shards;
+use crate::store::{check_fields, DocumentStore, StoreError, WriteOp, Written, VERSION_FIELD};
+
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.route("/_txn", web::post().to(begin))
+        .route("/_txn/{id}", web::post().to(write))
+        .route("/_txn/{id}/commit", web::post().to(commit))
+        .route("/_txn/{id}/rollback", web::post().to(rollback));
+}
+
+struct Open {
+    /// `None` once the transaction has ended.
+    tx: Option<Transaction<'static, Sqlite>>,
+    owner: Option<String>,
+    expires: Instant,
+    writes: Vec<Written>,
+    /// Tables were created or altered in the transaction.
+    schema: bool,
+}
+
+/// The open transactions, shared across workers; create one in `main` and
+/// register it as app data.
+#[derive(Default)]
+pub struct Transactions {
+    open: Mutex<HashMap<String, Arc<tokio::sync::Mutex<Open>>>>,
+}
+
+impl Transactions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn get(&self, id: &str) -> Option<Arc<tokio::sync::Mutex<Open>>> {
+        self.open.lock().unwrap().get(id).cloned()
+    }
+
+    fn remove(&self, id: &str) -> Option<Arc<tokio::sync::Mutex<Open>>> {
+        self.open.lock().unwrap().remove(id)
+    }
+
+    // 回滚超时的事务; 正在处理请求的事务下一轮再看
+    async fn expire(&self) {
+        let now = Instant::now();
+        let expired: Vec<(String, Arc<tokio::sync::Mutex<Open>>)> = {
+            let mut open = self.open.lock().unwrap();
+            let ids: Vec<String> = open
+                .iter()
+                .filter(|(_, txn)| txn.try_lock().is_ok_and(|txn| txn.expires <= now))
+                .map(|(id, _)| id.clone())
+                .collect();
+            ids.into_iter().filter_map(|id| open.remove(&id).map(|txn| (id, txn))).collect()
+        };
+        for (id, txn) in expired {
+            let mut txn = txn.lock().await;
+            if let Some(tx) = txn.tx.take() {
+                log::info!("rolling back transaction {} after {} writes: timed out", id, txn.writes.len());
+                if let Err(e) = tx.rollback().await {
+                    log::warn!("failed to roll back transaction {}: {}", id, e);
+                }
+                if txn.schema {
+                    schema_changed();
+                }
+            }
+        }
+    }
+}
+
+/// Rolls back the transactions that time out, once a second.
+pub fn start(transactions: Arc<Transactions>) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(Duration::from_secs(1));
+        loop {
+            interval.tick().await;
+            transactions.expire().await;
+        }
+    });
+}
+
+#[derive(Debug, Serialize)]
+pub struct Begun {
+    pub id: String,
+    pub timeout_secs: u64,
+}
+
+/// One write of a transaction.
+#[derive(Debug, Deserialize)]
+#[serde(tag = "op", rename_all = "lowercase")]
+pub enum Operation {
+    Insert { uri: String, data: Value },
+    /// `version`, if given, is the version the document has to be at.
+    Replace { uri: String, id: i64, data: Value, version: Option<i64> },
+    Update { uri: String, id: i64, data: Value, version: Option<i64> },
+    Delete { uri: String, id: i64, version: Option<i64> },
+}
+
+impl Operation {
+    fn uri(&self) -> &str {
+        match self {
+            Operation::Insert { uri, .. }
+            | Operation::Replace { uri, .. }
+            | Operation::Update { uri, .. }
+            | Operation::Delete { uri, .. } => uri,
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct WriteResponse {
+    pub id: i64,
+    /// The new version; deletes have none.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub version: Option<i64>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct CommitResponse {
+    pub writes: Vec<Written>,
+}
+
+fn principal_id(req: &HttpRequest) -> Option<String> {
+    req.extensions().get::<Principal>().map(|p| p.id.clone())
+}
+
+fn timeout(config: &ConfigHandle) -> Duration {
+    Duration::from_secs(config.get().transactions.timeout_secs)
+}
+
+// 开始一个事务, 占用连接池中的一个连接
+async fn begin(
+    req: HttpRequest,
+    pool: web::Data<SqlitePool>,
+    config: web::Data<ConfigHandle>,
+    transactions: web::Data<Transactions>,
+) -> HttpResponse {
+    let settings = config.get();
+    if settings.cluster.is_some() {
+        return HttpResponse::BadRequest().json("Transactions are not available in cluster mode");
+    }
+    if transactions.open.lock().unwrap().len() >= settings.transactions.max_open {
+        return HttpResponse::ServiceUnavailable().json(format!(
+            "Too many open transactions, at most {}",
+            settings.transactions.max_open
+        ));
+    }
+    let tx = match pool.begin().await {
+        Ok(tx) => tx,
+        Err(e) => return HttpResponse::InternalServerError().json(format!("Failed to begin transaction: {}", e)),
+    };
+
+    let id = random_token(16);
+    let open = Open {
+        tx: Some(tx),
+        owner: principal_id(&req),
+        expires: Instant::now() + timeout(&config),
+        writes: Vec::new(),
+        schema: false,
+    };
+    transactions.open.lock().unwrap().insert(id.clone(), Arc::new(tokio::sync::Mutex::new(open)));
+    HttpResponse::Ok().json(Begun { id, timeout_secs: settings.transactions.timeout_secs })
+}
+
+fn not_open(id: &str) -> HttpResponse {
+    HttpResponse::NotFound().json(format!("No open transaction '{}'", id))
+}
+
+// 在事务中执行一次写入
+async fn write(
+    req: HttpRequest,
+    id: web::Path<String>,
+    body: web::Json<Operation>,
+    config: web::Data<ConfigHandle>,
+    shards: web::Data<Shards>,
+    partitions: web::Data<Partitions>,
+    transactions: web::Data<Transactions>,
+) -> HttpResponse {
+    let operation = body.into_inner();
+    let uri = operation.uri().to_string();
+    let table = table_name(&uri);
+    if shards.get(&table).is_some() {
+        return HttpResponse::BadRequest().json(format!("Sharded collection '{}' cannot be written in a transaction", uri));
+    }
+    if partitions.tables(&table, None).await.is_some() {
+        return HttpResponse::BadRequest().json(format!("Partitioned collection '{}' cannot be written in a transaction", uri));
+    }
+    if config.get().collections.get(&uri).is_some_and(|c| c.splits()) {
+        return HttpResponse::BadRequest().json(format!("Nested collection '{}' cannot be written in a transaction", uri));
+    }
+    let dedup = config.get().collections.get(&uri).is_some_and(|c| c.dedup.is_some());
+    if dedup && matches!(operation, Operation::Insert { .. }) {
+        return HttpResponse::BadRequest().json(format!("Collection '{}' deduplicates inserts, which a transaction cannot", uri));
+    }
+
+    let Some(txn) = transactions.get(&id) else {
+        return not_open(&id);
+    };
+    let mut txn = txn.lock().await;
+    if txn.owner != principal_id(&req) {
+        return not_open(&id);
+    }
+    let Open { tx: Some(tx), .. } = &mut *txn else {
+        return not_open(&id);
+    };
+    let result = apply(tx, &table, &operation).await;
+    txn.expires = Instant::now() + timeout(&config);
+
+    match result {
+        Ok(applied) => {
+            txn.schema |= applied.schema;
+            let op = match operation {
+                Operation::Insert { .. } => WriteOp::Insert,
+                Operation::Replace { .. } => WriteOp::Replace,
+                Operation::Update { .. } => WriteOp::Update,
+                Operation::Delete { .. } => WriteOp::Delete,
+            };
+            txn.writes.push(Written { uri, id: applied.id, op });
+            HttpResponse::Ok().json(WriteResponse { id: applied.id, version: applied.version })
+        }
+        Err(e) => {
+            let document = match operation {
+                Operation::Insert { .. } => None,
+                Operation::Replace { id, .. } | Operation::Update { id, .. } | Operation::Delete { id, .. } => Some(id),
+            };
+            failure(e, document)
+        }
+    }
+}
+
+// 写入失败时事务保持打开, 由调用方决定回滚还是继续
+fn failure(e: StoreError, id: Option<i64>) -> HttpResponse {
+    match e {
+        StoreError::NotFound => HttpResponse::NotFound().json(format!("No document with id {}", id.unwrap_or_default())),
+        StoreError::VersionConflict { current } => HttpResponse::PreconditionFailed()
+            .json(format!("Document {} is at version {}", id.unwrap_or_default(), current)),
+        StoreError::Invalid(message) => HttpResponse::BadRequest().json(message),
+        StoreError::Database(e) if e.as_database_error().is_some_and(|e| e.is_unique_violation()) => {
+            HttpResponse::Conflict().json(format!("Failed to write data: {}", e))
+        }
+        e => HttpResponse::InternalServerError().json(format!("Failed to write data: {}", e)),
+    }
+}
+
+// 提交事务, 之后各层再处理这些写入
+async fn commit(
+    req: HttpRequest,
+    id: web::Path<String>,
+    store: web::Data<dyn DocumentStore>,
+    transactions: web::Data<Transactions>,
+) -> HttpResponse {
+    let Some(mut txn) = end(&req, &id, &transactions).await else {
+        return not_open(&id);
+    };
+    let Some(tx) = txn.tx.take() else {
+        return not_open(&id);
+    };
+    let committed = tx.commit().await;
+    if txn.schema {
+        schema_changed();
+    }
+    if let Err(e) = committed {
+        return HttpResponse::InternalServerError().json(format!("Failed to commit transaction: {}", e));
+    }
+    store.committed(&txn.writes).await;
+    HttpResponse::Ok().json(CommitResponse { writes: std::mem::take(&mut txn.writes) })
+}
+
+async fn rollback(req: HttpRequest, id: web::Path<String>, transactions: web::Data<Transactions>) -> HttpResponse {
+    let Some(mut txn) = end(&req, &id, &transactions).await else {
+        return not_open(&id);
+    };
+    let Some(tx) = txn.tx.take() else {
+        return not_open(&id);
+    };
+    let rolled_back = tx.rollback().await;
+    if txn.schema {
+        schema_changed();
+    }
+    match rolled_back {
+        Ok(()) => HttpResponse::Ok().json("Transaction rolled back"),
+        Err(e) => HttpResponse::InternalServerError().json(format!("Failed to roll back transaction: {}", e)),
+    }
+}
+
+// 取出调用方自己的事务, 不再接受新的写入
+async fn end(req: &HttpRequest, id: &str, transactions: &Transactions) -> Option<tokio::sync::OwnedMutexGuard<Open>> {
+    let txn = transactions.get(id)?.lock_owned().await;
+    if txn.owner != principal_id(req) {
+        return None;
+    }
+    transactions.remove(id);
+    Some(txn)
+}
+
+struct Applied {
+    id: i64,
+    version: Option<i64>,
+    schema: bool,
+}
+
+async fn apply(conn: &mut SqliteConnection, table: &str, operation: &Operation) -> Result<Applied, StoreError> {
+    match operation {
+        Operation::Insert { data, .. } => {
+            let Some(fields) = data.as_object() else {
+                return Err(StoreError::Invalid("Document must be a JSON object".to_string()));
+            };
+            check_fields(data)?;
+            let entries: Vec<(&String, &Value)> = fields.iter().filter(|(k, _)| *k != VERSION_FIELD).collect();
+            let columns: Vec<(&str, Option<&Value>)> =
+                entries.iter().filter(|(k, _)| *k != "id").map(|(k, v)| (k.as_str(), Some(*v))).collect();
+            let schema = ensure_columns(conn, table, &columns).await?;
+
+            let query = match entries.is_empty() {
+                true => format!("INSERT INTO {} DEFAULT VALUES", quote(table)),
+                false => format!(
+                    "INSERT INTO {} ({}) VALUES ({})",
+                    quote(table),
+                    entries.iter().map(|(k, _)| quote(k)).collect::<Vec<_>>().join(", "),
+                    vec!["?"; entries.len()].join(", ")
+                ),
+            };
+            let mut statement = sqlx::query(&query);
+            for (_, value) in &entries {
+                statement = bind_value(statement, Some(value));
+            }
+            let id = statement.execute(&mut *conn).await?.last_insert_rowid();
+            Ok(Applied { id, version: Some(1), schema })
+        }
+        Operation::Replace { id, data, version, .. } | Operation::Update { id, data, version, .. } => {
+            if !data.is_object() {
+                return Err(StoreError::Invalid("Document must be a JSON object".to_string()));
+            }
+            check_fields(data)?;
+            if columns(conn, table).await?.is_empty() {
+                return Err(StoreError::NotFound);
+            }
+            let mut assignments = field_assignments(data);
+            let fields: Vec<(&str, Option<&Value>)> = assignments.iter().map(|(column, value)| (column.as_str(), value.as_ref())).collect();
+            let schema = ensure_columns(conn, table, &fields).await?;
+            // 替换时文档中没有的列置为 NULL
+            if matches!(operation, Operation::Replace { .. }) {
+                for column in columns(conn, table).await? {
+                    if column != "id" && column != VERSION_FIELD && !assignments.iter().any(|(c, _)| c.eq_ignore_ascii_case(&column)) {
+                        assignments.push((column, None));
+                    }
+                }
+            }
+
+            let expected: Option<Vec<i64>> = version.map(|v| vec![v]);
+            let mut sets: Vec<String> = assignments.iter().map(|(column, _)| format!("{} = ?", quote(column))).collect();
+            sets.push(format!("{0} = {0} + 1", VERSION_FIELD));
+            let query = format!(
+                "UPDATE {} SET {} WHERE id = ?{} RETURNING {}",
+                quote(table),
+                sets.join(", "),
+                version_condition(expected.as_deref()),
+                VERSION_FIELD
+            );
+            let mut statement = sqlx::query(&query);
+            for (_, value) in &assignments {
+                statement = bind_value(statement, value.as_ref());
+            }
+            statement = statement.bind(id);
+            if let Some(version) = version {
+                statement = statement.bind(version);
+            }
+            let Some(row) = statement.fetch_optional(&mut *conn).await? else {
+                return Err(write_failure(conn, table, *id).await);
+            };
+            forget_hashes(conn, table, *id).await?;
+            Ok(Applied { id: *id, version: Some(row.get(0)), schema })
+        }
+        Operation::Delete { id, version, .. } => {
+            if columns(conn, table).await?.is_empty() {
+                return Err(StoreError::NotFound);
+            }
+            let expected: Option<Vec<i64>> = version.map(|v| vec![v]);
+            let query = format!("DELETE FROM {} WHERE id = ?{}", quote(table), version_condition(expected.as_deref()));
+            let mut statement = sqlx::query(&query).bind(id);
+            if let Some(version) = version {
+                statement = statement.bind(version);
+            }
+            if statement.execute(&mut *conn).await?.rows_affected() == 0 {
+                return Err(write_failure(conn, table, *id).await);
+            }
+            forget_hashes(conn, table, *id).await?;
+            Ok(Applied { id: *id, version: None, schema: false })
+        }
+    }
+}
+
+// 事务中的连接上读取表的列名; 表不存在时为空
+async fn columns(conn: &mut SqliteConnection, table: &str) -> Result<Vec<String>, sqlx::Error> {
+    let rows = sqlx::query(&format!("PRAGMA table_info({})", quote(table))).fetch_all(&mut *conn).await?;
+    Ok(rows.iter().map(|row| row.get::<String, _>("name")).collect())
+}
+
+// 在事务中建表, 补上缺少的列或放宽列的类型, 回滚时一并撤销; 返回是否改了表结构
+async fn ensure_columns(conn: &mut SqliteConnection, table: &str, fields: &[(&str, Option<&Value>)]) -> Result<bool, StoreError> {
+    let existing = columns(conn, table).await?;
+    if existing.is_empty() {
+        sqlx::query(&create_table_statement(table, &field_types(fields))).execute(&mut *conn).await.map_err(StoreError::Schema)?;
+        return Ok(true);
+    }
+    let mut missing: Vec<(&str, &str)> =
+        field_types(fields).into_iter().filter(|(key, _)| !existing.iter().any(|name| name.eq_ignore_ascii_case(key))).collect();
+    // 旧版本创建的表没有 _version 列
+    if !existing.iter().any(|name| name == VERSION_FIELD) {
+        missing.push((VERSION_FIELD, "INTEGER NOT NULL DEFAULT 1"));
+    }
+    for (key, column_type) in &missing {
+        let query = format!("ALTER TABLE {} ADD COLUMN {} {}", quote(table), quote(key), column_type);
+        sqlx::query(&query).execute(&mut *conn).await.map_err(StoreError::Schema)?;
+    }
+    let declared: Vec<(String, String)> =
+        sqlx::query_as("SELECT name, type FROM pragma_table_info(?)").bind(table).fetch_all(&mut *conn).await?;
+    let widened = widenings(&declared, fields);
+    if !widened.is_empty() {
+        rebuild_table(conn, table, &[], &widened).await.map_err(StoreError::Schema)?;
+    }
+    Ok(!missing.is_empty() || !widened.is_empty())
+}
+
+// 条件不满足时区分文档不存在和版本不一致
+async fn write_failure(conn: &mut SqliteConnection, table: &str, id: i64) -> StoreError {
+    let query = format!("SELECT {} FROM {} WHERE id = ?", VERSION_FIELD, quote(table));
+    match sqlx::query_scalar::<_, i64>(&query).bind(id).fetch_optional(&mut *conn).await {
+        Ok(Some(current)) => StoreError::VersionConflict { current },
+        Ok(None) => StoreError::NotFound,
+        Err(e) => StoreError::Database(e),
+    }
+}
+
+async fn forget_hashes(conn: &mut SqliteConnection, table: &str, id: i64) -> Result<(), sqlx::Error> {
+    sqlx::query("DELETE FROM _content_hashes WHERE collection = ? AND document_id = ?")
+        .bind(table)
+        .bind(id)
+        .execute(&mut *conn)
+        .await?;
+    Ok(())
+}
+
+#[cfg(all(test, feature = "sqlite"))]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use crate::database::{collection_exists, create_system_tables, pool_options, SqliteStore};
+    use actix_web::dev::{Service, ServiceResponse};
+    use actix_web::http::StatusCode;
+    use actix_web::{test, App};
+    use serde_json::json;
+    use sqlx::sqlite::SqliteConnectOptions;
+    use std::path::PathBuf;
+
+    // 事务占着一个连接, 内存数据库只有一个连接, 所以用临时文件
+    struct Database {
+        path: PathBuf,
+        pool: SqlitePool,
+    }
+
+    impl Database {
+        async fn open(name: &str, connections: u32) -> Self {
+            let path = std::env::temp_dir().join(format!("json_storage_txn_{}_{}.db", std::process::id(), name));
+            let _ = std::fs::remove_file(&path);
+            let options = SqliteConnectOptions::new().filename(&path).create_if_missing(true);
+            let pool = pool_options()
+                .max_connections(connections)
+                .acquire_timeout(Duration::from_millis(300))
+                .connect_with(options)
+                .await
+                .unwrap();
+            create_system_tables(&pool).await.unwrap();
+            Database { path, pool }
+        }
+    }
+
+    impl Drop for Database {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_file(&self.path);
+        }
+    }
+
+    async fn app(
+        pool: &SqlitePool,
+        transactions: &Arc<Transactions>,
+        config: Value,
+    ) -> impl Service<actix_http::Request, Response = ServiceResponse, Error = actix_web::Error> {
+        let config = ConfigHandle::new(None, serde_json::from_value::<Config>(config).unwrap());
+        let partitions = Partitions::open(&config, pool.clone()).await.unwrap();
+        let store: Arc<dyn DocumentStore> = Arc::new(SqliteStore::new(pool.clone()));
+        test::init_service(
+            App::new()
+                .app_data(web::Data::new(pool.clone()))
+                .app_data(web::Data::new(config))
+                .app_data(web::Data::new(Shards::default()))
+                .app_data(web::Data::new(partitions))
+                .app_data(web::Data::from(transactions.clone()))
+                .app_data(web::Data::from(store))
+                .configure(configure),
+        )
+        .await
+    }
+
+    async fn post(app: &impl Service<actix_http::Request, Response = ServiceResponse, Error = actix_web::Error>, uri: &str, body: Value) -> (StatusCode, Value) {
+        let response = test::call_service(app, test::TestRequest::post().uri(uri).set_json(body).to_request()).await;
+        (response.status(), test::read_body_json(response).await)
+    }
+
+    async fn begin(app: &impl Service<actix_http::Request, Response = ServiceResponse, Error = actix_web::Error>) -> String {
+        let (status, begun) = post(app, "/_txn", json!({})).await;
+        assert_eq!(status, StatusCode::OK);
+        begun["id"].as_str().unwrap().to_string()
+    }
+
+    // 列里存的是 JSON 文本
+    async fn names(pool: &SqlitePool, table: &str) -> Vec<Value> {
+        if !collection_exists(pool, table).await.unwrap() {
+            return Vec::new();
+        }
+        let names: Vec<String> = sqlx::query_scalar(&format!("SELECT name FROM {} ORDER BY id", quote(table))).fetch_all(pool).await.unwrap();
+        names.iter().map(|name| serde_json::from_str(name).unwrap()).collect()
+    }
+
+    #[actix_web::test]
+    async fn writes_to_several_collections_land_together_on_commit() {
+        let db = Database::open("commit", 3).await;
+        let transactions = Arc::new(Transactions::new());
+        let app = app(&db.pool, &transactions, json!({})).await;
+        sqlx::query("CREATE TABLE users (id INTEGER PRIMARY KEY AUTOINCREMENT, _version INTEGER NOT NULL DEFAULT 1, name TEXT)")
+            .execute(&db.pool)
+            .await
+            .unwrap();
+        sqlx::query("INSERT INTO users (name) VALUES ('\"a\"')").execute(&db.pool).await.unwrap();
+
+        let id = begin(&app).await;
+        let txn = format!("/_txn/{}", id);
+        let (status, written) = post(&app, &txn, json!({ "op": "insert", "uri": "orders", "data": { "name": "o1" } })).await;
+        assert_eq!((status, written), (StatusCode::OK, json!({ "id": 1, "version": 1 })));
+        let (status, written) = post(&app, &txn, json!({ "op": "update", "uri": "users", "id": 1, "data": { "name": "b" }, "version": 1 })).await;
+        assert_eq!((status, written), (StatusCode::OK, json!({ "id": 1, "version": 2 })));
+        // 失败的写入不结束事务
+        let (status, _) = post(&app, &txn, json!({ "op": "delete", "uri": "users", "id": 9 })).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        let (status, _) = post(&app, &txn, json!({ "op": "update", "uri": "users", "id": 1, "data": {}, "version": 1 })).await;
+        assert_eq!(status, StatusCode::PRECONDITION_FAILED);
+
+        // 提交之前其他连接看不到这些写入
+        assert_eq!(names(&db.pool, "orders").await, Vec::<Value>::new());
+        assert_eq!(names(&db.pool, "users").await, ["a"]);
+
+        let (status, committed) = post(&app, &format!("{}/commit", txn), json!({})).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(
+            committed["writes"],
+            json!([{ "uri": "orders", "id": 1, "op": "insert" }, { "uri": "users", "id": 1, "op": "update" }])
+        );
+        assert_eq!(names(&db.pool, "orders").await, ["o1"]);
+        assert_eq!(names(&db.pool, "users").await, ["b"]);
+        let (status, _) = post(&app, &txn, json!({ "op": "insert", "uri": "orders", "data": {} })).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        assert!(transactions.open.lock().unwrap().is_empty());
+    }
+
+    #[actix_web::test]
+    async fn rollback_undoes_every_write_and_the_tables_it_created() {
+        let db = Database::open("rollback", 3).await;
+        let transactions = Arc::new(Transactions::new());
+        let app = app(&db.pool, &transactions, json!({})).await;
+        let id = begin(&app).await;
+        let txn = format!("/_txn/{}", id);
+        for name in ["o1", "o2"] {
+            let (status, _) = post(&app, &txn, json!({ "op": "insert", "uri": "orders", "data": { "name": name } })).await;
+            assert_eq!(status, StatusCode::OK);
+        }
+        let (status, _) = post(&app, &format!("{}/rollback", txn), json!({})).await;
+        assert_eq!(status, StatusCode::OK);
+        assert!(!collection_exists(&db.pool, "orders").await.unwrap());
+        let (status, body) = post(&app, &format!("{}/commit", txn), json!({})).await;
+        assert_eq!((status, body), (StatusCode::NOT_FOUND, json!(format!("No open transaction '{}'", id))));
+    }
+
+    #[actix_web::test]
+    async fn collections_that_split_their_writes_are_refused() {
+        let db = Database::open("refused", 3).await;
+        let transactions = Arc::new(Transactions::new());
+        let config = json!({ "collections": { "orders": { "nested": true }, "events": { "dedup": { "mode": "skip" } } } });
+        let app = app(&db.pool, &transactions, config).await;
+        let id = begin(&app).await;
+        let txn = format!("/_txn/{}", id);
+        let (status, body) = post(&app, &txn, json!({ "op": "insert", "uri": "orders", "data": {} })).await;
+        assert_eq!((status, body), (StatusCode::BAD_REQUEST, json!("Nested collection 'orders' cannot be written in a transaction")));
+        let (status, _) = post(&app, &txn, json!({ "op": "insert", "uri": "events", "data": {} })).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        let (status, _) = post(&app, &format!("{}/rollback", txn), json!({})).await;
+        assert_eq!(status, StatusCode::OK);
+    }
+
+    #[actix_web::test]
+    async fn an_expired_transaction_is_rolled_back_and_frees_its_connection() {
+        let db = Database::open("expire", 1).await;
+        let transactions = Arc::new(Transactions::new());
+        let app = app(&db.pool, &transactions, json!({ "transactions": { "timeout_secs": 0 } })).await;
+        let id = begin(&app).await;
+        let (status, _) = post(&app, &format!("/_txn/{}", id), json!({ "op": "insert", "uri": "orders", "data": { "name": "o1" } })).await;
+        assert_eq!(status, StatusCode::OK);
+        // 事务占着池中唯一的连接
+        assert!(matches!(db.pool.acquire().await, Err(sqlx::Error::PoolTimedOut)));
+
+        transactions.expire().await;
+        assert!(transactions.open.lock().unwrap().is_empty());
+        assert!(!collection_exists(&db.pool, "orders").await.unwrap());
+        let (status, _) = post(&app, &format!("/_txn/{}/commit", id), json!({})).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+    }
+
+    #[actix_web::test]
+    async fn a_transaction_in_use_is_not_expired_under_it() {
+        let db = Database::open("in_use", 1).await;
+        let transactions = Arc::new(Transactions::new());
+        let app = app(&db.pool, &transactions, json!({ "transactions": { "timeout_secs": 0 } })).await;
+        let id = begin(&app).await;
+        let txn = transactions.get(&id).unwrap();
+        {
+            let _busy = txn.lock().await;
+            transactions.expire().await;
+            assert!(transactions.get(&id).is_some());
+        }
+        transactions.expire().await;
+        assert!(transactions.get(&id).is_none());
+        assert!(txn.lock().await.tx.is_none());
+    }
+}
@@ -0,0 +1,97 @@
+// Shared helpers for the admin pages.
+//
+// Authentication is a session cookie set by the server; the page only keeps
+// the session's CSRF token in memory and sends it with mutating requests.
+let csrfToken = '';
+
+async function loadSession() {
+  const res = await fetch('/_auth/session');
+  const session = res.ok ? await res.json() : null;
+  csrfToken = session ? session.csrf_token : '';
+  return session;
+}
+
+async function api(path, options = {}) {
+  const headers = Object.assign({}, options.headers);
+  const method = (options.method || 'GET').toUpperCase();
+  if (csrfToken && !['GET', 'HEAD', 'OPTIONS'].includes(method)) headers['X-CSRF-Token'] = csrfToken;
+  const res = await fetch(path, Object.assign({}, options, { headers }));
+  const body = res.status === 204 ? null : await res.json();
+  if (!res.ok) {
+    const message = typeof body === 'string' ? body : (body && body.error) || res.statusText;
+    const error = new Error(message);
+    error.body = body;
+    throw error;
+  }
+  return body;
+}
+
+function postJson(path, body) {
+  return api(path, { method: 'POST', headers: { 'Content-Type': 'application/json' }, body: JSON.stringify(body) });
+}
+
+function el(tag, text) {
+  const node = document.createElement(tag);
+  if (text !== undefined) node.textContent = text;
+  return node;
+}
+
+function showError(e) {
+  document.getElementById('error').textContent = e ? e.message : '';
+}
+
+// Renders documents as a table with one column per key seen in any document.
+function renderDocuments(table, documents) {
+  const keys = [];
+  for (const doc of documents) {
+    for (const key of Object.keys(doc)) if (!keys.includes(key)) keys.push(key);
+  }
+  table.replaceChildren();
+  const head = el('tr');
+  keys.forEach(k => head.append(el('th', k)));
+  table.append(head);
+  for (const doc of documents) {
+    const row = el('tr');
+    for (const key of keys) {
+      const value = doc[key];
+      const cell = el('td');
+      cell.append(el('pre', typeof value === 'object' && value !== null ? JSON.stringify(value, null, 2) : String(value ?? '')));
+      row.append(cell);
+    }
+    table.append(row);
+  }
+}
+
+// Exchanges an API key for a session cookie; the key itself is not kept.
+function setupApiKeyInput(input, onChange) {
+  localStorage.removeItem('apiKey');
+  const showSession = (session) => {
+    input.placeholder = session ? 'signed in as ' + session.id : 'admin key';
+  };
+  loadSession().then(showSession).finally(onChange);
+  input.onchange = async () => {
+    const apiKey = input.value.trim();
+    input.value = '';
+    if (!apiKey) return;
+    try {
+      const session = await postJson('/_auth/session', { api_key: apiKey });
+      csrfToken = session.csrf_token;
+      showSession(session);
+      showError(null);
+    } catch (e) {
+      showError(e);
+    }
+    onChange();
+  };
+}
+
+// Ends the browser session started through SSO.
+function setupLogout(link, onChange) {
+  link.onclick = async (event) => {
+    event.preventDefault();
+    await api('/_auth/logout', { method: 'POST' }).catch(showError);
+    csrfToken = '';
+    document.getElementById('key').placeholder = 'admin key';
+    onChange();
+  };
+}
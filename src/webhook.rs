@@ -0,0 +1,67 @@
+//! Webhook delivery.
+//!
+//! [`deliver`] queues a JSON POST as a [job](crate::jobs) of kind `webhook`,
+//! so a receiver that is down or answers with an error status gets it again
+//! with the queue's retries. Posting needs the `webhooks` feature.
+
+use async_trait::async_trait;
+use serde_json::{json, Value};
+
+use crate::jobs::{Context, Queue, Status, Task};
+
+/// Kind of the jobs delivering webhooks.
+pub const KIND: &str = "webhook";
+
+/// Queues a POST of `body` to `url`.
+pub async fn deliver(queue: &Queue, url: &str, body: &Value) -> Result<(), sqlx::Error> {
+    let payload = json!({ "url": url, "body": body });
+    queue.create(KIND, None, None, &payload, &json!({}), Status::Queued).await.map(|_| ())
+}
+
+/// Runs `webhook` jobs; register it with the job queue under [`KIND`].
+pub struct WebhookTask {
+    #[cfg(feature = "webhooks")]
+    client: reqwest::Client,
+}
+
+impl Default for WebhookTask {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl WebhookTask {
+    pub fn new() -> Self {
+        Self {
+            #[cfg(feature = "webhooks")]
+            client: reqwest::Client::builder()
+                .timeout(std::time::Duration::from_secs(10))
+                .build()
+                .expect("Failed to build the webhook client"),
+        }
+    }
+}
+
+#[async_trait]
+impl Task for WebhookTask {
+    #[cfg(feature = "webhooks")]
+    async fn run(&self, job: &Context<'_>) -> Result<(), String> {
+        let url = job.job.payload["url"].as_str().unwrap_or_default();
+        let response = self
+            .client
+            .post(url)
+            .json(&job.job.payload["body"])
+            .send()
+            .await
+            .map_err(|e| format!("Failed to post to {}: {}", url, e))?;
+        match response.status() {
+            status if status.is_success() => Ok(()),
+            status => Err(format!("{} answered {}", url, status)),
+        }
+    }
+
+    #[cfg(not(feature = "webhooks"))]
+    async fn run(&self, _job: &Context<'_>) -> Result<(), String> {
+        Err("the server was built without the `webhooks` feature".to_string())
+    }
+}
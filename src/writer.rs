@@ -0,0 +1,210 @@
+//! Per-collection write tasks.
+//!
+//! Every write to a collection is handed to a task of its own, which runs the
+//! writes it receives one after another, in the order they arrived. Writes to
+//! one collection therefore never contend for SQLite's write lock with each
+//! other, and their history versions, checksums and change events follow the
+//! order they were committed in. Writes to different collections still run
+//! in parallel, each in its collection's task.
+//!
+//! A task takes up to `writers.batch` queued writes at a time and runs them
+//! back to back. It ends once it has had no writes for `writers.idle_secs`,
+//! and the next write starts a new one. Reads do not go through the tasks.
+
+use async_trait::async_trait;
+use serde_json::Value;
+use std::collections::HashMap;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::mpsc::error::{SendError, TryRecvError};
+use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
+use tokio::sync::oneshot;
+
+use crate::config::{ConfigHandle, Writers};
+use crate::metrics::Metrics;
+use crate::store::{DedupMode, DocumentStore, Inserted, PageQuery, Paged, StoreError, UpdatedMany, Upserted, Written};
+use crate::telemetry;
+
+/// A write waiting in a collection's queue; it answers its caller itself.
+type Job = Pin<Box<dyn Future<Output = ()> + Send>>;
+
+/// A [`DocumentStore`] that runs the writes of each collection in a task of
+/// that collection, and passes reads straight on to `inner`.
+pub struct WriterStore {
+    inner: Arc<dyn DocumentStore>,
+    settings: Writers,
+    /// Queue of the running task of each collection. Jobs are only sent
+    /// while holding the lock, so a task that ends leaves none behind.
+    queues: Arc<Mutex<HashMap<String, UnboundedSender<Job>>>>,
+    metrics: Arc<Metrics>,
+}
+
+impl WriterStore {
+    pub fn new(inner: Arc<dyn DocumentStore>, settings: Writers, metrics: Arc<Metrics>) -> Self {
+        WriterStore { inner, settings, queues: Arc::new(Mutex::new(HashMap::new())), metrics }
+    }
+
+    /// Queues `write` behind the other writes to `uri` and waits for its result.
+    async fn submit<T, F, Fut>(&self, uri: &str, write: F) -> Result<T, StoreError>
+    where
+        T: Send + 'static,
+        F: FnOnce(Arc<dyn DocumentStore>) -> Fut + Send + 'static,
+        Fut: Future<Output = Result<T, StoreError>> + Send + 'static,
+    {
+        let (reply, result) = oneshot::channel();
+        let inner = self.inner.clone();
+        let job: Job = Box::pin(telemetry::in_current_trace(async move {
+            let _ = reply.send(write(inner).await);
+        }));
+
+        {
+            let mut queues = self.queues.lock().unwrap();
+            let unsent = match queues.get(uri) {
+                Some(queue) => queue.send(job).err().map(|SendError(job)| job),
+                None => Some(job),
+            };
+            // 没有任务, 或任务因 panic 而结束了
+            if let Some(job) = unsent {
+                let (queue, jobs) = mpsc::unbounded_channel();
+                let _ = queue.send(job);
+                queues.insert(uri.to_string(), queue);
+                self.spawn(uri, jobs);
+            }
+        }
+
+        result
+            .await
+            .map_err(|_| StoreError::Unavailable(format!("the writer of '{}' stopped", uri)))?
+    }
+
+    // 集合的写入任务: 每次取出若干个排队的写入依次执行, 空闲一段时间后结束
+    fn spawn(&self, uri: &str, mut jobs: UnboundedReceiver<Job>) {
+        let uri = uri.to_string();
+        let queues = self.queues.clone();
+        let metrics = self.metrics.clone();
+        let batch = self.settings.batch;
+        let idle = Duration::from_secs(self.settings.idle_secs);
+        metrics.gauge_add("collection_writers", Vec::new(), 1.0);
+        tokio::spawn(async move {
+            loop {
+                let first = match tokio::time::timeout(idle, jobs.recv()).await {
+                    Ok(Some(job)) => job,
+                    Ok(None) => break,
+                    Err(_) => {
+                        // 持有锁时确认队列为空再结束, 之后的写入会启动新的任务
+                        let mut queues = queues.lock().unwrap();
+                        match jobs.try_recv() {
+                            Ok(job) => job,
+                            Err(TryRecvError::Empty | TryRecvError::Disconnected) => {
+                                queues.remove(&uri);
+                                break;
+                            }
+                        }
+                    }
+                };
+                let mut pending = vec![first];
+                while pending.len() < batch {
+                    match jobs.try_recv() {
+                        Ok(job) => pending.push(job),
+                        Err(_) => break,
+                    }
+                }
+                metrics.incr("collection_write_batches_total", Vec::new(), 1.0);
+                metrics.incr("collection_writes_total", Vec::new(), pending.len() as f64);
+                for job in pending {
+                    job.await;
+                }
+            }
+            metrics.gauge_add("collection_writers", Vec::new(), -1.0);
+        });
+    }
+}
+
+#[async_trait]
+impl DocumentStore for WriterStore {
+    async fn insert(&self, uri: &str, doc: &Value) -> Result<i64, StoreError> {
+        let (collection, doc) = (uri.to_string(), doc.clone());
+        self.submit(uri, move |store| async move { store.insert(&collection, &doc).await }).await
+    }
+
+    async fn list(&self, uri: &str) -> Result<Vec<Value>, StoreError> {
+        self.inner.list(uri).await
+    }
+
+    async fn get(&self, uri: &str, id: i64) -> Result<Option<Value>, StoreError> {
+        self.inner.get(uri, id).await
+    }
+
+    async fn get_many(&self, uri: &str, ids: &[i64]) -> Result<Vec<Value>, StoreError> {
+        self.inner.get_many(uri, ids).await
+    }
+
+    async fn insert_unique(&self, uri: &str, doc: &Value, hash: &str, mode: DedupMode) -> Result<Inserted, StoreError> {
+        let (collection, doc, hash) = (uri.to_string(), doc.clone(), hash.to_string());
+        self.submit(uri, move |store| async move { store.insert_unique(&collection, &doc, &hash, mode).await }).await
+    }
+
+    async fn upsert(&self, uri: &str, key: &str, doc: &Value) -> Result<Upserted, StoreError> {
+        let (collection, key, doc) = (uri.to_string(), key.to_string(), doc.clone());
+        self.submit(uri, move |store| async move { store.upsert(&collection, &key, &doc).await }).await
+    }
+
+    async fn replace(&self, uri: &str, id: i64, doc: &Value, expected: Option<&[i64]>) -> Result<i64, StoreError> {
+        let (collection, doc, expected) = (uri.to_string(), doc.clone(), expected.map(<[i64]>::to_vec));
+        self.submit(uri, move |store| async move { store.replace(&collection, id, &doc, expected.as_deref()).await }).await
+    }
+
+    async fn update(&self, uri: &str, id: i64, doc: &Value, expected: Option<&[i64]>) -> Result<i64, StoreError> {
+        let (collection, doc, expected) = (uri.to_string(), doc.clone(), expected.map(<[i64]>::to_vec));
+        self.submit(uri, move |store| async move { store.update(&collection, id, &doc, expected.as_deref()).await }).await
+    }
+
+    async fn delete(&self, uri: &str, id: i64, expected: Option<&[i64]>) -> Result<(), StoreError> {
+        let (collection, expected) = (uri.to_string(), expected.map(<[i64]>::to_vec));
+        self.submit(uri, move |store| async move { store.delete(&collection, id, expected.as_deref()).await }).await
+    }
+
+    async fn update_many(&self, uri: &str, filter: &Value, update: &Value) -> Result<UpdatedMany, StoreError> {
+        let (collection, filter, update) = (uri.to_string(), filter.clone(), update.clone());
+        self.submit(uri, move |store| async move { store.update_many(&collection, &filter, &update).await }).await
+    }
+
+    // 只查找不删除时不必排队
+    async fn delete_many(&self, uri: &str, filter: &Value, dry_run: bool) -> Result<Vec<i64>, StoreError> {
+        if dry_run {
+            return self.inner.delete_many(uri, filter, true).await;
+        }
+        let (collection, filter) = (uri.to_string(), filter.clone());
+        self.submit(uri, move |store| async move { store.delete_many(&collection, &filter, false).await }).await
+    }
+
+    async fn find(&self, uri: &str, filter: &Value) -> Result<Vec<Value>, StoreError> {
+        self.inner.find(uri, filter).await
+    }
+
+    async fn count(&self, uri: &str, filter: &Value) -> Result<u64, StoreError> {
+        self.inner.count(uri, filter).await
+    }
+
+    async fn find_page(&self, uri: &str, filter: &Value, page: &PageQuery) -> Result<Paged, StoreError> {
+        self.inner.find_page(uri, filter, page).await
+    }
+
+    async fn version(&self, uri: &str) -> Result<String, StoreError> {
+        self.inner.version(uri).await
+    }
+
+    // 写入已经提交, 不必排队
+    async fn committed(&self, writes: &[Written]) {
+        self.inner.committed(writes).await
+    }
+}
+
+/// Wraps `store` in a [`WriterStore`]. Layers that depend on the request's
+/// task, such as the cluster's forwarding and read-your-writes sessions, have
+/// to wrap it from outside.
+pub fn wrap(config: &ConfigHandle, store: Arc<dyn DocumentStore>, metrics: Arc<Metrics>) -> Arc<dyn DocumentStore> {
+    Arc::new(WriterStore::new(store, config.get().writers.clone(), metrics))
+}